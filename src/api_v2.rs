@@ -0,0 +1,511 @@
+use crate::etag;
+use crate::events::{EventKind, unix_now};
+use crate::filter::Filter;
+use crate::handlers::{
+    allocate_charged, allocation_target, check_approval, check_renewal_fence, rejection_message,
+    requested, require_fence,
+};
+use crate::insights;
+use crate::ippool::{IpAllocation, IpPoolError, Lease, Slot};
+use crate::perf::Operation;
+use crate::pools::DEFAULT_POOL;
+use crate::state::AppState;
+use axum::{
+    Json, Router,
+    extract::{
+        Path, Query, State,
+        rejection::{JsonRejection, QueryRejection},
+    },
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+// API v2: typed addresses, cursor-paginated lists and RFC 9457
+// problem+json errors. v1 stays available alongside it.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/api/v2/allocations",
+            post(allocate)
+                .get(list_allocations)
+                .layer(middleware::from_fn(etag::conditional)),
+        )
+        .route(
+            "/api/v2/vms/{vm_id}/allocations",
+            get(vm_allocations).delete(release_vm),
+        )
+}
+
+// Default and maximum page size for list endpoints
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+// RFC 9457 problem details
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    #[serde(skip)]
+    pub retry_after: Option<Duration>,
+}
+
+impl Problem {
+    fn new(status: StatusCode, kind: &str, detail: String) -> Self {
+        Problem {
+            kind: format!("urn:ippool:problem:{}", kind),
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail,
+            retry_after: None,
+        }
+    }
+}
+
+impl From<IpPoolError> for Problem {
+    fn from(e: IpPoolError) -> Self {
+        let (status, message) = e.status_and_message();
+        Problem::new(status, e.kind(), message)
+    }
+}
+
+impl From<JsonRejection> for Problem {
+    fn from(rejection: JsonRejection) -> Self {
+        let detail = rejection_message(&rejection);
+        tracing::warn!("Request failed: Invalid JSON body: {}", detail);
+        Problem::new(rejection.status(), "invalid_body", detail)
+    }
+}
+
+impl From<QueryRejection> for Problem {
+    fn from(rejection: QueryRejection) -> Self {
+        tracing::warn!("Request failed: Invalid query: {}", rejection.body_text());
+        Problem::new(rejection.status(), "invalid_query", rejection.body_text())
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let retry_after = self.retry_after;
+
+        let mut response = (status, Json(self)).into_response();
+        let headers = response.headers_mut();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+        if let Some(retry_after) = retry_after {
+            headers.insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(retry_after.as_secs()),
+            );
+        }
+        response
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AllocateRequest {
+    pub vm_id: String,
+    #[serde(default)]
+    pub pool: Option<String>,
+    #[serde(default)]
+    pub interface: Option<String>,
+    #[serde(default)]
+    pub purpose: Option<String>,
+    #[serde(default)]
+    pub ttl: Option<u64>, // seconds, pool default when absent
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>, // kept when absent
+    #[serde(default)]
+    pub fence_token: Option<u64>, // renewals only: token of the allocation being renewed
+}
+
+#[derive(Debug, Serialize)]
+pub struct Allocation {
+    pub ip: Ipv4Addr,
+    pub vm_id: String,
+    pub pool: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub purpose: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub reserved: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    pub expires_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renew_before: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fence_token: Option<u64>,
+}
+
+impl Allocation {
+    fn new(pool: &str, allocation: IpAllocation) -> Option<Self> {
+        Some(Allocation {
+            ip: allocation.ip.parse().ok()?,
+            vm_id: allocation.vm_id,
+            pool: pool.to_string(),
+            interface: allocation.interface,
+            purpose: allocation.purpose,
+            reserved: allocation.reserved,
+            label: allocation.label,
+            labels: allocation.labels,
+            expires_at: allocation.expires_at,
+            renew_before: allocation.renew_before,
+            fence_token: allocation.fence_token,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AllocateResponse {
+    #[serde(flatten)]
+    pub allocation: Allocation,
+    pub gateway: Ipv4Addr,
+    pub network: Ipv4Addr,
+    pub prefix_len: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vlan_id: Option<u16>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PageQuery {
+    #[serde(default)]
+    pub pool: Option<String>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    // Last address of the previous page
+    #[serde(default)]
+    pub cursor: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub q: Option<String>, // filter expression, see `filter`
+}
+
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<Ipv4Addr>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReleaseQuery {
+    // Token of one of the VM's allocations; nothing is released if none
+    // of them carries it
+    #[serde(default)]
+    pub fence_token: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReleaseResponse {
+    pub vm_id: String,
+    pub released: Vec<Allocation>,
+}
+
+// Allocate handler
+async fn allocate(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Result<Json<AllocateRequest>, JsonRejection>,
+) -> Result<(StatusCode, Json<AllocateResponse>), Problem> {
+    let Json(req) = body?;
+    tracing::info!(
+        "v2 allocation request - vm_id: {}, pool: {:?}, interface: {:?}, purpose: {:?}, ttl: {:?}",
+        req.vm_id,
+        req.pool,
+        req.interface,
+        req.purpose,
+        req.ttl
+    );
+
+    let lease = match req.ttl {
+        Some(0) => return Err(IpPoolError::InvalidLease.into()),
+        Some(ttl) => Lease::Ttl(Duration::from_secs(ttl)),
+        None => Lease::PoolDefault,
+    };
+    let slot = Slot::new(req.interface, req.purpose);
+    let target = allocation_target(&state, &headers, req.pool.as_deref()).await?;
+    let (pool_name, pool, team) = (target.name.clone(), &target.pool, target.team.as_deref());
+    check_renewal_fence(&state, pool, &req.vm_id, &slot, req.fence_token).await?;
+    check_approval(&state, &pool_name, pool, &req.vm_id, &slot).await?;
+
+    let labels = Some(req.labels.clone()).filter(|labels| !labels.is_empty());
+    let requested = requested(labels, None, None, None, team, None);
+    let result = allocate_charged(
+        &state, &headers, &target, &req.vm_id, &slot, lease, &requested,
+    );
+    let (ip, expires_at) = match result.await {
+        Ok(allocated) => allocated,
+        Err(e) => {
+            let retry_after = match e {
+                IpPoolError::NoAvailableIps => Some(pool.retry_after().await),
+                IpPoolError::BudgetExceeded(retry_after) => Some(Duration::from_secs(retry_after)),
+                _ => None,
+            };
+            return Err(Problem {
+                retry_after,
+                ..e.into()
+            });
+        }
+    };
+
+    state
+        .events
+        .emit(
+            EventKind::Allocated,
+            &pool_name,
+            &req.vm_id,
+            &ip,
+            insights::details(&insights::caller(&headers, team)),
+        )
+        .await;
+    tracing::info!("v2 IP allocated - vm_id: {}, ip: {}", req.vm_id, ip);
+
+    let stats = pool.get_stats().await;
+    let network = stats["network"].as_str().unwrap_or_default();
+    let (network, prefix_len) = network.split_once('/').unwrap_or((network, "32"));
+    let response = AllocateResponse {
+        allocation: Allocation {
+            ip: ip.parse().map_err(|_| IpPoolError::InvalidIp)?,
+            vm_id: req.vm_id,
+            pool: pool_name,
+            interface: slot.interface,
+            purpose: Some(slot.purpose),
+            reserved: false,
+            label: None,
+            labels: req.labels,
+            expires_at,
+            renew_before: pool.renew_before(expires_at).await,
+            fence_token: pool.fence_token(&ip).await,
+        },
+        gateway: stats["gateway"]
+            .as_str()
+            .and_then(|gateway| gateway.parse().ok())
+            .ok_or(IpPoolError::InvalidIp)?,
+        network: network.parse().map_err(|_| IpPoolError::InvalidIp)?,
+        prefix_len: prefix_len.parse().unwrap_or(32),
+        vlan_id: stats["vlan_id"].as_u64().map(|vlan_id| vlan_id as u16),
+    };
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+// List allocations handler, ordered by address
+async fn list_allocations(
+    State(state): State<AppState>,
+    query: Result<Query<PageQuery>, QueryRejection>,
+) -> Result<Json<Page<Allocation>>, Problem> {
+    let Query(query) = query?;
+    tracing::debug!(
+        "v2 list allocations request - pool: {:?}, limit: {:?}, cursor: {:?}",
+        query.pool,
+        query.limit,
+        query.cursor
+    );
+
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    let pool = state.pools.get(&pool_name).await?;
+    let filter = (query.q.as_deref())
+        .map(|q| Filter::parse(q, unix_now()).map_err(IpPoolError::InvalidFilter))
+        .transpose()?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let mut allocations: Vec<Allocation> = pool
+        .list_allocations()
+        .await
+        .into_iter()
+        .filter(|allocation| {
+            filter
+                .as_ref()
+                .is_none_or(|f| f.matches(&pool_name, allocation))
+        })
+        .filter_map(|allocation| Allocation::new(&pool_name, allocation))
+        .filter(|allocation| query.cursor.is_none_or(|cursor| allocation.ip > cursor))
+        .collect();
+    allocations.sort_by_key(|allocation| allocation.ip);
+
+    let next_cursor = (allocations.len() > limit).then(|| allocations[limit - 1].ip);
+    allocations.truncate(limit);
+
+    tracing::debug!("Returning {} allocations", allocations.len());
+    Ok(Json(Page {
+        items: allocations,
+        next_cursor,
+    }))
+}
+
+// Every address of a VM across pools
+async fn vm_allocations(
+    State(state): State<AppState>,
+    Path(vm_id): Path<String>,
+) -> Result<Json<Vec<Allocation>>, Problem> {
+    tracing::debug!("v2 VM allocations request - vm_id: {}", vm_id);
+
+    let allocations: Vec<Allocation> = state
+        .pools
+        .allocations_of(&vm_id)
+        .await
+        .into_iter()
+        .filter_map(|(pool, allocation)| Allocation::new(&pool, allocation))
+        .collect();
+    if allocations.is_empty() {
+        return Err(IpPoolError::IpNotFound.into());
+    }
+    Ok(Json(allocations))
+}
+
+// Release every address of a VM across pools
+async fn release_vm(
+    State(state): State<AppState>,
+    Path(vm_id): Path<String>,
+    query: Result<Query<ReleaseQuery>, QueryRejection>,
+) -> Result<Json<ReleaseResponse>, Problem> {
+    let Query(query) = query?;
+    tracing::info!(
+        "v2 release request - vm_id: {}, fence_token: {:?}",
+        vm_id,
+        query.fence_token
+    );
+    require_fence(&state, query.fence_token)?;
+
+    let held = state.pools.allocations_of(&vm_id).await;
+    if held.is_empty() {
+        return Err(IpPoolError::IpNotFound.into());
+    }
+    if let Some(fence) = query.fence_token
+        && !held
+            .iter()
+            .any(|(_, allocation)| allocation.fence_token == Some(fence))
+    {
+        return Err(IpPoolError::StaleFenceToken.into());
+    }
+
+    let mut released = Vec::with_capacity(held.len());
+    for (pool_name, allocation) in held {
+        let pool = state.pools.get(&pool_name).await?;
+        let started = Instant::now();
+        let result = pool.release_ip_held_by(&allocation.ip, &vm_id).await;
+        state.perf.observe(Operation::Release, started, &result);
+        match result {
+            Ok(_) => {}
+            // Released concurrently (and maybe reallocated), nothing left to do
+            Err(IpPoolError::IpNotFound | IpPoolError::HeldByOtherVm) => continue,
+            Err(e) => return Err(e.into()),
+        }
+
+        state
+            .events
+            .emit(
+                EventKind::Released,
+                &pool_name,
+                &vm_id,
+                &allocation.ip,
+                None,
+            )
+            .await;
+        released.extend(Allocation::new(&pool_name, allocation));
+    }
+
+    tracing::info!("v2 released {} addresses of {}", released.len(), vm_id);
+    Ok(Json(ReleaseResponse { vm_id, released }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ippool::IpPool;
+
+    fn state() -> AppState {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        AppState::new(pool)
+    }
+
+    #[tokio::test]
+    async fn test_list_is_paginated_by_address() {
+        let state = state();
+        for i in 0..5 {
+            state.pool.allocate_ip(format!("vm-{}", i)).await.unwrap();
+        }
+
+        let page = |limit, cursor: Option<&str>| {
+            let state = state.clone();
+            let cursor = cursor.map(|cursor| cursor.parse().unwrap());
+            async move {
+                let query = PageQuery {
+                    pool: None,
+                    limit: Some(limit),
+                    cursor,
+                    q: None,
+                };
+                list_allocations(State(state), Ok(Query(query)))
+                    .await
+                    .unwrap()
+                    .0
+            }
+        };
+
+        let first = page(3, None).await;
+        let ips: Vec<String> = first.items.iter().map(|a| a.ip.to_string()).collect();
+        assert_eq!(ips, vec!["172.16.0.2", "172.16.0.3", "172.16.0.4"]);
+        assert_eq!(first.next_cursor, Some(Ipv4Addr::new(172, 16, 0, 4)));
+
+        let second = page(3, Some("172.16.0.4")).await;
+        assert_eq!(second.items.len(), 2);
+        assert_eq!(second.next_cursor, None);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_fields_are_rejected() {
+        use axum::extract::{FromRequest, Request};
+
+        let request = Request::builder()
+            .method("POST")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(axum::body::Body::from(r#"{"vmid": "vm-1"}"#))
+            .unwrap();
+        let rejection = Json::<AllocateRequest>::from_request(request, &())
+            .await
+            .unwrap_err();
+
+        let problem = Problem::from(rejection);
+        assert_eq!(problem.status, 422);
+        assert!(
+            problem.detail.contains("unknown field `vmid`"),
+            "{}",
+            problem.detail
+        );
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_problem_json() {
+        let response = Problem::from(IpPoolError::PoolNotFound).into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["type"], "urn:ippool:problem:pool_not_found");
+        assert_eq!(problem["title"], "Not Found");
+        assert_eq!(problem["status"], 404);
+    }
+}
@@ -0,0 +1,446 @@
+use crate::cluster::{self, Cluster};
+use crate::config::Config;
+use crate::deprecation::{self, V1Deprecation};
+use crate::ippool::IpPool;
+use crate::policy::ScriptPolicy;
+use crate::prefix6::Prefix6Pool;
+use crate::s3::S3Snapshots;
+use crate::shared::{self, SharedStorage};
+use crate::state::AppState;
+use crate::wireguard::WireGuardPool;
+use crate::{
+    api_v2, encoding, etag, grafana, graphql, handlers, k8s, maintenance, metrics, overload,
+    replication,
+};
+use axum::{
+    Router,
+    error_handling::HandleErrorLayer,
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{delete, get, post, put},
+};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UnixListener;
+use tower::ServiceBuilder;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower_http::LatencyUnit;
+use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer};
+use tracing::Level;
+
+// Optional parts of the API; their routes only exist when they are set
+#[derive(Default)]
+pub struct Extras {
+    pub wireguard: Option<WireGuardPool>,
+    pub prefix6: Option<Prefix6Pool>,
+    pub policy: Option<Arc<ScriptPolicy>>,
+    pub s3_snapshots: Option<S3Snapshots>,
+    pub cluster: Option<Cluster>,      // writes wait for a majority
+    pub shared: Option<SharedStorage>, // writes take the backend lock
+}
+
+// Limits and headers applied to every request
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub max_concurrent_requests: usize,
+    pub request_timeout: Duration,
+    pub max_body_bytes: usize,
+    pub max_import_bytes: usize, // whole files uploaded for import
+    pub api_v1_sunset: Option<String>,
+    #[cfg(feature = "fault-injection")]
+    pub faults: crate::faults::FaultConfig,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_concurrent_requests: 512,
+            request_timeout: Duration::from_secs(30),
+            max_body_bytes: 64 * 1024,
+            max_import_bytes: 16 * 1024 * 1024,
+            api_v1_sunset: None,
+            #[cfg(feature = "fault-injection")]
+            faults: Default::default(),
+        }
+    }
+}
+
+impl Limits {
+    pub fn from_config(config: &Config) -> Self {
+        Limits {
+            max_concurrent_requests: config.max_concurrent_requests.max(1),
+            request_timeout: Duration::from_secs(config.request_timeout_secs.max(1)),
+            max_body_bytes: config.max_body_bytes,
+            max_import_bytes: config.max_import_bytes,
+            api_v1_sunset: config.api_v1_sunset.clone(),
+            #[cfg(feature = "fault-injection")]
+            faults: config.faults.clone(),
+        }
+    }
+}
+
+// Bind a unix socket to serve the API on, replacing one left behind by a
+// previous run. A socket another server still answers on is left alone.
+pub fn bind_unix_socket(path: &str, mode: u32) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "exists and is not a socket",
+            ));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another server is listening on it",
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+// The whole API around a single pool with default settings and no storage,
+// for tests and for embedding the allocator in another process
+pub fn embedded(pool: IpPool) -> Router {
+    router(AppState::new(pool), Extras::default(), &Limits::default())
+}
+
+// Build the complete API: every route, the GraphQL endpoint and the
+// middleware stack, ready to be served or called in-process
+pub fn router(state: AppState, extras: Extras, limits: &Limits) -> Router {
+    let schema = graphql::build_schema(state.clone());
+    let maintenance = state.maintenance.clone();
+
+    let mut app = Router::new()
+        // Health check
+        .route("/api/v1/health", get(handlers::health_check))
+        // Dashboard
+        .route("/ui", get(handlers::dashboard))
+        // IP management - IMPORTANT: Specific routes first, wildcard routes last
+        .route("/api/v1/ip/allocate", post(handlers::allocate_ip))
+        .route(
+            "/api/v1/ip/allocations",
+            get(handlers::list_allocations).layer(middleware::from_fn(etag::conditional)),
+        )
+        .route(
+            "/api/v1/ip/allocations/{vm_id}",
+            put(handlers::put_allocation)
+                .patch(handlers::patch_allocation)
+                .delete(handlers::delete_allocation),
+        )
+        .route(
+            "/api/v1/ip/allocations/changes",
+            get(handlers::list_changes),
+        )
+        .route(
+            "/api/v1/ip/allocations/expiring",
+            get(handlers::list_expiring),
+        )
+        .route("/api/v1/ip/next-free", get(handlers::next_free))
+        .route(
+            "/api/v1/ip/conflicts",
+            get(handlers::list_conflicts).post(handlers::report_conflict),
+        )
+        .route("/api/v1/ip/leaks", get(handlers::list_leaks))
+        .route("/api/v1/ip/leaks/release", post(handlers::release_leaks))
+        .route(
+            "/api/v1/ip/stats",
+            get(handlers::get_stats).layer(middleware::from_fn(etag::conditional)),
+        )
+        .route("/api/v1/ip/stats/ranges", get(handlers::get_range_stats))
+        .route("/api/v1/ip/stats/forecast", get(handlers::get_forecast))
+        .route("/api/v1/ip/stats/check", get(handlers::check_stats))
+        .route("/api/v1/ip/release/{vm_id}", delete(handlers::release_ip))
+        .route(
+            "/api/v1/ip/release/{vm_id}/cancel",
+            post(handlers::cancel_release),
+        )
+        .route(
+            "/api/v1/ip/release-by-ip/{ip}",
+            delete(handlers::release_ip_by_address),
+        )
+        .route(
+            "/api/v1/ip/release-by-label",
+            delete(handlers::release_by_label),
+        )
+        .route("/api/v1/ip/{vm_id}", get(handlers::get_allocation))
+        .route("/api/v1/ip/{vm_id}/netplan", get(handlers::get_netplan))
+        .route(
+            "/api/v1/ip/{vm_id}/clone-to/{new_vm_id}",
+            post(handlers::clone_allocation),
+        )
+        // Admin
+        .route(
+            "/api/v1/admin/ip/allocate",
+            post(handlers::admin_allocate_ip),
+        )
+        .route(
+            "/api/v1/admin/ip/mark-external",
+            post(handlers::mark_external),
+        )
+        .route("/api/v1/admin/approvals", get(handlers::list_approvals))
+        .route(
+            "/api/v1/admin/approvals/{id}/approve",
+            post(handlers::approve_allocation),
+        )
+        .route(
+            "/api/v1/admin/approvals/{id}/reject",
+            post(handlers::reject_allocation),
+        )
+        .route("/api/v1/admin/pool/freeze", post(handlers::freeze_pool))
+        .route("/api/v1/admin/pool/unfreeze", post(handlers::unfreeze_pool))
+        .route("/api/v1/admin/migrate", post(handlers::migrate_pool))
+        .route("/api/v1/admin/pools/merge", post(handlers::merge_pools))
+        .route("/api/v1/admin/pools/split", post(handlers::split_pool))
+        .route("/api/v1/pools/templates", get(handlers::list_templates))
+        .route("/api/v1/pools/by-vlan/{vlan}", get(handlers::pools_by_vlan))
+        .route(
+            "/api/v1/pools/from-template",
+            post(handlers::create_pool_from_template),
+        )
+        .route(
+            "/api/v1/admin/leases/import",
+            post(handlers::import_leases).layer(DefaultBodyLimit::max(limits.max_import_bytes)),
+        )
+        .route(
+            "/api/v1/import/terraform",
+            post(handlers::import_terraform).layer(DefaultBodyLimit::max(limits.max_import_bytes)),
+        )
+        .route(
+            "/api/v1/admin/conflicts/{ip}",
+            delete(handlers::clear_conflict),
+        )
+        .route(
+            "/api/v1/admin/conflicts/{ip}/exclude",
+            post(handlers::exclude_conflict),
+        )
+        .route(
+            "/api/v1/admin/config/validate",
+            post(handlers::validate_config),
+        )
+        .route(
+            "/api/v1/admin/delegations",
+            get(handlers::list_delegations).post(handlers::create_delegation),
+        )
+        .route(
+            "/api/v1/admin/delegations/{team}",
+            delete(handlers::revoke_delegation),
+        )
+        .route(
+            "/api/v1/admin/snapshots",
+            get(handlers::list_snapshots).post(handlers::create_snapshot),
+        )
+        .route(
+            "/api/v1/admin/snapshots/restore/{name}",
+            post(handlers::restore_snapshot),
+        )
+        .route(
+            "/api/v1/admin/snapshots/diff",
+            post(handlers::diff_snapshots),
+        )
+        .route(
+            "/api/v1/admin/sweep",
+            get(handlers::get_sweep_reports).post(handlers::run_sweep),
+        )
+        .route(
+            "/api/v1/admin/sweep/unknown",
+            get(handlers::list_unknown_hosts),
+        )
+        .route(
+            "/api/v1/admin/sweep/unknown/{ip}",
+            post(handlers::adopt_unknown_host),
+        )
+        .route(
+            "/api/v1/admin/consistency",
+            get(handlers::check_consistency),
+        )
+        .route(
+            "/api/v1/admin/consistency/repair",
+            post(handlers::repair_consistency),
+        )
+        .route("/api/v1/admin/maintenance", get(handlers::get_maintenance))
+        .route(
+            "/api/v1/admin/maintenance/enable",
+            post(handlers::enable_maintenance),
+        )
+        .route(
+            "/api/v1/admin/maintenance/disable",
+            post(handlers::disable_maintenance),
+        )
+        // Events
+        .route("/api/v1/events", get(handlers::list_events))
+        .route("/api/v1/events/replay", get(handlers::replay_events))
+        .route("/api/v1/insights", get(handlers::get_insights))
+        .route("/api/v1/reports", get(handlers::get_report))
+        .route(replication::STREAM_PATH, get(handlers::replication_stream))
+        .route("/api/v1/webhooks", get(handlers::list_webhooks))
+        .route(
+            "/api/v1/webhooks/{id}/deliveries",
+            get(handlers::list_webhook_deliveries),
+        )
+        .route(
+            "/api/v1/webhooks/{id}/dead-letters",
+            get(handlers::list_dead_letters),
+        )
+        .route(
+            "/api/v1/webhooks/{id}/dead-letters/redrive",
+            post(handlers::redrive_dead_letters),
+        )
+        .route("/metrics", get(metrics::get_metrics))
+        .route("/api/v1/export/targets", get(handlers::export_targets))
+        .route("/api/v1/export/dhcp", get(handlers::export_dhcp))
+        .route(
+            "/api/v1/export/static-mappings",
+            get(handlers::export_static_mappings),
+        )
+        // Grafana SimpleJSON datasource
+        .route("/api/v1/grafana", get(grafana::test_datasource))
+        .route("/api/v1/grafana/search", post(grafana::search))
+        .route("/api/v1/grafana/query", post(grafana::query))
+        // Debug
+        .route("/api/v1/debug/perf", get(handlers::get_perf))
+        // v2 API
+        .merge(api_v2::router())
+        // Kubernetes operator
+        .merge(k8s::router())
+        .with_state(state);
+
+    // GraphQL API (queries, mutations and subscriptions)
+    let graphql_routes = Router::new()
+        .route(
+            "/graphql",
+            get(graphql::graphiql).post(graphql::graphql_handler),
+        )
+        .route("/graphql/ws", get(graphql::graphql_ws))
+        .with_state(schema);
+    app = app.merge(graphql_routes);
+
+    // WireGuard routes only exist when the mode is enabled
+    if let Some(wg) = extras.wireguard {
+        let wg_routes = Router::new()
+            .route(
+                "/api/v1/wireguard/peers",
+                get(handlers::list_wireguard_peers).post(handlers::add_wireguard_peer),
+            )
+            .route(
+                "/api/v1/wireguard/export",
+                get(handlers::export_wireguard_config),
+            )
+            .route(
+                "/api/v1/wireguard/peers/{public_key}",
+                get(handlers::get_wireguard_peer).delete(handlers::remove_wireguard_peer),
+            )
+            .with_state(wg);
+        app = app.merge(wg_routes);
+    }
+
+    // IPv6 prefix delegation routes only exist when it is enabled
+    if let Some(prefix6) = extras.prefix6 {
+        let prefix6_routes = Router::new()
+            .route("/api/v1/prefix6", get(handlers::list_prefixes6))
+            .route("/api/v1/prefix6/allocate", post(handlers::allocate_prefix6))
+            .route("/api/v1/prefix6/stats", get(handlers::get_prefix6_stats))
+            .route(
+                "/api/v1/prefix6/release/{vm_id}",
+                delete(handlers::release_prefix6),
+            )
+            .route("/api/v1/prefix6/{vm_id}", get(handlers::get_prefix6))
+            .with_state(prefix6);
+        app = app.merge(prefix6_routes);
+    }
+
+    if let Some(policy) = extras.policy {
+        let policy_routes = Router::new()
+            .route("/api/v1/admin/policy/reload", post(handlers::reload_policy))
+            .with_state(policy);
+        app = app.merge(policy_routes);
+    }
+
+    if let Some(snapshots) = extras.s3_snapshots {
+        let s3_routes = Router::new()
+            .route("/api/v1/admin/s3-snapshot", post(handlers::upload_snapshot))
+            .with_state(snapshots);
+        app = app.merge(s3_routes);
+    }
+
+    // Test builds can inject latency, failures and exhaustion
+    #[cfg(feature = "fault-injection")]
+    let app = {
+        tracing::warn!("💥 Fault injection enabled: {:?}", limits.faults);
+        let faults = crate::faults::Faults::new(limits.faults.clone());
+        app.merge(crate::faults::router(faults.clone()))
+            .layer(middleware::from_fn_with_state(
+                faults,
+                crate::faults::inject,
+            ))
+    };
+
+    // Cluster status and consensus RPCs; writes on the leader are answered
+    // once a majority has them
+    let app = match extras.cluster {
+        Some(cluster) => {
+            app.merge(cluster::router(cluster.clone()))
+                .layer(middleware::from_fn_with_state(
+                    cluster,
+                    cluster::commit_writes,
+                ))
+        }
+        None => app,
+    };
+
+    // Writes through a shared backend take turns with other instances
+    let app = match extras.shared {
+        Some(shared) => app.layer(middleware::from_fn_with_state(
+            shared,
+            shared::serialize_writes,
+        )),
+        None => app,
+    };
+
+    // Writes are refused while in maintenance mode, on replicas and on
+    // cluster followers
+    let app = app.layer(middleware::from_fn_with_state(
+        maintenance,
+        maintenance::enforce,
+    ));
+
+    // Shed load beyond the concurrency limit and cut off slow requests,
+    // both answered with 503 + Retry-After. The layer is applied to every
+    // route, so the limit's semaphore is shared to count them all together.
+    let app = app.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(overload::handle_overload))
+            .load_shed()
+            .layer(GlobalConcurrencyLimitLayer::new(
+                limits.max_concurrent_requests.max(1),
+            ))
+            .timeout(limits.request_timeout),
+    );
+
+    // Larger request bodies are rejected with 413
+    let app = app.layer(DefaultBodyLimit::max(limits.max_body_bytes));
+
+    // v1 responses announce the deprecation in favour of v2
+    let v1_deprecation = V1Deprecation::new(limits.api_v1_sunset.as_deref());
+    let app = app.layer(middleware::from_fn_with_state(
+        v1_deprecation,
+        deprecation::mark_v1,
+    ));
+
+    // JSON responses can be served as MessagePack or CBOR on request
+    app.layer(middleware::from_fn(encoding::negotiate)).layer(
+        TraceLayer::new_for_http()
+            .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+            .on_response(
+                DefaultOnResponse::new()
+                    .level(Level::INFO)
+                    .latency_unit(LatencyUnit::Millis),
+            ),
+    )
+}
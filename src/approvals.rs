@@ -0,0 +1,176 @@
+use crate::delegations::API_KEY_HEADER;
+use crate::handlers::AllocateIpRequest;
+use crate::insights::CALLER_HEADER;
+use crate::ippool::PRIMARY;
+use axum::http::HeaderMap;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+
+// An allocation waiting for an admin to approve it
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingAllocation {
+    pub id: u64,
+    pub pool: String,
+    pub vm_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    pub purpose: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    pub requested_by: String, // team of the API key, else the X-Caller header
+    pub requested_at: u64,    // unix seconds
+    #[serde(skip)]
+    pub request: AllocateIpRequest, // made as asked once approved
+    #[serde(skip)]
+    pub headers: HeaderMap, // API key and caller of the request
+}
+
+impl PendingAllocation {
+    fn same_slot(&self, pool: &str, request: &AllocateIpRequest) -> bool {
+        self.pool == pool
+            && self.vm_id == request.vm_id
+            && self.interface == request.interface
+            && self.purpose == request.purpose.as_deref().unwrap_or(PRIMARY)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    last_id: u64,
+    pending: BTreeMap<u64, PendingAllocation>,
+}
+
+// Allocations of new addresses in pools under change management, held
+// until an admin approves or rejects them. The queue lives in memory:
+// requests still pending at a restart have to be made again.
+#[derive(Debug, Clone, Default)]
+pub struct ApprovalQueue {
+    pools: Arc<BTreeSet<String>>,
+    queue: Arc<Mutex<Queue>>,
+}
+
+impl ApprovalQueue {
+    pub fn new(pools: BTreeSet<String>) -> Self {
+        ApprovalQueue {
+            pools: Arc::new(pools),
+            queue: Arc::default(),
+        }
+    }
+
+    // Whether new allocations in the pool need approval
+    pub fn required(&self, pool: &str) -> bool {
+        self.pools.contains(pool)
+    }
+
+    // Queue an allocation. Asking again for the same address slot while it
+    // waits gives the request already queued.
+    pub fn submit(
+        &self,
+        pool: &str,
+        request: AllocateIpRequest,
+        headers: &HeaderMap,
+        requested_by: String,
+        now: u64,
+    ) -> PendingAllocation {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(pending) = (queue.pending.values()).find(|p| p.same_slot(pool, &request)) {
+            return pending.clone();
+        }
+
+        let mut kept = HeaderMap::new();
+        for name in [API_KEY_HEADER, CALLER_HEADER] {
+            if let Some(value) = headers.get(name) {
+                kept.insert(name, value.clone());
+            }
+        }
+        queue.last_id += 1;
+        let pending = PendingAllocation {
+            id: queue.last_id,
+            pool: pool.to_string(),
+            vm_id: request.vm_id.clone(),
+            interface: request.interface.clone(),
+            purpose: (request.purpose.clone()).unwrap_or_else(|| PRIMARY.to_string()),
+            owner: request.owner.clone(),
+            requested_by,
+            requested_at: now,
+            request,
+            headers: kept,
+        };
+        queue.pending.insert(pending.id, pending.clone());
+        pending
+    }
+
+    // Pending allocations, oldest first, of one pool or all of them
+    pub fn list(&self, pool: Option<&str>) -> Vec<PendingAllocation> {
+        let queue = self.queue.lock().unwrap();
+        (queue.pending.values())
+            .filter(|pending| pool.is_none_or(|pool| pending.pool == pool))
+            .cloned()
+            .collect()
+    }
+
+    // Take a request off the queue to approve or reject it
+    pub fn take(&self, id: u64) -> Option<PendingAllocation> {
+        self.queue.lock().unwrap().pending.remove(&id)
+    }
+
+    // Put back a request whose approval failed, e.g. on an exhausted pool
+    pub fn restore(&self, pending: PendingAllocation) {
+        self.queue
+            .lock()
+            .unwrap()
+            .pending
+            .insert(pending.id, pending);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(vm_id: &str, purpose: Option<&str>) -> AllocateIpRequest {
+        serde_json::from_value(serde_json::json!({"vm_id": vm_id, "purpose": purpose})).unwrap()
+    }
+
+    #[test]
+    fn test_approval_queue() {
+        let queue = ApprovalQueue::new(BTreeSet::from(["prod".to_string()]));
+        assert!(queue.required("prod"));
+        assert!(!queue.required("default"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(API_KEY_HEADER, "key".parse().unwrap());
+        headers.insert("authorization", "secret".parse().unwrap());
+        let first = queue.submit("prod", request("vm-1", None), &headers, "ops".into(), 10);
+        assert_eq!((first.id, first.purpose.as_str()), (1, PRIMARY));
+        // Only what the approved allocation needs is kept of the headers
+        assert_eq!(first.headers.len(), 1);
+
+        // The same slot asked for again is the same request
+        let again = queue.submit(
+            "prod",
+            request("vm-1", Some(PRIMARY)),
+            &headers,
+            "ops".into(),
+            20,
+        );
+        assert_eq!((again.id, again.requested_at), (1, 10));
+        let floating = queue.submit(
+            "prod",
+            request("vm-1", Some("floating")),
+            &headers,
+            "ops".into(),
+            30,
+        );
+        assert_eq!(floating.id, 2);
+        assert_eq!(queue.list(Some("prod")).len(), 2);
+        assert!(queue.list(Some("default")).is_empty());
+
+        let taken = queue.take(1).unwrap();
+        assert!(queue.take(1).is_none());
+        queue.restore(taken);
+        let ids: Vec<u64> = queue.list(None).iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+    }
+}
@@ -0,0 +1,147 @@
+use crate::events::{Event, EventBus};
+use crate::health::{HealthRegistry, Status};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tokio::sync::broadcast::error::RecvError;
+
+// Audit trail as JSON lines in a local file. When the file would grow past
+// `max_bytes` it is rotated to `<path>.1`, older files shift up by one and
+// only `retain` rotated files are kept.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    retain: usize,
+    file: File,
+    size: u64,
+}
+
+impl AuditLog {
+    pub fn open(path: PathBuf, max_bytes: u64, retain: usize) -> std::io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(AuditLog {
+            path,
+            max_bytes: max_bytes.max(1),
+            retain,
+            file,
+            size,
+        })
+    }
+
+    pub fn write(&mut self, event: &Event) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        self.file.write_all(&line)?;
+        self.file.flush()?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.retain == 0 {
+            self.file = File::create(&self.path)?;
+            self.size = 0;
+            return Ok(());
+        }
+
+        // Shift <path>.N-1 -> <path>.N, dropping the oldest
+        remove_if_exists(&self.rotated(self.retain))?;
+        for index in (1..self.retain).rev() {
+            let from = self.rotated(index);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated(index + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated(1))?;
+
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    // Append every emitted event until the bus goes away
+    pub async fn run(mut self, events: EventBus, health: HealthRegistry) {
+        health.register("audit_log");
+        let mut receiver = events.subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(event) => match self.write(&event) {
+                    Ok(()) => health.success("audit_log"),
+                    Err(e) => {
+                        tracing::error!("Failed to write audit event {}: {}", event.id, e);
+                        health.failure("audit_log", Status::Degraded, e);
+                    }
+                },
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("Audit log fell behind, {} events not written", missed);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventKind;
+
+    #[tokio::test]
+    async fn test_rotation_keeps_retained_files() {
+        let dir = std::env::temp_dir().join(format!("ippool-audit-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("audit.log");
+
+        let events = EventBus::new();
+        let event = events
+            .emit(EventKind::Allocated, "default", "vm-1", "172.16.0.2", None)
+            .await;
+        let line_len = serde_json::to_vec(&event).unwrap().len() as u64 + 1;
+
+        // Two events per file, two rotated files kept
+        let mut log = AuditLog::open(path.clone(), line_len * 2, 2).unwrap();
+        for _ in 0..7 {
+            log.write(&event).unwrap();
+        }
+
+        let lines = |path: &Path| std::fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&log.rotated(1)), 2);
+        assert_eq!(lines(&log.rotated(2)), 2);
+        assert!(!log.rotated(3).exists());
+
+        // Reopening continues the current file
+        let log = AuditLog::open(path.clone(), line_len * 2, 2).unwrap();
+        assert_eq!(log.size, line_len);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,432 @@
+// Load generator for a running ippool: drives the HTTP API with a mix of
+// allocations, releases and lookups from many concurrent clients and
+// reports throughput and latency percentiles per operation.
+//
+//   ippool-bench --url http://localhost:8090 --concurrency 64 --duration 30 \
+//       --mix allocate=60,release=30,get=10 --target 1000
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "Usage: ippool-bench [options]
+
+  --url <url>            ippool to drive (default http://localhost:8090)
+  --pool <name>          pool to allocate from (default: the default pool)
+  --concurrency <n>      concurrent clients (default 32)
+  --duration <secs>      how long to run (default 10)
+  --mix <op=weight,...>  operation mix of allocate, release and get
+                         (default allocate=60,release=30,get=10)
+  --target <n>           allocations per second to reach; exit 1 if missed
+  --keep                 leave the allocations in place afterwards
+  --json                 print the report as JSON";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Op {
+    Allocate,
+    Release,
+    Get,
+}
+
+#[derive(Debug, Clone)]
+struct Options {
+    url: String,
+    pool: Option<String>,
+    concurrency: usize,
+    duration: Duration,
+    mix: Vec<(Op, u32)>,
+    target: Option<f64>,
+    keep: bool,
+    json: bool,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            url: "http://localhost:8090".to_string(),
+            pool: None,
+            concurrency: 32,
+            duration: Duration::from_secs(10),
+            mix: parse_mix("allocate=60,release=30,get=10")?,
+            target: None,
+            keep: false,
+            json: false,
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--url" => options.url = value()?.trim_end_matches('/').to_string(),
+                "--pool" => options.pool = Some(value()?),
+                "--concurrency" => options.concurrency = number::<usize>(&value()?)?.max(1),
+                "--duration" => options.duration = Duration::from_secs(number(&value()?)?),
+                "--mix" => options.mix = parse_mix(&value()?)?,
+                "--target" => options.target = Some(number(&value()?)?),
+                "--keep" => options.keep = true,
+                "--json" => options.json = true,
+                "-h" | "--help" => return Err(String::new()),
+                _ => return Err(format!("unknown option {}", arg)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} is not a number", value))
+}
+
+// "allocate=60,release=30,get=10"
+fn parse_mix(value: &str) -> Result<Vec<(Op, u32)>, String> {
+    let mut mix = Vec::new();
+    for part in value.split(',') {
+        let (op, weight) = part
+            .split_once('=')
+            .ok_or(format!("{} is not op=weight", part))?;
+        let op = match op.trim() {
+            "allocate" => Op::Allocate,
+            "release" => Op::Release,
+            "get" => Op::Get,
+            other => return Err(format!("unknown operation {}", other)),
+        };
+        mix.push((op, number(weight.trim())?));
+    }
+    if mix.iter().all(|(_, weight)| *weight == 0) {
+        return Err("the mix needs at least one operation".to_string());
+    }
+    Ok(mix)
+}
+
+// xorshift64, enough to pick operations without a dependency
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn pick(&mut self, mix: &[(Op, u32)]) -> Op {
+        let total: u64 = mix.iter().map(|(_, weight)| u64::from(*weight)).sum();
+        let mut roll = self.next() % total;
+        for (op, weight) in mix {
+            if roll < u64::from(*weight) {
+                return *op;
+            }
+            roll -= u64::from(*weight);
+        }
+        mix[0].0
+    }
+}
+
+// What one client saw
+#[derive(Debug, Default)]
+struct Samples {
+    latencies: BTreeMap<Op, Vec<Duration>>,
+    errors: BTreeMap<Op, BTreeMap<String, u64>>, // by status, or "network"
+}
+
+impl Samples {
+    fn record(&mut self, op: Op, started: Instant, result: Result<(), String>) {
+        self.latencies
+            .entry(op)
+            .or_default()
+            .push(started.elapsed());
+        if let Err(kind) = result {
+            *self.errors.entry(op).or_default().entry(kind).or_default() += 1;
+        }
+    }
+
+    fn merge(&mut self, other: Samples) {
+        for (op, latencies) in other.latencies {
+            self.latencies.entry(op).or_default().extend(latencies);
+        }
+        for (op, errors) in other.errors {
+            for (kind, count) in errors {
+                *self.errors.entry(op).or_default().entry(kind).or_default() += count;
+            }
+        }
+    }
+}
+
+struct Client {
+    http: reqwest::Client,
+    options: Options,
+    id: usize,
+    held: Vec<String>, // VMs this client allocated and has not released
+    next_vm: u64,
+}
+
+impl Client {
+    async fn allocate(&mut self) -> Result<(), String> {
+        let vm_id = format!("bench-{}-{}-{}", std::process::id(), self.id, self.next_vm);
+        self.next_vm += 1;
+        let mut body = serde_json::json!({ "vm_id": vm_id });
+        if let Some(pool) = &self.options.pool {
+            body["pool"] = pool.clone().into();
+        }
+        let url = format!("{}/api/v1/ip/allocate", self.options.url);
+        check(self.http.post(url).json(&body).send().await)?;
+        self.held.push(vm_id);
+        Ok(())
+    }
+
+    async fn release(&self, vm_id: &str) -> Result<(), String> {
+        let url = format!("{}/api/v1/ip/release/{}", self.options.url, vm_id);
+        check(self.http.delete(url).send().await)
+    }
+
+    async fn get(&self, vm_id: &str) -> Result<(), String> {
+        let url = format!("{}/api/v1/ip/{}", self.options.url, vm_id);
+        check(self.http.get(url).send().await)
+    }
+
+    async fn run(mut self, deadline: Instant) -> (Samples, Vec<String>) {
+        let seed = (std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH))
+            .map_or(0, |d| d.as_nanos() as u64);
+        let mut rng = Rng((self.id as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ seed | 1);
+        let mut samples = Samples::default();
+        while Instant::now() < deadline {
+            // Releases and lookups need an address; allocate one first
+            let op = match rng.pick(&self.options.mix) {
+                Op::Release | Op::Get if self.held.is_empty() => Op::Allocate,
+                op => op,
+            };
+            let started = Instant::now();
+            let result = match op {
+                Op::Allocate => self.allocate().await,
+                Op::Release => {
+                    let index = (rng.next() % self.held.len() as u64) as usize;
+                    let vm_id = self.held.swap_remove(index);
+                    self.release(&vm_id).await
+                }
+                Op::Get => {
+                    let index = (rng.next() % self.held.len() as u64) as usize;
+                    self.get(&self.held[index]).await
+                }
+            };
+            samples.record(op, started, result);
+        }
+        (samples, self.held)
+    }
+}
+
+fn check(response: reqwest::Result<reqwest::Response>) -> Result<(), String> {
+    match response {
+        Ok(response) if response.status().is_success() => Ok(()),
+        Ok(response) => Err(response.status().as_u16().to_string()),
+        Err(_) => Err("network".to_string()),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct OpReport {
+    requests: usize,
+    errors: BTreeMap<String, u64>,
+    per_second: f64,
+    p50_ms: f64,
+    p90_ms: f64,
+    p99_ms: f64,
+    max_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    url: String,
+    concurrency: usize,
+    seconds: f64,
+    requests: usize,
+    per_second: f64,
+    allocations_per_second: f64, // successful ones
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_met: Option<bool>,
+    operations: BTreeMap<Op, OpReport>,
+}
+
+// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn report(options: &Options, samples: Samples, elapsed: Duration) -> Report {
+    let seconds = elapsed.as_secs_f64();
+    let mut operations = BTreeMap::new();
+    for (op, mut latencies) in samples.latencies {
+        latencies.sort();
+        let errors = samples.errors.get(&op).cloned().unwrap_or_default();
+        operations.insert(
+            op,
+            OpReport {
+                requests: latencies.len(),
+                per_second: latencies.len() as f64 / seconds,
+                p50_ms: millis(percentile(&latencies, 50.0)),
+                p90_ms: millis(percentile(&latencies, 90.0)),
+                p99_ms: millis(percentile(&latencies, 99.0)),
+                max_ms: millis(latencies.last().copied().unwrap_or_default()),
+                errors,
+            },
+        );
+    }
+    let requests = operations.values().map(|op| op.requests).sum();
+    let allocations = operations.get(&Op::Allocate).map_or(0, |op| {
+        op.requests - op.errors.values().sum::<u64>() as usize
+    });
+    let allocations_per_second = allocations as f64 / seconds;
+    Report {
+        url: options.url.clone(),
+        concurrency: options.concurrency,
+        seconds,
+        requests,
+        per_second: requests as f64 / seconds,
+        allocations_per_second,
+        target_met: options
+            .target
+            .map(|target| allocations_per_second >= target),
+        operations,
+    }
+}
+
+fn print(report: &Report) {
+    println!(
+        "{} clients against {} for {:.1}s: {} requests, {:.0}/s",
+        report.concurrency, report.url, report.seconds, report.requests, report.per_second
+    );
+    println!();
+    println!(
+        "{:<10} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9}  errors",
+        "operation", "requests", "per sec", "p50 ms", "p90 ms", "p99 ms", "max ms"
+    );
+    for (op, stats) in &report.operations {
+        let errors: Vec<String> = (stats.errors.iter())
+            .map(|(kind, count)| format!("{}: {}", kind, count))
+            .collect();
+        println!(
+            "{:<10} {:>9} {:>9.0} {:>9.2} {:>9.2} {:>9.2} {:>9.2}  {}",
+            format!("{:?}", op).to_lowercase(),
+            stats.requests,
+            stats.per_second,
+            stats.p50_ms,
+            stats.p90_ms,
+            stats.p99_ms,
+            stats.max_ms,
+            if errors.is_empty() {
+                "-".to_string()
+            } else {
+                errors.join(", ")
+            }
+        );
+    }
+    println!();
+    print!(
+        "Successful allocations: {:.0}/s",
+        report.allocations_per_second
+    );
+    match report.target_met {
+        Some(true) => println!(" (target met)"),
+        Some(false) => println!(" (target missed)"),
+        None => println!(),
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("{}\n", e);
+            }
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let http = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .pool_max_idle_per_host(options.concurrency)
+        .build()
+        .expect("Failed to build HTTP client");
+
+    let started = Instant::now();
+    let deadline = started + options.duration;
+    let clients: Vec<_> = (0..options.concurrency)
+        .map(|id| {
+            let client = Client {
+                http: http.clone(),
+                options: options.clone(),
+                id,
+                held: Vec::new(),
+                next_vm: 0,
+            };
+            tokio::spawn(client.run(deadline))
+        })
+        .collect();
+
+    let mut samples = Samples::default();
+    let mut held = Vec::new();
+    for client in clients {
+        let (client_samples, client_held) = client.await.expect("client task panicked");
+        samples.merge(client_samples);
+        held.extend(client_held);
+    }
+    let report = report(&options, samples, started.elapsed());
+
+    // Hand the addresses back so runs can be repeated
+    if !options.keep {
+        let cleanup = Client {
+            http,
+            options: options.clone(),
+            id: 0,
+            held: Vec::new(),
+            next_vm: 0,
+        };
+        for vm_id in &held {
+            let _ = cleanup.release(vm_id).await;
+        }
+    }
+
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        print(&report);
+    }
+    match report.target_met {
+        Some(false) => ExitCode::FAILURE,
+        _ => ExitCode::SUCCESS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mix_and_percentiles() {
+        let mix = parse_mix("allocate=3, release=1").unwrap();
+        assert_eq!(mix, [(Op::Allocate, 3), (Op::Release, 1)]);
+        assert!(parse_mix("allocate=0").is_err());
+        assert!(parse_mix("delete=1").is_err());
+
+        let mut rng = Rng(42);
+        let allocations = (0..4000).filter(|_| rng.pick(&mix) == Op::Allocate).count();
+        assert!((2800..3200).contains(&allocations));
+
+        let sorted: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&sorted, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&[], 99.0), Duration::ZERO);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::storage::{NamedSnapshot, StateStore, StorageError, StorageFuture, StoredState};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,               // consecutive
+    open_until: Option<Instant>, // set once `failures` reaches the threshold
+}
+
+// Circuit breaker for the persistence backends. After `threshold`
+// consecutive failures writes are refused for `cooldown`; the first write
+// after that is the recovery probe, which either closes the breaker or
+// opens it again. Reads are always served from memory.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Arc<Mutex<BreakerState>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold: threshold.max(1),
+            cooldown,
+            state: Arc::default(),
+        }
+    }
+
+    // Refuse the call while the breaker is open
+    pub fn check(&self) -> Result<(), StorageError> {
+        let state = self.state.lock().unwrap();
+        match state.open_until {
+            Some(until) if Instant::now() < until => Err(StorageError::Unavailable(format!(
+                "circuit open after {} failures, retry in {}s",
+                state.failures,
+                until.saturating_duration_since(Instant::now()).as_secs() + 1
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn record<T>(&self, result: &Result<T, StorageError>) {
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(_) => {
+                if state.open_until.is_some() {
+                    tracing::info!("🔌 Storage circuit closed, backend recovered");
+                }
+                *state = BreakerState::default();
+            }
+            // The other writer's change is not a backend failure
+            Err(StorageError::Conflict) => {}
+            Err(e) => {
+                state.failures += 1;
+                if state.failures >= self.threshold {
+                    if state.open_until.is_none() {
+                        tracing::error!(
+                            "🔌 Storage circuit opened after {} failures: {}",
+                            state.failures,
+                            e
+                        );
+                    }
+                    state.open_until = Some(Instant::now() + self.cooldown);
+                }
+            }
+        }
+    }
+}
+
+// State store whose saves go through the breaker
+#[derive(Debug)]
+pub struct BreakerStore {
+    store: Arc<dyn StateStore>,
+    breaker: CircuitBreaker,
+}
+
+impl BreakerStore {
+    pub fn new(store: Arc<dyn StateStore>, breaker: CircuitBreaker) -> Self {
+        BreakerStore { store, breaker }
+    }
+}
+
+impl StateStore for BreakerStore {
+    fn name(&self) -> &'static str {
+        self.store.name()
+    }
+
+    fn load(&self) -> StorageFuture<'_, Option<StoredState>> {
+        self.store.load()
+    }
+
+    fn save<'a>(&'a self, state: &'a StoredState) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.breaker.check()?;
+            let result = self.store.save(state).await;
+            self.breaker.record(&result);
+            result
+        })
+    }
+
+    fn save_snapshot<'a>(&'a self, snapshot: &'a NamedSnapshot) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.breaker.check()?;
+            let result = self.store.save_snapshot(snapshot).await;
+            self.breaker.record(&result);
+            result
+        })
+    }
+
+    fn load_snapshot<'a>(&'a self, name: &'a str) -> StorageFuture<'a, Option<NamedSnapshot>> {
+        self.store.load_snapshot(name)
+    }
+
+    fn list_snapshots(&self) -> StorageFuture<'_, Vec<NamedSnapshot>> {
+        self.store.list_snapshots()
+    }
+
+    fn try_lock(&self) -> StorageFuture<'_, bool> {
+        Box::pin(async move {
+            self.breaker.check()?;
+            let result = self.store.try_lock().await;
+            self.breaker.record(&result);
+            result
+        })
+    }
+
+    fn unlock(&self) -> StorageFuture<'_, ()> {
+        self.store.unlock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl CircuitBreaker {
+        fn is_open(&self) -> bool {
+            self.check().is_err()
+        }
+    }
+
+    fn failure() -> Result<(), StorageError> {
+        Err(StorageError::Unavailable("connection refused".to_string()))
+    }
+
+    #[test]
+    fn test_opens_after_threshold_and_recovers() {
+        let breaker = CircuitBreaker::new(3, Duration::from_millis(20));
+
+        breaker.record(&failure());
+        breaker.record(&failure());
+        assert!(!breaker.is_open());
+        breaker.record(&failure());
+        assert!(breaker.is_open());
+        assert!(matches!(breaker.check(), Err(StorageError::Unavailable(_))));
+
+        // After the cooldown one probe goes through; failing re-opens
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.check().is_ok());
+        breaker.record(&failure());
+        assert!(breaker.is_open());
+
+        std::thread::sleep(Duration::from_millis(30));
+        breaker.record(&Ok(()));
+        assert!(!breaker.is_open());
+        breaker.record(&failure());
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn test_conflicts_do_not_count() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+        breaker.record::<()>(&Err(StorageError::Conflict));
+        assert!(!breaker.is_open());
+    }
+}
@@ -0,0 +1,107 @@
+use crate::clock::{Clock, SystemClock};
+use crate::delegations::hash_key;
+use crate::ippool::IpPoolError;
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Token bucket per API key capping new allocations, e.g. 50 per hour, so a
+// runaway autoscaler cannot drain the pool. A key starts with `limit`
+// allocations and gets them back evenly over `window`. Only hashes of the
+// keys are kept.
+#[derive(Debug, Clone)]
+pub struct AllocationBudget {
+    limit: u32,
+    window: Duration,
+    buckets: Arc<DashMap<String, Bucket>>, // by key hash
+    clock: Arc<dyn Clock>,
+}
+
+impl AllocationBudget {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        AllocationBudget {
+            limit: limit.max(1),
+            window: window.max(Duration::from_secs(1)),
+            buckets: Arc::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        AllocationBudget { clock, ..self }
+    }
+
+    // Allocations regained per second
+    fn rate(&self) -> f64 {
+        f64::from(self.limit) / self.window.as_secs_f64()
+    }
+
+    // Charge one allocation to the key, or tell when the next one is allowed
+    pub fn take(&self, key: &str) -> Result<(), IpPoolError> {
+        let now = self.clock.now();
+        let mut bucket = self.buckets.entry(hash_key(key)).or_insert(Bucket {
+            tokens: f64::from(self.limit),
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate()).min(f64::from(self.limit));
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let wait = ((1.0 - bucket.tokens) / self.rate()).ceil() as u64;
+        Err(IpPoolError::BudgetExceeded(wait.max(1)))
+    }
+
+    // Give back the allocation of a request that did not allocate
+    pub fn refund(&self, key: &str) {
+        if let Some(mut bucket) = self.buckets.get_mut(&hash_key(key)) {
+            bucket.tokens = (bucket.tokens + 1.0).min(f64::from(self.limit));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn test_budget_refills_over_window() {
+        let clock = MockClock::new(0);
+        let budget =
+            AllocationBudget::new(3, Duration::from_secs(3600)).with_clock(Arc::new(clock.clone()));
+
+        for _ in 0..3 {
+            assert_eq!(budget.take("key-a"), Ok(()));
+        }
+        assert_eq!(budget.take("key-a"), Err(IpPoolError::BudgetExceeded(1200)));
+        // Every key has its own budget
+        assert_eq!(budget.take("key-b"), Ok(()));
+
+        // One allocation comes back every 20 minutes
+        clock.advance(Duration::from_secs(1000));
+        assert_eq!(budget.take("key-a"), Err(IpPoolError::BudgetExceeded(200)));
+        clock.advance(Duration::from_secs(200));
+        assert_eq!(budget.take("key-a"), Ok(()));
+
+        // Refunds never exceed the limit
+        budget.refund("key-a");
+        assert_eq!(budget.take("key-a"), Ok(()));
+        clock.advance(Duration::from_secs(86400));
+        budget.refund("key-a");
+        for _ in 0..3 {
+            assert_eq!(budget.take("key-a"), Ok(()));
+        }
+        assert!(budget.take("key-a").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use crate::events::{EventBus, unix_now};
+use crate::pools::PoolRegistry;
+use crate::webhooks::Webhook;
+use std::collections::HashSet;
+use tokio::sync::broadcast::error::RecvError;
+
+// "Expand capacity" webhook, separate from the event stream. Fires once when
+// a pool's available addresses drop below `watermark` and re-arms when the
+// pool is back at or above it.
+#[derive(Debug)]
+pub struct CapacityWebhook {
+    webhook: Webhook,
+    watermark: usize,
+    low: HashSet<String>, // pools below the watermark, already notified
+}
+
+impl CapacityWebhook {
+    pub fn new(webhook: Webhook, watermark: usize) -> Self {
+        CapacityWebhook {
+            webhook,
+            watermark,
+            low: HashSet::new(),
+        }
+    }
+
+    // Whether `pool` just dropped below the watermark
+    fn crossed(&mut self, pool: &str, available: usize) -> bool {
+        if available >= self.watermark {
+            self.low.remove(pool);
+            return false;
+        }
+        self.low.insert(pool.to_string())
+    }
+
+    // Deliver the payload, dead-lettered when the endpoint stays unreachable
+    async fn notify(&self, event_id: u64, pool: &str, stats: serde_json::Value) {
+        let body = serde_json::json!({
+            "event": "expand_capacity",
+            "pool": pool,
+            "watermark": self.watermark,
+            "timestamp": unix_now(),
+            "stats": stats,
+        });
+        self.webhook.deliver(Some(event_id), body).await;
+    }
+
+    // Check the affected pool after every event until the bus goes away
+    pub async fn run(mut self, pools: PoolRegistry, events: EventBus) {
+        let mut receiver = events.subscribe();
+        loop {
+            let (event_id, name) = match receiver.recv().await {
+                Ok(event) => (event.id, event.pool),
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("Capacity webhook fell behind, {} events skipped", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let Ok(pool) = pools.get(&name).await else {
+                continue;
+            };
+
+            let stats = pool.get_stats().await;
+            let available = stats["available"].as_u64().unwrap_or(0) as usize;
+            if !self.crossed(&name, available) {
+                continue;
+            }
+
+            tracing::warn!(
+                "📉 Pool {} below capacity watermark ({} < {}), calling expand webhook",
+                name,
+                available,
+                self.watermark
+            );
+            self.notify(event_id, &name, stats).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webhooks::CAPACITY_WEBHOOK;
+
+    #[test]
+    fn test_fires_once_per_crossing() {
+        let mut webhook = CapacityWebhook::new(
+            Webhook::new(CAPACITY_WEBHOOK, "http://127.0.0.1:9/".to_string(), None),
+            10,
+        );
+
+        assert!(!webhook.crossed("default", 10));
+        assert!(webhook.crossed("default", 9));
+        assert!(!webhook.crossed("default", 8));
+        // Other pools are tracked separately
+        assert!(webhook.crossed("lab", 0));
+
+        // Recovering re-arms the webhook
+        assert!(!webhook.crossed("default", 12));
+        assert!(webhook.crossed("default", 5));
+    }
+}
@@ -0,0 +1,150 @@
+use crate::ippool::{IpAllocation, IpPool, IpPoolError};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+
+// Versions of each pool that changes can be asked for since
+const RETAINED_VIEWS: usize = 16;
+
+// Attempts at listing a pool without a write slipping in between
+const LIST_ATTEMPTS: usize = 3;
+
+// A pool's allocations and reservations by address
+type View = BTreeMap<Ipv4Addr, IpAllocation>;
+
+// Views retained for a pool, oldest first, by the version they were at
+type Retained = VecDeque<(u64, Arc<View>)>;
+
+// Allocations that differ between a version handed out earlier and now
+#[derive(Debug, Serialize)]
+pub struct Changes {
+    pub pool: String,
+    pub version: u64, // pass as `since` next time
+    pub added: Vec<IpAllocation>,
+    pub modified: Vec<IpAllocation>,
+    pub removed: Vec<IpAllocation>, // as they were at `since`
+}
+
+// Recent versions of each pool as handed out to clients, so each can be
+// sent only what changed since. Versions are only known here once handed
+// out; older ones, and any from before a restart, need a resync.
+#[derive(Debug, Clone, Default)]
+pub struct ChangeFeed {
+    views: Arc<Mutex<HashMap<String, Retained>>>,
+}
+
+impl ChangeFeed {
+    // Changes to pool `name` since version `since`, 0 for everything
+    pub async fn since(
+        &self,
+        name: &str,
+        pool: &IpPool,
+        since: u64,
+    ) -> Result<Changes, IpPoolError> {
+        let (version, current) = view(pool).await;
+        let before = match since {
+            0 => Arc::default(),
+            since => self.get(name, since).ok_or(IpPoolError::CursorExpired)?,
+        };
+
+        let mut changes = Changes {
+            pool: name.to_string(),
+            version,
+            added: Vec::new(),
+            modified: Vec::new(),
+            removed: Vec::new(),
+        };
+        for (ip, allocation) in &current {
+            match before.get(ip) {
+                None => changes.added.push(allocation.clone()),
+                Some(old) if old != allocation => changes.modified.push(allocation.clone()),
+                Some(_) => {}
+            }
+        }
+        changes.removed = (before.iter())
+            .filter(|(ip, _)| !current.contains_key(ip))
+            .map(|(_, allocation)| allocation.clone())
+            .collect();
+
+        self.retain(name, version, current);
+        Ok(changes)
+    }
+
+    fn get(&self, name: &str, version: u64) -> Option<Arc<View>> {
+        let views = self.views.lock().unwrap();
+        let (_, view) = views.get(name)?.iter().find(|(v, _)| *v == version)?;
+        Some(view.clone())
+    }
+
+    // The first view handed out for a version is the one kept
+    fn retain(&self, name: &str, version: u64, view: View) {
+        let mut views = self.views.lock().unwrap();
+        let retained = views.entry(name.to_string()).or_default();
+        if retained.iter().any(|(v, _)| *v == version) {
+            return;
+        }
+        if retained.len() == RETAINED_VIEWS {
+            retained.pop_front();
+        }
+        retained.push_back((version, Arc::new(view)));
+    }
+}
+
+// Current view of the pool and the version it is at
+async fn view(pool: &IpPool) -> (u64, View) {
+    let mut attempt = 0;
+    loop {
+        let version = pool.version().await;
+        let mut allocations = pool.list_allocations().await;
+        allocations.extend(pool.list_reserved().await);
+        attempt += 1;
+        if pool.version().await == version || attempt == LIST_ATTEMPTS {
+            let view = allocations
+                .into_iter()
+                .filter_map(|allocation| Some((allocation.ip.parse().ok()?, allocation)))
+                .collect();
+            return (version, view);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_changes_since() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let feed = ChangeFeed::default();
+        let ip = |allocations: &[IpAllocation]| -> Vec<String> {
+            allocations.iter().map(|a| a.ip.clone()).collect()
+        };
+
+        let a = pool.allocate_ip("vm-a".to_string()).await.unwrap();
+        let b = pool.allocate_ip("vm-b".to_string()).await.unwrap();
+        let full = feed.since("default", &pool, 0).await.unwrap();
+        // The gateway is reserved
+        assert_eq!(ip(&full.added), vec!["172.16.0.1", &a, &b]);
+
+        let c = pool.allocate_ip("vm-c".to_string()).await.unwrap();
+        pool.release_ip("vm-a").await.unwrap();
+        let labels = BTreeMap::from([("env".to_string(), "prod".to_string())]);
+        pool.label(&b, labels).await.unwrap();
+        let delta = feed.since("default", &pool, full.version).await.unwrap();
+        assert!(delta.version > full.version);
+        assert_eq!(ip(&delta.added), vec![c]);
+        assert_eq!(ip(&delta.modified), vec![b]);
+        assert_eq!(ip(&delta.removed), vec![a]);
+
+        // Nothing changed since the latest version
+        let empty = feed.since("default", &pool, delta.version).await.unwrap();
+        assert_eq!(empty.version, delta.version);
+        assert!(empty.added.is_empty() && empty.modified.is_empty() && empty.removed.is_empty());
+
+        assert!(matches!(
+            feed.since("default", &pool, 999).await,
+            Err(IpPoolError::CursorExpired)
+        ));
+    }
+}
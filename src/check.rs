@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+// Nagios plugin states, in order of severity; the discriminant is the
+// plugin exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CheckStatus {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl CheckStatus {
+    pub fn label(self) -> &'static str {
+        match self {
+            CheckStatus::Ok => "OK",
+            CheckStatus::Warning => "WARNING",
+            CheckStatus::Critical => "CRITICAL",
+            CheckStatus::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+// Usage percentages at which a pool turns WARNING and CRITICAL
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Thresholds {
+    pub warn: f64,
+    pub crit: f64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            warn: 80.0,
+            crit: 95.0,
+        }
+    }
+}
+
+// Utilization of one pool as the check sees it
+#[derive(Debug, Clone)]
+pub struct PoolUsage {
+    pub name: String,
+    pub allocated: u64,
+    pub total: u64,
+    pub thresholds: Thresholds,
+}
+
+impl PoolUsage {
+    pub fn percent(&self) -> f64 {
+        if self.total == 0 {
+            return 100.0;
+        }
+        self.allocated as f64 / self.total as f64 * 100.0
+    }
+
+    pub fn status(&self) -> CheckStatus {
+        let usage = self.percent();
+        if usage >= self.thresholds.crit {
+            CheckStatus::Critical
+        } else if usage >= self.thresholds.warn {
+            CheckStatus::Warning
+        } else {
+            CheckStatus::Ok
+        }
+    }
+}
+
+// Status line in Nagios plugin output format: the worst pool (by its own
+// thresholds) decides the status, the summary names the pools at that
+// status (or the fullest one when all are OK), and perfdata carries every
+// pool's usage:
+//
+//     IPPOOL WARNING - lab 85.0% used (215/253) | 'default'=12.3%;80;95;0;100 'lab'=85.0%;80;95;0;100
+pub fn evaluate(pools: &[PoolUsage]) -> (CheckStatus, String) {
+    if let Some(pool) = pools
+        .iter()
+        .find(|pool| pool.thresholds.warn > pool.thresholds.crit)
+    {
+        return unknown(&format!(
+            "warning threshold {} is above critical threshold {} for {}",
+            pool.thresholds.warn, pool.thresholds.crit, pool.name
+        ));
+    }
+    if pools.is_empty() {
+        return unknown("no pools");
+    }
+
+    let status = pools
+        .iter()
+        .map(PoolUsage::status)
+        .max()
+        .unwrap_or(CheckStatus::Ok);
+
+    let mut shown: Vec<&PoolUsage> = pools
+        .iter()
+        .filter(|pool| status != CheckStatus::Ok && pool.status() == status)
+        .collect();
+    if shown.is_empty() {
+        shown.extend(
+            pools
+                .iter()
+                .max_by(|a, b| a.percent().total_cmp(&b.percent())),
+        );
+    }
+    let summary: Vec<String> = shown
+        .iter()
+        .map(|pool| {
+            format!(
+                "{} {:.1}% used ({}/{})",
+                pool.name,
+                pool.percent(),
+                pool.allocated,
+                pool.total
+            )
+        })
+        .collect();
+
+    let mut line = format!("IPPOOL {} - {} |", status.label(), summary.join(", "));
+    for pool in pools {
+        let _ = write!(
+            line,
+            " '{}'={:.1}%;{};{};0;100",
+            pool.name,
+            pool.percent(),
+            pool.thresholds.warn,
+            pool.thresholds.crit
+        );
+    }
+    (status, line)
+}
+
+fn unknown(reason: &str) -> (CheckStatus, String) {
+    (
+        CheckStatus::Unknown,
+        format!("IPPOOL {} - {}", CheckStatus::Unknown.label(), reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(name: &str, allocated: u64) -> PoolUsage {
+        PoolUsage {
+            name: name.to_string(),
+            allocated,
+            total: 200,
+            thresholds: Thresholds::default(),
+        }
+    }
+
+    #[test]
+    fn test_worst_pool_decides() {
+        let (status, line) = evaluate(&[usage("default", 20), usage("lab", 100)]);
+        assert_eq!(status, CheckStatus::Ok);
+        assert_eq!(
+            line,
+            "IPPOOL OK - lab 50.0% used (100/200) | 'default'=10.0%;80;95;0;100 'lab'=50.0%;80;95;0;100"
+        );
+
+        let pools = [usage("default", 170), usage("lab", 192), usage("dmz", 10)];
+        let (status, line) = evaluate(&pools);
+        assert_eq!(status, CheckStatus::Critical);
+        assert!(line.starts_with("IPPOOL CRITICAL - lab 96.0% used (192/200) |"));
+        assert_eq!(evaluate(&pools[..1]).0, CheckStatus::Warning);
+    }
+
+    #[test]
+    fn test_per_pool_thresholds() {
+        let mut lab = usage("lab", 100);
+        lab.thresholds = Thresholds {
+            warn: 40.0,
+            crit: 60.0,
+        };
+        let (status, line) = evaluate(&[usage("default", 100), lab]);
+        assert_eq!(status, CheckStatus::Warning);
+        assert_eq!(
+            line,
+            "IPPOOL WARNING - lab 50.0% used (100/200) | 'default'=50.0%;80;95;0;100 'lab'=50.0%;40;60;0;100"
+        );
+    }
+
+    #[test]
+    fn test_bad_thresholds_are_unknown() {
+        let mut pool = usage("default", 20);
+        pool.thresholds = Thresholds {
+            warn: 95.0,
+            crit: 80.0,
+        };
+        let (status, line) = evaluate(&[pool]);
+        assert_eq!(status, CheckStatus::Unknown);
+        assert_eq!(status as u8, 3);
+        assert!(line.starts_with("IPPOOL UNKNOWN - "));
+    }
+}
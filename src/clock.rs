@@ -0,0 +1,80 @@
+use crate::events::unix_now;
+use std::fmt::Debug;
+use std::time::Instant;
+
+// Time source for lease expiry and allocation history, so tests can
+// control time instead of sleeping
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant; // monotonic, for rates and history
+    fn unix_now(&self) -> u64; // wall clock seconds, for lease expiry
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_now(&self) -> u64 {
+        unix_now()
+    }
+}
+
+// Clock that only moves with tokio's time, so tests can drive it with
+// tokio::time::pause/advance, or advance it by hand
+#[cfg(any(test, feature = "test-utils"))]
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    origin: tokio::time::Instant,
+    unix_origin: u64,
+    offset: std::sync::Arc<std::sync::Mutex<std::time::Duration>>,
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl MockClock {
+    pub fn new(unix_origin: u64) -> Self {
+        MockClock {
+            origin: tokio::time::Instant::now(),
+            unix_origin,
+            offset: std::sync::Arc::default(),
+        }
+    }
+
+    pub fn advance(&self, by: std::time::Duration) {
+        *self.offset.lock().unwrap() += by;
+    }
+
+    fn elapsed(&self) -> std::time::Duration {
+        self.origin.elapsed() + *self.offset.lock().unwrap()
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.origin.into_std() + self.elapsed()
+    }
+
+    fn unix_now(&self) -> u64 {
+        self.unix_origin + self.elapsed().as_secs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mock_clock_follows_tokio_time() {
+        let clock = MockClock::new(1_000);
+        let start = clock.now();
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.unix_now(), 1_035);
+        assert_eq!(clock.now() - start, Duration::from_secs(35));
+    }
+}
@@ -0,0 +1,1021 @@
+use crate::api_v2::Problem;
+use crate::events::EventBus;
+use crate::handlers::JsonBody;
+use crate::health::{HealthRegistry, Status};
+use crate::ippool::IpPoolError;
+use crate::maintenance::Maintenance;
+use crate::pools::PoolRegistry;
+use crate::storage::StoredState;
+use axum::{
+    Json, Router,
+    extract::{DefaultBodyLimit, Request, State},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+pub const STATUS_PATH: &str = "/api/v1/cluster";
+const VOTE_PATH: &str = "/api/v1/cluster/vote";
+const APPEND_PATH: &str = "/api/v1/cluster/append";
+
+// The leader appends this often even without changes; followers that hear
+// nothing for a randomized 1-2 election timeouts stand for election
+const HEARTBEAT: Duration = Duration::from_millis(100);
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(1000);
+const TICK: Duration = Duration::from_millis(50);
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(5); // appends carrying the state
+// Appends carry the state of every pool, far beyond the API's body limit
+const MAX_APPEND_BYTES: usize = 256 * 1024 * 1024;
+// Writes not on a majority by then answer 503
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+// Place in the replicated history: the term of the leader that produced the
+// state and its version. The log is compacted down to the latest state, so
+// appends carry that state instead of entries. Ordered by term first, which
+// is how elections compare how up to date candidates are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Position {
+    pub term: u64,
+    pub index: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VoteRequest {
+    term: u64,
+    candidate: String,
+    last: Position, // of the candidate's state
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VoteResponse {
+    term: u64,
+    granted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AppendRequest {
+    term: u64,
+    leader: String,
+    position: Position,
+    // Only when the follower is not at `position` yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    state: Option<StoredState>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AppendResponse {
+    term: u64,
+    success: bool,
+    position: Position, // the follower's, after applying the append
+}
+
+// Term and vote, kept on disk: a node that forgot its vote could vote again
+// in the same term and let two leaders in
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<String>,
+}
+
+impl HardState {
+    fn load(path: &Path) -> std::io::Result<Self> {
+        match std::fs::read(path) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HardState::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Synced to disk before returning, through a temporary file renamed over
+    // the old one
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeStatus {
+    pub id: String,
+    pub url: String,
+    // Known on the leader only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<Position>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_contact_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClusterStatus {
+    pub node_id: String,
+    pub role: Role,
+    pub term: u64,
+    pub leader: Option<String>,
+    pub leader_url: Option<String>,
+    pub position: Position,
+    pub commit_index: u64, // highest index on a majority, as far as this node knows
+    pub nodes: Vec<NodeStatus>,
+}
+
+struct Raft {
+    term: u64,
+    voted_for: Option<String>,
+    role: Role,
+    leader: Option<String>,
+    position: Position,
+    fingerprint: Vec<(String, u64)>, // of the state at `position`, on the leader
+    commit: u64,
+    deadline: Instant,                  // of the election timeout
+    matched: HashMap<String, Position>, // leader: last position of each peer
+    contact: HashMap<String, Instant>,  // last answer from each peer, or append from the leader
+    saved: HardState,                   // as last written to the state file
+}
+
+struct Inner {
+    id: String,
+    nodes: Vec<(String, String)>, // (id, URL), this node included
+    pools: PoolRegistry,
+    maintenance: Maintenance,
+    health: HealthRegistry,
+    client: reqwest::Client,
+    raft: Mutex<Raft>,
+    state_file: PathBuf,           // term and vote
+    kick: watch::Sender<u64>,      // wakes the replication to peers early
+    committed: watch::Sender<u64>, // commit index on the leader
+}
+
+// Embedded Raft consensus between a fixed set of instances. One leader,
+// elected by majority vote, takes every write; followers refuse them with
+// its URL and replace their state with the leader's. A write is only
+// acknowledged once a majority has the state it produced, so it survives
+// the leader's failure: a new leader needs votes from a majority, and nodes
+// never vote for candidates with an older state than their own.
+//
+// Term and vote are synced to `state_file` before a vote is granted or a
+// newer term acknowledged, so a restarted node never votes twice in a term.
+// Its pool state is not trusted: it rejoins as a follower at position zero
+// and takes the current leader's state.
+#[derive(Clone)]
+pub struct Cluster {
+    inner: Arc<Inner>,
+}
+
+impl Cluster {
+    pub fn new(
+        id: String,
+        nodes: Vec<(String, String)>,
+        pools: PoolRegistry,
+        maintenance: Maintenance,
+        health: HealthRegistry,
+        state_file: PathBuf,
+    ) -> std::io::Result<Self> {
+        let saved = HardState::load(&state_file)?;
+        let cluster = Cluster {
+            inner: Arc::new(Inner {
+                id,
+                nodes,
+                pools,
+                maintenance,
+                health,
+                client: reqwest::Client::new(),
+                raft: Mutex::new(Raft {
+                    term: saved.term,
+                    voted_for: saved.voted_for.clone(),
+                    role: Role::Follower,
+                    leader: None,
+                    position: Position::default(),
+                    fingerprint: Vec::new(),
+                    commit: 0,
+                    deadline: election_deadline(),
+                    matched: HashMap::new(),
+                    contact: HashMap::new(),
+                    saved,
+                }),
+                state_file,
+                kick: watch::Sender::new(0),
+                committed: watch::Sender::new(0),
+            }),
+        };
+        cluster.update_gate(&cluster.inner.raft.lock().unwrap());
+        Ok(cluster)
+    }
+
+    // Write term and vote if they changed; false when that failed, and the
+    // change must then not be acted on
+    fn persist(&self, raft: &mut Raft) -> bool {
+        let state = HardState {
+            term: raft.term,
+            voted_for: raft.voted_for.clone(),
+        };
+        if state == raft.saved {
+            return true;
+        }
+        match state.save(&self.inner.state_file) {
+            Ok(()) => {
+                raft.saved = state;
+                true
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Failed to save cluster term and vote to {}: {}",
+                    self.inner.state_file.display(),
+                    e
+                );
+                self.inner.health.failure(
+                    "cluster",
+                    Status::Unhealthy,
+                    format!("state file: {}", e),
+                );
+                false
+            }
+        }
+    }
+
+    fn peers(&self) -> impl Iterator<Item = &(String, String)> {
+        self.inner
+            .nodes
+            .iter()
+            .filter(|(id, _)| *id != self.inner.id)
+    }
+
+    fn majority(&self) -> usize {
+        self.inner.nodes.len() / 2 + 1
+    }
+
+    fn url(&self, id: &str) -> Option<String> {
+        self.inner
+            .nodes
+            .iter()
+            .find(|(node, _)| node == id)
+            .map(|(_, url)| url.clone())
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.inner.raft.lock().unwrap().role == Role::Leader
+    }
+
+    // Writes go through on the leader only; followers point at the leader
+    fn update_gate(&self, raft: &Raft) {
+        let redirect = match raft.role {
+            Role::Leader => None,
+            _ => Some(IpPoolError::NotLeader(
+                raft.leader.as_deref().and_then(|id| self.url(id)),
+            )),
+        };
+        match &redirect {
+            Some(IpPoolError::NotLeader(None)) => {
+                self.inner
+                    .health
+                    .failure("cluster", Status::Unhealthy, "no leader elected")
+            }
+            _ => self.inner.health.success("cluster"),
+        }
+        self.inner.maintenance.redirect(redirect);
+    }
+
+    fn step_down(&self, raft: &mut Raft, term: u64, leader: Option<String>) {
+        if term > raft.term {
+            raft.term = term;
+            raft.voted_for = None;
+        }
+        if raft.role == Role::Leader {
+            tracing::warn!(
+                "Cluster node {} stepped down in term {}",
+                self.inner.id,
+                term
+            );
+        }
+        raft.role = Role::Follower;
+        raft.leader = leader;
+        raft.matched.clear();
+        self.update_gate(raft);
+    }
+
+    pub fn status(&self) -> ClusterStatus {
+        let raft = self.inner.raft.lock().unwrap();
+        let leading = raft.role == Role::Leader;
+        let nodes = self
+            .inner
+            .nodes
+            .iter()
+            .map(|(id, url)| {
+                let this = *id == self.inner.id;
+                NodeStatus {
+                    id: id.clone(),
+                    url: url.clone(),
+                    position: match this {
+                        true => Some(raft.position),
+                        false if leading => raft.matched.get(id).copied(),
+                        false => None,
+                    },
+                    last_contact_ms: raft
+                        .contact
+                        .get(id)
+                        .filter(|_| !this)
+                        .map(|at| at.elapsed().as_millis() as u64),
+                }
+            })
+            .collect();
+        ClusterStatus {
+            node_id: self.inner.id.clone(),
+            role: raft.role,
+            term: raft.term,
+            leader_url: raft.leader.as_deref().and_then(|id| self.url(id)),
+            leader: raft.leader.clone(),
+            position: raft.position,
+            commit_index: raft.commit,
+            nodes,
+        }
+    }
+
+    // Elections and replication to the other nodes, for good
+    pub async fn run(self) {
+        tracing::info!(
+            "🗳️ Cluster node {} of {}",
+            self.inner.id,
+            self.inner
+                .nodes
+                .iter()
+                .map(|(id, _)| id.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let mut tasks: Vec<_> = self
+            .peers()
+            .map(|(id, url)| tokio::spawn(self.clone().replicate_to(id.clone(), url.clone())))
+            .collect();
+        tasks.push(tokio::spawn(self.clone().run_elections()));
+        // Keep the peer tasks tied to this one, so aborting it stops the node
+        let _guard = AbortOnDrop(tasks);
+        std::future::pending::<()>().await;
+    }
+
+    async fn run_elections(self) {
+        let mut ticker = tokio::time::interval(TICK);
+        loop {
+            ticker.tick().await;
+            let (role, expired) = {
+                let raft = self.inner.raft.lock().unwrap();
+                (raft.role, Instant::now() >= raft.deadline)
+            };
+            match role {
+                Role::Leader => {
+                    self.advance().await;
+                    self.check_quorum();
+                }
+                _ if expired => self.elect().await,
+                _ => {}
+            }
+        }
+    }
+
+    // A leader cut off from the majority steps down, so clients get sent
+    // to whoever the majority elects instead of timing out on commits
+    fn check_quorum(&self) {
+        let mut raft = self.inner.raft.lock().unwrap();
+        if raft.role != Role::Leader || Instant::now() < raft.deadline {
+            return;
+        }
+        let reachable = 1 + raft
+            .contact
+            .values()
+            .filter(|at| at.elapsed() < ELECTION_TIMEOUT)
+            .count();
+        if reachable < self.majority() {
+            let term = raft.term;
+            self.step_down(&mut raft, term, None);
+            raft.deadline = election_deadline();
+        } else {
+            raft.deadline = Instant::now() + ELECTION_TIMEOUT;
+        }
+    }
+
+    async fn elect(&self) {
+        let request = {
+            let mut raft = self.inner.raft.lock().unwrap();
+            raft.term += 1;
+            raft.role = Role::Candidate;
+            raft.voted_for = Some(self.inner.id.clone());
+            raft.leader = None;
+            raft.deadline = election_deadline();
+            self.update_gate(&raft);
+            if !self.persist(&mut raft) {
+                return;
+            }
+            VoteRequest {
+                term: raft.term,
+                candidate: self.inner.id.clone(),
+                last: raft.position,
+            }
+        };
+        tracing::info!(
+            "Cluster node {} standing for election in term {}",
+            self.inner.id,
+            request.term
+        );
+
+        let votes = futures_util::future::join_all(
+            self.peers()
+                .map(|(_, url)| self.call::<VoteResponse>(url, VOTE_PATH, &request, RPC_TIMEOUT)),
+        )
+        .await;
+        let fingerprint = self.inner.pools.fingerprint().await;
+
+        let mut raft = self.inner.raft.lock().unwrap();
+        let mut granted = 1;
+        for vote in votes.into_iter().flatten() {
+            if vote.term > raft.term {
+                self.step_down(&mut raft, vote.term, None);
+                self.persist(&mut raft);
+                return;
+            }
+            granted += vote.granted as usize;
+        }
+        if raft.role != Role::Candidate || raft.term != request.term || granted < self.majority() {
+            return;
+        }
+
+        // A new version in the new term, so followers with leftovers of an
+        // old leader's uncommitted state take this one
+        raft.role = Role::Leader;
+        raft.leader = Some(self.inner.id.clone());
+        raft.position = Position {
+            term: raft.term,
+            index: raft.position.index + 1,
+        };
+        raft.fingerprint = fingerprint;
+        raft.matched.clear();
+        raft.deadline = Instant::now() + ELECTION_TIMEOUT;
+        self.update_gate(&raft);
+        self.advance_commit(&mut raft);
+        tracing::info!(
+            "👑 Cluster node {} elected leader in term {} ({} of {} votes)",
+            self.inner.id,
+            raft.term,
+            granted,
+            self.inner.nodes.len()
+        );
+        drop(raft);
+        self.inner.kick.send_modify(|kick| *kick += 1);
+    }
+
+    // New version of the state if it changed since the last one; returns
+    // the current index, None when not leading
+    async fn advance(&self) -> Option<u64> {
+        let fingerprint = self.inner.pools.fingerprint().await;
+        let index = {
+            let mut raft = self.inner.raft.lock().unwrap();
+            if raft.role != Role::Leader {
+                return None;
+            }
+            if raft.fingerprint == fingerprint {
+                return Some(raft.position.index);
+            }
+            raft.fingerprint = fingerprint;
+            raft.position = Position {
+                term: raft.term,
+                index: raft.position.index + 1,
+            };
+            self.advance_commit(&mut raft);
+            raft.position.index
+        };
+        self.inner.kick.send_modify(|kick| *kick += 1);
+        Some(index)
+    }
+
+    // Highest index of this term a majority has reached
+    fn advance_commit(&self, raft: &mut Raft) {
+        let mut indexes: Vec<u64> = std::iter::once(raft.position)
+            .chain(raft.matched.values().copied())
+            .filter(|position| position.term == raft.term)
+            .map(|position| position.index)
+            .collect();
+        indexes.sort_unstable_by(|a, b| b.cmp(a));
+        if let Some(&commit) = indexes.get(self.majority() - 1)
+            && commit > raft.commit
+        {
+            raft.commit = commit;
+            self.inner.committed.send_replace(commit);
+        }
+    }
+
+    // Wait until a majority has the current state. Called after every write
+    // on the leader; fails when this node lost the leadership meanwhile or
+    // the followers did not answer in time.
+    pub async fn commit(&self) -> Result<(), IpPoolError> {
+        let Some(index) = self.advance().await else {
+            let raft = self.inner.raft.lock().unwrap();
+            return Err(IpPoolError::NotLeader(
+                raft.leader.as_deref().and_then(|id| self.url(id)),
+            ));
+        };
+        let mut committed = self.inner.committed.subscribe();
+        match tokio::time::timeout(COMMIT_TIMEOUT, committed.wait_for(|&c| c >= index)).await {
+            Ok(Ok(_)) => Ok(()),
+            _ => Err(IpPoolError::StorageUnavailable(
+                "not replicated to a majority of the cluster".to_string(),
+            )),
+        }
+    }
+
+    // Appends to one peer while leading: a heartbeat every HEARTBEAT, and
+    // right away when the state changed
+    async fn replicate_to(self, peer: String, url: String) {
+        let mut kick = self.inner.kick.subscribe();
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(HEARTBEAT) => {}
+                _ = kick.changed() => {}
+            }
+
+            let (term, position, behind) = {
+                let raft = self.inner.raft.lock().unwrap();
+                if raft.role != Role::Leader {
+                    continue;
+                }
+                let behind = raft.matched.get(&peer) != Some(&raft.position);
+                (raft.term, raft.position, behind)
+            };
+            let state = match behind {
+                true => Some(self.inner.pools.snapshot().await),
+                false => None,
+            };
+            let timeout = match state {
+                Some(_) => TRANSFER_TIMEOUT,
+                None => RPC_TIMEOUT,
+            };
+            let request = AppendRequest {
+                term,
+                leader: self.inner.id.clone(),
+                position,
+                state,
+            };
+            let response = match self
+                .call::<AppendResponse>(&url, APPEND_PATH, &request, timeout)
+                .await
+            {
+                Ok(response) => response,
+                Err(e) => {
+                    tracing::debug!("Append to cluster node {} failed: {}", peer, e);
+                    continue;
+                }
+            };
+
+            let mut raft = self.inner.raft.lock().unwrap();
+            if response.term > raft.term {
+                self.step_down(&mut raft, response.term, None);
+                self.persist(&mut raft);
+                continue;
+            }
+            if raft.role != Role::Leader || raft.term != term || !response.success {
+                continue;
+            }
+            raft.contact.insert(peer.clone(), Instant::now());
+            raft.matched.insert(peer.clone(), response.position);
+            self.advance_commit(&mut raft);
+        }
+    }
+
+    fn vote(&self, request: VoteRequest) -> VoteResponse {
+        let mut raft = self.inner.raft.lock().unwrap();
+        if request.term > raft.term {
+            self.step_down(&mut raft, request.term, None);
+        }
+        let granted = request.term == raft.term
+            && raft
+                .voted_for
+                .as_ref()
+                .is_none_or(|voted| *voted == request.candidate)
+            && request.last >= raft.position;
+        if granted {
+            raft.voted_for = Some(request.candidate);
+        }
+        // Nothing is promised that a restart could forget
+        let granted = match self.persist(&mut raft) {
+            true => granted,
+            false => {
+                raft.voted_for = raft.saved.voted_for.clone();
+                false
+            }
+        };
+        if granted {
+            raft.deadline = election_deadline();
+        }
+        VoteResponse {
+            term: raft.term,
+            granted,
+        }
+    }
+
+    async fn append(&self, request: AppendRequest) -> AppendResponse {
+        {
+            let mut raft = self.inner.raft.lock().unwrap();
+            if request.term < raft.term {
+                return AppendResponse {
+                    term: raft.term,
+                    success: false,
+                    position: raft.position,
+                };
+            }
+            if request.term > raft.term
+                || raft.role != Role::Follower
+                || raft.leader.as_ref() != Some(&request.leader)
+            {
+                tracing::info!(
+                    "Cluster node {} following {} in term {}",
+                    self.inner.id,
+                    request.leader,
+                    request.term
+                );
+                self.step_down(&mut raft, request.term, Some(request.leader.clone()));
+            }
+            if !self.persist(&mut raft) {
+                return AppendResponse {
+                    term: raft.term,
+                    success: false,
+                    position: raft.position,
+                };
+            }
+            raft.deadline = election_deadline();
+            raft.contact.insert(request.leader.clone(), Instant::now());
+        }
+
+        let received = request.state.is_some();
+        if let Some(state) = request.state {
+            self.inner.pools.replicate(state).await;
+            tracing::debug!(
+                "Replicated state {}.{} from cluster leader {}",
+                request.position.term,
+                request.position.index,
+                request.leader
+            );
+        }
+
+        // The leader's position is only taken on with its state. A node that
+        // lost or never had that state answers with its own position, and
+        // the leader sends the state along next time.
+        let mut raft = self.inner.raft.lock().unwrap();
+        if raft.term == request.term && (received || raft.position == request.position) {
+            raft.position = request.position;
+            raft.commit = raft.commit.max(request.position.index);
+        }
+        AppendResponse {
+            term: raft.term,
+            success: raft.term == request.term,
+            position: raft.position,
+        }
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        path: &str,
+        body: &impl Serialize,
+        timeout: Duration,
+    ) -> Result<T, String> {
+        self.inner
+            .client
+            .post(format!("{}{}", url, path))
+            .timeout(timeout)
+            .json(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // Lease expiry runs on the leader only; followers see the reclaims in
+    // the leader's state
+    pub async fn run_lease_expiry(self, events: EventBus, interval: Duration) {
+        self.inner.health.register("lease_expiry");
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if self.is_leader() {
+                self.inner.pools.expire_leases(&events).await;
+            }
+            self.inner.health.success("lease_expiry");
+        }
+    }
+}
+
+struct AbortOnDrop(Vec<tokio::task::JoinHandle<()>>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+// Randomized, so nodes rarely stand for election at the same time
+fn election_deadline() -> Instant {
+    let jitter = RandomState::new().hash_one(Instant::now()) % ELECTION_TIMEOUT.as_millis() as u64;
+    Instant::now() + ELECTION_TIMEOUT + Duration::from_millis(jitter)
+}
+
+pub fn router(cluster: Cluster) -> Router {
+    Router::new()
+        .route(STATUS_PATH, get(get_status))
+        .route(VOTE_PATH, post(request_vote))
+        .route(
+            APPEND_PATH,
+            post(append_state).layer(DefaultBodyLimit::max(MAX_APPEND_BYTES)),
+        )
+        .with_state(cluster)
+}
+
+async fn get_status(State(cluster): State<Cluster>) -> Json<ClusterStatus> {
+    Json(cluster.status())
+}
+
+async fn request_vote(
+    State(cluster): State<Cluster>,
+    JsonBody(request): JsonBody<VoteRequest>,
+) -> Json<VoteResponse> {
+    Json(cluster.vote(request))
+}
+
+async fn append_state(
+    State(cluster): State<Cluster>,
+    JsonBody(request): JsonBody<AppendRequest>,
+) -> Json<AppendResponse> {
+    Json(cluster.append(request).await)
+}
+
+// Hold the response to a write on the leader until a majority has the
+// resulting state; answer 503 instead when that does not happen in time
+pub async fn commit_writes(
+    State(cluster): State<Cluster>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    let write = !matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) && !path.starts_with(STATUS_PATH);
+    let v2 = path.starts_with("/api/v2/");
+
+    let response = next.run(request).await;
+    if !write || !response.status().is_success() {
+        return response;
+    }
+    match cluster.commit().await {
+        Ok(()) => response,
+        Err(e) if v2 => Problem::from(e).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ippool::IpPool;
+    use std::collections::BTreeMap;
+    use tokio::net::TcpListener;
+
+    struct Node {
+        cluster: Cluster,
+        pools: PoolRegistry,
+        maintenance: Maintenance,
+        _tasks: AbortOnDrop, // stops the node when dropped
+        state_file: PathBuf,
+    }
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.state_file);
+        }
+    }
+
+    async fn start(count: usize) -> Vec<Node> {
+        let mut listeners = Vec::new();
+        let mut nodes = Vec::new();
+        for i in 1..=count {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            listeners.push(listener);
+            nodes.push((format!("n{}", i), url));
+        }
+
+        let mut started = Vec::new();
+        for (listener, (id, url)) in listeners.into_iter().zip(&nodes) {
+            let pools = PoolRegistry::new(IpPool::new(
+                "172.16.0".to_string(),
+                "172.16.0.1".to_string(),
+            ));
+            let maintenance = Maintenance::default();
+            let cluster = Cluster::new(
+                id.clone(),
+                nodes.clone(),
+                pools.clone(),
+                maintenance.clone(),
+                HealthRegistry::default(),
+                state_file(url),
+            )
+            .unwrap();
+            let state_file = state_file(url);
+            // Under the API's own body limit, as when served by app::router
+            let app = router(cluster.clone()).layer(DefaultBodyLimit::max(64 * 1024));
+            let server = tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+            let run = tokio::spawn(cluster.clone().run());
+            started.push(Node {
+                cluster,
+                pools,
+                maintenance,
+                _tasks: AbortOnDrop(vec![server, run]),
+                state_file,
+            });
+        }
+        started
+    }
+
+    // Per node, named after its unique URL
+    fn state_file(url: &str) -> PathBuf {
+        let port = url.rsplit(':').next().unwrap();
+        std::env::temp_dir().join(format!(
+            "ippool-cluster-{}-{}.json",
+            std::process::id(),
+            port
+        ))
+    }
+
+    // Index of the single leader among `nodes` once there is one
+    async fn leader(nodes: &[&Node]) -> usize {
+        for _ in 0..200 {
+            let leaders: Vec<usize> = (0..nodes.len())
+                .filter(|&i| nodes[i].cluster.is_leader())
+                .collect();
+            if let [leader] = leaders[..] {
+                return leader;
+            }
+            tokio::time::sleep(Duration::from_millis(25)).await;
+        }
+        panic!("no leader elected");
+    }
+
+    async fn allocated(node: &Node, vm_id: &str) -> Option<String> {
+        for _ in 0..100 {
+            let pool = node.pools.get("default").await.unwrap();
+            if let Ok(allocation) = pool.get_allocation(vm_id).await {
+                return Some(allocation.ip);
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn test_cluster_elects_leader_and_fails_over() {
+        let mut nodes = start(3).await;
+        let all: Vec<&Node> = nodes.iter().collect();
+        let first = leader(&all).await;
+        let leader_url = nodes[first].cluster.status().leader_url.unwrap();
+
+        // Writes on the leader are acknowledged once a majority has them
+        let pool = nodes[first].pools.get("default").await.unwrap();
+        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        nodes[first].cluster.commit().await.unwrap();
+        for node in &nodes {
+            assert_eq!(allocated(node, "vm-1").await, Some(ip.clone()));
+        }
+        assert_eq!(nodes[first].maintenance.check(), Ok(()));
+        let follower = (first + 1) % 3;
+        assert_eq!(
+            nodes[follower].maintenance.check(),
+            Err(IpPoolError::NotLeader(Some(leader_url.clone())))
+        );
+        let status = nodes[first].cluster.status();
+        assert_eq!(status.role, Role::Leader);
+        assert!(status.commit_index >= status.position.index);
+
+        // The survivors elect a new leader with everything committed
+        let term = status.term;
+        let stopped = nodes.remove(first);
+        drop(stopped);
+        let survivors: Vec<&Node> = nodes.iter().collect();
+        let second = leader(&survivors).await;
+        assert!(nodes[second].cluster.status().term > term);
+        assert_eq!(allocated(&nodes[second], "vm-1").await, Some(ip));
+
+        // Two of three are still a majority
+        let pool = nodes[second].pools.get("default").await.unwrap();
+        let ip = pool.allocate_ip("vm-2".to_string()).await.unwrap();
+        nodes[second].cluster.commit().await.unwrap();
+        assert_eq!(allocated(&nodes[1 - second], "vm-2").await, Some(ip));
+    }
+
+    #[tokio::test]
+    async fn test_replicates_state_above_body_limit() {
+        let nodes = start(3).await;
+        let all: Vec<&Node> = nodes.iter().collect();
+        let first = leader(&all).await;
+
+        let pool = nodes[first].pools.get("default").await.unwrap();
+        for n in 0..250 {
+            let ip = pool.allocate_ip(format!("vm-{:03}", n)).await.unwrap();
+            let labels = BTreeMap::from([
+                ("project".to_string(), "payments-platform".to_string()),
+                ("owner".to_string(), format!("team-{}@example.com", n)),
+                ("purpose".to_string(), "x".repeat(200)),
+            ]);
+            pool.label(&ip, labels).await.unwrap();
+        }
+        let state = nodes[first].pools.snapshot().await;
+        assert!(serde_json::to_vec(&state).unwrap().len() > 64 * 1024);
+
+        nodes[first].cluster.commit().await.unwrap();
+        for node in &nodes {
+            assert!(allocated(node, "vm-249").await.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restarted_node_keeps_vote_and_not_position() {
+        let path = state_file("restart:0");
+        let _ = std::fs::remove_file(&path);
+        let node = || {
+            let pools = PoolRegistry::new(IpPool::new(
+                "172.16.0".to_string(),
+                "172.16.0.1".to_string(),
+            ));
+            let nodes = ["n1", "n2", "n3"].map(|id| (id.to_string(), format!("http://{}", id)));
+            Cluster::new(
+                "n1".to_string(),
+                nodes.to_vec(),
+                pools,
+                Maintenance::default(),
+                HealthRegistry::default(),
+                path.clone(),
+            )
+            .unwrap()
+        };
+        let vote = |cluster: &Cluster, candidate: &str| {
+            cluster
+                .vote(VoteRequest {
+                    term: 5,
+                    candidate: candidate.to_string(),
+                    last: Position::default(),
+                })
+                .granted
+        };
+
+        let cluster = node();
+        assert!(vote(&cluster, "n2"));
+        // After a restart the vote in term 5 is still taken
+        let cluster = node();
+        assert_eq!(cluster.status().term, 5);
+        assert!(!vote(&cluster, "n3"));
+        assert!(vote(&cluster, "n2"));
+
+        // A heartbeat alone does not move the node to the leader's position
+        let heartbeat = |state| AppendRequest {
+            term: 5,
+            leader: "n2".to_string(),
+            position: Position { term: 5, index: 9 },
+            state,
+        };
+        let response = cluster.append(heartbeat(None)).await;
+        assert!(response.success);
+        assert_eq!(response.position, Position::default());
+        assert_eq!(cluster.status().commit_index, 0);
+        // It does with the state at that position
+        let state = cluster.inner.pools.snapshot().await;
+        let response = cluster.append(heartbeat(Some(state))).await;
+        assert_eq!(response.position, Position { term: 5, index: 9 });
+        assert_eq!(cluster.append(heartbeat(None)).await.position.index, 9);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
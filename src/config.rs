@@ -0,0 +1,718 @@
+use crate::consistency::ConsistencyMode;
+use crate::dhcp::DhcpOptions;
+use crate::events;
+use crate::insights::AnomalyThresholds;
+use crate::ippool::VLAN_IDS;
+use crate::maintenance;
+use crate::notify::ChatKind;
+use crate::routes::StaticRoute;
+use crate::slaac::Ipv6Mode;
+use std::collections::BTreeSet;
+use std::env;
+use std::time::Duration;
+
+// Runtime configuration, read from environment variables
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub port: u16,
+    pub unix_socket: Option<UnixSocketConfig>,
+    pub network: String,
+    pub gateway: String,
+    pub reserved: Vec<(String, String)>, // label, IP
+    pub routes: Vec<StaticRoute>,
+    pub dhcp_options: DhcpOptions, // DNS servers, search domains and NTP servers
+    pub lease_ttl: Option<Duration>, // None: leases never expire
+    pub hostname_template: Option<String>, // e.g. "vm-{last_octet}.lab.local"
+    pub vlan_id: Option<u16>,
+    pub allow_public_networks: bool, // pools may use networks outside private and CGNAT ranges
+    pub ipv6_prefix: Option<String>, // /64 VM IPv6 addresses are derived in
+    pub ipv6_mode: Ipv6Mode,
+    pub ipv6_secret: String, // keys stable IPv6 interface identifiers
+    pub lease_expiry_interval_secs: u64,
+    pub lease_expiry_warning: Option<Duration>, // warn holders this long before expiry
+    pub extra_pools: Vec<PoolConfig>,
+    pub wireguard: Option<WireGuardConfig>,
+    pub prefix6: Option<Prefix6Config>,
+    pub consul: Option<ConsulConfig>,
+    pub state_file: Option<String>,
+    pub save_interval_ms: u64, // debounce window for state writes
+    pub shared_storage: bool,  // other instances write to the same backend
+    pub consistency_check: ConsistencyMode, // for state loaded on startup
+    pub replica_of: Option<String>, // primary URL; serve reads from its state stream
+    pub cluster: Option<ClusterConfig>,
+    pub storage_breaker_threshold: u32, // consecutive failures that open the circuit
+    pub storage_breaker_cooldown_secs: u64,
+    pub journal: Option<JournalConfig>,
+    pub s3: Option<S3Config>,
+    pub audit_log: Option<AuditLogConfig>,
+    pub syslog: Option<SyslogConfig>,
+    pub event_store_file: Option<String>, // events kept for replay across restarts
+    pub event_retain: usize,              // events kept for listing and replay
+    pub capacity_webhook: Option<CapacityWebhookConfig>,
+    pub event_webhook_url: Option<String>, // every allocation event is posted here
+    pub webhook_secret: Option<String>,    // signs webhook payloads (HMAC-SHA256)
+    pub webhook_max_attempts: u32,         // before a payload is dead-lettered
+    pub report_webhook: Option<ReportWebhookConfig>,
+    pub chat: Option<ChatConfig>,
+    pub email: Option<EmailConfig>,
+    pub allocation_budget: Option<BudgetConfig>,
+    pub anomalies: AnomalyThresholds,
+    pub leak_idle_secs: u64, // without renewal before an allocation counts as a probable leak
+    pub require_fence_tokens: bool, // refuse releases and renewals without a fence token
+    pub approval_pools: BTreeSet<String>, // new allocations wait for an admin's approval
+    pub policy_script: Option<String>, // Rhai allocation policy
+    pub pool_templates_file: Option<String>, // JSON object of pool templates by name
+    pub api_v1_sunset: Option<String>, // RFC 3339, announced in v1 Sunset headers
+    pub max_body_bytes: usize,
+    pub max_import_bytes: usize, // lease files and Terraform states
+    pub max_concurrent_requests: usize, // beyond this requests are shed
+    pub request_timeout_secs: u64,
+    pub maintenance: Option<String>, // start read-only with this reason
+    pub gateway_check: Option<GatewayCheckConfig>,
+    pub ping_sweep: PingSweepConfig,
+    pub mdns: Option<MdnsConfig>,
+    #[cfg(feature = "snmp")]
+    pub snmp: Option<SnmpConfig>,
+    #[cfg(feature = "fault-injection")]
+    pub faults: crate::faults::FaultConfig,
+}
+
+// Additional listener on a unix socket (enabled when UNIX_SOCKET is set)
+#[derive(Debug, Clone)]
+pub struct UnixSocketConfig {
+    pub path: String,
+    pub mode: u32, // file permissions, e.g. 0o660
+}
+
+// Gateway reachability self-check (enabled when GATEWAY_CHECK is set)
+#[derive(Debug, Clone)]
+pub struct GatewayCheckConfig {
+    pub interval_secs: u64, // 0: only check at startup
+    pub timeout_secs: u64,
+}
+
+// mDNS/DNS-SD announcement of the service and its pools (enabled when
+// MDNS_ANNOUNCE is set)
+#[derive(Debug, Clone)]
+pub struct MdnsConfig {
+    pub instance: String,        // DNS-SD instance name
+    pub host: String,            // announced as <host>.local
+    pub address: Option<String>, // None: the address multicast goes out from
+    pub interval_secs: u64,
+}
+
+// Read-only SNMP agent for the pool gauges (enabled when SNMP_AGENT is set)
+#[cfg(feature = "snmp")]
+#[derive(Debug, Clone)]
+pub struct SnmpConfig {
+    pub bind: String,      // UDP address, e.g. "0.0.0.0:1161"
+    pub community: String, // v1/v2c community requests must carry
+    pub base_oid: String,  // the pool objects live under it
+}
+
+// Ping sweep audit, always available on demand, scheduled when
+// PING_SWEEP_INTERVAL is set
+#[derive(Debug, Clone)]
+pub struct PingSweepConfig {
+    pub interval_secs: u64, // 0: only on demand
+    pub timeout_secs: u64,
+    pub concurrency: usize,
+}
+
+// Slack/Discord notifications for critical conditions (enabled when CHAT_WEBHOOK_URL is set)
+#[derive(Debug, Clone)]
+pub struct ChatConfig {
+    pub url: String,
+    pub kind: ChatKind,
+    pub template: String,
+    pub rate_limit_secs: u64, // per condition and pool
+    pub usage_threshold: f64, // percent
+}
+
+// Alert emails and daily digest through an SMTP relay (enabled when
+// SMTP_HOST and ALERT_EMAIL_TO are set)
+#[derive(Debug, Clone)]
+pub struct EmailConfig {
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub from: String,
+    pub to: Vec<String>,
+    pub digest_hour: Option<u32>, // UTC, None: no digest
+}
+
+// New allocations allowed per API key (enabled when ALLOCATION_BUDGET is set)
+#[derive(Debug, Clone)]
+pub struct BudgetConfig {
+    pub limit: u32,
+    pub window_secs: u64, // over which the budget refills
+}
+
+// Scheduled utilization report pushed to a webhook (enabled when
+// REPORT_WEBHOOK_URL is set)
+#[derive(Debug, Clone)]
+pub struct ReportWebhookConfig {
+    pub url: String,
+    pub interval_secs: u64, // between reports
+    pub window_secs: u64,   // covered by each report
+}
+
+// Embedded consensus cluster (enabled when CLUSTER_PEERS is set)
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    pub node_id: String,
+    pub nodes: Vec<(String, String)>, // (id, URL) of every member, this one included
+    pub state_file: String,           // term and vote, which must survive restarts
+}
+
+// Low-watermark "expand capacity" webhook (enabled when CAPACITY_WEBHOOK_URL is set)
+#[derive(Debug, Clone)]
+pub struct CapacityWebhookConfig {
+    pub url: String,
+    pub watermark: usize, // fire when available addresses drop below this
+}
+
+// Local audit trail file (enabled when AUDIT_LOG_FILE is set)
+#[derive(Debug, Clone)]
+pub struct AuditLogConfig {
+    pub path: String,
+    pub max_bytes: u64,
+    pub retain: usize, // rotated files kept
+}
+
+// Events shipped to a syslog collector (enabled when SYSLOG_URL is set)
+#[derive(Debug, Clone)]
+pub struct SyslogConfig {
+    pub url: String, // udp://, tcp:// or tls://host[:port]
+    pub facility: u8,
+    pub hostname: String,
+    pub app_name: String,
+}
+
+// Write-ahead journal with periodic snapshots (enabled when JOURNAL_DIR is set)
+#[derive(Debug, Clone)]
+pub struct JournalConfig {
+    pub dir: String,
+    pub fsync: bool,
+    pub compact_interval_secs: u64,
+}
+
+// Scheduled snapshots to S3-compatible storage (enabled when S3_BUCKET is set)
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    pub prefix: String,
+    pub interval_secs: u64,
+    pub retain: usize,
+}
+
+// Consul integration (enabled when CONSUL_ADDR is set)
+#[derive(Debug, Clone)]
+pub struct ConsulConfig {
+    pub addr: String,
+    pub token: Option<String>,
+    pub register: bool,
+    pub service_name: String,
+    pub service_address: String,
+    pub kv_key: Option<String>, // persist pool state under this key
+}
+
+// Additional named pool, from POOLS="name=prefix[:gateway],..."
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub name: String,
+    pub network: String,
+    pub gateway: String,
+    pub reserved: Vec<(String, String)>,   // from RESERVED_<NAME>
+    pub routes: Vec<StaticRoute>,          // from ROUTES_<NAME>
+    pub dhcp_options: DhcpOptions,         // from DNS_SERVERS_<NAME>, SEARCH_DOMAINS_<NAME>, ...
+    pub lease_ttl: Option<Duration>,       // from LEASE_TTL_<NAME>
+    pub hostname_template: Option<String>, // from HOSTNAME_TEMPLATE_<NAME>
+    pub vlan_id: Option<u16>,              // from VLAN_ID_<NAME>
+    pub ipv6_prefix: Option<String>,       // from IPV6_PREFIX_<NAME>
+}
+
+// IPv6 prefix delegation (enabled when PREFIX6_NETWORK is set)
+#[derive(Debug, Clone)]
+pub struct Prefix6Config {
+    pub network: String, // parent prefix, e.g. "2001:db8:100::/56"
+    pub prefix_len: u8,  // of the delegated prefixes
+}
+
+// WireGuard peer address mode (enabled when WG_NETWORK is set)
+#[derive(Debug, Clone)]
+pub struct WireGuardConfig {
+    pub network: String,
+    pub server_ip: String,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let network = env_or("NETWORK", "172.16.0");
+        let gateway = env::var("GATEWAY").unwrap_or_else(|_| format!("{}.1", network));
+        let port = env_parse("PORT", 8090);
+
+        let reserved = env::var("RESERVED")
+            .map(|value| parse_reserved(&value))
+            .unwrap_or_default();
+
+        let routes = env::var("ROUTES")
+            .map(|value| parse_routes(&value))
+            .unwrap_or_default();
+
+        let extra_pools = env::var("POOLS")
+            .map(|pools| parse_pools(&pools))
+            .unwrap_or_default();
+
+        let wireguard = env::var("WG_NETWORK").ok().map(|wg_network| {
+            let server_ip =
+                env::var("WG_SERVER_IP").unwrap_or_else(|_| format!("{}.1", wg_network));
+            WireGuardConfig {
+                network: wg_network,
+                server_ip,
+            }
+        });
+
+        let prefix6 = env::var("PREFIX6_NETWORK")
+            .ok()
+            .map(|network| Prefix6Config {
+                network,
+                prefix_len: env_parse("PREFIX6_LENGTH", 64),
+            });
+
+        let consul = env::var("CONSUL_ADDR").ok().map(|addr| ConsulConfig {
+            addr,
+            token: env::var("CONSUL_TOKEN").ok(),
+            register: env_flag("CONSUL_REGISTER"),
+            service_name: env_or("CONSUL_SERVICE_NAME", "ippool"),
+            service_address: env_or("CONSUL_SERVICE_ADDRESS", "127.0.0.1"),
+            kv_key: env::var("CONSUL_KV_KEY").ok(),
+        });
+
+        let s3 = env::var("S3_BUCKET").ok().map(|bucket| S3Config {
+            endpoint: env_or("S3_ENDPOINT", "https://s3.amazonaws.com"),
+            bucket,
+            region: env_or("S3_REGION", "us-east-1"),
+            access_key: env_or("S3_ACCESS_KEY", ""),
+            secret_key: env_or("S3_SECRET_KEY", ""),
+            prefix: env_or("S3_PREFIX", "ippool/"),
+            interval_secs: env_parse("S3_SNAPSHOT_INTERVAL", 3600),
+            retain: env_parse("S3_SNAPSHOT_RETAIN", 24),
+        });
+
+        let journal = env::var("JOURNAL_DIR").ok().map(|dir| JournalConfig {
+            dir,
+            fsync: env_flag("JOURNAL_FSYNC"),
+            compact_interval_secs: env_parse("JOURNAL_COMPACT_INTERVAL", 300),
+        });
+
+        let audit_log = env::var("AUDIT_LOG_FILE").ok().map(|path| AuditLogConfig {
+            path,
+            max_bytes: env_parse("AUDIT_LOG_MAX_BYTES", 10 * 1024 * 1024),
+            retain: env_parse("AUDIT_LOG_RETAIN", 5),
+        });
+
+        let syslog = env::var("SYSLOG_URL").ok().map(|url| SyslogConfig {
+            url,
+            facility: syslog_facility(),
+            hostname: env::var("SYSLOG_HOSTNAME")
+                .ok()
+                .or_else(local_hostname)
+                .unwrap_or_else(|| "-".to_string()),
+            app_name: env_or("SYSLOG_APP_NAME", "ippool"),
+        });
+
+        let capacity_webhook =
+            env::var("CAPACITY_WEBHOOK_URL")
+                .ok()
+                .map(|url| CapacityWebhookConfig {
+                    url,
+                    watermark: env_parse("CAPACITY_WATERMARK", 10),
+                });
+
+        let chat = env::var("CHAT_WEBHOOK_URL").ok().map(|url| ChatConfig {
+            url,
+            kind: env::var("CHAT_WEBHOOK_KIND")
+                .ok()
+                .and_then(|kind| {
+                    let parsed = ChatKind::parse(&kind);
+                    if parsed.is_none() {
+                        tracing::warn!("Unknown CHAT_WEBHOOK_KIND {}, using slack", kind);
+                    }
+                    parsed
+                })
+                .unwrap_or(ChatKind::Slack),
+            template: env_or(
+                "CHAT_TEMPLATE",
+                ":warning: ippool {condition} on {pool}: {message}",
+            ),
+            rate_limit_secs: env_parse("CHAT_RATE_LIMIT", 300),
+            usage_threshold: env_parse("CHAT_USAGE_THRESHOLD", 90.0),
+        });
+
+        let email_to: Vec<String> = env_or("ALERT_EMAIL_TO", "")
+            .split(',')
+            .map(str::trim)
+            .filter(|to| !to.is_empty())
+            .map(String::from)
+            .collect();
+        let email = env::var("SMTP_HOST")
+            .ok()
+            .filter(|_| !email_to.is_empty())
+            .map(|smtp_host| EmailConfig {
+                smtp_host,
+                smtp_port: env_parse("SMTP_PORT", 25),
+                from: env_or("ALERT_EMAIL_FROM", "ippool@localhost"),
+                to: email_to,
+                digest_hour: match env_or("ALERT_EMAIL_DIGEST_HOUR", "8").as_str() {
+                    "off" => None,
+                    hour => hour.parse().ok().filter(|hour| *hour < 24).or_else(|| {
+                        tracing::warn!("Invalid ALERT_EMAIL_DIGEST_HOUR {}, using 8", hour);
+                        Some(8)
+                    }),
+                },
+            });
+
+        let unix_socket = env::var("UNIX_SOCKET").ok().map(|path| {
+            let mode = env_or("UNIX_SOCKET_MODE", "660");
+            UnixSocketConfig {
+                path,
+                mode: u32::from_str_radix(&mode, 8).unwrap_or_else(|_| {
+                    tracing::warn!("Invalid UNIX_SOCKET_MODE {}, using 660", mode);
+                    0o660
+                }),
+            }
+        });
+
+        Config {
+            port,
+            unix_socket,
+            network,
+            gateway,
+            reserved,
+            routes,
+            dhcp_options: dhcp_options(""),
+            lease_ttl: lease_ttl("LEASE_TTL"),
+            hostname_template: env::var("HOSTNAME_TEMPLATE").ok(),
+            vlan_id: vlan_id("VLAN_ID"),
+            allow_public_networks: env_flag("ALLOW_PUBLIC_NETWORKS"),
+            ipv6_prefix: env::var("IPV6_PREFIX").ok(),
+            ipv6_mode: env::var("IPV6_MODE")
+                .ok()
+                .and_then(|mode| {
+                    mode.parse()
+                        .map_err(|e| tracing::warn!("Ignoring IPV6_MODE: {}", e))
+                        .ok()
+                })
+                .unwrap_or_default(),
+            ipv6_secret: env_or("IPV6_SECRET", ""),
+            lease_expiry_interval_secs: env_parse("LEASE_EXPIRY_INTERVAL", 30),
+            lease_expiry_warning: lease_ttl("LEASE_EXPIRY_WARNING"),
+            extra_pools,
+            wireguard,
+            prefix6,
+            consul,
+            state_file: env::var("STATE_FILE").ok(),
+            save_interval_ms: env_parse("STATE_SAVE_INTERVAL_MS", 500),
+            shared_storage: env_flag("SHARED_STORAGE"),
+            consistency_check: env::var("CONSISTENCY_CHECK")
+                .ok()
+                .and_then(|mode| {
+                    mode.parse()
+                        .map_err(|e| tracing::warn!("Ignoring CONSISTENCY_CHECK: {}", e))
+                        .ok()
+                })
+                .unwrap_or_default(),
+            replica_of: env::var("REPLICA_OF").ok().filter(|url| !url.is_empty()),
+            cluster: env::var("CLUSTER_PEERS")
+                .ok()
+                .map(|value| parse_cluster_nodes(&value))
+                .filter(|nodes| !nodes.is_empty())
+                .and_then(|nodes| {
+                    let node_id = env_or("CLUSTER_NODE_ID", "");
+                    if nodes.iter().any(|(id, _)| *id == node_id) {
+                        Some(ClusterConfig {
+                            node_id,
+                            nodes,
+                            state_file: env_or("CLUSTER_STATE_FILE", "cluster-state.json"),
+                        })
+                    } else {
+                        tracing::warn!(
+                            "Ignoring CLUSTER_PEERS: CLUSTER_NODE_ID '{}' is not one of them",
+                            node_id
+                        );
+                        None
+                    }
+                }),
+            storage_breaker_threshold: env_parse("STORAGE_BREAKER_THRESHOLD", 5),
+            storage_breaker_cooldown_secs: env_parse("STORAGE_BREAKER_COOLDOWN", 10),
+            journal,
+            s3,
+            audit_log,
+            syslog,
+            event_store_file: env::var("EVENT_STORE_FILE").ok(),
+            event_retain: env_parse("EVENT_RETAIN", events::MAX_RECENT_EVENTS),
+            capacity_webhook,
+            event_webhook_url: env::var("EVENT_WEBHOOK_URL").ok(),
+            webhook_secret: env::var("WEBHOOK_SECRET").ok().filter(|s| !s.is_empty()),
+            webhook_max_attempts: env_parse("WEBHOOK_MAX_ATTEMPTS", 3),
+            report_webhook: env::var("REPORT_WEBHOOK_URL")
+                .ok()
+                .map(|url| ReportWebhookConfig {
+                    url,
+                    interval_secs: env_parse("REPORT_INTERVAL", 7 * 86400).max(60),
+                    window_secs: env_parse("REPORT_WINDOW", 7 * 86400),
+                }),
+            chat,
+            email,
+            allocation_budget: env::var("ALLOCATION_BUDGET")
+                .ok()
+                .and_then(|limit| limit.parse().ok())
+                .filter(|limit| *limit > 0)
+                .map(|limit| BudgetConfig {
+                    limit,
+                    window_secs: env_parse("ALLOCATION_BUDGET_WINDOW", 3600),
+                }),
+            anomalies: AnomalyThresholds {
+                window_secs: env_parse("INSIGHTS_WINDOW", 3600),
+                leak_allocations: env_parse("INSIGHTS_LEAK_ALLOCATIONS", 20),
+                leak_release_ratio: env_parse("INSIGHTS_LEAK_RELEASE_RATIO", 0.1),
+                thrash_cycles: env_parse("INSIGHTS_THRASH_CYCLES", 5),
+            },
+            leak_idle_secs: env_parse("LEAK_IDLE_AFTER", 7 * 86400),
+            require_fence_tokens: env_flag("REQUIRE_FENCE_TOKENS"),
+            approval_pools: env::var("APPROVAL_POOLS")
+                .map(|pools| parse_names(&pools))
+                .unwrap_or_default(),
+            policy_script: env::var("POLICY_SCRIPT").ok(),
+            pool_templates_file: env::var("POOL_TEMPLATES_FILE").ok(),
+            api_v1_sunset: env::var("API_V1_SUNSET").ok(),
+            max_body_bytes: env_parse("MAX_BODY_BYTES", 64 * 1024),
+            max_import_bytes: env_parse("MAX_IMPORT_BYTES", 16 * 1024 * 1024),
+            max_concurrent_requests: env_parse("MAX_CONCURRENT_REQUESTS", 512),
+            request_timeout_secs: env_parse("REQUEST_TIMEOUT", 30),
+            maintenance: env_flag("MAINTENANCE_MODE")
+                .then(|| env_or("MAINTENANCE_REASON", maintenance::DEFAULT_REASON)),
+            gateway_check: env_flag("GATEWAY_CHECK").then(|| GatewayCheckConfig {
+                interval_secs: env_parse("GATEWAY_CHECK_INTERVAL", 300),
+                timeout_secs: env_parse("GATEWAY_CHECK_TIMEOUT", 1),
+            }),
+            ping_sweep: PingSweepConfig {
+                interval_secs: env_parse("PING_SWEEP_INTERVAL", 0),
+                timeout_secs: env_parse("PING_SWEEP_TIMEOUT", 1),
+                concurrency: env_parse("PING_SWEEP_CONCURRENCY", 32),
+            },
+            mdns: env_flag("MDNS_ANNOUNCE").then(|| MdnsConfig {
+                instance: env_or("MDNS_INSTANCE", "ippool"),
+                host: env::var("MDNS_HOSTNAME")
+                    .ok()
+                    .or_else(local_hostname)
+                    .unwrap_or_else(|| "ippool".to_string()),
+                address: env::var("MDNS_ADDRESS").ok(),
+                interval_secs: env_parse("MDNS_INTERVAL", 60),
+            }),
+            #[cfg(feature = "snmp")]
+            snmp: env_flag("SNMP_AGENT").then(|| SnmpConfig {
+                bind: env_or("SNMP_BIND", "0.0.0.0:1161"),
+                community: env_or("SNMP_COMMUNITY", "public"),
+                base_oid: env_or("SNMP_BASE_OID", crate::snmp::DEFAULT_BASE_OID),
+            }),
+            #[cfg(feature = "fault-injection")]
+            faults: crate::faults::FaultConfig {
+                latency_ms: env_parse("FAULT_LATENCY_MS", 0),
+                latency_jitter_ms: env_parse("FAULT_LATENCY_JITTER_MS", 0),
+                failure_rate: env_parse("FAULT_FAILURE_RATE", 0.0),
+                exhausted: env_flag("FAULT_EXHAUSTED"),
+            },
+        }
+    }
+}
+
+fn parse_pools(value: &str) -> Vec<PoolConfig> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let Some((name, rest)) = entry.split_once('=') else {
+                tracing::warn!("Ignoring malformed POOLS entry: {}", entry);
+                return None;
+            };
+            let (network, gateway) = match rest.split_once(':') {
+                Some((network, gateway)) => (network.to_string(), gateway.to_string()),
+                None => (rest.to_string(), format!("{}.1", rest)),
+            };
+            let name = name.trim().to_string();
+            let reserved = env::var(format!("RESERVED_{}", name.to_uppercase()))
+                .map(|value| parse_reserved(&value))
+                .unwrap_or_default();
+            let routes = env::var(format!("ROUTES_{}", name.to_uppercase()))
+                .map(|value| parse_routes(&value))
+                .unwrap_or_default();
+            let dhcp_options = dhcp_options(&format!("_{}", name.to_uppercase()));
+            let lease_ttl = lease_ttl(&format!("LEASE_TTL_{}", name.to_uppercase()));
+            let hostname_template =
+                env::var(format!("HOSTNAME_TEMPLATE_{}", name.to_uppercase())).ok();
+            let vlan_id = vlan_id(&format!("VLAN_ID_{}", name.to_uppercase()));
+            let ipv6_prefix = env::var(format!("IPV6_PREFIX_{}", name.to_uppercase())).ok();
+            Some(PoolConfig {
+                name,
+                network,
+                gateway,
+                reserved,
+                routes,
+                dhcp_options,
+                lease_ttl,
+                hostname_template,
+                vlan_id,
+                ipv6_prefix,
+            })
+        })
+        .collect()
+}
+
+// "n1=http://ippool-1:8090,n2=http://ippool-2:8090" -> [(id, URL)]
+fn parse_cluster_nodes(value: &str) -> Vec<(String, String)> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match entry.split_once('=') {
+            Some((id, url)) => Some((
+                id.trim().to_string(),
+                url.trim().trim_end_matches('/').to_string(),
+            )),
+            None => {
+                tracing::warn!("Ignoring malformed cluster node: {}", entry);
+                None
+            }
+        })
+        .collect()
+}
+
+// "prod,dmz" -> pool names
+fn parse_names(value: &str) -> BTreeSet<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// "router=172.16.0.254,dns=172.16.0.53" -> [(label, ip)]
+fn parse_reserved(value: &str) -> Vec<(String, String)> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match entry.split_once('=') {
+            Some((label, ip)) => Some((label.trim().to_string(), ip.trim().to_string())),
+            None => {
+                tracing::warn!("Ignoring malformed reserved address: {}", entry);
+                None
+            }
+        })
+        .collect()
+}
+
+// "10.50.0.0/16=172.16.0.254,..." -> static routes
+fn parse_routes(value: &str) -> Vec<StaticRoute> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let route = StaticRoute::parse(entry);
+            if route.is_none() {
+                tracing::warn!("Ignoring malformed route: {}", entry);
+            }
+            route
+        })
+        .collect()
+}
+
+// DNS_SERVERS<suffix>, SEARCH_DOMAINS<suffix> and NTP_SERVERS<suffix>,
+// comma separated
+fn dhcp_options(suffix: &str) -> DhcpOptions {
+    fn parse<T>(key: &str, parse: fn(&str) -> Option<Vec<T>>) -> Vec<T> {
+        let Ok(value) = env::var(key) else {
+            return Vec::new();
+        };
+        parse(&value).unwrap_or_else(|| {
+            tracing::warn!("Ignoring malformed {}: {}", key, value);
+            Vec::new()
+        })
+    }
+    DhcpOptions {
+        dns_servers: parse(
+            &format!("DNS_SERVERS{}", suffix),
+            DhcpOptions::parse_servers,
+        ),
+        search_domains: parse(
+            &format!("SEARCH_DOMAINS{}", suffix),
+            DhcpOptions::parse_search_domains,
+        ),
+        ntp_servers: parse(
+            &format!("NTP_SERVERS{}", suffix),
+            DhcpOptions::parse_servers,
+        ),
+    }
+}
+
+// Lease TTL in seconds, 0 or unset for leases that never expire
+fn lease_ttl(key: &str) -> Option<Duration> {
+    match env_parse(key, 0) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+fn vlan_id(key: &str) -> Option<u16> {
+    let value = env::var(key).ok()?;
+    match value.parse() {
+        Ok(vlan_id) if VLAN_IDS.contains(&vlan_id) => Some(vlan_id),
+        _ => {
+            tracing::warn!("Ignoring {}: {} is not a VLAN ID (1-4094)", key, value);
+            None
+        }
+    }
+}
+
+fn syslog_facility() -> u8 {
+    let value = env_or("SYSLOG_FACILITY", "local0");
+    crate::syslog::facility(&value).unwrap_or_else(|| {
+        tracing::warn!("Ignoring SYSLOG_FACILITY: {} is not a facility", value);
+        crate::syslog::facility("local0").unwrap_or(16)
+    })
+}
+
+// First label of the machine's host name
+fn local_hostname() -> Option<String> {
+    let hostname = env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())?;
+    let label = hostname.trim().split('.').next()?;
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+fn env_or(key: &str, default: &str) -> String {
+    env::var(key).unwrap_or_else(|_| default.to_string())
+}
+
+fn env_parse<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+fn env_flag(key: &str) -> bool {
+    matches!(
+        env::var(key).as_deref(),
+        Ok("1") | Ok("true") | Ok("yes") | Ok("on")
+    )
+}
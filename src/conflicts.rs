@@ -0,0 +1,23 @@
+use serde::{Deserialize, Serialize};
+
+// How an address came to be flagged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictSource {
+    Decline, // a VM reported its address already in use (like DHCPDECLINE)
+    Probe,   // a reachability probe contradicted the pool state
+}
+
+// Address suspected to be used by someone else. Flagged addresses are
+// not handed out until an admin clears or permanently excludes them;
+// allocations already holding one are left alone.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conflict {
+    pub source: ConflictSource,
+    pub detail: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reported_by: Option<String>, // VM that declined the address
+    pub reported_at: u64, // unix seconds, latest report
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub investigation: Option<String>, // note of the admin looking into it
+}
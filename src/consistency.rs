@@ -0,0 +1,222 @@
+use crate::pools::PoolRegistry;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// What to do about inconsistent state found on startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConsistencyMode {
+    #[default]
+    Repair, // fix it and carry on
+    Refuse, // log the report and do not start
+    Off,
+}
+
+impl std::str::FromStr for ConsistencyMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "repair" => Ok(ConsistencyMode::Repair),
+            "refuse" => Ok(ConsistencyMode::Refuse),
+            "off" => Ok(ConsistencyMode::Off),
+            _ => Err(format!("{} is not repair, refuse or off", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InconsistencyKind {
+    OutOfRange,         // allocated outside the pool range
+    MissingFromVmIndex, // allocated, but not among the VM's addresses
+    DanglingVmEntry,    // among the VM's addresses, but not allocated to it
+    OrphanedMetadata,   // lease, labels or activity of an address nobody holds
+    FreeOutOfRange,     // on the free list outside the pool range
+    UnavailableFree,    // on the free list while allocated, reserved or in conflict
+    LostAddress,        // neither allocated, reserved, in conflict nor free
+}
+
+impl InconsistencyKind {
+    fn describe(self) -> &'static str {
+        match self {
+            InconsistencyKind::OutOfRange => "allocated outside the pool range",
+            InconsistencyKind::MissingFromVmIndex => {
+                "allocated but missing from the VM's addresses"
+            }
+            InconsistencyKind::DanglingVmEntry => {
+                "listed as the VM's address but not allocated to it"
+            }
+            InconsistencyKind::OrphanedMetadata => {
+                "lease or labels kept for an unallocated address"
+            }
+            InconsistencyKind::FreeOutOfRange => "free outside the pool range",
+            InconsistencyKind::UnavailableFree => "free while allocated, reserved or in conflict",
+            InconsistencyKind::LostAddress => "missing from the free list",
+        }
+    }
+
+    // What repairing does about it
+    fn repair(self) -> &'static str {
+        match self {
+            InconsistencyKind::OutOfRange | InconsistencyKind::MissingFromVmIndex => {
+                "allocation dropped"
+            }
+            InconsistencyKind::DanglingVmEntry => "removed from the VM's addresses",
+            InconsistencyKind::OrphanedMetadata => "metadata dropped",
+            InconsistencyKind::FreeOutOfRange | InconsistencyKind::UnavailableFree => {
+                "removed from the free list"
+            }
+            InconsistencyKind::LostAddress => "returned to the free list",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Inconsistency {
+    pub kind: InconsistencyKind,
+    pub ip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vm_id: Option<String>,
+    pub description: &'static str,
+    pub repair: &'static str,
+}
+
+impl Inconsistency {
+    pub fn new(kind: InconsistencyKind, ip: &str, vm_id: Option<&str>) -> Self {
+        Inconsistency {
+            kind,
+            ip: ip.to_string(),
+            vm_id: vm_id.map(str::to_string),
+            description: kind.describe(),
+            repair: kind.repair(),
+        }
+    }
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.ip)?;
+        if let Some(vm_id) = &self.vm_id {
+            write!(f, " ({})", vm_id)?;
+        }
+        write!(f, ": {}", self.description)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConsistencyReport {
+    pub consistent: bool,
+    pub repaired: bool,
+    pub issues: usize,
+    pub pools: BTreeMap<String, Vec<Inconsistency>>, // only pools with issues
+}
+
+// Check every pool, repairing them if asked to
+pub async fn check(pools: &PoolRegistry, repair: bool) -> ConsistencyReport {
+    let mut report = BTreeMap::new();
+    for name in pools.names().await {
+        let Ok(pool) = pools.get(&name).await else {
+            continue;
+        };
+        let issues = pool.check_consistency(repair).await;
+        if !issues.is_empty() {
+            report.insert(name, issues);
+        }
+    }
+    let issues = report.values().map(Vec::len).sum();
+    ConsistencyReport {
+        consistent: issues == 0,
+        repaired: repair && issues > 0,
+        issues,
+        pools: report,
+    }
+}
+
+// Check the state just loaded from storage, returning whether it was
+// repaired. Panics with the report in refuse mode, so nothing serves from
+// state that breaks the invariants.
+pub async fn check_on_startup(pools: &PoolRegistry, mode: ConsistencyMode) -> bool {
+    if mode == ConsistencyMode::Off {
+        return false;
+    }
+    let report = check(pools, mode == ConsistencyMode::Repair).await;
+    if report.consistent {
+        tracing::info!("✅ Loaded state is consistent");
+        return false;
+    }
+    for (pool, issues) in &report.pools {
+        for issue in issues {
+            match mode {
+                ConsistencyMode::Refuse => {
+                    tracing::error!("Inconsistent state in pool {}: {}", pool, issue)
+                }
+                _ => tracing::warn!(
+                    "Inconsistent state in pool {}: {} ({})",
+                    pool,
+                    issue,
+                    issue.repair
+                ),
+            }
+        }
+    }
+    if mode == ConsistencyMode::Refuse {
+        panic!(
+            "Refusing to start: {} inconsistencies in the loaded state, set CONSISTENCY_CHECK=repair to fix them",
+            report.issues
+        );
+    }
+    tracing::warn!(
+        "🩹 Repaired {} inconsistencies in the loaded state",
+        report.issues
+    );
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ippool::IpPool;
+
+    #[tokio::test]
+    async fn test_finds_and_repairs_broken_state() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        let mut snapshot = pool.snapshot().await;
+
+        // Outside the range, a second primary address for vm-1 and labels
+        // for an address nobody holds
+        let allocations = &mut snapshot.allocations;
+        allocations.insert("172.16.0.255".to_string(), "vm-2".to_string());
+        allocations.insert("172.16.0.9".to_string(), "vm-1".to_string());
+        let labels = BTreeMap::from([("team".to_string(), "a".to_string())]);
+        snapshot.labels.insert("172.16.0.20".to_string(), labels);
+        let pools = PoolRegistry::new(IpPool::from_snapshot(snapshot).unwrap());
+
+        let report = check(&pools, false).await;
+        let kinds: Vec<InconsistencyKind> =
+            report.pools["default"].iter().map(|i| i.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                InconsistencyKind::OutOfRange,
+                InconsistencyKind::MissingFromVmIndex,
+                InconsistencyKind::OrphanedMetadata,
+            ]
+        );
+        assert!(!report.repaired);
+        assert!(!check(&pools, false).await.consistent);
+
+        // Repairing leaves vm-1 with the address it is indexed under
+        let report = check(&pools, true).await;
+        assert!(report.repaired);
+        assert_eq!(report.issues, 3);
+        assert!(check(&pools, false).await.consistent);
+        let pool = pools.get("default").await.unwrap();
+        assert_eq!(pool.get_allocation("vm-1").await.unwrap().ip, "172.16.0.9");
+        assert!(pool.get_allocation("vm-2").await.is_err());
+        let stats = pool.get_stats().await;
+        assert_eq!(stats["allocated"], 1);
+        assert_eq!(stats["available"], stats["total"].as_u64().unwrap() - 1);
+    }
+}
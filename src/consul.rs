@@ -0,0 +1,400 @@
+use crate::storage::{
+    LOCK_TTL, NamedSnapshot, StateStore, StorageError, StorageFuture, StoredState,
+};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::Deserialize;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Minimal Consul HTTP API client (agent service registration and KV)
+#[derive(Debug, Clone)]
+pub struct ConsulClient {
+    base_url: String,
+    token: Option<String>,
+    http: reqwest::Client,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct KvPair {
+    value: Option<String>, // base64
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SessionResponse {
+    #[serde(rename = "ID")]
+    id: String,
+}
+
+// Consul session holding the write lock; dropped by Consul when not
+// renewed within LOCK_TTL, which releases the lock
+#[derive(Debug, Clone)]
+struct Session {
+    id: String,
+    renewed: Instant,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TxnResponse {
+    results: Option<Vec<TxnResult>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TxnResult {
+    #[serde(rename = "KV")]
+    kv: TxnKv,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TxnKv {
+    modify_index: u64,
+}
+
+impl ConsulClient {
+    pub fn new(base_url: String, token: Option<String>) -> Self {
+        ConsulClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .http
+            .request(method, format!("{}{}", self.base_url, path));
+        match &self.token {
+            Some(token) => request.header("X-Consul-Token", token),
+            None => request,
+        }
+    }
+
+    // Register this instance with an HTTP health check against /api/v1/health
+    pub async fn register_service(
+        &self,
+        name: &str,
+        address: &str,
+        port: u16,
+    ) -> Result<(), reqwest::Error> {
+        let body = serde_json::json!({
+            "ID": format!("{}-{}-{}", name, address, port),
+            "Name": name,
+            "Address": address,
+            "Port": port,
+            "Check": {
+                "HTTP": format!("http://{}:{}/api/v1/health", address, port),
+                "Interval": "10s",
+                "DeregisterCriticalServiceAfter": "1m",
+            },
+        });
+
+        self.request(reqwest::Method::PUT, "/v1/agent/service/register")
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+// Pool state stored under a single Consul KV key. Writes use check-and-set
+// against the last seen ModifyIndex so two writers never clobber each other.
+// Named snapshots are keys under `<key>/snapshots/`. The write lock is
+// `<key>/lock`, acquired with a session.
+#[derive(Debug)]
+pub struct ConsulKvStore {
+    client: ConsulClient,
+    key: String,
+    modify_index: Mutex<u64>, // 0 = key must not exist yet
+    session: Mutex<Option<Session>>,
+}
+
+impl ConsulKvStore {
+    pub fn new(client: ConsulClient, key: String) -> Self {
+        ConsulKvStore {
+            client,
+            key: key.trim_start_matches('/').to_string(),
+            modify_index: Mutex::new(0),
+            session: Mutex::new(None),
+        }
+    }
+
+    // Session for the write lock, renewed once half its TTL is gone and
+    // replaced when Consul no longer knows it
+    async fn session(&self) -> Result<String, StorageError> {
+        let current = self.session.lock().unwrap().clone();
+        if let Some(session) = current {
+            if session.renewed.elapsed() < LOCK_TTL / 2 {
+                return Ok(session.id);
+            }
+            let response = self
+                .client
+                .request(
+                    reqwest::Method::PUT,
+                    &format!("/v1/session/renew/{}", session.id),
+                )
+                .send()
+                .await
+                .map_err(unavailable)?;
+            if response.status() != reqwest::StatusCode::NOT_FOUND {
+                response.error_for_status().map_err(unavailable)?;
+                *self.session.lock().unwrap() = Some(Session {
+                    id: session.id.clone(),
+                    renewed: Instant::now(),
+                });
+                return Ok(session.id);
+            }
+        }
+
+        let body = serde_json::json!({
+            "Name": format!("{}-lock", self.key),
+            "TTL": format!("{}s", LOCK_TTL.as_secs()),
+            "Behavior": "release",
+            "LockDelay": "0s",
+        });
+        let created: SessionResponse = self
+            .client
+            .request(reqwest::Method::PUT, "/v1/session/create")
+            .json(&body)
+            .send()
+            .await
+            .map_err(unavailable)?
+            .error_for_status()
+            .map_err(unavailable)?
+            .json()
+            .await
+            .map_err(unavailable)?;
+        *self.session.lock().unwrap() = Some(Session {
+            id: created.id.clone(),
+            renewed: Instant::now(),
+        });
+        Ok(created.id)
+    }
+
+    async fn acquire(&self) -> Result<bool, StorageError> {
+        let session = self.session().await?;
+        let path = format!("/v1/kv/{}/lock?acquire={}", self.key, session);
+        let response = self
+            .client
+            .request(reqwest::Method::PUT, &path)
+            .send()
+            .await
+            .map_err(unavailable)?;
+        // An expired session is refused outright; start a new one next time
+        if !response.status().is_success() {
+            *self.session.lock().unwrap() = None;
+        }
+        let acquired: bool = response
+            .error_for_status()
+            .map_err(unavailable)?
+            .json()
+            .await
+            .map_err(unavailable)?;
+        Ok(acquired)
+    }
+
+    async fn release(&self) -> Result<(), StorageError> {
+        let Some(session) = self.session.lock().unwrap().clone() else {
+            return Ok(());
+        };
+        let path = format!("/v1/kv/{}/lock?release={}", self.key, session.id);
+        self.client
+            .request(reqwest::Method::PUT, &path)
+            .send()
+            .await
+            .map_err(unavailable)?
+            .error_for_status()
+            .map_err(unavailable)?;
+        Ok(())
+    }
+
+    async fn fetch(&self) -> Result<Option<StoredState>, StorageError> {
+        let response = self
+            .client
+            .request(reqwest::Method::GET, &format!("/v1/kv/{}?raw", self.key))
+            .send()
+            .await
+            .map_err(unavailable)?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            *self.modify_index.lock().unwrap() = 0;
+            return Ok(None);
+        }
+        let response = response.error_for_status().map_err(unavailable)?;
+
+        // For a single key the index header is the key's ModifyIndex
+        let index = response
+            .headers()
+            .get("X-Consul-Index")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| StorageError::Unavailable("missing X-Consul-Index".to_string()))?;
+
+        let body = response.bytes().await.map_err(unavailable)?;
+        let state =
+            serde_json::from_slice(&body).map_err(|e| StorageError::Corrupt(e.to_string()))?;
+
+        *self.modify_index.lock().unwrap() = index;
+        Ok(Some(state))
+    }
+
+    async fn store(&self, state: &StoredState) -> Result<(), StorageError> {
+        let value = serde_json::to_vec(state).map_err(|e| StorageError::Corrupt(e.to_string()))?;
+        let index = *self.modify_index.lock().unwrap();
+
+        // The txn endpoint reports the new ModifyIndex in the same round trip
+        let txn = serde_json::json!([{
+            "KV": {
+                "Verb": "cas",
+                "Key": self.key,
+                "Value": STANDARD.encode(value),
+                "Index": index,
+            }
+        }]);
+
+        let response = self
+            .client
+            .request(reqwest::Method::PUT, "/v1/txn")
+            .json(&txn)
+            .send()
+            .await
+            .map_err(unavailable)?;
+
+        // Consul answers 409 when the CAS check fails
+        if response.status() == reqwest::StatusCode::CONFLICT {
+            return Err(StorageError::Conflict);
+        }
+        let response: TxnResponse = response
+            .error_for_status()
+            .map_err(unavailable)?
+            .json()
+            .await
+            .map_err(unavailable)?;
+
+        let new_index = response
+            .results
+            .and_then(|results| results.into_iter().next())
+            .map(|result| result.kv.modify_index)
+            .ok_or_else(|| StorageError::Unavailable("empty txn response".to_string()))?;
+
+        *self.modify_index.lock().unwrap() = new_index;
+        Ok(())
+    }
+
+    fn snapshot_prefix(&self) -> String {
+        format!("{}/snapshots/", self.key)
+    }
+
+    async fn fetch_snapshot(&self, name: &str) -> Result<Option<NamedSnapshot>, StorageError> {
+        let path = format!("/v1/kv/{}{}?raw", self.snapshot_prefix(), name);
+        let response = self
+            .client
+            .request(reqwest::Method::GET, &path)
+            .send()
+            .await
+            .map_err(unavailable)?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let body = response
+            .error_for_status()
+            .map_err(unavailable)?
+            .bytes()
+            .await
+            .map_err(unavailable)?;
+        serde_json::from_slice(&body)
+            .map(Some)
+            .map_err(|e| StorageError::Corrupt(e.to_string()))
+    }
+
+    async fn fetch_snapshots(&self) -> Result<Vec<NamedSnapshot>, StorageError> {
+        let path = format!("/v1/kv/{}?recurse", self.snapshot_prefix());
+        let response = self
+            .client
+            .request(reqwest::Method::GET, &path)
+            .send()
+            .await
+            .map_err(unavailable)?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+        let pairs: Vec<KvPair> = response
+            .error_for_status()
+            .map_err(unavailable)?
+            .json()
+            .await
+            .map_err(unavailable)?;
+
+        let mut snapshots = Vec::new();
+        for value in pairs.into_iter().filter_map(|pair| pair.value) {
+            let data = STANDARD
+                .decode(value)
+                .map_err(|e| StorageError::Corrupt(e.to_string()))?;
+            snapshots.push(
+                serde_json::from_slice(&data).map_err(|e| StorageError::Corrupt(e.to_string()))?,
+            );
+        }
+        Ok(snapshots)
+    }
+
+    async fn store_snapshot(&self, snapshot: &NamedSnapshot) -> Result<(), StorageError> {
+        let value =
+            serde_json::to_vec(snapshot).map_err(|e| StorageError::Corrupt(e.to_string()))?;
+        let path = format!("/v1/kv/{}{}", self.snapshot_prefix(), snapshot.name);
+
+        self.client
+            .request(reqwest::Method::PUT, &path)
+            .body(value)
+            .send()
+            .await
+            .map_err(unavailable)?
+            .error_for_status()
+            .map_err(unavailable)?;
+        Ok(())
+    }
+}
+
+impl StateStore for ConsulKvStore {
+    fn name(&self) -> &'static str {
+        "consul"
+    }
+
+    fn load(&self) -> StorageFuture<'_, Option<StoredState>> {
+        Box::pin(self.fetch())
+    }
+
+    fn save<'a>(&'a self, state: &'a StoredState) -> StorageFuture<'a, ()> {
+        Box::pin(self.store(state))
+    }
+
+    fn save_snapshot<'a>(&'a self, snapshot: &'a NamedSnapshot) -> StorageFuture<'a, ()> {
+        Box::pin(self.store_snapshot(snapshot))
+    }
+
+    fn load_snapshot<'a>(&'a self, name: &'a str) -> StorageFuture<'a, Option<NamedSnapshot>> {
+        Box::pin(self.fetch_snapshot(name))
+    }
+
+    fn list_snapshots(&self) -> StorageFuture<'_, Vec<NamedSnapshot>> {
+        Box::pin(self.fetch_snapshots())
+    }
+
+    fn try_lock(&self) -> StorageFuture<'_, bool> {
+        Box::pin(self.acquire())
+    }
+
+    fn unlock(&self) -> StorageFuture<'_, ()> {
+        Box::pin(self.release())
+    }
+}
+
+fn unavailable(e: reqwest::Error) -> StorageError {
+    StorageError::Unavailable(e.to_string())
+}
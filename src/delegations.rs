@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::net::Ipv4Addr;
+
+// Header carrying a team's API key
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+// Sub-range of a pool handed to a team. Allocations made with the team's
+// API key only draw from it, and other allocations never do. Only a hash of
+// the key is kept.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delegation {
+    pub start: Ipv4Addr,
+    pub end: Ipv4Addr,
+    pub key_hash: String, // hex SHA-256 of the API key
+    pub created_at: u64,  // unix seconds
+}
+
+// How much of a delegation is in use
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DelegationUsage {
+    pub team: String,
+    pub start: Ipv4Addr,
+    pub end: Ipv4Addr,
+    pub total: usize, // addresses minus reserved ones
+    pub allocated: usize,
+    pub available: usize,
+    pub usage: f64, // percent of total
+}
+
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+// Fresh API key, 32 random bytes hex encoded
+pub fn new_key() -> std::io::Result<String> {
+    let mut bytes = [0u8; 32];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(hex::encode(bytes))
+}
@@ -0,0 +1,84 @@
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+// When v1 was deprecated in favour of v2
+const V1_DEPRECATED_AT: &str = "2026-10-17T00:00:00Z";
+
+// Deprecation (RFC 9745) and Sunset (RFC 8594) headers for v1 responses,
+// with a link to the successor API
+#[derive(Debug, Clone)]
+pub struct V1Deprecation {
+    deprecation: HeaderValue,
+    sunset: Option<HeaderValue>,
+}
+
+impl V1Deprecation {
+    // `sunset` is an RFC 3339 timestamp, e.g. "2027-06-30T00:00:00Z"
+    pub fn new(sunset: Option<&str>) -> Self {
+        let deprecated_at = chrono::DateTime::parse_from_rfc3339(V1_DEPRECATED_AT)
+            .map(|at| at.timestamp())
+            .unwrap_or(0);
+        let sunset = sunset.and_then(
+            |sunset| match chrono::DateTime::parse_from_rfc3339(sunset) {
+                Ok(at) => HeaderValue::from_str(
+                    &at.to_utc().format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+                )
+                .ok(),
+                Err(e) => {
+                    tracing::warn!("Ignoring invalid API_V1_SUNSET {}: {}", sunset, e);
+                    None
+                }
+            },
+        );
+
+        V1Deprecation {
+            deprecation: HeaderValue::from_str(&format!("@{}", deprecated_at))
+                .expect("numeric header value"),
+            sunset,
+        }
+    }
+}
+
+pub async fn mark_v1(
+    State(deprecation): State<V1Deprecation>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let v1 = request.uri().path().starts_with("/api/v1/");
+    let mut response = next.run(request).await;
+    if !v1 {
+        return response;
+    }
+
+    let headers = response.headers_mut();
+    headers.insert(
+        HeaderName::from_static("deprecation"),
+        deprecation.deprecation,
+    );
+    if let Some(sunset) = deprecation.sunset {
+        headers.insert(HeaderName::from_static("sunset"), sunset);
+    }
+    headers.insert(
+        axum::http::header::LINK,
+        HeaderValue::from_static("</api/v2>; rel=\"successor-version\""),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_values() {
+        let deprecation = V1Deprecation::new(Some("2027-06-30T00:00:00Z"));
+        assert_eq!(deprecation.deprecation, "@1792195200");
+        assert_eq!(deprecation.sunset.unwrap(), "Wed, 30 Jun 2027 00:00:00 GMT");
+
+        assert!(V1Deprecation::new(Some("next summer")).sunset.is_none());
+    }
+}
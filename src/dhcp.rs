@@ -0,0 +1,288 @@
+use crate::hostnames::{self, is_hostname};
+use crate::ippool::{IpAllocation, PoolSnapshot};
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+
+// Network settings handed to every VM of a pool next to its address
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DhcpOptions {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns_servers: Vec<Ipv4Addr>, // DHCP option 6
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub search_domains: Vec<String>, // DHCP option 119, e.g. "lab.local"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ntp_servers: Vec<Ipv4Addr>, // DHCP option 42
+}
+
+impl DhcpOptions {
+    pub fn is_empty(&self) -> bool {
+        self.dns_servers.is_empty() && self.search_domains.is_empty() && self.ntp_servers.is_empty()
+    }
+
+    // "lab.local,corp.local" -> search domains, None if one is not a domain
+    pub fn parse_search_domains(value: &str) -> Option<Vec<String>> {
+        entries(value)
+            .map(|domain| is_hostname(domain).then(|| domain.to_lowercase()))
+            .collect()
+    }
+
+    // "172.16.0.10,172.16.0.11" -> DNS or NTP servers, None if one is not an
+    // address
+    pub fn parse_servers(value: &str) -> Option<Vec<Ipv4Addr>> {
+        entries(value).map(|server| server.parse().ok()).collect()
+    }
+}
+
+fn entries(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+}
+
+fn join<T: ToString>(values: &[T], separator: &str) -> String {
+    values
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+fn netmask(prefix_len: u8) -> Ipv4Addr {
+    Ipv4Addr::from(
+        u32::MAX
+            .checked_shl(32 - u32::from(prefix_len))
+            .unwrap_or(0),
+    )
+}
+
+// dnsmasq configuration of a pool, tagged with its name. The range is
+// static: addresses come from the pool, dnsmasq only hands out the options.
+pub fn dnsmasq(name: &str, pool: &PoolSnapshot) -> String {
+    let options = &pool.dhcp_options;
+    let mut lines = vec![
+        format!("# pool {}", name),
+        format!(
+            "dhcp-range=set:{},{},static,{}",
+            name,
+            pool.start,
+            netmask(pool.prefix_len)
+        ),
+        format!("dhcp-option=tag:{},option:router,{}", name, pool.gateway),
+    ];
+    if !pool.routes.is_empty() {
+        let routes: Vec<String> = (pool.routes.iter())
+            .map(|route| format!("{},{}", route.cidr(), route.next_hop))
+            .collect();
+        lines.push(format!(
+            "dhcp-option=tag:{},option:classless-static-route,{}",
+            name,
+            routes.join(",")
+        ));
+    }
+    if !options.dns_servers.is_empty() {
+        lines.push(format!(
+            "dhcp-option=tag:{},option:dns-server,{}",
+            name,
+            join(&options.dns_servers, ",")
+        ));
+    }
+    if !options.search_domains.is_empty() {
+        lines.push(format!(
+            "dhcp-option=tag:{},option:domain-search,{}",
+            name,
+            join(&options.search_domains, ",")
+        ));
+    }
+    if !options.ntp_servers.is_empty() {
+        lines.push(format!(
+            "dhcp-option=tag:{},option:ntp-server,{}",
+            name,
+            join(&options.ntp_servers, ",")
+        ));
+    }
+    lines.join("\n") + "\n"
+}
+
+// Kea `subnet4` entry of a pool. `id` must be unique across the subnets of
+// one Kea configuration.
+pub fn kea(id: u32, name: &str, pool: &PoolSnapshot) -> serde_json::Value {
+    let options = &pool.dhcp_options;
+    let mut option_data = vec![serde_json::json!({"name": "routers", "data": pool.gateway})];
+    if !pool.routes.is_empty() {
+        let routes: Vec<String> = (pool.routes.iter())
+            .map(|route| format!("{} - {}", route.cidr(), route.next_hop))
+            .collect();
+        option_data.push(serde_json::json!({
+            "name": "classless-static-route",
+            "data": routes.join(", "),
+        }));
+    }
+    if !options.dns_servers.is_empty() {
+        option_data.push(serde_json::json!({
+            "name": "domain-name-servers",
+            "data": join(&options.dns_servers, ", "),
+        }));
+    }
+    if !options.search_domains.is_empty() {
+        option_data.push(serde_json::json!({
+            "name": "domain-search",
+            "data": join(&options.search_domains, ", "),
+        }));
+    }
+    if !options.ntp_servers.is_empty() {
+        option_data.push(serde_json::json!({
+            "name": "ntp-servers",
+            "data": join(&options.ntp_servers, ", "),
+        }));
+    }
+
+    serde_json::json!({
+        "id": id,
+        "subnet": format!("{}/{}", pool.network, pool.prefix_len),
+        "user-context": {"ippool": name},
+        "option-data": option_data,
+    })
+}
+
+// Static DHCP mapping of an allocation, with the field names of the
+// `<staticmap>` entries in pfSense and OPNsense configurations
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StaticMapping {
+    pub mac: String,
+    pub ipaddr: String,
+    pub hostname: String, // without the domain, as the firewalls expect
+    pub descr: String,    // "<vm_id> (<pool>) <ticket>: <notes>"
+}
+
+impl StaticMapping {
+    // None for allocations whose MAC is unknown
+    pub fn new(pool: &str, allocation: &IpAllocation) -> Option<Self> {
+        let hostname = match &allocation.hostname {
+            Some(hostname) => hostname.split('.').next().unwrap_or_default().to_string(),
+            None => hostnames::host_label(&allocation.vm_id),
+        };
+        // The ticket and notes say why the address is static
+        let mut descr = format!("{} ({})", allocation.vm_id, pool);
+        if let Some(ticket) = &allocation.ticket {
+            descr = format!("{} {}", descr, ticket);
+        }
+        if let Some(notes) = &allocation.notes {
+            descr = format!("{}: {}", descr, notes);
+        }
+        Some(StaticMapping {
+            mac: allocation.mac.clone()?,
+            ipaddr: allocation.ip.clone(),
+            hostname,
+            descr,
+        })
+    }
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// `<staticmap>` elements to paste into the `<dhcpd>` section of an interface
+// in a pfSense or OPNsense config.xml
+pub fn staticmap_xml(mappings: &[StaticMapping]) -> String {
+    let mut xml = String::new();
+    for mapping in mappings {
+        xml.push_str("<staticmap>\n");
+        for (field, value) in [
+            ("mac", &mapping.mac),
+            ("ipaddr", &mapping.ipaddr),
+            ("hostname", &mapping.hostname),
+            ("descr", &mapping.descr),
+        ] {
+            xml.push_str(&format!("\t<{0}>{1}</{0}>\n", field, escape_xml(value)));
+        }
+        xml.push_str("</staticmap>\n");
+    }
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ippool::{Annotation, IpPool};
+    use crate::routes::StaticRoute;
+
+    #[tokio::test]
+    async fn test_exports() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        pool.set_routes(vec![
+            StaticRoute::parse("10.50.0.0/16=172.16.0.254").unwrap(),
+        ])
+        .await;
+        let options = DhcpOptions {
+            search_domains: DhcpOptions::parse_search_domains("lab.local, Corp.Local").unwrap(),
+            ntp_servers: DhcpOptions::parse_servers("172.16.0.10").unwrap(),
+            ..DhcpOptions::default()
+        };
+        assert_eq!(options.search_domains, vec!["lab.local", "corp.local"]);
+        assert!(DhcpOptions::parse_search_domains("lab_local").is_none());
+        assert!(DhcpOptions::parse_servers("ntp.lab.local").is_none());
+        pool.set_dhcp_options(options).await;
+
+        let snapshot = pool.snapshot().await;
+        assert_eq!(
+            dnsmasq("default", &snapshot),
+            "# pool default\n\
+             dhcp-range=set:default,172.16.0.1,static,255.255.255.0\n\
+             dhcp-option=tag:default,option:router,172.16.0.1\n\
+             dhcp-option=tag:default,option:classless-static-route,10.50.0.0/16,172.16.0.254\n\
+             dhcp-option=tag:default,option:domain-search,lab.local,corp.local\n\
+             dhcp-option=tag:default,option:ntp-server,172.16.0.10\n"
+        );
+
+        let subnet = kea(1, "default", &snapshot);
+        assert_eq!(subnet["subnet"], "172.16.0.0/24");
+        assert_eq!(
+            subnet["option-data"],
+            serde_json::json!([
+                {"name": "routers", "data": "172.16.0.1"},
+                {"name": "classless-static-route", "data": "10.50.0.0/16 - 172.16.0.254"},
+                {"name": "domain-search", "data": "lab.local, corp.local"},
+                {"name": "ntp-servers", "data": "172.16.0.10"},
+            ])
+        );
+
+        // Static mappings need the MAC and drop the domain of the hostname
+        let ip = pool.allocate_ip("web&db".to_string()).await.unwrap();
+        let allocation = pool.get_allocation("web&db").await.unwrap();
+        assert_eq!(StaticMapping::new("default", &allocation), None);
+        pool.set_mac(&ip, "52:54:00:12:34:56".to_string())
+            .await
+            .unwrap();
+        let allocation = pool.get_allocation("web&db").await.unwrap();
+        let mapping = StaticMapping::new("default", &allocation).unwrap();
+        assert_eq!(mapping.hostname, "web-db");
+        assert_eq!(
+            staticmap_xml(&[mapping]),
+            "<staticmap>\n\
+             \t<mac>52:54:00:12:34:56</mac>\n\
+             \t<ipaddr>172.16.0.2</ipaddr>\n\
+             \t<hostname>web-db</hostname>\n\
+             \t<descr>web&amp;db (default)</descr>\n\
+             </staticmap>\n"
+        );
+        pool.set_hostname(&ip, "web.lab.local".to_string())
+            .await
+            .unwrap();
+        let annotation = Annotation {
+            owner: None,
+            notes: Some("license server".to_string()),
+            ticket: Some("OPS-1234".to_string()),
+        };
+        pool.annotate(&ip, annotation).await.unwrap();
+        let allocation = pool.get_allocation("web&db").await.unwrap();
+        let mapping = StaticMapping::new("default", &allocation).unwrap();
+        assert_eq!(mapping.hostname, "web");
+        assert_eq!(mapping.descr, "web&db (default) OPS-1234: license server");
+    }
+}
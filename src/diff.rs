@@ -0,0 +1,167 @@
+use crate::ippool::{PRIMARY, PoolSnapshot};
+use crate::storage::StoredState;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+
+// What an allocation looked like in one snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AllocationState {
+    pub vm_id: String,
+    pub purpose: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AllocationEntry {
+    pub pool: String,
+    pub ip: String,
+    #[serde(flatten)]
+    pub allocation: AllocationState,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AllocationChange {
+    pub pool: String,
+    pub ip: String,
+    pub before: AllocationState,
+    pub after: AllocationState,
+}
+
+// Allocations that differ between two snapshots, keyed by pool and address
+// and listed in address order
+#[derive(Debug, Default, Serialize)]
+pub struct SnapshotDiff {
+    pub added: Vec<AllocationEntry>,
+    pub removed: Vec<AllocationEntry>,
+    pub changed: Vec<AllocationChange>, // other holder, lease, labels or slot
+    pub unchanged: usize,
+}
+
+pub fn diff(from: &StoredState, to: &StoredState) -> SnapshotDiff {
+    let before = allocations(from);
+    let after = allocations(to);
+    let mut diff = SnapshotDiff::default();
+
+    for ((pool, ip), old) in &before {
+        match after.get(&(pool.clone(), *ip)) {
+            None => diff.removed.push(entry(pool, *ip, old)),
+            Some(new) if new != old => diff.changed.push(AllocationChange {
+                pool: pool.clone(),
+                ip: ip.to_string(),
+                before: old.clone(),
+                after: new.clone(),
+            }),
+            Some(_) => diff.unchanged += 1,
+        }
+    }
+    for ((pool, ip), new) in &after {
+        if !before.contains_key(&(pool.clone(), *ip)) {
+            diff.added.push(entry(pool, *ip, new));
+        }
+    }
+    diff
+}
+
+fn entry(pool: &str, ip: Ipv4Addr, allocation: &AllocationState) -> AllocationEntry {
+    AllocationEntry {
+        pool: pool.to_string(),
+        ip: ip.to_string(),
+        allocation: allocation.clone(),
+    }
+}
+
+// Every allocation of every pool, ordered by pool and numeric address
+fn allocations(state: &StoredState) -> BTreeMap<(String, Ipv4Addr), AllocationState> {
+    let mut allocations = BTreeMap::new();
+    for (pool, snapshot) in state {
+        for (ip, vm_id) in &snapshot.allocations {
+            let Ok(address) = ip.parse() else {
+                continue;
+            };
+            allocations.insert((pool.clone(), address), state_of(snapshot, ip, vm_id));
+        }
+    }
+    allocations
+}
+
+fn state_of(snapshot: &PoolSnapshot, ip: &str, vm_id: &str) -> AllocationState {
+    AllocationState {
+        vm_id: vm_id.to_string(),
+        purpose: snapshot
+            .purposes
+            .get(ip)
+            .cloned()
+            .unwrap_or_else(|| PRIMARY.to_string()),
+        interface: snapshot.interfaces.get(ip).cloned(),
+        expires_at: snapshot.leases.get(ip).copied(),
+        labels: snapshot.labels.get(ip).cloned().unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ippool::IpPool;
+    use crate::pools::PoolRegistry;
+
+    #[tokio::test]
+    async fn test_diff_between_snapshots() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let pools = PoolRegistry::new(pool.clone());
+        for vm_id in ["vm-1", "vm-2", "vm-3"] {
+            pool.allocate_ip(vm_id.to_string()).await.unwrap();
+        }
+        let from = pools.snapshot().await;
+
+        // vm-1 leaves and vm-4 takes its address, vm-2 is relabelled,
+        // vm-5 is new and vm-3 stays as it was
+        pool.release_ip("vm-1").await.unwrap();
+        pool.allocate_ip("vm-4".to_string()).await.unwrap();
+        pool.label(
+            "172.16.0.3",
+            BTreeMap::from([("project".to_string(), "payments".to_string())]),
+        )
+        .await
+        .unwrap();
+        pool.allocate_ip("vm-5".to_string()).await.unwrap();
+        let to = pools.snapshot().await;
+
+        let diff = diff(&from, &to);
+        assert_eq!(diff.unchanged, 1);
+        assert!(diff.removed.is_empty());
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].ip, "172.16.0.5");
+        assert_eq!(diff.added[0].allocation.vm_id, "vm-5");
+
+        let changed: Vec<(&str, &str, &str)> = diff
+            .changed
+            .iter()
+            .map(|c| {
+                (
+                    c.ip.as_str(),
+                    c.before.vm_id.as_str(),
+                    c.after.vm_id.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            changed,
+            vec![
+                ("172.16.0.2", "vm-1", "vm-4"),
+                ("172.16.0.3", "vm-2", "vm-2")
+            ]
+        );
+        assert_eq!(diff.changed[1].after.labels["project"], "payments");
+
+        // The other way round, additions become removals
+        let reverse = super::diff(&to, &from);
+        assert_eq!(reverse.removed.len(), 1);
+        assert!(reverse.added.is_empty());
+    }
+}
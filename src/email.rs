@@ -0,0 +1,414 @@
+use crate::check::{self, CheckStatus, PoolUsage};
+use crate::events::EventBus;
+use crate::pools::PoolRegistry;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+
+// Plain SMTP client for an internal relay (no TLS or authentication), in
+// the spirit of the hand-rolled S3 and Consul clients
+#[derive(Debug, Clone)]
+pub struct Mailer {
+    host: String,
+    port: u16,
+    from: String,
+    to: Vec<String>,
+    timeout: Duration, // for the whole transaction
+}
+
+impl Mailer {
+    pub fn new(host: String, port: u16, from: String, to: Vec<String>) -> Self {
+        Mailer {
+            host,
+            port,
+            from,
+            to,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub async fn send(&self, subject: &str, body: &str) -> std::io::Result<()> {
+        tokio::time::timeout(self.timeout, self.transaction(subject, body))
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "SMTP server timed out"))?
+    }
+
+    async fn transaction(&self, subject: &str, body: &str) -> std::io::Result<()> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        expect(&mut reader, 2).await?;
+        command(&mut reader, &mut writer, "EHLO ippool", 2).await?;
+        command(
+            &mut reader,
+            &mut writer,
+            &format!("MAIL FROM:<{}>", self.from),
+            2,
+        )
+        .await?;
+        for to in &self.to {
+            command(&mut reader, &mut writer, &format!("RCPT TO:<{}>", to), 2).await?;
+        }
+        command(&mut reader, &mut writer, "DATA", 3).await?;
+        writer
+            .write_all(self.message(subject, body, Utc::now()).as_bytes())
+            .await?;
+        command(&mut reader, &mut writer, ".", 2).await?;
+        command(&mut reader, &mut writer, "QUIT", 2).await?;
+        Ok(())
+    }
+
+    // Headers and dot-stuffed body with CRLF line endings, without the
+    // terminating "."
+    fn message(&self, subject: &str, body: &str, now: DateTime<Utc>) -> String {
+        let mut message = String::new();
+        let _ = write!(
+            message,
+            "From: <{}>\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\n\
+             MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: 8bit\r\n\r\n",
+            self.from,
+            self.to
+                .iter()
+                .map(|to| format!("<{}>", to))
+                .collect::<Vec<_>>()
+                .join(", "),
+            subject.replace(['\r', '\n'], " "),
+            now.to_rfc2822()
+        );
+        for line in body.lines() {
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message
+    }
+}
+
+// Read a (possibly multi-line) reply and check its first digit
+async fn expect<R: AsyncBufReadExt + Unpin>(reader: &mut R, class: u8) -> std::io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "SMTP server closed the connection",
+            ));
+        }
+        let line = line.trim_end();
+        if line.len() < 3 || !line.is_char_boundary(3) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("bad SMTP reply: {}", line),
+            ));
+        }
+        if line.as_bytes()[0] != b'0' + class {
+            return Err(Error::other(format!("SMTP server refused: {}", line)));
+        }
+        // "250-..." continues, "250 ..." ends the reply
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+async fn command<R, W>(reader: &mut R, writer: &mut W, line: &str, class: u8) -> std::io::Result<()>
+where
+    R: AsyncBufReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    writer.write_all(format!("{}\r\n", line).as_bytes()).await?;
+    expect(reader, class).await
+}
+
+// How bad a pool looks, in increasing order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Level {
+    Ok,
+    Warning,
+    Critical,
+    Exhausted,
+}
+
+impl Level {
+    fn of(usage: &PoolUsage) -> Level {
+        if usage.allocated >= usage.total {
+            return Level::Exhausted;
+        }
+        match usage.status() {
+            CheckStatus::Warning => Level::Warning,
+            CheckStatus::Critical => Level::Critical,
+            _ => Level::Ok,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Level::Ok => "OK",
+            Level::Warning => "WARNING",
+            Level::Critical => "CRITICAL",
+            Level::Exhausted => "EXHAUSTED",
+        }
+    }
+}
+
+// Alert emails when a pool crosses its warning or critical threshold (the
+// template's, or 80% and 95%) or runs out of addresses. Each level is
+// mailed once per crossing: a pool has to drop back below it to be mailed
+// about it again.
+#[derive(Debug)]
+pub struct EmailAlerts {
+    mailer: Mailer,
+    levels: HashMap<String, Level>, // last level seen per pool
+}
+
+impl EmailAlerts {
+    pub fn new(mailer: Mailer) -> Self {
+        EmailAlerts {
+            mailer,
+            levels: HashMap::new(),
+        }
+    }
+
+    // The new level when the pool got worse
+    fn escalated(&mut self, usage: &PoolUsage) -> Option<Level> {
+        let level = Level::of(usage);
+        let previous = self
+            .levels
+            .insert(usage.name.clone(), level)
+            .unwrap_or(Level::Ok);
+        (level > previous).then_some(level)
+    }
+
+    // Check the affected pool after every event until the bus goes away
+    pub async fn run(mut self, pools: PoolRegistry, events: EventBus) {
+        let mut receiver = events.subscribe();
+        loop {
+            let name = match receiver.recv().await {
+                Ok(event) => event.pool,
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("Email alerts fell behind, {} events skipped", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let Some(usage) = pool_usage(&pools, name).await else {
+                continue;
+            };
+            let Some(level) = self.escalated(&usage) else {
+                continue;
+            };
+
+            let subject = format!("[ippool] {} {}", usage.name, level.label());
+            let body = format!(
+                "Pool {} is {}: {:.1}% used ({}/{}), {} addresses available.\n\
+                 Thresholds: warning {}%, critical {}%.\n",
+                usage.name,
+                level.label(),
+                usage.percent(),
+                usage.allocated,
+                usage.total,
+                usage.total.saturating_sub(usage.allocated),
+                usage.thresholds.warn,
+                usage.thresholds.crit
+            );
+            tracing::warn!("📧 Mailing alert: {}", subject);
+            if let Err(e) = self.mailer.send(&subject, &body).await {
+                tracing::error!("Alert email for pool {} failed: {}", usage.name, e);
+            }
+        }
+    }
+}
+
+async fn pool_usage(pools: &PoolRegistry, name: String) -> Option<PoolUsage> {
+    let pool = pools.get(&name).await.ok()?;
+    let stats = pool.get_stats().await;
+    Some(PoolUsage {
+        name,
+        allocated: stats["allocated"].as_u64().unwrap_or(0),
+        total: stats["total"].as_u64().unwrap_or(0),
+        thresholds: pool.thresholds().await.unwrap_or_default(),
+    })
+}
+
+// Subject and body of the utilization digest: one line per pool, then the
+// monitoring check line
+fn digest(pools: &[PoolUsage], now: DateTime<Utc>) -> (String, String) {
+    let (status, line) = check::evaluate(pools);
+    let mut body = format!(
+        "Pool utilization as of {}\n\n{:<20} {:>12} {:>7}  {}\n",
+        now.format("%Y-%m-%d %H:%M UTC"),
+        "pool",
+        "allocated",
+        "usage",
+        "status"
+    );
+    for pool in pools {
+        let _ = writeln!(
+            body,
+            "{:<20} {:>12} {:>6.1}%  {}",
+            pool.name,
+            format!("{}/{}", pool.allocated, pool.total),
+            pool.percent(),
+            Level::of(pool).label()
+        );
+    }
+    let _ = write!(body, "\n{}\n", line);
+    (
+        format!("[ippool] Daily utilization digest: {}", status.label()),
+        body,
+    )
+}
+
+// Time until the next `hour`:00 UTC
+fn until(now: DateTime<Utc>, hour: u32) -> Duration {
+    let today = now
+        .date_naive()
+        .and_hms_opt(hour, 0, 0)
+        .unwrap_or_default()
+        .and_utc();
+    let next = if today > now {
+        today
+    } else {
+        today + chrono::Duration::days(1)
+    };
+    (next - now).to_std().unwrap_or_default()
+}
+
+// Mail the utilization digest every day at `hour`:00 UTC
+pub async fn run_digest(mailer: Mailer, hour: u32, pools: PoolRegistry) {
+    loop {
+        tokio::time::sleep(until(Utc::now(), hour)).await;
+
+        let mut usage = Vec::new();
+        for name in pools.names().await {
+            usage.extend(pool_usage(&pools, name).await);
+        }
+        let (subject, body) = digest(&usage, Utc::now());
+        tracing::info!("📧 Mailing utilization digest");
+        if let Err(e) = mailer.send(&subject, &body).await {
+            tracing::error!("Utilization digest email failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check::Thresholds;
+    use tokio::net::TcpListener;
+
+    fn usage(name: &str, allocated: u64) -> PoolUsage {
+        PoolUsage {
+            name: name.to_string(),
+            allocated,
+            total: 100,
+            thresholds: Thresholds::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_smtp_transaction() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Minimal relay recording everything the client sends
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut received = Vec::new();
+            writer.write_all(b"220 relay ready\r\n").await.unwrap();
+            let mut data = false;
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let reply: &[u8] = if data {
+                    data = line != ".";
+                    if data { b"" } else { b"250 queued\r\n" }
+                } else if line.starts_with("EHLO") {
+                    b"250-relay\r\n250 8BITMIME\r\n"
+                } else if line == "DATA" {
+                    data = true;
+                    b"354 go ahead\r\n"
+                } else if line == "QUIT" {
+                    b"221 bye\r\n"
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).await.unwrap();
+                received.push(line);
+            }
+            received
+        });
+
+        let mailer = Mailer::new(
+            "127.0.0.1".to_string(),
+            port,
+            "ippool@example.com".to_string(),
+            vec!["ops@example.com".to_string(), "net@example.com".to_string()],
+        );
+        mailer
+            .send("[ippool] lab\r\nBcc: x", "Pool lab is full.\n.hidden\n")
+            .await
+            .unwrap();
+
+        let received = server.await.unwrap();
+        assert_eq!(received[0], "EHLO ippool");
+        assert_eq!(received[1], "MAIL FROM:<ippool@example.com>");
+        assert_eq!(received[2], "RCPT TO:<ops@example.com>");
+        assert_eq!(received[3], "RCPT TO:<net@example.com>");
+        assert!(received.contains(&"Subject: [ippool] lab  Bcc: x".to_string()));
+        assert!(received.contains(&"..hidden".to_string()));
+        assert_eq!(received.last().unwrap(), "QUIT");
+    }
+
+    #[tokio::test]
+    async fn test_refused_by_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"554 go away\r\n").await.unwrap();
+        });
+        let mailer = Mailer::new("127.0.0.1".to_string(), port, "a@b".into(), vec![]);
+        let error = mailer.send("subject", "body").await.unwrap_err();
+        assert!(error.to_string().contains("554 go away"));
+    }
+
+    #[test]
+    fn test_alert_once_per_crossing() {
+        let mailer = Mailer::new("127.0.0.1".to_string(), 25, "a@b".into(), vec![]);
+        let mut alerts = EmailAlerts::new(mailer);
+
+        assert_eq!(alerts.escalated(&usage("lab", 50)), None);
+        assert_eq!(alerts.escalated(&usage("lab", 85)), Some(Level::Warning));
+        assert_eq!(alerts.escalated(&usage("lab", 90)), None);
+        assert_eq!(alerts.escalated(&usage("lab", 100)), Some(Level::Exhausted));
+        // Getting better is not mailed, getting worse again is
+        assert_eq!(alerts.escalated(&usage("lab", 96)), None);
+        assert_eq!(alerts.escalated(&usage("lab", 100)), Some(Level::Exhausted));
+        assert_eq!(alerts.escalated(&usage("dmz", 97)), Some(Level::Critical));
+    }
+
+    #[test]
+    fn test_digest() {
+        let now = DateTime::parse_from_rfc3339("2026-01-02T08:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let (subject, body) = digest(&[usage("default", 12), usage("lab", 85)], now);
+        assert_eq!(subject, "[ippool] Daily utilization digest: WARNING");
+        assert!(body.starts_with("Pool utilization as of 2026-01-02 08:00 UTC\n"));
+        assert!(body.contains("lab                        85/100   85.0%  WARNING\n"));
+        assert!(body.ends_with("'lab'=85.0%;80;95;0;100\n"));
+
+        assert_eq!(until(now, 8), Duration::from_secs(24 * 3600));
+        assert_eq!(until(now, 9), Duration::from_secs(3600));
+        assert_eq!(until(now, 7), Duration::from_secs(23 * 3600));
+    }
+}
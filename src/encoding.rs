@@ -0,0 +1,163 @@
+use axum::{
+    body::{Body, to_bytes},
+    extract::Request,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+// Largest JSON response that will be re-encoded
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::MessagePack => "application/msgpack",
+            Encoding::Cbor => "application/cbor",
+        }
+    }
+
+    // Preferred encoding from an Accept header: highest quality wins, ties
+    // go to the range listed first, anything unknown means JSON
+    pub fn from_accept(accept: &str) -> Self {
+        let mut best: Option<(Encoding, f32)> = None;
+
+        for range in accept.split(',') {
+            let mut parts = range.split(';');
+            let media = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            let encoding = match media.as_str() {
+                "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                    Encoding::MessagePack
+                }
+                "application/cbor" => Encoding::Cbor,
+                "application/json" | "application/*" | "*/*" => Encoding::Json,
+                _ => continue,
+            };
+            if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+                best = Some((encoding, quality));
+            }
+        }
+
+        best.map(|(encoding, _)| encoding).unwrap_or(Encoding::Json)
+    }
+
+    fn encode(self, value: &serde_json::Value) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Encoding::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+            Encoding::Cbor => {
+                let mut out = Vec::new();
+                ciborium::into_writer(value, &mut out).map_err(|e| e.to_string())?;
+                Ok(out)
+            }
+        }
+    }
+}
+
+fn requested(headers: &HeaderMap) -> Encoding {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(Encoding::from_accept)
+        .unwrap_or(Encoding::Json)
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"))
+}
+
+// Re-encode JSON responses as MessagePack or CBOR when the client asks for
+// it via Accept
+pub async fn negotiate(request: Request, next: Next) -> Response {
+    let encoding = requested(request.headers());
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept"));
+
+    if encoding == Encoding::Json || !is_json(response.headers()) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let encoded = to_bytes(body, MAX_BODY_BYTES)
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| serde_json::from_slice(&bytes).map_err(|e| e.to_string()))
+        .and_then(|value| encoding.encode(&value));
+
+    match encoded {
+        Ok(bytes) => {
+            parts.headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(encoding.content_type()),
+            );
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(bytes))
+        }
+        Err(e) => {
+            tracing::error!("Failed to encode response as {:?}: {}", encoding, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to encode response",
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_accept() {
+        assert_eq!(Encoding::from_accept("application/json"), Encoding::Json);
+        assert_eq!(Encoding::from_accept("*/*"), Encoding::Json);
+        assert_eq!(
+            Encoding::from_accept("application/msgpack"),
+            Encoding::MessagePack
+        );
+        assert_eq!(
+            Encoding::from_accept("application/cbor, application/json;q=0.5"),
+            Encoding::Cbor
+        );
+        assert_eq!(
+            Encoding::from_accept("application/json, application/msgpack;q=0.8"),
+            Encoding::Json
+        );
+        assert_eq!(
+            Encoding::from_accept("application/json, application/cbor"),
+            Encoding::Json
+        );
+    }
+
+    #[test]
+    fn test_encodings_roundtrip() {
+        let value = serde_json::json!({"ip": "172.16.0.2", "usage": 0.4, "frozen": false});
+
+        let packed = Encoding::MessagePack.encode(&value).unwrap();
+        let decoded: serde_json::Value = rmp_serde::from_slice(&packed).unwrap();
+        assert_eq!(decoded, value);
+
+        let cbor = Encoding::Cbor.encode(&value).unwrap();
+        let decoded: serde_json::Value = ciborium::from_reader(cbor.as_slice()).unwrap();
+        assert_eq!(decoded, value);
+    }
+}
@@ -0,0 +1,137 @@
+use axum::{
+    body::{Body, to_bytes},
+    extract::Request,
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+
+// Largest response that gets an ETag
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+// Strong validator of a response: a hash of its body and of the Accept
+// header, since the same state is served as JSON, MessagePack or CBOR
+pub fn etag(accept: Option<&HeaderValue>, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(accept.map(HeaderValue::as_bytes).unwrap_or_default());
+    hasher.update([0]);
+    hasher.update(body);
+    format!("\"{}\"", hex::encode(&hasher.finalize()[..16]))
+}
+
+// Weak validator of a response that also carries values moving with time
+// alone, e.g. rates over a sliding window. Only the `stable` rest of the
+// body is hashed, so the tag changes with the state and nothing else.
+pub fn weak(accept: Option<&HeaderValue>, stable: &[u8]) -> String {
+    format!("W/{}", etag(accept, stable))
+}
+
+// If-None-Match uses the weak comparison (RFC 9110 13.1.2)
+fn none_match(headers: &HeaderMap, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+// Conditional GETs for endpoints that are polled: successful responses
+// carry an ETag, and a request whose If-None-Match has it again is answered
+// 304 without a body. A tag the handler set itself, see weak(), is kept.
+pub async fn conditional(request: Request, next: Next) -> Response {
+    if !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return next.run(request).await;
+    }
+    let headers = request.headers().clone();
+    let response = next.run(request).await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match to_bytes(body, MAX_BODY_BYTES).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!("Failed to buffer response for its ETag: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to buffer response",
+            )
+                .into_response();
+        }
+    };
+    let etag = match parts.headers.get(header::ETAG) {
+        Some(tag) => tag.to_str().unwrap_or_default().to_string(),
+        None => {
+            let etag = etag(headers.get(header::ACCEPT), &bytes);
+            let value = HeaderValue::from_str(&etag).expect("hex ETag is a valid header value");
+            parts.headers.insert(header::ETAG, value);
+            etag
+        }
+    };
+
+    if none_match(&headers, &etag) {
+        parts.status = StatusCode::NOT_MODIFIED;
+        parts.headers.remove(header::CONTENT_TYPE);
+        parts.headers.remove(header::CONTENT_LENGTH);
+        return Response::from_parts(parts, Body::empty());
+    }
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, middleware, routing::get};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_conditional_get() {
+        let version = Arc::new(AtomicU64::new(1));
+        let state = version.clone();
+        let stats =
+            move || async move { format!("{{\"version\":{}}}", state.load(Ordering::SeqCst)) };
+        let app = Router::new()
+            .route("/stats", get(stats))
+            .layer(middleware::from_fn(conditional));
+
+        let get = |if_none_match: Option<&str>| {
+            let mut request = Request::get("/stats");
+            if let Some(tag) = if_none_match {
+                request = request.header(header::IF_NONE_MATCH, tag);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let first = get(None).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        let tag = first.headers()[header::ETAG].to_str().unwrap().to_string();
+
+        let unchanged = get(Some(&tag)).await.unwrap();
+        assert_eq!(unchanged.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(unchanged.headers()[header::ETAG], tag.as_str());
+        let body = to_bytes(unchanged.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+        let listed = format!("\"other\", W/{}", tag);
+        assert_eq!(
+            get(Some(&listed)).await.unwrap().status(),
+            StatusCode::NOT_MODIFIED
+        );
+
+        // A change gives a new ETag and the full body again
+        version.store(2, Ordering::SeqCst);
+        let changed = get(Some(&tag)).await.unwrap();
+        assert_eq!(changed.status(), StatusCode::OK);
+        assert_ne!(changed.headers()[header::ETAG], tag.as_str());
+
+        // Representations differ by Accept, and so do their ETags
+        let json = HeaderValue::from_static("application/json");
+        assert_ne!(etag(Some(&json), b"{}"), etag(None, b"{}"));
+    }
+}
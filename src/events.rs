@@ -0,0 +1,302 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{RwLock, broadcast};
+
+// Default number of events kept for the recent events listing and replay
+pub const MAX_RECENT_EVENTS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Allocated,
+    Released,
+    Migrated,
+    Expired,
+    Expiring, // lease about to expire, see LEASE_EXPIRY_WARNING
+    Anomaly,  // suspicious allocation pattern, see insights
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub id: u64,
+    pub timestamp: u64, // unix seconds
+    pub kind: EventKind,
+    pub pool: String,
+    pub vm_id: String,
+    pub ip: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+// Retained events as JSON lines, so replay survives restarts. Appended on
+// every event and rewritten with just the retained ones once it holds
+// twice as many.
+#[derive(Debug)]
+struct EventStore {
+    path: PathBuf,
+    file: File,
+    lines: usize,
+}
+
+impl EventStore {
+    // Open the store, returning it with the last `retain` events it holds
+    fn open(path: PathBuf, retain: usize) -> std::io::Result<(Self, VecDeque<Event>)> {
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut events = VecDeque::new();
+        let mut lines = 0;
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            lines += 1;
+            match serde_json::from_str::<Event>(line) {
+                Ok(event) => {
+                    if events.len() == retain {
+                        events.pop_front();
+                    }
+                    events.push_back(event);
+                }
+                Err(e) => tracing::warn!("Skipping unreadable stored event: {}", e),
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok((EventStore { path, file, lines }, events))
+    }
+
+    fn append(&mut self, event: &Event, retained: &VecDeque<Event>) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.flush()?;
+        self.lines += 1;
+
+        if self.lines > retained.len().max(1) * 2 {
+            self.compact(retained)?;
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self, retained: &VecDeque<Event>) -> std::io::Result<()> {
+        let mut contents = Vec::new();
+        for event in retained {
+            contents.extend(serde_json::to_vec(event)?);
+            contents.push(b'\n');
+        }
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.lines = retained.len();
+        Ok(())
+    }
+}
+
+// Allocation event stream: a broadcast channel for live subscribers plus
+// a bounded buffer of the most recent events, optionally persisted for
+// replay across restarts.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+    recent: Arc<RwLock<VecDeque<Event>>>,
+    next_id: Arc<AtomicU64>,
+    retain: usize,
+    store: Option<Arc<Mutex<EventStore>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::with_retain(MAX_RECENT_EVENTS)
+    }
+
+    // Keep the last `retain` events for listing and replay
+    pub fn with_retain(retain: usize) -> Self {
+        let (sender, _) = broadcast::channel(256);
+        EventBus {
+            sender,
+            recent: Arc::new(RwLock::new(VecDeque::new())),
+            next_id: Arc::new(AtomicU64::new(1)),
+            retain: retain.max(1),
+            store: None,
+        }
+    }
+
+    // Like `with_retain`, persisting events in `path` and picking up the
+    // events and id sequence stored there
+    pub fn with_store(path: PathBuf, retain: usize) -> std::io::Result<Self> {
+        let mut bus = Self::with_retain(retain);
+        let (store, events) = EventStore::open(path, bus.retain)?;
+        let next_id = events.back().map(|event| event.id + 1).unwrap_or(1);
+        bus.recent = Arc::new(RwLock::new(events));
+        bus.next_id = Arc::new(AtomicU64::new(next_id));
+        bus.store = Some(Arc::new(Mutex::new(store)));
+        Ok(bus)
+    }
+
+    pub async fn emit(
+        &self,
+        kind: EventKind,
+        pool: &str,
+        vm_id: &str,
+        ip: &str,
+        details: Option<serde_json::Value>,
+    ) -> Event {
+        // Ids are taken under the lock so the buffer, the store and the
+        // stream all see them in order, which replay cursors rely on
+        let mut recent = self.recent.write().await;
+        let event = Event {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            timestamp: unix_now(),
+            kind,
+            pool: pool.to_string(),
+            vm_id: vm_id.to_string(),
+            ip: ip.to_string(),
+            details,
+        };
+
+        if recent.len() == self.retain {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+        if let Some(store) = &self.store
+            && let Err(e) = store.lock().unwrap().append(&event, &recent)
+        {
+            tracing::error!("Failed to store event {}: {}", event.id, e);
+        }
+
+        // No subscribers is fine, the event is still kept in `recent`
+        let _ = self.sender.send(event.clone());
+        event
+    }
+
+    // Live stream of events emitted from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    // Most recent events, newest first
+    pub async fn recent(&self, limit: usize) -> Vec<Event> {
+        let recent = self.recent.read().await;
+        recent.iter().rev().take(limit).cloned().collect()
+    }
+
+    // Up to `limit` events after cursor `since` (the id of the last event
+    // seen, 0 for none), oldest first. None when events after the cursor
+    // are no longer retained, or the cursor is from before the id sequence
+    // restarted: the consumer has to resync.
+    pub async fn replay(&self, since: u64, limit: usize) -> Option<Vec<Event>> {
+        let recent = self.recent.read().await;
+        let last = self.next_id.load(Ordering::SeqCst) - 1;
+        let oldest = recent.front().map(|event| event.id).unwrap_or(last + 1);
+        if since > last || since + 1 < oldest {
+            return None;
+        }
+        Some(
+            recent
+                .iter()
+                .filter(|event| event.id > since)
+                .take(limit)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    // Id of the latest event, 0 before the first one
+    pub fn last_id(&self) -> u64 {
+        self.next_id.load(Ordering::SeqCst) - 1
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn emit(bus: &EventBus, count: usize) {
+        for i in 0..count {
+            bus.emit(
+                EventKind::Allocated,
+                "default",
+                &format!("vm-{}", i),
+                "172.16.0.2",
+                None,
+            )
+            .await;
+        }
+    }
+
+    fn ids(events: &[Event]) -> Vec<u64> {
+        events.iter().map(|event| event.id).collect()
+    }
+
+    #[tokio::test]
+    async fn test_replay_after_cursor() {
+        let bus = EventBus::with_retain(5);
+        assert_eq!(bus.replay(0, 10).await.map(|e| ids(&e)), Some(vec![]));
+        emit(&bus, 3).await;
+
+        assert_eq!(
+            bus.replay(0, 10).await.map(|e| ids(&e)),
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(bus.replay(1, 1).await.map(|e| ids(&e)), Some(vec![2]));
+        assert_eq!(bus.replay(3, 10).await.map(|e| ids(&e)), Some(vec![]));
+        // A cursor from before a restart without a store
+        assert!(bus.replay(4, 10).await.is_none());
+
+        // Events 1-3 fall out of the window: only cursors at 3 or later
+        // can still catch up
+        emit(&bus, 5).await;
+        assert!(bus.replay(2, 10).await.is_none());
+        assert_eq!(
+            bus.replay(3, 10).await.map(|e| ids(&e)),
+            Some(vec![4, 5, 6, 7, 8])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store_survives_restart() {
+        let path = std::env::temp_dir().join(format!("ippool-events-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let bus = EventBus::with_store(path.clone(), 3).unwrap();
+        emit(&bus, 10).await;
+        drop(bus);
+
+        // Compaction keeps the file bounded
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines <= 6, "{} lines stored", lines);
+
+        let bus = EventBus::with_store(path.clone(), 3).unwrap();
+        assert_eq!(bus.last_id(), 10);
+        assert_eq!(
+            bus.replay(7, 10).await.map(|e| ids(&e)),
+            Some(vec![8, 9, 10])
+        );
+        emit(&bus, 1).await;
+        assert_eq!(bus.replay(10, 10).await.map(|e| ids(&e)), Some(vec![11]));
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::api_v2::Problem;
+use crate::handlers::JsonBody;
+use crate::ippool::IpPoolError;
+use axum::{
+    Json, Router,
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+// Allocation endpoints that injected failures and exhaustion apply to
+const ALLOCATION_PATHS: [&str; 3] = [
+    "/api/v1/ip/allocate",
+    "/api/v1/admin/ip/allocate",
+    "/api/v2/allocations",
+];
+
+// Never delayed or failed, so faults can always be inspected and cleared
+const EXEMPT_PATHS: [&str; 2] = ["/api/v1/admin/faults", "/api/v1/health"];
+
+// Faults injected into API requests, for client teams testing their retry
+// and fallback logic. Only compiled in with the fault-injection feature.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaultConfig {
+    pub latency_ms: u64,        // added to every request
+    pub latency_jitter_ms: u64, // plus up to this much at random
+    pub failure_rate: f64,      // share of allocations failing with 503, 0.0 - 1.0
+    pub exhausted: bool,        // every allocation finds the pool empty
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    config: Arc<RwLock<FaultConfig>>,
+}
+
+impl Faults {
+    pub fn new(config: FaultConfig) -> Self {
+        Faults {
+            config: Arc::new(RwLock::new(config)),
+        }
+    }
+
+    pub fn config(&self) -> FaultConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn set(&self, config: FaultConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    fn delay(&self) -> Duration {
+        let config = self.config();
+        let jitter = match config.latency_jitter_ms {
+            0 => 0,
+            max => fastrand::u64(0..=max),
+        };
+        Duration::from_millis(config.latency_ms + jitter)
+    }
+
+    // Error an allocation request fails with, if any
+    fn allocation_fault(&self) -> Option<IpPoolError> {
+        let config = self.config();
+        if config.exhausted {
+            return Some(IpPoolError::NoAvailableIps);
+        }
+        (fastrand::f64() < config.failure_rate)
+            .then(|| IpPoolError::StorageUnavailable("injected failure".to_string()))
+    }
+}
+
+pub fn router(faults: Faults) -> Router {
+    Router::new()
+        .route(
+            "/api/v1/admin/faults",
+            get(get_faults).post(set_faults).delete(clear_faults),
+        )
+        .with_state(faults)
+}
+
+async fn get_faults(State(faults): State<Faults>) -> Json<FaultConfig> {
+    Json(faults.config())
+}
+
+async fn set_faults(
+    State(faults): State<Faults>,
+    JsonBody(config): JsonBody<FaultConfig>,
+) -> Json<FaultConfig> {
+    tracing::warn!("💥 Fault injection changed: {:?}", config);
+    faults.set(config.clone());
+    Json(config)
+}
+
+async fn clear_faults(State(faults): State<Faults>) -> Json<FaultConfig> {
+    tracing::info!("Fault injection cleared");
+    faults.set(FaultConfig::default());
+    Json(FaultConfig::default())
+}
+
+pub async fn inject(State(faults): State<Faults>, request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    if EXEMPT_PATHS.contains(&path) {
+        return next.run(request).await;
+    }
+
+    let delay = faults.delay();
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+
+    if request.method() == Method::POST
+        && ALLOCATION_PATHS.contains(&path)
+        && let Some(e) = faults.allocation_fault()
+    {
+        tracing::warn!("💥 Injecting fault into {}: {}", path, e);
+        if path.starts_with("/api/v2/") {
+            return Problem::from(e).into_response();
+        }
+        return e.into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::StatusCode, middleware, routing::post};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_injected_faults() {
+        let faults = Faults::default();
+        let app = Router::new()
+            .route("/api/v1/ip/allocate", post(|| async { "allocated" }))
+            .route("/api/v1/ip/stats", get(|| async { "stats" }))
+            .layer(middleware::from_fn_with_state(faults.clone(), inject));
+
+        let status = |method: Method, uri: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(axum::body::Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+
+        assert_eq!(
+            status(Method::POST, "/api/v1/ip/allocate").await,
+            StatusCode::OK
+        );
+
+        faults.set(FaultConfig {
+            failure_rate: 1.0,
+            ..FaultConfig::default()
+        });
+        assert_eq!(
+            status(Method::POST, "/api/v1/ip/allocate").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        // Only allocations fail
+        assert_eq!(
+            status(Method::GET, "/api/v1/ip/stats").await,
+            StatusCode::OK
+        );
+
+        faults.set(FaultConfig {
+            latency_ms: 20,
+            exhausted: true,
+            ..FaultConfig::default()
+        });
+        let started = std::time::Instant::now();
+        assert_eq!(
+            status(Method::POST, "/api/v1/ip/allocate").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+}
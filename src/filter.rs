@@ -0,0 +1,483 @@
+use crate::handlers::parse_duration;
+use crate::ippool::IpAllocation;
+use std::net::Ipv4Addr;
+
+// Deepest nesting of parentheses and negations accepted
+const MAX_DEPTH: usize = 32;
+
+// Filter expression over allocations, e.g.
+// `label.env == "prod" && lease.expires < now+1h`. Comparisons combine with
+// `&&`, `||`, `!` and parentheses.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Compare(Field, Op, Value),
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Field {
+    VmId,
+    Ip,
+    Pool,
+    Purpose,
+    Interface,
+    Hostname,
+    Mac,
+    Owner,
+    Notes,
+    Ticket,
+    Label(String),
+    Reserved,
+    External,
+    Expires,
+    RenewBefore,
+    HardExpires,
+    PendingRelease,
+    FenceToken,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Text,
+    Ip,
+    Time, // unix seconds, or `now` plus or minus a duration
+    Number,
+    Bool,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        if let Some(key) = name.strip_prefix("label.")
+            && !key.is_empty()
+        {
+            return Some(Field::Label(key.to_string()));
+        }
+        Some(match name {
+            "vm_id" => Field::VmId,
+            "ip" => Field::Ip,
+            "pool" => Field::Pool,
+            "purpose" => Field::Purpose,
+            "interface" => Field::Interface,
+            "hostname" => Field::Hostname,
+            "mac" => Field::Mac,
+            "owner" => Field::Owner,
+            "notes" => Field::Notes,
+            "ticket" => Field::Ticket,
+            "reserved" => Field::Reserved,
+            "external" => Field::External,
+            "lease.expires" | "expires_at" => Field::Expires,
+            "lease.renew_before" | "renew_before" => Field::RenewBefore,
+            "hard_expires_at" => Field::HardExpires,
+            "pending_release_at" => Field::PendingRelease,
+            "fence_token" => Field::FenceToken,
+            _ => return None,
+        })
+    }
+
+    fn kind(&self) -> Kind {
+        match self {
+            Field::Ip => Kind::Ip,
+            Field::Reserved | Field::External => Kind::Bool,
+            Field::Expires | Field::RenewBefore | Field::HardExpires | Field::PendingRelease => {
+                Kind::Time
+            }
+            Field::FenceToken => Kind::Number,
+            _ => Kind::Text,
+        }
+    }
+
+    fn value(&self, pool: &str, allocation: &IpAllocation) -> Option<Value> {
+        let text = |value: &Option<String>| value.clone().map(Value::Text);
+        match self {
+            Field::VmId => Some(Value::Text(allocation.vm_id.clone())),
+            Field::Ip => allocation.ip.parse().ok().map(Value::Ip),
+            Field::Pool => Some(Value::Text(pool.to_string())),
+            Field::Purpose => text(&allocation.purpose),
+            Field::Interface => text(&allocation.interface),
+            Field::Hostname => text(&allocation.hostname),
+            Field::Mac => text(&allocation.mac),
+            Field::Owner => text(&allocation.owner),
+            Field::Notes => text(&allocation.notes),
+            Field::Ticket => text(&allocation.ticket),
+            Field::Label(key) => allocation.labels.get(key).cloned().map(Value::Text),
+            Field::Reserved => Some(Value::Bool(allocation.reserved)),
+            Field::External => Some(Value::Bool(allocation.external)),
+            Field::Expires => allocation.expires_at.map(Value::Number),
+            Field::RenewBefore => allocation.renew_before.map(Value::Number),
+            Field::HardExpires => allocation.hard_expires_at.map(Value::Number),
+            Field::PendingRelease => allocation.pending_release_at.map(Value::Number),
+            Field::FenceToken => allocation.fence_token.map(Value::Number),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains, // `~`, substring of a text field
+}
+
+impl Op {
+    fn parse(op: &str) -> Option<Self> {
+        Some(match op {
+            "==" => Op::Eq,
+            "!=" => Op::Ne,
+            "<" => Op::Lt,
+            "<=" => Op::Le,
+            ">" => Op::Gt,
+            ">=" => Op::Ge,
+            "~" => Op::Contains,
+            _ => return None,
+        })
+    }
+
+    fn applies_to(self, kind: Kind) -> bool {
+        match self {
+            Op::Eq | Op::Ne => true,
+            Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+                matches!(kind, Kind::Ip | Kind::Time | Kind::Number)
+            }
+            Op::Contains => kind == Kind::Text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Value {
+    Text(String),
+    Ip(Ipv4Addr),
+    Number(u64),
+    Bool(bool),
+}
+
+impl Value {
+    fn parse(token: &str, kind: Kind, now: u64) -> Option<Self> {
+        match kind {
+            Kind::Text => Some(Value::Text(token.to_string())),
+            Kind::Ip => token.parse().ok().map(Value::Ip),
+            Kind::Number => token.parse().ok().map(Value::Number),
+            Kind::Bool => token.parse().ok().map(Value::Bool),
+            Kind::Time => {
+                let Some(offset) = token.strip_prefix("now") else {
+                    return token.parse().ok().map(Value::Number);
+                };
+                let time = match offset.split_at_checked(1) {
+                    None => now,
+                    Some(("+", duration)) => {
+                        now.checked_add(parse_duration(duration)?.as_secs())?
+                    }
+                    Some(("-", duration)) => {
+                        now.saturating_sub(parse_duration(duration)?.as_secs())
+                    }
+                    Some(_) => return None,
+                };
+                Some(Value::Number(time))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),   // field names and bare values
+    Quoted(String), // "..." values
+    Op(Op),
+    Not,
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-' | '+' | ':' | '/')
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '~' => Token::Op(Op::Contains),
+            '&' | '|' => {
+                if chars.next_if_eq(&c).is_none() {
+                    return Err(format!("expected `{0}{0}`", c));
+                }
+                if c == '&' { Token::And } else { Token::Or }
+            }
+            '=' | '!' | '<' | '>' => {
+                let op = match chars.next_if_eq(&'=') {
+                    Some(_) => format!("{}=", c),
+                    None => c.to_string(),
+                };
+                match (op.as_str(), Op::parse(&op)) {
+                    ("!", _) => Token::Not,
+                    (_, Some(op)) => Token::Op(op),
+                    (op, None) => return Err(format!("unknown operator `{}`, use `==`", op)),
+                }
+            }
+            '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => value.extend(chars.next()),
+                        Some(c) => value.push(c),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                Token::Quoted(value)
+            }
+            c if is_word_char(c) => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|c| is_word_char(*c)) {
+                    word.push(c);
+                }
+                Token::Word(word)
+            }
+            c => return Err(format!("unexpected `{}`", c)),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: std::vec::IntoIter<Token>,
+    peeked: Option<Token>,
+    now: u64,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&mut self) -> Option<&Token> {
+        if self.peeked.is_none() {
+            self.peeked = self.tokens.next();
+        }
+        self.peeked.as_ref()
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        self.peek();
+        self.peeked.take()
+    }
+
+    fn or(&mut self) -> Result<Filter, String> {
+        let mut filter = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, String> {
+        let mut filter = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            filter = Filter::And(Box::new(filter), Box::new(self.unary()?));
+        }
+        Ok(filter)
+    }
+
+    fn unary(&mut self) -> Result<Filter, String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err("expression nested too deeply".to_string());
+        }
+        let filter = match self.next() {
+            Some(Token::Not) => Filter::Not(Box::new(self.unary()?)),
+            Some(Token::Open) => {
+                let filter = self.or()?;
+                if self.next() != Some(Token::Close) {
+                    return Err("expected `)`".to_string());
+                }
+                filter
+            }
+            Some(Token::Word(name)) => self.comparison(&name)?,
+            Some(token) => return Err(format!("expected a field, found {}", describe(&token))),
+            None => return Err("unexpected end of filter".to_string()),
+        };
+        self.depth -= 1;
+        Ok(filter)
+    }
+
+    fn comparison(&mut self, name: &str) -> Result<Filter, String> {
+        let field = Field::parse(name).ok_or_else(|| format!("unknown field `{}`", name))?;
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            _ => return Err(format!("expected an operator after `{}`", name)),
+        };
+        if !op.applies_to(field.kind()) {
+            return Err(format!("operator does not apply to `{}`", name));
+        }
+        let token = match self.next() {
+            Some(Token::Word(token) | Token::Quoted(token)) => token,
+            _ => return Err(format!("expected a value to compare `{}` with", name)),
+        };
+        let value = Value::parse(&token, field.kind(), self.now)
+            .ok_or_else(|| format!("`{}` is not a valid value for `{}`", token, name))?;
+        Ok(Filter::Compare(field, op, value))
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Word(word) => format!("`{}`", word),
+        Token::Quoted(value) => format!("\"{}\"", value),
+        Token::Op(_) => "an operator".to_string(),
+        Token::Not => "`!`".to_string(),
+        Token::And => "`&&`".to_string(),
+        Token::Or => "`||`".to_string(),
+        Token::Open => "`(`".to_string(),
+        Token::Close => "`)`".to_string(),
+    }
+}
+
+impl Filter {
+    // `now` (unix seconds) is what `now` in the expression stands for
+    pub fn parse(source: &str, now: u64) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?.into_iter(),
+            peeked: None,
+            now,
+            depth: 0,
+        };
+        let filter = parser.or()?;
+        match parser.next() {
+            None => Ok(filter),
+            Some(token) => Err(format!("unexpected {}", describe(&token))),
+        }
+    }
+
+    // Comparisons with a field the allocation does not have, like the
+    // expiry of a lease that never expires, only hold for `!=`
+    pub fn matches(&self, pool: &str, allocation: &IpAllocation) -> bool {
+        match self {
+            Filter::Compare(field, op, expected) => {
+                let Some(actual) = field.value(pool, allocation) else {
+                    return *op == Op::Ne;
+                };
+                match op {
+                    Op::Eq => actual == *expected,
+                    Op::Ne => actual != *expected,
+                    Op::Lt => actual < *expected,
+                    Op::Le => actual <= *expected,
+                    Op::Gt => actual > *expected,
+                    Op::Ge => actual >= *expected,
+                    Op::Contains => match (&actual, expected) {
+                        (Value::Text(actual), Value::Text(expected)) => actual.contains(expected),
+                        _ => false,
+                    },
+                }
+            }
+            Filter::Not(filter) => !filter.matches(pool, allocation),
+            Filter::And(left, right) => {
+                left.matches(pool, allocation) && right.matches(pool, allocation)
+            }
+            Filter::Or(left, right) => {
+                left.matches(pool, allocation) || right.matches(pool, allocation)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn allocation(ip: &str, env: Option<&str>, expires_at: Option<u64>) -> IpAllocation {
+        IpAllocation {
+            ip: ip.to_string(),
+            vm_id: format!("web-{}", ip.rsplit('.').next().unwrap()),
+            hostname: None,
+            mac: None,
+            reserved: false,
+            external: false,
+            label: None,
+            expires_at,
+            renew_before: None,
+            hard_expires_at: None,
+            purpose: Some("primary".to_string()),
+            interface: None,
+            labels: env
+                .map(|env| BTreeMap::from([("env".to_string(), env.to_string())]))
+                .unwrap_or_default(),
+            owner: None,
+            notes: None,
+            ticket: None,
+            pending_release_at: None,
+            fence_token: None,
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let now = 1_000_000;
+        let allocations = [
+            allocation("172.16.0.2", Some("prod"), Some(now + 600)),
+            allocation("172.16.0.3", Some("prod"), Some(now + 7200)),
+            allocation("172.16.0.10", Some("dev"), Some(now + 60)),
+            allocation("172.16.0.11", None, None),
+        ];
+        let matching = |source: &str| -> Vec<&str> {
+            let filter = Filter::parse(source, now).unwrap();
+            (allocations.iter())
+                .filter(|allocation| filter.matches("default", allocation))
+                .map(|allocation| allocation.ip.as_str())
+                .collect()
+        };
+
+        assert_eq!(
+            matching(r#"label.env=="prod" && lease.expires<now+1h"#),
+            vec!["172.16.0.2"]
+        );
+        assert_eq!(
+            matching("label.env != prod"),
+            vec!["172.16.0.10", "172.16.0.11"]
+        );
+        // Addresses compare as addresses, not as text
+        assert_eq!(
+            matching("ip >= 172.16.0.3 && !(vm_id ~ \"-1\")"),
+            vec!["172.16.0.3"]
+        );
+        assert_eq!(
+            matching("(label.env == dev || lease.expires > now+1h) && pool == default"),
+            vec!["172.16.0.3", "172.16.0.10"]
+        );
+        assert_eq!(matching("reserved == true").len(), 0);
+
+        for (source, error) in [
+            ("label.env = prod", "unknown operator `=`, use `==`"),
+            ("tenant == me", "unknown field `tenant`"),
+            ("ip ~ 172", "operator does not apply to `ip`"),
+            (
+                "lease.expires < tomorrow",
+                "`tomorrow` is not a valid value for `lease.expires`",
+            ),
+            (
+                "lease.expires < now+1w",
+                "`now+1w` is not a valid value for `lease.expires`",
+            ),
+            ("(ip == 172.16.0.2", "expected `)`"),
+            ("ip == 172.16.0.2 pool", "unexpected `pool`"),
+            ("vm_id == \"web", "unterminated string"),
+            ("ip == 172.16.0.2 &&", "unexpected end of filter"),
+        ] {
+            assert_eq!(Filter::parse(source, now).unwrap_err(), error, "{}", source);
+        }
+        let nested = format!("{}ip == 172.16.0.2", "!".repeat(100));
+        assert!(Filter::parse(&nested, now).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+use crate::ippool::IpPoolError;
+
+// Number of independently locked segments per pool
+const SEGMENTS: u32 = 16;
+
+// Free addresses split into contiguous segments, each behind its own lock.
+// Segments are ordered sets, so the lowest free address is handed out
+// first no matter in which order addresses were released. Allocators walk
+// the segments in order and wait for busy ones, so an allocation never
+// passes over a lower free address; releases and exclusions only lock the
+// segment they touch.
+#[derive(Debug)]
+pub struct FreeList {
+    start: u32,
+    segment_size: u32,
+    segments: Vec<Mutex<BTreeSet<u32>>>,
+}
+
+impl FreeList {
+    // Empty list covering `start..=end`
+    pub fn new(start: u32, end: u32) -> Result<Self, IpPoolError> {
+        if start > end {
+            return Err(IpPoolError::InvertedRange);
+        }
+        let size = (end - start).saturating_add(1);
+        let segment_size = size.div_ceil(SEGMENTS).max(1);
+        let segments = (0..size.div_ceil(segment_size))
+            .map(|_| Mutex::new(BTreeSet::new()))
+            .collect();
+
+        Ok(FreeList {
+            start,
+            segment_size,
+            segments,
+        })
+    }
+
+    fn segment(&self, ip: u32) -> &Mutex<BTreeSet<u32>> {
+        let index = (ip.saturating_sub(self.start) / self.segment_size) as usize;
+        &self.segments[index.min(self.segments.len() - 1)]
+    }
+
+    // Take the lowest free address
+    pub fn pop(&self) -> Option<u32> {
+        self.segments
+            .iter()
+            .find_map(|segment| segment.lock().unwrap().pop_first())
+    }
+
+    // Take the next free address within `from..=to`
+    pub fn pop_in(&self, from: u32, to: u32) -> Option<u32> {
+        self.pop_in_excluding(from, to, &[])
+    }
+
+    // Take the next free address within `from..=to` that lies outside every
+    // `excluded` range
+    pub fn pop_in_excluding(&self, from: u32, to: u32, excluded: &[(u32, u32)]) -> Option<u32> {
+        if from > to {
+            return None;
+        }
+        let first = (from.saturating_sub(self.start) / self.segment_size) as usize;
+        self.segments.iter().skip(first).find_map(|segment| {
+            let mut free = segment.lock().unwrap();
+            let ip = free
+                .range(from..=to)
+                .find(|ip| !is_excluded(**ip, excluded))
+                .copied()?;
+            free.remove(&ip);
+            Some(ip)
+        })
+    }
+
+    // The next `count` addresses outside every `excluded` range, in order;
+    // without exclusions the ones pop would return
+    pub fn peek_n(&self, count: usize, excluded: &[(u32, u32)]) -> Vec<u32> {
+        let mut next = Vec::with_capacity(count);
+        for segment in &self.segments {
+            if next.len() == count {
+                break;
+            }
+            let free = segment.lock().unwrap();
+            next.extend(
+                free.iter()
+                    .filter(|ip| !is_excluded(**ip, excluded))
+                    .take(count - next.len())
+                    .copied(),
+            );
+        }
+        next
+    }
+
+    // Return a released address
+    pub fn push(&self, ip: u32) {
+        self.segment(ip).lock().unwrap().insert(ip);
+    }
+
+    // Put back an address that was taken but not used
+    pub fn unpop(&self, ip: u32) {
+        self.push(ip);
+    }
+
+    pub fn remove(&self, ip: u32) {
+        self.segment(ip).lock().unwrap().remove(&ip);
+    }
+
+    // Every free address, lowest first
+    pub fn addresses(&self) -> Vec<u32> {
+        self.segments
+            .iter()
+            .flat_map(|segment| segment.lock().unwrap().iter().copied().collect::<Vec<_>>())
+            .collect()
+    }
+
+    pub fn count(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|segment| segment.lock().unwrap().len())
+            .sum()
+    }
+
+    pub fn clear(&self) {
+        for segment in &self.segments {
+            segment.lock().unwrap().clear();
+        }
+    }
+}
+
+fn is_excluded(ip: u32, excluded: &[(u32, u32)]) -> bool {
+    excluded
+        .iter()
+        .any(|(from, to)| (*from..=*to).contains(&ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full(start: u32, end: u32) -> FreeList {
+        let free = FreeList::new(start, end).unwrap();
+        for ip in start..=end {
+            free.push(ip);
+        }
+        free
+    }
+
+    #[test]
+    fn test_lowest_address_first() {
+        let free = FreeList::new(1, 254).unwrap();
+        for ip in 1..=254 {
+            free.push(ip);
+        }
+
+        assert_eq!(free.count(), 254);
+        assert_eq!(free.peek_n(1, &[]), vec![1]);
+        assert_eq!(free.pop(), Some(1));
+        assert_eq!(free.pop(), Some(2));
+
+        // Released addresses are handed out lowest first, whatever the
+        // release order
+        assert_eq!(free.pop(), Some(3));
+        free.push(3);
+        free.push(1);
+        assert_eq!(free.pop(), Some(1));
+        free.unpop(1);
+        assert_eq!(free.pop(), Some(1));
+        assert_eq!(free.pop(), Some(3));
+
+        free.remove(4);
+        assert_eq!(free.peek_n(3, &[]), vec![5, 6, 7]);
+        assert_eq!(free.pop(), Some(5));
+        assert_eq!(free.count(), 249);
+    }
+
+    #[test]
+    fn test_lowest_after_releases_across_segments() {
+        let free = full(0, 255);
+        let taken: Vec<u32> = (0..200).map(|_| free.pop().unwrap()).collect();
+        assert_eq!(taken, (0..200).collect::<Vec<_>>());
+
+        // Released in reverse and spread over several segments
+        for ip in [190, 130, 70, 17, 16] {
+            free.push(ip);
+        }
+        assert_eq!(free.pop(), Some(16));
+        assert_eq!(free.pop(), Some(17));
+        assert_eq!(free.pop(), Some(70));
+        assert_eq!(free.pop(), Some(130));
+        assert_eq!(free.pop(), Some(190));
+        assert_eq!(free.pop(), Some(200));
+    }
+
+    #[test]
+    fn test_waits_for_busy_segment_instead_of_skipping_it() {
+        let free = full(0, 31);
+
+        let busy = free.segments[0].lock().unwrap();
+        std::thread::scope(|scope| {
+            let popped = scope.spawn(|| free.pop());
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert!(!popped.is_finished());
+            drop(busy);
+            assert_eq!(popped.join().unwrap(), Some(0));
+        });
+    }
+
+    #[test]
+    fn test_concurrent_pops_hand_out_the_lowest_addresses() {
+        let free = full(0, 1023);
+
+        let mut taken: Vec<u32> = std::thread::scope(|scope| {
+            let poppers: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| (0..50).map(|_| free.pop().unwrap()).collect::<Vec<_>>()))
+                .collect();
+            poppers
+                .into_iter()
+                .flat_map(|popper| popper.join().unwrap())
+                .collect()
+        });
+
+        // Exactly the lowest 400, none twice and none passed over
+        taken.sort_unstable();
+        assert_eq!(taken, (0..400).collect::<Vec<_>>());
+        assert_eq!(free.pop(), Some(400));
+    }
+
+    #[test]
+    fn test_inverted_range_is_rejected() {
+        assert_eq!(
+            FreeList::new(10, 5).unwrap_err(),
+            IpPoolError::InvertedRange
+        );
+        assert_eq!(FreeList::new(5, 5).unwrap().count(), 0);
+        assert_eq!(full(u32::MAX - 3, u32::MAX).count(), 4);
+
+        // An empty search range finds nothing instead of panicking
+        let free = full(10, 20);
+        assert_eq!(free.pop_in(15, 12), None);
+        assert_eq!(free.pop_in(12, 15), Some(12));
+    }
+
+    #[test]
+    fn test_excluded_ranges_are_skipped() {
+        let free = full(1, 254);
+
+        let excluded = [(1, 10), (12, 12)];
+        assert_eq!(free.peek_n(3, &excluded), vec![11, 13, 14]);
+        assert_eq!(free.pop_in_excluding(1, 254, &excluded), Some(11));
+        assert_eq!(free.pop_in_excluding(1, 254, &excluded), Some(13));
+        assert_eq!(free.pop_in_excluding(5, 10, &excluded), None);
+        assert_eq!(free.pop_in(5, 10), Some(5));
+    }
+}
@@ -0,0 +1,146 @@
+use crate::health::{HealthRegistry, Status};
+use crate::pools::PoolRegistry;
+use std::future::Future;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::process::Command;
+
+// Kernel neighbour table, consulted before sending pings
+const ARP_TABLE: &str = "/proc/net/arp";
+
+// ATF_COM: the neighbour entry has a resolved hardware address
+const ARP_COMPLETE: u32 = 0x2;
+
+// Whether `gateway` answers: a resolved ARP entry is enough, otherwise a
+// single ping must get a reply within `timeout`
+pub async fn probe(gateway: Ipv4Addr, timeout: Duration) -> Result<(), String> {
+    if let Ok(table) = tokio::fs::read_to_string(ARP_TABLE).await
+        && arp_resolved(&table, gateway)
+    {
+        return Ok(());
+    }
+
+    let output = Command::new("ping")
+        .args(["-c", "1", "-W"])
+        .arg(timeout.as_secs().max(1).to_string())
+        .arg(gateway.to_string())
+        .output()
+        .await
+        .map_err(|e| format!("cannot run ping: {}", e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!("no ARP entry and no ping reply from {}", gateway))
+    }
+}
+
+// /proc/net/arp: "IP address  HW type  Flags  HW address  Mask  Device"
+fn arp_resolved(table: &str, ip: Ipv4Addr) -> bool {
+    table.lines().skip(1).any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [address, _, flags, ..] => {
+                address.parse() == Ok(ip)
+                    && u32::from_str_radix(flags.trim_start_matches("0x"), 16)
+                        .is_ok_and(|flags| flags & ARP_COMPLETE != 0)
+            }
+            _ => false,
+        }
+    })
+}
+
+// Check the gateway of every pool, recording the outcome in the pool stats
+// and as the `gateway` health component (degraded while any is unreachable)
+pub async fn check_gateways<F, Fut>(pools: &PoolRegistry, health: &HealthRegistry, probe: F)
+where
+    F: Fn(Ipv4Addr) -> Fut,
+    Fut: Future<Output = Result<(), String>>,
+{
+    let mut problems = Vec::new();
+    for name in pools.names().await {
+        let Ok(pool) = pools.get(&name).await else {
+            continue;
+        };
+        let gateway = pool.get_gateway().await;
+        let result = match gateway.parse() {
+            Ok(address) => probe(address).await,
+            Err(_) => Err(format!("invalid gateway address {}", gateway)),
+        };
+        pool.set_gateway_reachable(result.is_ok()).await;
+        if let Err(e) = result {
+            tracing::warn!(
+                "⚠️  Gateway {} of pool '{}' is unreachable: {}",
+                gateway,
+                name,
+                e
+            );
+            problems.push(format!("gateway {} of pool {} unreachable", gateway, name));
+        }
+    }
+
+    if problems.is_empty() {
+        health.success("gateway");
+    } else {
+        health.failure("gateway", Status::Degraded, problems.join(", "));
+    }
+}
+
+// Re-check gateways every `interval` after the startup check
+pub async fn run_gateway_check(
+    pools: PoolRegistry,
+    health: HealthRegistry,
+    interval: Duration,
+    timeout: Duration,
+) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        ticker.tick().await;
+        check_gateways(&pools, &health, |gateway| probe(gateway, timeout)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ippool::IpPool;
+
+    #[test]
+    fn test_arp_resolved() {
+        let table = "\
+IP address       HW type     Flags       HW address            Mask     Device
+172.16.0.1       0x1         0x2         52:54:00:12:34:56     *        eth0
+172.16.1.1       0x1         0x0         00:00:00:00:00:00     *        eth0
+";
+        assert!(arp_resolved(table, Ipv4Addr::new(172, 16, 0, 1)));
+        assert!(!arp_resolved(table, Ipv4Addr::new(172, 16, 1, 1)));
+        assert!(!arp_resolved(table, Ipv4Addr::new(172, 16, 2, 1)));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_gateway_degrades_health() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let pools = PoolRegistry::new(pool.clone());
+        let lab = IpPool::new("172.16.1".to_string(), "172.16.1.1".to_string());
+        pools.insert("lab".to_string(), lab.clone()).await;
+        let health = HealthRegistry::default();
+
+        let down = Ipv4Addr::new(172, 16, 1, 1);
+        let probe = |gateway: Ipv4Addr| async move {
+            if gateway == down {
+                Err("no reply".to_string())
+            } else {
+                Ok(())
+            }
+        };
+        check_gateways(&pools, &health, probe).await;
+
+        assert_eq!(pool.get_stats().await["gateway_reachable"], true);
+        assert_eq!(lab.get_stats().await["gateway_reachable"], false);
+        let gateway = &health.components()["gateway"];
+        assert_eq!(gateway.status, Status::Degraded);
+        assert_eq!(
+            gateway.error.as_deref(),
+            Some("gateway 172.16.1.1 of pool lab unreachable")
+        );
+    }
+}
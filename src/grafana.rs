@@ -0,0 +1,203 @@
+use crate::ippool::IpPoolError;
+use crate::state::AppState;
+use axum::{Json, extract::State};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Grafana SimpleJSON datasource over the pool stats and allocation history.
+// Targets are "<pool>.<metric>" with metric one of METRICS.
+const METRICS: [&str; 3] = ["allocated", "available", "usage"];
+
+// Upper bound on datapoints per target
+const MAX_DATAPOINTS: u64 = 1000;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchRequest {
+    #[serde(default)]
+    pub target: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryRequest {
+    pub range: TimeRange,
+    #[serde(default)]
+    pub interval_ms: u64,
+    #[serde(default = "default_max_datapoints")]
+    pub max_data_points: u64,
+    pub targets: Vec<QueryTarget>,
+}
+
+fn default_max_datapoints() -> u64 {
+    100
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimeRange {
+    #[serde(deserialize_with = "de_unix_millis")]
+    pub from: u64,
+    #[serde(deserialize_with = "de_unix_millis")]
+    pub to: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryTarget {
+    pub target: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TimeSeries {
+    pub target: String,
+    pub datapoints: Vec<(f64, u64)>, // value, unix millis
+}
+
+// RFC 3339 timestamp, as Grafana sends it, to unix millis
+fn de_unix_millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    chrono::DateTime::parse_from_rfc3339(&value)
+        .map(|at| at.timestamp_millis().max(0) as u64)
+        .map_err(serde::de::Error::custom)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Sample times from `from` to `to` (never in the future), at least
+// `interval_ms` apart and at most `max_points` of them
+fn sample_times(from: u64, to: u64, interval_ms: u64, max_points: u64, now: u64) -> Vec<u64> {
+    let to = to.min(now);
+    if from > to {
+        return Vec::new();
+    }
+    let max_points = max_points.clamp(1, MAX_DATAPOINTS);
+    let step = interval_ms.max((to - from) / max_points).max(1);
+    (0..max_points)
+        .map_while(|i| to.checked_sub(i * step).filter(|at| *at >= from))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect()
+}
+
+// Datasource test ("Save & test" in Grafana)
+pub async fn test_datasource() -> &'static str {
+    "OK"
+}
+
+// Metric names for the query editor
+pub async fn search(
+    State(state): State<AppState>,
+    body: Option<Json<SearchRequest>>,
+) -> Json<Vec<String>> {
+    let Json(req) = body.unwrap_or_default();
+    tracing::debug!("Grafana search request - target: {:?}", req.target);
+
+    let targets = state
+        .pools
+        .names()
+        .await
+        .into_iter()
+        .flat_map(|pool| METRICS.map(|metric| format!("{}.{}", pool, metric)))
+        .filter(|target| target.contains(&req.target))
+        .collect();
+    Json(targets)
+}
+
+// Time series for the requested targets
+pub async fn query(
+    State(state): State<AppState>,
+    Json(req): Json<QueryRequest>,
+) -> Result<Json<Vec<TimeSeries>>, IpPoolError> {
+    tracing::debug!(
+        "Grafana query request - {} targets, {}..{}",
+        req.targets.len(),
+        req.range.from,
+        req.range.to
+    );
+
+    let now = now_millis();
+    let times = sample_times(
+        req.range.from,
+        req.range.to,
+        req.interval_ms,
+        req.max_data_points,
+        now,
+    );
+    let ages: Vec<Duration> = times
+        .iter()
+        .map(|at| Duration::from_millis(now - at))
+        .collect();
+
+    let mut series = Vec::with_capacity(req.targets.len());
+    for QueryTarget { target } in req.targets {
+        let Some((name, metric)) = target
+            .rsplit_once('.')
+            .filter(|(_, metric)| METRICS.contains(metric))
+        else {
+            tracing::debug!("Skipping unknown Grafana target: {}", target);
+            continue;
+        };
+        let pool = state.pools.get(name).await?;
+
+        // History only covers allocations, capacity is today's
+        let total = pool.get_stats().await["total"].as_u64().unwrap_or(0) as f64;
+        let counts = pool.allocated_at(&ages).await;
+        let datapoints = counts
+            .into_iter()
+            .zip(times.iter().copied())
+            .map(|(allocated, at)| {
+                let allocated = allocated as f64;
+                let value = match metric {
+                    "allocated" => allocated,
+                    "available" => (total - allocated).max(0.0),
+                    _ if total > 0.0 => allocated / total * 100.0,
+                    _ => 0.0,
+                };
+                (value, at)
+            })
+            .collect();
+        series.push(TimeSeries { target, datapoints });
+    }
+
+    tracing::debug!("Returning {} Grafana series", series.len());
+    Ok(Json(series))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_times() {
+        // Step is the larger of the interval and range / max points
+        assert_eq!(
+            sample_times(0, 1000, 100, 5, 5000),
+            vec![200, 400, 600, 800, 1000]
+        );
+        assert_eq!(sample_times(0, 1000, 400, 5, 5000), vec![200, 600, 1000]);
+        // Nothing after now
+        assert_eq!(sample_times(0, 1000, 250, 10, 500), vec![0, 250, 500]);
+        assert!(sample_times(600, 1000, 100, 5, 500).is_empty());
+    }
+
+    #[test]
+    fn test_query_request_parses_grafana_body() {
+        let req: QueryRequest = serde_json::from_str(
+            r#"{
+                "range": {"from": "1970-01-01T00:00:01.000Z", "to": "1970-01-01T00:01:00Z"},
+                "intervalMs": 30000,
+                "maxDataPoints": 550,
+                "targets": [{"target": "default.usage", "refId": "A", "type": "timeserie"}]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(req.range.from, 1000);
+        assert_eq!(req.range.to, 60000);
+        assert_eq!(req.interval_ms, 30000);
+        assert_eq!(req.targets[0].target, "default.usage");
+    }
+}
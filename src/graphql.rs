@@ -0,0 +1,477 @@
+use crate::events::{self, EventKind};
+use crate::handlers::{
+    allocate_charged, allocation_target, check_approval, check_renewal_fence, requested,
+    require_fence,
+};
+use crate::insights;
+use crate::ippool::{IpAllocation, IpPool, IpPoolError, Lease, PRIMARY, Slot};
+use crate::pools::DEFAULT_POOL;
+use crate::state::AppState;
+use async_graphql::http::{
+    ALL_WEBSOCKET_PROTOCOLS, GraphiQLSource, WebSocket, WebSocketProtocols as Protocols, WsMessage,
+};
+use async_graphql::{
+    Context, Enum, ErrorExtensions, Object, Result, Schema, SimpleObject, Subscription,
+};
+use axum::{
+    Json,
+    extract::{
+        State,
+        ws::{CloseFrame, Message, WebSocketUpgrade},
+    },
+    http::HeaderMap,
+    response::{Html, IntoResponse, Response},
+};
+use futures_util::{SinkExt, Stream, StreamExt};
+use tokio_stream::wrappers::BroadcastStream;
+
+pub type IpPoolSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+pub fn build_schema(state: AppState) -> IpPoolSchema {
+    Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .data(state)
+        .finish()
+}
+
+// GraphQL errors carry the same identifier as the perf counters
+fn to_gql(e: IpPoolError) -> async_graphql::Error {
+    e.extend_with(|e, ext| ext.set("code", e.kind()))
+}
+
+#[derive(SimpleObject)]
+struct Allocation {
+    pool: String,
+    ip: String,
+    vm_id: String,
+    reserved: bool,
+    label: Option<String>,
+    expires_at: Option<u64>,
+    renew_before: Option<u64>,
+    purpose: Option<String>,
+    fence_token: Option<u64>,
+}
+
+impl Allocation {
+    fn new(pool: &str, allocation: IpAllocation) -> Self {
+        Allocation {
+            pool: pool.to_string(),
+            ip: allocation.ip,
+            vm_id: allocation.vm_id,
+            reserved: allocation.reserved,
+            label: allocation.label,
+            expires_at: allocation.expires_at,
+            renew_before: allocation.renew_before,
+            purpose: allocation.purpose,
+            fence_token: allocation.fence_token,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+struct Stats {
+    network: String,
+    gateway: String,
+    total: u64,
+    allocated: u64,
+    available: u64,
+    reserved: u64,
+    usage: f64,
+    frozen: bool,
+}
+
+impl Stats {
+    fn from_json(stats: &serde_json::Value) -> Self {
+        Stats {
+            network: stats["network"].as_str().unwrap_or_default().to_string(),
+            gateway: stats["gateway"].as_str().unwrap_or_default().to_string(),
+            total: stats["total"].as_u64().unwrap_or(0),
+            allocated: stats["allocated"].as_u64().unwrap_or(0),
+            available: stats["available"].as_u64().unwrap_or(0),
+            reserved: stats["reserved"].as_u64().unwrap_or(0),
+            usage: stats["usage"].as_f64().unwrap_or(0.0),
+            frozen: stats["frozen"].as_bool().unwrap_or(false),
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+struct Pool {
+    name: String,
+    stats: Stats,
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "crate::events::EventKind")]
+enum GqlEventKind {
+    Allocated,
+    Released,
+    Migrated,
+    Expired,
+    Expiring,
+    Anomaly,
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "Event")]
+struct GqlEvent {
+    id: u64,
+    timestamp: u64,
+    kind: GqlEventKind,
+    pool: String,
+    vm_id: String,
+    ip: String,
+}
+
+impl From<events::Event> for GqlEvent {
+    fn from(event: events::Event) -> Self {
+        GqlEvent {
+            id: event.id,
+            timestamp: event.timestamp,
+            kind: event.kind.into(),
+            pool: event.pool,
+            vm_id: event.vm_id,
+            ip: event.ip,
+        }
+    }
+}
+
+async fn pool_by_name(ctx: &Context<'_>, name: &str) -> Result<IpPool> {
+    let state = ctx.data::<AppState>()?;
+    state.pools.get(name).await.map_err(to_gql)
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    // VM allocations and reserved addresses of a pool
+    async fn allocations(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default_with = "DEFAULT_POOL.to_string()")] pool: String,
+    ) -> Result<Vec<Allocation>> {
+        let ip_pool = pool_by_name(ctx, &pool).await?;
+
+        let mut allocations = ip_pool.list_allocations().await;
+        allocations.extend(ip_pool.list_reserved().await);
+        Ok(allocations
+            .into_iter()
+            .map(|allocation| Allocation::new(&pool, allocation))
+            .collect())
+    }
+
+    async fn pools(&self, ctx: &Context<'_>) -> Result<Vec<Pool>> {
+        let state = ctx.data::<AppState>()?;
+
+        let mut pools = Vec::new();
+        for name in state.pools.names().await {
+            if let Ok(pool) = state.pools.get(&name).await {
+                let stats = Stats::from_json(&pool.get_stats().await);
+                pools.push(Pool { name, stats });
+            }
+        }
+        Ok(pools)
+    }
+
+    async fn stats(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default_with = "DEFAULT_POOL.to_string()")] pool: String,
+    ) -> Result<Stats> {
+        let ip_pool = pool_by_name(ctx, &pool).await?;
+        Ok(Stats::from_json(&ip_pool.get_stats().await))
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    async fn allocate(
+        &self,
+        ctx: &Context<'_>,
+        vm_id: String,
+        // The default pool, or the one the API key is delegated in
+        pool: Option<String>,
+        // Lease TTL in seconds, overrides the pool default
+        ttl: Option<u64>,
+        // Renewals only: token of the allocation being renewed
+        fence_token: Option<u64>,
+    ) -> Result<Allocation> {
+        tracing::info!(
+            "GraphQL allocation request - pool: {:?}, vm_id: {}, ttl: {:?}",
+            pool,
+            vm_id,
+            ttl
+        );
+
+        let lease = match ttl {
+            Some(0) => return Err(to_gql(IpPoolError::InvalidLease)),
+            Some(ttl) => Lease::Ttl(std::time::Duration::from_secs(ttl)),
+            None => Lease::PoolDefault,
+        };
+        let state = ctx.data::<AppState>()?;
+        let headers = ctx.data_opt::<HeaderMap>().cloned().unwrap_or_default();
+        state.maintenance.check().map_err(to_gql)?;
+        let target = allocation_target(state, &headers, pool.as_deref())
+            .await
+            .map_err(to_gql)?;
+        let (pool, ip_pool, team) = (target.name.clone(), &target.pool, target.team.as_deref());
+        let slot = Slot::primary();
+        check_renewal_fence(state, ip_pool, &vm_id, &slot, fence_token)
+            .await
+            .map_err(to_gql)?;
+        check_approval(state, &pool, ip_pool, &vm_id, &slot)
+            .await
+            .map_err(to_gql)?;
+        let requested = requested(None, None, None, None, team, None);
+        let (ip, expires_at) =
+            allocate_charged(state, &headers, &target, &vm_id, &slot, lease, &requested)
+                .await
+                .map_err(to_gql)?;
+        let caller = insights::caller(&headers, team);
+        state
+            .events
+            .emit(
+                EventKind::Allocated,
+                &pool,
+                &vm_id,
+                &ip,
+                insights::details(&caller),
+            )
+            .await;
+
+        Ok(Allocation {
+            pool,
+            renew_before: ip_pool.renew_before(expires_at).await,
+            fence_token: ip_pool.fence_token(&ip).await,
+            ip,
+            vm_id,
+            reserved: false,
+            label: None,
+            expires_at,
+            purpose: Some(PRIMARY.to_string()),
+        })
+    }
+
+    // Releases every address of the VM, returns its primary one
+    async fn release(
+        &self,
+        ctx: &Context<'_>,
+        vm_id: String,
+        #[graphql(default_with = "DEFAULT_POOL.to_string()")] pool: String,
+        // Token of one of the VM's allocations, fences off stale callers
+        fence_token: Option<u64>,
+    ) -> Result<String> {
+        tracing::info!(
+            "GraphQL release request - pool: {}, vm_id: {}, fence_token: {:?}",
+            pool,
+            vm_id,
+            fence_token
+        );
+
+        let state = ctx.data::<AppState>()?;
+        state.maintenance.check().map_err(to_gql)?;
+        require_fence(state, fence_token).map_err(to_gql)?;
+        let ip_pool = pool_by_name(ctx, &pool).await?;
+        let primary = ip_pool.get_allocation(&vm_id).await.map_err(to_gql)?.ip;
+        let released = match fence_token {
+            Some(fence) => ip_pool.release_ip_fenced(&vm_id, fence).await,
+            None => ip_pool.release_ip(&vm_id).await,
+        };
+        for ip in released.map_err(to_gql)? {
+            state
+                .events
+                .emit(EventKind::Released, &pool, &vm_id, &ip, None)
+                .await;
+        }
+
+        Ok(primary)
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    // Live allocation events, optionally for a single pool
+    async fn events(
+        &self,
+        ctx: &Context<'_>,
+        pool: Option<String>,
+    ) -> Result<impl Stream<Item = GqlEvent> + use<>> {
+        let state = ctx.data::<AppState>()?;
+
+        Ok(
+            BroadcastStream::new(state.events.subscribe()).filter_map(move |event| {
+                // Lagged subscribers skip what they missed
+                let event = event
+                    .ok()
+                    .filter(|event| pool.as_ref().is_none_or(|pool| *pool == event.pool))
+                    .map(GqlEvent::from);
+                async move { event }
+            }),
+        )
+    }
+}
+
+// GraphiQL explorer
+pub async fn graphiql() -> Html<String> {
+    Html(
+        GraphiQLSource::build()
+            .endpoint("/graphql")
+            .subscription_endpoint("/graphql/ws")
+            .finish(),
+    )
+}
+
+// Queries and mutations over HTTP POST
+pub async fn graphql_handler(
+    State(schema): State<IpPoolSchema>,
+    headers: HeaderMap,
+    Json(request): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    tracing::debug!("GraphQL request received");
+    // Mutations allocate with the caller's API key, like the REST API
+    Json(schema.execute(request.data(headers)).await)
+}
+
+// Subscriptions over WebSocket (graphql-transport-ws and legacy graphql-ws)
+pub async fn graphql_ws(
+    State(schema): State<IpPoolSchema>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let protocol = headers
+        .get("sec-websocket-protocol")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            value
+                .split(',')
+                .find_map(|protocol| protocol.trim().parse::<Protocols>().ok())
+        })
+        .unwrap_or(Protocols::GraphQLWS);
+
+    upgrade
+        .protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |socket| async move {
+            let (mut sink, stream) = socket.split();
+            let input = stream
+                .take_while(|message| futures_util::future::ready(message.is_ok()))
+                .filter_map(|message| {
+                    futures_util::future::ready(match message {
+                        Ok(Message::Text(text)) => Some(text.as_bytes().to_vec()),
+                        Ok(Message::Binary(data)) => Some(data.to_vec()),
+                        _ => None,
+                    })
+                });
+
+            let mut output = WebSocket::new(schema, input, protocol);
+            while let Some(message) = output.next().await {
+                let message = match message {
+                    WsMessage::Text(text) => Message::Text(text.into()),
+                    WsMessage::Close(code, reason) => Message::Close(Some(CloseFrame {
+                        code,
+                        reason: reason.into(),
+                    })),
+                };
+                if sink.send(message).await.is_err() {
+                    break;
+                }
+            }
+        })
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::AllocationBudget;
+    use crate::delegations;
+    use std::time::Duration;
+
+    fn schema() -> IpPoolSchema {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        build_schema(AppState::new(pool))
+    }
+
+    #[tokio::test]
+    async fn test_allocate_and_query() {
+        let schema = schema();
+
+        let response = schema
+            .execute(r#"mutation { allocate(vmId: "vm-1") { ip pool } }"#)
+            .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        let data = response.data.into_json().unwrap();
+        assert_eq!(data["allocate"]["ip"], "172.16.0.2");
+        assert_eq!(data["allocate"]["pool"], "default");
+
+        let response = schema
+            .execute("{ stats { allocated } pools { name } allocations { vmId reserved } }")
+            .await;
+        let data = response.data.into_json().unwrap();
+        assert_eq!(data["stats"]["allocated"], 1);
+        assert_eq!(data["pools"][0]["name"], "default");
+        // vm-1 plus the reserved gateway
+        assert_eq!(data["allocations"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_allocate_charges_the_key() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let range = (
+            "172.16.0.10".parse().unwrap(),
+            "172.16.0.19".parse().unwrap(),
+        );
+        let key_hash = delegations::hash_key("key-a");
+        pool.delegate("squad-a", range.0, range.1, key_hash)
+            .await
+            .unwrap();
+        let schema = build_schema(AppState {
+            budget: Some(AllocationBudget::new(1, Duration::from_secs(3600))),
+            ..AppState::new(pool.clone())
+        });
+        let mut headers = HeaderMap::new();
+        headers.insert(delegations::API_KEY_HEADER, "key-a".parse().unwrap());
+        let allocate = |vm_id: &str| {
+            let mutation = format!(
+                r#"mutation {{ allocate(vmId: "{}") {{ ip pool }} }}"#,
+                vm_id
+            );
+            schema.execute(async_graphql::Request::new(mutation).data(headers.clone()))
+        };
+
+        // The key's delegation places and owns the address
+        let response = allocate("vm-1").await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        let data = response.data.into_json().unwrap();
+        assert_eq!(data["allocate"]["ip"], "172.16.0.10");
+        let allocation = pool.get_allocation("vm-1").await.unwrap();
+        assert_eq!(allocation.owner.as_deref(), Some("squad-a"));
+
+        // Its budget is spent
+        let response = allocate("vm-2").await;
+        let code = response.errors[0].extensions.as_ref().unwrap().get("code");
+        assert_eq!(
+            code.unwrap().clone().into_json().unwrap(),
+            serde_json::json!("budget_exceeded")
+        );
+        assert!(pool.get_allocation("vm-2").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_errors_carry_code() {
+        let schema = schema();
+
+        let response = schema
+            .execute(r#"mutation { release(vmId: "missing") }"#)
+            .await;
+        let error = &response.errors[0];
+        let code = error.extensions.as_ref().unwrap().get("code").unwrap();
+        assert_eq!(
+            code.clone().into_json().unwrap(),
+            serde_json::json!("ip_not_found")
+        );
+    }
+}
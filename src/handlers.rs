@@ -1,11 +1,55 @@
-use crate::ippool::{IpPool, IpPoolError};
+use crate::approvals::PendingAllocation;
+use crate::changes::Changes;
+use crate::check::{self, PoolUsage, Thresholds};
+use crate::conflicts::{Conflict, ConflictSource};
+use crate::consistency::{self, ConsistencyReport};
+use crate::delegations::{self, DelegationUsage};
+use crate::dhcp;
+use crate::diff::{self, SnapshotDiff};
+use crate::etag;
+use crate::events::{Event, EventKind, unix_now};
+use crate::filter::Filter;
+use crate::health::{self, ComponentHealth, Status};
+use crate::hostnames;
+use crate::insights::{self, InsightsReport};
+use crate::ippool::{
+    self, Annotation, IpAllocation, IpPool, IpPoolError, Lease, Requested, Slot, VLAN_IDS,
+};
+use crate::leaks::{self, LeakCandidate};
+use crate::leases::{self, ImportReport, LeaseFormat};
+use crate::maintenance;
+use crate::netplan;
+use crate::perf::Operation;
+use crate::policy::ScriptPolicy;
+use crate::pools::{DEFAULT_POOL, Migration};
+use crate::prefix6::{DelegatedPrefix, Prefix6Pool};
+use crate::replication;
+use crate::reports::{self, ReportFormat};
+use crate::routes::dhcp_option_121;
+use crate::s3::S3Snapshots;
+use crate::slaac;
+use crate::state::AppState;
+use crate::storage::{self, NamedSnapshot, StateStore, StoredState};
+use crate::sweep::{Adoption, SweepReport, UnknownHost};
+use crate::templates::{self, Templates};
+use crate::terraform::{self, AdoptionReport};
+use crate::validate::{self, Finding, ProposedConfig, Severity, ValidationReport};
+use crate::webhooks::{Delivery, DeliveryStatus, WebhookSummary};
+use crate::wireguard::{WireGuardPeer, WireGuardPool};
 use axum::{
     Json,
-    extract::{Path, State},
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    extract::{
+        FromRequest, OptionalFromRequest, Path, Query, Request, State, rejection::JsonRejection,
+    },
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{Html, IntoResponse, Response},
 };
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 // Error response type
 #[derive(Debug, Serialize)]
@@ -13,22 +57,173 @@ struct ErrorResponse {
     error: String,
 }
 
+// JSON request body. Rejections use the API error format and name the
+// offending field, e.g. a misspelled "vmid".
+pub struct JsonBody<T>(pub T);
+
+impl<S, T> FromRequest<S> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = <Json<T> as FromRequest<S>>::from_request(req, state)
+            .await
+            .map_err(reject_body)?;
+        Ok(JsonBody(value))
+    }
+}
+
+// Optional body: None when the request has no JSON content type
+impl<S, T> OptionalFromRequest<S> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Option<Self>, Self::Rejection> {
+        let value = <Json<T> as OptionalFromRequest<S>>::from_request(req, state)
+            .await
+            .map_err(reject_body)?;
+        Ok(value.map(|Json(value)| JsonBody(value)))
+    }
+}
+
+fn reject_body(rejection: JsonRejection) -> Response {
+    let status = rejection.status();
+    let message = rejection_message(&rejection);
+    tracing::warn!("Request failed: Invalid request body: {}", message);
+    let body = Json(ErrorResponse {
+        error: format!("Invalid request body: {}", message),
+    });
+    (status, body).into_response()
+}
+
+// Why a body was rejected; for bad data "<field path>: <problem>"
+pub fn rejection_message(rejection: &JsonRejection) -> String {
+    match rejection {
+        JsonRejection::JsonDataError(e) => std::error::Error::source(e)
+            .map(|source| source.to_string())
+            .unwrap_or_else(|| e.body_text()),
+        _ => rejection.body_text(),
+    }
+}
+
 // Request/Response types
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct AllocateIpRequest {
     pub vm_id: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub hostname: Option<String>,
+    #[serde(default)]
+    pub pool: Option<String>, // defaults to the default pool
+    #[serde(default)]
+    pub interface: Option<String>, // e.g. "eth0", one address per interface
+    #[serde(default)]
+    pub purpose: Option<String>, // secondary address, e.g. "floating"
+    #[serde(default)]
+    pub ttl: Option<u64>, // seconds, overrides the pool default
+    #[serde(default)]
+    pub infinite: bool, // lease never expires (admin API only)
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>, // e.g. {"project": "payments"}, kept when absent
+    #[serde(default)]
+    pub mac: Option<String>, // derives the IPv6 address in EUI-64 mode
+    #[serde(default)]
+    pub fence_token: Option<u64>, // renewals only: token of the allocation being renewed
+    #[serde(default)]
+    pub owner: Option<String>, // defaults to the team of the API key
+    #[serde(default)]
+    pub expires_at: Option<u64>, // unix seconds, reclaimed then however often renewed
+}
+
+impl AllocateIpRequest {
+    fn lease(&self, admin: bool) -> Result<Lease, IpPoolError> {
+        match (self.ttl, self.infinite) {
+            (Some(_), true) | (Some(0), false) => Err(IpPoolError::InvalidLease),
+            (None, true) if !admin => Err(IpPoolError::AdminOnly),
+            (None, true) => Ok(Lease::Infinite),
+            (Some(ttl), false) => Ok(Lease::Ttl(Duration::from_secs(ttl))),
+            (None, false) => Ok(Lease::PoolDefault),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AllocateIpQuery {
+    #[serde(default)]
+    pub dry_run: bool,
 }
 
 #[derive(Debug, Serialize)]
 pub struct AllocateIpResponse {
     pub ip: String,
     pub vm_id: String,
+    pub pool: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    pub purpose: String,
     pub gateway: String,
     pub network: String,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub vlan_id: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<Ipv6Addr>, // in the pool's /64, when it has one
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub hostname: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<RouteResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dhcp_option_121: Option<String>, // RFC 3442 encoding of `routes`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dns_servers: Vec<Ipv4Addr>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub search_domains: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ntp_servers: Vec<Ipv4Addr>,
+    pub lease_ttl: Option<u64>, // seconds, null when the lease never expires
+    pub lease_expires_at: Option<u64>, // unix seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renew_before: Option<u64>, // unix seconds, renew by then to avoid the expiry warning
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>, // unix seconds, hard expiry no renewal extends
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fence_token: Option<u64>, // pass back on renewal and release
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
+}
+
+// One of a VM's addresses, in whichever pool it lives
+#[derive(Debug, Serialize)]
+pub struct VmAddressResponse {
+    pub pool: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vlan_id: Option<u16>,
+    #[serde(flatten)]
+    pub allocation: IpAllocation,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RouteResponse {
+    pub destination: String, // CIDR
+    pub next_hop: String,
+}
+
+// Guard for releasing by address: only release if this VM still holds it.
+// Accepted as `?vm_id=` or as a JSON body.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReleaseByIpRequest {
+    #[serde(default)]
+    pub vm_id: Option<String>,
+    #[serde(default)]
+    pub fence_token: Option<u64>, // only release the allocation this token was issued for
 }
 
 #[derive(Debug, Serialize)]
@@ -38,147 +233,3765 @@ pub struct ReleaseIpResponse {
     pub vm_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_at: Option<u64>, // unix seconds, for deferred releases
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReleaseByLabelQuery {
+    pub label: String, // key=value
+    #[serde(default)]
+    pub pool: Option<String>, // every pool when absent
 }
 
 #[derive(Debug, Serialize)]
-pub struct HealthResponse {
-    pub status: String,
+pub struct LabelRelease {
+    pub pool: String,
+    pub vm_id: String,
+    pub ip: String,
 }
 
-// Custom error type for handlers
-impl IntoResponse for IpPoolError {
-    fn into_response(self) -> Response {
-        let (status, message) = match self {
-            IpPoolError::NoAvailableIps => {
-                tracing::warn!("Request failed: No available IPs in pool");
-                (
-                    StatusCode::SERVICE_UNAVAILABLE,
-                    "No available IPs in pool".to_string(),
-                )
-            }
-            IpPoolError::IpNotFound => {
-                tracing::warn!("Request failed: IP not found");
-                (StatusCode::NOT_FOUND, "IP not found".to_string())
-            }
-            IpPoolError::InvalidIp => {
-                tracing::warn!("Request failed: Invalid IP address");
-                (StatusCode::BAD_REQUEST, "Invalid IP address".to_string())
-            }
-        };
+#[derive(Debug, Serialize)]
+pub struct ReleaseByLabelResponse {
+    pub label: String,
+    pub released: Vec<LabelRelease>,
+}
 
-        let body = Json(ErrorResponse { error: message });
-        (status, body).into_response()
+#[derive(Debug, Deserialize)]
+pub struct ReleaseQuery {
+    // Seconds to keep the addresses before releasing them; the release can
+    // be cancelled until then
+    #[serde(default)]
+    pub grace: Option<u64>,
+    #[serde(default)]
+    pub fence_token: Option<u64>, // token of one of the VM's allocations
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AllocatePrefix6Request {
+    pub vm_id: String, // router or VM the prefix is delegated to
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AddPeerRequest {
+    pub public_key: String,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForecastQuery {
+    #[serde(default)]
+    pub pool: Option<String>, // defaults to the default pool
+    // Lookback window in seconds used to measure the allocation rate
+    #[serde(default = "default_forecast_window")]
+    pub window: u64,
+}
+
+fn default_forecast_window() -> u64 {
+    3600
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatsQuery {
+    #[serde(default)]
+    pub pool: Option<String>,
+    // Label to break allocations down by, e.g. "project" or "tenant"
+    #[serde(default)]
+    pub group_by: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateSnapshotRequest {
+    pub name: String,
+}
+
+// Named snapshot as listed, without the state itself
+#[derive(Debug, Serialize)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub created_at: u64,
+    pub pools: Vec<String>,
+    pub allocations: usize,
+}
+
+impl From<&NamedSnapshot> for SnapshotInfo {
+    fn from(snapshot: &NamedSnapshot) -> Self {
+        SnapshotInfo {
+            name: snapshot.name.clone(),
+            created_at: snapshot.created_at,
+            pools: snapshot.state.keys().cloned().collect(),
+            allocations: snapshot
+                .state
+                .values()
+                .map(|pool| pool.allocations.len())
+                .sum(),
+        }
     }
 }
 
-// Health check handler
-pub async fn health_check() -> Json<HealthResponse> {
-    tracing::debug!("Health check request received");
-    Json(HealthResponse {
-        status: "healthy".to_string(),
-    })
+// Two exported snapshots (state file, S3 snapshot) to compare; without
+// `to` the first one is compared with the current state
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotDiffRequest {
+    pub from: StoredState,
+    #[serde(default)]
+    pub to: Option<StoredState>,
 }
 
-// Allocate IP handler
-pub async fn allocate_ip(
-    State(pool): State<IpPool>,
-    Json(req): Json<AllocateIpRequest>,
-) -> Result<(StatusCode, Json<AllocateIpResponse>), IpPoolError> {
-    tracing::info!(
-        "IP allocation request - vm_id: {}, hostname: {:?}",
-        req.vm_id,
-        req.hostname
-    );
+#[derive(Debug, Deserialize)]
+pub struct CheckQuery {
+    #[serde(default)]
+    pub pool: Option<String>, // every pool when absent
+    #[serde(default)]
+    pub warn: Option<f64>, // usage percent, overrides the pool thresholds
+    #[serde(default)]
+    pub crit: Option<f64>,
+}
 
-    let ip = pool.allocate_ip(req.vm_id.clone()).await?;
-    let stats = pool.get_stats().await;
+#[derive(Debug, Deserialize)]
+pub struct RangeStatsQuery {
+    // Sub-range prefix length, e.g. 27 for one entry per /27
+    #[serde(default)]
+    pub prefix: Option<u8>,
+    #[serde(default)]
+    pub pool: Option<String>,
+}
 
-    let response = AllocateIpResponse {
-        ip: ip.clone(),
-        vm_id: req.vm_id.clone(),
-        gateway: stats["gateway"].as_str().unwrap().to_string(),
-        network: stats["network"].as_str().unwrap().to_string(),
-        hostname: req.hostname,
+#[derive(Debug, Deserialize)]
+pub struct NextFreeQuery {
+    #[serde(default = "default_next_free_count")]
+    pub count: usize,
+    #[serde(default)]
+    pub pool: Option<String>,
+}
+
+fn default_next_free_count() -> usize {
+    1
+}
+
+// Upper bound on addresses listed by the next-free endpoint
+const MAX_NEXT_FREE: usize = 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct ListAllocationsQuery {
+    #[serde(default)]
+    pub pool: Option<String>, // defaults to the default pool
+    #[serde(default)]
+    pub q: Option<String>, // filter expression, e.g. `label.env == "prod"`
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangesQuery {
+    #[serde(default)]
+    pub pool: Option<String>, // defaults to the default pool
+    #[serde(default)]
+    pub since: u64, // version of an earlier response, 0 for everything
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExpiringQuery {
+    #[serde(default)]
+    pub pool: Option<String>, // defaults to the default pool
+    // How far ahead to look, e.g. "1h", "30m" or plain seconds
+    #[serde(default = "default_expiring_within", deserialize_with = "de_duration")]
+    pub within: Duration,
+}
+
+fn default_expiring_within() -> Duration {
+    Duration::from_secs(3600)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportQuery {
+    // Covered time span, e.g. "7d", "24h" or plain seconds
+    #[serde(default = "default_report_window", deserialize_with = "de_duration")]
+    pub window: Duration,
+    #[serde(default)]
+    pub format: ReportFormat,
+}
+
+fn default_report_window() -> Duration {
+    Duration::from_secs(7 * 86400)
+}
+
+fn de_duration<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_duration(&value)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid duration: {}", value)))
+}
+
+// "90", "90s", "30m", "1h", "2d" -> Duration
+pub(crate) fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
     };
+    let number: u64 = number.parse().ok()?;
+    let seconds = match unit {
+        "s" => number,
+        "m" => number.checked_mul(60)?,
+        "h" => number.checked_mul(3600)?,
+        "d" => number.checked_mul(86400)?,
+        _ => return None,
+    };
+    Some(Duration::from_secs(seconds))
+}
 
-    tracing::info!(
-        "IP allocated successfully - vm_id: {}, ip: {}",
-        req.vm_id,
-        ip
-    );
-    Ok((StatusCode::CREATED, Json(response)))
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MigrateRequest {
+    pub from_pool: String,
+    pub to_pool: String,
+    #[serde(default)]
+    pub vm_ids: Option<Vec<String>>,
 }
 
-// Release IP by VM_ID handler
-pub async fn release_ip(
-    State(pool): State<IpPool>,
-    Path(vm_id): Path<String>,
-) -> Result<Json<ReleaseIpResponse>, IpPoolError> {
-    tracing::info!("IP release request by VM ID - vm_id: {}", vm_id);
+#[derive(Debug, Serialize)]
+pub struct MigrateResponse {
+    pub from_pool: String,
+    pub to_pool: String,
+    pub migrated: Vec<Migration>,
+}
 
-    pool.release_ip(&vm_id).await?;
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MergePoolsRequest {
+    pub pool: String,
+    pub other: String,
+}
 
-    tracing::info!("IP released successfully - vm_id: {}", vm_id);
-    Ok(Json(ReleaseIpResponse {
-        message: "IP released successfully".to_string(),
-        vm_id: Some(vm_id),
-        ip: None,
-    }))
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SplitPoolRequest {
+    pub pool: String,
+    pub at: std::net::Ipv4Addr,
+    pub new_pool: String,
 }
 
-// Release IP by address handler
-pub async fn release_ip_by_address(
-    State(pool): State<IpPool>,
-    Path(ip): Path<String>,
-) -> Result<Json<ReleaseIpResponse>, IpPoolError> {
-    tracing::info!("IP release request by address - ip: {}", ip);
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PoolFromTemplateRequest {
+    pub template: String,
+    pub cidr: String, // e.g. "10.20.5.0/24"
+    pub name: String,
+    #[serde(default)]
+    pub vlan_id: Option<u16>,
+    #[serde(default)]
+    pub allow_public: bool, // the network may lie outside private and CGNAT ranges
+}
 
-    pool.release_ip_by_address(&ip).await?;
+#[derive(Debug, Serialize)]
+pub struct PoolFromTemplateResponse {
+    pub name: String,
+    pub template: String,
+    pub stats: serde_json::Value,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<Finding>, // validation warnings, the pool was created anyway
+}
 
-    tracing::info!("IP released successfully - ip: {}", ip);
-    Ok(Json(ReleaseIpResponse {
-        message: "IP released successfully".to_string(),
-        vm_id: None,
-        ip: Some(ip),
-    }))
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReportConflictRequest {
+    pub ip: String,
+    #[serde(default)]
+    pub pool: Option<String>, // defaults to the default pool
+    #[serde(default)]
+    pub vm_id: Option<String>, // VM declining the address
+    #[serde(default)]
+    pub reason: Option<String>,
 }
 
-// Get allocation handler
-pub async fn get_allocation(
-    State(pool): State<IpPool>,
-    Path(vm_id): Path<String>,
-) -> Result<Json<crate::ippool::IpAllocation>, IpPoolError> {
-    tracing::debug!("Get allocation request - vm_id: {}", vm_id);
+#[derive(Debug, Deserialize)]
+pub struct ConflictQuery {
+    #[serde(default)]
+    pub pool: Option<String>, // list: every pool, admin actions: the default pool
+}
 
-    let allocation = pool.get_allocation(&vm_id).await?;
+#[derive(Debug, Deserialize)]
+pub struct LeakQuery {
+    #[serde(default)]
+    pub pool: Option<String>, // every pool when absent
+    #[serde(default)]
+    pub min_confidence: Option<f64>, // defaults to leaks::LIST_CONFIDENCE
+}
 
-    tracing::debug!("Allocation found - vm_id: {}, ip: {}", vm_id, allocation.ip);
-    Ok(Json(allocation))
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReleaseLeaksRequest {
+    #[serde(default)]
+    pub pool: Option<String>, // every pool when absent
+    #[serde(default)]
+    pub min_confidence: Option<f64>, // defaults to leaks::RELEASE_CONFIDENCE
+    #[serde(default)]
+    pub ips: Vec<String>, // only these candidates, empty: all of them
 }
 
-// List allocations handler
-pub async fn list_allocations(
-    State(pool): State<IpPool>,
-) -> Json<Vec<crate::ippool::IpAllocation>> {
-    tracing::debug!("List allocations request received");
+#[derive(Debug, Serialize)]
+pub struct LeakReleaseFailure {
+    pub pool: String,
+    pub ip: String,
+    pub vm_id: String,
+    pub error: String,
+}
 
-    let allocations = pool.list_allocations().await;
+#[derive(Debug, Serialize)]
+pub struct ReleaseLeaksResponse {
+    pub released: Vec<LeakCandidate>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failed: Vec<LeakReleaseFailure>, // e.g. vetoed by a hook or reallocated meanwhile
+}
 
-    tracing::debug!("Returning {} allocations", allocations.len());
-    Json(allocations)
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExcludeConflictRequest {
+    pub label: Option<String>,
 }
 
-// Get stats handler
-pub async fn get_stats(State(pool): State<IpPool>) -> Json<serde_json::Value> {
-    tracing::debug!("Get stats request received");
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MarkExternalRequest {
+    pub ip: String,
+    #[serde(default)]
+    pub description: Option<String>, // e.g. "lobby printer"
+    #[serde(default)]
+    pub pool: Option<String>, // defaults to the default pool
+}
 
-    let stats = pool.get_stats().await;
+#[derive(Debug, Serialize)]
+pub struct ConflictResponse {
+    pub pool: String,
+    pub ip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vm_id: Option<String>, // current holder of the address
+    #[serde(flatten)]
+    pub conflict: Conflict,
+}
 
-    tracing::debug!(
-        "Returning pool stats: total={}, allocated={}, available={}",
-        stats["total"],
-        stats["allocated"],
-        stats["available"]
-    );
-    Json(stats)
+#[derive(Debug, Deserialize)]
+pub struct LeaseImportQuery {
+    #[serde(default)]
+    pub pool: Option<String>, // defaults to the default pool
+    #[serde(default)]
+    pub format: Option<LeaseFormat>, // detected from the file when absent
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TerraformImportQuery {
+    #[serde(default)]
+    pub pool: Option<String>, // defaults to the default pool
+}
+
+#[derive(Debug, Serialize)]
+pub struct TerraformImportResponse {
+    pub pool: String,
+    #[serde(flatten)]
+    pub report: AdoptionReport,
+}
+
+// Line format of the scan target export
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetFormat {
+    #[default]
+    Nmap, // for `nmap -iL`: "172.16.0.2", hostnames as "# vm-1" comments
+    Ssh,   // for `ssh-keyscan -f`: "172.16.0.2", hostnames as "172.16.0.2,vm-1"
+    Hosts, // for /etc/hosts or dnsmasq addn-hosts: "172.16.0.2 vm-2.lab.local"
+}
+
+// DHCP server the configuration export is for
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DhcpFormat {
+    #[default]
+    Dnsmasq, // dhcp-range and dhcp-option lines, tagged with the pool name
+    Kea, // Dhcp4 configuration with a subnet4 entry per pool
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportDhcpQuery {
+    #[serde(default)]
+    pub format: DhcpFormat,
+    #[serde(default)]
+    pub pool: Option<String>, // every pool when absent
+}
+
+// Format of the static mapping export
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MappingFormat {
+    #[default]
+    Xml, // <staticmap> elements of a pfSense/OPNsense config.xml
+    Json, // the same fields as a JSON array, for API-driven imports
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportMappingsQuery {
+    #[serde(default)]
+    pub format: MappingFormat,
+    #[serde(default)]
+    pub pool: Option<String>, // every pool when absent
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportTargetsQuery {
+    #[serde(default)]
+    pub format: TargetFormat,
+    #[serde(default)]
+    pub pool: Option<String>, // every pool when absent
+    #[serde(default)]
+    pub hostnames: bool, // add the VM ID of each address
+    #[serde(default)]
+    pub vlan: Option<u16>, // only pools on this VLAN
+}
+
+// A pool on a VLAN, with its stats
+#[derive(Debug, Serialize)]
+pub struct VlanPoolResponse {
+    pub pool: String,
+    #[serde(flatten)]
+    pub stats: serde_json::Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LeaseImportResponse {
+    pub pool: String,
+    pub format: LeaseFormat,
+    #[serde(flatten)]
+    pub report: ImportReport,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventsQuery {
+    #[serde(default = "default_events_limit")]
+    pub limit: usize,
+}
+
+fn default_events_limit() -> usize {
+    100
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventReplayQuery {
+    // Id of the last event seen, 0 for none
+    #[serde(default)]
+    pub since: u64,
+    #[serde(default = "default_events_limit")]
+    pub limit: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EventReplayResponse {
+    pub events: Vec<Event>, // oldest first
+    pub next_cursor: u64,   // `since` for the next call
+    pub has_more: bool,     // more events after this page
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FreezeQuery {
+    #[serde(default)]
+    pub pool: Option<String>, // defaults to the default pool
+}
+
+#[derive(Debug, Serialize)]
+pub struct FreezeResponse {
+    pub message: String,
+    pub pool: String,
+    pub frozen: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceRequest {
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MaintenanceResponse {
+    pub read_only: bool,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthResponse {
+    pub status: Status, // worst component status
+    pub components: BTreeMap<String, ComponentHealth>,
+}
+
+impl IpPoolError {
+    // HTTP status and client-facing message, shared by every API version
+    pub fn status_and_message(&self) -> (StatusCode, String) {
+        match self {
+            IpPoolError::NoAvailableIps => {
+                tracing::warn!("Request failed: No available IPs in pool");
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "No available IPs in pool".to_string(),
+                )
+            }
+            IpPoolError::IpNotFound => {
+                tracing::warn!("Request failed: IP not found");
+                (StatusCode::NOT_FOUND, "IP not found".to_string())
+            }
+            IpPoolError::ConflictNotFound => {
+                tracing::warn!("Request failed: No conflict recorded for IP");
+                (
+                    StatusCode::NOT_FOUND,
+                    "No conflict recorded for IP".to_string(),
+                )
+            }
+            IpPoolError::HeldByOtherVm => {
+                tracing::warn!("Request failed: IP is held by another VM");
+                (StatusCode::CONFLICT, "IP is held by another VM".to_string())
+            }
+            IpPoolError::StaleFenceToken => {
+                tracing::warn!("Request failed: Stale fence token");
+                (
+                    StatusCode::CONFLICT,
+                    "Fence token is stale: the address was re-allocated since".to_string(),
+                )
+            }
+            IpPoolError::FenceTokenRequired => {
+                tracing::warn!("Request failed: Fence token required");
+                (
+                    StatusCode::PRECONDITION_REQUIRED,
+                    "A fence token is required for this operation".to_string(),
+                )
+            }
+            IpPoolError::ReleaseNotPending => {
+                tracing::warn!("Request failed: No release pending for VM");
+                (
+                    StatusCode::NOT_FOUND,
+                    "No release pending for VM".to_string(),
+                )
+            }
+            IpPoolError::CursorExpired => {
+                tracing::warn!("Request failed: Event cursor expired");
+                (
+                    StatusCode::GONE,
+                    "Event cursor expired, resync required".to_string(),
+                )
+            }
+            IpPoolError::SnapshotNotFound => {
+                tracing::warn!("Request failed: Snapshot not found");
+                (StatusCode::NOT_FOUND, "Snapshot not found".to_string())
+            }
+            IpPoolError::SnapshotAlreadyExists => {
+                tracing::warn!("Request failed: Snapshot already exists");
+                (StatusCode::CONFLICT, "Snapshot already exists".to_string())
+            }
+            IpPoolError::InvalidSnapshotName => {
+                tracing::warn!("Request failed: Invalid snapshot name");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "Invalid snapshot name (letters, digits, '-', '_' and '.', up to 64)"
+                        .to_string(),
+                )
+            }
+            IpPoolError::InvalidDelegation => {
+                tracing::warn!("Request failed: Invalid delegation");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "Delegated range must lie within the pool range and overlap no other delegation (one per team and pool)"
+                        .to_string(),
+                )
+            }
+            IpPoolError::DelegationNotFound => {
+                tracing::warn!("Request failed: Delegation not found");
+                (StatusCode::NOT_FOUND, "Delegation not found".to_string())
+            }
+            IpPoolError::UnknownApiKey => {
+                tracing::warn!("Request failed: Unknown API key");
+                (StatusCode::UNAUTHORIZED, "Unknown API key".to_string())
+            }
+            IpPoolError::PoolNotDelegated => {
+                tracing::warn!("Request failed: API key has no delegation in pool");
+                (
+                    StatusCode::FORBIDDEN,
+                    "API key has no delegation in this pool".to_string(),
+                )
+            }
+            IpPoolError::InvalidIp => {
+                tracing::warn!("Request failed: Invalid IP address");
+                (StatusCode::BAD_REQUEST, "Invalid IP address".to_string())
+            }
+            IpPoolError::PoolFrozen => {
+                tracing::warn!("Request failed: Pool is frozen");
+                (
+                    StatusCode::LOCKED,
+                    "Pool is frozen, new allocations are rejected".to_string(),
+                )
+            }
+            IpPoolError::PoolNotFound => {
+                tracing::warn!("Request failed: Pool not found");
+                (StatusCode::NOT_FOUND, "Pool not found".to_string())
+            }
+            IpPoolError::InvalidMigration => {
+                tracing::warn!("Request failed: Invalid migration");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "Source and target pool must differ".to_string(),
+                )
+            }
+            IpPoolError::InvalidMerge => {
+                tracing::warn!("Request failed: Pools cannot be merged");
+                (
+                    StatusCode::CONFLICT,
+                    "Pools cannot be merged (not adjacent or VM IDs overlap)".to_string(),
+                )
+            }
+            IpPoolError::InvalidSplit => {
+                tracing::warn!("Request failed: Invalid split boundary");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "Split boundary is outside the pool range".to_string(),
+                )
+            }
+            IpPoolError::PoolAlreadyExists => {
+                tracing::warn!("Request failed: Pool already exists");
+                (StatusCode::CONFLICT, "Pool already exists".to_string())
+            }
+            IpPoolError::InvalidMac => {
+                tracing::warn!("Request failed: Invalid MAC address");
+                (StatusCode::BAD_REQUEST, "Invalid MAC address".to_string())
+            }
+            IpPoolError::InvalidLabelSelector => {
+                tracing::warn!("Request failed: Invalid label selector");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "Label selector must be key=value".to_string(),
+                )
+            }
+            IpPoolError::InvalidHostname => {
+                tracing::warn!("Request failed: Invalid hostname");
+                (StatusCode::BAD_REQUEST, "Invalid hostname".to_string())
+            }
+            IpPoolError::BudgetExceeded(retry_after) => {
+                tracing::warn!(
+                    "Request failed: Allocation budget exceeded, retry in {}s",
+                    retry_after
+                );
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    format!("Allocation budget exceeded, retry in {}s", retry_after),
+                )
+            }
+            IpPoolError::WebhookNotFound => {
+                tracing::warn!("Request failed: Webhook not found");
+                (StatusCode::NOT_FOUND, "Webhook not found".to_string())
+            }
+            IpPoolError::ApprovalNotFound => {
+                tracing::warn!("Request failed: Pending allocation not found");
+                (
+                    StatusCode::NOT_FOUND,
+                    "Pending allocation not found".to_string(),
+                )
+            }
+            IpPoolError::AlreadyAllocated => {
+                tracing::warn!("Request failed: VM already holds an address for the slot");
+                (
+                    StatusCode::CONFLICT,
+                    "VM already holds an address for the slot".to_string(),
+                )
+            }
+            IpPoolError::AllocationDiffers => {
+                tracing::warn!("Request failed: VM already holds an address with other parameters");
+                (
+                    StatusCode::CONFLICT,
+                    "VM already holds an address with other parameters".to_string(),
+                )
+            }
+            IpPoolError::ApprovalRequired => {
+                tracing::warn!("Request failed: Allocation needs approval");
+                (
+                    StatusCode::FORBIDDEN,
+                    "New allocations in this pool need approval, request them with POST /api/v1/ip/allocate".to_string(),
+                )
+            }
+            IpPoolError::TemplateNotFound => {
+                tracing::warn!("Request failed: Pool template not found");
+                (StatusCode::NOT_FOUND, "Pool template not found".to_string())
+            }
+            IpPoolError::InvalidPoolConfig(reason) => {
+                tracing::warn!("Request failed: Invalid pool configuration: {}", reason);
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid pool configuration: {}", reason),
+                )
+            }
+            IpPoolError::IpInUse => {
+                tracing::warn!("Request failed: IP already in use");
+                (
+                    StatusCode::CONFLICT,
+                    "IP address is already in use".to_string(),
+                )
+            }
+            IpPoolError::StorageUnavailable(reason) => {
+                tracing::warn!("Request failed: Storage unavailable: {}", reason);
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!("Storage unavailable: {}", reason),
+                )
+            }
+            IpPoolError::ReadOnly(reason) => {
+                tracing::warn!("Request failed: Read-only maintenance mode: {}", reason);
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!("API is read-only for maintenance: {}", reason),
+                )
+            }
+            IpPoolError::ReadReplica(primary) => {
+                tracing::warn!("Request failed: Write sent to read replica of {}", primary);
+                (
+                    StatusCode::MISDIRECTED_REQUEST,
+                    format!("Read replica: send writes to the primary at {}", primary),
+                )
+            }
+            IpPoolError::NotLeader(Some(leader)) => {
+                tracing::warn!("Request failed: Write sent to follower of {}", leader);
+                (
+                    StatusCode::MISDIRECTED_REQUEST,
+                    format!("Not the cluster leader: send writes to {}", leader),
+                )
+            }
+            IpPoolError::NotLeader(None) => {
+                tracing::warn!("Request failed: No cluster leader elected");
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "No cluster leader elected yet, retry shortly".to_string(),
+                )
+            }
+            IpPoolError::PolicyViolation(reason) => {
+                tracing::warn!("Request failed: Rejected by allocation policy: {}", reason);
+                (
+                    StatusCode::FORBIDDEN,
+                    format!("Rejected by allocation policy: {}", reason),
+                )
+            }
+            IpPoolError::InvalidPolicy(reason) => {
+                tracing::warn!("Request failed: Invalid policy script: {}", reason);
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid policy script: {}", reason),
+                )
+            }
+            IpPoolError::InvalidLeaseFile(reason) => {
+                tracing::warn!("Request failed: Invalid lease file: {}", reason);
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid lease file: {}", reason),
+                )
+            }
+            IpPoolError::InvalidTerraformState(reason) => {
+                tracing::warn!("Request failed: Invalid Terraform state: {}", reason);
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid Terraform state: {}", reason),
+                )
+            }
+            IpPoolError::InvalidFilter(reason) => {
+                tracing::warn!("Request failed: Invalid filter: {}", reason);
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid filter: {}", reason),
+                )
+            }
+            IpPoolError::InvalidLease => {
+                tracing::warn!("Request failed: Invalid lease TTL");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "Lease TTL must be positive and cannot be combined with infinite".to_string(),
+                )
+            }
+            IpPoolError::InvalidExpiry => {
+                tracing::warn!("Request failed: Expiry not in the future");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "expires_at must be in the future".to_string(),
+                )
+            }
+            IpPoolError::InvalidRange => {
+                tracing::warn!("Request failed: Invalid sub-range prefix");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "Sub-range prefix must lie between the pool prefix and /32, at most 12 bits deeper"
+                        .to_string(),
+                )
+            }
+            IpPoolError::InvertedRange => {
+                tracing::warn!("Request failed: Range start above its end");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "Range start must not be above its end".to_string(),
+                )
+            }
+            IpPoolError::AdminOnly => {
+                tracing::warn!("Request failed: Admin-only operation");
+                (
+                    StatusCode::FORBIDDEN,
+                    "Infinite leases can only be requested through the admin API".to_string(),
+                )
+            }
+            IpPoolError::InvalidPublicKey => {
+                tracing::warn!("Request failed: Invalid WireGuard public key");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "Invalid WireGuard public key".to_string(),
+                )
+            }
+        }
+    }
+}
+
+// Custom error type for handlers
+impl IntoResponse for IpPoolError {
+    fn into_response(self) -> Response {
+        let (status, message) = self.status_and_message();
+        let body = Json(ErrorResponse { error: message });
+        (status, body).into_response()
+    }
+}
+
+// Health check handler, 503 while any component is unhealthy
+pub async fn health_check(State(state): State<AppState>) -> (StatusCode, Json<HealthResponse>) {
+    tracing::debug!("Health check request received");
+
+    let mut components = state.health.components();
+    components.insert("pool".to_string(), health::check_pools(&state.pools).await);
+    components.insert(
+        "events".to_string(),
+        health::check_events(&state.events).await,
+    );
+    if let Some(reason) = state.maintenance.reason() {
+        components.insert(
+            "maintenance".to_string(),
+            ComponentHealth {
+                status: Status::Degraded,
+                last_success: None,
+                error: Some(format!("read-only: {}", reason)),
+            },
+        );
+    }
+
+    let status = components
+        .values()
+        .map(|component| component.status)
+        .max()
+        .unwrap_or(Status::Healthy);
+    let code = if status == Status::Unhealthy {
+        tracing::warn!("Health check failed: {:?}", components);
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (code, Json(HealthResponse { status, components }))
+}
+
+// Embedded operator dashboard
+pub async fn dashboard() -> Html<&'static str> {
+    Html(include_str!("dashboard.html"))
+}
+
+// Allocate IP handler
+pub async fn allocate_ip(
+    State(state): State<AppState>,
+    Query(query): Query<AllocateIpQuery>,
+    headers: HeaderMap,
+    JsonBody(req): JsonBody<AllocateIpRequest>,
+) -> Result<(StatusCode, Json<AllocateIpResponse>), Response> {
+    if !query.dry_run
+        && let Some(pending) = hold_for_approval(&state, &headers, &req)
+            .await
+            .map_err(IntoResponse::into_response)?
+    {
+        return Err(pending);
+    }
+    allocate(state, query, &headers, req, false).await
+}
+
+// Admin allocate handler, the only way to get a lease that never expires
+pub async fn admin_allocate_ip(
+    State(state): State<AppState>,
+    Query(query): Query<AllocateIpQuery>,
+    headers: HeaderMap,
+    JsonBody(req): JsonBody<AllocateIpRequest>,
+) -> Result<(StatusCode, Json<AllocateIpResponse>), Response> {
+    allocate(state, query, &headers, req, true).await
+}
+
+// Pool and team of the request's API key, None without one. The key's
+// delegation decides the pool; naming another one is refused.
+async fn delegation(
+    state: &AppState,
+    headers: &HeaderMap,
+    pool: Option<&str>,
+) -> Result<Option<(String, String)>, IpPoolError> {
+    let Some(key) = headers.get(delegations::API_KEY_HEADER) else {
+        return Ok(None);
+    };
+    let key = key.to_str().map_err(|_| IpPoolError::UnknownApiKey)?;
+    let (pool_name, team) = state
+        .pools
+        .delegation_for_key(key)
+        .await
+        .ok_or(IpPoolError::UnknownApiKey)?;
+    if pool.is_some_and(|pool| pool != pool_name) {
+        return Err(IpPoolError::PoolNotDelegated);
+    }
+    Ok(Some((pool_name, team)))
+}
+
+// Whether the VM already holds an address for the slot, so allocating
+// only renews it
+async fn holds_slot(pool: &IpPool, vm_id: &str, slot: &Slot) -> bool {
+    pool.get_allocations(vm_id).await.is_ok_and(|allocations| {
+        allocations.iter().any(|allocation| {
+            allocation.interface == slot.interface
+                && allocation.purpose.as_deref() == Some(slot.purpose.as_str())
+        })
+    })
+}
+
+// A token on an allocation request renews that allocation or nothing. When
+// tokens are required, renewing without one is refused too.
+pub(crate) async fn check_renewal_fence(
+    state: &AppState,
+    pool: &IpPool,
+    vm_id: &str,
+    slot: &Slot,
+    fence: Option<u64>,
+) -> Result<(), IpPoolError> {
+    match fence {
+        Some(fence) => pool.check_fence(vm_id, Some(slot), fence).await,
+        None if state.require_fence && holds_slot(pool, vm_id, slot).await => {
+            Err(IpPoolError::FenceTokenRequired)
+        }
+        None => Ok(()),
+    }
+}
+
+// New allocations in pools under change management wait for approval.
+// Endpoints without an approval queue of their own refuse them; renewals
+// go through.
+pub(crate) async fn check_approval(
+    state: &AppState,
+    pool_name: &str,
+    pool: &IpPool,
+    vm_id: &str,
+    slot: &Slot,
+) -> Result<(), IpPoolError> {
+    if state.approvals.required(pool_name) && !holds_slot(pool, vm_id, slot).await {
+        return Err(IpPoolError::ApprovalRequired);
+    }
+    Ok(())
+}
+
+// Queue a request for a new address in a pool under change management,
+// answering 202 with the pending allocation. None when the request needs
+// no approval: the pool is not under change management or it renews an
+// address the VM holds.
+async fn hold_for_approval(
+    state: &AppState,
+    headers: &HeaderMap,
+    req: &AllocateIpRequest,
+) -> Result<Option<Response>, IpPoolError> {
+    let delegation = delegation(state, headers, req.pool.as_deref()).await?;
+    let pool_name = match &delegation {
+        Some((pool_name, _)) => pool_name.clone(),
+        None => req.pool.clone().unwrap_or_else(|| DEFAULT_POOL.to_string()),
+    };
+    let pool = state.pools.get(&pool_name).await?;
+    let slot = Slot::new(req.interface.clone(), req.purpose.clone());
+    if check_approval(state, &pool_name, &pool, &req.vm_id, &slot)
+        .await
+        .is_ok()
+    {
+        return Ok(None);
+    }
+    // Refuse now what approval would fail on
+    req.lease(false)?;
+    slaac::check_mac(req.mac.as_deref())?;
+    hostnames::check_hostname(req.hostname.as_deref())?;
+
+    let team = delegation.as_ref().map(|(_, team)| team.as_str());
+    let pending = state.approvals.submit(
+        &pool_name,
+        req.clone(),
+        headers,
+        insights::caller(headers, team),
+        unix_now(),
+    );
+    tracing::info!(
+        "Allocation awaiting approval - id: {}, pool: {}, vm_id: {}",
+        pending.id,
+        pool_name,
+        req.vm_id
+    );
+    Ok(Some((StatusCode::ACCEPTED, Json(pending)).into_response()))
+}
+
+pub(crate) fn require_fence(state: &AppState, fence: Option<u64>) -> Result<(), IpPoolError> {
+    match fence {
+        None if state.require_fence => Err(IpPoolError::FenceTokenRequired),
+        _ => Ok(()),
+    }
+}
+
+// Charge a new allocation to the budget of the request's API key, if
+// budgets are on. Requests without a key are charged to their X-Caller,
+// those without either share one budget. The budget key is returned so a
+// failed allocation can be refunded.
+fn charge_budget(state: &AppState, headers: &HeaderMap) -> Result<Option<String>, IpPoolError> {
+    let Some(budget) = &state.budget else {
+        return Ok(None);
+    };
+    let key = match headers
+        .get(delegations::API_KEY_HEADER)
+        .and_then(|key| key.to_str().ok())
+    {
+        Some(key) => key.to_string(),
+        None => format!("caller:{}", insights::caller(headers, None)),
+    };
+    budget.take(&key)?;
+    Ok(Some(key))
+}
+
+// Trimmed text, None when blank
+fn non_empty(value: &str) -> Option<String> {
+    Some(value.trim())
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+}
+
+// A hard expiry asked for has to be in the future
+fn check_expiry(expires_at: Option<u64>) -> Result<(), IpPoolError> {
+    match expires_at {
+        Some(expires_at) if expires_at <= unix_now() => Err(IpPoolError::InvalidExpiry),
+        _ => Ok(()),
+    }
+}
+
+// Where an allocation lands. An API key's delegation decides the pool and
+// the team, else it goes to the pool asked for.
+pub(crate) struct Target {
+    pub name: String,
+    pub pool: IpPool,
+    pub team: Option<String>,
+}
+
+pub(crate) async fn allocation_target(
+    state: &AppState,
+    headers: &HeaderMap,
+    pool: Option<&str>,
+) -> Result<Target, IpPoolError> {
+    let (name, team) = match delegation(state, headers, pool).await? {
+        Some((name, team)) => (name, Some(team)),
+        None => (pool.unwrap_or(DEFAULT_POOL).to_string(), None),
+    };
+    Ok(Target {
+        pool: state.pools.get(&name).await?,
+        name,
+        team,
+    })
+}
+
+// Allocates for the target's team, charged to the caller's budget unless
+// the VM only renews the slot's address. Every API allocates through here;
+// a failed allocation is refunded.
+pub(crate) async fn allocate_charged(
+    state: &AppState,
+    headers: &HeaderMap,
+    target: &Target,
+    vm_id: &str,
+    slot: &Slot,
+    lease: Lease,
+    requested: &Requested,
+) -> Result<(String, Option<u64>), IpPoolError> {
+    let Target { pool, team, .. } = target;
+    let charged = if state.budget.is_some() && !holds_slot(pool, vm_id, slot).await {
+        charge_budget(state, headers)?
+    } else {
+        None
+    };
+    let started = Instant::now();
+    let result =
+        (pool.allocate_with(team.as_deref(), vm_id.to_string(), slot, lease, requested)).await;
+    state.perf.observe(Operation::Allocate, started, &result);
+    if result.is_err() {
+        refund_budget(state, charged);
+    }
+    result
+}
+
+// What an allocation sets on its address. The owner is the one asked for,
+// else the team of the API key, the authenticated principal, for addresses
+// without an owner yet.
+pub(crate) fn requested(
+    labels: Option<BTreeMap<String, String>>,
+    hostname: Option<String>,
+    mac: Option<&str>,
+    owner: Option<&str>,
+    team: Option<&str>,
+    deadline: Option<u64>,
+) -> Requested {
+    Requested {
+        labels,
+        hostname,
+        mac: mac.and_then(slaac::canonical_mac),
+        owner: owner.and_then(non_empty),
+        default_owner: team.map(str::to_string),
+        deadline,
+        ..Requested::default()
+    }
+}
+
+fn refund_budget(state: &AppState, key: Option<String>) {
+    if let (Some(budget), Some(key)) = (&state.budget, key.as_deref()) {
+        budget.refund(key);
+    }
+}
+
+async fn allocate(
+    state: AppState,
+    query: AllocateIpQuery,
+    headers: &HeaderMap,
+    req: AllocateIpRequest,
+    admin: bool,
+) -> Result<(StatusCode, Json<AllocateIpResponse>), Response> {
+    tracing::info!(
+        "IP allocation request - vm_id: {}, pool: {:?}, interface: {:?}, purpose: {:?}, hostname: {:?}, ttl: {:?}, infinite: {}, dry_run: {}",
+        req.vm_id,
+        req.pool,
+        req.interface,
+        req.purpose,
+        req.hostname,
+        req.ttl,
+        req.infinite,
+        query.dry_run
+    );
+
+    let lease = req.lease(admin).map_err(IntoResponse::into_response)?;
+    check_expiry(req.expires_at).map_err(IntoResponse::into_response)?;
+    slaac::check_mac(req.mac.as_deref()).map_err(IntoResponse::into_response)?;
+    hostnames::check_hostname(req.hostname.as_deref()).map_err(IntoResponse::into_response)?;
+    let slot = Slot::new(req.interface.clone(), req.purpose.clone());
+    let target = allocation_target(&state, headers, req.pool.as_deref())
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let (pool_name, pool, team) = (&target.name, &target.pool, target.team.as_deref());
+    check_renewal_fence(&state, pool, &req.vm_id, &slot, req.fence_token)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let result = if query.dry_run {
+        let preview = match team {
+            Some(team) => pool.preview_delegated(team, &req.vm_id, &slot).await,
+            None => pool.preview_allocation(&req.vm_id, &slot).await,
+        };
+        match preview {
+            Ok(ip) => Ok((ip, pool.lease_expiry(lease).await)),
+            Err(e) => Err(e),
+        }
+    } else {
+        let requested = requested(
+            Some(req.labels.clone()).filter(|labels| !labels.is_empty()),
+            req.hostname.clone(),
+            req.mac.as_deref(),
+            req.owner.as_deref(),
+            team,
+            req.expires_at,
+        );
+        allocate_charged(
+            &state, headers, &target, &req.vm_id, &slot, lease, &requested,
+        )
+        .await
+    };
+    let (ip, expires_at) = match result {
+        Ok(allocated) => allocated,
+        Err(e) => return Err(allocation_error(pool, e).await),
+    };
+
+    let ipv6 = ipv6_address(pool, &req.vm_id, &slot, req.mac.as_deref()).await;
+    let mut response = allocation_response(
+        pool,
+        pool_name,
+        &req.vm_id,
+        slot,
+        ip.clone(),
+        expires_at,
+        req.labels,
+    )
+    .await;
+    response.hostname = req.hostname.or(response.hostname);
+    response.ipv6 = ipv6;
+    response.dry_run = query.dry_run;
+
+    if query.dry_run {
+        // Nothing was capped, the earlier of the two caps is what it would get
+        response.expires_at = response.expires_at.into_iter().chain(req.expires_at).min();
+        tracing::info!("IP allocation preview - vm_id: {}, ip: {}", req.vm_id, ip);
+        return Ok((StatusCode::OK, Json(response)));
+    }
+
+    let caller = insights::caller(headers, team);
+    state
+        .events
+        .emit(
+            EventKind::Allocated,
+            pool_name,
+            &req.vm_id,
+            &ip,
+            insights::details(&caller),
+        )
+        .await;
+
+    tracing::info!(
+        "IP allocated successfully - vm_id: {}, ip: {}",
+        req.vm_id,
+        ip
+    );
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+// The VM's IPv6 address in the pool's /64, as SLAAC derives it
+async fn ipv6_address(
+    pool: &IpPool,
+    vm_id: &str,
+    slot: &Slot,
+    mac: Option<&str>,
+) -> Option<Ipv6Addr> {
+    pool.slaac().await?.address(vm_id, slot, mac)
+}
+
+// Allocation response with the pool's network settings
+async fn allocation_response(
+    pool: &IpPool,
+    pool_name: &str,
+    vm_id: &str,
+    slot: Slot,
+    ip: String,
+    expires_at: Option<u64>,
+    labels: BTreeMap<String, String>,
+) -> AllocateIpResponse {
+    let stats = pool.get_stats().await;
+    let routes = pool.routes().await;
+    let options = pool.dhcp_options().await;
+
+    AllocateIpResponse {
+        vm_id: vm_id.to_string(),
+        pool: pool_name.to_string(),
+        interface: slot.interface,
+        purpose: slot.purpose,
+        gateway: stats["gateway"].as_str().unwrap().to_string(),
+        network: stats["network"].as_str().unwrap().to_string(),
+        vlan_id: pool.vlan_id().await,
+        ipv6: None,
+        hostname: pool.hostname(&ip).await,
+        dhcp_option_121: dhcp_option_121(&routes),
+        dns_servers: options.dns_servers,
+        search_domains: options.search_domains,
+        ntp_servers: options.ntp_servers,
+        routes: routes
+            .iter()
+            .map(|route| RouteResponse {
+                destination: route.cidr(),
+                next_hop: route.next_hop.to_string(),
+            })
+            .collect(),
+        lease_ttl: expires_at.map(|expires_at| expires_at.saturating_sub(unix_now())),
+        lease_expires_at: expires_at,
+        renew_before: pool.renew_before(expires_at).await,
+        expires_at: pool.deadline(&ip).await,
+        fence_token: pool.fence_token(&ip).await,
+        ip,
+        labels,
+        dry_run: false,
+    }
+}
+
+// Desired state of a VM's allocation, for declarative clients such as
+// Terraform providers
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PutAllocationRequest {
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub pool: Option<String>, // defaults to the default pool
+    #[serde(default)]
+    pub interface: Option<String>,
+    #[serde(default)]
+    pub purpose: Option<String>,
+    #[serde(default)]
+    pub ttl: Option<u64>, // seconds, overrides the pool default
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>, // replaces the current labels
+    #[serde(default)]
+    pub mac: Option<String>,
+    #[serde(default)]
+    pub owner: Option<String>, // defaults to the team of the API key
+    #[serde(default)]
+    pub expires_at: Option<u64>, // unix seconds, hard expiry; only ever brought forward
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApprovalsQuery {
+    #[serde(default)]
+    pub pool: Option<String>, // every pool when absent
+}
+
+// Owner, notes and ticket of one of a VM's addresses. Absent fields are
+// left as they are, empty ones are cleared.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatchAllocationRequest {
+    #[serde(default)]
+    pub pool: Option<String>, // defaults to the default pool
+    #[serde(default)]
+    pub interface: Option<String>,
+    #[serde(default)]
+    pub purpose: Option<String>,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub notes: Option<String>,
+    #[serde(default)]
+    pub ticket: Option<String>, // e.g. "OPS-1234"
+}
+
+// Which of a VM's addresses to clone
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CloneAllocationRequest {
+    #[serde(default)]
+    pub pool: Option<String>, // defaults to the default pool
+    #[serde(default)]
+    pub interface: Option<String>,
+    #[serde(default)]
+    pub purpose: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CloneAllocationResponse {
+    pub source: IpAllocation,
+    pub clone: IpAllocation,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteAllocationQuery {
+    #[serde(default)]
+    pub pool: Option<String>, // defaults to the default pool
+}
+
+// Create-or-get allocation handler. Repeating the same request converges
+// on the same allocation: 201 when it was created, 200 when it existed,
+// with its labels set to the requested ones either way. In pools under
+// change management a new allocation is 202 until approved.
+pub async fn put_allocation(
+    State(state): State<AppState>,
+    Path(vm_id): Path<String>,
+    headers: HeaderMap,
+    body: Option<JsonBody<PutAllocationRequest>>,
+) -> Result<(StatusCode, Json<AllocateIpResponse>), Response> {
+    let JsonBody(req) = body.unwrap_or(JsonBody(PutAllocationRequest::default()));
+    tracing::info!(
+        "Put allocation request - vm_id: {}, pool: {:?}, interface: {:?}, purpose: {:?}, ttl: {:?}",
+        vm_id,
+        req.pool,
+        req.interface,
+        req.purpose,
+        req.ttl
+    );
+
+    let request = AllocateIpRequest {
+        vm_id: vm_id.clone(),
+        hostname: req.hostname.clone(),
+        pool: req.pool.clone(),
+        interface: req.interface.clone(),
+        purpose: req.purpose.clone(),
+        ttl: req.ttl,
+        infinite: false,
+        labels: req.labels.clone(),
+        mac: req.mac.clone(),
+        fence_token: None,
+        owner: req.owner.clone(),
+        expires_at: req.expires_at,
+    };
+    if let Some(pending) = hold_for_approval(&state, &headers, &request)
+        .await
+        .map_err(IntoResponse::into_response)?
+    {
+        return Err(pending);
+    }
+
+    let lease = match req.ttl {
+        Some(0) => return Err(IpPoolError::InvalidLease.into_response()),
+        Some(ttl) => Lease::Ttl(Duration::from_secs(ttl)),
+        None => Lease::PoolDefault,
+    };
+    check_expiry(req.expires_at).map_err(IntoResponse::into_response)?;
+    slaac::check_mac(req.mac.as_deref()).map_err(IntoResponse::into_response)?;
+    hostnames::check_hostname(req.hostname.as_deref()).map_err(IntoResponse::into_response)?;
+    let slot = Slot::new(req.interface.clone(), req.purpose.clone());
+    let delegation = delegation(&state, &headers, req.pool.as_deref())
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let pool_name = match &delegation {
+        Some((pool_name, _)) => pool_name.clone(),
+        None => req.pool.clone().unwrap_or_else(|| DEFAULT_POOL.to_string()),
+    };
+    let pool = &state
+        .pools
+        .get(&pool_name)
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    // The resource is the VM's address in the pool: one for another
+    // interface or purpose is not converged on, it has to be deleted first
+    let existed = holds_slot(pool, &vm_id, &slot).await;
+    if !existed && pool.get_allocations(&vm_id).await.is_ok() {
+        return Err(IpPoolError::AllocationDiffers.into_response());
+    }
+    let charged = if existed {
+        None
+    } else {
+        match charge_budget(&state, &headers) {
+            Ok(charged) => charged,
+            Err(e) => return Err(allocation_error(pool, e).await),
+        }
+    };
+    let team = delegation.as_ref().map(|(_, team)| team.as_str());
+    let requested = requested(
+        Some(req.labels.clone()),
+        req.hostname.clone(),
+        req.mac.as_deref(),
+        req.owner.as_deref(),
+        team,
+        req.expires_at,
+    );
+    let started = Instant::now();
+    let result = (pool.allocate_with(team, vm_id.clone(), &slot, lease, &requested)).await;
+    state.perf.observe(Operation::Allocate, started, &result);
+    let (ip, expires_at) = match result {
+        Ok(allocated) => allocated,
+        Err(e) => {
+            refund_budget(&state, charged);
+            return Err(allocation_error(pool, e).await);
+        }
+    };
+
+    let ipv6 = ipv6_address(pool, &vm_id, &slot, req.mac.as_deref()).await;
+    let mut response = allocation_response(
+        pool,
+        &pool_name,
+        &vm_id,
+        slot,
+        ip.clone(),
+        expires_at,
+        req.labels,
+    )
+    .await;
+    response.ipv6 = ipv6;
+
+    if existed {
+        tracing::info!("Allocation unchanged - vm_id: {}, ip: {}", vm_id, ip);
+        return Ok((StatusCode::OK, Json(response)));
+    }
+    let caller = insights::caller(&headers, team);
+    state
+        .events
+        .emit(
+            EventKind::Allocated,
+            &pool_name,
+            &vm_id,
+            &ip,
+            insights::details(&caller),
+        )
+        .await;
+    tracing::info!("Allocation created - vm_id: {}, ip: {}", vm_id, ip);
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+// Clone allocation handler: gives the replacement VM of a blue/green
+// deployment a fresh address in the same pool and slot, with the labels,
+// owner, notes and ticket of the VM it replaces. The hostname is not
+// copied, as both VMs run side by side; the pool's template names the
+// clone. The source keeps its address until it is released.
+pub async fn clone_allocation(
+    State(state): State<AppState>,
+    Path((vm_id, new_vm_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: Option<JsonBody<CloneAllocationRequest>>,
+) -> Result<(StatusCode, Json<CloneAllocationResponse>), Response> {
+    let JsonBody(req) = body.unwrap_or(JsonBody(CloneAllocationRequest::default()));
+    tracing::info!(
+        "Clone allocation request - vm_id: {}, new_vm_id: {}, pool: {:?}, interface: {:?}, purpose: {:?}",
+        vm_id,
+        new_vm_id,
+        req.pool,
+        req.interface,
+        req.purpose
+    );
+
+    let slot = Slot::new(req.interface, req.purpose);
+    let delegation = delegation(&state, &headers, req.pool.as_deref())
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let pool_name = match &delegation {
+        Some((pool_name, _)) => pool_name.clone(),
+        None => req.pool.unwrap_or_else(|| DEFAULT_POOL.to_string()),
+    };
+    let pool = &state
+        .pools
+        .get(&pool_name)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let source = (pool.get_allocations(&vm_id).await)
+        .map_err(IntoResponse::into_response)?
+        .into_iter()
+        .find(|allocation| {
+            allocation.interface == slot.interface
+                && allocation.purpose.as_deref() == Some(slot.purpose.as_str())
+        })
+        .ok_or_else(|| IpPoolError::IpNotFound.into_response())?;
+    if holds_slot(pool, &new_vm_id, &slot).await {
+        return Err(IpPoolError::AlreadyAllocated.into_response());
+    }
+    check_approval(&state, &pool_name, pool, &new_vm_id, &slot)
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    let charged = match charge_budget(&state, &headers) {
+        Ok(charged) => charged,
+        Err(e) => return Err(allocation_error(pool, e).await),
+    };
+    let team = delegation.as_ref().map(|(_, team)| team.as_str());
+    let requested = Requested {
+        labels: Some(source.labels.clone()).filter(|labels| !labels.is_empty()),
+        owner: source.owner.clone(),
+        notes: source.notes.clone(),
+        ticket: source.ticket.clone(),
+        ..Requested::default()
+    };
+    let started = Instant::now();
+    let result = pool
+        .allocate_with(
+            team,
+            new_vm_id.clone(),
+            &slot,
+            Lease::PoolDefault,
+            &requested,
+        )
+        .await;
+    state.perf.observe(Operation::Allocate, started, &result);
+    let (ip, _) = match result {
+        Ok(allocated) => allocated,
+        Err(e) => {
+            refund_budget(&state, charged);
+            return Err(allocation_error(pool, e).await);
+        }
+    };
+    let clone = (pool.get_allocations(&new_vm_id).await)
+        .map_err(IntoResponse::into_response)?
+        .into_iter()
+        .find(|allocation| allocation.ip == ip)
+        .ok_or_else(|| IpPoolError::IpNotFound.into_response())?;
+
+    let caller = insights::caller(&headers, team);
+    let details = serde_json::json!({ "caller": caller, "cloned_from": vm_id });
+    state
+        .events
+        .emit(
+            EventKind::Allocated,
+            &pool_name,
+            &new_vm_id,
+            &ip,
+            Some(details),
+        )
+        .await;
+    tracing::info!(
+        "Allocation cloned - vm_id: {}, new_vm_id: {}, ip: {}",
+        vm_id,
+        new_vm_id,
+        ip
+    );
+    Ok((
+        StatusCode::CREATED,
+        Json(CloneAllocationResponse { source, clone }),
+    ))
+}
+
+// Patch allocation handler: records why an address was handed out, so
+// audits find the answer next to the allocation
+pub async fn patch_allocation(
+    State(state): State<AppState>,
+    Path(vm_id): Path<String>,
+    JsonBody(req): JsonBody<PatchAllocationRequest>,
+) -> Result<Json<IpAllocation>, IpPoolError> {
+    let pool_name = req.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    tracing::info!(
+        "Patch allocation request - vm_id: {}, pool: {}, interface: {:?}, purpose: {:?}",
+        vm_id,
+        pool_name,
+        req.interface,
+        req.purpose
+    );
+
+    let pool = state.pools.get(&pool_name).await?;
+    let slot = Slot::new(req.interface, req.purpose);
+    let mut allocation = (pool.get_allocations(&vm_id).await?)
+        .into_iter()
+        .find(|allocation| {
+            allocation.interface == slot.interface
+                && allocation.purpose.as_deref() == Some(slot.purpose.as_str())
+        })
+        .ok_or(IpPoolError::IpNotFound)?;
+
+    if let Some(owner) = req.owner {
+        allocation.owner = non_empty(&owner);
+    }
+    if let Some(notes) = req.notes {
+        allocation.notes = non_empty(&notes);
+    }
+    if let Some(ticket) = req.ticket {
+        allocation.ticket = non_empty(&ticket);
+    }
+    let annotation = Annotation {
+        owner: allocation.owner.clone(),
+        notes: allocation.notes.clone(),
+        ticket: allocation.ticket.clone(),
+    };
+    pool.annotate(&allocation.ip, annotation).await?;
+
+    tracing::info!(
+        "Allocation patched - vm_id: {}, ip: {}, ticket: {:?}",
+        vm_id,
+        allocation.ip,
+        allocation.ticket
+    );
+    Ok(Json(allocation))
+}
+
+// Delete allocation handler: releases every address of the VM in the pool
+// and succeeds (204) whether or not it held any
+pub async fn delete_allocation(
+    State(state): State<AppState>,
+    Path(vm_id): Path<String>,
+    Query(query): Query<DeleteAllocationQuery>,
+) -> Result<StatusCode, IpPoolError> {
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    tracing::info!(
+        "Delete allocation request - vm_id: {}, pool: {}",
+        vm_id,
+        pool_name
+    );
+
+    let pool = state.pools.get(&pool_name).await?;
+    let started = Instant::now();
+    let result = pool.release_ip(&vm_id).await;
+    state.perf.observe(Operation::Release, started, &result);
+    let released = match result {
+        Ok(released) => released,
+        Err(IpPoolError::IpNotFound) => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    for ip in &released {
+        state
+            .events
+            .emit(EventKind::Released, &pool_name, &vm_id, ip, None)
+            .await;
+    }
+
+    tracing::info!(
+        "Allocation deleted - vm_id: {}, released: {}",
+        vm_id,
+        released.len()
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Pending allocations handler: requests waiting for approval, oldest first
+pub async fn list_approvals(
+    State(state): State<AppState>,
+    Query(query): Query<ApprovalsQuery>,
+) -> Json<Vec<PendingAllocation>> {
+    tracing::debug!("List approvals request - pool: {:?}", query.pool);
+    Json(state.approvals.list(query.pool.as_deref()))
+}
+
+// Approve handler: makes the allocation as it was requested, with the
+// requester's API key. A request that still cannot be allocated, e.g. on
+// an exhausted pool, stays pending.
+pub async fn approve_allocation(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<(StatusCode, Json<AllocateIpResponse>), Response> {
+    let pending =
+        (state.approvals.take(id)).ok_or_else(|| IpPoolError::ApprovalNotFound.into_response())?;
+    tracing::info!(
+        "Allocation approved - id: {}, pool: {}, vm_id: {}",
+        id,
+        pending.pool,
+        pending.vm_id
+    );
+
+    let query = AllocateIpQuery::default();
+    let request = pending.request.clone();
+    let result = allocate(state.clone(), query, &pending.headers, request, false).await;
+    if result.is_err() {
+        tracing::warn!("Approved allocation failed, kept pending - id: {}", id);
+        state.approvals.restore(pending);
+    }
+    result
+}
+
+// Reject handler: drops the request, nothing is allocated
+pub async fn reject_allocation(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<PendingAllocation>, IpPoolError> {
+    let pending = state
+        .approvals
+        .take(id)
+        .ok_or(IpPoolError::ApprovalNotFound)?;
+    tracing::info!(
+        "Allocation rejected - id: {}, pool: {}, vm_id: {}",
+        id,
+        pending.pool,
+        pending.vm_id
+    );
+    Ok(Json(pending))
+}
+
+// Exhausted pools and spent budgets tell clients when a retry is worthwhile
+async fn allocation_error(pool: &IpPool, e: IpPoolError) -> Response {
+    let retry_after = match e {
+        IpPoolError::NoAvailableIps => Some(pool.retry_after().await),
+        IpPoolError::BudgetExceeded(retry_after) => Some(Duration::from_secs(retry_after)),
+        _ => None,
+    };
+
+    let mut response = e.into_response();
+    if let Some(retry_after) = retry_after {
+        response.headers_mut().insert(
+            header::RETRY_AFTER,
+            HeaderValue::from(retry_after.as_secs()),
+        );
+    }
+    response
+}
+
+// Release IP by VM_ID handler
+pub async fn release_ip(
+    State(state): State<AppState>,
+    Path(vm_id): Path<String>,
+    Query(query): Query<ReleaseQuery>,
+) -> Result<(StatusCode, Json<ReleaseIpResponse>), IpPoolError> {
+    tracing::info!(
+        "IP release request by VM ID - vm_id: {}, grace: {:?}, fence_token: {:?}",
+        vm_id,
+        query.grace,
+        query.fence_token
+    );
+    require_fence(&state, query.fence_token)?;
+
+    if let Some(grace) = query.grace.filter(|grace| *grace > 0) {
+        if let Some(fence) = query.fence_token {
+            state.pool.check_fence(&vm_id, None, fence).await?;
+        }
+        let (ips, release_at) = state
+            .pool
+            .defer_release(&vm_id, Duration::from_secs(grace))
+            .await?;
+        tracing::info!(
+            "IP release scheduled - vm_id: {}, ips: {:?}, release_at: {}",
+            vm_id,
+            ips,
+            release_at
+        );
+        return Ok((
+            StatusCode::ACCEPTED,
+            Json(ReleaseIpResponse {
+                message: "IP release scheduled".to_string(),
+                vm_id: Some(vm_id),
+                ip: None,
+                release_at: Some(release_at),
+            }),
+        ));
+    }
+
+    let started = Instant::now();
+    let result = match query.fence_token {
+        Some(fence) => state.pool.release_ip_fenced(&vm_id, fence).await,
+        None => state.pool.release_ip(&vm_id).await,
+    };
+    state.perf.observe(Operation::Release, started, &result);
+    for ip in result? {
+        state
+            .events
+            .emit(EventKind::Released, DEFAULT_POOL, &vm_id, &ip, None)
+            .await;
+    }
+
+    tracing::info!("IP released successfully - vm_id: {}", vm_id);
+    Ok((
+        StatusCode::OK,
+        Json(ReleaseIpResponse {
+            message: "IP released successfully".to_string(),
+            vm_id: Some(vm_id),
+            ip: None,
+            release_at: None,
+        }),
+    ))
+}
+
+// Cancel a deferred release handler
+pub async fn cancel_release(
+    State(state): State<AppState>,
+    Path(vm_id): Path<String>,
+) -> Result<Json<ReleaseIpResponse>, IpPoolError> {
+    tracing::info!("Cancel release request - vm_id: {}", vm_id);
+
+    let ips = state.pool.cancel_release(&vm_id).await?;
+
+    tracing::info!(
+        "Pending release cancelled - vm_id: {}, ips: {:?}",
+        vm_id,
+        ips
+    );
+    Ok(Json(ReleaseIpResponse {
+        message: "IP release cancelled".to_string(),
+        vm_id: Some(vm_id),
+        ip: None,
+        release_at: None,
+    }))
+}
+
+// Release IP by address handler
+pub async fn release_ip_by_address(
+    State(state): State<AppState>,
+    Path(ip): Path<String>,
+    Query(query): Query<ReleaseByIpRequest>,
+    body: Option<JsonBody<ReleaseByIpRequest>>,
+) -> Result<Json<ReleaseIpResponse>, IpPoolError> {
+    let JsonBody(req) = body.unwrap_or(JsonBody(ReleaseByIpRequest::default()));
+    let expected = query.vm_id.or(req.vm_id);
+    let fence = query.fence_token.or(req.fence_token);
+    tracing::info!(
+        "IP release request by address - ip: {}, expected vm_id: {:?}, fence_token: {:?}",
+        ip,
+        expected,
+        fence
+    );
+    require_fence(&state, fence)?;
+
+    let started = Instant::now();
+    let result = match (&expected, fence) {
+        (_, Some(fence)) => {
+            state
+                .pool
+                .release_address_fenced(&ip, expected.as_deref(), fence)
+                .await
+        }
+        (Some(vm_id), None) => state.pool.release_ip_held_by(&ip, vm_id).await,
+        (None, None) => state.pool.release_ip_by_address(&ip).await,
+    };
+    state.perf.observe(Operation::Release, started, &result);
+    let vm_id = result?;
+    state
+        .events
+        .emit(EventKind::Released, DEFAULT_POOL, &vm_id, &ip, None)
+        .await;
+
+    tracing::info!("IP released successfully - ip: {}", ip);
+    Ok(Json(ReleaseIpResponse {
+        message: "IP released successfully".to_string(),
+        vm_id: None,
+        ip: Some(ip),
+        release_at: None,
+    }))
+}
+
+// Release by label handler
+// Tears down everything carrying the label, e.g. an ephemeral CI cluster
+pub async fn release_by_label(
+    State(state): State<AppState>,
+    Query(query): Query<ReleaseByLabelQuery>,
+) -> Result<Json<ReleaseByLabelResponse>, IpPoolError> {
+    tracing::info!(
+        "IP release request by label - label: {}, pool: {:?}",
+        query.label,
+        query.pool
+    );
+    let (key, value) = query
+        .label
+        .split_once('=')
+        .filter(|(key, _)| !key.trim().is_empty())
+        .ok_or(IpPoolError::InvalidLabelSelector)?;
+
+    let names = match query.pool {
+        Some(name) => vec![name],
+        None => state.pools.names().await,
+    };
+    let mut released = Vec::new();
+    for name in names {
+        let pool = state.pools.get(&name).await?;
+        let started = Instant::now();
+        let result = pool.release_labeled(key.trim(), value.trim()).await;
+        state.perf.observe(Operation::Release, started, &result);
+        for (vm_id, ip) in result? {
+            state
+                .events
+                .emit(
+                    EventKind::Released,
+                    &name,
+                    &vm_id,
+                    &ip,
+                    Some(serde_json::json!({ "reason": "label", "label": query.label })),
+                )
+                .await;
+            released.push(LabelRelease {
+                pool: name.clone(),
+                vm_id,
+                ip,
+            });
+        }
+    }
+
+    tracing::info!(
+        "IPs released by label - label: {}, released: {}",
+        query.label,
+        released.len()
+    );
+    Ok(Json(ReleaseByLabelResponse {
+        label: query.label,
+        released,
+    }))
+}
+
+// Get allocation handler
+// Every interface and address of the VM, across pools
+pub async fn get_allocation(
+    State(state): State<AppState>,
+    Path(vm_id): Path<String>,
+) -> Result<Json<Vec<VmAddressResponse>>, IpPoolError> {
+    tracing::debug!("Get allocation request - vm_id: {}", vm_id);
+
+    let allocations = state.pools.allocations_of(&vm_id).await;
+    if allocations.is_empty() {
+        return Err(IpPoolError::IpNotFound);
+    }
+
+    tracing::debug!(
+        "Allocations found - vm_id: {}, addresses: {}",
+        vm_id,
+        allocations.len()
+    );
+    let mut addresses = Vec::new();
+    for (pool, allocation) in allocations {
+        let vlan_id = match state.pools.get(&pool).await {
+            Ok(pool) => pool.vlan_id().await,
+            Err(_) => None,
+        };
+        addresses.push(VmAddressResponse {
+            pool,
+            vlan_id,
+            allocation,
+        });
+    }
+    Ok(Json(addresses))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NetplanQuery {
+    #[serde(default = "default_interface")]
+    pub interface: String, // for addresses allocated without one
+}
+
+fn default_interface() -> String {
+    "eth0".to_string()
+}
+
+// Netplan handler: the VM's addresses as a netplan configuration, with the
+// gateway, routes and DNS settings of their pools, for cloud images to
+// consume as is
+pub async fn get_netplan(
+    State(state): State<AppState>,
+    Path(vm_id): Path<String>,
+    Query(query): Query<NetplanQuery>,
+) -> Result<Response, IpPoolError> {
+    tracing::debug!(
+        "Netplan request - vm_id: {}, interface: {}",
+        vm_id,
+        query.interface
+    );
+
+    let allocations = state.pools.allocations_of(&vm_id).await;
+    if allocations.is_empty() {
+        return Err(IpPoolError::IpNotFound);
+    }
+
+    let mut addresses = Vec::new();
+    for (pool_name, allocation) in allocations {
+        let pool = state.pools.get(&pool_name).await?;
+        let Ok(ip) = allocation.ip.parse() else {
+            continue;
+        };
+        // Secondary addresses do not route through the gateway
+        let gateway = if allocation.purpose.as_deref() == Some(ippool::PRIMARY) {
+            pool.gateway().await.parse().ok()
+        } else {
+            None
+        };
+        addresses.push(netplan::Address {
+            interface: allocation
+                .interface
+                .unwrap_or_else(|| query.interface.clone()),
+            ip,
+            prefix_len: pool.prefix_len().await,
+            gateway,
+            routes: pool.routes().await,
+            options: pool.dhcp_options().await,
+        });
+    }
+
+    // Primary addresses first, so guests use them as source addresses
+    addresses.sort_by_key(|address| address.gateway.is_none());
+    let yaml = netplan::render(&addresses);
+    Ok(([(header::CONTENT_TYPE, "application/yaml")], yaml).into_response())
+}
+
+// List allocations handler
+// Optionally narrowed down by a filter expression, see `filter`
+pub async fn list_allocations(
+    State(state): State<AppState>,
+    Query(query): Query<ListAllocationsQuery>,
+) -> Result<Json<Vec<IpAllocation>>, IpPoolError> {
+    tracing::debug!(
+        "List allocations request - pool: {:?}, q: {:?}",
+        query.pool,
+        query.q
+    );
+
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    let pool = state.pools.get(&pool_name).await?;
+    let filter = (query.q.as_deref())
+        .map(|q| Filter::parse(q, unix_now()).map_err(IpPoolError::InvalidFilter))
+        .transpose()?;
+
+    let mut allocations = pool.list_allocations().await;
+    allocations.extend(pool.list_reserved().await);
+    if let Some(filter) = filter {
+        allocations.retain(|allocation| filter.matches(&pool_name, allocation));
+    }
+
+    tracing::debug!("Returning {} allocations", allocations.len());
+    Ok(Json(allocations))
+}
+
+// Delta sync handler: allocations added, modified and removed since a
+// version handed out earlier, for controllers keeping a cache in sync
+pub async fn list_changes(
+    State(state): State<AppState>,
+    Query(query): Query<ChangesQuery>,
+) -> Result<Json<Changes>, IpPoolError> {
+    tracing::debug!(
+        "List changes request - pool: {:?}, since: {}",
+        query.pool,
+        query.since
+    );
+
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    let pool = state.pools.get(&pool_name).await?;
+    let changes = state.changes.since(&pool_name, &pool, query.since).await?;
+
+    tracing::debug!(
+        "Returning changes - version: {}, added: {}, modified: {}, removed: {}",
+        changes.version,
+        changes.added.len(),
+        changes.modified.len(),
+        changes.removed.len()
+    );
+    Ok(Json(changes))
+}
+
+// Upcoming free addresses handler
+pub async fn next_free(
+    State(state): State<AppState>,
+    Query(query): Query<NextFreeQuery>,
+) -> Result<Json<serde_json::Value>, IpPoolError> {
+    tracing::debug!(
+        "Next free request - pool: {:?}, count: {}",
+        query.pool,
+        query.count
+    );
+
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    let pool = state.pools.get(&pool_name).await?;
+    let next = pool.next_free(query.count.min(MAX_NEXT_FREE)).await?;
+
+    tracing::debug!("Returning {} upcoming free addresses", next.len());
+    Ok(Json(serde_json::json!({
+        "pool": pool_name,
+        "next_free": next,
+    })))
+}
+
+// List leases expiring soon handler
+pub async fn list_expiring(
+    State(state): State<AppState>,
+    Query(query): Query<ExpiringQuery>,
+) -> Result<Json<Vec<IpAllocation>>, IpPoolError> {
+    tracing::debug!(
+        "List expiring leases request - pool: {:?}, within: {:?}",
+        query.pool,
+        query.within
+    );
+
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    let pool = state.pools.get(&pool_name).await?;
+    let expiring = pool.list_expiring(query.within).await;
+
+    tracing::debug!("Returning {} expiring leases", expiring.len());
+    Ok(Json(expiring))
+}
+
+// Get stats handler
+pub async fn get_stats(
+    State(state): State<AppState>,
+    Query(query): Query<StatsQuery>,
+    headers: HeaderMap,
+) -> Result<Response, IpPoolError> {
+    tracing::debug!(
+        "Get stats request - pool: {:?}, group_by: {:?}",
+        query.pool,
+        query.group_by
+    );
+
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    let pool = state.pools.get(&pool_name).await?;
+    let mut stats = pool.get_stats().await;
+    if let Some(key) = query.group_by {
+        let total = stats["total"].as_f64().unwrap_or_default().max(1.0);
+        let groups: Vec<serde_json::Value> = pool
+            .group_stats(&key)
+            .await
+            .into_iter()
+            .map(|(value, allocated)| {
+                serde_json::json!({
+                    "value": value,
+                    "allocated": allocated,
+                    "usage": allocated as f64 / total * 100.0,
+                })
+            })
+            .collect();
+        stats["group_by"] = key.into();
+        stats["groups"] = groups.into();
+    }
+    // Burn rates drop as changes age out of their windows, the tag stays
+    // with the state
+    let stable = serde_json::to_vec(&stats).unwrap_or_default();
+    let tag = etag::weak(headers.get(header::ACCEPT), &stable);
+    stats["burn_rate"] = (pool.burn_rates().await.into_iter())
+        .map(|(window, rate)| (window.to_string(), rate.into()))
+        .collect::<serde_json::Map<_, _>>()
+        .into();
+
+    tracing::debug!(
+        "Returning pool stats: total={}, allocated={}, available={}",
+        stats["total"],
+        stats["allocated"],
+        stats["available"]
+    );
+    Ok(([(header::ETAG, tag)], Json(stats)).into_response())
+}
+
+// Get per-range stats handler
+pub async fn get_range_stats(
+    State(state): State<AppState>,
+    Query(query): Query<RangeStatsQuery>,
+) -> Result<Json<serde_json::Value>, IpPoolError> {
+    tracing::debug!(
+        "Get range stats request - pool: {:?}, prefix: {:?}",
+        query.pool,
+        query.prefix
+    );
+
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    let pool = state.pools.get(&pool_name).await?;
+    let stats = pool.get_range_stats(query.prefix).await?;
+
+    tracing::debug!(
+        "Returning {} range stats at /{}",
+        stats["ranges"].as_array().map_or(0, Vec::len),
+        stats["prefix"]
+    );
+    Ok(Json(stats))
+}
+
+// Get exhaustion forecast handler
+pub async fn get_forecast(
+    State(state): State<AppState>,
+    Query(query): Query<ForecastQuery>,
+) -> Result<Json<serde_json::Value>, IpPoolError> {
+    tracing::debug!(
+        "Get forecast request - pool: {:?}, window: {}s",
+        query.pool,
+        query.window
+    );
+
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    let pool = state.pools.get(&pool_name).await?;
+    let forecast = pool
+        .get_forecast(std::time::Duration::from_secs(query.window))
+        .await;
+
+    tracing::debug!(
+        "Returning forecast: net_rate_per_hour={}, seconds_to_exhaustion={}",
+        forecast["net_rate_per_hour"],
+        forecast["seconds_to_exhaustion"]
+    );
+    Ok(Json(forecast))
+}
+
+// Migrate VMs between pools handler
+pub async fn migrate_pool(
+    State(state): State<AppState>,
+    JsonBody(req): JsonBody<MigrateRequest>,
+) -> Result<Json<MigrateResponse>, IpPoolError> {
+    tracing::info!(
+        "Pool migration request - from: {}, to: {}, vm_ids: {:?}",
+        req.from_pool,
+        req.to_pool,
+        req.vm_ids
+    );
+
+    let migrated = state
+        .pools
+        .migrate(&req.from_pool, &req.to_pool, req.vm_ids)
+        .await?;
+
+    for migration in &migrated {
+        let details = serde_json::json!({
+            "from_pool": req.from_pool,
+            "old_ip": migration.old_ip,
+        });
+        state
+            .events
+            .emit(
+                EventKind::Migrated,
+                &req.to_pool,
+                &migration.vm_id,
+                &migration.new_ip,
+                Some(details),
+            )
+            .await;
+    }
+
+    tracing::info!(
+        "Pool migration completed - from: {}, to: {}, migrated: {}",
+        req.from_pool,
+        req.to_pool,
+        migrated.len()
+    );
+    Ok(Json(MigrateResponse {
+        from_pool: req.from_pool,
+        to_pool: req.to_pool,
+        migrated,
+    }))
+}
+
+// Merge pools handler
+pub async fn merge_pools(
+    State(state): State<AppState>,
+    JsonBody(req): JsonBody<MergePoolsRequest>,
+) -> Result<Json<serde_json::Value>, IpPoolError> {
+    tracing::info!(
+        "Pool merge request - pool: {}, other: {}",
+        req.pool,
+        req.other
+    );
+
+    let merged = state.pools.merge(&req.pool, &req.other).await?;
+    let stats = merged.get_stats().await;
+
+    tracing::info!(
+        "Pools merged - pool: {}, network: {}",
+        req.pool,
+        stats["network"]
+    );
+    Ok(Json(serde_json::json!({ req.pool: stats })))
+}
+
+// Split pool handler
+pub async fn split_pool(
+    State(state): State<AppState>,
+    JsonBody(req): JsonBody<SplitPoolRequest>,
+) -> Result<Json<serde_json::Value>, IpPoolError> {
+    tracing::info!(
+        "Pool split request - pool: {}, at: {}, new_pool: {}",
+        req.pool,
+        req.at,
+        req.new_pool
+    );
+
+    let (lower, upper) = state
+        .pools
+        .split(&req.pool, req.at, req.new_pool.clone())
+        .await?;
+
+    tracing::info!(
+        "Pool split - pool: {}, new_pool: {}",
+        req.pool,
+        req.new_pool
+    );
+    Ok(Json(serde_json::json!({
+        req.pool: lower.get_stats().await,
+        req.new_pool: upper.get_stats().await,
+    })))
+}
+
+// Pools on a VLAN handler, for automation keyed by VLAN ID
+pub async fn pools_by_vlan(
+    State(state): State<AppState>,
+    Path(vlan_id): Path<u16>,
+) -> Result<Json<Vec<VlanPoolResponse>>, IpPoolError> {
+    tracing::debug!("Pools by VLAN request - vlan: {}", vlan_id);
+
+    let mut pools = Vec::new();
+    for name in state.pools.names().await {
+        let pool = state.pools.get(&name).await?;
+        if pool.vlan_id().await == Some(vlan_id) {
+            pools.push(VlanPoolResponse {
+                pool: name,
+                stats: pool.get_stats().await,
+            });
+        }
+    }
+    if pools.is_empty() {
+        return Err(IpPoolError::PoolNotFound);
+    }
+
+    tracing::debug!("Pools on VLAN {}: {}", vlan_id, pools.len());
+    Ok(Json(pools))
+}
+
+// List pool templates handler
+pub async fn list_templates(State(state): State<AppState>) -> Json<Templates> {
+    Json(state.templates.as_ref().clone())
+}
+
+// Create a pool from a template handler. The new pool is validated along
+// with the existing ones, so it cannot overlap them.
+pub async fn create_pool_from_template(
+    State(state): State<AppState>,
+    JsonBody(req): JsonBody<PoolFromTemplateRequest>,
+) -> Result<(StatusCode, Json<PoolFromTemplateResponse>), IpPoolError> {
+    tracing::info!(
+        "Pool from template request - template: {}, cidr: {}, name: {}",
+        req.template,
+        req.cidr,
+        req.name
+    );
+
+    let template = state
+        .templates
+        .get(&req.template)
+        .ok_or(IpPoolError::TemplateNotFound)?;
+    let layout = template
+        .instantiate(&req.cidr)
+        .map_err(IpPoolError::InvalidPoolConfig)?;
+    if let Some(vlan_id) = req.vlan_id
+        && !VLAN_IDS.contains(&vlan_id)
+    {
+        return Err(IpPoolError::InvalidPoolConfig(format!(
+            "vlan_id: {} is not a VLAN ID (1-4094)",
+            vlan_id
+        )));
+    }
+
+    let existing = state.pools.snapshot().await;
+    if existing.contains_key(&req.name) {
+        return Err(IpPoolError::PoolAlreadyExists);
+    }
+    let mut config = ProposedConfig {
+        pools: existing
+            .iter()
+            .map(|(name, snapshot)| templates::existing(name, snapshot))
+            .collect(),
+    };
+    config
+        .pools
+        .push(layout.proposed(&req.name, req.allow_public));
+    let (errors, warnings): (Vec<Finding>, Vec<Finding>) = validate::validate(&config)
+        .findings
+        .into_iter()
+        .filter(|finding| finding.pool == req.name)
+        .partition(|finding| finding.severity == Severity::Error);
+    if !errors.is_empty() {
+        let reasons: Vec<String> = errors
+            .iter()
+            .map(|finding| format!("{}: {}", finding.field, finding.message))
+            .collect();
+        return Err(IpPoolError::InvalidPoolConfig(reasons.join("; ")));
+    }
+
+    let pool = layout.build().await?;
+    pool.set_vlan_id(req.vlan_id).await;
+    state.pools.create(req.name.clone(), pool.clone()).await?;
+
+    tracing::info!(
+        "Pool created from template - template: {}, name: {}, network: {}/{}",
+        req.template,
+        req.name,
+        layout.network,
+        layout.prefix_len
+    );
+    Ok((
+        StatusCode::CREATED,
+        Json(PoolFromTemplateResponse {
+            name: req.name,
+            template: req.template,
+            stats: pool.get_stats().await,
+            warnings,
+        }),
+    ))
+}
+
+// List flagged addresses handler
+pub async fn list_conflicts(
+    State(state): State<AppState>,
+    Query(query): Query<ConflictQuery>,
+) -> Result<Json<Vec<ConflictResponse>>, IpPoolError> {
+    tracing::debug!("List conflicts request - pool: {:?}", query.pool);
+
+    let names = match query.pool {
+        Some(pool) => vec![pool],
+        None => state.pools.names().await,
+    };
+    let mut conflicts = Vec::new();
+    for name in names {
+        let pool = state.pools.get(&name).await?;
+        for (ip, conflict, vm_id) in pool.list_conflicts().await {
+            conflicts.push(ConflictResponse {
+                pool: name.clone(),
+                ip,
+                vm_id,
+                conflict,
+            });
+        }
+    }
+
+    tracing::debug!("Returning {} conflicts", conflicts.len());
+    Ok(Json(conflicts))
+}
+
+// Ping sweep handler: probe every address of the pool (every pool when
+// none is given), flagging free addresses that answer as conflicts
+pub async fn run_sweep(
+    State(state): State<AppState>,
+    Query(query): Query<ConflictQuery>,
+) -> Result<Json<Vec<SweepReport>>, IpPoolError> {
+    tracing::info!("Ping sweep request - pool: {:?}", query.pool);
+
+    let names = match query.pool {
+        Some(pool) => vec![pool],
+        None => state.pools.names().await,
+    };
+    let mut reports = Vec::new();
+    for name in names {
+        let pool = state.pools.get(&name).await?;
+        reports.push(state.sweeps.run(&name, &pool).await);
+    }
+
+    tracing::info!("Ping sweep finished - pools: {}", reports.len());
+    Ok(Json(reports))
+}
+
+// Unknown hosts handler: free addresses that answered a ping sweep and
+// are flagged until someone says what they are
+pub async fn list_unknown_hosts(
+    State(state): State<AppState>,
+    Query(query): Query<ConflictQuery>,
+) -> Result<Json<Vec<UnknownHost>>, IpPoolError> {
+    tracing::debug!("Unknown hosts request - pool: {:?}", query.pool);
+
+    let names = match query.pool {
+        Some(pool) => vec![pool],
+        None => state.pools.names().await,
+    };
+    let mut hosts = Vec::new();
+    for name in names {
+        let pool = state.pools.get(&name).await?;
+        hosts.extend(state.sweeps.unknown(&name, &pool).await);
+    }
+    Ok(Json(hosts))
+}
+
+// Adopt unknown host handler: records a host found by a ping sweep as an
+// external device or a VM's static address, or notes it is being looked
+// into
+pub async fn adopt_unknown_host(
+    State(state): State<AppState>,
+    Path(ip): Path<String>,
+    Query(query): Query<ConflictQuery>,
+    JsonBody(adoption): JsonBody<Adoption>,
+) -> Result<Json<serde_json::Value>, IpPoolError> {
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    let action = adoption.action();
+    tracing::info!(
+        "Adopt unknown host request - pool: {}, ip: {}, action: {}",
+        pool_name,
+        ip,
+        action
+    );
+
+    let pool = state.pools.get(&pool_name).await?;
+    let vm_id = match &adoption {
+        Adoption::Static { vm_id } => Some(vm_id.clone()),
+        _ => None,
+    };
+    state.sweeps.adopt(&pool_name, &pool, &ip, adoption).await?;
+    if let Some(vm_id) = &vm_id {
+        let details = serde_json::json!({ "adopted_from": "sweep" });
+        state
+            .events
+            .emit(EventKind::Allocated, &pool_name, vm_id, &ip, Some(details))
+            .await;
+    }
+
+    tracing::info!(
+        "Unknown host adopted - pool: {}, ip: {}, action: {}",
+        pool_name,
+        ip,
+        action
+    );
+    Ok(Json(serde_json::json!({
+        "message": "Unknown host adopted",
+        "pool": pool_name,
+        "ip": ip,
+        "action": action,
+        "vm_id": vm_id,
+    })))
+}
+
+// Latest ping sweep reports handler
+pub async fn get_sweep_reports(
+    State(state): State<AppState>,
+    Query(query): Query<ConflictQuery>,
+) -> Result<Json<Vec<SweepReport>>, IpPoolError> {
+    tracing::debug!("Sweep reports request - pool: {:?}", query.pool);
+
+    let reports = match query.pool {
+        Some(pool) => {
+            state.pools.get(&pool).await?;
+            state.sweeps.report(&pool).into_iter().collect()
+        }
+        None => state.sweeps.reports(),
+    };
+    Ok(Json(reports))
+}
+
+// Allocation insights handler: per-caller activity in the window and the
+// ongoing anomalies
+pub async fn get_insights(State(state): State<AppState>) -> Json<InsightsReport> {
+    tracing::debug!("Insights request received");
+    Json(state.insights.report(unix_now()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeliveryQuery {
+    pub status: Option<DeliveryStatus>,
+}
+
+// List configured webhooks handler
+pub async fn list_webhooks(State(state): State<AppState>) -> Json<Vec<WebhookSummary>> {
+    tracing::debug!("List webhooks request received");
+    Json(state.webhooks.list())
+}
+
+// Webhook delivery status handler: recent deliveries, newest first
+pub async fn list_webhook_deliveries(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<DeliveryQuery>,
+) -> Result<Json<Vec<Delivery>>, IpPoolError> {
+    tracing::debug!(
+        "Webhook deliveries request - webhook: {}, status: {:?}",
+        id,
+        query.status
+    );
+    let webhook = state.webhooks.get(&id)?;
+    Ok(Json(
+        webhook
+            .deliveries()
+            .into_iter()
+            .filter(|delivery| query.status.is_none_or(|status| delivery.status == status))
+            .collect(),
+    ))
+}
+
+// Dead-lettered deliveries handler, oldest first
+pub async fn list_dead_letters(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<Delivery>>, IpPoolError> {
+    tracing::debug!("Dead letters request - webhook: {}", id);
+    Ok(Json(state.webhooks.get(&id)?.dead_letters()))
+}
+
+// Redrive handler: try every dead letter of the webhook again
+pub async fn redrive_dead_letters(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<Delivery>>, IpPoolError> {
+    tracing::info!("Redrive request - webhook: {}", id);
+    let redriven = state.webhooks.get(&id)?.redrive().await;
+    tracing::info!(
+        "Redrive finished - webhook: {}, redriven: {}, delivered: {}",
+        id,
+        redriven.len(),
+        redriven
+            .iter()
+            .filter(|delivery| delivery.status == DeliveryStatus::Delivered)
+            .count()
+    );
+    Ok(Json(redriven))
+}
+
+// Report handler: utilization of every pool and the allocation changes
+// within the window, as JSON, Markdown or HTML
+pub async fn get_report(
+    State(state): State<AppState>,
+    Query(query): Query<ReportQuery>,
+) -> Response {
+    tracing::debug!(
+        "Report request - window: {:?}, format: {:?}",
+        query.window,
+        query.format
+    );
+
+    let report = reports::generate(&state.pools, &state.events, query.window, unix_now()).await;
+    let body = match query.format {
+        ReportFormat::Json => return Json(report).into_response(),
+        ReportFormat::Markdown => reports::markdown(&report),
+        ReportFormat::Html => reports::html(&report),
+    };
+    ([(header::CONTENT_TYPE, query.format.content_type())], body).into_response()
+}
+
+// State stream for read replicas: the full state as a JSON line whenever
+// it changed, with heartbeats in between
+pub async fn replication_stream(State(state): State<AppState>) -> Response {
+    tracing::info!("Read replica connected to the state stream");
+    (
+        [(header::CONTENT_TYPE, replication::CONTENT_TYPE)],
+        replication::stream(state.pools.clone(), replication::CHECK_INTERVAL),
+    )
+        .into_response()
+}
+
+// Leak candidates of the pool (every pool when none is given) at or above
+// the confidence, most likely first
+async fn scan_leaks(
+    state: &AppState,
+    pool: Option<String>,
+    min_confidence: f64,
+) -> Result<Vec<LeakCandidate>, IpPoolError> {
+    let names = match pool {
+        Some(pool) => vec![pool],
+        None => state.pools.names().await,
+    };
+    let now = unix_now();
+    let mut candidates = Vec::new();
+    for name in names {
+        let pool = state.pools.get(&name).await?;
+        let sweep = state.sweeps.report(&name);
+        candidates.extend(state.leaks.scan(&name, &pool, sweep.as_ref(), now).await);
+    }
+    candidates.retain(|candidate| candidate.confidence >= min_confidence);
+    leaks::sort(&mut candidates);
+    Ok(candidates)
+}
+
+// Leak report handler: allocations that probably outlived their VM, scored
+// from lease age, renewals and the last ping sweep
+pub async fn list_leaks(
+    State(state): State<AppState>,
+    Query(query): Query<LeakQuery>,
+) -> Result<Json<Vec<LeakCandidate>>, IpPoolError> {
+    tracing::debug!(
+        "Leak report request - pool: {:?}, min_confidence: {:?}",
+        query.pool,
+        query.min_confidence
+    );
+
+    let min_confidence = query.min_confidence.unwrap_or(leaks::LIST_CONFIDENCE);
+    let candidates = scan_leaks(&state, query.pool, min_confidence).await?;
+
+    tracing::debug!("Returning {} leak candidates", candidates.len());
+    Ok(Json(candidates))
+}
+
+// Release probable leaks handler: re-scores and releases every candidate at
+// or above the confidence, each only while the same VM still holds it
+pub async fn release_leaks(
+    State(state): State<AppState>,
+    body: Option<JsonBody<ReleaseLeaksRequest>>,
+) -> Result<Json<ReleaseLeaksResponse>, IpPoolError> {
+    let JsonBody(req) = body.unwrap_or(JsonBody(ReleaseLeaksRequest::default()));
+    let min_confidence = req.min_confidence.unwrap_or(leaks::RELEASE_CONFIDENCE);
+    tracing::info!(
+        "Leak release request - pool: {:?}, min_confidence: {}, ips: {}",
+        req.pool,
+        min_confidence,
+        req.ips.len()
+    );
+
+    let mut candidates = scan_leaks(&state, req.pool, min_confidence).await?;
+    if !req.ips.is_empty() {
+        candidates.retain(|candidate| req.ips.contains(&candidate.ip));
+    }
+
+    let mut released = Vec::new();
+    let mut failed = Vec::new();
+    for candidate in candidates {
+        let pool = state.pools.get(&candidate.pool).await?;
+        let started = Instant::now();
+        let result = pool
+            .release_ip_held_by(&candidate.ip, &candidate.vm_id)
+            .await;
+        state.perf.observe(Operation::Release, started, &result);
+        match result {
+            Ok(vm_id) => {
+                state
+                    .events
+                    .emit(
+                        EventKind::Released,
+                        &candidate.pool,
+                        &vm_id,
+                        &candidate.ip,
+                        Some(serde_json::json!({
+                            "reason": "leak",
+                            "confidence": candidate.confidence,
+                            "owner": candidate.owner,
+                        })),
+                    )
+                    .await;
+                released.push(candidate);
+            }
+            Err(e) => failed.push(LeakReleaseFailure {
+                pool: candidate.pool,
+                ip: candidate.ip,
+                vm_id: candidate.vm_id,
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    tracing::info!(
+        "🧹 Released probable leaks - released: {}, failed: {}",
+        released.len(),
+        failed.len()
+    );
+    Ok(Json(ReleaseLeaksResponse { released, failed }))
+}
+
+// Decline report handler: a VM found its address already in use
+pub async fn report_conflict(
+    State(state): State<AppState>,
+    JsonBody(req): JsonBody<ReportConflictRequest>,
+) -> Result<(StatusCode, Json<ConflictResponse>), IpPoolError> {
+    tracing::warn!(
+        "Conflict report - ip: {}, pool: {:?}, vm_id: {:?}, reason: {:?}",
+        req.ip,
+        req.pool,
+        req.vm_id,
+        req.reason
+    );
+
+    let pool_name = req.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    let pool = state.pools.get(&pool_name).await?;
+    let conflict = Conflict {
+        source: ConflictSource::Decline,
+        detail: req
+            .reason
+            .unwrap_or_else(|| "address already in use".to_string()),
+        reported_by: req.vm_id,
+        reported_at: unix_now(),
+        investigation: None,
+    };
+    let vm_id = pool.flag_conflict(&req.ip, conflict.clone()).await?;
+
+    tracing::warn!(
+        "🚩 Address flagged as conflicting - pool: {}, ip: {}",
+        pool_name,
+        req.ip
+    );
+    Ok((
+        StatusCode::CREATED,
+        Json(ConflictResponse {
+            pool: pool_name,
+            ip: req.ip,
+            vm_id,
+            conflict,
+        }),
+    ))
+}
+
+// Clear a conflict flag handler, the address can be handed out again
+pub async fn clear_conflict(
+    State(state): State<AppState>,
+    Path(ip): Path<String>,
+    Query(query): Query<ConflictQuery>,
+) -> Result<Json<serde_json::Value>, IpPoolError> {
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    tracing::info!("Clear conflict request - pool: {}, ip: {}", pool_name, ip);
+
+    let pool = state.pools.get(&pool_name).await?;
+    pool.clear_conflict(&ip).await?;
+
+    tracing::info!("Conflict cleared - pool: {}, ip: {}", pool_name, ip);
+    Ok(Json(serde_json::json!({
+        "message": "Conflict cleared",
+        "pool": pool_name,
+        "ip": ip,
+    })))
+}
+
+// Permanently exclude a conflicting address handler
+pub async fn exclude_conflict(
+    State(state): State<AppState>,
+    Path(ip): Path<String>,
+    Query(query): Query<ConflictQuery>,
+    body: Option<JsonBody<ExcludeConflictRequest>>,
+) -> Result<Json<serde_json::Value>, IpPoolError> {
+    let JsonBody(req) = body.unwrap_or(JsonBody(ExcludeConflictRequest::default()));
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    let label = req
+        .label
+        .filter(|label| !label.trim().is_empty())
+        .unwrap_or_else(|| "conflict".to_string());
+    tracing::info!(
+        "Exclude conflict request - pool: {}, ip: {}, label: {}",
+        pool_name,
+        ip,
+        label
+    );
+
+    let pool = state.pools.get(&pool_name).await?;
+    pool.exclude_conflict(&ip, label.clone()).await?;
+
+    tracing::info!(
+        "Conflicting address reserved - pool: {}, ip: {}",
+        pool_name,
+        ip
+    );
+    Ok(Json(serde_json::json!({
+        "message": "Address excluded from allocation",
+        "pool": pool_name,
+        "ip": ip,
+        "label": label,
+    })))
+}
+
+// Mark external handler: keeps an address used by a device outside this
+// system from being allocated, without inventing a VM to hold it
+pub async fn mark_external(
+    State(state): State<AppState>,
+    JsonBody(req): JsonBody<MarkExternalRequest>,
+) -> Result<Json<serde_json::Value>, IpPoolError> {
+    let pool_name = req.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    let description = req
+        .description
+        .filter(|description| !description.trim().is_empty())
+        .unwrap_or_else(|| "external".to_string());
+    tracing::info!(
+        "Mark external request - pool: {}, ip: {}, description: {}",
+        pool_name,
+        req.ip,
+        description
+    );
+
+    let pool = state.pools.get(&pool_name).await?;
+    pool.mark_external(&req.ip, description.clone()).await?;
+
+    tracing::info!(
+        "Address marked as used externally - pool: {}, ip: {}",
+        pool_name,
+        req.ip
+    );
+    Ok(Json(serde_json::json!({
+        "message": "Address marked as used externally",
+        "pool": pool_name,
+        "ip": req.ip,
+        "description": description,
+    })))
+}
+
+// Validate a proposed pool configuration handler; nothing is applied
+pub async fn validate_config(JsonBody(config): JsonBody<ProposedConfig>) -> Json<ValidationReport> {
+    tracing::info!("Config validation request - pools: {}", config.pools.len());
+
+    let report = validate::validate(&config);
+
+    tracing::info!(
+        "Config validated - valid: {}, findings: {}",
+        report.valid,
+        report.findings.len()
+    );
+    Json(report)
+}
+
+// Import the leases of a legacy DHCP server handler
+pub async fn import_leases(
+    State(state): State<AppState>,
+    Query(query): Query<LeaseImportQuery>,
+    contents: String,
+) -> Result<Json<LeaseImportResponse>, IpPoolError> {
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    let format = query
+        .format
+        .unwrap_or_else(|| LeaseFormat::detect(&contents));
+    tracing::info!(
+        "Lease import request - pool: {}, format: {:?}, bytes: {}",
+        pool_name,
+        format,
+        contents.len()
+    );
+
+    let pool = state.pools.get(&pool_name).await?;
+    let parsed = leases::parse(&contents, format).map_err(IpPoolError::InvalidLeaseFile)?;
+    let report = leases::import(&pool, parsed, unix_now()).await;
+
+    for lease in &report.imported {
+        let details = serde_json::json!({
+            "imported_from": format,
+            "mac": lease.mac,
+        });
+        state
+            .events
+            .emit(
+                EventKind::Allocated,
+                &pool_name,
+                &lease.vm_id,
+                &lease.ip,
+                Some(details),
+            )
+            .await;
+    }
+
+    tracing::info!(
+        "📥 Leases imported - pool: {}, imported: {}, skipped: {}",
+        pool_name,
+        report.imported.len(),
+        report.skipped.len()
+    );
+    Ok(Json(LeaseImportResponse {
+        pool: pool_name,
+        format,
+        report,
+    }))
+}
+
+// Adopt the address assignments of a Terraform state handler
+pub async fn import_terraform(
+    State(state): State<AppState>,
+    Query(query): Query<TerraformImportQuery>,
+    contents: String,
+) -> Result<Json<TerraformImportResponse>, IpPoolError> {
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    tracing::info!(
+        "Terraform import request - pool: {}, bytes: {}",
+        pool_name,
+        contents.len()
+    );
+
+    let pool = state.pools.get(&pool_name).await?;
+    let addresses = terraform::parse(&contents).map_err(IpPoolError::InvalidTerraformState)?;
+    let report = terraform::adopt(&pool, addresses).await;
+
+    for address in &report.adopted {
+        let details = serde_json::json!({
+            "imported_from": "terraform",
+            "resource": address.resource,
+        });
+        state
+            .events
+            .emit(
+                EventKind::Allocated,
+                &pool_name,
+                &address.vm_id,
+                &address.ip,
+                Some(details),
+            )
+            .await;
+    }
+
+    tracing::info!(
+        "📥 Terraform state adopted - pool: {}, adopted: {}, skipped: {}",
+        pool_name,
+        report.adopted.len(),
+        report.skipped.len()
+    );
+    Ok(Json(TerraformImportResponse {
+        pool: pool_name,
+        report,
+    }))
+}
+
+// List recent events handler
+pub async fn list_events(
+    State(state): State<AppState>,
+    Query(query): Query<EventsQuery>,
+) -> Json<Vec<Event>> {
+    tracing::debug!("List events request - limit: {}", query.limit);
+
+    let events = state.events.recent(query.limit).await;
+
+    tracing::debug!("Returning {} events", events.len());
+    Json(events)
+}
+
+// Replay missed events handler: events after a cursor, for consumers
+// catching up after being offline
+pub async fn replay_events(
+    State(state): State<AppState>,
+    Query(query): Query<EventReplayQuery>,
+) -> Result<Json<EventReplayResponse>, IpPoolError> {
+    tracing::debug!(
+        "Replay events request - since: {}, limit: {}",
+        query.since,
+        query.limit
+    );
+
+    let events = state
+        .events
+        .replay(query.since, query.limit)
+        .await
+        .ok_or(IpPoolError::CursorExpired)?;
+    let next_cursor = events.last().map_or(query.since, |event| event.id);
+
+    tracing::debug!("Replaying {} events", events.len());
+    Ok(Json(EventReplayResponse {
+        has_more: next_cursor < state.events.last_id(),
+        events,
+        next_cursor,
+    }))
+}
+
+// Allocator performance counters handler
+pub async fn get_perf(State(state): State<AppState>) -> Json<serde_json::Value> {
+    tracing::debug!("Perf counters request received");
+
+    let mut report = state.perf.report();
+    report["lock_wait"] = serde_json::json!(state.pools.lock_wait().await);
+
+    Json(report)
+}
+
+// Freeze pool handler
+pub async fn freeze_pool(
+    State(state): State<AppState>,
+    Query(query): Query<FreezeQuery>,
+) -> Result<Json<FreezeResponse>, IpPoolError> {
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    tracing::info!("Pool freeze request received - pool: {}", pool_name);
+
+    state.pools.get(&pool_name).await?.set_frozen(true).await;
+
+    tracing::info!(
+        "Pool {} frozen, new allocations will be rejected",
+        pool_name
+    );
+    Ok(Json(FreezeResponse {
+        message: "Pool frozen".to_string(),
+        pool: pool_name,
+        frozen: true,
+    }))
+}
+
+// Unfreeze pool handler
+pub async fn unfreeze_pool(
+    State(state): State<AppState>,
+    Query(query): Query<FreezeQuery>,
+) -> Result<Json<FreezeResponse>, IpPoolError> {
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    tracing::info!("Pool unfreeze request received - pool: {}", pool_name);
+
+    state.pools.get(&pool_name).await?.set_frozen(false).await;
+
+    tracing::info!("Pool {} unfrozen, allocations resumed", pool_name);
+    Ok(Json(FreezeResponse {
+        message: "Pool unfrozen".to_string(),
+        pool: pool_name,
+        frozen: false,
+    }))
+}
+
+// Read-only maintenance mode status
+pub async fn get_maintenance(State(state): State<AppState>) -> Json<MaintenanceResponse> {
+    let reason = state.maintenance.reason();
+    Json(MaintenanceResponse {
+        read_only: reason.is_some(),
+        reason,
+    })
+}
+
+// Make the whole API read-only, e.g. for a backup/restore window
+pub async fn enable_maintenance(
+    State(state): State<AppState>,
+    body: Option<JsonBody<MaintenanceRequest>>,
+) -> Json<MaintenanceResponse> {
+    let JsonBody(req) = body.unwrap_or(JsonBody(MaintenanceRequest::default()));
+    let reason = req
+        .reason
+        .filter(|reason| !reason.trim().is_empty())
+        .unwrap_or_else(|| maintenance::DEFAULT_REASON.to_string());
+    tracing::info!("Maintenance mode request received - reason: {}", reason);
+
+    state.maintenance.enable(reason.clone());
+
+    tracing::warn!("🚧 API is read-only: {}", reason);
+    Json(MaintenanceResponse {
+        read_only: true,
+        reason: Some(reason),
+    })
+}
+
+pub async fn disable_maintenance(State(state): State<AppState>) -> Json<MaintenanceResponse> {
+    tracing::info!("Maintenance mode end request received");
+
+    state.maintenance.disable();
+
+    tracing::info!("Maintenance mode off, writes resumed");
+    Json(MaintenanceResponse {
+        read_only: false,
+        reason: None,
+    })
+}
+
+// Consistency check handler: verify the invariants of every pool without
+// changing anything
+pub async fn check_consistency(State(state): State<AppState>) -> Json<ConsistencyReport> {
+    tracing::info!("Consistency check request received");
+
+    let report = consistency::check(&state.pools, false).await;
+
+    tracing::info!(
+        "Consistency check done - inconsistencies: {}",
+        report.issues
+    );
+    Json(report)
+}
+
+// Consistency repair handler: the same check, fixing what it finds
+pub async fn repair_consistency(State(state): State<AppState>) -> Json<ConsistencyReport> {
+    tracing::info!("Consistency repair request received");
+
+    let report = consistency::check(&state.pools, true).await;
+
+    if report.repaired {
+        tracing::warn!("Repaired {} inconsistencies", report.issues);
+    }
+    Json(report)
+}
+
+// Upload a pool snapshot to object storage on demand
+pub async fn upload_snapshot(
+    State(snapshots): State<S3Snapshots>,
+) -> Result<(StatusCode, Json<serde_json::Value>), IpPoolError> {
+    tracing::info!("On-demand S3 snapshot request received");
+
+    let key = snapshots
+        .take_snapshot()
+        .await
+        .map_err(IpPoolError::StorageUnavailable)?;
+
+    tracing::info!("S3 snapshot uploaded - key: {}", key);
+    Ok((StatusCode::CREATED, Json(serde_json::json!({ "key": key }))))
+}
+
+// Re-read the allocation policy script, keeping the old one on errors
+pub async fn reload_policy(
+    State(policy): State<Arc<ScriptPolicy>>,
+) -> Result<Json<serde_json::Value>, IpPoolError> {
+    tracing::info!("Policy script reload request received");
+
+    policy.reload().map_err(IpPoolError::InvalidPolicy)?;
+
+    tracing::info!("📜 Policy script reloaded from {}", policy.path().display());
+    Ok(Json(serde_json::json!({
+        "message": "Policy script reloaded",
+        "path": policy.path(),
+    })))
+}
+
+// Delegate an IPv6 prefix handler
+pub async fn allocate_prefix6(
+    State(prefix6): State<Prefix6Pool>,
+    JsonBody(req): JsonBody<AllocatePrefix6Request>,
+) -> Result<(StatusCode, Json<DelegatedPrefix>), IpPoolError> {
+    tracing::info!("IPv6 prefix request - vm_id: {}", req.vm_id);
+
+    let prefix = prefix6.allocate(req.vm_id).await?;
+
+    tracing::info!(
+        "IPv6 prefix delegated - vm_id: {}, prefix: {}",
+        prefix.vm_id,
+        prefix.prefix
+    );
+    Ok((StatusCode::CREATED, Json(prefix)))
+}
+
+// Release a delegated IPv6 prefix handler
+pub async fn release_prefix6(
+    State(prefix6): State<Prefix6Pool>,
+    Path(vm_id): Path<String>,
+) -> Result<Json<ReleaseIpResponse>, IpPoolError> {
+    tracing::info!("IPv6 prefix release request - vm_id: {}", vm_id);
+
+    let prefix = prefix6.release(&vm_id).await?;
+
+    tracing::info!(
+        "IPv6 prefix released - vm_id: {}, prefix: {}",
+        vm_id,
+        prefix.prefix
+    );
+    Ok(Json(ReleaseIpResponse {
+        message: "Prefix released successfully".to_string(),
+        vm_id: Some(prefix.vm_id),
+        ip: Some(prefix.prefix),
+        release_at: None,
+    }))
+}
+
+// Get delegated IPv6 prefix handler
+pub async fn get_prefix6(
+    State(prefix6): State<Prefix6Pool>,
+    Path(vm_id): Path<String>,
+) -> Result<Json<DelegatedPrefix>, IpPoolError> {
+    tracing::debug!("Get IPv6 prefix request - vm_id: {}", vm_id);
+
+    let prefix = prefix6.get(&vm_id).await?;
+    Ok(Json(prefix))
+}
+
+// List delegated IPv6 prefixes handler
+pub async fn list_prefixes6(State(prefix6): State<Prefix6Pool>) -> Json<Vec<DelegatedPrefix>> {
+    tracing::debug!("List IPv6 prefixes request received");
+    Json(prefix6.list().await)
+}
+
+// IPv6 prefix delegation stats handler
+pub async fn get_prefix6_stats(State(prefix6): State<Prefix6Pool>) -> Json<serde_json::Value> {
+    tracing::debug!("IPv6 prefix stats request received");
+    Json(prefix6.stats().await)
+}
+
+// Add WireGuard peer handler
+pub async fn add_wireguard_peer(
+    State(wg): State<WireGuardPool>,
+    JsonBody(req): JsonBody<AddPeerRequest>,
+) -> Result<(StatusCode, Json<WireGuardPeer>), IpPoolError> {
+    tracing::info!(
+        "WireGuard peer request - public_key: {}, name: {:?}",
+        req.public_key,
+        req.name
+    );
+
+    let peer = wg.add_peer(req.public_key, req.name).await?;
+
+    tracing::info!(
+        "WireGuard peer added - public_key: {}, address: {}",
+        peer.public_key,
+        peer.address
+    );
+    Ok((StatusCode::CREATED, Json(peer)))
+}
+
+// Remove WireGuard peer handler
+pub async fn remove_wireguard_peer(
+    State(wg): State<WireGuardPool>,
+    Path(public_key): Path<String>,
+) -> Result<Json<ReleaseIpResponse>, IpPoolError> {
+    tracing::info!(
+        "WireGuard peer removal request - public_key: {}",
+        public_key
+    );
+
+    wg.remove_peer(&public_key).await?;
+
+    tracing::info!("WireGuard peer removed - public_key: {}", public_key);
+    Ok(Json(ReleaseIpResponse {
+        message: "Peer removed successfully".to_string(),
+        vm_id: None,
+        ip: None,
+        release_at: None,
+    }))
+}
+
+// Get WireGuard peer handler
+pub async fn get_wireguard_peer(
+    State(wg): State<WireGuardPool>,
+    Path(public_key): Path<String>,
+) -> Result<Json<WireGuardPeer>, IpPoolError> {
+    tracing::debug!("Get WireGuard peer request - public_key: {}", public_key);
+
+    let peer = wg.get_peer(&public_key).await?;
+    Ok(Json(peer))
+}
+
+// List WireGuard peers handler
+pub async fn list_wireguard_peers(State(wg): State<WireGuardPool>) -> Json<Vec<WireGuardPeer>> {
+    tracing::debug!("List WireGuard peers request received");
+
+    let peers = wg.list_peers().await;
+
+    tracing::debug!("Returning {} WireGuard peers", peers.len());
+    Json(peers)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateDelegationRequest {
+    pub team: String,
+    pub start: Ipv4Addr,
+    pub end: Ipv4Addr,
+    #[serde(default)]
+    pub pool: Option<String>, // defaults to the default pool
+}
+
+#[derive(Debug, Serialize)]
+pub struct DelegationResponse {
+    pub pool: String,
+    #[serde(flatten)]
+    pub usage: DelegationUsage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>, // only when created, never stored
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DelegationQuery {
+    #[serde(default)]
+    pub pool: Option<String>,
+}
+
+// Delegate a sub-range of a pool to a team, returning the team's API key
+pub async fn create_delegation(
+    State(state): State<AppState>,
+    JsonBody(req): JsonBody<CreateDelegationRequest>,
+) -> Result<(StatusCode, Json<DelegationResponse>), IpPoolError> {
+    let pool_name = req.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    tracing::info!(
+        "Delegation request - team: {}, pool: {}, range: {}-{}",
+        req.team,
+        pool_name,
+        req.start,
+        req.end
+    );
+
+    let pool = state.pools.get(&pool_name).await?;
+    let api_key = delegations::new_key()
+        .map_err(|e| IpPoolError::StorageUnavailable(format!("cannot generate API key: {}", e)))?;
+    pool.delegate(
+        &req.team,
+        req.start,
+        req.end,
+        delegations::hash_key(&api_key),
+    )
+    .await?;
+    let usage = pool
+        .delegations()
+        .await
+        .into_iter()
+        .find(|usage| usage.team == req.team)
+        .ok_or(IpPoolError::DelegationNotFound)?;
+
+    tracing::info!(
+        "Range {}-{} of pool {} delegated to team {} ({} addresses, {} already allocated)",
+        usage.start,
+        usage.end,
+        pool_name,
+        usage.team,
+        usage.total,
+        usage.allocated
+    );
+    Ok((
+        StatusCode::CREATED,
+        Json(DelegationResponse {
+            pool: pool_name,
+            usage,
+            api_key: Some(api_key),
+        }),
+    ))
+}
+
+// Delegations of every pool (or one) with their usage
+pub async fn list_delegations(
+    State(state): State<AppState>,
+    Query(query): Query<DelegationQuery>,
+) -> Result<Json<Vec<DelegationResponse>>, IpPoolError> {
+    tracing::debug!("Delegation list request - pool: {:?}", query.pool);
+
+    let names = match query.pool {
+        Some(pool) => vec![pool],
+        None => state.pools.names().await,
+    };
+    let mut delegations = Vec::new();
+    for name in names {
+        let pool = state.pools.get(&name).await?;
+        delegations.extend(
+            pool.delegations()
+                .await
+                .into_iter()
+                .map(|usage| DelegationResponse {
+                    pool: name.clone(),
+                    usage,
+                    api_key: None,
+                }),
+        );
+    }
+    Ok(Json(delegations))
+}
+
+// Hand a team's delegation back to the pool; its allocations stay
+pub async fn revoke_delegation(
+    State(state): State<AppState>,
+    Path(team): Path<String>,
+    Query(query): Query<DelegationQuery>,
+) -> Result<StatusCode, IpPoolError> {
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    tracing::info!(
+        "Delegation revoke request - team: {}, pool: {}",
+        team,
+        pool_name
+    );
+
+    let pool = state.pools.get(&pool_name).await?;
+    let delegation = pool.revoke_delegation(&team).await?;
+
+    tracing::info!(
+        "Delegation of {}-{} in pool {} revoked from team {}",
+        delegation.start,
+        delegation.end,
+        pool_name,
+        team
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn snapshot_store(state: &AppState) -> Result<&Arc<dyn StateStore>, IpPoolError> {
+    state
+        .snapshot_store
+        .as_ref()
+        .ok_or_else(|| IpPoolError::StorageUnavailable("no storage backend configured".to_string()))
+}
+
+fn storage_error(e: storage::StorageError) -> IpPoolError {
+    IpPoolError::StorageUnavailable(e.to_string())
+}
+
+// Take a named snapshot of every pool, e.g. before a risky bulk operation
+pub async fn create_snapshot(
+    State(state): State<AppState>,
+    JsonBody(req): JsonBody<CreateSnapshotRequest>,
+) -> Result<(StatusCode, Json<SnapshotInfo>), IpPoolError> {
+    tracing::info!("Snapshot request received - name: {}", req.name);
+
+    if !storage::valid_snapshot_name(&req.name) {
+        return Err(IpPoolError::InvalidSnapshotName);
+    }
+    let store = snapshot_store(&state)?;
+    if store
+        .load_snapshot(&req.name)
+        .await
+        .map_err(storage_error)?
+        .is_some()
+    {
+        return Err(IpPoolError::SnapshotAlreadyExists);
+    }
+
+    let snapshot = NamedSnapshot {
+        name: req.name,
+        created_at: unix_now(),
+        state: state.pools.snapshot().await,
+    };
+    store
+        .save_snapshot(&snapshot)
+        .await
+        .map_err(storage_error)?;
+
+    let info = SnapshotInfo::from(&snapshot);
+    tracing::info!(
+        "📸 Snapshot '{}' taken in {} backend - pools: {}, allocations: {}",
+        info.name,
+        store.name(),
+        info.pools.len(),
+        info.allocations
+    );
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+// Named snapshots, oldest first
+pub async fn list_snapshots(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<SnapshotInfo>>, IpPoolError> {
+    tracing::debug!("Snapshot list request received");
+
+    let snapshots = snapshot_store(&state)?
+        .list_snapshots()
+        .await
+        .map_err(storage_error)?;
+    let mut infos: Vec<SnapshotInfo> = snapshots.iter().map(SnapshotInfo::from).collect();
+    infos.sort_by(|a, b| (a.created_at, &a.name).cmp(&(b.created_at, &b.name)));
+
+    tracing::debug!("Snapshot list - count: {}", infos.len());
+    Ok(Json(infos))
+}
+
+// Roll every pool in the snapshot back to it; pools created since are kept
+pub async fn restore_snapshot(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<SnapshotInfo>, IpPoolError> {
+    tracing::info!("Snapshot restore request received - name: {}", name);
+
+    if !storage::valid_snapshot_name(&name) {
+        return Err(IpPoolError::SnapshotNotFound);
+    }
+    let snapshot = snapshot_store(&state)?
+        .load_snapshot(&name)
+        .await
+        .map_err(storage_error)?
+        .ok_or(IpPoolError::SnapshotNotFound)?;
+
+    let info = SnapshotInfo::from(&snapshot);
+    state.pools.restore(snapshot.state).await;
+
+    tracing::warn!(
+        "⏪ Pools restored to snapshot '{}' - pools: {}, allocations: {}",
+        info.name,
+        info.pools.len(),
+        info.allocations
+    );
+    Ok(Json(info))
+}
+
+// Snapshot diff handler: allocations added, removed and changed between
+// two snapshots, for change reports
+pub async fn diff_snapshots(
+    State(state): State<AppState>,
+    JsonBody(req): JsonBody<SnapshotDiffRequest>,
+) -> Json<SnapshotDiff> {
+    tracing::debug!(
+        "Snapshot diff request - pools: {}, against current state: {}",
+        req.from.len(),
+        req.to.is_none()
+    );
+
+    let to = match req.to {
+        Some(to) => to,
+        None => state.pools.snapshot().await,
+    };
+    let diff = diff::diff(&req.from, &to);
+
+    tracing::debug!(
+        "Snapshot diff - added: {}, removed: {}, changed: {}",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.changed.len()
+    );
+    Json(diff)
+}
+
+// Monitoring check handler: one Nagios-style status line (OK, WARNING,
+// CRITICAL or UNKNOWN) on pool utilization, for tools that do not parse
+// JSON
+pub async fn check_stats(
+    State(state): State<AppState>,
+    Query(query): Query<CheckQuery>,
+) -> Result<String, IpPoolError> {
+    tracing::debug!(
+        "Stats check request - pool: {:?}, warn: {:?}, crit: {:?}",
+        query.pool,
+        query.warn,
+        query.crit
+    );
+
+    let names = match query.pool {
+        Some(pool) => vec![pool],
+        None => state.pools.names().await,
+    };
+    let mut pools = Vec::new();
+    for name in names {
+        let pool = state.pools.get(&name).await?;
+        let stats = pool.get_stats().await;
+        let thresholds = pool.thresholds().await.unwrap_or_default();
+        pools.push(PoolUsage {
+            name,
+            allocated: stats["allocated"].as_u64().unwrap_or(0),
+            total: stats["total"].as_u64().unwrap_or(0),
+            thresholds: Thresholds {
+                warn: query.warn.unwrap_or(thresholds.warn),
+                crit: query.crit.unwrap_or(thresholds.crit),
+            },
+        });
+    }
+    let (status, mut line) = check::evaluate(&pools);
+
+    tracing::debug!("Stats check status: {}", status.label());
+    line.push('\n');
+    Ok(line)
+}
+
+// Scan target export handler: allocated addresses one per line, in
+// address order, for security scans and monitoring target lists
+pub async fn export_targets(
+    State(state): State<AppState>,
+    Query(query): Query<ExportTargetsQuery>,
+) -> Result<String, IpPoolError> {
+    tracing::debug!(
+        "Target export request - format: {:?}, pool: {:?}, hostnames: {}, vlan: {:?}",
+        query.format,
+        query.pool,
+        query.hostnames,
+        query.vlan
+    );
+
+    let names = match query.pool {
+        Some(pool) => vec![pool],
+        None => state.pools.names().await,
+    };
+    let mut targets = Vec::new();
+    for name in names {
+        let pool = state.pools.get(&name).await?;
+        let vlan_id = pool.vlan_id().await;
+        if query.vlan.is_some() && vlan_id != query.vlan {
+            continue;
+        }
+        for allocation in pool.list_allocations().await {
+            if let Ok(ip) = allocation.ip.parse::<Ipv4Addr>() {
+                targets.push((ip, allocation.vm_id, allocation.hostname, vlan_id));
+            }
+        }
+    }
+    targets.sort();
+
+    let mut export = String::new();
+    for (ip, vm_id, hostname, vlan_id) in &targets {
+        let line = match (query.format, query.hostnames, vlan_id) {
+            // Addresses without a hostname have no hosts entry
+            (TargetFormat::Hosts, _, _) => match hostname {
+                Some(hostname) => format!("{} {}", ip, hostname),
+                None => continue,
+            },
+            (_, false, _) => ip.to_string(),
+            (TargetFormat::Nmap, true, Some(vlan_id)) => {
+                format!("{} # {} vlan {}", ip, vm_id, vlan_id)
+            }
+            (TargetFormat::Nmap, true, None) => format!("{} # {}", ip, vm_id),
+            (TargetFormat::Ssh, true, _) => format!("{},{}", ip, vm_id),
+        };
+        export.push_str(&line);
+        export.push('\n');
+    }
+
+    tracing::debug!("Exporting {} targets", targets.len());
+    Ok(export)
+}
+
+// DHCP configuration export handler: each pool's range, gateway, routes,
+// search domains and NTP servers, so the DHCP server configures guests from
+// the same source as the allocation API
+pub async fn export_dhcp(
+    State(state): State<AppState>,
+    Query(query): Query<ExportDhcpQuery>,
+) -> Result<String, IpPoolError> {
+    tracing::debug!(
+        "DHCP export request - format: {:?}, pool: {:?}",
+        query.format,
+        query.pool
+    );
+
+    let names = match query.pool {
+        Some(pool) => vec![pool],
+        None => state.pools.names().await,
+    };
+    let mut pools = Vec::new();
+    for name in names {
+        let snapshot = state.pools.get(&name).await?.snapshot().await;
+        pools.push((name, snapshot));
+    }
+
+    let export = match query.format {
+        DhcpFormat::Dnsmasq => (pools.iter())
+            .map(|(name, snapshot)| dhcp::dnsmasq(name, snapshot))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        DhcpFormat::Kea => {
+            let subnets: Vec<serde_json::Value> = (pools.iter().zip(1..))
+                .map(|((name, snapshot), id)| dhcp::kea(id, name, snapshot))
+                .collect();
+            let config = serde_json::json!({"Dhcp4": {"subnet4": subnets}});
+            serde_json::to_string_pretty(&config).unwrap() + "\n"
+        }
+    };
+
+    tracing::debug!("Exporting DHCP configuration of {} pools", pools.len());
+    Ok(export)
+}
+
+// Static mapping export handler: a DHCP reservation for every allocation
+// with a known MAC, in address order, to keep pfSense/OPNsense DHCP
+// reservations in sync with the pools
+pub async fn export_static_mappings(
+    State(state): State<AppState>,
+    Query(query): Query<ExportMappingsQuery>,
+) -> Result<Response, IpPoolError> {
+    tracing::debug!(
+        "Static mapping export request - format: {:?}, pool: {:?}",
+        query.format,
+        query.pool
+    );
+
+    let names = match query.pool {
+        Some(pool) => vec![pool],
+        None => state.pools.names().await,
+    };
+    let mut mappings = Vec::new();
+    for name in names {
+        let pool = state.pools.get(&name).await?;
+        for allocation in pool.list_allocations().await {
+            if let (Ok(ip), Some(mapping)) = (
+                allocation.ip.parse::<Ipv4Addr>(),
+                dhcp::StaticMapping::new(&name, &allocation),
+            ) {
+                mappings.push((ip, mapping));
+            }
+        }
+    }
+    mappings.sort_by_key(|(ip, _)| *ip);
+    let mappings: Vec<dhcp::StaticMapping> =
+        mappings.into_iter().map(|(_, mapping)| mapping).collect();
+
+    tracing::debug!("Exporting {} static mappings", mappings.len());
+    Ok(match query.format {
+        MappingFormat::Xml => (
+            [(header::CONTENT_TYPE, "application/xml")],
+            dhcp::staticmap_xml(&mappings),
+        )
+            .into_response(),
+        MappingFormat::Json => Json(mappings).into_response(),
+    })
+}
+
+// Export WireGuard [Peer] blocks handler
+pub async fn export_wireguard_config(State(wg): State<WireGuardPool>) -> String {
+    tracing::debug!("WireGuard config export request received");
+    wg.export_config().await
 }
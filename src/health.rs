@@ -0,0 +1,132 @@
+use crate::events::{EventBus, unix_now};
+use crate::pools::PoolRegistry;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Healthy,
+    Degraded,  // working, but something needs attention
+    Unhealthy, // state may be lost or requests may fail
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ComponentHealth {
+    pub status: Status,
+    pub last_success: Option<u64>, // unix seconds
+    pub error: Option<String>,
+}
+
+impl ComponentHealth {
+    fn healthy(last_success: Option<u64>) -> Self {
+        ComponentHealth {
+            status: Status::Healthy,
+            last_success,
+            error: None,
+        }
+    }
+}
+
+// Health of the background components (storage, scheduled tasks), each
+// reporting its own successes and failures
+#[derive(Debug, Clone, Default)]
+pub struct HealthRegistry {
+    components: Arc<Mutex<BTreeMap<String, ComponentHealth>>>,
+}
+
+impl HealthRegistry {
+    // Start tracking `name`, healthy until it reports otherwise
+    pub fn register(&self, name: &str) {
+        let mut components = self.components.lock().unwrap();
+        components
+            .entry(name.to_string())
+            .or_insert_with(|| ComponentHealth::healthy(None));
+    }
+
+    pub fn success(&self, name: &str) {
+        let mut components = self.components.lock().unwrap();
+        components.insert(name.to_string(), ComponentHealth::healthy(Some(unix_now())));
+    }
+
+    pub fn failure(&self, name: &str, status: Status, error: impl std::fmt::Display) {
+        let mut components = self.components.lock().unwrap();
+        let component = components
+            .entry(name.to_string())
+            .or_insert_with(|| ComponentHealth::healthy(None));
+        component.status = status;
+        component.error = Some(error.to_string());
+    }
+
+    pub fn components(&self) -> BTreeMap<String, ComponentHealth> {
+        self.components.lock().unwrap().clone()
+    }
+}
+
+// Pools answer reads; frozen or exhausted pools are flagged as degraded
+pub async fn check_pools(pools: &PoolRegistry) -> ComponentHealth {
+    let mut problems = Vec::new();
+    for name in pools.names().await {
+        let Ok(pool) = pools.get(&name).await else {
+            continue;
+        };
+        let stats = pool.get_stats().await;
+        if stats["frozen"] == true {
+            problems.push(format!("pool {} is frozen", name));
+        } else if stats["available"] == 0 {
+            problems.push(format!("pool {} is exhausted", name));
+        }
+    }
+
+    let mut health = ComponentHealth::healthy(Some(unix_now()));
+    if !problems.is_empty() {
+        health.status = Status::Degraded;
+        health.error = Some(problems.join(", "));
+    }
+    health
+}
+
+// The bus itself cannot fail; last success is the most recent event
+pub async fn check_events(events: &EventBus) -> ComponentHealth {
+    let last_event = events.recent(1).await.first().map(|event| event.timestamp);
+    ComponentHealth::healthy(last_event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ippool::IpPool;
+
+    #[test]
+    fn test_failure_keeps_last_success() {
+        let health = HealthRegistry::default();
+        health.register("storage");
+        assert_eq!(health.components()["storage"].last_success, None);
+
+        health.success("storage");
+        health.failure("storage", Status::Unhealthy, "disk full");
+        let storage = &health.components()["storage"];
+        assert_eq!(storage.status, Status::Unhealthy);
+        assert!(storage.last_success.is_some());
+        assert_eq!(storage.error.as_deref(), Some("disk full"));
+
+        // Recovering clears the error
+        health.success("storage");
+        let storage = &health.components()["storage"];
+        assert_eq!(storage.status, Status::Healthy);
+        assert_eq!(storage.error, None);
+    }
+
+    #[tokio::test]
+    async fn test_frozen_pool_is_degraded() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let pools = PoolRegistry::new(pool.clone());
+        assert_eq!(check_pools(&pools).await.status, Status::Healthy);
+
+        pool.set_frozen(true).await;
+        let health = check_pools(&pools).await;
+        assert_eq!(health.status, Status::Degraded);
+        assert_eq!(health.error.as_deref(), Some("pool default is frozen"));
+    }
+}
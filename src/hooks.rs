@@ -0,0 +1,41 @@
+use crate::ippool::Slot;
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+
+pub type HookFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// Custom allocation policy (naming rules, CMDB checks, ...) registered on
+// an IpPool. `pre_*` hooks veto the operation by returning the reason;
+// they run before any state changes. Every method defaults to a no-op.
+pub trait AllocationHook: Send + Sync + Debug {
+    // Before a VM gets a new address; re-requesting an address the VM
+    // already holds does not run hooks
+    fn pre_allocate<'a>(
+        &'a self,
+        _vm_id: &'a str,
+        _slot: &'a Slot,
+    ) -> HookFuture<'a, Result<(), String>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    // After the address was handed out
+    fn post_allocate<'a>(
+        &'a self,
+        _vm_id: &'a str,
+        _slot: &'a Slot,
+        _ip: &'a str,
+    ) -> HookFuture<'a, ()> {
+        Box::pin(async {})
+    }
+
+    // Before an address is released on request; lease expiry and pool
+    // migrations do not run hooks
+    fn pre_release<'a>(
+        &'a self,
+        _vm_id: &'a str,
+        _ip: &'a str,
+    ) -> HookFuture<'a, Result<(), String>> {
+        Box::pin(async { Ok(()) })
+    }
+}
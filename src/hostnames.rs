@@ -0,0 +1,124 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+
+use crate::ippool::IpPoolError;
+
+// Placeholders a hostname template may use
+const PLACEHOLDERS: [&str; 3] = ["last_octet", "ip", "vm_id"];
+
+// Hostname given to allocations that do not bring their own, e.g.
+// "vm-{last_octet}.lab.local". `{last_octet}` is the last octet of the
+// address, `{ip}` the address with dashes for dots and `{vm_id}` the VM ID,
+// lowercased with anything but letters, digits and dashes replaced by dashes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostnameTemplate(String);
+
+impl std::str::FromStr for HostnameTemplate {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut rest = value;
+        while let Some(open) = rest.find('{') {
+            let Some(close) = rest[open..].find('}') else {
+                return Err(format!("unclosed placeholder in {}", value));
+            };
+            let name = &rest[open + 1..open + close];
+            if !PLACEHOLDERS.contains(&name) {
+                return Err(format!(
+                    "unknown placeholder {{{}}} in {}, expected one of {{{}}}",
+                    name,
+                    value,
+                    PLACEHOLDERS.join("}, {")
+                ));
+            }
+            rest = &rest[open + close + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("unopened placeholder in {}", value));
+        }
+
+        // Placeholders render to letters, digits and dashes, so checking
+        // the literal parts checks every hostname it can produce
+        let sample = HostnameTemplate(value.to_string()).render(Ipv4Addr::UNSPECIFIED, "vm");
+        if !is_hostname(&sample) {
+            return Err(format!("{} does not give valid hostnames", value));
+        }
+        Ok(HostnameTemplate(value.to_string()))
+    }
+}
+
+impl fmt::Display for HostnameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl HostnameTemplate {
+    pub fn render(&self, ip: Ipv4Addr, vm_id: &str) -> String {
+        self.0
+            .replace("{last_octet}", &ip.octets()[3].to_string())
+            .replace("{ip}", &ip.to_string().replace('.', "-"))
+            .replace("{vm_id}", &host_label(vm_id))
+    }
+}
+
+// A VM ID as a host name label: lowercased, with anything but letters,
+// digits and dashes replaced by dashes
+pub fn host_label(vm_id: &str) -> String {
+    let label: String = vm_id
+        .chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            c @ ('a'..='z' | '0'..='9') => c,
+            _ => '-',
+        })
+        .collect();
+    label.trim_matches('-').to_string()
+}
+
+// RFC 1123 host name: dot-separated labels of letters, digits and inner
+// dashes, at most 63 characters each and 253 in all
+pub fn is_hostname(value: &str) -> bool {
+    value.len() <= 253
+        && value.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+// Check a hostname given with an allocation request
+pub fn check_hostname(hostname: Option<&str>) -> Result<(), IpPoolError> {
+    match hostname {
+        Some(hostname) if !is_hostname(hostname) => Err(IpPoolError::InvalidHostname),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_templates() {
+        let template: HostnameTemplate = "vm-{last_octet}.lab.local".parse().unwrap();
+        let ip = Ipv4Addr::new(172, 16, 0, 12);
+        assert_eq!(template.render(ip, "web-1"), "vm-12.lab.local");
+
+        let template: HostnameTemplate = "{vm_id}-{ip}".parse().unwrap();
+        assert_eq!(
+            template.render(ip, "CI_Runner.7"),
+            "ci-runner-7-172-16-0-12"
+        );
+
+        assert!("vm-{octet}".parse::<HostnameTemplate>().is_err());
+        assert!("vm-{last_octet".parse::<HostnameTemplate>().is_err());
+        assert!("vm_{last_octet}".parse::<HostnameTemplate>().is_err());
+        assert!("vm-{last_octet}..lab".parse::<HostnameTemplate>().is_err());
+
+        assert!(check_hostname(Some("web-1.lab.local")).is_ok());
+        assert!(check_hostname(Some("web_1")).is_err());
+        assert!(check_hostname(Some("-web")).is_err());
+        assert!(check_hostname(None).is_ok());
+    }
+}
@@ -0,0 +1,474 @@
+use crate::events::{Event, EventBus, EventKind};
+use axum::http::HeaderMap;
+use serde::Serialize;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+
+// Header naming the automation behind a request, e.g. "autoscaler-eu"
+pub const CALLER_HEADER: &str = "x-caller";
+
+// Caller of requests without an API key or X-Caller header
+pub const ANONYMOUS: &str = "anonymous";
+
+// Who is allocating: the team of the API key, else the X-Caller header
+pub fn caller(headers: &HeaderMap, team: Option<&str>) -> String {
+    team.or_else(|| {
+        headers
+            .get(CALLER_HEADER)
+            .and_then(|caller| caller.to_str().ok())
+            .map(str::trim)
+            .filter(|caller| !caller.is_empty())
+    })
+    .unwrap_or(ANONYMOUS)
+    .to_string()
+}
+
+// Allocation event details naming the caller
+pub fn details(caller: &str) -> Option<serde_json::Value> {
+    Some(serde_json::json!({ "caller": caller }))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyKind {
+    Leak,      // allocates much more than it releases
+    Thrashing, // allocates and releases the same VM over and over
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Anomaly {
+    pub kind: AnomalyKind,
+    pub caller: String,
+    pub pool: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vm_id: Option<String>, // the thrashing VM
+    pub detected_at: u64,
+    pub detail: String,
+}
+
+// What one caller did within the window
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CallerInsight {
+    pub caller: String,
+    pub allocations: usize,
+    pub releases: usize,
+    pub expirations: usize, // leases of the caller that ran out
+    pub held: usize,        // addresses the caller holds now
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InsightsReport {
+    pub window_secs: u64,
+    pub callers: Vec<CallerInsight>,
+    pub anomalies: Vec<Anomaly>, // still ongoing
+}
+
+// When a caller's pattern counts as an anomaly
+#[derive(Debug, Clone, Copy)]
+pub struct AnomalyThresholds {
+    pub window_secs: u64,
+    pub leak_allocations: usize, // allocations in the window before a leak is considered
+    pub leak_release_ratio: f64, // releases per allocation below which it is a leak
+    pub thrash_cycles: usize,    // releases of one VM in the window
+}
+
+impl Default for AnomalyThresholds {
+    fn default() -> Self {
+        AnomalyThresholds {
+            window_secs: 3600,
+            leak_allocations: 20,
+            leak_release_ratio: 0.1,
+            thrash_cycles: 5,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CallerState {
+    allocations: VecDeque<(u64, String)>, // timestamp, pool
+    releases: VecDeque<u64>,
+    expirations: VecDeque<u64>,
+    cycles: HashMap<(String, String), VecDeque<u64>>, // (pool, VM) -> release times
+    held: usize,
+}
+
+impl CallerState {
+    fn prune(&mut self, since: u64) {
+        while self.allocations.front().is_some_and(|(at, _)| *at < since) {
+            self.allocations.pop_front();
+        }
+        for times in [&mut self.releases, &mut self.expirations] {
+            while times.front().is_some_and(|at| *at < since) {
+                times.pop_front();
+            }
+        }
+        self.cycles.retain(|_, times| {
+            while times.front().is_some_and(|at| *at < since) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+    }
+
+    fn idle(&self) -> bool {
+        self.held == 0
+            && self.allocations.is_empty()
+            && self.releases.is_empty()
+            && self.expirations.is_empty()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Tracker {
+    callers: HashMap<String, CallerState>,
+    owners: HashMap<(String, String), String>, // (pool, IP) -> caller
+    // Raised anomalies by caller, kind and VM, reported once until they clear
+    raised: BTreeMap<(String, AnomalyKind, Option<String>), Anomaly>,
+}
+
+// Per-caller allocation patterns from the event stream, flagging callers
+// that leak addresses or thrash. Windows follow the event timestamps.
+#[derive(Debug, Clone)]
+pub struct Insights {
+    thresholds: AnomalyThresholds,
+    tracker: Arc<Mutex<Tracker>>,
+}
+
+impl Default for Insights {
+    fn default() -> Self {
+        Insights::new(AnomalyThresholds::default())
+    }
+}
+
+impl Insights {
+    pub fn new(thresholds: AnomalyThresholds) -> Self {
+        Insights {
+            thresholds,
+            tracker: Arc::default(),
+        }
+    }
+
+    // Account for an event, returning the anomalies it newly raises
+    fn observe(&self, event: &Event) -> Vec<Anomaly> {
+        let mut tracker = self.tracker.lock().unwrap();
+        let key = (event.pool.clone(), event.ip.clone());
+        let caller = match event.kind {
+            EventKind::Allocated => {
+                let caller = event
+                    .details
+                    .as_ref()
+                    .and_then(|details| details["caller"].as_str())
+                    .unwrap_or(ANONYMOUS)
+                    .to_string();
+                // Renewals of an address the caller holds are not new
+                if tracker.owners.get(&key) == Some(&caller) {
+                    return Vec::new();
+                }
+                if let Some(previous) = tracker.owners.insert(key, caller.clone()) {
+                    release_hold(&mut tracker, &previous);
+                }
+                let state = tracker.callers.entry(caller.clone()).or_default();
+                state
+                    .allocations
+                    .push_back((event.timestamp, event.pool.clone()));
+                state.held += 1;
+                caller
+            }
+            EventKind::Released | EventKind::Expired => {
+                let Some(caller) = tracker.owners.remove(&key) else {
+                    return Vec::new();
+                };
+                release_hold(&mut tracker, &caller);
+                let state = tracker.callers.entry(caller.clone()).or_default();
+                if event.kind == EventKind::Expired {
+                    state.expirations.push_back(event.timestamp);
+                } else {
+                    state.releases.push_back(event.timestamp);
+                    state
+                        .cycles
+                        .entry((event.pool.clone(), event.vm_id.clone()))
+                        .or_default()
+                        .push_back(event.timestamp);
+                }
+                caller
+            }
+            EventKind::Migrated | EventKind::Expiring | EventKind::Anomaly => return Vec::new(),
+        };
+        self.detect(&mut tracker, &caller, event.timestamp)
+    }
+
+    fn detect(&self, tracker: &mut Tracker, caller: &str, now: u64) -> Vec<Anomaly> {
+        let since = now.saturating_sub(self.thresholds.window_secs);
+        let Some(state) = tracker.callers.get_mut(caller) else {
+            return Vec::new();
+        };
+        state.prune(since);
+        let current = self.find(caller, state, now);
+
+        // Anomalies that stopped are cleared, new ones are raised once
+        let ongoing: HashSet<_> = current
+            .iter()
+            .map(|anomaly| (anomaly.kind, anomaly.vm_id.clone()))
+            .collect();
+        tracker.raised.retain(|(owner, kind, vm_id), _| {
+            owner != caller || ongoing.contains(&(*kind, vm_id.clone()))
+        });
+        let mut raised = Vec::new();
+        for anomaly in current {
+            let key = (caller.to_string(), anomaly.kind, anomaly.vm_id.clone());
+            if let Entry::Vacant(entry) = tracker.raised.entry(key) {
+                entry.insert(anomaly.clone());
+                raised.push(anomaly);
+            }
+        }
+        raised
+    }
+
+    // The anomalies of a pruned caller state
+    fn find(&self, caller: &str, state: &CallerState, now: u64) -> Vec<Anomaly> {
+        let mut current = Vec::new();
+        let allocations = state.allocations.len();
+        let releases = state.releases.len();
+        if allocations >= self.thresholds.leak_allocations
+            && (releases as f64) < allocations as f64 * self.thresholds.leak_release_ratio
+        {
+            let pool = state
+                .allocations
+                .back()
+                .map(|(_, pool)| pool.clone())
+                .unwrap_or_default();
+            current.push(Anomaly {
+                kind: AnomalyKind::Leak,
+                caller: caller.to_string(),
+                pool,
+                vm_id: None,
+                detected_at: now,
+                detail: format!(
+                    "{} allocations and {} releases in the last {}s, {} addresses held",
+                    allocations, releases, self.thresholds.window_secs, state.held
+                ),
+            });
+        }
+        for ((pool, vm_id), times) in &state.cycles {
+            if times.len() >= self.thresholds.thrash_cycles {
+                current.push(Anomaly {
+                    kind: AnomalyKind::Thrashing,
+                    caller: caller.to_string(),
+                    pool: pool.clone(),
+                    vm_id: Some(vm_id.clone()),
+                    detected_at: now,
+                    detail: format!(
+                        "{} released {} times in the last {}s",
+                        vm_id,
+                        times.len(),
+                        self.thresholds.window_secs
+                    ),
+                });
+            }
+        }
+        current
+    }
+
+    // Every caller's activity within the window ending `now`, busiest
+    // first, with the ongoing anomalies
+    pub fn report(&self, now: u64) -> InsightsReport {
+        let mut tracker = self.tracker.lock().unwrap();
+        let since = now.saturating_sub(self.thresholds.window_secs);
+        for state in tracker.callers.values_mut() {
+            state.prune(since);
+        }
+        tracker.callers.retain(|_, state| !state.idle());
+        // Anomalies whose callers went quiet age out with the window
+        let ongoing: HashSet<_> = tracker
+            .callers
+            .iter()
+            .flat_map(|(caller, state)| self.find(caller, state, now))
+            .map(|anomaly| (anomaly.caller, anomaly.kind, anomaly.vm_id))
+            .collect();
+        tracker.raised.retain(|key, _| ongoing.contains(key));
+
+        let mut callers: Vec<CallerInsight> = tracker
+            .callers
+            .iter()
+            .map(|(caller, state)| CallerInsight {
+                caller: caller.clone(),
+                allocations: state.allocations.len(),
+                releases: state.releases.len(),
+                expirations: state.expirations.len(),
+                held: state.held,
+            })
+            .collect();
+        callers.sort_by(|a, b| {
+            (b.allocations + b.releases)
+                .cmp(&(a.allocations + a.releases))
+                .then_with(|| a.caller.cmp(&b.caller))
+        });
+        InsightsReport {
+            window_secs: self.thresholds.window_secs,
+            callers,
+            anomalies: tracker.raised.values().cloned().collect(),
+        }
+    }
+
+    // Follow the event stream, emitting an anomaly event for every anomaly
+    // raised, until the bus goes away
+    pub async fn run(self, events: EventBus) {
+        let mut receiver = events.subscribe();
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("Insights fell behind, {} events skipped", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            for anomaly in self.observe(&event) {
+                tracing::warn!(
+                    "🔍 Allocation anomaly ({:?}) from {} in pool {}: {}",
+                    anomaly.kind,
+                    anomaly.caller,
+                    anomaly.pool,
+                    anomaly.detail
+                );
+                let details = serde_json::to_value(&anomaly).ok();
+                events
+                    .emit(
+                        EventKind::Anomaly,
+                        &anomaly.pool,
+                        anomaly.vm_id.as_deref().unwrap_or(""),
+                        "",
+                        details,
+                    )
+                    .await;
+            }
+        }
+    }
+}
+
+fn release_hold(tracker: &mut Tracker, caller: &str) {
+    if let Some(state) = tracker.callers.get_mut(caller) {
+        state.held = state.held.saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: u64, kind: EventKind, vm_id: &str, ip: &str, caller: &str) -> Event {
+        Event {
+            id,
+            timestamp: 1_000 + id,
+            kind,
+            pool: "default".to_string(),
+            vm_id: vm_id.to_string(),
+            ip: ip.to_string(),
+            details: (kind == EventKind::Allocated)
+                .then(|| details(caller))
+                .flatten(),
+        }
+    }
+
+    fn sensitive() -> Insights {
+        Insights::new(AnomalyThresholds {
+            window_secs: 3600,
+            leak_allocations: 5,
+            leak_release_ratio: 0.2,
+            thrash_cycles: 3,
+        })
+    }
+
+    #[test]
+    fn test_leaking_caller_flagged_once() {
+        let insights = sensitive();
+        let mut raised = Vec::new();
+        for i in 0..6 {
+            let ip = format!("172.16.0.{}", i + 2);
+            raised.extend(insights.observe(&event(
+                i,
+                EventKind::Allocated,
+                &format!("vm-{}", i),
+                &ip,
+                "autoscaler",
+            )));
+        }
+        // A well-behaved caller next to it
+        insights.observe(&event(10, EventKind::Allocated, "ci", "172.16.0.50", "ci"));
+        insights.observe(&event(11, EventKind::Released, "ci", "172.16.0.50", ""));
+
+        assert_eq!(raised.len(), 1);
+        assert_eq!(raised[0].kind, AnomalyKind::Leak);
+        assert_eq!(raised[0].caller, "autoscaler");
+
+        let report = insights.report(1_020);
+        assert_eq!(report.callers[0].caller, "autoscaler");
+        assert_eq!(report.callers[0].held, 6);
+        assert_eq!(report.callers[1].releases, 1);
+        assert_eq!(report.anomalies.len(), 1);
+
+        // Releasing clears it
+        for i in 0..2 {
+            insights.observe(&event(
+                20 + i,
+                EventKind::Released,
+                &format!("vm-{}", i),
+                &format!("172.16.0.{}", i + 2),
+                "",
+            ));
+        }
+        assert!(insights.report(1_030).anomalies.is_empty());
+
+        // And the window forgets old allocations
+        let report = insights.report(10_000);
+        assert_eq!(report.callers[0].allocations, 0);
+        assert_eq!(report.callers[0].held, 4);
+
+        // Anomalies of callers that went quiet age out with the window
+        let quiet = sensitive();
+        for i in 0..5 {
+            let ip = format!("172.16.0.{}", i + 2);
+            quiet.observe(&event(i, EventKind::Allocated, "vm", &ip, "autoscaler"));
+        }
+        assert_eq!(quiet.report(1_010).anomalies.len(), 1);
+        assert!(quiet.report(10_000).anomalies.is_empty());
+    }
+
+    #[test]
+    fn test_thrashing_vm_flagged() {
+        let insights = sensitive();
+        let mut raised = Vec::new();
+        for cycle in 0..3 {
+            let id = cycle * 2;
+            raised.extend(insights.observe(&event(
+                id,
+                EventKind::Allocated,
+                "vm-1",
+                "172.16.0.2",
+                "flaky-ci",
+            )));
+            raised.extend(insights.observe(&event(
+                id + 1,
+                EventKind::Released,
+                "vm-1",
+                "172.16.0.2",
+                "",
+            )));
+        }
+        assert_eq!(raised.len(), 1);
+        assert_eq!(raised[0].kind, AnomalyKind::Thrashing);
+        assert_eq!(raised[0].vm_id.as_deref(), Some("vm-1"));
+        assert_eq!(insights.report(1_010).callers[0].held, 0);
+    }
+
+    #[test]
+    fn test_caller_identity() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(caller(&headers, None), ANONYMOUS);
+        headers.insert(CALLER_HEADER, "terraform".parse().unwrap());
+        assert_eq!(caller(&headers, None), "terraform");
+        assert_eq!(caller(&headers, Some("squad-a")), "squad-a");
+    }
+}
@@ -1,13 +1,80 @@
-use std::collections::HashMap;
+use crate::breaker::CircuitBreaker;
+use crate::check::Thresholds;
+use crate::clock::{Clock, SystemClock};
+use crate::conflicts::Conflict;
+use crate::consistency::{Inconsistency, InconsistencyKind};
+use crate::delegations::{Delegation, DelegationUsage};
+use crate::dhcp::DhcpOptions;
+use crate::freelist::FreeList;
+use crate::hooks::AllocationHook;
+use crate::hostnames::HostnameTemplate;
+use crate::journal::{Journal, JournalEntry};
+use crate::perf::{Histogram, HistogramSummary};
+use crate::policy::{Placement, ScriptPolicy};
+use crate::routes::StaticRoute;
+use crate::slaac::Slaac;
+use crate::storage::StorageError;
+use crate::wireguard::Tunnel;
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::net::Ipv4Addr;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum IpPoolError {
     NoAvailableIps,
     IpNotFound,
     InvalidIp,
+    InvalidPublicKey,
+    PoolFrozen,
+    PoolNotFound,
+    InvalidMigration,
+    InvalidMerge,
+    InvalidSplit,
+    PoolAlreadyExists,
+    IpInUse,
+    InvalidLease,
+    InvalidExpiry, // hard expiry not in the future
+    AdminOnly,
+    InvalidRange,
+    InvertedRange, // range start above its end
+    StorageUnavailable(String),
+    ReadOnly(String),              // maintenance mode, with its reason
+    ReadReplica(String),           // read replica, with the primary's URL
+    NotLeader(Option<String>),     // cluster follower, with the leader's URL once one is elected
+    PolicyViolation(String),       // vetoed by an allocation hook or policy script, with its reason
+    InvalidPolicy(String),         // policy script failed to load
+    InvalidLeaseFile(String),      // DHCP lease file could not be parsed
+    InvalidTerraformState(String), // Terraform state could not be parsed
+    InvalidFilter(String),         // allocation filter expression could not be parsed
+    ConflictNotFound,
+    HeldByOtherVm,      // release guarded by a VM that no longer holds the address
+    StaleFenceToken,    // fencing token of an allocation that no longer exists
+    FenceTokenRequired, // release or renewal without a fencing token while they are required
+    ReleaseNotPending,
+    CursorExpired, // events after a replay cursor are no longer retained
+    SnapshotNotFound,
+    SnapshotAlreadyExists,
+    InvalidSnapshotName,
+    InvalidDelegation, // range outside the pool or overlapping another delegation
+    DelegationNotFound,
+    UnknownApiKey,
+    PoolNotDelegated, // API key used for a pool its team has no delegation in
+    TemplateNotFound,
+    InvalidPoolConfig(String), // pool definition failed validation, with the findings
+    InvalidMac,
+    InvalidLabelSelector, // not key=value
+    InvalidHostname,
+    WebhookNotFound,
+    ApprovalNotFound,
+    ApprovalRequired,    // new allocations in the pool go through the approval queue
+    AlreadyAllocated,    // clone target already holds an address for the slot
+    AllocationDiffers,   // PUT for a VM holding an address in the pool for another slot
+    BudgetExceeded(u64), // seconds until the API key may allocate again
 }
 
 impl std::fmt::Display for IpPoolError {
@@ -16,313 +83,4767 @@ impl std::fmt::Display for IpPoolError {
             IpPoolError::NoAvailableIps => write!(f, "no available IPs in pool"),
             IpPoolError::IpNotFound => write!(f, "IP not found in allocations"),
             IpPoolError::InvalidIp => write!(f, "invalid IP address"),
+            IpPoolError::InvalidPublicKey => write!(f, "invalid WireGuard public key"),
+            IpPoolError::PoolFrozen => write!(f, "pool is frozen, new allocations are rejected"),
+            IpPoolError::PoolNotFound => write!(f, "pool not found"),
+            IpPoolError::InvalidMigration => write!(f, "source and target pool must differ"),
+            IpPoolError::InvalidMerge => write!(f, "pools cannot be merged"),
+            IpPoolError::InvalidSplit => write!(f, "split boundary is outside the pool range"),
+            IpPoolError::PoolAlreadyExists => write!(f, "pool already exists"),
+            IpPoolError::IpInUse => write!(f, "IP address is already in use"),
+            IpPoolError::InvalidLease => write!(f, "invalid lease TTL"),
+            IpPoolError::InvalidExpiry => write!(f, "expiry is not in the future"),
+            IpPoolError::AdminOnly => write!(f, "operation requires the admin API"),
+            IpPoolError::InvalidRange => write!(f, "invalid sub-range prefix length"),
+            IpPoolError::InvertedRange => write!(f, "range start is above its end"),
+            IpPoolError::StorageUnavailable(reason) => write!(f, "storage unavailable: {}", reason),
+            IpPoolError::ReadOnly(reason) => write!(f, "read-only for maintenance: {}", reason),
+            IpPoolError::ReadReplica(primary) => write!(f, "read replica of {}", primary),
+            IpPoolError::NotLeader(Some(leader)) => write!(f, "not the leader, {} is", leader),
+            IpPoolError::NotLeader(None) => write!(f, "no cluster leader elected"),
+            IpPoolError::PolicyViolation(reason) => {
+                write!(f, "rejected by allocation policy: {}", reason)
+            }
+            IpPoolError::InvalidPolicy(reason) => write!(f, "invalid policy script: {}", reason),
+            IpPoolError::InvalidLeaseFile(reason) => write!(f, "invalid lease file: {}", reason),
+            IpPoolError::InvalidTerraformState(reason) => {
+                write!(f, "invalid Terraform state: {}", reason)
+            }
+            IpPoolError::InvalidFilter(reason) => write!(f, "invalid filter: {}", reason),
+            IpPoolError::ConflictNotFound => write!(f, "no conflict recorded for IP"),
+            IpPoolError::HeldByOtherVm => write!(f, "IP is held by another VM"),
+            IpPoolError::StaleFenceToken => write!(f, "fence token is stale"),
+            IpPoolError::FenceTokenRequired => write!(f, "fence token required"),
+            IpPoolError::ReleaseNotPending => write!(f, "no release pending for VM"),
+            IpPoolError::CursorExpired => write!(f, "event cursor expired"),
+            IpPoolError::SnapshotNotFound => write!(f, "snapshot not found"),
+            IpPoolError::SnapshotAlreadyExists => write!(f, "snapshot already exists"),
+            IpPoolError::InvalidSnapshotName => write!(f, "invalid snapshot name"),
+            IpPoolError::InvalidDelegation => write!(f, "invalid delegation"),
+            IpPoolError::DelegationNotFound => write!(f, "delegation not found"),
+            IpPoolError::UnknownApiKey => write!(f, "unknown API key"),
+            IpPoolError::PoolNotDelegated => write!(f, "API key has no delegation in pool"),
+            IpPoolError::TemplateNotFound => write!(f, "pool template not found"),
+            IpPoolError::InvalidMac => write!(f, "invalid MAC address"),
+            IpPoolError::InvalidLabelSelector => write!(f, "label selector is not key=value"),
+            IpPoolError::InvalidHostname => write!(f, "invalid hostname"),
+            IpPoolError::WebhookNotFound => write!(f, "webhook not found"),
+            IpPoolError::ApprovalNotFound => write!(f, "pending allocation not found"),
+            IpPoolError::AlreadyAllocated => write!(f, "VM already holds an address for the slot"),
+            IpPoolError::AllocationDiffers => {
+                write!(f, "VM already holds an address with other parameters")
+            }
+            IpPoolError::ApprovalRequired => write!(f, "new allocations in pool need approval"),
+            IpPoolError::BudgetExceeded(retry_after) => {
+                write!(f, "allocation budget exceeded, retry in {}s", retry_after)
+            }
+            IpPoolError::InvalidPoolConfig(reason) => {
+                write!(f, "invalid pool configuration: {}", reason)
+            }
         }
     }
 }
 
 impl std::error::Error for IpPoolError {}
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+impl IpPoolError {
+    // Stable identifier used for metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            IpPoolError::NoAvailableIps => "no_available_ips",
+            IpPoolError::IpNotFound => "ip_not_found",
+            IpPoolError::InvalidIp => "invalid_ip",
+            IpPoolError::InvalidPublicKey => "invalid_public_key",
+            IpPoolError::PoolFrozen => "pool_frozen",
+            IpPoolError::PoolNotFound => "pool_not_found",
+            IpPoolError::InvalidMigration => "invalid_migration",
+            IpPoolError::InvalidMerge => "invalid_merge",
+            IpPoolError::InvalidSplit => "invalid_split",
+            IpPoolError::PoolAlreadyExists => "pool_already_exists",
+            IpPoolError::IpInUse => "ip_in_use",
+            IpPoolError::InvalidLease => "invalid_lease",
+            IpPoolError::InvalidExpiry => "invalid_expiry",
+            IpPoolError::AdminOnly => "admin_only",
+            IpPoolError::InvalidRange => "invalid_range",
+            IpPoolError::InvertedRange => "inverted_range",
+            IpPoolError::StorageUnavailable(_) => "storage_unavailable",
+            IpPoolError::ReadOnly(_) => "read_only",
+            IpPoolError::ReadReplica(_) => "read_replica",
+            IpPoolError::NotLeader(_) => "not_leader",
+            IpPoolError::PolicyViolation(_) => "policy_violation",
+            IpPoolError::InvalidPolicy(_) => "invalid_policy",
+            IpPoolError::InvalidLeaseFile(_) => "invalid_lease_file",
+            IpPoolError::InvalidTerraformState(_) => "invalid_terraform_state",
+            IpPoolError::InvalidFilter(_) => "invalid_filter",
+            IpPoolError::ConflictNotFound => "conflict_not_found",
+            IpPoolError::HeldByOtherVm => "held_by_other_vm",
+            IpPoolError::StaleFenceToken => "stale_fence_token",
+            IpPoolError::FenceTokenRequired => "fence_token_required",
+            IpPoolError::ReleaseNotPending => "release_not_pending",
+            IpPoolError::CursorExpired => "cursor_expired",
+            IpPoolError::SnapshotNotFound => "snapshot_not_found",
+            IpPoolError::SnapshotAlreadyExists => "snapshot_already_exists",
+            IpPoolError::InvalidSnapshotName => "invalid_snapshot_name",
+            IpPoolError::InvalidDelegation => "invalid_delegation",
+            IpPoolError::DelegationNotFound => "delegation_not_found",
+            IpPoolError::UnknownApiKey => "unknown_api_key",
+            IpPoolError::PoolNotDelegated => "pool_not_delegated",
+            IpPoolError::TemplateNotFound => "template_not_found",
+            IpPoolError::InvalidMac => "invalid_mac",
+            IpPoolError::InvalidLabelSelector => "invalid_label_selector",
+            IpPoolError::InvalidHostname => "invalid_hostname",
+            IpPoolError::WebhookNotFound => "webhook_not_found",
+            IpPoolError::ApprovalNotFound => "approval_not_found",
+            IpPoolError::AlreadyAllocated => "already_allocated",
+            IpPoolError::AllocationDiffers => "allocation_differs",
+            IpPoolError::ApprovalRequired => "approval_required",
+            IpPoolError::BudgetExceeded(_) => "budget_exceeded",
+            IpPoolError::InvalidPoolConfig(_) => "invalid_pool_config",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct IpAllocation {
     pub ip: String,
     pub vm_id: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub hostname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>, // "52:54:00:12:34:56"
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reserved: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub external: bool, // reserved for a device outside the pool, e.g. a printer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>, // unix seconds, absent for leases that never expire
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub renew_before: Option<u64>, // unix seconds, set with LEASE_EXPIRY_WARNING
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hard_expires_at: Option<u64>, // unix seconds, reclaimed then whatever the lease
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purpose: Option<String>, // "primary", "floating", ...
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>, // "eth0", "eth1", ...
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>, // e.g. "project" -> "payments"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>, // e.g. "alice@example.com" or a team
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ticket: Option<String>, // e.g. "OPS-1234"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_release_at: Option<u64>, // unix seconds, set while a deferred release is scheduled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fence_token: Option<u64>,
+}
+
+// Who an allocation is for and why: the owner to contact about it,
+// free-form notes and the ticket it was made for
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Annotation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ticket: Option<String>,
+}
+
+impl Annotation {
+    pub fn is_empty(&self) -> bool {
+        self.owner.is_none() && self.notes.is_none() && self.ticket.is_none()
+    }
+}
+
+// What an allocation request sets on the address along with it. A new
+// address gets it in the same journal entry as the allocation, so it never
+// exists without; a renewal updates the address it holds.
+#[derive(Debug, Clone, Default)]
+pub struct Requested {
+    pub labels: Option<BTreeMap<String, String>>, // replaces the labels, None: unchanged
+    pub hostname: Option<String>,
+    pub mac: Option<String>, // "52:54:00:12:34:56"
+    pub owner: Option<String>,
+    pub notes: Option<String>,
+    pub ticket: Option<String>,
+    pub default_owner: Option<String>, // owner of an address that has none yet
+    pub deadline: Option<u64>,         // hard expiry (unix seconds), only ever brought forward
+}
+
+// Per-address details journaled with an allocation, each absent when
+// unchanged
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct AllocationDetails {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<BTreeMap<String, String>>, // replaces the labels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotation: Option<Annotation>, // replaces the owner, notes and ticket
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<u64>, // hard expiry (unix seconds)
+}
+
+// When an address was handed out and last asked for again by its holder
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Activity {
+    pub allocated_at: u64, // unix seconds
+    pub renewed_at: u64,   // unix seconds, allocated_at until the first renewal
+    #[serde(default)]
+    pub renewals: u32,
+    // Fencing token, unique to this allocation within the pool and larger
+    // than any handed out before it
+    #[serde(default)]
+    pub fence: u64,
+}
+
+impl Activity {
+    fn new(now: u64, fence: u64) -> Self {
+        Activity {
+            allocated_at: now,
+            renewed_at: now,
+            renewals: 0,
+            fence,
+        }
+    }
+}
+
+// Purpose of the address a plain allocation hands out
+pub const PRIMARY: &str = "primary";
+
+// 802.1Q VLAN IDs a pool can carry; 0 and 4095 are reserved
+pub const VLAN_IDS: std::ops::RangeInclusive<u16> = 1..=4094;
+
+// Journal and snapshots only spell out secondary purposes
+fn journal_purpose(purpose: &str) -> Option<String> {
+    (purpose != PRIMARY).then(|| purpose.to_string())
+}
+
+// Which of a VM's addresses an allocation is: a VM holds one address per
+// interface and purpose in a pool
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Slot {
+    pub interface: Option<String>,
+    pub purpose: String,
+}
+
+impl Slot {
+    pub fn new(interface: Option<String>, purpose: Option<String>) -> Self {
+        Slot {
+            interface,
+            purpose: purpose.unwrap_or_else(|| PRIMARY.to_string()),
+        }
+    }
+
+    // What a plain allocation hands out
+    pub fn primary() -> Self {
+        Self::new(None, None)
+    }
+}
+
+// Lease requested along with an allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Lease {
+    #[default]
+    PoolDefault,
+    Ttl(Duration),
+    Infinite,
 }
 
+// Serializable point-in-time copy of a pool, used by storage backends
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PoolSnapshot {
+    pub network: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: String,
+    pub start: Ipv4Addr,
+    pub end: Ipv4Addr,
+    pub allocations: BTreeMap<String, String>, // IP -> VM_ID
+    #[serde(default)]
+    pub reserved: BTreeMap<String, String>, // IP -> label
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub external: BTreeSet<String>, // reserved IPs used by devices outside the pool
+    #[serde(default)]
+    pub frozen: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<StaticRoute>,
+    #[serde(default, skip_serializing_if = "DhcpOptions::is_empty")]
+    pub dhcp_options: DhcpOptions,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub leases: BTreeMap<String, u64>, // IP -> lease expiry (unix seconds)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub purposes: BTreeMap<String, String>, // IP -> purpose, secondary addresses only
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub interfaces: BTreeMap<String, String>, // IP -> interface
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub conflicts: BTreeMap<String, Conflict>, // IP -> suspected conflict
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, BTreeMap<String, String>>, // IP -> labels
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hostnames: BTreeMap<String, String>, // IP -> hostname
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub macs: BTreeMap<String, String>, // IP -> MAC address
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, Annotation>, // IP -> owner, notes and ticket
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub deadlines: BTreeMap<String, u64>, // IP -> hard expiry (unix seconds)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pending_releases: BTreeMap<String, u64>, // IP -> deferred release time
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub activity: BTreeMap<String, Activity>, // IP -> allocation and renewal times
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub delegations: BTreeMap<String, Delegation>, // team -> delegated sub-range
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub prefixes6: BTreeMap<String, String>, // delegated IPv6 prefix -> VM_ID
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub wireguard: BTreeMap<String, Tunnel>, // WireGuard public key -> tunnel address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thresholds: Option<Thresholds>, // utilization check, None: check defaults
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vlan_id: Option<u16>,
+    #[serde(default)]
+    pub fence: u64, // last fencing token handed out
+}
+
+// Allocation paths only take the outer lock shared, so they run in parallel
+// with each other and with readers. Per-address state lives in concurrent
+// maps and a segmented free list; the exclusive lock is reserved for
+// structural changes (reserve, freeze, split, merge, restore).
 #[derive(Debug, Clone)]
 pub struct IpPool {
     inner: Arc<RwLock<IpPoolInner>>,
+    lock_wait: Arc<Histogram>, // time allocate/release spend waiting for the lock
+}
+
+// Exclusive hold on a pool: no mutation is journaled or applied meanwhile
+pub struct PoolLock<'a> {
+    inner: RwLockWriteGuard<'a, IpPoolInner>,
+}
+
+impl PoolLock<'_> {
+    pub fn snapshot(&self) -> PoolSnapshot {
+        self.inner.to_snapshot()
+    }
+}
+
+#[derive(Debug)]
+struct IpPoolInner {
+    network: Ipv4Addr,
+    prefix_len: u8,
+    gateway: String,
+    start: u32,                                        // first allocatable address
+    end: u32,                                          // last allocatable address
+    reserved: HashMap<String, String>,                 // IP -> label, never handed out
+    external: HashSet<String>, // reserved IPs used by devices outside the pool
+    conflicts: HashMap<String, Conflict>, // IP -> flagged conflict, not handed out
+    allocated: DashMap<String, String>, // IP -> VM_ID
+    vm_to_ip: DashMap<String, BTreeMap<Slot, String>>, // VM_ID -> slot -> IP
+    expires: DashMap<String, u64>, // IP -> lease expiry, absent: never expires
+    labels: DashMap<String, BTreeMap<String, String>>, // IP -> labels, absent: none
+    hostnames: DashMap<String, String>, // IP -> hostname, absent: none
+    macs: DashMap<String, String>, // IP -> MAC address, absent: unknown
+    annotations: DashMap<String, Annotation>, // IP -> owner, notes and ticket, absent: none
+    deadlines: DashMap<String, u64>, // IP -> hard expiry, absent: only the lease applies
+    pending: DashMap<String, u64>, // IP -> deferred release time
+    activity: DashMap<String, Activity>, // IP -> allocation and renewal times
+    fence: AtomicU64,          // last fencing token handed out
+    delegations: BTreeMap<String, Delegation>, // team -> delegated sub-range
+    prefixes6: BTreeMap<String, String>, // delegated IPv6 prefix -> VM_ID
+    wireguard: BTreeMap<String, Tunnel>, // WireGuard public key -> tunnel address
+    free: FreeList,
+    frozen: bool,
+    routes: Vec<StaticRoute>,    // handed out with every allocation
+    dhcp_options: DhcpOptions,   // likewise
+    lease_ttl: Option<Duration>, // pool default, None: leases never expire
+    hostname_template: Option<HostnameTemplate>, // for allocations without a hostname
+    expiry_warning: Option<Duration>, // how long before expiry holders are warned, None: never
+    warned: DashMap<String, u64>, // IP -> lease expiry its holder was warned about
+    history: Mutex<VecDeque<(Instant, PoolChange)>>, // recent allocations/releases
+    version: AtomicU64,          // bumped on every mutation
+    journal: Option<(String, Arc<Journal>)>, // pool name, write-ahead journal
+    breaker: Option<CircuitBreaker>, // refuses writes while storage is failing
+    clock: Arc<dyn Clock>,
+    hooks: Vec<Arc<dyn AllocationHook>>, // run in registration order
+    policy: Option<(String, Arc<ScriptPolicy>)>, // pool name, allocation policy script
+    gateway_reachable: Option<bool>,     // last gateway self-check, None: not checked
+    thresholds: Option<Thresholds>,      // utilization check, None: check defaults
+    vlan_id: Option<u16>,                // 802.1Q VLAN the network lives on
+    slaac: Option<Slaac>,                // IPv6 /64 VM addresses are derived in
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PoolChange {
+    Allocated,
+    Released,
+}
+
+// Upper bound on recorded history entries used for rate forecasting
+const MAX_HISTORY: usize = 10_000;
+
+// Windows the burn rate is reported over: the short one reacts to a
+// provisioning storm, the long ones show the trend
+const BURN_RATE_WINDOWS: [(&str, Duration); 3] = [
+    ("5m", Duration::from_secs(300)),
+    ("1h", Duration::from_secs(3600)),
+    ("6h", Duration::from_secs(6 * 3600)),
+];
+
+// Sub-range size used by the per-range breakdown when none is requested,
+// and the deepest split (in prefix bits below the pool) it will report
+const DEFAULT_RANGE_PREFIX: u8 = 27;
+const MAX_RANGE_SPLIT_BITS: u8 = 12;
+
+// Retry-After bounds for exhausted pools, and the release history considered
+const RETRY_AFTER_WINDOW: Duration = Duration::from_secs(3600);
+const RETRY_AFTER_DEFAULT: Duration = Duration::from_secs(60);
+const RETRY_AFTER_MIN: Duration = Duration::from_secs(1);
+const RETRY_AFTER_MAX: Duration = Duration::from_secs(600);
+
+impl IpPoolInner {
+    fn new(
+        network: Ipv4Addr,
+        prefix_len: u8,
+        gateway: String,
+        start: u32,
+        end: u32,
+    ) -> Result<Self, IpPoolError> {
+        Ok(IpPoolInner {
+            network,
+            prefix_len,
+            gateway,
+            start,
+            end,
+            reserved: HashMap::new(),
+            external: HashSet::new(),
+            conflicts: HashMap::new(),
+            allocated: DashMap::new(),
+            vm_to_ip: DashMap::new(),
+            expires: DashMap::new(),
+            labels: DashMap::new(),
+            hostnames: DashMap::new(),
+            macs: DashMap::new(),
+            annotations: DashMap::new(),
+            deadlines: DashMap::new(),
+            pending: DashMap::new(),
+            activity: DashMap::new(),
+            fence: AtomicU64::new(0),
+            delegations: BTreeMap::new(),
+            prefixes6: BTreeMap::new(),
+            wireguard: BTreeMap::new(),
+            free: FreeList::new(start, end)?,
+            frozen: false,
+            routes: Vec::new(),
+            dhcp_options: DhcpOptions::default(),
+            lease_ttl: None,
+            hostname_template: None,
+            expiry_warning: None,
+            warned: DashMap::new(),
+            history: Mutex::new(VecDeque::new()),
+            version: AtomicU64::new(0),
+            journal: None,
+            breaker: None,
+            clock: Arc::new(SystemClock),
+            hooks: Vec::new(),
+            policy: None,
+            gateway_reachable: None,
+            thresholds: None,
+            vlan_id: None,
+            slaac: None,
+        })
+    }
+
+    fn touch(&self) {
+        self.version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn next_fence(&self) -> u64 {
+        self.fence.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn fence_of(&self, ip: &str) -> Option<u64> {
+        self.activity.get(ip).map(|activity| activity.fence)
+    }
+
+    // A token fences off the caller unless it belongs to one of `ips`, the
+    // allocations it is meant to act on
+    fn check_fence<'a>(
+        &self,
+        mut ips: impl Iterator<Item = &'a String>,
+        fence: Option<u64>,
+    ) -> Result<(), IpPoolError> {
+        match fence {
+            Some(fence) if !ips.any(|ip| self.fence_of(ip) == Some(fence)) => {
+                Err(IpPoolError::StaleFenceToken)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn record(&self, change: PoolChange) {
+        self.touch();
+        let mut history = self.history.lock().unwrap();
+        if history.len() == MAX_HISTORY {
+            history.pop_front();
+        }
+        history.push_back((self.clock.now(), change));
+    }
+
+    // Write-ahead: the entry must reach the journal before the change is
+    // applied, otherwise the change is refused
+    fn log(&self, entry: impl FnOnce(String) -> JournalEntry) -> Result<(), IpPoolError> {
+        let unavailable = |e: StorageError| match e {
+            StorageError::Unavailable(reason) => IpPoolError::StorageUnavailable(reason),
+            e => IpPoolError::StorageUnavailable(e.to_string()),
+        };
+        if let Some(breaker) = &self.breaker {
+            breaker.check().map_err(unavailable)?;
+        }
+
+        let Some((pool, journal)) = &self.journal else {
+            return Ok(());
+        };
+        let result = journal
+            .append(&entry(pool.clone()))
+            .map_err(|e| StorageError::Unavailable(e.to_string()));
+        if let Some(breaker) = &self.breaker {
+            breaker.record(&result);
+        }
+        result.map_err(unavailable)
+    }
+
+    // Record the full pool state after a structural change
+    fn log_state(&self) {
+        if let Err(e) = self.log(|pool| JournalEntry::Replace {
+            pool,
+            snapshot: Box::new(self.to_snapshot()),
+        }) {
+            tracing::error!("Failed to journal pool state: {}", e);
+        }
+    }
+
+    fn to_snapshot(&self) -> PoolSnapshot {
+        PoolSnapshot {
+            network: self.network,
+            prefix_len: self.prefix_len,
+            gateway: self.gateway.clone(),
+            start: Ipv4Addr::from(self.start),
+            end: Ipv4Addr::from(self.end),
+            allocations: self
+                .allocated
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect(),
+            reserved: self
+                .reserved
+                .iter()
+                .map(|(ip, label)| (ip.clone(), label.clone()))
+                .collect(),
+            external: self.external.iter().cloned().collect(),
+            frozen: self.frozen,
+            routes: self.routes.clone(),
+            dhcp_options: self.dhcp_options.clone(),
+            leases: self
+                .expires
+                .iter()
+                .map(|entry| (entry.key().clone(), *entry.value()))
+                .collect(),
+            purposes: self
+                .slots()
+                .filter(|(_, slot)| slot.purpose != PRIMARY)
+                .map(|(ip, slot)| (ip, slot.purpose))
+                .collect(),
+            interfaces: self
+                .slots()
+                .filter_map(|(ip, slot)| Some((ip, slot.interface?)))
+                .collect(),
+            conflicts: self
+                .conflicts
+                .iter()
+                .map(|(ip, conflict)| (ip.clone(), conflict.clone()))
+                .collect(),
+            labels: self
+                .labels
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect(),
+            hostnames: self
+                .hostnames
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect(),
+            macs: self
+                .macs
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect(),
+            annotations: self
+                .annotations
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect(),
+            deadlines: self
+                .deadlines
+                .iter()
+                .map(|entry| (entry.key().clone(), *entry.value()))
+                .collect(),
+            pending_releases: self
+                .pending
+                .iter()
+                .map(|entry| (entry.key().clone(), *entry.value()))
+                .collect(),
+            activity: self
+                .activity
+                .iter()
+                .map(|entry| (entry.key().clone(), *entry.value()))
+                .collect(),
+            delegations: self.delegations.clone(),
+            prefixes6: self.prefixes6.clone(),
+            wireguard: self.wireguard.clone(),
+            thresholds: self.thresholds,
+            vlan_id: self.vlan_id,
+            fence: self.fence.load(Ordering::Relaxed),
+        }
+    }
+
+    fn prefix6_of(&self, vm_id: &str) -> Option<String> {
+        self.prefixes6
+            .iter()
+            .find(|(_, holder)| *holder == vm_id)
+            .map(|(prefix, _)| prefix.clone())
+    }
+
+    // Delegated sub-ranges, handed out to their team only
+    fn delegated_ranges(&self) -> Vec<(u32, u32)> {
+        self.delegations
+            .values()
+            .map(|delegation| (u32::from(delegation.start), u32::from(delegation.end)))
+            .collect()
+    }
+
+    fn delegation_usage(&self, team: &str, delegation: &Delegation) -> DelegationUsage {
+        let mut total = 0;
+        let mut allocated = 0;
+        let mut available = 0;
+        for ip in u32::from(delegation.start)..=u32::from(delegation.end) {
+            let ip = Ipv4Addr::from(ip).to_string();
+            if self.reserved.contains_key(&ip) {
+                continue;
+            }
+            total += 1;
+            if self.allocated.contains_key(&ip) {
+                allocated += 1;
+            } else if !self.conflicts.contains_key(&ip) {
+                available += 1;
+            }
+        }
+
+        DelegationUsage {
+            team: team.to_string(),
+            start: delegation.start,
+            end: delegation.end,
+            total,
+            allocated,
+            available,
+            usage: allocated as f64 / total.max(1) as f64 * 100.0,
+        }
+    }
+
+    fn apply_snapshot(&mut self, snapshot: PoolSnapshot) -> Result<(), IpPoolError> {
+        if snapshot.start > snapshot.end {
+            return Err(IpPoolError::InvertedRange);
+        }
+        self.network = snapshot.network;
+        self.prefix_len = snapshot.prefix_len;
+        self.gateway = snapshot.gateway;
+        self.start = u32::from(snapshot.start);
+        self.end = u32::from(snapshot.end);
+        self.frozen = snapshot.frozen;
+        self.routes = snapshot.routes;
+        self.dhcp_options = snapshot.dhcp_options;
+        self.reserved = snapshot.reserved.into_iter().collect();
+        self.external = snapshot.external.into_iter().collect();
+        self.conflicts = snapshot.conflicts.into_iter().collect();
+        self.vm_to_ip = DashMap::new();
+        for (ip, vm_id) in &snapshot.allocations {
+            let slot = Slot::new(
+                snapshot.interfaces.get(ip).cloned(),
+                snapshot.purposes.get(ip).cloned(),
+            );
+            self.vm_to_ip
+                .entry(vm_id.clone())
+                .or_default()
+                .insert(slot, ip.clone());
+        }
+        self.allocated = snapshot.allocations.into_iter().collect();
+        self.expires = snapshot.leases.into_iter().collect();
+        self.labels = snapshot.labels.into_iter().collect();
+        self.hostnames = snapshot.hostnames.into_iter().collect();
+        self.macs = snapshot.macs.into_iter().collect();
+        self.annotations = snapshot.annotations.into_iter().collect();
+        self.deadlines = snapshot.deadlines.into_iter().collect();
+        self.pending = snapshot.pending_releases.into_iter().collect();
+        // Snapshots from before activity was tracked count from the restore
+        // and get their fencing tokens now
+        let now = self.clock.unix_now();
+        let issued = snapshot.activity.values().map(|activity| activity.fence);
+        self.fence = AtomicU64::new(issued.fold(snapshot.fence, u64::max));
+        self.activity = self
+            .allocated
+            .iter()
+            .map(|entry| {
+                let ip = entry.key();
+                let activity = snapshot.activity.get(ip).copied();
+                let activity = activity.unwrap_or_else(|| Activity::new(now, self.next_fence()));
+                (ip.clone(), activity)
+            })
+            .collect();
+        self.delegations = snapshot.delegations;
+        self.prefixes6 = snapshot.prefixes6;
+        self.wireguard = snapshot.wireguard;
+        self.thresholds = snapshot.thresholds;
+        self.vlan_id = snapshot.vlan_id;
+        self.rebuild_available();
+        self.touch();
+        Ok(())
+    }
+
+    fn in_range(&self, ip: &str) -> bool {
+        ip.parse::<Ipv4Addr>()
+            .map(|ip| (self.start..=self.end).contains(&u32::from(ip)))
+            .unwrap_or(false)
+    }
+
+    // Number of addresses in the range that can ever be allocated
+    fn capacity(&self) -> usize {
+        let reserved = self.reserved.keys().filter(|ip| self.in_range(ip)).count();
+        (self.end - self.start + 1) as usize - reserved
+    }
+
+    fn cidr(&self) -> String {
+        format!("{}/{}", self.network, self.prefix_len)
+    }
+
+    fn contains(&self, ip: u32) -> bool {
+        let mask = prefix_mask(self.prefix_len);
+        ip & mask == u32::from(self.network)
+    }
+
+    // Expiry for a lease requested now, None if it never expires
+    fn lease_expiry(&self, lease: Lease) -> Option<u64> {
+        let ttl = match lease {
+            Lease::PoolDefault => self.lease_ttl?,
+            Lease::Ttl(ttl) => ttl,
+            Lease::Infinite => return None,
+        };
+        Some(self.clock.unix_now() + ttl.as_secs())
+    }
+
+    // Take an address out of allocation for good, for a device outside the
+    // pool if `external`
+    fn set_reserved(&mut self, ip: &str, label: String, external: bool) {
+        if let Ok(addr) = ip.parse::<Ipv4Addr>() {
+            self.free.remove(u32::from(addr));
+        }
+        self.reserved.insert(ip.to_string(), label);
+        if external {
+            self.external.insert(ip.to_string());
+        } else {
+            self.external.remove(ip);
+        }
+    }
+
+    // Keep a flagged address from being handed out
+    fn flag(&mut self, ip: &str, conflict: Conflict) {
+        if let Ok(addr) = ip.parse::<Ipv4Addr>() {
+            self.free.remove(u32::from(addr));
+        }
+        self.conflicts.insert(ip.to_string(), conflict);
+    }
+
+    // Drop the flag, handing the address out again if it is free
+    fn unflag(&mut self, ip: &str) {
+        if self.conflicts.remove(ip).is_some()
+            && !self.allocated.contains_key(ip)
+            && !self.reserved.contains_key(ip)
+            && self.in_range(ip)
+            && let Ok(addr) = ip.parse::<Ipv4Addr>()
+        {
+            self.free.push(u32::from(addr));
+        }
+    }
+
+    fn set_annotation(&self, ip: &str, annotation: Annotation) {
+        if annotation.is_empty() {
+            self.annotations.remove(ip);
+        } else {
+            self.annotations.insert(ip.to_string(), annotation);
+        }
+    }
+
+    fn set_labels(&self, ip: &str, labels: BTreeMap<String, String>) {
+        if labels.is_empty() {
+            self.labels.remove(ip);
+        } else {
+            self.labels.insert(ip.to_string(), labels);
+        }
+    }
+
+    // Release every allocation whose time in `due` (lease expiry or deferred
+    // release) has come, returning (VM_ID, IP)
+    fn release_due(&self, due: &DashMap<String, u64>, now: u64) -> Vec<(String, String)> {
+        let candidates: Vec<String> = due
+            .iter()
+            .filter(|entry| *entry.value() <= now)
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        let mut released = Vec::new();
+        for ip in candidates {
+            let Some(vm_id) = self.allocated.get(&ip).map(|vm_id| vm_id.clone()) else {
+                due.remove(&ip);
+                continue;
+            };
+            // Holding the VM's entry keeps a concurrent renewal or
+            // cancellation out
+            match self.vm_to_ip.entry(vm_id.clone()) {
+                Entry::Occupied(mut entry)
+                    if entry.get().values().any(|held| *held == ip)
+                        && due.get(&ip).is_some_and(|at| *at <= now) =>
+                {
+                    if let Err(e) = self.log(|pool| JournalEntry::Release {
+                        pool,
+                        ip: ip.clone(),
+                    }) {
+                        tracing::error!("Failed to journal release of {}: {}", ip, e);
+                        continue;
+                    }
+                    entry.get_mut().retain(|_, held| *held != ip);
+                    if entry.get().is_empty() {
+                        entry.remove();
+                    }
+                }
+                _ => continue,
+            }
+
+            self.allocated.remove(&ip);
+            self.free_ip(&ip);
+            self.record(PoolChange::Released);
+            released.push((vm_id, ip));
+        }
+        released
+    }
+
+    // Allocations and releases recorded within `window` of now
+    fn changes_within(&self, window: Duration) -> (u64, u64) {
+        let now = self.clock.now();
+        let (mut allocations, mut releases) = (0u64, 0u64);
+        let history = self.history.lock().unwrap();
+        for (at, change) in history.iter().rev() {
+            if now.duration_since(*at) > window {
+                break;
+            }
+            match change {
+                PoolChange::Allocated => allocations += 1,
+                PoolChange::Released => releases += 1,
+            }
+        }
+        (allocations, releases)
+    }
+
+    // Share of the pool's capacity consumed per hour over `window`, net of
+    // releases: 1.0 would use up the whole pool in an hour. Releases
+    // outpacing allocations burn nothing.
+    fn burn_rate(&self, window: Duration) -> f64 {
+        let (allocations, releases) = self.changes_within(window);
+        let hours = window.as_secs_f64().max(1.0) / 3600.0;
+        allocations.saturating_sub(releases) as f64 / self.capacity().max(1) as f64 / hours
+    }
+
+    // Record `ip` as the VM's primary address, see IpPool::claim
+    // Journaled as settling the flagged conflict on the address if `resolves`
+    fn claim(
+        &self,
+        ip: &str,
+        vm_id: &str,
+        expires_at: Option<u64>,
+        requested: &Requested,
+        resolves: bool,
+    ) -> Result<(), IpPoolError> {
+        let addr = match ip.parse::<Ipv4Addr>() {
+            Ok(addr) if self.in_range(ip) => u32::from(addr),
+            _ => return Err(IpPoolError::InvalidIp),
+        };
+        let slot = Slot::primary();
+        if self.reserved.contains_key(ip)
+            || self.allocated.contains_key(ip)
+            || self
+                .vm_to_ip
+                .get(vm_id)
+                .is_some_and(|ips| ips.contains_key(&slot))
+        {
+            return Err(IpPoolError::IpInUse);
+        }
+        let fence = self.next_fence();
+        let (details, hostname) = self.changes(ip, vm_id, requested, true);
+        self.log(|pool| JournalEntry::Allocate {
+            pool,
+            ip: ip.to_string(),
+            vm_id: vm_id.to_string(),
+            purpose: None,
+            interface: None,
+            expires_at,
+            fence: Some(fence),
+            hostname: hostname.clone(),
+            details: details.clone(),
+            resolves,
+        })?;
+
+        self.free.remove(addr);
+        self.set_expiry(ip, expires_at);
+        if let Some(hostname) = hostname {
+            self.hostnames.insert(ip.to_string(), hostname);
+        }
+        self.apply_details(ip, details);
+        self.activity
+            .insert(ip.to_string(), Activity::new(self.clock.unix_now(), fence));
+        self.allocated.insert(ip.to_string(), vm_id.to_string());
+        self.vm_to_ip
+            .entry(vm_id.to_string())
+            .or_default()
+            .insert(slot, ip.to_string());
+        self.record(PoolChange::Allocated);
+
+        Ok(())
+    }
+
+    // What `requested` changes about `ip`, and the hostname to set: the one
+    // asked for, else for a new address the one from the pool's template
+    fn changes(
+        &self,
+        ip: &str,
+        vm_id: &str,
+        requested: &Requested,
+        new: bool,
+    ) -> (AllocationDetails, Option<String>) {
+        let labels = requested.labels.clone().filter(|labels| {
+            let held = self.labels.get(ip).map(|held| held.clone());
+            held.unwrap_or_default() != *labels
+        });
+        let hostname = match &requested.hostname {
+            Some(hostname) => Some(hostname.clone())
+                .filter(|hostname| self.hostnames.get(ip).is_none_or(|held| *held != *hostname)),
+            None if new => self.default_hostname(ip, vm_id),
+            None => None,
+        };
+        let mac = (requested.mac.clone())
+            .filter(|mac| self.macs.get(ip).is_none_or(|held| *held != *mac));
+
+        let held = (self.annotations.get(ip))
+            .map(|annotation| annotation.clone())
+            .unwrap_or_default();
+        let mut annotation = held.clone();
+        if let Some(owner) = requested
+            .owner
+            .clone()
+            .or_else(|| (requested.default_owner.clone()).filter(|_| held.owner.is_none()))
+        {
+            annotation.owner = Some(owner);
+        }
+        if let Some(notes) = &requested.notes {
+            annotation.notes = Some(notes.clone());
+        }
+        if let Some(ticket) = &requested.ticket {
+            annotation.ticket = Some(ticket.clone());
+        }
+        let annotation = (annotation != held).then_some(annotation);
+        let deadline = (requested.deadline)
+            .filter(|deadline| self.deadlines.get(ip).is_none_or(|held| *held > *deadline));
+
+        let details = AllocationDetails {
+            labels,
+            mac,
+            annotation,
+            deadline,
+        };
+        (details, hostname)
+    }
+
+    fn apply_details(&self, ip: &str, details: AllocationDetails) {
+        if let Some(labels) = details.labels {
+            self.set_labels(ip, labels);
+        }
+        if let Some(mac) = details.mac {
+            self.macs.insert(ip.to_string(), mac);
+        }
+        if let Some(annotation) = details.annotation {
+            self.set_annotation(ip, annotation);
+        }
+        if let Some(deadline) = details.deadline {
+            self.deadlines.insert(ip.to_string(), deadline);
+        }
+    }
+
+    // Hostname the pool's template gives a new allocation
+    fn default_hostname(&self, ip: &str, vm_id: &str) -> Option<String> {
+        let template = self.hostname_template.as_ref()?;
+        Some(template.render(ip.parse().ok()?, vm_id))
+    }
+
+    fn set_expiry(&self, ip: &str, expires_at: Option<u64>) {
+        match expires_at {
+            Some(expires_at) => {
+                self.expires.insert(ip.to_string(), expires_at);
+            }
+            None => {
+                self.expires.remove(ip);
+            }
+        }
+    }
+
+    // Move an existing allocation onto the newly requested lease
+    fn renew(
+        &self,
+        ip: &str,
+        vm_id: &str,
+        slot: &Slot,
+        expires_at: Option<u64>,
+        requested: &Requested,
+    ) -> Result<(), IpPoolError> {
+        let (details, hostname) = self.changes(ip, vm_id, requested, false);
+        if self.expires.get(ip).map(|e| *e) != expires_at
+            || hostname.is_some()
+            || details != AllocationDetails::default()
+        {
+            self.log(|pool| JournalEntry::Allocate {
+                pool,
+                ip: ip.to_string(),
+                vm_id: vm_id.to_string(),
+                purpose: journal_purpose(&slot.purpose),
+                interface: slot.interface.clone(),
+                expires_at,
+                fence: self.fence_of(ip),
+                hostname: hostname.clone(),
+                details: details.clone(),
+                resolves: false,
+            })?;
+            self.set_expiry(ip, expires_at);
+            if let Some(hostname) = hostname {
+                self.hostnames.insert(ip.to_string(), hostname);
+            }
+            self.apply_details(ip, details);
+        }
+        // Asking again shows the holder still uses the address
+        let now = self.clock.unix_now();
+        let mut activity = self
+            .activity
+            .entry(ip.to_string())
+            .or_insert_with(|| Activity::new(now, self.next_fence()));
+        activity.renewed_at = now;
+        activity.renewals = activity.renewals.saturating_add(1);
+        drop(activity);
+        self.touch();
+        Ok(())
+    }
+
+    // Every allocated IP with its slot
+    fn slots(&self) -> impl Iterator<Item = (String, Slot)> {
+        let slots: Vec<(String, Slot)> = self
+            .vm_to_ip
+            .iter()
+            .flat_map(|entry| {
+                entry
+                    .value()
+                    .iter()
+                    .map(|(slot, ip)| (ip.clone(), slot.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        slots.into_iter()
+    }
+
+    // When the holder of a lease expiring at `expires_at` should renew it
+    // to avoid the expiry warning
+    fn renew_before(&self, expires_at: Option<u64>) -> Option<u64> {
+        Some(expires_at?.saturating_sub(self.expiry_warning?.as_secs()))
+    }
+
+    fn allocation(&self, vm_id: String, slot: Slot, ip: String) -> IpAllocation {
+        let expires_at = self.expires.get(&ip).map(|e| *e);
+        let annotation = (self.annotations.get(&ip))
+            .map(|annotation| annotation.clone())
+            .unwrap_or_default();
+        IpAllocation {
+            expires_at,
+            renew_before: self.renew_before(expires_at),
+            hard_expires_at: self.deadlines.get(&ip).map(|at| *at),
+            labels: self
+                .labels
+                .get(&ip)
+                .map(|labels| labels.clone())
+                .unwrap_or_default(),
+            pending_release_at: self.pending.get(&ip).map(|at| *at),
+            fence_token: self.fence_of(&ip),
+            hostname: self.hostnames.get(&ip).map(|hostname| hostname.clone()),
+            mac: self.macs.get(&ip).map(|mac| mac.clone()),
+            owner: annotation.owner,
+            notes: annotation.notes,
+            ticket: annotation.ticket,
+            ip,
+            vm_id,
+            reserved: false,
+            external: false,
+            label: None,
+            purpose: Some(slot.purpose),
+            interface: slot.interface,
+        }
+    }
+
+    fn slot_of(&self, vm_id: &str, ip: &str) -> Slot {
+        self.vm_to_ip
+            .get(vm_id)
+            .and_then(|ips| {
+                ips.iter()
+                    .find(|(_, held)| *held == ip)
+                    .map(|(slot, _)| slot.clone())
+            })
+            .unwrap_or_else(Slot::primary)
+    }
+
+    // Drop `ip` from the VM's addresses, forgetting VMs left without any
+    fn unbind(&self, vm_id: &str, ip: &str) {
+        if let Entry::Occupied(mut entry) = self.vm_to_ip.entry(vm_id.to_string()) {
+            entry.get_mut().retain(|_, held| held != ip);
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+        }
+    }
+
+    async fn pre_allocate(&self, vm_id: &str, slot: &Slot) -> Result<(), IpPoolError> {
+        for hook in &self.hooks {
+            hook.pre_allocate(vm_id, slot)
+                .await
+                .map_err(IpPoolError::PolicyViolation)?;
+        }
+        Ok(())
+    }
+
+    async fn post_allocate(&self, vm_id: &str, slot: &Slot, ip: &str) {
+        for hook in &self.hooks {
+            hook.post_allocate(vm_id, slot, ip).await;
+        }
+    }
+
+    async fn pre_release(&self, vm_id: &str, ip: &str) -> Result<(), IpPoolError> {
+        for hook in &self.hooks {
+            hook.pre_release(vm_id, ip)
+                .await
+                .map_err(IpPoolError::PolicyViolation)?;
+        }
+        Ok(())
+    }
+
+    // Hand an address back to the free list, dropping what was kept about
+    // its allocation
+    fn free_ip(&self, ip: &str) {
+        self.expires.remove(ip);
+        self.warned.remove(ip);
+        self.labels.remove(ip);
+        self.hostnames.remove(ip);
+        self.macs.remove(ip);
+        self.annotations.remove(ip);
+        self.deadlines.remove(ip);
+        self.pending.remove(ip);
+        self.activity.remove(ip);
+        if self.conflicts.contains_key(ip) {
+            return;
+        }
+        if let Ok(addr) = ip.parse::<Ipv4Addr>() {
+            self.free.push(u32::from(addr));
+        }
+    }
+
+    // Rebuild the free list from the range, skipping used, reserved and
+    // conflicting IPs
+    fn rebuild_available(&mut self) {
+        // Ranges are checked when a pool is created or restored, and split
+        // and merge keep them ordered
+        self.free = FreeList::new(self.start, self.end).expect("pool range is ordered");
+        for ip in self.start..=self.end {
+            let addr = Ipv4Addr::from(ip).to_string();
+            if !self.allocated.contains_key(&addr)
+                && !self.reserved.contains_key(&addr)
+                && !self.conflicts.contains_key(&addr)
+            {
+                self.free.push(ip);
+            }
+        }
+    }
+}
+
+// The part of a policy's placement range inside a team's delegation; a
+// range entirely outside it leaves the team nowhere to allocate
+fn within_delegation(
+    (from, to): (u32, u32),
+    (start, end): (u32, u32),
+) -> Result<(u32, u32), IpPoolError> {
+    let (from, to) = (from.max(start), to.min(end));
+    if from > to {
+        return Err(IpPoolError::PolicyViolation(
+            "policy range lies outside the team's delegation".to_string(),
+        ));
+    }
+    Ok((from, to))
 }
 
-#[derive(Debug)]
-struct IpPoolInner {
-    network: String,
-    gateway: String,
-    start: u8,
-    end: u8,
-    allocated: HashMap<String, String>, // IP -> VM_ID
-    vm_to_ip: HashMap<String, String>,  // VM_ID -> IP
-    available: Vec<String>,
-}
+// Everything in `start..=end` outside `from..=to`, as exclusions
+fn outside((from, to): (u32, u32), start: u32, end: u32) -> [(u32, u32); 2] {
+    [(start, from.saturating_sub(1)), (to.saturating_add(1), end)]
+}
+
+fn prefix_mask(prefix_len: u8) -> u32 {
+    if prefix_len == 0 {
+        0
+    } else {
+        u32::MAX << (32 - prefix_len)
+    }
+}
+
+impl IpPool {
+    // /24 pool from a network prefix (e.g., "172.16.0"), handing out
+    // .1-.254 except the gateway
+    pub fn new(network: String, gateway: String) -> Self {
+        let base: Ipv4Addr = format!("{}.0", network)
+            .parse()
+            .expect("invalid network prefix");
+        let base = u32::from(base);
+
+        Self::with_range(Ipv4Addr::from(base), 24, gateway, base + 1, base + 254)
+            .expect("/24 range is ordered")
+    }
+
+    pub fn with_range(
+        network: Ipv4Addr,
+        prefix_len: u8,
+        gateway: String,
+        start: u32,
+        end: u32,
+    ) -> Result<Self, IpPoolError> {
+        let mut inner = IpPoolInner::new(network, prefix_len, gateway, start, end)?;
+        let gateway = inner.gateway.clone();
+        inner.reserved.insert(gateway, "gateway".to_string());
+        inner.rebuild_available();
+
+        Ok(IpPool {
+            inner: Arc::new(RwLock::new(inner)),
+            lock_wait: Arc::default(),
+        })
+    }
+
+    // Take lease and history time from `clock` instead of the system clock
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        self.inner
+            .try_write()
+            .expect("new pool is not shared")
+            .clock = clock;
+        self
+    }
+
+    // Register an allocation policy hook
+    pub fn with_hook(self, hook: Arc<dyn AllocationHook>) -> Self {
+        self.inner
+            .try_write()
+            .expect("new pool is not shared")
+            .hooks
+            .push(hook);
+        self
+    }
+
+    // Shared lock for the hot paths, recording how long it took to get
+    async fn read_timed(&self) -> RwLockReadGuard<'_, IpPoolInner> {
+        let started = Instant::now();
+        let inner = self.inner.read().await;
+        self.lock_wait.record(started.elapsed());
+        inner
+    }
+
+    pub fn lock_wait(&self) -> HistogramSummary {
+        self.lock_wait.summary()
+    }
+
+    pub async fn allocate_ip(&self, vm_id: String) -> Result<String, IpPoolError> {
+        self.allocate_ip_with_lease(vm_id, Lease::PoolDefault)
+            .await
+            .map(|(ip, _)| ip)
+    }
+
+    // Allocate the VM's primary address under the given lease
+    pub async fn allocate_ip_with_lease(
+        &self,
+        vm_id: String,
+        lease: Lease,
+    ) -> Result<(String, Option<u64>), IpPoolError> {
+        self.allocate_address(vm_id, &Slot::primary(), lease).await
+    }
+
+    // Allocate the VM's address for `slot`, returning the IP and the lease
+    // expiry. Asking again for an allocated slot renews its lease.
+    pub async fn allocate_address(
+        &self,
+        vm_id: String,
+        slot: &Slot,
+        lease: Lease,
+    ) -> Result<(String, Option<u64>), IpPoolError> {
+        (self.allocate_in(vm_id, slot, lease, None, &Requested::default())).await
+    }
+
+    // Allocate like allocate_address, but only from the team's delegation
+    pub async fn allocate_delegated(
+        &self,
+        team: &str,
+        vm_id: String,
+        slot: &Slot,
+        lease: Lease,
+    ) -> Result<(String, Option<u64>), IpPoolError> {
+        (self.allocate_in(vm_id, slot, lease, Some(team), &Requested::default())).await
+    }
+
+    // Allocate like allocate_address, from the team's delegation if there
+    // is one, setting what was requested along with the allocation
+    pub async fn allocate_with(
+        &self,
+        team: Option<&str>,
+        vm_id: String,
+        slot: &Slot,
+        lease: Lease,
+        requested: &Requested,
+    ) -> Result<(String, Option<u64>), IpPoolError> {
+        self.allocate_in(vm_id, slot, lease, team, requested).await
+    }
+
+    async fn allocate_in(
+        &self,
+        vm_id: String,
+        slot: &Slot,
+        lease: Lease,
+        team: Option<&str>,
+        requested: &Requested,
+    ) -> Result<(String, Option<u64>), IpPoolError> {
+        let inner = self.read_timed().await;
+        let expires_at = inner.lease_expiry(lease);
+        let delegated = match team {
+            Some(team) => {
+                let delegation = inner
+                    .delegations
+                    .get(team)
+                    .ok_or(IpPoolError::PoolNotDelegated)?;
+                Some((u32::from(delegation.start), u32::from(delegation.end)))
+            }
+            None => None,
+        };
+
+        // Check if VM already has an IP for this slot (idempotent)
+        if let Some(ips) = inner.vm_to_ip.get(&vm_id)
+            && let Some(ip) = ips.get(slot)
+        {
+            inner.renew(ip, &vm_id, slot, expires_at, requested)?;
+            return Ok((ip.clone(), expires_at));
+        }
+
+        // Frozen pools only serve existing allocations
+        if inner.frozen {
+            return Err(IpPoolError::PoolFrozen);
+        }
+        inner.pre_allocate(&vm_id, slot).await?;
+        let placement = match &inner.policy {
+            Some((pool, policy)) => policy
+                .evaluate(pool, &vm_id, slot)
+                .map_err(IpPoolError::PolicyViolation)?,
+            None => Placement::Any,
+        };
+
+        // Holding the VM's entry makes concurrent requests for the same VM
+        // agree on a single address per slot
+        let entry = inner.vm_to_ip.entry(vm_id.clone());
+        if let Entry::Occupied(entry) = &entry
+            && let Some(ip) = entry.get().get(slot)
+        {
+            inner.renew(ip, &vm_id, slot, expires_at, requested)?;
+            return Ok((ip.clone(), expires_at));
+        }
+
+        // Take first available IP the policy allows, from the delegation if
+        // any and otherwise outside every delegation
+        let addr = match (delegated, placement) {
+            (Some((start, end)), Placement::Any) => inner.free.pop_in(start, end),
+            (Some(delegated), Placement::Range(from, to)) => {
+                let (from, to) = within_delegation((from, to), delegated)?;
+                inner.free.pop_in(from, to)
+            }
+            (None, Placement::Any) if inner.delegations.is_empty() => inner.free.pop(),
+            (None, Placement::Any) => {
+                inner
+                    .free
+                    .pop_in_excluding(inner.start, inner.end, &inner.delegated_ranges())
+            }
+            (None, Placement::Range(from, to)) => {
+                inner
+                    .free
+                    .pop_in_excluding(from, to, &inner.delegated_ranges())
+            }
+        }
+        .ok_or(IpPoolError::NoAvailableIps)?;
+        let ip = Ipv4Addr::from(addr).to_string();
+        let fence = inner.next_fence();
+        let (details, hostname) = inner.changes(&ip, &vm_id, requested, true);
+        if let Err(e) = inner.log(|pool| JournalEntry::Allocate {
+            pool,
+            ip: ip.clone(),
+            vm_id: vm_id.clone(),
+            purpose: journal_purpose(&slot.purpose),
+            interface: slot.interface.clone(),
+            expires_at,
+            fence: Some(fence),
+            hostname: hostname.clone(),
+            details: details.clone(),
+            resolves: false,
+        }) {
+            inner.free.unpop(addr);
+            return Err(e);
+        }
+
+        // Mark as allocated
+        inner.set_expiry(&ip, expires_at);
+        if let Some(hostname) = hostname {
+            inner.hostnames.insert(ip.clone(), hostname);
+        }
+        inner.apply_details(&ip, details);
+        inner
+            .activity
+            .insert(ip.clone(), Activity::new(inner.clock.unix_now(), fence));
+        inner.allocated.insert(ip.clone(), vm_id.clone());
+        entry.or_default().insert(slot.clone(), ip.clone());
+        inner.record(PoolChange::Allocated);
+
+        inner.post_allocate(&vm_id, slot, &ip).await;
+        Ok((ip, expires_at))
+    }
+
+    // Expiry a lease requested now would get, without allocating anything
+    pub async fn lease_expiry(&self, lease: Lease) -> Option<u64> {
+        let inner = self.inner.read().await;
+        inner.lease_expiry(lease)
+    }
+
+    // Leases due to expire within the warning period whose holders were not
+    // warned about that expiry yet, returning (VM_ID, IP, expiry). Each is
+    // returned once, and again only after a renewal moves its expiry.
+    pub async fn expiring_leases(&self) -> Vec<(String, String, u64)> {
+        let inner = self.inner.read().await;
+        let Some(warning) = inner.expiry_warning else {
+            return Vec::new();
+        };
+        let now = inner.clock.unix_now();
+
+        let mut expiring: Vec<(String, String, u64)> = inner
+            .expires
+            .iter()
+            .filter(|entry| *entry.value() > now && *entry.value() <= now + warning.as_secs())
+            .filter(|entry| inner.warned.get(entry.key()).map(|at| *at) != Some(*entry.value()))
+            .filter_map(|entry| {
+                let vm_id = inner.allocated.get(entry.key())?.clone();
+                Some((vm_id, entry.key().clone(), *entry.value()))
+            })
+            .collect();
+        expiring.sort_by_key(|(_, ip, _)| ip.parse::<Ipv4Addr>().ok());
+        for (_, ip, expires_at) in &expiring {
+            inner.warned.insert(ip.clone(), *expires_at);
+        }
+        expiring
+    }
+
+    // When the holder of a lease expiring at `expires_at` should renew it,
+    // None when expiry warnings are off or the lease never expires
+    pub async fn renew_before(&self, expires_at: Option<u64>) -> Option<u64> {
+        let inner = self.inner.read().await;
+        inner.renew_before(expires_at)
+    }
+
+    // Release every allocation whose lease ran out, returning (VM_ID, IP)
+    pub async fn expire_leases(&self) -> Vec<(String, String)> {
+        let inner = self.read_timed().await;
+        let now = inner.clock.unix_now();
+        inner.release_due(&inner.expires, now)
+    }
+
+    // Release every allocation past its hard expiry, renewed or not,
+    // returning (VM_ID, IP)
+    pub async fn reclaim_overdue(&self) -> Vec<(String, String)> {
+        let inner = self.read_timed().await;
+        let now = inner.clock.unix_now();
+        inner.release_due(&inner.deadlines, now)
+    }
+
+    // Carry out every deferred release whose grace period is over, returning
+    // (VM_ID, IP)
+    pub async fn release_pending(&self) -> Vec<(String, String)> {
+        let inner = self.read_timed().await;
+        let now = inner.clock.unix_now();
+        inner.release_due(&inner.pending, now)
+    }
+
+    // Schedule every address of the VM for release after `grace`. Until then
+    // the allocation stays in place, flagged, and the release can be
+    // cancelled; hooks get to veto it now rather than when it happens.
+    pub async fn defer_release(
+        &self,
+        vm_id: &str,
+        grace: Duration,
+    ) -> Result<(Vec<String>, u64), IpPoolError> {
+        let inner = self.read_timed().await;
+
+        let held: Vec<String> = inner
+            .vm_to_ip
+            .get(vm_id)
+            .map(|ips| ips.values().cloned().collect())
+            .unwrap_or_default();
+        if held.is_empty() {
+            return Err(IpPoolError::IpNotFound);
+        }
+        for ip in &held {
+            inner.pre_release(vm_id, ip).await?;
+        }
+
+        // Holding the VM's entry keeps concurrent releases out
+        let release_at = inner.clock.unix_now() + grace.as_secs();
+        let ips = inner.vm_to_ip.get(vm_id).ok_or(IpPoolError::IpNotFound)?;
+        let scheduled: Vec<String> = ips.values().cloned().collect();
+        for ip in &scheduled {
+            inner.log(|pool| JournalEntry::PendingRelease {
+                pool,
+                ip: ip.clone(),
+                release_at: Some(release_at),
+            })?;
+            inner.pending.insert(ip.clone(), release_at);
+        }
+        drop(ips);
+        inner.touch();
+
+        Ok((scheduled, release_at))
+    }
+
+    // Check the invariants between the allocations, the VM index, the
+    // per-address metadata and the free list, fixing what is found when
+    // `repair` is set. Allocations are the source of truth: VM index entries
+    // they do not back are dropped, as are allocations outside the range or
+    // missing from the index, and the free list is rebuilt.
+    pub async fn check_consistency(&self, repair: bool) -> Vec<Inconsistency> {
+        let mut inner = self.inner.write().await;
+        let mut issues = Vec::new();
+
+        let mut dropped = Vec::new();
+        for entry in inner.allocated.iter() {
+            let (ip, vm_id) = (entry.key(), entry.value());
+            let kind = if !inner.in_range(ip) {
+                InconsistencyKind::OutOfRange
+            } else if !inner
+                .vm_to_ip
+                .get(vm_id)
+                .is_some_and(|ips| ips.values().any(|held| held == ip))
+            {
+                InconsistencyKind::MissingFromVmIndex
+            } else {
+                continue;
+            };
+            issues.push(Inconsistency::new(kind, ip, Some(vm_id)));
+            dropped.push((ip.clone(), vm_id.clone()));
+        }
+
+        let mut dangling = Vec::new();
+        for entry in inner.vm_to_ip.iter() {
+            for (slot, ip) in entry.value() {
+                if inner
+                    .allocated
+                    .get(ip)
+                    .is_none_or(|holder| *holder != *entry.key())
+                {
+                    let kind = InconsistencyKind::DanglingVmEntry;
+                    issues.push(Inconsistency::new(kind, ip, Some(entry.key())));
+                    dangling.push((entry.key().clone(), slot.clone()));
+                }
+            }
+        }
+
+        let orphaned: BTreeSet<String> = (inner.expires.iter().map(|e| e.key().clone()))
+            .chain(inner.labels.iter().map(|e| e.key().clone()))
+            .chain(inner.hostnames.iter().map(|e| e.key().clone()))
+            .chain(inner.macs.iter().map(|e| e.key().clone()))
+            .chain(inner.annotations.iter().map(|e| e.key().clone()))
+            .chain(inner.deadlines.iter().map(|e| e.key().clone()))
+            .chain(inner.pending.iter().map(|e| e.key().clone()))
+            .chain(inner.activity.iter().map(|e| e.key().clone()))
+            .filter(|ip| !inner.allocated.contains_key(ip))
+            .collect();
+        for ip in &orphaned {
+            let kind = InconsistencyKind::OrphanedMetadata;
+            issues.push(Inconsistency::new(kind, ip, None));
+        }
+
+        let free: HashSet<u32> = inner.free.addresses().into_iter().collect();
+        for addr in &free {
+            if !(inner.start..=inner.end).contains(addr) {
+                let kind = InconsistencyKind::FreeOutOfRange;
+                issues.push(Inconsistency::new(
+                    kind,
+                    &Ipv4Addr::from(*addr).to_string(),
+                    None,
+                ));
+            }
+        }
+        for addr in inner.start..=inner.end {
+            let ip = Ipv4Addr::from(addr).to_string();
+            let holder = inner.allocated.get(&ip).map(|vm_id| vm_id.clone());
+            let unavailable = holder.is_some()
+                || inner.reserved.contains_key(&ip)
+                || inner.conflicts.contains_key(&ip);
+            let kind = match (unavailable, free.contains(&addr)) {
+                (false, false) => InconsistencyKind::LostAddress,
+                (true, true) => InconsistencyKind::UnavailableFree,
+                _ => continue,
+            };
+            issues.push(Inconsistency::new(kind, &ip, holder.as_deref()));
+        }
+
+        if repair && !issues.is_empty() {
+            for (vm_id, slot) in dangling {
+                if let Entry::Occupied(mut entry) = inner.vm_to_ip.entry(vm_id) {
+                    entry.get_mut().remove(&slot);
+                    if entry.get().is_empty() {
+                        entry.remove();
+                    }
+                }
+            }
+            for (ip, vm_id) in &dropped {
+                if let Entry::Occupied(mut entry) = inner.vm_to_ip.entry(vm_id.clone()) {
+                    entry.get_mut().retain(|_, held| held != ip);
+                    if entry.get().is_empty() {
+                        entry.remove();
+                    }
+                }
+            }
+            for ip in dropped.iter().map(|(ip, _)| ip).chain(&orphaned) {
+                inner.allocated.remove(ip);
+                inner.expires.remove(ip);
+                inner.labels.remove(ip);
+                inner.hostnames.remove(ip);
+                inner.macs.remove(ip);
+                inner.annotations.remove(ip);
+                inner.deadlines.remove(ip);
+                inner.pending.remove(ip);
+                inner.activity.remove(ip);
+            }
+            inner.rebuild_available();
+            inner.touch();
+        }
+        issues.sort_by(|a, b| (a.kind, &a.ip).cmp(&(b.kind, &b.ip)));
+        issues
+    }
+
+    // Fencing token of the allocation holding `ip`
+    pub async fn fence_token(&self, ip: &str) -> Option<u64> {
+        let inner = self.inner.read().await;
+        if !inner.allocated.contains_key(ip) {
+            return None;
+        }
+        inner.fence_of(ip)
+    }
+
+    // Check a caller's fencing token against the VM's allocation for `slot`,
+    // or any of its allocations without one. Holding a stale token, or one
+    // for an allocation that is gone, fences the caller off.
+    pub async fn check_fence(
+        &self,
+        vm_id: &str,
+        slot: Option<&Slot>,
+        fence: u64,
+    ) -> Result<(), IpPoolError> {
+        let inner = self.inner.read().await;
+        let ips = inner
+            .vm_to_ip
+            .get(vm_id)
+            .ok_or(IpPoolError::StaleFenceToken)?;
+        let held = ips
+            .iter()
+            .filter(|(held, _)| slot.is_none_or(|slot| *held == slot))
+            .map(|(_, ip)| ip);
+        inner.check_fence(held, Some(fence))
+    }
+
+    // Keep the VM's addresses after all, returning the ones that were pending
+    pub async fn cancel_release(&self, vm_id: &str) -> Result<Vec<String>, IpPoolError> {
+        let inner = self.read_timed().await;
+
+        let ips = inner
+            .vm_to_ip
+            .get(vm_id)
+            .ok_or(IpPoolError::ReleaseNotPending)?;
+        let pending: Vec<String> = ips
+            .values()
+            .filter(|ip| inner.pending.contains_key(*ip))
+            .cloned()
+            .collect();
+        if pending.is_empty() {
+            return Err(IpPoolError::ReleaseNotPending);
+        }
+        for ip in &pending {
+            inner.log(|pool| JournalEntry::PendingRelease {
+                pool,
+                ip: ip.clone(),
+                release_at: None,
+            })?;
+            inner.pending.remove(ip);
+        }
+        drop(ips);
+        inner.touch();
+
+        Ok(pending)
+    }
+
+    // Report the IP allocate_address would return without mutating state
+    pub async fn preview_allocation(
+        &self,
+        vm_id: &str,
+        slot: &Slot,
+    ) -> Result<String, IpPoolError> {
+        self.preview_in(vm_id, slot, None).await
+    }
+
+    // Report the IP allocate_delegated would return without mutating state
+    pub async fn preview_delegated(
+        &self,
+        team: &str,
+        vm_id: &str,
+        slot: &Slot,
+    ) -> Result<String, IpPoolError> {
+        self.preview_in(vm_id, slot, Some(team)).await
+    }
+
+    async fn preview_in(
+        &self,
+        vm_id: &str,
+        slot: &Slot,
+        team: Option<&str>,
+    ) -> Result<String, IpPoolError> {
+        let inner = self.inner.read().await;
+
+        if let Some(ips) = inner.vm_to_ip.get(vm_id)
+            && let Some(ip) = ips.get(slot)
+        {
+            return Ok(ip.clone());
+        }
+
+        if inner.frozen {
+            return Err(IpPoolError::PoolFrozen);
+        }
+
+        // Where the policy would place it, without running allocation hooks
+        let range = match &inner.policy {
+            Some((pool, policy)) => match policy
+                .evaluate(pool, vm_id, slot)
+                .map_err(IpPoolError::PolicyViolation)?
+            {
+                Placement::Range(from, to) => (from, to),
+                Placement::Any => (inner.start, inner.end),
+            },
+            None => (inner.start, inner.end),
+        };
+        let next = match team {
+            Some(team) => {
+                let delegation = inner
+                    .delegations
+                    .get(team)
+                    .ok_or(IpPoolError::PoolNotDelegated)?;
+                let delegated = (u32::from(delegation.start), u32::from(delegation.end));
+                let range = within_delegation(range, delegated)?;
+                inner
+                    .free
+                    .peek_n(1, &outside(range, inner.start, inner.end))
+            }
+            None => {
+                let mut excluded = inner.delegated_ranges();
+                excluded.extend(outside(range, inner.start, inner.end));
+                inner.free.peek_n(1, &excluded)
+            }
+        };
+        next.first()
+            .map(|ip| Ipv4Addr::from(*ip).to_string())
+            .ok_or(IpPoolError::NoAvailableIps)
+    }
+
+    // Release every address held by the VM, returning them
+    pub async fn release_ip(&self, vm_id: &str) -> Result<Vec<String>, IpPoolError> {
+        self.release_vm(vm_id, None).await
+    }
+
+    // Release every address held by the VM, only if `fence` is the token of
+    // one of them: a caller that saw an allocation since released, and the
+    // VM get new addresses, cannot release those
+    pub async fn release_ip_fenced(
+        &self,
+        vm_id: &str,
+        fence: u64,
+    ) -> Result<Vec<String>, IpPoolError> {
+        self.release_vm(vm_id, Some(fence)).await
+    }
+
+    async fn release_vm(
+        &self,
+        vm_id: &str,
+        fence: Option<u64>,
+    ) -> Result<Vec<String>, IpPoolError> {
+        let inner = self.read_timed().await;
+
+        // Policies see every address before any is released
+        let held: Vec<String> = inner
+            .vm_to_ip
+            .get(vm_id)
+            .map(|ips| ips.values().cloned().collect())
+            .unwrap_or_default();
+        if !held.is_empty() {
+            inner.check_fence(held.iter(), fence)?;
+        }
+        for ip in &held {
+            inner.pre_release(vm_id, ip).await?;
+        }
+
+        // Find IPs for this VM
+        let mut released = Vec::new();
+        let mut failure = None;
+        match inner.vm_to_ip.entry(vm_id.to_string()) {
+            Entry::Occupied(mut entry) => {
+                // Checked again now that nothing else can change the VM
+                inner.check_fence(entry.get().values(), fence)?;
+                for (slot, ip) in entry.get().clone() {
+                    if let Err(e) = inner.log(|pool| JournalEntry::Release {
+                        pool,
+                        ip: ip.clone(),
+                    }) {
+                        failure = Some(e);
+                        break;
+                    }
+                    entry.get_mut().remove(&slot);
+                    released.push(ip);
+                }
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+            }
+            Entry::Vacant(_) => return Err(IpPoolError::IpNotFound),
+        }
+
+        // Remove allocations and add back to available pool
+        for ip in &released {
+            inner.allocated.remove(ip);
+            inner.free_ip(ip);
+            inner.record(PoolChange::Released);
+        }
+
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(released),
+        }
+    }
+
+    // The next `count` addresses allocations would get, without reserving them
+    pub async fn next_free(&self, count: usize) -> Result<Vec<String>, IpPoolError> {
+        let inner = self.inner.read().await;
+
+        if inner.frozen {
+            return Err(IpPoolError::PoolFrozen);
+        }
+
+        Ok(inner
+            .free
+            .peek_n(count, &inner.delegated_ranges())
+            .into_iter()
+            .map(|ip| Ipv4Addr::from(ip).to_string())
+            .collect())
+    }
+
+    // Release a single address, returning the VM that held it
+    pub async fn release_ip_by_address(&self, ip: &str) -> Result<String, IpPoolError> {
+        self.release_address(ip, None, None, true).await
+    }
+
+    // Release a single address only if `vm_id` still holds it, so a stale
+    // caller cannot release an address that was since handed to another VM
+    pub async fn release_ip_held_by(&self, ip: &str, vm_id: &str) -> Result<String, IpPoolError> {
+        self.release_address(ip, Some(vm_id), None, true).await
+    }
+
+    // Release a single address only if `fence` is still its token, which
+    // also catches it being re-allocated to the same VM
+    pub async fn release_address_fenced(
+        &self,
+        ip: &str,
+        expected_vm: Option<&str>,
+        fence: u64,
+    ) -> Result<String, IpPoolError> {
+        self.release_address(ip, expected_vm, Some(fence), true)
+            .await
+    }
+
+    // Release for internal moves and rollbacks, which allocation hooks do
+    // not get to veto
+    pub async fn release_unchecked(&self, ip: &str) -> Result<String, IpPoolError> {
+        self.release_address(ip, None, None, false).await
+    }
+
+    async fn release_address(
+        &self,
+        ip: &str,
+        expected_vm: Option<&str>,
+        fence: Option<u64>,
+        run_hooks: bool,
+    ) -> Result<String, IpPoolError> {
+        let inner = self.read_timed().await;
+
+        // Validate IP is in our network
+        if !Self::is_valid_ip(&inner, ip) {
+            return Err(IpPoolError::InvalidIp);
+        }
+
+        // Find VM for this IP
+        let vm_id = inner
+            .allocated
+            .get(ip)
+            .map(|vm_id| vm_id.clone())
+            .ok_or(IpPoolError::IpNotFound)?;
+        if expected_vm.is_some_and(|expected| expected != vm_id) {
+            return Err(IpPoolError::HeldByOtherVm);
+        }
+        let target = ip.to_string();
+        inner.check_fence(std::iter::once(&target), fence)?;
+        if run_hooks {
+            inner.pre_release(&vm_id, ip).await?;
+        }
+
+        match inner.vm_to_ip.entry(vm_id.clone()) {
+            Entry::Occupied(mut entry) if entry.get().values().any(|held| held == ip) => {
+                inner.check_fence(std::iter::once(&target), fence)?;
+                inner.log(|pool| JournalEntry::Release {
+                    pool,
+                    ip: ip.to_string(),
+                })?;
+                entry.get_mut().retain(|_, held| held != ip);
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+            }
+            // Released concurrently
+            _ => return Err(IpPoolError::IpNotFound),
+        }
+
+        // Remove allocation and add back to available pool
+        inner.allocated.remove(ip);
+        inner.free_ip(ip);
+        inner.record(PoolChange::Released);
+
+        Ok(vm_id)
+    }
+
+    // Release every address labelled `key`=`value`, returning (VM_ID, IP) of
+    // each in address order. The pool is locked throughout so nothing can
+    // take the label meanwhile, and a policy vetoing any of them releases none
+    pub async fn release_labeled(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<(String, String)>, IpPoolError> {
+        let inner = self.inner.write().await;
+
+        let mut labeled: Vec<(String, String)> = inner
+            .labels
+            .iter()
+            .filter(|entry| entry.value().get(key).is_some_and(|held| held == value))
+            .filter_map(|entry| {
+                let ip = entry.key();
+                inner
+                    .allocated
+                    .get(ip)
+                    .map(|vm_id| (vm_id.clone(), ip.clone()))
+            })
+            .collect();
+        labeled.sort_by_key(|(_, ip)| ip.parse::<Ipv4Addr>().ok());
+        for (vm_id, ip) in &labeled {
+            inner.pre_release(vm_id, ip).await?;
+        }
+
+        for (vm_id, ip) in &labeled {
+            inner.log(|pool| JournalEntry::Release {
+                pool,
+                ip: ip.clone(),
+            })?;
+            if let Entry::Occupied(mut entry) = inner.vm_to_ip.entry(vm_id.clone()) {
+                entry.get_mut().retain(|_, held| held != ip);
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+            }
+            inner.allocated.remove(ip);
+            inner.free_ip(ip);
+            inner.record(PoolChange::Released);
+        }
+
+        Ok(labeled)
+    }
+
+    // The VM's primary address, or its first one if it has no primary
+    pub async fn get_allocation(&self, vm_id: &str) -> Result<IpAllocation, IpPoolError> {
+        let mut allocations = self.get_allocations(vm_id).await?;
+        let index = allocations
+            .iter()
+            .position(|a| a.interface.is_none() && a.purpose.as_deref() == Some(PRIMARY))
+            .unwrap_or(0);
+        Ok(allocations.swap_remove(index))
+    }
+
+    // Every address held by the VM, ordered by interface and purpose
+    pub async fn get_allocations(&self, vm_id: &str) -> Result<Vec<IpAllocation>, IpPoolError> {
+        let inner = self.inner.read().await;
+
+        let ips = inner
+            .vm_to_ip
+            .get(vm_id)
+            .map(|ips| ips.clone())
+            .ok_or(IpPoolError::IpNotFound)?;
+
+        Ok(ips
+            .into_iter()
+            .map(|(slot, ip)| inner.allocation(vm_id.to_string(), slot, ip))
+            .collect())
+    }
+
+    pub async fn list_allocations(&self) -> Vec<IpAllocation> {
+        let inner = self.inner.read().await;
+
+        let held: Vec<(String, BTreeMap<Slot, String>)> = inner
+            .vm_to_ip
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+
+        held.into_iter()
+            .flat_map(|(vm_id, ips)| {
+                ips.into_iter()
+                    .map(move |(slot, ip)| (vm_id.clone(), slot, ip))
+            })
+            .map(|(vm_id, slot, ip)| inner.allocation(vm_id, slot, ip))
+            .collect()
+    }
+
+    // Allocation and last renewal time of every allocated address
+    pub async fn activity(&self) -> BTreeMap<String, Activity> {
+        let inner = self.inner.read().await;
+        inner
+            .activity
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect()
+    }
+
+    // Allocations whose lease runs out within `within`, soonest first
+    pub async fn list_expiring(&self, within: Duration) -> Vec<IpAllocation> {
+        let now = self.inner.read().await.clock.unix_now();
+        let deadline = now.saturating_add(within.as_secs());
+
+        let mut expiring: Vec<IpAllocation> = self
+            .list_allocations()
+            .await
+            .into_iter()
+            .filter(|a| a.expires_at.is_some_and(|e| e <= deadline))
+            .collect();
+        expiring.sort_by_key(|a| (a.expires_at, a.ip.parse::<Ipv4Addr>().ok()));
+        expiring
+    }
+
+    // Well-known infrastructure addresses (gateway, DNS, ...) and addresses
+    // used by devices outside the pool, with labels
+    pub async fn list_reserved(&self) -> Vec<IpAllocation> {
+        let inner = self.inner.read().await;
+
+        let mut reserved: Vec<IpAllocation> = inner
+            .reserved
+            .iter()
+            .map(|(ip, label)| IpAllocation {
+                ip: ip.clone(),
+                vm_id: String::new(),
+                hostname: None,
+                mac: None,
+                reserved: true,
+                external: inner.external.contains(ip),
+                label: Some(label.clone()),
+                expires_at: None,
+                renew_before: None,
+                hard_expires_at: None,
+                purpose: None,
+                interface: None,
+                labels: BTreeMap::new(),
+                owner: None,
+                notes: None,
+                ticket: None,
+                pending_release_at: None,
+                fence_token: None,
+            })
+            .collect();
+        reserved.sort_by_key(|a| a.ip.parse::<Ipv4Addr>().ok());
+        reserved
+    }
+
+    // Mark an address as reserved infrastructure so it is never allocated
+    pub async fn reserve(&self, ip: &str, label: String) -> Result<(), IpPoolError> {
+        self.add_reservation(ip, label, false).await
+    }
+
+    // Keep an address squatted by a device outside this system (a printer,
+    // an appliance) from being allocated. It is listed as an external
+    // reservation with its description rather than as a made-up VM.
+    pub async fn mark_external(&self, ip: &str, description: String) -> Result<(), IpPoolError> {
+        self.add_reservation(ip, description, true).await
+    }
+
+    async fn add_reservation(
+        &self,
+        ip: &str,
+        label: String,
+        external: bool,
+    ) -> Result<(), IpPoolError> {
+        let mut inner = self.inner.write().await;
+
+        if !Self::is_valid_ip(&inner, ip) {
+            return Err(IpPoolError::InvalidIp);
+        }
+        if inner.allocated.contains_key(ip) {
+            return Err(IpPoolError::IpInUse);
+        }
+        inner.log(|pool| JournalEntry::Reserve {
+            pool,
+            ip: ip.to_string(),
+            label: label.clone(),
+            external,
+            resolves: false,
+        })?;
+
+        inner.set_reserved(ip, label, external);
+        inner.touch();
+
+        Ok(())
+    }
+
+    // Record an address handed out elsewhere (e.g. by the DHCP server this
+    // pool replaces) as the VM's primary address, with what it knew about
+    // it in `requested`. Hooks and the policy script are not consulted, the
+    // address is already in use.
+    pub async fn claim(
+        &self,
+        ip: &str,
+        vm_id: &str,
+        expires_at: Option<u64>,
+        requested: &Requested,
+    ) -> Result<(), IpPoolError> {
+        let inner = self.inner.write().await;
+        inner.claim(ip, vm_id, expires_at, requested, false)
+    }
+
+    // Replace the labels of an allocated address (empty clears them)
+    pub async fn label(
+        &self,
+        ip: &str,
+        labels: BTreeMap<String, String>,
+    ) -> Result<(), IpPoolError> {
+        let inner = self.read_timed().await;
+
+        if !inner.allocated.contains_key(ip) {
+            return Err(IpPoolError::IpNotFound);
+        }
+        if inner
+            .labels
+            .get(ip)
+            .map(|held| held.clone())
+            .unwrap_or_default()
+            == labels
+        {
+            return Ok(());
+        }
+        inner.log(|pool| JournalEntry::Label {
+            pool,
+            ip: ip.to_string(),
+            labels: labels.clone(),
+        })?;
+        inner.set_labels(ip, labels);
+        inner.touch();
+
+        Ok(())
+    }
+
+    // Set the hostname of an allocated address
+    pub async fn set_hostname(&self, ip: &str, hostname: String) -> Result<(), IpPoolError> {
+        let inner = self.read_timed().await;
+
+        if !inner.allocated.contains_key(ip) {
+            return Err(IpPoolError::IpNotFound);
+        }
+        if inner
+            .hostnames
+            .get(ip)
+            .is_some_and(|held| *held == hostname)
+        {
+            return Ok(());
+        }
+        inner.log(|pool| JournalEntry::Hostname {
+            pool,
+            ip: ip.to_string(),
+            hostname: hostname.clone(),
+        })?;
+        inner.hostnames.insert(ip.to_string(), hostname);
+        inner.touch();
+
+        Ok(())
+    }
+
+    // Record the MAC address of the interface holding an allocated address,
+    // in the "52:54:00:12:34:56" form
+    pub async fn set_mac(&self, ip: &str, mac: String) -> Result<(), IpPoolError> {
+        let inner = self.read_timed().await;
+
+        if !inner.allocated.contains_key(ip) {
+            return Err(IpPoolError::IpNotFound);
+        }
+        if inner.macs.get(ip).is_some_and(|held| *held == mac) {
+            return Ok(());
+        }
+        inner.log(|pool| JournalEntry::Mac {
+            pool,
+            ip: ip.to_string(),
+            mac: mac.clone(),
+        })?;
+        inner.macs.insert(ip.to_string(), mac);
+        inner.touch();
+
+        Ok(())
+    }
+
+    // Hard expiry of an allocated address, None if only its lease applies
+    pub async fn deadline(&self, ip: &str) -> Option<u64> {
+        let inner = self.inner.read().await;
+        inner.deadlines.get(ip).map(|at| *at)
+    }
+
+    // Cap an allocated address at `expires_at` (unix seconds): it is
+    // reclaimed then however often it is renewed. A cap can be brought
+    // forward but never pushed back; returns the one in force.
+    pub async fn set_deadline(&self, ip: &str, expires_at: u64) -> Result<u64, IpPoolError> {
+        let inner = self.read_timed().await;
+
+        if !inner.allocated.contains_key(ip) {
+            return Err(IpPoolError::IpNotFound);
+        }
+        if let Some(held) = inner.deadlines.get(ip).map(|at| *at)
+            && held <= expires_at
+        {
+            return Ok(held);
+        }
+        inner.log(|pool| JournalEntry::Deadline {
+            pool,
+            ip: ip.to_string(),
+            expires_at,
+        })?;
+        inner.deadlines.insert(ip.to_string(), expires_at);
+        inner.touch();
+
+        Ok(expires_at)
+    }
+
+    // Owner, notes and ticket of an allocated address, empty if it has none
+    pub async fn annotation(&self, ip: &str) -> Annotation {
+        let inner = self.inner.read().await;
+        (inner.annotations.get(ip))
+            .map(|annotation| annotation.clone())
+            .unwrap_or_default()
+    }
+
+    // Replace the owner, notes and ticket of an allocated address (empty
+    // clears them)
+    pub async fn annotate(&self, ip: &str, annotation: Annotation) -> Result<(), IpPoolError> {
+        let inner = self.read_timed().await;
+
+        if !inner.allocated.contains_key(ip) {
+            return Err(IpPoolError::IpNotFound);
+        }
+        if inner
+            .annotations
+            .get(ip)
+            .map(|held| held.clone())
+            .unwrap_or_default()
+            == annotation
+        {
+            return Ok(());
+        }
+        inner.log(|pool| JournalEntry::Annotate {
+            pool,
+            ip: ip.to_string(),
+            annotation: annotation.clone(),
+        })?;
+        inner.set_annotation(ip, annotation);
+        inner.touch();
+
+        Ok(())
+    }
+
+    // Hostname of an allocated address, given or from the pool's template
+    pub async fn hostname(&self, ip: &str) -> Option<String> {
+        let inner = self.inner.read().await;
+        inner.hostnames.get(ip).map(|hostname| hostname.clone())
+    }
+
+    // Allocated addresses per value of label `key`, most used first;
+    // allocations without the label are counted under None
+    pub async fn group_stats(&self, key: &str) -> Vec<(Option<String>, usize)> {
+        let inner = self.inner.read().await;
+
+        let mut groups: BTreeMap<Option<String>, usize> = BTreeMap::new();
+        for entry in inner.allocated.iter() {
+            let value = inner
+                .labels
+                .get(entry.key())
+                .and_then(|labels| labels.get(key).cloned());
+            *groups.entry(value).or_default() += 1;
+        }
+        let mut groups: Vec<(Option<String>, usize)> = groups.into_iter().collect();
+        groups.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        groups
+    }
+
+    // Flag an address as used by someone else so it is not handed out,
+    // returning the VM currently holding it; repeated reports replace the
+    // earlier one
+    pub async fn flag_conflict(
+        &self,
+        ip: &str,
+        conflict: Conflict,
+    ) -> Result<Option<String>, IpPoolError> {
+        let mut inner = self.inner.write().await;
+
+        if !ip
+            .parse::<Ipv4Addr>()
+            .is_ok_and(|addr| inner.contains(u32::from(addr)))
+        {
+            return Err(IpPoolError::InvalidIp);
+        }
+        inner.log(|pool| JournalEntry::Conflict {
+            pool,
+            ip: ip.to_string(),
+            conflict: Some(conflict.clone()),
+        })?;
+        inner.flag(ip, conflict);
+        inner.touch();
+
+        Ok(inner.allocated.get(ip).map(|vm_id| vm_id.clone()))
+    }
+
+    // Drop the flag, handing the address out again if it is free
+    pub async fn clear_conflict(&self, ip: &str) -> Result<Conflict, IpPoolError> {
+        let mut inner = self.inner.write().await;
+
+        let conflict = (inner.conflicts.get(ip).cloned()).ok_or(IpPoolError::ConflictNotFound)?;
+        inner.log(|pool| JournalEntry::Conflict {
+            pool,
+            ip: ip.to_string(),
+            conflict: None,
+        })?;
+        inner.unflag(ip);
+        inner.touch();
+
+        Ok(conflict)
+    }
+
+    // Turn a flagged address into a permanent reservation
+    pub async fn exclude_conflict(&self, ip: &str, label: String) -> Result<(), IpPoolError> {
+        self.exclude(ip, label, false).await
+    }
+
+    // Turn a flagged address into an external reservation: the host
+    // answering on it is a device outside the pool
+    pub async fn adopt_external(&self, ip: &str, description: String) -> Result<(), IpPoolError> {
+        self.exclude(ip, description, true).await
+    }
+
+    async fn exclude(&self, ip: &str, label: String, external: bool) -> Result<(), IpPoolError> {
+        let mut inner = self.inner.write().await;
+
+        if !inner.conflicts.contains_key(ip) {
+            return Err(IpPoolError::ConflictNotFound);
+        }
+        if inner.allocated.contains_key(ip) {
+            return Err(IpPoolError::IpInUse);
+        }
+        inner.log(|pool| JournalEntry::Reserve {
+            pool,
+            ip: ip.to_string(),
+            label: label.clone(),
+            external,
+            resolves: true,
+        })?;
+        inner.conflicts.remove(ip);
+        inner.set_reserved(ip, label, external);
+        inner.touch();
+
+        Ok(())
+    }
+
+    // Turn a flagged free address into the VM's primary address, for a
+    // host set up by hand that belongs in the pool. The lease never
+    // expires.
+    pub async fn adopt_static(&self, ip: &str, vm_id: &str) -> Result<(), IpPoolError> {
+        let mut inner = self.inner.write().await;
+
+        if !inner.conflicts.contains_key(ip) {
+            return Err(IpPoolError::ConflictNotFound);
+        }
+        inner.claim(ip, vm_id, None, &Requested::default(), true)?;
+        inner.conflicts.remove(ip);
+        inner.touch();
+
+        Ok(())
+    }
+
+    // Keep a flagged address out of allocation while someone looks into
+    // it, noting who and why
+    pub async fn investigate_conflict(&self, ip: &str, note: String) -> Result<(), IpPoolError> {
+        let mut inner = self.inner.write().await;
+
+        let mut conflict =
+            (inner.conflicts.get(ip).cloned()).ok_or(IpPoolError::ConflictNotFound)?;
+        conflict.investigation = Some(note);
+        inner.log(|pool| JournalEntry::Conflict {
+            pool,
+            ip: ip.to_string(),
+            conflict: Some(conflict.clone()),
+        })?;
+        inner.conflicts.insert(ip.to_string(), conflict);
+        inner.touch();
+
+        Ok(())
+    }
+
+    // Flagged addresses with the VM holding each, if any, in address order
+    pub async fn list_conflicts(&self) -> Vec<(String, Conflict, Option<String>)> {
+        let inner = self.inner.read().await;
+
+        let mut conflicts: Vec<(String, Conflict, Option<String>)> = inner
+            .conflicts
+            .iter()
+            .map(|(ip, conflict)| {
+                let holder = inner.allocated.get(ip).map(|vm_id| vm_id.clone());
+                (ip.clone(), conflict.clone(), holder)
+            })
+            .collect();
+        conflicts.sort_by_key(|(ip, _, _)| ip.parse::<Ipv4Addr>().ok());
+        conflicts
+    }
+
+    pub async fn get_stats(&self) -> serde_json::Value {
+        let inner = self.inner.read().await;
+
+        let total = inner.capacity();
+        let allocated = inner.allocated.len();
+        let available = inner.free.count();
+        let usage = (allocated as f64 / total as f64) * 100.0;
+
+        let mut stats = serde_json::json!({
+            "network": inner.cidr(),
+            "gateway": inner.gateway,
+            "total": total,
+            "allocated": allocated,
+            "available": available,
+            "reserved": inner.reserved.len(),
+            "pending_release": inner.pending.len(),
+            "usage": usage,
+            "frozen": inner.frozen,
+        });
+        if let Some(reachable) = inner.gateway_reachable {
+            stats["gateway_reachable"] = reachable.into();
+        }
+        if let Some(thresholds) = inner.thresholds {
+            stats["thresholds"] = serde_json::json!(thresholds);
+        }
+        if !inner.external.is_empty() {
+            stats["external"] = inner.external.len().into();
+        }
+        if let Some(vlan_id) = inner.vlan_id {
+            stats["vlan_id"] = vlan_id.into();
+        }
+        if let Some(slaac) = &inner.slaac {
+            stats["ipv6_prefix"] = slaac.prefix().into();
+        }
+        if !inner.delegations.is_empty() {
+            stats["delegations"] = serde_json::json!(
+                inner
+                    .delegations
+                    .iter()
+                    .map(|(team, delegation)| inner.delegation_usage(team, delegation))
+                    .collect::<Vec<_>>()
+            );
+        }
+        stats
+    }
+
+    // Carve `start..=end` out of the pool for `team`. Addresses in it that
+    // are already allocated stay with their VMs.
+    pub async fn delegate(
+        &self,
+        team: &str,
+        start: Ipv4Addr,
+        end: Ipv4Addr,
+        key_hash: String,
+    ) -> Result<Delegation, IpPoolError> {
+        let mut inner = self.inner.write().await;
+
+        let (from, to) = (u32::from(start), u32::from(end));
+        if team.is_empty()
+            || from > to
+            || from < inner.start
+            || to > inner.end
+            || inner.delegations.contains_key(team)
+            || inner
+                .delegated_ranges()
+                .iter()
+                .any(|(start, end)| from <= *end && *start <= to)
+        {
+            return Err(IpPoolError::InvalidDelegation);
+        }
+
+        let delegation = Delegation {
+            start,
+            end,
+            key_hash,
+            created_at: inner.clock.unix_now(),
+        };
+        inner
+            .delegations
+            .insert(team.to_string(), delegation.clone());
+        inner.touch();
+        inner.log_state();
+        Ok(delegation)
+    }
+
+    // Hand a delegation back to the pool
+    pub async fn revoke_delegation(&self, team: &str) -> Result<Delegation, IpPoolError> {
+        let mut inner = self.inner.write().await;
+
+        let delegation = inner
+            .delegations
+            .remove(team)
+            .ok_or(IpPoolError::DelegationNotFound)?;
+        inner.touch();
+        inner.log_state();
+        Ok(delegation)
+    }
+
+    // Team whose API key hashes to `key_hash`
+    pub async fn delegated_team(&self, key_hash: &str) -> Option<String> {
+        let inner = self.inner.read().await;
+        inner
+            .delegations
+            .iter()
+            .find(|(_, delegation)| delegation.key_hash == key_hash)
+            .map(|(team, _)| team.clone())
+    }
+
+    pub async fn delegations(&self) -> Vec<DelegationUsage> {
+        let inner = self.inner.read().await;
+        inner
+            .delegations
+            .iter()
+            .map(|(team, delegation)| inner.delegation_usage(team, delegation))
+            .collect()
+    }
+
+    // Delegate an IPv6 prefix to `vm_id`: the one it already holds, or the
+    // first of `candidates` nobody holds
+    pub async fn delegate_prefix6(
+        &self,
+        vm_id: &str,
+        mut candidates: impl Iterator<Item = String>,
+    ) -> Result<String, IpPoolError> {
+        let mut inner = self.inner.write().await;
+
+        if let Some(prefix) = inner.prefix6_of(vm_id) {
+            return Ok(prefix);
+        }
+        let prefix = candidates
+            .find(|prefix| !inner.prefixes6.contains_key(prefix))
+            .ok_or(IpPoolError::NoAvailableIps)?;
+        inner.log(|pool| JournalEntry::Prefix6 {
+            pool,
+            prefix: prefix.clone(),
+            vm_id: Some(vm_id.to_string()),
+        })?;
+        inner.prefixes6.insert(prefix.clone(), vm_id.to_string());
+        inner.touch();
+        Ok(prefix)
+    }
+
+    // Hand back the IPv6 prefix delegated to `vm_id`
+    pub async fn release_prefix6(&self, vm_id: &str) -> Result<String, IpPoolError> {
+        let mut inner = self.inner.write().await;
+
+        let prefix = inner.prefix6_of(vm_id).ok_or(IpPoolError::IpNotFound)?;
+        inner.log(|pool| JournalEntry::Prefix6 {
+            pool,
+            prefix: prefix.clone(),
+            vm_id: None,
+        })?;
+        inner.prefixes6.remove(&prefix);
+        inner.touch();
+        Ok(prefix)
+    }
+
+    // Delegated IPv6 prefix -> VM_ID
+    pub async fn prefixes6(&self) -> BTreeMap<String, String> {
+        self.inner.read().await.prefixes6.clone()
+    }
+
+    // Give a WireGuard peer its tunnel address: the one it holds, or the
+    // first of `candidates` no peer holds. A `name` replaces the peer's.
+    pub async fn add_wireguard_peer(
+        &self,
+        public_key: &str,
+        name: Option<String>,
+        mut candidates: impl Iterator<Item = String>,
+    ) -> Result<Tunnel, IpPoolError> {
+        let mut inner = self.inner.write().await;
+
+        let held = inner.wireguard.get(public_key);
+        let tunnel = match held {
+            Some(tunnel) => Tunnel {
+                address: tunnel.address.clone(),
+                name: name.or_else(|| tunnel.name.clone()),
+            },
+            None => {
+                let taken: HashSet<&String> = inner
+                    .wireguard
+                    .values()
+                    .map(|tunnel| &tunnel.address)
+                    .collect();
+                let address = candidates
+                    .find(|address| !taken.contains(address))
+                    .ok_or(IpPoolError::NoAvailableIps)?;
+                Tunnel { address, name }
+            }
+        };
+        if held != Some(&tunnel) {
+            inner.log(|pool| JournalEntry::WireGuard {
+                pool,
+                public_key: public_key.to_string(),
+                tunnel: Some(tunnel.clone()),
+            })?;
+            inner
+                .wireguard
+                .insert(public_key.to_string(), tunnel.clone());
+            inner.touch();
+        }
+        Ok(tunnel)
+    }
+
+    pub async fn remove_wireguard_peer(&self, public_key: &str) -> Result<Tunnel, IpPoolError> {
+        let mut inner = self.inner.write().await;
+
+        let tunnel = (inner.wireguard.get(public_key).cloned()).ok_or(IpPoolError::IpNotFound)?;
+        inner.log(|pool| JournalEntry::WireGuard {
+            pool,
+            public_key: public_key.to_string(),
+            tunnel: None,
+        })?;
+        inner.wireguard.remove(public_key);
+        inner.touch();
+        Ok(tunnel)
+    }
+
+    // WireGuard public key -> tunnel address
+    pub async fn wireguard_peers(&self) -> BTreeMap<String, Tunnel> {
+        self.inner.read().await.wireguard.clone()
+    }
+
+    // Free/used counts for each /`prefix` block of the pool network
+    pub async fn get_range_stats(
+        &self,
+        prefix: Option<u8>,
+    ) -> Result<serde_json::Value, IpPoolError> {
+        let inner = self.inner.read().await;
+
+        let prefix = prefix.unwrap_or(DEFAULT_RANGE_PREFIX.max(inner.prefix_len));
+        if prefix < inner.prefix_len
+            || prefix > 32
+            || prefix - inner.prefix_len > MAX_RANGE_SPLIT_BITS
+        {
+            return Err(IpPoolError::InvalidRange);
+        }
+
+        let base = u64::from(u32::from(inner.network));
+        let size = 1u64 << (32 - prefix);
+        let blocks = 1usize << (prefix - inner.prefix_len);
+        let block_of = |ip: &str| {
+            let addr = u32::from(ip.parse::<Ipv4Addr>().ok()?);
+            (inner.start..=inner.end)
+                .contains(&addr)
+                .then(|| ((u64::from(addr) - base) / size) as usize)
+        };
+
+        let mut allocated = vec![0usize; blocks];
+        let mut reserved = vec![0usize; blocks];
+        for entry in inner.allocated.iter() {
+            if let Some(block) = block_of(entry.key()) {
+                allocated[block] += 1;
+            }
+        }
+        for ip in inner.reserved.keys() {
+            if let Some(block) = block_of(ip) {
+                reserved[block] += 1;
+            }
+        }
+
+        let ranges: Vec<_> = (0..blocks)
+            .map(|block| {
+                let first = base + block as u64 * size;
+                let lo = first.max(u64::from(inner.start));
+                let hi = (first + size - 1).min(u64::from(inner.end));
+                let total = (hi + 1).saturating_sub(lo) as usize - reserved[block];
+                let usage = if total == 0 {
+                    0.0
+                } else {
+                    allocated[block] as f64 / total as f64 * 100.0
+                };
+                serde_json::json!({
+                    "range": format!("{}/{}", Ipv4Addr::from(first as u32), prefix),
+                    "total": total,
+                    "allocated": allocated[block],
+                    "available": total.saturating_sub(allocated[block]),
+                    "reserved": reserved[block],
+                    "usage": usage,
+                })
+            })
+            .collect();
+
+        Ok(serde_json::json!({
+            "network": inner.cidr(),
+            "prefix": prefix,
+            "ranges": ranges,
+        }))
+    }
+
+    // Estimate time-to-exhaustion from the net allocation rate over `window`
+    pub async fn get_forecast(&self, window: Duration) -> serde_json::Value {
+        let inner = self.inner.read().await;
+
+        let (allocations, releases) = inner.changes_within(window);
+        let available = inner.free.count();
+        let window_secs = window.as_secs_f64().max(1.0);
+        let net_per_hour = (allocations as f64 - releases as f64) / window_secs * 3600.0;
+
+        // Only a positive net consumption rate leads to exhaustion
+        let seconds_to_exhaustion = if net_per_hour > 0.0 {
+            Some((available as f64 / net_per_hour * 3600.0).round() as u64)
+        } else {
+            None
+        };
+
+        serde_json::json!({
+            "window_seconds": window.as_secs(),
+            "allocations_in_window": allocations,
+            "releases_in_window": releases,
+            "net_rate_per_hour": net_per_hour,
+            "remaining_allocations": available,
+            "seconds_to_exhaustion": seconds_to_exhaustion,
+        })
+    }
+
+    // Burn rate over each of BURN_RATE_WINDOWS, by window name
+    pub async fn burn_rates(&self) -> Vec<(&'static str, f64)> {
+        let inner = self.inner.read().await;
+        BURN_RATE_WINDOWS
+            .iter()
+            .map(|(name, window)| (*name, inner.burn_rate(*window)))
+            .collect()
+    }
+
+    // Allocated address count `age` ago for each of `ages`, reconstructed
+    // from the recorded history. Before the oldest recorded change the
+    // count is assumed flat.
+    pub async fn allocated_at(&self, ages: &[Duration]) -> Vec<usize> {
+        let inner = self.inner.read().await;
+
+        let now = inner.clock.now();
+        let current = inner.allocated.len() as i64;
+        let history = inner.history.lock().unwrap();
+        ages.iter()
+            .map(|age| {
+                let delta: i64 = history
+                    .iter()
+                    .rev()
+                    .take_while(|(at, _)| now.duration_since(*at) < *age)
+                    .map(|(_, change)| match change {
+                        PoolChange::Allocated => 1,
+                        PoolChange::Released => -1,
+                    })
+                    .sum();
+                (current - delta).max(0) as usize
+            })
+            .collect()
+    }
+
+    // Suggested wait before retrying on an exhausted pool: when the next
+    // release is due if releases keep their recent average spacing
+    pub async fn retry_after(&self) -> Duration {
+        let inner = self.inner.read().await;
+
+        let now = inner.clock.now();
+        let history = inner.history.lock().unwrap();
+        let releases: Vec<Instant> = history
+            .iter()
+            .rev()
+            .take_while(|(at, _)| now.duration_since(*at) <= RETRY_AFTER_WINDOW)
+            .filter(|(_, change)| *change == PoolChange::Released)
+            .map(|(at, _)| *at)
+            .collect();
+
+        let Some(last) = releases.first() else {
+            return RETRY_AFTER_DEFAULT;
+        };
+        let gap = RETRY_AFTER_WINDOW / releases.len() as u32;
+        gap.saturating_sub(now.duration_since(*last))
+            .clamp(RETRY_AFTER_MIN, RETRY_AFTER_MAX)
+    }
+
+    pub async fn set_frozen(&self, frozen: bool) {
+        let mut inner = self.inner.write().await;
+        if let Err(e) = inner.log(|pool| JournalEntry::Freeze { pool, frozen }) {
+            tracing::error!("Failed to journal freeze state: {}", e);
+        }
+        inner.frozen = frozen;
+        inner.touch();
+    }
+
+    // Utilization thresholds of this pool for the monitoring check
+    pub async fn set_thresholds(&self, thresholds: Option<Thresholds>) {
+        let mut inner = self.inner.write().await;
+        inner.thresholds = thresholds;
+        inner.touch();
+        inner.log_state();
+    }
+
+    pub async fn thresholds(&self) -> Option<Thresholds> {
+        let inner = self.inner.read().await;
+        inner.thresholds
+    }
+
+    // VLAN the pool's network is on, reported with allocations
+    pub async fn set_vlan_id(&self, vlan_id: Option<u16>) {
+        let mut inner = self.inner.write().await;
+        if inner.vlan_id == vlan_id {
+            return;
+        }
+        inner.vlan_id = vlan_id;
+        inner.touch();
+        inner.log_state();
+    }
+
+    pub async fn vlan_id(&self) -> Option<u16> {
+        let inner = self.inner.read().await;
+        inner.vlan_id
+    }
+
+    pub async fn prefix_len(&self) -> u8 {
+        let inner = self.inner.read().await;
+        inner.prefix_len
+    }
+
+    pub async fn gateway(&self) -> String {
+        let inner = self.inner.read().await;
+        inner.gateway.clone()
+    }
+
+    // Outcome of the gateway self-check, reported in stats
+    pub async fn set_gateway_reachable(&self, reachable: bool) {
+        self.inner.write().await.gateway_reachable = Some(reachable);
+    }
+
+    // Monotonic change counter, bumped by every mutation
+    pub async fn version(&self) -> u64 {
+        let inner = self.inner.read().await;
+        inner.version.load(Ordering::Relaxed)
+    }
+
+    // Exclusive so the snapshot is a consistent cut: every journaled
+    // allocation is either fully applied or not started
+    pub async fn snapshot(&self) -> PoolSnapshot {
+        let inner = self.inner.write().await;
+        inner.to_snapshot()
+    }
+
+    // Keep every writer out until the lock is dropped
+    pub async fn lock(&self) -> PoolLock<'_> {
+        PoolLock {
+            inner: self.inner.write().await,
+        }
+    }
+
+    pub fn from_snapshot(snapshot: PoolSnapshot) -> Result<Self, IpPoolError> {
+        let pool = Self::with_range(
+            snapshot.network,
+            snapshot.prefix_len,
+            snapshot.gateway.clone(),
+            u32::from(snapshot.start),
+            u32::from(snapshot.end),
+        )?;
+        pool.inner
+            .try_write()
+            .expect("new pool is not shared")
+            .apply_snapshot(snapshot)?;
+        Ok(pool)
+    }
+
+    // Replace this pool's state in place, keeping existing handles valid
+    pub async fn restore(&self, snapshot: PoolSnapshot) -> Result<(), IpPoolError> {
+        let mut inner = self.inner.write().await;
+        inner.apply_snapshot(snapshot)?;
+        inner.log_state();
+        Ok(())
+    }
+
+    // Journal future mutations of this pool under `name`
+    pub async fn attach_journal(&self, name: String, journal: Arc<Journal>) {
+        let mut inner = self.inner.write().await;
+        inner.journal = Some((name, journal));
+    }
+
+    pub async fn attach_breaker(&self, breaker: CircuitBreaker) {
+        let mut inner = self.inner.write().await;
+        inner.breaker = Some(breaker);
+    }
+
+    pub async fn attach_policy(&self, name: String, policy: Arc<ScriptPolicy>) {
+        let mut inner = self.inner.write().await;
+        inner.policy = Some((name, policy));
+    }
+
+    // Write the current state to the attached journal, if any
+    pub async fn journal_state(&self) {
+        let inner = self.inner.read().await;
+        inner.log_state();
+    }
+
+    // Re-apply a journaled mutation during recovery. Entries carry the
+    // outcome, so no allocation decisions are made here.
+    pub async fn apply_journal(&self, entry: JournalEntry) {
+        let mut inner = self.inner.write().await;
+
+        match entry {
+            JournalEntry::Allocate {
+                ip,
+                vm_id,
+                purpose,
+                interface,
+                expires_at,
+                fence,
+                hostname,
+                details,
+                resolves,
+                ..
+            } => {
+                let slot = Slot::new(interface, purpose);
+                let old_ip = inner
+                    .vm_to_ip
+                    .get_mut(&vm_id)
+                    .and_then(|mut ips| ips.remove(&slot));
+                if let Some(old_ip) = old_ip {
+                    inner.allocated.remove(&old_ip);
+                    inner.free_ip(&old_ip);
+                }
+                if let Some((_, old_vm)) = inner.allocated.remove(&ip) {
+                    inner.unbind(&old_vm, &ip);
+                    // Labels, the hostname, the MAC, notes, a hard expiry
+                    // and a deferred release belong to the holder, a new
+                    // one starts without
+                    if old_vm != vm_id {
+                        inner.labels.remove(&ip);
+                        inner.hostnames.remove(&ip);
+                        inner.macs.remove(&ip);
+                        inner.annotations.remove(&ip);
+                        inner.deadlines.remove(&ip);
+                        inner.pending.remove(&ip);
+                        inner.activity.remove(&ip);
+                    }
+                }
+                // Entries from before fencing tokens were journaled get one now
+                let now = inner.clock.unix_now();
+                let fence = match fence {
+                    Some(fence) => {
+                        inner.fence.fetch_max(fence, Ordering::Relaxed);
+                        fence
+                    }
+                    None => inner.fence_of(&ip).unwrap_or_else(|| inner.next_fence()),
+                };
+                inner
+                    .activity
+                    .entry(ip.clone())
+                    .or_insert_with(|| Activity::new(now, fence))
+                    .fence = fence;
+                if let Ok(addr) = ip.parse::<Ipv4Addr>() {
+                    inner.free.remove(u32::from(addr));
+                }
+                inner.set_expiry(&ip, expires_at);
+                if let Some(hostname) = hostname {
+                    inner.hostnames.insert(ip.clone(), hostname);
+                }
+                inner.apply_details(&ip, details);
+                inner
+                    .vm_to_ip
+                    .entry(vm_id.clone())
+                    .or_default()
+                    .insert(slot, ip.clone());
+                if resolves {
+                    inner.conflicts.remove(&ip);
+                }
+                inner.allocated.insert(ip, vm_id);
+                inner.record(PoolChange::Allocated);
+            }
+            JournalEntry::Release { ip, .. } => {
+                if let Some((_, vm_id)) = inner.allocated.remove(&ip) {
+                    inner.unbind(&vm_id, &ip);
+                    inner.free_ip(&ip);
+                    inner.record(PoolChange::Released);
+                }
+            }
+            JournalEntry::Reserve {
+                ip,
+                label,
+                external,
+                resolves,
+                ..
+            } => {
+                if resolves {
+                    inner.conflicts.remove(&ip);
+                }
+                inner.set_reserved(&ip, label, external);
+                inner.touch();
+            }
+            JournalEntry::Prefix6 { prefix, vm_id, .. } => {
+                match vm_id {
+                    Some(vm_id) => inner.prefixes6.insert(prefix, vm_id),
+                    None => inner.prefixes6.remove(&prefix),
+                };
+                inner.touch();
+            }
+            JournalEntry::WireGuard {
+                public_key, tunnel, ..
+            } => {
+                match tunnel {
+                    Some(tunnel) => inner.wireguard.insert(public_key, tunnel),
+                    None => inner.wireguard.remove(&public_key),
+                };
+                inner.touch();
+            }
+            JournalEntry::Conflict { ip, conflict, .. } => {
+                match conflict {
+                    Some(conflict) => inner.flag(&ip, conflict),
+                    None => inner.unflag(&ip),
+                }
+                inner.touch();
+            }
+            JournalEntry::Label { ip, labels, .. } => {
+                if inner.allocated.contains_key(&ip) {
+                    inner.set_labels(&ip, labels);
+                    inner.touch();
+                }
+            }
+            JournalEntry::Hostname { ip, hostname, .. } => {
+                if inner.allocated.contains_key(&ip) {
+                    inner.hostnames.insert(ip, hostname);
+                    inner.touch();
+                }
+            }
+            JournalEntry::Mac { ip, mac, .. } => {
+                if inner.allocated.contains_key(&ip) {
+                    inner.macs.insert(ip, mac);
+                    inner.touch();
+                }
+            }
+            JournalEntry::Annotate { ip, annotation, .. } => {
+                if inner.allocated.contains_key(&ip) {
+                    inner.set_annotation(&ip, annotation);
+                    inner.touch();
+                }
+            }
+            JournalEntry::Deadline { ip, expires_at, .. } => {
+                if inner.allocated.contains_key(&ip) {
+                    inner.deadlines.insert(ip, expires_at);
+                    inner.touch();
+                }
+            }
+            JournalEntry::PendingRelease { ip, release_at, .. } => {
+                match release_at {
+                    Some(release_at) if inner.allocated.contains_key(&ip) => {
+                        inner.pending.insert(ip, release_at);
+                    }
+                    _ => {
+                        inner.pending.remove(&ip);
+                    }
+                }
+                inner.touch();
+            }
+            JournalEntry::Freeze { frozen, .. } => {
+                inner.frozen = frozen;
+                inner.touch();
+            }
+            JournalEntry::Replace { pool, snapshot } => {
+                if let Err(e) = inner.apply_snapshot(*snapshot) {
+                    tracing::warn!("Skipping snapshot of pool {}: {}", pool, e);
+                }
+            }
+            JournalEntry::Remove { .. } => {}
+        }
+    }
+
+    // Split the pool at `at`: this pool keeps the addresses below it and the
+    // returned pool takes `at` and everything above, along with their
+    // allocations. Both halves stay in the same network.
+    pub async fn split(&self, at: Ipv4Addr) -> Result<IpPool, IpPoolError> {
+        let mut inner = self.inner.write().await;
+
+        let at = u32::from(at);
+        if at <= inner.start || at > inner.end {
+            return Err(IpPoolError::InvalidSplit);
+        }
+        // Delegations go to one half as a whole
+        if inner
+            .delegations
+            .values()
+            .any(|delegation| u32::from(delegation.start) < at && at <= u32::from(delegation.end))
+        {
+            return Err(IpPoolError::InvalidSplit);
+        }
+
+        let mut upper = IpPoolInner::new(
+            inner.network,
+            inner.prefix_len,
+            inner.gateway.clone(),
+            at,
+            inner.end,
+        )?;
+        upper.frozen = inner.frozen;
+        upper.routes = inner.routes.clone();
+        upper.dhcp_options = inner.dhcp_options.clone();
+        upper.lease_ttl = inner.lease_ttl;
+        upper.hostname_template = inner.hostname_template.clone();
+        upper.expiry_warning = inner.expiry_warning;
+        upper.thresholds = inner.thresholds;
+        upper.vlan_id = inner.vlan_id;
+        upper.slaac = inner.slaac.clone();
+        upper.clock = inner.clock.clone();
+        upper.hooks = inner.hooks.clone();
+        upper.fence = AtomicU64::new(inner.fence.load(Ordering::Relaxed));
+
+        let in_upper = |ip: &str| {
+            ip.parse::<Ipv4Addr>()
+                .map(|ip| u32::from(ip) >= at)
+                .unwrap_or(false)
+        };
+
+        let moved: Vec<String> = inner
+            .allocated
+            .iter()
+            .map(|entry| entry.key().clone())
+            .filter(|ip| in_upper(ip))
+            .collect();
+        for ip in moved {
+            let (_, vm_id) = inner.allocated.remove(&ip).unwrap();
+            let slot = inner.slot_of(&vm_id, &ip);
+            inner.unbind(&vm_id, &ip);
+            if let Some((_, expires_at)) = inner.expires.remove(&ip) {
+                upper.expires.insert(ip.clone(), expires_at);
+            }
+            if let Some((_, labels)) = inner.labels.remove(&ip) {
+                upper.labels.insert(ip.clone(), labels);
+            }
+            if let Some((_, hostname)) = inner.hostnames.remove(&ip) {
+                upper.hostnames.insert(ip.clone(), hostname);
+            }
+            if let Some((_, mac)) = inner.macs.remove(&ip) {
+                upper.macs.insert(ip.clone(), mac);
+            }
+            if let Some((_, annotation)) = inner.annotations.remove(&ip) {
+                upper.annotations.insert(ip.clone(), annotation);
+            }
+            if let Some((_, expires_at)) = inner.deadlines.remove(&ip) {
+                upper.deadlines.insert(ip.clone(), expires_at);
+            }
+            if let Some((_, release_at)) = inner.pending.remove(&ip) {
+                upper.pending.insert(ip.clone(), release_at);
+            }
+            if let Some((_, activity)) = inner.activity.remove(&ip) {
+                upper.activity.insert(ip.clone(), activity);
+            }
+            upper
+                .vm_to_ip
+                .entry(vm_id.clone())
+                .or_default()
+                .insert(slot, ip.clone());
+            upper.allocated.insert(ip, vm_id);
+        }
+        // Reserved addresses follow their half, both halves share the gateway
+        upper.reserved = inner
+            .reserved
+            .iter()
+            .filter(|(ip, _)| in_upper(ip) || **ip == inner.gateway)
+            .map(|(ip, label)| (ip.clone(), label.clone()))
+            .collect();
+        let gateway = inner.gateway.clone();
+        inner
+            .reserved
+            .retain(|ip, _| !in_upper(ip) || *ip == gateway);
+        let (upper_external, external) = std::mem::take(&mut inner.external)
+            .into_iter()
+            .partition(|ip| in_upper(ip));
+        inner.external = external;
+        upper.external = upper_external;
+        let (upper_conflicts, conflicts) = std::mem::take(&mut inner.conflicts)
+            .into_iter()
+            .partition(|(ip, _)| in_upper(ip));
+        inner.conflicts = conflicts;
+        upper.conflicts = upper_conflicts;
+        let (upper_delegations, delegations) = std::mem::take(&mut inner.delegations)
+            .into_iter()
+            .partition(|(_, delegation)| u32::from(delegation.start) >= at);
+        inner.delegations = delegations;
+        upper.delegations = upper_delegations;
+
+        inner.end = at - 1;
+        inner.rebuild_available();
+        inner.touch();
+        inner.log_state();
+        upper.rebuild_available();
+
+        Ok(IpPool {
+            inner: Arc::new(RwLock::new(upper)),
+            lock_wait: Arc::default(),
+        })
+    }
+
+    // Absorb `other` into this pool. Pools merge when their ranges touch
+    // inside the same network, or when their networks are the two halves of
+    // one supernet (e.g. two /24s forming a /23).
+    pub async fn merge(&self, other: &IpPool) -> Result<(), IpPoolError> {
+        if Arc::ptr_eq(&self.inner, &other.inner) {
+            return Err(IpPoolError::InvalidMerge);
+        }
+
+        let mut inner = self.inner.write().await;
+        let mut other_inner = other.inner.write().await;
+
+        let adjacent_ranges = inner.end.checked_add(1) == Some(other_inner.start)
+            || other_inner.end.checked_add(1) == Some(inner.start);
+
+        if let (Some(vlan_id), Some(other_vlan_id)) = (inner.vlan_id, other_inner.vlan_id)
+            && vlan_id != other_vlan_id
+        {
+            return Err(IpPoolError::InvalidMerge);
+        }
+
+        if inner.network == other_inner.network && inner.prefix_len == other_inner.prefix_len {
+            if !adjacent_ranges {
+                return Err(IpPoolError::InvalidMerge);
+            }
+        } else {
+            // Sibling networks: same size, same parent prefix
+            if inner.prefix_len != other_inner.prefix_len || inner.prefix_len == 0 {
+                return Err(IpPoolError::InvalidMerge);
+            }
+            let parent_len = inner.prefix_len - 1;
+            let parent_mask = prefix_mask(parent_len);
+            let network = u32::from(inner.network) & parent_mask;
+            if network != u32::from(other_inner.network) & parent_mask {
+                return Err(IpPoolError::InvalidMerge);
+            }
+            inner.network = Ipv4Addr::from(network);
+            inner.prefix_len = parent_len;
+        }
+
+        // A VM can only hold one address per slot in a pool, and a team one
+        // delegation
+        if other_inner.vm_to_ip.iter().any(|entry| {
+            inner
+                .vm_to_ip
+                .get(entry.key())
+                .is_some_and(|ips| entry.value().keys().any(|slot| ips.contains_key(slot)))
+        }) || other_inner
+            .delegations
+            .keys()
+            .any(|team| inner.delegations.contains_key(team))
+        {
+            return Err(IpPoolError::InvalidMerge);
+        }
+
+        inner.start = inner.start.min(other_inner.start);
+        inner.end = inner.end.max(other_inner.end);
+        inner.frozen |= other_inner.frozen;
+        inner.thresholds = inner.thresholds.or(other_inner.thresholds);
+        inner.vlan_id = inner.vlan_id.or(other_inner.vlan_id);
+        inner.slaac = inner.slaac.take().or_else(|| other_inner.slaac.clone());
+        for route in std::mem::take(&mut other_inner.routes) {
+            if !inner.routes.contains(&route) {
+                inner.routes.push(route);
+            }
+        }
+        if inner.dhcp_options.is_empty() {
+            inner.dhcp_options = std::mem::take(&mut other_inner.dhcp_options);
+        }
+
+        // Reserved addresses (including the other gateway) stay reserved
+        let reserved: Vec<(String, String)> = other_inner.reserved.drain().collect();
+        inner.reserved.extend(reserved);
+        inner.external.extend(other_inner.external.drain());
+        let conflicts: Vec<(String, Conflict)> = other_inner.conflicts.drain().collect();
+        inner.conflicts.extend(conflicts);
+        for (vm_id, ips) in std::mem::take(&mut other_inner.vm_to_ip) {
+            inner.vm_to_ip.entry(vm_id).or_default().extend(ips);
+        }
+        for (ip, vm_id) in std::mem::take(&mut other_inner.allocated) {
+            inner.allocated.insert(ip, vm_id);
+        }
+        for (ip, expires_at) in std::mem::take(&mut other_inner.expires) {
+            inner.expires.insert(ip, expires_at);
+        }
+        for (ip, labels) in std::mem::take(&mut other_inner.labels) {
+            inner.labels.insert(ip, labels);
+        }
+        for (ip, hostname) in std::mem::take(&mut other_inner.hostnames) {
+            inner.hostnames.insert(ip, hostname);
+        }
+        for (ip, mac) in std::mem::take(&mut other_inner.macs) {
+            inner.macs.insert(ip, mac);
+        }
+        for (ip, annotation) in std::mem::take(&mut other_inner.annotations) {
+            inner.annotations.insert(ip, annotation);
+        }
+        for (ip, expires_at) in std::mem::take(&mut other_inner.deadlines) {
+            inner.deadlines.insert(ip, expires_at);
+        }
+        for (ip, release_at) in std::mem::take(&mut other_inner.pending) {
+            inner.pending.insert(ip, release_at);
+        }
+        for (ip, activity) in std::mem::take(&mut other_inner.activity) {
+            inner.activity.insert(ip, activity);
+        }
+        let fence = other_inner.fence.load(Ordering::Relaxed);
+        inner.fence.fetch_max(fence, Ordering::Relaxed);
+        let delegations = std::mem::take(&mut other_inner.delegations);
+        inner.delegations.extend(delegations);
+        inner.rebuild_available();
+        inner.touch();
+        inner.log_state();
+
+        // Leave the absorbed pool empty and frozen for any stale handles
+        other_inner.vm_to_ip.clear();
+        other_inner.free.clear();
+        other_inner.frozen = true;
+        other_inner.journal = None;
+        other_inner.touch();
+
+        Ok(())
+    }
+
+    fn is_valid_ip(inner: &IpPoolInner, ip: &str) -> bool {
+        // Parse IP address and check it is inside our network
+        match ip.parse::<Ipv4Addr>() {
+            Ok(addr) => inner.contains(u32::from(addr)),
+            Err(_) => false,
+        }
+    }
+
+    pub async fn dhcp_options(&self) -> DhcpOptions {
+        let inner = self.inner.read().await;
+        inner.dhcp_options.clone()
+    }
+
+    // Replace the DNS servers, search domains and NTP servers handed out
+    // with allocations
+    pub async fn set_dhcp_options(&self, options: DhcpOptions) {
+        let mut inner = self.inner.write().await;
+        if inner.dhcp_options == options {
+            return;
+        }
+        inner.dhcp_options = options;
+        inner.touch();
+        inner.log_state();
+    }
+
+    pub async fn routes(&self) -> Vec<StaticRoute> {
+        let inner = self.inner.read().await;
+        inner.routes.clone()
+    }
+
+    // Replace the static routes handed out with allocations
+    pub async fn set_routes(&self, routes: Vec<StaticRoute>) {
+        let mut inner = self.inner.write().await;
+        if inner.routes == routes {
+            return;
+        }
+        inner.routes = routes;
+        inner.touch();
+        inner.log_state();
+    }
+
+    // IPv6 /64 in which VM addresses are derived, from configuration only
+    pub async fn set_slaac(&self, slaac: Option<Slaac>) {
+        let mut inner = self.inner.write().await;
+        inner.slaac = slaac;
+    }
+
+    pub async fn slaac(&self) -> Option<Slaac> {
+        let inner = self.inner.read().await;
+        inner.slaac.clone()
+    }
+
+    // Default lease for allocations that do not ask for one
+    pub async fn set_lease_ttl(&self, ttl: Option<Duration>) {
+        let mut inner = self.inner.write().await;
+        inner.lease_ttl = ttl;
+    }
+
+    // Name allocations that do not bring a hostname after `template`
+    pub async fn set_hostname_template(&self, template: Option<HostnameTemplate>) {
+        let mut inner = self.inner.write().await;
+        inner.hostname_template = template;
+    }
+
+    // Warn holders this long before their lease expires
+    pub async fn set_expiry_warning(&self, warning: Option<Duration>) {
+        let mut inner = self.inner.write().await;
+        inner.expiry_warning = warning;
+    }
+
+    pub async fn get_network(&self) -> String {
+        let inner = self.inner.read().await;
+        inner.cidr()
+    }
+
+    pub async fn get_gateway(&self) -> String {
+        let inner = self.inner.read().await;
+        inner.gateway.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::testkit::{self, Harness};
+    use proptest::prelude::*;
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        // Any interleaving of allocations, renewals, releases, reservations
+        // and lease expiry answers what the reference model does
+        #[test]
+        fn prop_allocator_matches_model(ops in testkit::ops(80)) {
+            testkit::block_on(Harness::new().run(&ops));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_new_ip_pool() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let stats = pool.get_stats().await;
+
+        assert_eq!(stats["total"].as_u64().unwrap(), 253);
+        assert_eq!(stats["allocated"].as_u64().unwrap(), 0);
+        assert_eq!(stats["available"].as_u64().unwrap(), 253);
+    }
+
+    #[tokio::test]
+    async fn test_allocate_ip() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+
+        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        assert_eq!(ip, "172.16.0.2");
+
+        let stats = pool.get_stats().await;
+        assert_eq!(stats["allocated"].as_u64().unwrap(), 1);
+        assert_eq!(stats["available"].as_u64().unwrap(), 252);
+    }
+
+    #[tokio::test]
+    async fn test_allocate_ip_idempotent() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+
+        let ip1 = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        let ip2 = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+
+        assert_eq!(ip1, ip2);
+
+        let stats = pool.get_stats().await;
+        assert_eq!(stats["allocated"].as_u64().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_preview_allocation() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+
+        let preview = pool
+            .preview_allocation("vm-1", &Slot::primary())
+            .await
+            .unwrap();
+        assert_eq!(preview, "172.16.0.2");
+
+        // Previewing does not reserve anything
+        let stats = pool.get_stats().await;
+        assert_eq!(stats["allocated"].as_u64().unwrap(), 0);
+
+        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        assert_eq!(ip, preview);
+        assert_eq!(
+            pool.preview_allocation("vm-1", &Slot::primary())
+                .await
+                .unwrap(),
+            ip
+        );
+        assert_eq!(
+            pool.preview_allocation("vm-2", &Slot::primary())
+                .await
+                .unwrap(),
+            "172.16.0.3"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_open_breaker_fails_writes_fast() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+        pool.attach_breaker(breaker.clone()).await;
+
+        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        breaker.record::<()>(&Err(StorageError::Unavailable("down".to_string())));
+
+        assert!(matches!(
+            pool.allocate_ip("vm-2".to_string()).await,
+            Err(IpPoolError::StorageUnavailable(_))
+        ));
+        assert!(matches!(
+            pool.release_ip("vm-1").await,
+            Err(IpPoolError::StorageUnavailable(_))
+        ));
+        // Reads are served from memory
+        assert_eq!(pool.get_allocation("vm-1").await.unwrap().ip, ip);
+    }
+
+    #[tokio::test]
+    async fn test_allocated_at() {
+        let clock = MockClock::new(0);
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string())
+            .with_clock(Arc::new(clock.clone()));
+
+        pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        pool.allocate_ip("vm-2".to_string()).await.unwrap();
+        clock.advance(Duration::from_millis(50));
+        pool.allocate_ip("vm-3".to_string()).await.unwrap();
+        pool.release_ip("vm-1").await.unwrap();
+
+        let counts = pool
+            .allocated_at(&[
+                Duration::ZERO,
+                Duration::from_millis(25),
+                Duration::from_secs(60),
+            ])
+            .await;
+        assert_eq!(counts, vec![2, 2, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_range_stats() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+
+        pool.reserve("172.16.0.40", "dns".to_string())
+            .await
+            .unwrap();
+        for i in 0..3 {
+            pool.allocate_ip(format!("vm-{}", i)).await.unwrap();
+        }
+
+        let stats = pool.get_range_stats(Some(26)).await.unwrap();
+        let ranges = stats["ranges"].as_array().unwrap();
+        assert_eq!(ranges.len(), 4);
+        assert_eq!(ranges[0]["range"], "172.16.0.0/26");
+        // .0 is the network address, the gateway (.1) and .40 are reserved
+        assert_eq!(ranges[0]["total"], 61);
+        assert_eq!(ranges[0]["allocated"], 3);
+        assert_eq!(ranges[0]["available"], 58);
+        assert_eq!(ranges[0]["reserved"], 2);
+        assert_eq!(ranges[3]["range"], "172.16.0.192/26");
+        assert_eq!(ranges[3]["total"], 63);
+
+        let stats = pool.get_range_stats(None).await.unwrap();
+        assert_eq!(stats["ranges"].as_array().unwrap().len(), 8);
+
+        assert_eq!(
+            pool.get_range_stats(Some(16)).await,
+            Err(IpPoolError::InvalidRange)
+        );
+        assert_eq!(
+            pool.get_range_stats(Some(33)).await,
+            Err(IpPoolError::InvalidRange)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_next_free() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+
+        pool.reserve("172.16.0.3", "dns".to_string()).await.unwrap();
+        pool.allocate_ip("vm-1".to_string()).await.unwrap();
+
+        let next = pool.next_free(3).await.unwrap();
+        assert_eq!(next, vec!["172.16.0.4", "172.16.0.5", "172.16.0.6"]);
+        assert_eq!(pool.allocate_ip("vm-2".to_string()).await.unwrap(), next[0]);
+
+        pool.set_frozen(true).await;
+        assert_eq!(pool.next_free(3).await, Err(IpPoolError::PoolFrozen));
+    }
+
+    #[tokio::test]
+    async fn test_release_ip() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+
+        pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        pool.release_ip("vm-1").await.unwrap();
+
+        let stats = pool.get_stats().await;
+        assert_eq!(stats["allocated"].as_u64().unwrap(), 0);
+        assert_eq!(stats["available"].as_u64().unwrap(), 253);
+    }
+
+    #[tokio::test]
+    async fn test_release_ip_by_address() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+
+        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        pool.release_ip_by_address(&ip).await.unwrap();
+
+        let stats = pool.get_stats().await;
+        assert_eq!(stats["allocated"].as_u64().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_release_ip_held_by() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+
+        // vm-1 releases its address, which is then handed to vm-2
+        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        pool.release_ip_by_address(&ip).await.unwrap();
+        assert_eq!(pool.allocate_ip("vm-2".to_string()).await.unwrap(), ip);
+
+        // A stale release on behalf of vm-1 leaves vm-2's address alone
+        assert_eq!(
+            pool.release_ip_held_by(&ip, "vm-1").await,
+            Err(IpPoolError::HeldByOtherVm)
+        );
+        assert_eq!(pool.get_allocation("vm-2").await.unwrap().ip, ip);
+
+        assert_eq!(pool.release_ip_held_by(&ip, "vm-2").await.unwrap(), "vm-2");
+        assert_eq!(
+            pool.release_ip_held_by(&ip, "vm-2").await,
+            Err(IpPoolError::IpNotFound)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fence_tokens() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+
+        // vm-1's address is released after a failover and handed out again,
+        // to vm-1 itself
+        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        let stale = pool.fence_token(&ip).await.unwrap();
+        pool.release_ip("vm-1").await.unwrap();
+        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        let fence = pool.fence_token(&ip).await.unwrap();
+        assert!(fence > stale);
+
+        // The replica still holding the old token cannot release or renew it
+        assert_eq!(
+            pool.release_ip_fenced("vm-1", stale).await,
+            Err(IpPoolError::StaleFenceToken)
+        );
+        assert_eq!(
+            pool.release_address_fenced(&ip, Some("vm-1"), stale).await,
+            Err(IpPoolError::StaleFenceToken)
+        );
+        assert_eq!(
+            pool.check_fence("vm-1", Some(&Slot::primary()), stale)
+                .await,
+            Err(IpPoolError::StaleFenceToken)
+        );
+        pool.check_fence("vm-1", Some(&Slot::primary()), fence)
+            .await
+            .unwrap();
+
+        // Renewals keep the token, and restores keep handing out larger ones
+        pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        assert_eq!(pool.fence_token(&ip).await, Some(fence));
+        let restored = IpPool::from_snapshot(pool.snapshot().await).unwrap();
+        assert_eq!(restored.fence_token(&ip).await, Some(fence));
+        let other = restored.allocate_ip("vm-2".to_string()).await.unwrap();
+        assert!(restored.fence_token(&other).await.unwrap() > fence);
+
+        assert_eq!(pool.release_ip_fenced("vm-1", fence).await.unwrap(), [ip]);
+    }
+
+    #[tokio::test]
+    async fn test_get_allocation() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+
+        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        let allocation = pool.get_allocation("vm-1").await.unwrap();
+
+        assert_eq!(allocation.ip, ip);
+        assert_eq!(allocation.vm_id, "vm-1");
+    }
+
+    #[tokio::test]
+    async fn test_list_allocations() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+
+        pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        pool.allocate_ip("vm-2".to_string()).await.unwrap();
+        pool.allocate_ip("vm-3".to_string()).await.unwrap();
+
+        let allocations = pool.list_allocations().await;
+        assert_eq!(allocations.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_no_available_ips() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+
+        // Manually exhaust the pool
+        pool.inner.write().await.free.clear();
 
-impl IpPool {
-    pub fn new(network: String, gateway: String) -> Self {
-        let start = 2;
-        let end = 254;
-        let mut available = Vec::with_capacity((end - start + 1) as usize);
+        let result = pool.allocate_ip("vm-overflow".to_string()).await;
+        assert!(matches!(result, Err(IpPoolError::NoAvailableIps)));
+    }
+
+    #[tokio::test]
+    async fn test_forecast() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
 
-        // Use network prefix as-is (e.g., "172.16.0")
-        let prefix = network.clone();
+        let forecast = pool.get_forecast(Duration::from_secs(3600)).await;
+        assert!(forecast["seconds_to_exhaustion"].is_null());
+        assert_eq!(forecast["remaining_allocations"].as_u64().unwrap(), 253);
 
-        // Initialize available IPs
-        for i in start..=end {
-            let ip = format!("{}.{}", prefix, i);
-            available.push(ip);
+        for i in 0..4 {
+            pool.allocate_ip(format!("vm-{}", i)).await.unwrap();
         }
+        pool.release_ip("vm-0").await.unwrap();
 
-        let inner = IpPoolInner {
-            network: prefix,
-            gateway,
-            start,
-            end,
-            allocated: HashMap::new(),
-            vm_to_ip: HashMap::new(),
-            available,
-        };
+        let forecast = pool.get_forecast(Duration::from_secs(3600)).await;
+        assert_eq!(forecast["allocations_in_window"].as_u64().unwrap(), 4);
+        assert_eq!(forecast["releases_in_window"].as_u64().unwrap(), 1);
+        assert_eq!(forecast["net_rate_per_hour"].as_f64().unwrap(), 3.0);
+        // 250 addresses left at 3/hour
+        assert_eq!(
+            forecast["seconds_to_exhaustion"].as_u64().unwrap(),
+            250 * 1200
+        );
+    }
 
-        IpPool {
-            inner: Arc::new(RwLock::new(inner)),
+    #[tokio::test]
+    async fn test_retry_after() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+
+        // No release history: fixed default
+        assert_eq!(pool.retry_after().await, RETRY_AFTER_DEFAULT);
+
+        // 12 releases in the last hour: one every 5 minutes
+        for i in 0..12 {
+            pool.allocate_ip(format!("vm-{}", i)).await.unwrap();
+            pool.release_ip(&format!("vm-{}", i)).await.unwrap();
         }
+        let retry_after = pool.retry_after().await;
+        assert!(retry_after <= Duration::from_secs(300));
+        assert!(retry_after > Duration::from_secs(290));
     }
 
-    pub async fn allocate_ip(&self, vm_id: String) -> Result<String, IpPoolError> {
-        let mut inner = self.inner.write().await;
+    #[tokio::test]
+    async fn test_secondary_addresses() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
 
-        // Check if VM already has an IP (idempotent)
-        if let Some(ip) = inner.vm_to_ip.get(&vm_id) {
-            return Ok(ip.clone());
-        }
+        let primary = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        let (floating, _) = pool
+            .allocate_address(
+                "vm-1".to_string(),
+                &Slot::new(None, Some("floating".to_string())),
+                Lease::PoolDefault,
+            )
+            .await
+            .unwrap();
+        assert_ne!(primary, floating);
 
-        // Check if there are available IPs
-        if inner.available.is_empty() {
-            return Err(IpPoolError::NoAvailableIps);
-        }
+        // Idempotent per purpose
+        let (again, _) = pool
+            .allocate_address(
+                "vm-1".to_string(),
+                &Slot::new(None, Some("floating".to_string())),
+                Lease::PoolDefault,
+            )
+            .await
+            .unwrap();
+        assert_eq!(again, floating);
 
-        // Take first available IP
-        let ip = inner.available.remove(0);
+        let allocations = pool.get_allocations("vm-1").await.unwrap();
+        let purposes: Vec<_> = allocations
+            .iter()
+            .map(|a| a.purpose.as_deref().unwrap())
+            .collect();
+        assert_eq!(purposes, vec!["floating", "primary"]);
+        assert_eq!(pool.get_allocation("vm-1").await.unwrap().ip, primary);
 
-        // Mark as allocated
-        inner.allocated.insert(ip.clone(), vm_id.clone());
-        inner.vm_to_ip.insert(vm_id, ip.clone());
+        // Snapshots keep the purposes
+        let restored = IpPool::from_snapshot(pool.snapshot().await).unwrap();
+        assert_eq!(restored.snapshot().await, pool.snapshot().await);
+        assert_eq!(restored.get_allocations("vm-1").await.unwrap().len(), 2);
 
-        Ok(ip)
-    }
+        // Individual addresses can be released on their own
+        assert_eq!(pool.release_ip_by_address(&floating).await.unwrap(), "vm-1");
+        assert_eq!(pool.get_allocations("vm-1").await.unwrap().len(), 1);
 
-    pub async fn release_ip(&self, vm_id: &str) -> Result<(), IpPoolError> {
-        let mut inner = self.inner.write().await;
+        pool.allocate_address(
+            "vm-1".to_string(),
+            &Slot::new(None, Some("floating".to_string())),
+            Lease::PoolDefault,
+        )
+        .await
+        .unwrap();
+        assert_eq!(pool.release_ip("vm-1").await.unwrap().len(), 2);
+        assert!(pool.get_allocations("vm-1").await.is_err());
+        assert_eq!(pool.get_stats().await["allocated"].as_u64().unwrap(), 0);
+    }
 
-        // Find IP for this VM
-        let ip = inner
-            .vm_to_ip
-            .get(vm_id)
-            .ok_or(IpPoolError::IpNotFound)?
-            .clone();
+    #[tokio::test]
+    async fn test_lease_override() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string())
+            .with_clock(Arc::new(MockClock::new(1_000)));
+        pool.set_lease_ttl(Some(Duration::from_secs(3600))).await;
 
-        // Remove allocation
-        inner.allocated.remove(&ip);
-        inner.vm_to_ip.remove(vm_id);
+        let (_, expires_at) = pool
+            .allocate_ip_with_lease("vm-1".to_string(), Lease::PoolDefault)
+            .await
+            .unwrap();
+        assert_eq!(expires_at, Some(4_600));
 
-        // Add back to available pool
-        inner.available.push(ip);
+        let (_, expires_at) = pool
+            .allocate_ip_with_lease("vm-2".to_string(), Lease::Ttl(Duration::from_secs(60)))
+            .await
+            .unwrap();
+        assert_eq!(expires_at, Some(1_060));
+        assert_eq!(
+            pool.get_allocation("vm-2").await.unwrap().expires_at,
+            expires_at
+        );
 
-        Ok(())
+        // Asking again renews onto the new lease
+        let (_, expires_at) = pool
+            .allocate_ip_with_lease("vm-1".to_string(), Lease::Infinite)
+            .await
+            .unwrap();
+        assert_eq!(expires_at, None);
+        assert_eq!(pool.get_allocation("vm-1").await.unwrap().expires_at, None);
     }
 
-    pub async fn release_ip_by_address(&self, ip: &str) -> Result<(), IpPoolError> {
-        let mut inner = self.inner.write().await;
+    #[tokio::test]
+    async fn test_list_expiring() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
 
-        // Validate IP is in our network
-        if !Self::is_valid_ip(&inner.network, ip) {
-            return Err(IpPoolError::InvalidIp);
-        }
+        pool.allocate_ip_with_lease("vm-1".to_string(), Lease::Ttl(Duration::from_secs(600)))
+            .await
+            .unwrap();
+        pool.allocate_ip_with_lease("vm-2".to_string(), Lease::Ttl(Duration::from_secs(60)))
+            .await
+            .unwrap();
+        pool.allocate_ip_with_lease("vm-3".to_string(), Lease::Ttl(Duration::from_secs(7200)))
+            .await
+            .unwrap();
+        pool.allocate_ip("vm-4".to_string()).await.unwrap();
 
-        // Find VM for this IP
-        let vm_id = inner
-            .allocated
-            .get(ip)
-            .ok_or(IpPoolError::IpNotFound)?
-            .clone();
+        let expiring = pool.list_expiring(Duration::from_secs(3600)).await;
+        let vm_ids: Vec<_> = expiring.iter().map(|a| a.vm_id.as_str()).collect();
+        assert_eq!(vm_ids, vec!["vm-2", "vm-1"]);
+    }
 
-        // Remove allocation
-        inner.allocated.remove(ip);
-        inner.vm_to_ip.remove(&vm_id);
+    #[tokio::test]
+    async fn test_expire_leases() {
+        let clock = MockClock::new(1_000);
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string())
+            .with_clock(Arc::new(clock.clone()));
+
+        pool.allocate_ip_with_lease("vm-1".to_string(), Lease::Ttl(Duration::from_secs(10)))
+            .await
+            .unwrap();
+        pool.allocate_ip_with_lease("vm-2".to_string(), Lease::Ttl(Duration::from_secs(60)))
+            .await
+            .unwrap();
+        pool.allocate_ip("vm-3".to_string()).await.unwrap();
 
-        // Add back to available pool
-        inner.available.push(ip.to_string());
+        // Holders are warned once per expiry, from 30s before it
+        pool.set_expiry_warning(Some(Duration::from_secs(30))).await;
+        let vm_2 = pool.get_allocation("vm-2").await.unwrap();
+        assert_eq!(vm_2.renew_before, Some(1_030));
+        assert_eq!(
+            pool.get_allocation("vm-3").await.unwrap().renew_before,
+            None
+        );
+        assert_eq!(
+            pool.expiring_leases().await,
+            vec![("vm-1".to_string(), "172.16.0.2".to_string(), 1_010)]
+        );
+        assert!(pool.expiring_leases().await.is_empty());
 
-        Ok(())
+        clock.advance(Duration::from_secs(10));
+        let expired = pool.expire_leases().await;
+        assert_eq!(
+            expired,
+            vec![("vm-1".to_string(), "172.16.0.2".to_string())]
+        );
+        assert!(pool.get_allocation("vm-1").await.is_err());
+        assert_eq!(pool.list_allocations().await.len(), 2);
+        assert!(pool.expire_leases().await.is_empty());
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(
+            pool.expiring_leases().await,
+            vec![("vm-2".to_string(), "172.16.0.3".to_string(), 1_060)]
+        );
+
+        // The expired address is back in the free list
+        let stats = pool.get_stats().await;
+        assert_eq!(stats["available"].as_u64().unwrap(), 251);
     }
 
-    pub async fn get_allocation(&self, vm_id: &str) -> Result<IpAllocation, IpPoolError> {
-        let inner = self.inner.read().await;
+    #[tokio::test]
+    async fn test_deferred_release() {
+        let clock = MockClock::new(1_000);
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string())
+            .with_clock(Arc::new(clock.clone()));
 
-        let ip = inner
-            .vm_to_ip
-            .get(vm_id)
-            .ok_or(IpPoolError::IpNotFound)?
-            .clone();
+        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        pool.allocate_ip("vm-2".to_string()).await.unwrap();
+        assert_eq!(
+            pool.defer_release("vm-3", Duration::from_secs(300)).await,
+            Err(IpPoolError::IpNotFound)
+        );
 
-        Ok(IpAllocation {
-            ip,
-            vm_id: vm_id.to_string(),
-            hostname: None,
-        })
+        // A cancelled release keeps the address
+        pool.defer_release("vm-1", Duration::from_secs(300))
+            .await
+            .unwrap();
+        assert_eq!(pool.cancel_release("vm-1").await.unwrap(), vec![ip.clone()]);
+        assert_eq!(
+            pool.cancel_release("vm-1").await,
+            Err(IpPoolError::ReleaseNotPending)
+        );
+
+        let (ips, release_at) = pool
+            .defer_release("vm-1", Duration::from_secs(300))
+            .await
+            .unwrap();
+        assert_eq!((ips, release_at), (vec![ip.clone()], 1_300));
+        assert_eq!(
+            pool.get_allocation("vm-1")
+                .await
+                .unwrap()
+                .pending_release_at,
+            Some(1_300)
+        );
+        assert_eq!(pool.get_stats().await["pending_release"], 1);
+
+        // Still allocated during the grace period, and across restores
+        clock.advance(Duration::from_secs(299));
+        assert!(pool.release_pending().await.is_empty());
+        let restored = IpPool::from_snapshot(pool.snapshot().await).unwrap();
+        assert_eq!(
+            restored
+                .get_allocation("vm-1")
+                .await
+                .unwrap()
+                .pending_release_at,
+            Some(1_300)
+        );
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(pool.release_pending().await, vec![("vm-1".to_string(), ip)]);
+        assert!(pool.get_allocation("vm-1").await.is_err());
+        assert!(pool.get_allocation("vm-2").await.is_ok());
+        assert_eq!(pool.get_stats().await["pending_release"], 0);
     }
 
-    pub async fn list_allocations(&self) -> Vec<IpAllocation> {
-        let inner = self.inner.read().await;
+    // Only "web-*" VMs get addresses, "pinned-*" ones keep them
+    #[derive(Debug, Default)]
+    struct NamingPolicy {
+        allocated: Mutex<Vec<String>>,
+    }
 
-        inner
-            .allocated
-            .iter()
-            .map(|(ip, vm_id)| IpAllocation {
-                ip: ip.clone(),
-                vm_id: vm_id.clone(),
-                hostname: None,
+    impl AllocationHook for NamingPolicy {
+        fn pre_allocate<'a>(
+            &'a self,
+            vm_id: &'a str,
+            _slot: &'a Slot,
+        ) -> crate::hooks::HookFuture<'a, Result<(), String>> {
+            Box::pin(async move {
+                match vm_id.starts_with("web-") || vm_id.starts_with("pinned-") {
+                    true => Ok(()),
+                    false => Err(format!("{} does not match web-*", vm_id)),
+                }
             })
-            .collect()
+        }
+
+        fn post_allocate<'a>(
+            &'a self,
+            _vm_id: &'a str,
+            _slot: &'a Slot,
+            ip: &'a str,
+        ) -> crate::hooks::HookFuture<'a, ()> {
+            Box::pin(async move { self.allocated.lock().unwrap().push(ip.to_string()) })
+        }
+
+        fn pre_release<'a>(
+            &'a self,
+            vm_id: &'a str,
+            _ip: &'a str,
+        ) -> crate::hooks::HookFuture<'a, Result<(), String>> {
+            Box::pin(async move {
+                match vm_id.starts_with("pinned-") {
+                    true => Err(format!("{} is pinned", vm_id)),
+                    false => Ok(()),
+                }
+            })
+        }
     }
 
-    pub async fn get_stats(&self) -> serde_json::Value {
-        let inner = self.inner.read().await;
+    #[tokio::test]
+    async fn test_allocation_hooks() {
+        let policy = Arc::new(NamingPolicy::default());
+        let pool =
+            IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string()).with_hook(policy.clone());
 
-        let total = (inner.end - inner.start + 1) as usize;
-        let allocated = inner.allocated.len();
-        let available = inner.available.len();
-        let usage = (allocated as f64 / total as f64) * 100.0;
+        let result = pool.allocate_ip("db-1".to_string()).await;
+        assert_eq!(
+            result,
+            Err(IpPoolError::PolicyViolation(
+                "db-1 does not match web-*".to_string()
+            ))
+        );
+        assert_eq!(pool.get_stats().await["available"].as_u64().unwrap(), 253);
 
-        serde_json::json!({
-            "network": format!("{}.0/24", inner.network),
-            "gateway": inner.gateway,
-            "total": total,
-            "allocated": allocated,
-            "available": available,
-            "usage": usage,
-        })
+        let ip = pool.allocate_ip("web-1".to_string()).await.unwrap();
+        let pinned = pool.allocate_ip("pinned-1".to_string()).await.unwrap();
+        // Re-requesting a held address does not run hooks again
+        pool.allocate_ip("web-1".to_string()).await.unwrap();
+        assert_eq!(*policy.allocated.lock().unwrap(), vec![ip, pinned.clone()]);
+
+        assert!(matches!(
+            pool.release_ip("pinned-1").await,
+            Err(IpPoolError::PolicyViolation(_))
+        ));
+        assert!(matches!(
+            pool.release_ip_by_address(&pinned).await,
+            Err(IpPoolError::PolicyViolation(_))
+        ));
+        assert_eq!(pool.get_allocation("pinned-1").await.unwrap().ip, pinned);
+        assert_eq!(pool.release_unchecked(&pinned).await.unwrap(), "pinned-1");
+        pool.release_ip("web-1").await.unwrap();
     }
 
-    fn is_valid_ip(network: &str, ip: &str) -> bool {
-        // Parse IP address
-        if ip.parse::<Ipv4Addr>().is_err() {
-            return false;
-        }
+    #[tokio::test]
+    async fn test_frozen_pool() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
 
-        // Check if IP starts with network prefix
-        let network_prefix = format!("{}.", network);
-        ip.starts_with(&network_prefix)
-    }
+        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        pool.allocate_ip("vm-2".to_string()).await.unwrap();
+        pool.set_frozen(true).await;
 
-    #[allow(dead_code)]
-    pub async fn clear(&self) {
-        let mut inner = self.inner.write().await;
+        // New allocations are rejected, existing ones are still returned
+        let result = pool.allocate_ip("vm-3".to_string()).await;
+        assert!(matches!(result, Err(IpPoolError::PoolFrozen)));
+        assert_eq!(pool.allocate_ip("vm-1".to_string()).await.unwrap(), ip);
 
-        inner.allocated.clear();
-        inner.vm_to_ip.clear();
-        inner.available.clear();
+        // Releases keep working while frozen
+        pool.release_ip("vm-2").await.unwrap();
 
-        // Reinitialize available IPs
-        for i in inner.start..=inner.end {
-            let ip = format!("{}.{}", inner.network, i);
-            inner.available.push(ip);
-        }
+        pool.set_frozen(false).await;
+        assert!(pool.allocate_ip("vm-3".to_string()).await.is_ok());
     }
 
-    pub async fn get_network(&self) -> String {
-        let inner = self.inner.read().await;
-        inner.network.clone()
-    }
+    #[tokio::test]
+    async fn test_reserved_addresses() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
 
-    pub async fn get_gateway(&self) -> String {
-        let inner = self.inner.read().await;
-        inner.gateway.clone()
+        pool.reserve("172.16.0.2", "dns".to_string()).await.unwrap();
+        pool.reserve("172.16.0.3", "monitoring".to_string())
+            .await
+            .unwrap();
+
+        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        assert_eq!(ip, "172.16.0.4");
+
+        let reserved = pool.list_reserved().await;
+        let labels: Vec<_> = reserved
+            .iter()
+            .map(|r| r.label.as_deref().unwrap())
+            .collect();
+        assert_eq!(labels, vec!["gateway", "dns", "monitoring"]);
+
+        let stats = pool.get_stats().await;
+        assert_eq!(stats["total"].as_u64().unwrap(), 251);
+        assert_eq!(stats["reserved"].as_u64().unwrap(), 3);
+
+        assert_eq!(
+            pool.reserve("172.16.0.4", "router".to_string()).await,
+            Err(IpPoolError::IpInUse)
+        );
+        assert_eq!(
+            pool.reserve("10.0.0.1", "router".to_string()).await,
+            Err(IpPoolError::InvalidIp)
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn test_external_addresses() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        pool.mark_external("172.16.0.2", "lobby printer".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            pool.allocate_ip("vm-1".to_string()).await.unwrap(),
+            "172.16.0.3"
+        );
+        assert_eq!(
+            pool.mark_external("172.16.0.3", "NAS".to_string()).await,
+            Err(IpPoolError::IpInUse)
+        );
+
+        let printer = pool.list_reserved().await.pop().unwrap();
+        assert!(printer.reserved && printer.external);
+        assert_eq!(printer.label.as_deref(), Some("lobby printer"));
+        assert_eq!(pool.get_stats().await["external"], 1);
+
+        // Reserving it as infrastructure instead drops the external mark
+        let restored = IpPool::from_snapshot(pool.snapshot().await).unwrap();
+        assert!(restored.list_reserved().await[1].external);
+        restored
+            .reserve("172.16.0.2", "dns".to_string())
+            .await
+            .unwrap();
+        assert!(restored.snapshot().await.external.is_empty());
+    }
 
     #[tokio::test]
-    async fn test_new_ip_pool() {
+    async fn test_labels_and_group_stats() {
         let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
-        let stats = pool.get_stats().await;
+        let project = |name: &str| BTreeMap::from([("project".to_string(), name.to_string())]);
 
-        assert_eq!(stats["total"].as_u64().unwrap(), 253);
-        assert_eq!(stats["allocated"].as_u64().unwrap(), 0);
-        assert_eq!(stats["available"].as_u64().unwrap(), 253);
+        for (vm_id, labels) in [
+            ("vm-1", project("payments")),
+            ("vm-2", project("payments")),
+            ("vm-3", project("search")),
+            ("vm-4", BTreeMap::new()),
+        ] {
+            let ip = pool.allocate_ip(vm_id.to_string()).await.unwrap();
+            pool.label(&ip, labels).await.unwrap();
+        }
+        assert_eq!(
+            pool.get_allocation("vm-1").await.unwrap().labels,
+            project("payments")
+        );
+        assert_eq!(
+            pool.group_stats("project").await,
+            vec![
+                (Some("payments".to_string()), 2),
+                (None, 1),
+                (Some("search".to_string()), 1),
+            ]
+        );
+        assert_eq!(
+            pool.label("172.16.0.200", project("search")).await,
+            Err(IpPoolError::IpNotFound)
+        );
+
+        // Labels survive snapshots and go away with the allocation
+        let restored = IpPool::from_snapshot(pool.snapshot().await).unwrap();
+        assert_eq!(restored.group_stats("project").await.len(), 3);
+        pool.release_ip("vm-3").await.unwrap();
+        assert_eq!(
+            pool.group_stats("project").await,
+            vec![(Some("payments".to_string()), 2), (None, 1)]
+        );
+
+        // Releasing by label takes every holder of it at once
+        assert_eq!(
+            pool.release_labeled("project", "payments").await.unwrap(),
+            vec![
+                ("vm-1".to_string(), "172.16.0.2".to_string()),
+                ("vm-2".to_string(), "172.16.0.3".to_string()),
+            ]
+        );
+        assert_eq!(pool.group_stats("project").await, vec![(None, 1)]);
+        assert!(pool.get_allocation("vm-2").await.is_err());
+        assert!(
+            pool.release_labeled("project", "payments")
+                .await
+                .unwrap()
+                .is_empty()
+        );
     }
 
     #[tokio::test]
-    async fn test_allocate_ip() {
+    async fn test_hostnames() {
         let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let template = "vm-{last_octet}.lab.local".parse().unwrap();
+        pool.set_hostname_template(Some(template)).await;
 
+        // Allocations without a hostname are named after the template
         let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
-        assert_eq!(ip, "172.16.0.2");
+        assert_eq!(
+            pool.get_allocation("vm-1")
+                .await
+                .unwrap()
+                .hostname
+                .as_deref(),
+            Some("vm-2.lab.local")
+        );
+        let other = pool.allocate_ip("vm-2".to_string()).await.unwrap();
+        pool.set_hostname(&other, "db.lab.local".to_string())
+            .await
+            .unwrap();
+        assert_eq!(pool.hostname(&other).await.as_deref(), Some("db.lab.local"));
+        assert_eq!(
+            pool.set_hostname("172.16.0.200", "x".to_string()).await,
+            Err(IpPoolError::IpNotFound)
+        );
 
-        let stats = pool.get_stats().await;
-        assert_eq!(stats["allocated"].as_u64().unwrap(), 1);
-        assert_eq!(stats["available"].as_u64().unwrap(), 252);
+        // Hostnames survive snapshots and go away with the allocation
+        let restored = IpPool::from_snapshot(pool.snapshot().await).unwrap();
+        assert_eq!(
+            restored.hostname(&other).await.as_deref(),
+            Some("db.lab.local")
+        );
+        pool.release_ip("vm-1").await.unwrap();
+        assert_eq!(pool.hostname(&ip).await, None);
     }
 
     #[tokio::test]
-    async fn test_allocate_ip_idempotent() {
+    async fn test_annotations() {
         let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        let annotation = Annotation {
+            owner: Some("alice@example.com".to_string()),
+            notes: Some("static for the license server".to_string()),
+            ticket: Some("OPS-1234".to_string()),
+        };
+        pool.annotate(&ip, annotation.clone()).await.unwrap();
+        let allocation = pool.get_allocation("vm-1").await.unwrap();
+        assert_eq!(allocation.ticket.as_deref(), Some("OPS-1234"));
+        assert_eq!(
+            pool.annotate("172.16.0.200", annotation).await,
+            Err(IpPoolError::IpNotFound)
+        );
 
-        let ip1 = pool.allocate_ip("vm-1".to_string()).await.unwrap();
-        let ip2 = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        // Notes survive snapshots, and clearing them or releasing the
+        // address drops them
+        let restored = IpPool::from_snapshot(pool.snapshot().await).unwrap();
+        assert_eq!(restored.get_allocation("vm-1").await.unwrap(), allocation);
+        restored.annotate(&ip, Annotation::default()).await.unwrap();
+        assert!(restored.snapshot().await.annotations.is_empty());
+        pool.release_ip("vm-1").await.unwrap();
+        pool.allocate_ip("vm-2".to_string()).await.unwrap();
+        assert_eq!(pool.get_allocation("vm-2").await.unwrap().notes, None);
+    }
 
-        assert_eq!(ip1, ip2);
+    #[tokio::test]
+    async fn test_burn_rate() {
+        let clock = MockClock::new(1_000);
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string())
+            .with_clock(Arc::new(clock.clone()));
+        let capacity = pool.get_stats().await["total"].as_f64().unwrap();
+        for vm in 0..4 {
+            pool.allocate_ip(format!("vm-{}", vm)).await.unwrap();
+        }
+        clock.advance(Duration::from_secs(1800));
+        // A storm: the short window burns far faster than the long ones
+        for vm in 4..10 {
+            pool.allocate_ip(format!("vm-{}", vm)).await.unwrap();
+        }
+        pool.release_ip("vm-0").await.unwrap();
 
-        let stats = pool.get_stats().await;
-        assert_eq!(stats["allocated"].as_u64().unwrap(), 1);
+        let burn_rates = pool.burn_rates().await;
+        let expected = [5.0 * 12.0, 9.0, 9.0 / 6.0];
+        for ((_, rate), expected) in burn_rates.iter().zip(expected) {
+            assert!((rate - expected / capacity).abs() < 1e-9);
+        }
+
+        // Releasing more than was allocated burns nothing
+        for vm in 1..10 {
+            pool.release_ip(&format!("vm-{}", vm)).await.unwrap();
+        }
+        assert_eq!(pool.burn_rates().await[0], ("5m", 0.0));
     }
 
     #[tokio::test]
-    async fn test_release_ip() {
+    async fn test_hard_expiry() {
+        let clock = MockClock::new(1_000);
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string())
+            .with_clock(Arc::new(clock.clone()));
+        let lease = Lease::Ttl(Duration::from_secs(60));
+        let (ip, _) = pool
+            .allocate_ip_with_lease("vm-1".to_string(), lease)
+            .await
+            .unwrap();
+        pool.allocate_ip("vm-2".to_string()).await.unwrap();
+
+        // A cap can be brought forward but not pushed back
+        assert_eq!(pool.set_deadline(&ip, 1_100).await, Ok(1_100));
+        assert_eq!(pool.set_deadline(&ip, 2_000).await, Ok(1_100));
+        assert_eq!(pool.set_deadline(&ip, 1_090).await, Ok(1_090));
+        assert_eq!(
+            pool.set_deadline("172.16.0.200", 1_100).await,
+            Err(IpPoolError::IpNotFound)
+        );
+        let restored = IpPool::from_snapshot(pool.snapshot().await).unwrap();
+        assert_eq!(restored.deadline(&ip).await, Some(1_090));
+
+        // Renewing moves the lease, not the cap
+        clock.advance(Duration::from_secs(50));
+        pool.allocate_ip_with_lease("vm-1".to_string(), lease)
+            .await
+            .unwrap();
+        let allocation = pool.get_allocation("vm-1").await.unwrap();
+        assert_eq!(
+            (allocation.expires_at, allocation.hard_expires_at),
+            (Some(1_110), Some(1_090))
+        );
+        assert!(pool.reclaim_overdue().await.is_empty());
+        clock.advance(Duration::from_secs(40));
+        assert_eq!(
+            pool.reclaim_overdue().await,
+            vec![("vm-1".to_string(), ip.clone())]
+        );
+        assert!(pool.get_allocation("vm-1").await.is_err());
+        assert!(pool.snapshot().await.deadlines.is_empty());
+        assert_eq!(pool.list_allocations().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_conflicting_addresses() {
+        use crate::conflicts::ConflictSource;
+
         let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let conflict = Conflict {
+            source: ConflictSource::Decline,
+            detail: "address already in use".to_string(),
+            reported_by: Some("vm-1".to_string()),
+            reported_at: 1_000,
+            investigation: None,
+        };
 
-        pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        assert_eq!(
+            pool.flag_conflict(&ip, conflict.clone()).await,
+            Ok(Some("vm-1".to_string()))
+        );
+        pool.flag_conflict("172.16.0.3", conflict.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            pool.flag_conflict("10.0.0.3", conflict.clone()).await,
+            Err(IpPoolError::InvalidIp)
+        );
+
+        // Flagged addresses are skipped, also once released
         pool.release_ip("vm-1").await.unwrap();
+        assert_eq!(
+            pool.allocate_ip("vm-2".to_string()).await.unwrap(),
+            "172.16.0.4"
+        );
+        let flagged: Vec<String> = pool
+            .list_conflicts()
+            .await
+            .into_iter()
+            .map(|(ip, _, _)| ip)
+            .collect();
+        assert_eq!(flagged, vec!["172.16.0.2", "172.16.0.3"]);
 
-        let stats = pool.get_stats().await;
-        assert_eq!(stats["allocated"].as_u64().unwrap(), 0);
-        assert_eq!(stats["available"].as_u64().unwrap(), 253);
+        // Flags survive snapshots
+        let restored = IpPool::from_snapshot(pool.snapshot().await).unwrap();
+        assert_eq!(restored.list_conflicts().await.len(), 2);
+
+        pool.clear_conflict("172.16.0.2").await.unwrap();
+        let free = pool.next_free(256).await.unwrap();
+        assert!(free.contains(&"172.16.0.2".to_string()));
+        assert!(!free.contains(&"172.16.0.3".to_string()));
+        pool.exclude_conflict("172.16.0.3", "printer".to_string())
+            .await
+            .unwrap();
+        assert!(pool.list_conflicts().await.is_empty());
+        assert_eq!(pool.list_reserved().await.len(), 2);
+        assert_eq!(
+            pool.clear_conflict("172.16.0.3").await,
+            Err(IpPoolError::ConflictNotFound)
+        );
     }
 
     #[tokio::test]
-    async fn test_release_ip_by_address() {
+    async fn test_conflict_actions_are_refused_when_not_journaled() {
+        use crate::conflicts::ConflictSource;
+
         let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+        pool.attach_breaker(breaker.clone()).await;
+        let conflict = Conflict {
+            source: ConflictSource::Probe,
+            detail: "answers ping".to_string(),
+            reported_by: None,
+            reported_at: 1_000,
+            investigation: None,
+        };
+        pool.flag_conflict("172.16.0.2", conflict.clone())
+            .await
+            .unwrap();
+        let before = pool.snapshot().await;
 
-        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
-        pool.release_ip_by_address(&ip).await.unwrap();
+        breaker.record::<()>(&Err(StorageError::Unavailable("down".to_string())));
+        let unavailable = |result: Result<(), IpPoolError>| {
+            assert!(matches!(result, Err(IpPoolError::StorageUnavailable(_))));
+        };
+        unavailable(pool.flag_conflict("172.16.0.3", conflict).await.map(drop));
+        unavailable(pool.clear_conflict("172.16.0.2").await.map(drop));
+        unavailable(
+            pool.investigate_conflict("172.16.0.2", "ops".to_string())
+                .await,
+        );
+        unavailable(
+            pool.exclude_conflict("172.16.0.2", "printer".to_string())
+                .await,
+        );
+        unavailable(
+            pool.adopt_external("172.16.0.2", "printer".to_string())
+                .await,
+        );
+        unavailable(pool.adopt_static("172.16.0.2", "vm-1").await);
+        unavailable(
+            pool.mark_external("172.16.0.4", "printer".to_string())
+                .await,
+        );
 
-        let stats = pool.get_stats().await;
-        assert_eq!(stats["allocated"].as_u64().unwrap(), 0);
+        // Nothing was applied in memory either; only fencing tokens drawn
+        // for the refused adoption are skipped
+        let after = pool.snapshot().await;
+        assert_eq!(
+            PoolSnapshot { fence: 0, ..after },
+            PoolSnapshot { fence: 0, ..before }
+        );
     }
 
     #[tokio::test]
-    async fn test_get_allocation() {
+    async fn test_allocation_details_are_refused_with_it() {
         let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+        pool.attach_breaker(breaker.clone()).await;
+        let requested = Requested {
+            labels: Some(BTreeMap::from([("role".to_string(), "db".to_string())])),
+            hostname: Some("db.lab.local".to_string()),
+            mac: Some("52:54:00:12:34:56".to_string()),
+            owner: Some("alice".to_string()),
+            deadline: Some(u64::MAX - 1),
+            ..Requested::default()
+        };
+        let allocate = |vm_id: &str| {
+            let vm_id = vm_id.to_string();
+            let (pool, requested) = (pool.clone(), requested.clone());
+            async move {
+                (pool.allocate_with(
+                    None,
+                    vm_id,
+                    &Slot::primary(),
+                    Lease::PoolDefault,
+                    &requested,
+                ))
+                .await
+            }
+        };
 
-        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        allocate("vm-1").await.unwrap();
         let allocation = pool.get_allocation("vm-1").await.unwrap();
+        assert_eq!(Some(allocation.labels), requested.labels);
+        assert_eq!(allocation.hostname.as_deref(), Some("db.lab.local"));
+        assert_eq!(allocation.mac.as_deref(), Some("52:54:00:12:34:56"));
+        assert_eq!(allocation.owner.as_deref(), Some("alice"));
+        assert_eq!(allocation.hard_expires_at, Some(u64::MAX - 1));
 
-        assert_eq!(allocation.ip, ip);
-        assert_eq!(allocation.vm_id, "vm-1");
+        // Renewing never pushes the deadline back
+        let later = Requested {
+            deadline: Some(u64::MAX),
+            ..Requested::default()
+        };
+        (pool.allocate_with(
+            None,
+            "vm-1".to_string(),
+            &Slot::primary(),
+            Lease::PoolDefault,
+            &later,
+        ))
+        .await
+        .unwrap();
+        assert_eq!(pool.deadline(&allocation.ip).await, Some(u64::MAX - 1));
+
+        // A refused allocation leaves neither the address nor its details
+        let before = pool.snapshot().await;
+        breaker.record::<()>(&Err(StorageError::Unavailable("down".to_string())));
+        assert!(matches!(
+            allocate("vm-2").await,
+            Err(IpPoolError::StorageUnavailable(_))
+        ));
+        let after = pool.snapshot().await;
+        assert_eq!(
+            PoolSnapshot { fence: 0, ..after },
+            PoolSnapshot { fence: 0, ..before }
+        );
     }
 
     #[tokio::test]
-    async fn test_list_allocations() {
+    async fn test_snapshot_roundtrip() {
         let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
 
+        pool.reserve("172.16.0.53", "dns".to_string())
+            .await
+            .unwrap();
         pool.allocate_ip("vm-1".to_string()).await.unwrap();
-        pool.allocate_ip("vm-2".to_string()).await.unwrap();
-        pool.allocate_ip("vm-3".to_string()).await.unwrap();
+        pool.allocate_ip_with_lease("vm-2".to_string(), Lease::Ttl(Duration::from_secs(60)))
+            .await
+            .unwrap();
+        pool.set_frozen(true).await;
 
-        let allocations = pool.list_allocations().await;
-        assert_eq!(allocations.len(), 3);
+        let snapshot = pool.snapshot().await;
+        assert_eq!(snapshot.leases.len(), 1);
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let restored = IpPool::from_snapshot(serde_json::from_str(&json).unwrap()).unwrap();
+
+        assert_eq!(restored.snapshot().await, snapshot);
+        assert_eq!(restored.get_stats().await, pool.get_stats().await);
+        assert_eq!(
+            restored.get_allocation("vm-2").await.unwrap().ip,
+            "172.16.0.3"
+        );
+
+        // Restoring in place replaces the state but keeps the handle
+        let version = pool.version().await;
+        pool.restore(
+            IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string())
+                .snapshot()
+                .await,
+        )
+        .await
+        .unwrap();
+        assert!(pool.version().await > version);
+        assert!(pool.list_allocations().await.is_empty());
+
+        // A snapshot whose range is inverted is refused and changes nothing
+        let before = pool.snapshot().await;
+        let mut inverted = before.clone();
+        inverted.start = "172.16.0.200".parse().unwrap();
+        inverted.end = "172.16.0.100".parse().unwrap();
+        assert_eq!(
+            pool.restore(inverted.clone()).await,
+            Err(IpPoolError::InvertedRange)
+        );
+        assert_eq!(pool.snapshot().await, before);
+        assert_eq!(
+            IpPool::from_snapshot(inverted).err(),
+            Some(IpPoolError::InvertedRange)
+        );
     }
 
     #[tokio::test]
-    async fn test_no_available_ips() {
+    async fn test_split_pool() {
         let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
 
-        // Manually exhaust the pool
-        pool.inner.write().await.available.clear();
+        // .2 through .131
+        for i in 0..130 {
+            pool.allocate_ip(format!("vm-{}", i)).await.unwrap();
+        }
+        let upper = pool.split("172.16.0.128".parse().unwrap()).await.unwrap();
 
-        let result = pool.allocate_ip("vm-overflow".to_string()).await;
-        assert!(matches!(result, Err(IpPoolError::NoAvailableIps)));
+        // Allocations follow the half that contains their address
+        assert_eq!(pool.list_allocations().await.len(), 126);
+        assert_eq!(upper.list_allocations().await.len(), 4);
+        assert_eq!(
+            upper.get_allocation("vm-126").await.unwrap().ip,
+            "172.16.0.128"
+        );
+        assert!(pool.get_allocation("vm-126").await.is_err());
+
+        let stats = upper.get_stats().await;
+        assert_eq!(stats["network"], "172.16.0.0/24");
+        assert_eq!(stats["total"].as_u64().unwrap(), 127);
+        assert_eq!(stats["available"].as_u64().unwrap(), 123);
+        assert!(matches!(
+            pool.allocate_ip("vm-new".to_string()).await,
+            Err(IpPoolError::NoAvailableIps)
+        ));
+
+        let result = pool.split("172.16.0.200".parse().unwrap()).await;
+        assert!(matches!(result, Err(IpPoolError::InvalidSplit)));
+    }
+
+    #[tokio::test]
+    async fn test_delegated_sub_range() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let ip = |last: u8| Ipv4Addr::new(172, 16, 0, last);
+        pool.delegate("squad-a", ip(10), ip(19), "hash-a".to_string())
+            .await
+            .unwrap();
+
+        // Other allocations skip the delegation
+        for i in 2..=9 {
+            pool.allocate_ip(format!("vm-{}", i)).await.unwrap();
+        }
+        assert_eq!(pool.next_free(1).await.unwrap(), vec!["172.16.0.20"]);
+        assert_eq!(
+            pool.allocate_ip("vm-20".to_string()).await.unwrap(),
+            "172.16.0.20"
+        );
+
+        // The team only draws from it
+        let slot = Slot::primary();
+        assert_eq!(
+            pool.preview_delegated("squad-a", "vm-a", &slot)
+                .await
+                .unwrap(),
+            "172.16.0.10"
+        );
+        let (allocated, _) = pool
+            .allocate_delegated("squad-a", "vm-a".to_string(), &slot, Lease::PoolDefault)
+            .await
+            .unwrap();
+        assert_eq!(allocated, "172.16.0.10");
+        assert_eq!(
+            pool.allocate_delegated("squad-b", "vm-b".to_string(), &slot, Lease::PoolDefault)
+                .await,
+            Err(IpPoolError::PoolNotDelegated)
+        );
+        assert_eq!(
+            pool.delegated_team("hash-a").await.as_deref(),
+            Some("squad-a")
+        );
+
+        let usage = &pool.get_stats().await["delegations"][0];
+        assert_eq!(usage["team"], "squad-a");
+        assert_eq!(usage["total"], 10);
+        assert_eq!(usage["allocated"], 1);
+        assert_eq!(usage["available"], 9);
+
+        // Overlapping, outside the pool, or a second one for the team
+        for (team, start, end) in [
+            ("squad-b", ip(19), ip(25)),
+            ("squad-b", ip(250), ip(255)),
+            ("squad-a", ip(30), ip(39)),
+        ] {
+            assert_eq!(
+                pool.delegate(team, start, end, "hash-b".to_string()).await,
+                Err(IpPoolError::InvalidDelegation)
+            );
+        }
+
+        // Delegations survive snapshots and are never split
+        let restored = IpPool::from_snapshot(pool.snapshot().await).unwrap();
+        assert_eq!(restored.delegations().await, pool.delegations().await);
+        assert_eq!(
+            pool.split(ip(15)).await.err(),
+            Some(IpPoolError::InvalidSplit)
+        );
+
+        // Revoked ranges are handed out as usual again
+        pool.revoke_delegation("squad-a").await.unwrap();
+        assert_eq!(
+            pool.allocate_ip("vm-21".to_string()).await.unwrap(),
+            "172.16.0.11"
+        );
+        assert_eq!(
+            pool.revoke_delegation("squad-a").await,
+            Err(IpPoolError::DelegationNotFound)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_sibling_networks() {
+        let low = IpPool::new("10.0.0".to_string(), "10.0.0.1".to_string());
+        let high = IpPool::new("10.0.1".to_string(), "10.0.1.1".to_string());
+
+        low.allocate_ip("vm-1".to_string()).await.unwrap();
+        high.allocate_ip("vm-2".to_string()).await.unwrap();
+
+        low.merge(&high).await.unwrap();
+
+        let stats = low.get_stats().await;
+        assert_eq!(stats["network"], "10.0.0.0/23");
+        assert_eq!(stats["allocated"].as_u64().unwrap(), 2);
+        assert_eq!(low.get_allocation("vm-2").await.unwrap().ip, "10.0.1.2");
+        // .2 through 10.0.1.254, minus the second gateway
+        assert_eq!(stats["total"].as_u64().unwrap(), 508);
+
+        let result = high.allocate_ip("vm-3".to_string()).await;
+        assert!(matches!(result, Err(IpPoolError::PoolFrozen)));
+    }
+
+    #[tokio::test]
+    async fn test_merge_rejects_distant_networks() {
+        let a = IpPool::new("10.0.1".to_string(), "10.0.1.1".to_string());
+        let b = IpPool::new("10.0.2".to_string(), "10.0.2.1".to_string());
+
+        let result = a.merge(&b).await;
+        assert!(matches!(result, Err(IpPoolError::InvalidMerge)));
+    }
+
+    #[tokio::test]
+    async fn test_vlan_follows_pool() {
+        let pool = IpPool::new("10.0.0".to_string(), "10.0.0.1".to_string());
+        pool.set_vlan_id(Some(120)).await;
+        assert_eq!(pool.get_stats().await["vlan_id"], 120);
+
+        let restored = IpPool::from_snapshot(pool.snapshot().await).unwrap();
+        assert_eq!(restored.vlan_id().await, Some(120));
+        let upper = pool.split("10.0.0.128".parse().unwrap()).await.unwrap();
+        assert_eq!(upper.vlan_id().await, Some(120));
+
+        // Networks on different VLANs are not merged
+        let high = IpPool::new("10.0.1".to_string(), "10.0.1.1".to_string());
+        high.set_vlan_id(Some(121)).await;
+        assert!(matches!(
+            pool.merge(&high).await,
+            Err(IpPoolError::InvalidMerge)
+        ));
+        assert_eq!(pool.get_stats().await["network"], "10.0.0.0/24");
     }
 
     #[tokio::test]
@@ -351,4 +4872,28 @@ mod tests {
         let stats = pool.get_stats().await;
         assert_eq!(stats["allocated"].as_u64().unwrap(), 100);
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_allocations_same_vm() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+
+        let mut handles = vec![];
+        for _ in 0..50 {
+            let pool = pool.clone();
+            handles.push(tokio::spawn(async move {
+                pool.allocate_ip("vm-1".to_string()).await
+            }));
+        }
+
+        let mut ips = std::collections::HashSet::new();
+        for handle in handles {
+            ips.insert(handle.await.unwrap().unwrap());
+        }
+
+        // Every request agrees on one address and nothing leaked
+        assert_eq!(ips.len(), 1);
+        let stats = pool.get_stats().await;
+        assert_eq!(stats["allocated"].as_u64().unwrap(), 1);
+        assert_eq!(stats["available"].as_u64().unwrap(), 252);
+    }
 }
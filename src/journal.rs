@@ -0,0 +1,529 @@
+use crate::conflicts::Conflict;
+use crate::health::{HealthRegistry, Status};
+use crate::ippool::{AllocationDetails, Annotation, PoolSnapshot};
+use crate::pools::PoolRegistry;
+use crate::storage::{FileStore, StateStore, StorageError};
+use crate::wireguard::Tunnel;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const JOURNAL_FILE: &str = "journal.jsonl";
+const ROTATED_JOURNAL_FILE: &str = "journal.jsonl.old";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+// One mutation, written before it is applied. Entries describe absolute
+// state (this IP now belongs to that VM), so replaying an entry that is
+// already reflected in the snapshot is harmless.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum JournalEntry {
+    Allocate {
+        pool: String,
+        ip: String,
+        vm_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        purpose: Option<String>, // absent for the primary address
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        interface: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>, // lease expiry, unix seconds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fence: Option<u64>, // fencing token of the allocation
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hostname: Option<String>, // absent: unchanged
+        #[serde(flatten)]
+        details: AllocationDetails, // set along with the allocation
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        resolves: bool, // settles the conflict flagged on the address
+    },
+    Release {
+        pool: String,
+        ip: String,
+    },
+    Reserve {
+        pool: String,
+        ip: String,
+        label: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        external: bool, // used by a device outside the pool
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        resolves: bool, // settles the conflict flagged on the address
+    },
+    Conflict {
+        pool: String,
+        ip: String,
+        conflict: Option<Conflict>, // replaces the previous report, None: cleared
+    },
+    Prefix6 {
+        pool: String,
+        prefix: String,        // delegated IPv6 prefix, e.g. "2001:db8:100:1::/64"
+        vm_id: Option<String>, // None: released
+    },
+    WireGuard {
+        pool: String,
+        public_key: String,
+        tunnel: Option<Tunnel>, // None: peer removed
+    },
+    Label {
+        pool: String,
+        ip: String,
+        labels: BTreeMap<String, String>, // replaces the previous labels
+    },
+    Hostname {
+        pool: String,
+        ip: String,
+        hostname: String,
+    },
+    Mac {
+        pool: String,
+        ip: String,
+        mac: String,
+    },
+    Deadline {
+        pool: String,
+        ip: String,
+        expires_at: u64, // unix seconds
+    },
+    Annotate {
+        pool: String,
+        ip: String,
+        annotation: Annotation, // replaces the previous owner, notes and ticket
+    },
+    PendingRelease {
+        pool: String,
+        ip: String,
+        release_at: Option<u64>, // unix seconds, None: cancelled
+    },
+    Freeze {
+        pool: String,
+        frozen: bool,
+    },
+    Replace {
+        pool: String,
+        snapshot: Box<PoolSnapshot>, // boxed, it dwarfs the other entries
+    },
+    Remove {
+        pool: String,
+    },
+}
+
+impl JournalEntry {
+    pub fn pool(&self) -> &str {
+        match self {
+            JournalEntry::Allocate { pool, .. }
+            | JournalEntry::Release { pool, .. }
+            | JournalEntry::Reserve { pool, .. }
+            | JournalEntry::Conflict { pool, .. }
+            | JournalEntry::Prefix6 { pool, .. }
+            | JournalEntry::WireGuard { pool, .. }
+            | JournalEntry::Label { pool, .. }
+            | JournalEntry::Hostname { pool, .. }
+            | JournalEntry::Mac { pool, .. }
+            | JournalEntry::Deadline { pool, .. }
+            | JournalEntry::Annotate { pool, .. }
+            | JournalEntry::PendingRelease { pool, .. }
+            | JournalEntry::Freeze { pool, .. }
+            | JournalEntry::Replace { pool, .. }
+            | JournalEntry::Remove { pool } => pool,
+        }
+    }
+}
+
+// Write-ahead journal (JSONL) plus the snapshot it is compacted into
+#[derive(Debug)]
+pub struct Journal {
+    dir: PathBuf,
+    file: Mutex<File>,
+    fsync: bool,
+    pending: AtomicU64, // entries written since the last compaction
+}
+
+impl Journal {
+    pub fn open(dir: PathBuf, fsync: bool) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let file = open_append(&dir.join(JOURNAL_FILE))?;
+
+        Ok(Journal {
+            dir,
+            file: Mutex::new(file),
+            fsync,
+            pending: AtomicU64::new(0),
+        })
+    }
+
+    pub fn append(&self, entry: &JournalEntry) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        file.flush()?;
+        if self.fsync {
+            file.sync_data()?;
+        }
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Also home to the named snapshots when the journal is the backend
+    pub fn snapshot_store(&self) -> FileStore {
+        FileStore::new(self.dir.join(SNAPSHOT_FILE))
+    }
+
+    // Rebuild state: snapshot first, then any rotated journal left behind by
+    // an interrupted compaction, then the live journal.
+    pub async fn recover(&self, pools: &PoolRegistry) -> Result<usize, StorageError> {
+        if let Some(state) = self.snapshot_store().load().await? {
+            pools.restore(state).await;
+        }
+
+        let mut replayed = 0;
+        for name in [ROTATED_JOURNAL_FILE, JOURNAL_FILE] {
+            for entry in read_entries(&self.dir.join(name))? {
+                pools.apply_journal(entry).await;
+                replayed += 1;
+            }
+        }
+        Ok(replayed)
+    }
+
+    // Fold the journal into a fresh snapshot. Writers append under a shared
+    // pool lock and apply before letting go of it, so the journal is rotated
+    // with every pool locked: each rotated entry is then applied and in the
+    // snapshot. Writers wait for the in-memory copy only, not the save.
+    pub async fn compact(&self, pools: &PoolRegistry) -> Result<(), StorageError> {
+        let state = pools.snapshot_at(|| self.rotate()).await?;
+        self.snapshot_store().save(&state).await?;
+        std::fs::remove_file(self.dir.join(ROTATED_JOURNAL_FILE)).map_err(io_error)?;
+
+        Ok(())
+    }
+
+    // Move the live journal's entries to the rotated one and start afresh
+    fn rotate(&self) -> Result<(), StorageError> {
+        let live = self.dir.join(JOURNAL_FILE);
+        let rotated = self.dir.join(ROTATED_JOURNAL_FILE);
+
+        let mut file = self.file.lock().unwrap();
+        if rotated.exists() {
+            // A previous compaction did not finish, keep its entries
+            let data = std::fs::read(&live).map_err(io_error)?;
+            open_append(&rotated)
+                .and_then(|mut old| old.write_all(&data))
+                .map_err(io_error)?;
+            std::fs::remove_file(&live).map_err(io_error)?;
+        } else {
+            std::fs::rename(&live, &rotated).map_err(io_error)?;
+        }
+        *file = open_append(&live).map_err(io_error)?;
+        self.pending.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Periodically fold new journal entries into the snapshot
+    pub async fn run_compaction(
+        self: Arc<Self>,
+        pools: PoolRegistry,
+        interval: Duration,
+        health: HealthRegistry,
+    ) {
+        health.register("journal");
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+
+            if self.pending.load(Ordering::Relaxed) == 0 {
+                continue;
+            }
+            match self.compact(&pools).await {
+                Ok(()) => {
+                    tracing::debug!("Journal compacted into snapshot");
+                    health.success("journal");
+                }
+                Err(e) => {
+                    tracing::error!("Journal compaction failed: {}", e);
+                    health.failure("journal", Status::Degraded, e);
+                }
+            }
+        }
+    }
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn read_entries(path: &Path) -> Result<Vec<JournalEntry>, StorageError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(io_error(e)),
+    };
+
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(io_error)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            // A torn final write from a crash is expected, stop there
+            Err(e) => {
+                tracing::warn!("Stopping journal replay at unreadable entry: {}", e);
+                break;
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn io_error(e: std::io::Error) -> StorageError {
+    StorageError::Unavailable(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conflicts::ConflictSource;
+    use crate::ippool::{IpPool, Lease, Requested, Slot};
+    use crate::leases::{self, DhcpLease};
+    use crate::pools::DEFAULT_POOL;
+    use crate::testkit;
+    use proptest::prelude::*;
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        // Replaying the journal of any run ends where the pool did
+        #[test]
+        fn prop_replay_matches_pool(ops in testkit::ops(40)) {
+            testkit::block_on(testkit::check_journal(temp_dir("journal-prop"), &ops));
+        }
+    }
+
+    fn registry() -> PoolRegistry {
+        PoolRegistry::new(IpPool::new(
+            "172.16.0".to_string(),
+            "172.16.0.1".to_string(),
+        ))
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ippool-{}-{}", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn test_replay_after_crash() {
+        let dir = temp_dir("journal-replay");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let pools = registry();
+        let journal = Arc::new(Journal::open(dir.clone(), false).unwrap());
+        pools.attach_journal(journal.clone()).await;
+
+        let pool = pools.get(DEFAULT_POOL).await.unwrap();
+        pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        let ip = pool.allocate_ip("vm-2".to_string()).await.unwrap();
+        pool.label(
+            &ip,
+            BTreeMap::from([("project".to_string(), "payments".to_string())]),
+        )
+        .await
+        .unwrap();
+        pool.allocate_ip("vm-3".to_string()).await.unwrap();
+        pool.allocate_address(
+            "vm-3".to_string(),
+            &Slot::new(None, Some("floating".to_string())),
+            Lease::PoolDefault,
+        )
+        .await
+        .unwrap();
+        pool.release_ip("vm-1").await.unwrap();
+        // What the request sets rides in the allocation's own entry
+        let requested = Requested {
+            labels: Some(BTreeMap::from([("role".to_string(), "db".to_string())])),
+            hostname: Some("db.lab.local".to_string()),
+            mac: Some("52:54:00:12:34:56".to_string()),
+            owner: Some("alice".to_string()),
+            deadline: Some(u64::MAX),
+            ..Requested::default()
+        };
+        (pool.allocate_with(
+            None,
+            "vm-6".to_string(),
+            &Slot::primary(),
+            Lease::PoolDefault,
+            &requested,
+        ))
+        .await
+        .unwrap();
+        pool.reserve("172.16.0.53", "dns".to_string())
+            .await
+            .unwrap();
+
+        // Each conflict action is one entry, replayed as a whole
+        let conflict = Conflict {
+            source: ConflictSource::Probe,
+            detail: "answers ping".to_string(),
+            reported_by: None,
+            reported_at: 1_000,
+            investigation: None,
+        };
+        for ip in ["172.16.0.60", "172.16.0.61", "172.16.0.62", "172.16.0.63"] {
+            pool.flag_conflict(ip, conflict.clone()).await.unwrap();
+        }
+        pool.investigate_conflict("172.16.0.60", "ops looking".to_string())
+            .await
+            .unwrap();
+        pool.clear_conflict("172.16.0.61").await.unwrap();
+        pool.adopt_external("172.16.0.62", "printer".to_string())
+            .await
+            .unwrap();
+        pool.adopt_static("172.16.0.63", "vm-5").await.unwrap();
+        // An imported lease is claimed along with its hostname and MAC
+        let lease = DhcpLease {
+            ip: "172.16.0.70".parse().unwrap(),
+            mac: "52:54:00:aa:bb:70".to_string(),
+            hostname: Some("nas".to_string()),
+            expires_at: None,
+        };
+        assert_eq!(
+            leases::import(&pool, vec![lease], 0).await.imported.len(),
+            1
+        );
+
+        // IPv6 prefix delegations live with the default pool
+        let prefixes = || (0..4).map(|n| format!("2001:db8:100:{:x}::/64", n));
+        pool.delegate_prefix6("router-1", prefixes()).await.unwrap();
+        pool.delegate_prefix6("router-2", prefixes()).await.unwrap();
+        pool.release_prefix6("router-1").await.unwrap();
+        let tunnels = || (2..4).map(|n| format!("10.100.0.{}", n));
+        pool.add_wireguard_peer("key-a", Some("laptop".to_string()), tunnels())
+            .await
+            .unwrap();
+        pool.add_wireguard_peer("key-b", None, tunnels())
+            .await
+            .unwrap();
+        pool.remove_wireguard_peer("key-a").await.unwrap();
+
+        pools
+            .split(
+                DEFAULT_POOL,
+                "172.16.0.128".parse().unwrap(),
+                "upper".to_string(),
+            )
+            .await
+            .unwrap();
+        pools
+            .get("upper")
+            .await
+            .unwrap()
+            .allocate_ip("vm-4".to_string())
+            .await
+            .unwrap();
+
+        // "Crash": rebuild from disk only
+        let recovered = registry();
+        let replayed = Journal::open(dir.clone(), false)
+            .unwrap()
+            .recover(&recovered)
+            .await
+            .unwrap();
+        assert_eq!(replayed, 26);
+        assert_eq!(recovered.snapshot().await, pools.snapshot().await);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compaction() {
+        let dir = temp_dir("journal-compact");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let pools = registry();
+        let journal = Arc::new(Journal::open(dir.clone(), false).unwrap());
+        pools.attach_journal(journal.clone()).await;
+
+        let pool = pools.get(DEFAULT_POOL).await.unwrap();
+        pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        journal.compact(&pools).await.unwrap();
+        pool.allocate_ip("vm-2".to_string()).await.unwrap();
+
+        // Only the post-compaction entry is left in the journal
+        assert_eq!(read_entries(&dir.join(JOURNAL_FILE)).unwrap().len(), 1);
+        assert!(!dir.join(ROTATED_JOURNAL_FILE).exists());
+
+        let recovered = registry();
+        let replayed = Journal::open(dir.clone(), false)
+            .unwrap()
+            .recover(&recovered)
+            .await
+            .unwrap();
+        assert_eq!(replayed, 1);
+        assert_eq!(recovered.snapshot().await, pools.snapshot().await);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_compaction_during_allocations() {
+        let dir = temp_dir("journal-compact-concurrent");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let pools = registry();
+        let journal = Arc::new(Journal::open(dir.clone(), false).unwrap());
+        pools.attach_journal(journal.clone()).await;
+        let pool = pools.get(DEFAULT_POOL).await.unwrap();
+
+        // Compact back to back while writers allocate and release
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let compactor = {
+            let (journal, pools, done) = (journal.clone(), pools.clone(), done.clone());
+            tokio::spawn(async move {
+                while !done.load(Ordering::Relaxed) {
+                    journal.compact(&pools).await.unwrap();
+                }
+            })
+        };
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    for n in 0..90 {
+                        let vm_id = format!("vm-{}-{}", writer, n);
+                        pool.allocate_ip(vm_id.clone()).await.unwrap();
+                        if n % 3 != 0 {
+                            pool.release_ip(&vm_id).await.unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        compactor.await.unwrap();
+
+        // "Restart": every allocation kept is still there
+        let recovered = registry();
+        Journal::open(dir.clone(), false)
+            .unwrap()
+            .recover(&recovered)
+            .await
+            .unwrap();
+        let recovered = recovered.get(DEFAULT_POOL).await.unwrap();
+        assert_eq!(recovered.get_stats().await["allocated"], 8 * 30);
+        assert_eq!(recovered.snapshot().await, pool.snapshot().await);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
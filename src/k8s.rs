@@ -0,0 +1,526 @@
+use crate::events::{Event, EventKind};
+use crate::handlers::{JsonBody, PutAllocationRequest, put_allocation, require_fence};
+use crate::ippool::{IpAllocation, IpPoolError};
+use crate::perf::Operation;
+use crate::pools::DEFAULT_POOL;
+use crate::state::AppState;
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+// Endpoints for a Kubernetes operator reconciling IPAllocation custom
+// resources. Allocations are keyed by the resource's UID, lists carry the
+// event cursor as a resourceVersion to watch from, and deletes can wait out
+// a grace period while the operator holds its finalizer.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/v1/k8s/allocations", get(list_allocations))
+        .route(
+            "/api/v1/k8s/allocations/{uid}",
+            get(get_allocations)
+                .put(apply_allocation)
+                .delete(delete_allocations),
+        )
+}
+
+// Default and maximum time a watch waits for an event
+const DEFAULT_WATCH_TIMEOUT: u64 = 30;
+const MAX_WATCH_TIMEOUT: u64 = 300;
+
+// Most events returned by one watch call
+const WATCH_LIMIT: usize = 500;
+
+// One address of a custom resource, as the operator mirrors it into the
+// resource's status
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AllocationObject {
+    pub uid: String,
+    pub pool: String,
+    pub ip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub purpose: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>, // unix seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_release_at: Option<u64>, // unix seconds, set while a delete waits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fence_token: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ticket: Option<String>,
+}
+
+impl AllocationObject {
+    pub fn new(pool: &str, allocation: IpAllocation) -> Self {
+        AllocationObject {
+            uid: allocation.vm_id,
+            pool: pool.to_string(),
+            ip: allocation.ip,
+            interface: allocation.interface,
+            purpose: allocation.purpose,
+            hostname: allocation.hostname,
+            labels: allocation.labels,
+            expires_at: allocation.expires_at,
+            pending_release_at: allocation.pending_release_at,
+            fence_token: allocation.fence_token,
+            owner: allocation.owner,
+            notes: allocation.notes,
+            ticket: allocation.ticket,
+        }
+    }
+
+    // What an event says about an address that is no longer allocated
+    fn from_event(event: &Event) -> Self {
+        AllocationObject {
+            uid: event.vm_id.clone(),
+            pool: event.pool.clone(),
+            ip: event.ip.clone(),
+            interface: None,
+            purpose: None,
+            hostname: None,
+            labels: BTreeMap::new(),
+            expires_at: None,
+            pending_release_at: None,
+            fence_token: None,
+            owner: None,
+            notes: None,
+            ticket: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum WatchEventType {
+    Added,
+    Modified,
+    Deleted,
+}
+
+impl WatchEventType {
+    // None for events that do not change an allocation
+    fn of(kind: EventKind) -> Option<Self> {
+        match kind {
+            EventKind::Allocated => Some(WatchEventType::Added),
+            EventKind::Migrated | EventKind::Expiring => Some(WatchEventType::Modified),
+            EventKind::Released | EventKind::Expired => Some(WatchEventType::Deleted),
+            EventKind::Anomaly => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WatchEvent {
+    #[serde(rename = "type")]
+    pub kind: WatchEventType,
+    pub object: AllocationObject,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListQuery {
+    #[serde(default)]
+    pub watch: bool,
+    #[serde(default)]
+    pub resource_version: Option<String>, // watch from here, defaults to now
+    #[serde(default)]
+    pub timeout_seconds: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListResponse {
+    pub resource_version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub items: Option<Vec<AllocationObject>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub events: Option<Vec<WatchEvent>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApplyResponse {
+    pub resource_version: String,
+    pub object: AllocationObject,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteQuery {
+    #[serde(default)]
+    pub pool: Option<String>, // defaults to the default pool
+    #[serde(default)]
+    pub grace_period_seconds: Option<u64>,
+    #[serde(default)]
+    pub fence_token: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DeletePhase {
+    Releasing, // keep the finalizer and ask again after `releaseAt`
+    Released,  // nothing is held any more, the finalizer can go
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteResponse {
+    pub uid: String,
+    pub phase: DeletePhase,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_at: Option<u64>, // unix seconds
+}
+
+// Current state of an address, if `uid` still holds it in `pool`
+async fn current(state: &AppState, pool: &str, uid: &str, ip: &str) -> Option<AllocationObject> {
+    let allocations = state.pools.get(pool).await.ok()?.get_allocations(uid).await;
+    (allocations.ok()?.into_iter())
+        .find(|allocation| allocation.ip == ip)
+        .map(|allocation| AllocationObject::new(pool, allocation))
+}
+
+// Every allocation of every pool. The resourceVersion is read first, so
+// a watch from it sees whatever changes during the listing.
+async fn list(state: &AppState) -> ListResponse {
+    let resource_version = state.events.last_id();
+    let mut items = Vec::new();
+    for name in state.pools.names().await {
+        let Ok(pool) = state.pools.get(&name).await else {
+            continue;
+        };
+        items.extend(
+            (pool.list_allocations().await.into_iter())
+                .map(|allocation| AllocationObject::new(&name, allocation)),
+        );
+    }
+    items.sort_by(|a, b| (&a.pool, &a.uid, &a.ip).cmp(&(&b.pool, &b.uid, &b.ip)));
+    ListResponse {
+        resource_version: resource_version.to_string(),
+        items: Some(items),
+        events: None,
+    }
+}
+
+// Changes after `since`, waiting up to `timeout` for the first one. Objects
+// that still exist are reported as they are now.
+async fn watch(
+    state: &AppState,
+    since: u64,
+    timeout: Duration,
+) -> Result<ListResponse, IpPoolError> {
+    // Subscribing before replaying leaves no gap for an event to slip through
+    let mut receiver = state.events.subscribe();
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut since = since;
+    loop {
+        let replayed = state
+            .events
+            .replay(since, WATCH_LIMIT)
+            .await
+            .ok_or(IpPoolError::CursorExpired)?;
+        since = replayed.last().map_or(since, |event| event.id);
+
+        let mut events = Vec::new();
+        for event in &replayed {
+            let Some(kind) = WatchEventType::of(event.kind) else {
+                continue;
+            };
+            let object = match kind {
+                WatchEventType::Deleted => None,
+                _ => current(state, &event.pool, &event.vm_id, &event.ip).await,
+            };
+            events.push(WatchEvent {
+                kind,
+                object: object.unwrap_or_else(|| AllocationObject::from_event(event)),
+            });
+        }
+
+        if !events.is_empty() {
+            return Ok(ListResponse {
+                resource_version: since.to_string(),
+                items: None,
+                events: Some(events),
+            });
+        }
+        match tokio::time::timeout_at(deadline, receiver.recv()).await {
+            // Lagging only means the replay has more to catch up on
+            Ok(Ok(_)) | Ok(Err(tokio::sync::broadcast::error::RecvError::Lagged(_))) => {}
+            _ => {
+                return Ok(ListResponse {
+                    resource_version: since.to_string(),
+                    items: None,
+                    events: Some(Vec::new()),
+                });
+            }
+        }
+    }
+}
+
+// List or watch handler. A watch is a long poll: it returns as soon as
+// there are changes after `resourceVersion`, or empty after
+// `timeoutSeconds`; an expired resourceVersion (410) means relisting.
+pub async fn list_allocations(
+    State(state): State<AppState>,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<ListResponse>, IpPoolError> {
+    tracing::debug!(
+        "K8s list request - watch: {}, resource_version: {:?}",
+        query.watch,
+        query.resource_version
+    );
+
+    if !query.watch {
+        return Ok(Json(list(&state).await));
+    }
+    let since = match &query.resource_version {
+        Some(version) => version.parse().map_err(|_| IpPoolError::CursorExpired)?,
+        None => state.events.last_id(),
+    };
+    let timeout = query
+        .timeout_seconds
+        .unwrap_or(DEFAULT_WATCH_TIMEOUT)
+        .min(MAX_WATCH_TIMEOUT);
+    Ok(Json(
+        watch(&state, since, Duration::from_secs(timeout)).await?,
+    ))
+}
+
+// Addresses a custom resource holds, across pools
+pub async fn get_allocations(
+    State(state): State<AppState>,
+    Path(uid): Path<String>,
+) -> Result<Json<ListResponse>, IpPoolError> {
+    tracing::debug!("K8s get request - uid: {}", uid);
+
+    let resource_version = state.events.last_id();
+    let items: Vec<AllocationObject> = (state.pools.allocations_of(&uid).await.into_iter())
+        .map(|(pool, allocation)| AllocationObject::new(&pool, allocation))
+        .collect();
+    if items.is_empty() {
+        return Err(IpPoolError::IpNotFound);
+    }
+    Ok(Json(ListResponse {
+        resource_version: resource_version.to_string(),
+        items: Some(items),
+        events: None,
+    }))
+}
+
+// Idempotent apply handler: the create-or-get PUT keyed by the resource's
+// UID, answering with the object the operator should record. 201 when the
+// address was handed out now, 200 when the resource already had it.
+pub async fn apply_allocation(
+    State(state): State<AppState>,
+    Path(uid): Path<String>,
+    headers: HeaderMap,
+    body: Option<JsonBody<PutAllocationRequest>>,
+) -> Result<(StatusCode, Json<ApplyResponse>), Response> {
+    let (status, Json(response)) =
+        put_allocation(State(state.clone()), Path(uid.clone()), headers, body).await?;
+    let object = current(&state, &response.pool, &uid, &response.ip)
+        .await
+        .ok_or_else(|| IpPoolError::IpNotFound.into_response())?;
+    Ok((
+        status,
+        Json(ApplyResponse {
+            resource_version: state.events.last_id().to_string(),
+            object,
+        }),
+    ))
+}
+
+// Finalizer-friendly delete handler. Without a grace period the addresses
+// go now; with one they are released once it has passed, and repeating the
+// delete meanwhile reports the same deadline rather than pushing it back.
+// The finalizer can be removed once the phase is Released.
+pub async fn delete_allocations(
+    State(state): State<AppState>,
+    Path(uid): Path<String>,
+    Query(query): Query<DeleteQuery>,
+) -> Result<(StatusCode, Json<DeleteResponse>), IpPoolError> {
+    let pool_name = query.pool.unwrap_or_else(|| DEFAULT_POOL.to_string());
+    tracing::info!(
+        "K8s delete request - uid: {}, pool: {}, grace: {:?}, fence_token: {:?}",
+        uid,
+        pool_name,
+        query.grace_period_seconds,
+        query.fence_token
+    );
+    require_fence(&state, query.fence_token)?;
+
+    let pool = state.pools.get(&pool_name).await?;
+    let released = |uid| {
+        (
+            StatusCode::OK,
+            Json(DeleteResponse {
+                uid,
+                phase: DeletePhase::Released,
+                release_at: None,
+            }),
+        )
+    };
+    let held = match pool.get_allocations(&uid).await {
+        Ok(held) => held,
+        Err(IpPoolError::IpNotFound) => return Ok(released(uid)),
+        Err(e) => return Err(e),
+    };
+
+    if let Some(grace) = query.grace_period_seconds.filter(|grace| *grace > 0) {
+        if let Some(fence) = query.fence_token {
+            pool.check_fence(&uid, None, fence).await?;
+        }
+        let pending = held
+            .iter()
+            .filter_map(|allocation| allocation.pending_release_at);
+        let release_at = match pending.min() {
+            Some(release_at) => release_at,
+            None => {
+                pool.defer_release(&uid, Duration::from_secs(grace))
+                    .await?
+                    .1
+            }
+        };
+        tracing::info!(
+            "K8s release scheduled - uid: {}, release_at: {}",
+            uid,
+            release_at
+        );
+        return Ok((
+            StatusCode::ACCEPTED,
+            Json(DeleteResponse {
+                uid,
+                phase: DeletePhase::Releasing,
+                release_at: Some(release_at),
+            }),
+        ));
+    }
+
+    let started = Instant::now();
+    let result = match query.fence_token {
+        Some(fence) => pool.release_ip_fenced(&uid, fence).await,
+        None => pool.release_ip(&uid).await,
+    };
+    state.perf.observe(Operation::Release, started, &result);
+    let ips = match result {
+        Ok(ips) => ips,
+        Err(IpPoolError::IpNotFound) => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    for ip in &ips {
+        state
+            .events
+            .emit(EventKind::Released, &pool_name, &uid, ip, None)
+            .await;
+    }
+
+    tracing::info!("K8s allocations released - uid: {}, ips: {:?}", uid, ips);
+    Ok(released(uid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ippool::IpPool;
+
+    #[tokio::test]
+    async fn test_reconcile() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let state = AppState::new(pool);
+
+        let listed = list_allocations(State(state.clone()), Query(ListQuery::default()))
+            .await
+            .unwrap();
+        assert_eq!(listed.resource_version, "0");
+        assert_eq!(listed.items.as_ref().unwrap().len(), 0);
+
+        // Applying twice converges on the same address
+        let apply = || {
+            apply_allocation(
+                State(state.clone()),
+                Path("uid-1".to_string()),
+                HeaderMap::new(),
+                None,
+            )
+        };
+        let (status, created) = apply().await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, existing) = apply().await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(created.object, existing.object);
+        assert_eq!(created.object.ip, "172.16.0.2");
+
+        // A watch from the listed version sees the allocation
+        let watch = |resource_version: &str| {
+            let query = ListQuery {
+                watch: true,
+                resource_version: Some(resource_version.to_string()),
+                timeout_seconds: Some(0),
+            };
+            list_allocations(State(state.clone()), Query(query))
+        };
+        let watched = watch("0").await.unwrap();
+        let events = watched.events.as_ref().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, WatchEventType::Added);
+        assert_eq!(events[0].object, created.object);
+        let resource_version = watched.resource_version.clone();
+        assert!(
+            watch(&resource_version)
+                .await
+                .unwrap()
+                .0
+                .events
+                .unwrap()
+                .is_empty()
+        );
+        assert!(matches!(
+            watch("999").await,
+            Err(IpPoolError::CursorExpired)
+        ));
+
+        // Deleting with a grace period keeps the first deadline
+        let delete = |grace| {
+            let query = DeleteQuery {
+                grace_period_seconds: grace,
+                ..DeleteQuery::default()
+            };
+            delete_allocations(
+                State(state.clone()),
+                Path("uid-1".to_string()),
+                Query(query),
+            )
+        };
+        let (status, first) = delete(Some(60)).await.unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(first.phase, DeletePhase::Releasing);
+        let (_, second) = delete(Some(600)).await.unwrap();
+        assert_eq!(second.release_at, first.release_at);
+
+        let (status, released) = delete(None).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(released.phase, DeletePhase::Released);
+        let events = watch(&resource_version).await.unwrap().0.events.unwrap();
+        assert_eq!(events[0].kind, WatchEventType::Deleted);
+        assert_eq!(events[0].object.ip, "172.16.0.2");
+
+        // Deleting what is gone is fine, so is a repeated delete
+        let (status, _) = delete(Some(60)).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+}
@@ -0,0 +1,241 @@
+use crate::ippool::{Activity, IpAllocation, IpPool};
+use crate::sweep::SweepReport;
+use serde::Serialize;
+use std::time::Duration;
+
+// Least confidence the report lists, and the least a release acts on
+pub const LIST_CONFIDENCE: f64 = 0.5;
+pub const RELEASE_CONFIDENCE: f64 = 0.8;
+
+// Share of the confidence each signal contributes
+const IDLE: f64 = 0.3; // not renewed for the idle period
+const LONG_IDLE: f64 = 0.6; // not renewed for four idle periods
+const NEVER_RENEWED: f64 = 0.1;
+const NO_EXPIRY: f64 = 0.1; // nothing will ever reclaim it
+const UNRESPONSIVE: f64 = 0.4; // no answer in the last ping sweep
+
+// Allocation that probably outlived its VM
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct LeakCandidate {
+    pub pool: String,
+    pub ip: String,
+    pub vm_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>, // who to ask whether the VM is still needed
+    pub allocated_at: u64, // unix seconds
+    pub last_renewed_at: u64,
+    pub renewals: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    pub unresponsive: bool,
+    pub confidence: f64, // 0.0 - 1.0
+    pub reasons: Vec<String>,
+}
+
+// Scores allocations on how likely their VM is gone: holders that stopped
+// asking for their address, leases nothing will reclaim and addresses that
+// no longer answer pings
+#[derive(Debug, Clone, Copy)]
+pub struct LeakDetector {
+    idle: Duration, // without renewal before an allocation looks abandoned
+}
+
+impl Default for LeakDetector {
+    fn default() -> Self {
+        LeakDetector::new(Duration::from_secs(7 * 86400))
+    }
+}
+
+impl LeakDetector {
+    pub fn new(idle: Duration) -> Self {
+        LeakDetector {
+            idle: idle.max(Duration::from_secs(1)),
+        }
+    }
+
+    // Leak candidates of the pool, most likely first. Allocations already
+    // scheduled for release are left alone.
+    pub async fn scan(
+        &self,
+        name: &str,
+        pool: &IpPool,
+        sweep: Option<&SweepReport>,
+        now: u64,
+    ) -> Vec<LeakCandidate> {
+        let activity = pool.activity().await;
+        let mut candidates: Vec<LeakCandidate> = pool
+            .list_allocations()
+            .await
+            .iter()
+            .filter(|allocation| allocation.pending_release_at.is_none())
+            .filter_map(|allocation| {
+                let unresponsive = sweep.is_some_and(|report| {
+                    report
+                        .unresponsive
+                        .iter()
+                        .any(|u| u.ip == allocation.ip && u.vm_id == allocation.vm_id)
+                });
+                self.score(
+                    name,
+                    allocation,
+                    activity.get(&allocation.ip),
+                    unresponsive,
+                    now,
+                )
+            })
+            .collect();
+        sort(&mut candidates);
+        candidates
+    }
+
+    fn score(
+        &self,
+        pool: &str,
+        allocation: &IpAllocation,
+        activity: Option<&Activity>,
+        unresponsive: bool,
+        now: u64,
+    ) -> Option<LeakCandidate> {
+        // Allocations from before activity was tracked count as new
+        let activity = activity.copied().unwrap_or(Activity {
+            allocated_at: now,
+            renewed_at: now,
+            renewals: 0,
+            fence: 0,
+        });
+        let idle = now.saturating_sub(activity.renewed_at);
+        let period = self.idle.as_secs();
+
+        let mut confidence = 0.0;
+        let mut reasons = Vec::new();
+        if idle >= period {
+            confidence += if idle >= 4 * period { LONG_IDLE } else { IDLE };
+            reasons.push(format!("not renewed for {}", age(idle)));
+            if activity.renewals == 0 {
+                confidence += NEVER_RENEWED;
+                reasons.push("never renewed since allocation".to_string());
+            }
+            if allocation.expires_at.is_none() {
+                confidence += NO_EXPIRY;
+                reasons.push("lease never expires".to_string());
+            }
+        }
+        if unresponsive {
+            confidence += UNRESPONSIVE;
+            reasons.push("no answer in the last ping sweep".to_string());
+        }
+        if reasons.is_empty() {
+            return None;
+        }
+
+        Some(LeakCandidate {
+            pool: pool.to_string(),
+            ip: allocation.ip.clone(),
+            vm_id: allocation.vm_id.clone(),
+            owner: allocation.owner.clone(),
+            allocated_at: activity.allocated_at,
+            last_renewed_at: activity.renewed_at,
+            renewals: activity.renewals,
+            expires_at: allocation.expires_at,
+            unresponsive,
+            confidence: (confidence.min(1.0_f64) * 100.0).round() / 100.0,
+            reasons,
+        })
+    }
+}
+
+// Most likely leaks first, then by pool and address
+pub fn sort(candidates: &mut [LeakCandidate]) {
+    candidates.sort_by(|a, b| {
+        b.confidence
+            .total_cmp(&a.confidence)
+            .then_with(|| a.pool.cmp(&b.pool))
+            .then_with(|| {
+                let a = a.ip.parse::<std::net::Ipv4Addr>().ok();
+                a.cmp(&b.ip.parse().ok())
+            })
+    });
+}
+
+// "12d", "5h", "40m" or "30s"
+fn age(secs: u64) -> String {
+    match secs {
+        86400.. => format!("{}d", secs / 86400),
+        3600.. => format!("{}h", secs / 3600),
+        60.. => format!("{}m", secs / 60),
+        _ => format!("{}s", secs),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, MockClock};
+    use crate::ippool::Lease;
+    use crate::sweep::Unresponsive;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_scan_scores_idle_and_unresponsive_allocations() {
+        let clock = MockClock::new(1_000_000);
+        let pool = IpPool::new("10.0.0".to_string(), "10.0.0.1".to_string())
+            .with_clock(Arc::new(clock.clone()));
+        let detector = LeakDetector::new(Duration::from_secs(86400));
+
+        let idle = pool.allocate_ip("vm-idle".to_string()).await.unwrap();
+        let renewed = pool.allocate_ip("vm-renewed".to_string()).await.unwrap();
+        let lease = Lease::Ttl(Duration::from_secs(30 * 86400));
+        let (leased, _) = pool
+            .allocate_ip_with_lease("vm-leased".to_string(), lease)
+            .await
+            .unwrap();
+        let silent = pool.allocate_ip("vm-silent".to_string()).await.unwrap();
+
+        // Nothing is idle yet
+        let now = clock.unix_now();
+        assert!(detector.scan("default", &pool, None, now).await.is_empty());
+
+        clock.advance(Duration::from_secs(2 * 86400));
+        pool.allocate_ip("vm-renewed".to_string()).await.unwrap();
+        clock.advance(Duration::from_secs(3 * 86400));
+        pool.allocate_ip("vm-silent".to_string()).await.unwrap();
+
+        let sweep = SweepReport {
+            pool: "default".to_string(),
+            started_at: 0,
+            finished_at: 0,
+            probed: 4,
+            unresponsive: vec![Unresponsive {
+                ip: silent.clone(),
+                vm_id: "vm-silent".to_string(),
+            }],
+            rogue: Vec::new(),
+        };
+        let now = clock.unix_now();
+        let candidates = detector.scan("default", &pool, Some(&sweep), now).await;
+        let scores: Vec<(&str, f64)> = candidates
+            .iter()
+            .map(|c| (c.ip.as_str(), c.confidence))
+            .collect();
+        // 5 days idle, never renewed, no expiry: long idle + 0.1 + 0.1
+        assert_eq!(
+            scores,
+            vec![
+                (idle.as_str(), 0.8),
+                (leased.as_str(), 0.7),
+                (renewed.as_str(), 0.4),
+                (silent.as_str(), 0.4),
+            ]
+        );
+        assert_eq!(candidates[0].renewals, 0);
+        assert_eq!(candidates[0].reasons[0], "not renewed for 5d");
+        assert_eq!(candidates[2].renewals, 1);
+        assert_eq!(candidates[2].last_renewed_at, 1_000_000 + 2 * 86400);
+        assert!(candidates[3].unresponsive);
+
+        // Released addresses are no longer candidates
+        pool.release_ip("vm-idle").await.unwrap();
+        let candidates = detector.scan("default", &pool, None, now).await;
+        assert!(candidates.iter().all(|c| c.ip != idle));
+    }
+}
@@ -0,0 +1,437 @@
+use crate::ippool::{IpPool, Requested};
+use crate::slaac;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+
+// Lease file of the DHCP server a pool takes over from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LeaseFormat {
+    Dnsmasq, // dnsmasq.leases
+    Isc,     // ISC dhcpd.leases
+}
+
+impl LeaseFormat {
+    // ISC files are made of `lease <ip> { ... }` blocks, dnsmasq files of
+    // one lease per line
+    pub fn detect(contents: &str) -> Self {
+        let isc = tokenize(contents)
+            .unwrap_or_default()
+            .windows(3)
+            .any(|t| t[0] == Token::Word("lease".to_string()) && t[2] == Token::Open);
+        if isc {
+            LeaseFormat::Isc
+        } else {
+            LeaseFormat::Dnsmasq
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhcpLease {
+    pub ip: Ipv4Addr,
+    pub mac: String,
+    pub hostname: Option<String>,
+    pub expires_at: Option<u64>, // unix seconds, None for infinite leases
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportedLease {
+    pub ip: String,
+    pub vm_id: String,
+    pub mac: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SkippedLease {
+    pub ip: String,
+    pub mac: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub imported: Vec<ImportedLease>,
+    pub skipped: Vec<SkippedLease>,
+}
+
+pub fn parse(contents: &str, format: LeaseFormat) -> Result<Vec<DhcpLease>, String> {
+    match format {
+        LeaseFormat::Dnsmasq => parse_dnsmasq(contents),
+        LeaseFormat::Isc => parse_isc(contents),
+    }
+}
+
+// `<expiry> <mac> <ip> <hostname> <client-id>` per line, expiry 0 for
+// infinite leases and `*` for unknown hostnames. DHCPv6 leases (after the
+// `duid` line) are skipped.
+fn parse_dnsmasq(contents: &str) -> Result<Vec<DhcpLease>, String> {
+    let mut leases = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [] => continue,
+            ["duid", ..] => break,
+            [expiry, mac, ip, hostname, ..] => {
+                let invalid = |what: &str| format!("line {}: invalid {}", number + 1, what);
+                let expiry: u64 = expiry.parse().map_err(|_| invalid("expiry"))?;
+                leases.push(DhcpLease {
+                    ip: ip.parse().map_err(|_| invalid("IPv4 address"))?,
+                    mac: mac.to_lowercase(),
+                    hostname: (*hostname != "*").then(|| hostname.to_string()),
+                    expires_at: (expiry != 0).then_some(expiry),
+                });
+            }
+            _ => return Err(format!("line {}: expected at least 4 fields", number + 1)),
+        }
+    }
+    Ok(leases)
+}
+
+// dhcpd appends a new block whenever a lease changes, so the last block
+// for an address wins. Only leases in the active binding state count.
+fn parse_isc(contents: &str) -> Result<Vec<DhcpLease>, String> {
+    let tokens = tokenize(contents)?;
+    let mut order: Vec<Ipv4Addr> = Vec::new();
+    let mut latest: HashMap<Ipv4Addr, Option<DhcpLease>> = HashMap::new();
+
+    let mut tokens = tokens.into_iter();
+    let mut statement = Vec::new();
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Semicolon => statement.clear(),
+            Token::Open => {
+                let body = block(&mut tokens)?;
+                if let [Token::Word(keyword), Token::Word(ip)] = statement.as_slice()
+                    && keyword == "lease"
+                {
+                    let ip: Ipv4Addr = ip
+                        .parse()
+                        .map_err(|_| format!("invalid lease address {}", ip))?;
+                    if !latest.contains_key(&ip) {
+                        order.push(ip);
+                    }
+                    latest.insert(ip, isc_lease(ip, &body)?);
+                }
+                statement.clear();
+            }
+            Token::Close => return Err("unexpected `}`".to_string()),
+            token => statement.push(token),
+        }
+    }
+
+    Ok(order
+        .into_iter()
+        .filter_map(|ip| latest.remove(&ip).flatten())
+        .collect())
+}
+
+// Lease from the statements of a `lease` block, None unless it is active
+fn isc_lease(ip: Ipv4Addr, body: &[Vec<Token>]) -> Result<Option<DhcpLease>, String> {
+    let mut active = true;
+    let mut mac = None;
+    let mut hostname = None;
+    let mut expires_at = None;
+
+    for statement in body {
+        let words: Vec<&str> = statement.iter().map(Token::text).collect();
+        match words.as_slice() {
+            ["binding", "state", state] => active = *state == "active",
+            ["hardware", "ethernet", address] => mac = Some(address.to_lowercase()),
+            ["client-hostname", name] => hostname = Some(name.to_string()),
+            ["ends", "never"] => expires_at = None,
+            ["ends", "epoch", seconds] => {
+                expires_at = Some(
+                    seconds
+                        .parse()
+                        .map_err(|_| format!("lease {}: invalid end {}", ip, seconds))?,
+                )
+            }
+            ["ends", _weekday, date, time] => {
+                let ends = NaiveDateTime::parse_from_str(
+                    &format!("{} {}", date, time),
+                    "%Y/%m/%d %H:%M:%S",
+                )
+                .map_err(|_| format!("lease {}: invalid end {} {}", ip, date, time))?;
+                expires_at = Some(ends.and_utc().timestamp().max(0) as u64);
+            }
+            _ => {}
+        }
+    }
+
+    if !active {
+        return Ok(None);
+    }
+    let mac = mac.ok_or_else(|| format!("lease {}: no hardware ethernet address", ip))?;
+    Ok(Some(DhcpLease {
+        ip,
+        mac,
+        hostname,
+        expires_at,
+    }))
+}
+
+// Statements of a block whose `{` was just read; nested blocks are skipped
+fn block(tokens: &mut impl Iterator<Item = Token>) -> Result<Vec<Vec<Token>>, String> {
+    let mut statements = Vec::new();
+    let mut statement = Vec::new();
+    let mut depth = 0;
+    for token in tokens {
+        match token {
+            Token::Open => depth += 1,
+            Token::Close if depth == 0 => return Ok(statements),
+            Token::Close => depth -= 1,
+            Token::Semicolon if depth == 0 => statements.push(std::mem::take(&mut statement)),
+            token if depth == 0 => statement.push(token),
+            _ => {}
+        }
+    }
+    Err("unterminated block".to_string())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Open,
+    Close,
+    Semicolon,
+}
+
+impl Token {
+    fn text(&self) -> &str {
+        match self {
+            Token::Word(text) | Token::Quoted(text) => text,
+            Token::Open => "{",
+            Token::Close => "}",
+            Token::Semicolon => ";",
+        }
+    }
+}
+
+fn tokenize(contents: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = contents.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => while chars.next_if(|c| *c != '\n').is_some() {},
+            '{' => tokens.push(Token::Open),
+            '}' => tokens.push(Token::Close),
+            ';' => tokens.push(Token::Semicolon),
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => text.extend(chars.next()),
+                        Some(c) => text.push(c),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                tokens.push(Token::Quoted(text));
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) =
+                    chars.next_if(|c| !c.is_whitespace() && !matches!(c, '{' | '}' | ';' | '"'))
+                {
+                    word.push(c);
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// Claim every lease still valid at `now` in `pool`. VMs are named after the
+// lease hostname, or the MAC address when there is none or the hostname
+// already holds an address.
+pub async fn import(pool: &IpPool, leases: Vec<DhcpLease>, now: u64) -> ImportReport {
+    let mut report = ImportReport::default();
+    for lease in leases {
+        let ip = lease.ip.to_string();
+        let skip = |reason: String| SkippedLease {
+            ip: ip.clone(),
+            mac: lease.mac.clone(),
+            reason,
+        };
+        if lease.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            report.skipped.push(skip("lease expired".to_string()));
+            continue;
+        }
+
+        let vm_id = match &lease.hostname {
+            Some(hostname) if pool.get_allocation(hostname).await.is_err() => hostname.clone(),
+            _ => lease.mac.clone(),
+        };
+        // The MAC is kept for static mapping exports
+        let requested = Requested {
+            hostname: lease.hostname.clone(),
+            mac: slaac::canonical_mac(&lease.mac),
+            ..Requested::default()
+        };
+        match pool.claim(&ip, &vm_id, lease.expires_at, &requested).await {
+            Ok(()) => report.imported.push(ImportedLease {
+                ip,
+                vm_id,
+                mac: lease.mac,
+                hostname: lease.hostname,
+                expires_at: lease.expires_at,
+            }),
+            Err(e) => report.skipped.push(skip(e.to_string())),
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DNSMASQ: &str = "\
+1767225600 52:54:00:aa:bb:01 172.16.0.10 web-1 01:52:54:00:aa:bb:01
+0 52:54:00:AA:BB:02 172.16.0.11 * *
+1000 52:54:00:aa:bb:03 172.16.0.12 old-vm *
+duid 00:01:00:01:2c:1f:00:00:52:54:00:aa:bb:01
+1767225600 1234 fd00::10 web-1 00:01:00:01
+";
+
+    const ISC: &str = r#"
+# The format of this file is documented in the dhcpd.leases(5) manual page.
+authoring-byte-order little-endian;
+
+lease 172.16.0.10 {
+  starts 4 2026/01/01 00:00:00;
+  ends 5 2026/01/02 00:00:00;
+  binding state active;
+  next binding state free;
+  hardware ethernet 52:54:00:aa:bb:01;
+  client-hostname "web-1";
+}
+lease 172.16.0.11 {
+  ends never;
+  binding state active;
+  hardware ethernet 52:54:00:aa:bb:02;
+  option agent.circuit-id "eth0;{1}";
+}
+lease 172.16.0.12 {
+  binding state active;
+  hardware ethernet 52:54:00:aa:bb:03;
+}
+lease 172.16.0.12 {
+  binding state free;
+  hardware ethernet 52:54:00:aa:bb:03;
+}
+failover peer "dhcp" state {
+  my state normal;
+}
+"#;
+
+    #[test]
+    fn test_parse_dnsmasq() {
+        assert_eq!(LeaseFormat::detect(DNSMASQ), LeaseFormat::Dnsmasq);
+        let leases = parse(DNSMASQ, LeaseFormat::Dnsmasq).unwrap();
+        assert_eq!(
+            leases,
+            vec![
+                DhcpLease {
+                    ip: Ipv4Addr::new(172, 16, 0, 10),
+                    mac: "52:54:00:aa:bb:01".to_string(),
+                    hostname: Some("web-1".to_string()),
+                    expires_at: Some(1767225600),
+                },
+                DhcpLease {
+                    ip: Ipv4Addr::new(172, 16, 0, 11),
+                    mac: "52:54:00:aa:bb:02".to_string(),
+                    hostname: None,
+                    expires_at: None,
+                },
+                DhcpLease {
+                    ip: Ipv4Addr::new(172, 16, 0, 12),
+                    mac: "52:54:00:aa:bb:03".to_string(),
+                    hostname: Some("old-vm".to_string()),
+                    expires_at: Some(1000),
+                },
+            ]
+        );
+        assert!(parse("1767225600 52:54:00:aa:bb:01", LeaseFormat::Dnsmasq).is_err());
+    }
+
+    #[test]
+    fn test_parse_isc() {
+        assert_eq!(LeaseFormat::detect(ISC), LeaseFormat::Isc);
+        let leases = parse(ISC, LeaseFormat::Isc).unwrap();
+        assert_eq!(
+            leases,
+            vec![
+                DhcpLease {
+                    ip: Ipv4Addr::new(172, 16, 0, 10),
+                    mac: "52:54:00:aa:bb:01".to_string(),
+                    hostname: Some("web-1".to_string()),
+                    expires_at: Some(1767312000),
+                },
+                DhcpLease {
+                    ip: Ipv4Addr::new(172, 16, 0, 11),
+                    mac: "52:54:00:aa:bb:02".to_string(),
+                    hostname: None,
+                    expires_at: None,
+                },
+            ]
+        );
+        assert!(
+            parse(
+                "lease 172.16.0.10 { binding state active;",
+                LeaseFormat::Isc
+            )
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import_claims_leases() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        pool.allocate_ip("web-1".to_string()).await.unwrap();
+        let mut leases = parse(DNSMASQ, LeaseFormat::Dnsmasq).unwrap();
+        leases.push(DhcpLease {
+            ip: Ipv4Addr::new(172, 16, 0, 2),
+            mac: "52:54:00:aa:bb:04".to_string(),
+            hostname: None,
+            expires_at: None,
+        });
+
+        let report = import(&pool, leases, 2000).await;
+        let imported: Vec<(&str, &str)> = report
+            .imported
+            .iter()
+            .map(|lease| (lease.ip.as_str(), lease.vm_id.as_str()))
+            .collect();
+        // web-1 already holds an address, so its lease goes to the MAC
+        assert_eq!(
+            imported,
+            vec![
+                ("172.16.0.10", "52:54:00:aa:bb:01"),
+                ("172.16.0.11", "52:54:00:aa:bb:02"),
+            ]
+        );
+        let skipped: Vec<&str> = report.skipped.iter().map(|s| s.ip.as_str()).collect();
+        assert_eq!(skipped, vec!["172.16.0.12", "172.16.0.2"]);
+
+        let allocation = pool.get_allocation("52:54:00:aa:bb:02").await.unwrap();
+        assert_eq!(allocation.ip, "172.16.0.11");
+        assert_eq!(allocation.mac.as_deref(), Some("52:54:00:aa:bb:02"));
+        // The lease's hostname stays with its address, whatever the VM is named
+        let allocation = pool.get_allocation("52:54:00:aa:bb:01").await.unwrap();
+        assert_eq!(allocation.hostname.as_deref(), Some("web-1"));
+        // Imported addresses are no longer handed out
+        let ips = pool.next_free(20).await.unwrap();
+        assert!(!ips.contains(&"172.16.0.10".to_string()));
+        assert!(!ips.contains(&"172.16.0.11".to_string()));
+    }
+}
@@ -0,0 +1,69 @@
+pub mod api_v2;
+pub mod app;
+pub mod approvals;
+pub mod audit;
+pub mod breaker;
+pub mod budget;
+pub mod capacity;
+pub mod changes;
+pub mod check;
+pub mod clock;
+pub mod cluster;
+pub mod config;
+pub mod conflicts;
+pub mod consistency;
+pub mod consul;
+pub mod delegations;
+pub mod deprecation;
+pub mod dhcp;
+pub mod diff;
+pub mod email;
+pub mod encoding;
+pub mod etag;
+pub mod events;
+#[cfg(feature = "fault-injection")]
+pub mod faults;
+pub mod filter;
+pub mod freelist;
+pub mod gateway;
+pub mod grafana;
+pub mod graphql;
+pub mod handlers;
+pub mod health;
+pub mod hooks;
+pub mod hostnames;
+pub mod insights;
+pub mod ippool;
+pub mod journal;
+pub mod k8s;
+pub mod leaks;
+pub mod leases;
+pub mod maintenance;
+pub mod mdns;
+pub mod metrics;
+pub mod netplan;
+pub mod notify;
+pub mod overload;
+pub mod perf;
+pub mod policy;
+pub mod pools;
+pub mod prefix6;
+pub mod replication;
+pub mod reports;
+pub mod routes;
+pub mod s3;
+pub mod shared;
+pub mod slaac;
+#[cfg(feature = "snmp")]
+pub mod snmp;
+pub mod state;
+pub mod storage;
+pub mod sweep;
+pub mod syslog;
+pub mod templates;
+pub mod terraform;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testkit;
+pub mod validate;
+pub mod webhooks;
+pub mod wireguard;
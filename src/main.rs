@@ -1,15 +1,40 @@
-mod handlers;
-mod ippool;
-
-use axum::{
-    Router,
-    routing::{delete, get, post},
-};
-use ippool::IpPool;
+use ippool::app::{self, Extras, Limits};
+use ippool::approvals::ApprovalQueue;
+use ippool::audit::AuditLog;
+use ippool::breaker::{BreakerStore, CircuitBreaker};
+use ippool::budget::AllocationBudget;
+use ippool::capacity::CapacityWebhook;
+use ippool::changes::ChangeFeed;
+use ippool::cluster::Cluster;
+use ippool::config::Config;
+use ippool::consul::{ConsulClient, ConsulKvStore};
+use ippool::email::{self, EmailAlerts, Mailer};
+use ippool::events::EventBus;
+use ippool::health::HealthRegistry;
+use ippool::hostnames::HostnameTemplate;
+use ippool::insights::Insights;
+use ippool::ippool::{IpPool, IpPoolError};
+use ippool::journal::Journal;
+use ippool::leaks::LeakDetector;
+use ippool::maintenance::Maintenance;
+use ippool::notify::{ChatNotifier, Condition};
+use ippool::perf::PerfStats;
+use ippool::policy::ScriptPolicy;
+use ippool::pools::{DEFAULT_POOL, PoolRegistry};
+use ippool::prefix6::Prefix6Pool;
+use ippool::s3::{S3Client, S3Snapshots};
+use ippool::shared::SharedStorage;
+use ippool::slaac::Slaac;
+use ippool::state::AppState;
+use ippool::storage::{self, FileStore, StateStore};
+use ippool::sweep::{self, Sweeps};
+use ippool::templates::{self, Templates};
+use ippool::webhooks::{self, Signer, Webhook, Webhooks};
+use ippool::wireguard::WireGuardPool;
+use ippool::{consistency, gateway, mdns, replication, reports, syslog, validate};
 use std::net::SocketAddr;
-use tower_http::LatencyUnit;
-use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer};
-use tracing::Level;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() {
@@ -19,44 +44,638 @@ async fn main() {
         .compact()
         .init();
 
-    // Create IP pool with hardcoded values
-    let network = "172.16.0".to_string();
-    let gateway = "172.16.0.1".to_string();
-    let pool = IpPool::new(network, gateway);
+    let config = Config::from_env();
+    let health = HealthRegistry::default();
+
+    // Optional Slack/Discord notifications for critical conditions
+    let chat = config.chat.as_ref().map(|chat| {
+        tracing::info!(
+            "💬 Chat notifications enabled (usage threshold {}%, at most one per {}s per condition)",
+            chat.usage_threshold,
+            chat.rate_limit_secs
+        );
+        ChatNotifier::new(
+            chat.url.clone(),
+            chat.kind,
+            chat.template.clone(),
+            Duration::from_secs(chat.rate_limit_secs),
+            chat.usage_threshold,
+        )
+    });
+
+    // Refuse networks and gateways that would give an unusable pool
+    let configured = std::iter::once((DEFAULT_POOL, &config.network, &config.gateway)).chain(
+        config
+            .extra_pools
+            .iter()
+            .map(|extra| (extra.name.as_str(), &extra.network, &extra.gateway)),
+    );
+    for (name, network, gateway) in configured {
+        let errors = validate::network_errors(name, network, gateway, config.allow_public_networks);
+        if !errors.is_empty() {
+            panic!(
+                "Invalid configuration of pool '{}': {}",
+                name,
+                errors.join("; ")
+            );
+        }
+    }
+
+    // Create IP pool
+    let pool = IpPool::new(config.network.clone(), config.gateway.clone());
 
     tracing::info!(
-        "🌐 IP Pool initialized: {}.0/24 (Gateway: {})",
+        "🌐 IP Pool initialized: {} (Gateway: {})",
         pool.get_network().await,
         pool.get_gateway().await
     );
 
-    // Build application routes
-    let app = Router::new()
-        // Health check
-        .route("/api/v1/health", get(handlers::health_check))
-        // IP management - IMPORTANT: Specific routes first, wildcard routes last
-        .route("/api/v1/ip/allocate", post(handlers::allocate_ip))
-        .route("/api/v1/ip/allocations", get(handlers::list_allocations))
-        .route("/api/v1/ip/stats", get(handlers::get_stats))
-        .route("/api/v1/ip/release/{vm_id}", delete(handlers::release_ip))
-        .route(
-            "/api/v1/ip/release-by-ip/{ip}",
-            delete(handlers::release_ip_by_address),
-        )
-        .route("/api/v1/ip/{vm_id}", get(handlers::get_allocation))
-        .with_state(pool)
-        .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
-                .on_response(
-                    DefaultOnResponse::new()
-                        .level(Level::INFO)
-                        .latency_unit(LatencyUnit::Millis),
+    reserve_addresses(&pool, DEFAULT_POOL, &config.reserved, chat.as_ref()).await;
+
+    // Additional named pools
+    let pools = PoolRegistry::new(pool.clone());
+    for extra in &config.extra_pools {
+        let extra_pool = IpPool::new(extra.network.clone(), extra.gateway.clone());
+        reserve_addresses(&extra_pool, &extra.name, &extra.reserved, chat.as_ref()).await;
+        pools.insert(extra.name.clone(), extra_pool).await;
+        tracing::info!(
+            "🌐 Pool '{}' initialized: {}.0/24 (Gateway: {})",
+            extra.name,
+            extra.network,
+            extra.gateway
+        );
+    }
+
+    if config.replica_of.is_some() && config.cluster.is_some() {
+        panic!("REPLICA_OF and CLUSTER_PEERS cannot be combined");
+    }
+    if config.shared_storage && (config.replica_of.is_some() || config.cluster.is_some()) {
+        panic!("SHARED_STORAGE cannot be combined with REPLICA_OF or CLUSTER_PEERS");
+    }
+
+    // Read replicas take their state from the primary and keep none of their own
+    let replica = config.replica_of.is_some();
+    if replica
+        && (config.journal.is_some()
+            || config.state_file.is_some()
+            || config.consul.as_ref().is_some_and(|c| c.kv_key.is_some()))
+    {
+        tracing::warn!("Read replica: ignoring the configured storage backend");
+    }
+
+    // Optional Consul service registration and KV state backend
+    let mut store: Option<Arc<dyn StateStore>> = None;
+    if let Some(consul) = &config.consul {
+        let client = ConsulClient::new(consul.addr.clone(), consul.token.clone());
+
+        if consul.register {
+            match client
+                .register_service(&consul.service_name, &consul.service_address, config.port)
+                .await
+            {
+                Ok(()) => tracing::info!(
+                    "📇 Registered '{}' in Consul at {}",
+                    consul.service_name,
+                    consul.addr
                 ),
+                Err(e) => tracing::warn!("Consul service registration failed: {}", e),
+            }
+        }
+
+        if let Some(key) = &consul.kv_key
+            && !replica
+        {
+            store = Some(Arc::new(ConsulKvStore::new(client, key.clone())));
+        }
+    }
+
+    // Write-ahead journal: replay what was recorded since the last snapshot
+    let mut journaled = false;
+    let mut journal_snapshots = None;
+    if store.is_none()
+        && !replica
+        && let Some(journal_config) = &config.journal
+    {
+        let journal = Journal::open(journal_config.dir.clone().into(), journal_config.fsync)
+            .expect("Failed to open journal");
+        match journal.recover(&pools).await {
+            Ok(replayed) => tracing::info!(
+                "📓 Journal recovered from {} ({} entries replayed)",
+                journal_config.dir,
+                replayed
+            ),
+            Err(e) => panic!(
+                "Failed to recover journal from {}: {}",
+                journal_config.dir, e
+            ),
+        }
+        // Repairs are saved by the compaction below
+        consistency::check_on_startup(&pools, config.consistency_check).await;
+
+        let journal = Arc::new(journal);
+        journal_snapshots = Some(Arc::new(journal.snapshot_store()) as Arc<dyn StateStore>);
+        pools.attach_journal(journal.clone()).await;
+        // Start from a compact snapshot so the next replay is short
+        if let Err(e) = journal.compact(&pools).await {
+            tracing::warn!("Initial journal compaction failed: {}", e);
+        }
+        tokio::spawn(journal.run_compaction(
+            pools.clone(),
+            Duration::from_secs(journal_config.compact_interval_secs.max(1)),
+            health.clone(),
+        ));
+        journaled = true;
+    }
+
+    // Local state file (e.g. on the /data volume) when no other backend is set
+    if store.is_none()
+        && !journaled
+        && !replica
+        && let Some(path) = &config.state_file
+    {
+        store = Some(Arc::new(FileStore::new(path.into())));
+    }
+
+    // Fail writes fast instead of hanging while the backend is down
+    if journaled || store.is_some() {
+        let breaker = CircuitBreaker::new(
+            config.storage_breaker_threshold,
+            Duration::from_secs(config.storage_breaker_cooldown_secs),
+        );
+        pools.attach_breaker(breaker.clone()).await;
+        store =
+            store.map(|store| Arc::new(BreakerStore::new(store, breaker)) as Arc<dyn StateStore>);
+    }
+    // Named snapshots go wherever the state goes
+    let snapshot_store = store.clone().or(journal_snapshots);
+
+    // Allocation policy script, consulted for every new allocation
+    let policy = config.policy_script.as_ref().map(|path| {
+        let policy = ScriptPolicy::load(path.into())
+            .unwrap_or_else(|e| panic!("Failed to load policy script: {}", e));
+        tracing::info!("📜 Allocation policy loaded from {}", path);
+        Arc::new(policy)
+    });
+    if let Some(policy) = &policy {
+        pools.attach_policy(policy.clone()).await;
+    }
+
+    // Pool templates for POST /api/v1/pools/from-template
+    let templates = match &config.pool_templates_file {
+        Some(path) => {
+            let templates = templates::load(path.as_ref())
+                .unwrap_or_else(|e| panic!("Failed to load pool templates: {}", e));
+            tracing::info!("🧩 {} pool templates loaded from {}", templates.len(), path);
+            templates
+        }
+        None => Templates::new(),
+    };
+
+    // Instances sharing the backend serialize their writes with its lock
+    let shared = match &store {
+        Some(store) if config.shared_storage => {
+            tracing::info!(
+                "🔒 Sharing the {} backend with other instances",
+                store.name()
+            );
+            Some(SharedStorage::new(store.clone(), pools.clone()))
+        }
+        None if config.shared_storage => {
+            tracing::warn!("Ignoring SHARED_STORAGE: it needs CONSUL_KV_KEY or STATE_FILE");
+            None
+        }
+        _ => None,
+    };
+
+    if let Some(store) = &store {
+        match storage::load_into(store.as_ref(), &pools).await {
+            Ok(true) => tracing::info!("💾 Pool state loaded from {} backend", store.name()),
+            Ok(false) => tracing::info!("💾 No saved state in {} backend yet", store.name()),
+            Err(e) => panic!("Failed to load pool state from {}: {}", store.name(), e),
+        }
+        if consistency::check_on_startup(&pools, config.consistency_check).await
+            && let Err(e) = store.save(&pools.snapshot().await).await
+        {
+            tracing::warn!("Failed to save the repaired state: {}", e);
+        }
+        let interval = Duration::from_millis(config.save_interval_ms);
+        match &shared {
+            Some(shared) => {
+                tokio::spawn(shared.clone().run_refresh(interval, health.clone()));
+            }
+            None => {
+                tokio::spawn(storage::run_persister(
+                    store.clone(),
+                    pools.clone(),
+                    interval,
+                    health.clone(),
+                ));
+            }
+        }
+    }
+
+    // Configured routes, DHCP options and VLANs win over persisted ones
+    pool.set_routes(config.routes.clone()).await;
+    pool.set_dhcp_options(config.dhcp_options.clone()).await;
+    pool.set_lease_ttl(config.lease_ttl).await;
+    pool.set_expiry_warning(config.lease_expiry_warning).await;
+    let hostname_template = |template: &Option<String>| {
+        template.as_ref().map(|template| {
+            template
+                .parse::<HostnameTemplate>()
+                .unwrap_or_else(|e| panic!("Invalid hostname template: {}", e))
+        })
+    };
+    pool.set_hostname_template(hostname_template(&config.hostname_template))
+        .await;
+    if let Some(vlan_id) = config.vlan_id {
+        pool.set_vlan_id(Some(vlan_id)).await;
+    }
+    let slaac = |prefix: &Option<String>| {
+        prefix.as_ref().map(|prefix| {
+            Slaac::new(prefix, config.ipv6_mode, config.ipv6_secret.clone())
+                .unwrap_or_else(|e| panic!("Invalid IPv6 prefix: {}", e))
+        })
+    };
+    pool.set_slaac(slaac(&config.ipv6_prefix)).await;
+    for extra in &config.extra_pools {
+        if let Ok(extra_pool) = pools.get(&extra.name).await {
+            extra_pool.set_routes(extra.routes.clone()).await;
+            extra_pool
+                .set_dhcp_options(extra.dhcp_options.clone())
+                .await;
+            extra_pool.set_lease_ttl(extra.lease_ttl).await;
+            extra_pool
+                .set_expiry_warning(config.lease_expiry_warning)
+                .await;
+            extra_pool
+                .set_hostname_template(hostname_template(&extra.hostname_template))
+                .await;
+            if let Some(vlan_id) = extra.vlan_id {
+                extra_pool.set_vlan_id(Some(vlan_id)).await;
+            }
+            extra_pool.set_slaac(slaac(&extra.ipv6_prefix)).await;
+        }
+    }
+
+    // Optional scheduled snapshots to S3-compatible storage
+    let s3_snapshots = config.s3.as_ref().map(|s3| {
+        let client = S3Client::new(
+            &s3.endpoint,
+            s3.bucket.clone(),
+            s3.region.clone(),
+            s3.access_key.clone(),
+            s3.secret_key.clone(),
+        )
+        .expect("Invalid S3 configuration");
+        let snapshots = S3Snapshots::new(client, s3.prefix.clone(), s3.retain, pools.clone());
+
+        tracing::info!(
+            "📦 S3 snapshots enabled: {}/{}{} every {}s (keeping {})",
+            s3.endpoint,
+            s3.bucket,
+            s3.prefix,
+            s3.interval_secs,
+            s3.retain
+        );
+        tokio::spawn(
+            snapshots
+                .clone()
+                .run_schedule(Duration::from_secs(s3.interval_secs.max(1)), health.clone()),
+        );
+        snapshots
+    });
+
+    // Events kept for listing and replay, optionally across restarts
+    let events = match &config.event_store_file {
+        Some(path) => {
+            let events = EventBus::with_store(path.into(), config.event_retain)
+                .expect("Failed to open event store");
+            tracing::info!(
+                "📼 Event store: {} (keeping {} events, last id {})",
+                path,
+                config.event_retain,
+                events.last_id()
+            );
+            events
+        }
+        None => EventBus::with_retain(config.event_retain),
+    };
+
+    // Optional local audit trail with size-based rotation
+    if let Some(audit) = &config.audit_log {
+        let log = AuditLog::open(audit.path.clone().into(), audit.max_bytes, audit.retain)
+            .expect("Failed to open audit log");
+        tracing::info!(
+            "📝 Audit log: {} (rotating at {} bytes, keeping {})",
+            audit.path,
+            audit.max_bytes,
+            audit.retain
+        );
+        tokio::spawn(log.run(events.clone(), health.clone()));
+    }
+
+    // Optional syslog output, for a central collector of network changes
+    if let Some(syslog) = &config.syslog {
+        let target = syslog::Target::parse(&syslog.url)
+            .unwrap_or_else(|e| panic!("Invalid SYSLOG_URL: {}", e));
+        tracing::info!(
+            "📜 Sending events to syslog at {} (facility {})",
+            target,
+            syslog.facility
         );
+        tokio::spawn(syslog::SyslogOutput::new(target, syslog).run(events.clone(), health.clone()));
+    }
+
+    // Flag callers that leak addresses or thrash, as anomaly events
+    let insights = Insights::new(config.anomalies);
+    tokio::spawn(insights.clone().run(events.clone()));
+
+    // Webhook payloads are signed when WEBHOOK_SECRET is set
+    let signer = config.webhook_secret.as_deref().map(Signer::new);
+    let webhook_registry = Webhooks::default();
+
+    // Optional webhook receiving every allocation event
+    if let Some(url) = &config.event_webhook_url {
+        tracing::info!(
+            "🪝 Event webhook: {}{}",
+            url,
+            if signer.is_some() { " (signed)" } else { "" }
+        );
+        let webhook = Webhook::new(webhooks::EVENTS_WEBHOOK, url.clone(), signer.clone())
+            .with_retries(config.webhook_max_attempts, Duration::from_secs(1));
+        webhook_registry.add(webhook.clone());
+        tokio::spawn(webhooks::forward_events(webhook, events.clone()));
+    }
+
+    // Optional "expand capacity" webhook for automation
+    if let Some(capacity) = &config.capacity_webhook {
+        tracing::info!(
+            "📈 Capacity webhook: {} (below {} available addresses)",
+            capacity.url,
+            capacity.watermark
+        );
+        let webhook = Webhook::new(
+            webhooks::CAPACITY_WEBHOOK,
+            capacity.url.clone(),
+            signer.clone(),
+        )
+        .with_retries(config.webhook_max_attempts, Duration::from_secs(1));
+        webhook_registry.add(webhook.clone());
+        let webhook = CapacityWebhook::new(webhook, capacity.watermark);
+        tokio::spawn(webhook.run(pools.clone(), events.clone()));
+    }
+
+    // Optional scheduled utilization report
+    if let Some(report) = &config.report_webhook {
+        tracing::info!(
+            "📊 Report webhook: {} (every {}s, covering {}s)",
+            report.url,
+            report.interval_secs,
+            report.window_secs
+        );
+        let webhook = Webhook::new(
+            webhooks::REPORTS_WEBHOOK,
+            report.url.clone(),
+            signer.clone(),
+        )
+        .with_retries(config.webhook_max_attempts, Duration::from_secs(1));
+        webhook_registry.add(webhook.clone());
+        tokio::spawn(reports::run(
+            webhook,
+            Duration::from_secs(report.interval_secs),
+            Duration::from_secs(report.window_secs),
+            pools.clone(),
+            events.clone(),
+        ));
+    }
+
+    if let Some(chat) = chat {
+        tokio::spawn(chat.run(pools.clone(), events.clone()));
+    }
+
+    // Optional alert emails and daily digest through an SMTP relay
+    if let Some(email) = &config.email {
+        tracing::info!(
+            "📧 Alert emails via {}:{} to {} (digest: {})",
+            email.smtp_host,
+            email.smtp_port,
+            email.to.join(", "),
+            email
+                .digest_hour
+                .map_or("off".to_string(), |hour| format!("{:02}:00 UTC", hour))
+        );
+        let mailer = Mailer::new(
+            email.smtp_host.clone(),
+            email.smtp_port,
+            email.from.clone(),
+            email.to.clone(),
+        );
+        tokio::spawn(EmailAlerts::new(mailer.clone()).run(pools.clone(), events.clone()));
+        if let Some(hour) = email.digest_hour {
+            tokio::spawn(email::run_digest(mailer, hour, pools.clone()));
+        }
+    }
+
+    let maintenance = match &config.replica_of {
+        Some(primary) => Maintenance::new(config.maintenance.clone()).replica_of(primary),
+        None => Maintenance::new(config.maintenance.clone()),
+    };
+
+    // Embedded consensus: the elected leader takes the writes
+    let cluster = config.cluster.as_ref().map(|cluster| {
+        Cluster::new(
+            cluster.node_id.clone(),
+            cluster.nodes.clone(),
+            pools.clone(),
+            maintenance.clone(),
+            health.clone(),
+            cluster.state_file.clone().into(),
+        )
+        .unwrap_or_else(|e| panic!("Failed to load {}: {}", cluster.state_file, e))
+    });
+    if let Some(cluster) = &cluster {
+        tokio::spawn(cluster.clone().run());
+    }
+
+    // Reclaim addresses whose lease ran out; replicas see the primary's
+    // reclaims instead, cluster followers the leader's
+    if let Some(primary) = &config.replica_of {
+        tracing::info!("🪞 Read replica of {}", primary);
+        tokio::spawn(replication::follow(
+            primary.clone(),
+            pools.clone(),
+            health.clone(),
+        ));
+    } else if let Some(shared) = &shared {
+        tokio::spawn(shared.clone().run_lease_expiry(
+            events.clone(),
+            Duration::from_secs(config.lease_expiry_interval_secs.max(1)),
+            health.clone(),
+        ));
+    } else if let Some(cluster) = &cluster {
+        tokio::spawn(cluster.clone().run_lease_expiry(
+            events.clone(),
+            Duration::from_secs(config.lease_expiry_interval_secs.max(1)),
+        ));
+    } else {
+        tokio::spawn(pools.clone().run_lease_expiry(
+            events.clone(),
+            Duration::from_secs(config.lease_expiry_interval_secs.max(1)),
+            health.clone(),
+        ));
+    }
+
+    // Optional gateway reachability self-check, at startup and periodically
+    if let Some(check) = &config.gateway_check {
+        let timeout = Duration::from_secs(check.timeout_secs);
+        gateway::check_gateways(&pools, &health, |address| gateway::probe(address, timeout)).await;
+        if check.interval_secs > 0 {
+            tokio::spawn(gateway::run_gateway_check(
+                pools.clone(),
+                health.clone(),
+                Duration::from_secs(check.interval_secs),
+                timeout,
+            ));
+        }
+    }
+
+    // Ping sweep audit, on demand and optionally on a schedule
+    let sweeps = Sweeps::new(
+        Duration::from_secs(config.ping_sweep.timeout_secs),
+        config.ping_sweep.concurrency,
+    );
+    if config.ping_sweep.interval_secs > 0 && !replica {
+        tokio::spawn(sweep::run_ping_sweep(
+            pools.clone(),
+            sweeps.clone(),
+            Duration::from_secs(config.ping_sweep.interval_secs),
+        ));
+    }
+
+    // Optional mDNS/DNS-SD announcement, for agents discovering the service
+    if let Some(announce) = &config.mdns {
+        let address = match &announce.address {
+            Some(address) => address
+                .parse()
+                .unwrap_or_else(|e| panic!("Invalid MDNS_ADDRESS: {}", e)),
+            None => mdns::default_address()
+                .unwrap_or_else(|e| panic!("No address to announce over mDNS: {}", e)),
+        };
+        match mdns::bind(address) {
+            Ok(socket) => {
+                tracing::info!(
+                    "📣 Announcing '{}' as {} on {}.local ({}) every {}s",
+                    announce.instance,
+                    mdns::SERVICE_TYPE,
+                    announce.host,
+                    address,
+                    announce.interval_secs
+                );
+                tokio::spawn(mdns::run(
+                    socket,
+                    announce.clone(),
+                    address,
+                    config.port,
+                    pools.clone(),
+                ));
+            }
+            Err(e) => tracing::warn!("mDNS announcement disabled: {}", e),
+        }
+    }
+
+    // Optional read-only SNMP agent, for NOC tooling that only polls SNMP
+    #[cfg(feature = "snmp")]
+    if let Some(agent) = &config.snmp {
+        let base = ippool::snmp::parse_oid(&agent.base_oid)
+            .unwrap_or_else(|| panic!("Invalid SNMP_BASE_OID: {}", agent.base_oid));
+        match tokio::net::UdpSocket::bind(&agent.bind).await {
+            Ok(socket) => {
+                tracing::info!(
+                    "📟 SNMP agent listening on udp://{} (pool table under {})",
+                    agent.bind,
+                    agent.base_oid
+                );
+                tokio::spawn(ippool::snmp::run(
+                    socket,
+                    agent.clone(),
+                    base,
+                    pools.clone(),
+                ));
+            }
+            Err(e) => tracing::warn!("SNMP agent disabled: {}", e),
+        }
+    }
+
+    let state = AppState {
+        pool,
+        pools,
+        events,
+        perf: PerfStats::default(),
+        health,
+        maintenance: maintenance.clone(),
+        sweeps,
+        snapshot_store,
+        templates: Arc::new(templates),
+        webhooks: webhook_registry,
+        insights: insights.clone(),
+        leaks: LeakDetector::new(Duration::from_secs(config.leak_idle_secs)),
+        require_fence: config.require_fence_tokens,
+        changes: ChangeFeed::default(),
+        approvals: ApprovalQueue::new(config.approval_pools.clone()),
+        budget: config.allocation_budget.as_ref().map(|budget| {
+            tracing::info!(
+                "🪙 Allocation budget: {} new allocations per API key every {}s",
+                budget.limit,
+                budget.window_secs
+            );
+            AllocationBudget::new(budget.limit, Duration::from_secs(budget.window_secs))
+        }),
+    };
+    if let Some(reason) = &config.maintenance {
+        tracing::warn!("🚧 Starting in read-only maintenance mode: {}", reason);
+    }
+
+    // Optional IPv6 prefix delegation, kept with the default pool
+    let prefix6 = config.prefix6.as_ref().map(|prefix6| {
+        let pool = Prefix6Pool::new(&prefix6.network, prefix6.prefix_len, state.pool.clone())
+            .unwrap_or_else(|e| panic!("Invalid PREFIX6_NETWORK: {}", e));
+        tracing::info!(
+            "🌐 IPv6 prefix delegation enabled: /{} prefixes from {}",
+            prefix6.prefix_len,
+            prefix6.network
+        );
+        pool
+    });
+
+    // Optional WireGuard peer address pool
+    let wireguard = config
+        .wireguard
+        .as_ref()
+        .map(|wg| WireGuardPool::new(wg.network.clone(), wg.server_ip.clone(), state.pool.clone()));
+    if let Some(wg) = &config.wireguard {
+        tracing::info!(
+            "🔐 WireGuard mode enabled: {}.0/24 (Server: {})",
+            wg.network,
+            wg.server_ip
+        );
+    }
+
+    let app = app::router(
+        state,
+        Extras {
+            wireguard,
+            prefix6,
+            policy,
+            s3_snapshots,
+            cluster,
+            shared,
+        },
+        &Limits::from_config(&config),
+    );
 
     // Configure server address
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8090));
+    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
     tracing::info!("🚀 IP Pool API server starting on {}", addr);
 
     // Start the server
@@ -66,7 +685,44 @@ async fn main() {
 
     tracing::info!("✅ Server listening on http://{}", addr);
 
+    // Co-located agents can talk to the allocator without a network port
+    if let Some(unix) = &config.unix_socket {
+        let unix_listener = app::bind_unix_socket(&unix.path, unix.mode)
+            .unwrap_or_else(|e| panic!("Failed to bind to unix socket {}: {}", unix.path, e));
+        tracing::info!("✅ Server listening on unix:{}", unix.path);
+        let app = app.clone();
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(unix_listener, app).await {
+                tracing::error!("Unix socket server failed: {}", e);
+            }
+        });
+    }
+
     axum::serve(listener, app)
         .await
         .expect("Server failed to start");
 }
+
+// Apply configured well-known addresses, skipping invalid entries
+async fn reserve_addresses(
+    pool: &IpPool,
+    name: &str,
+    reserved: &[(String, String)],
+    chat: Option<&ChatNotifier>,
+) {
+    for (label, ip) in reserved {
+        match pool.reserve(ip, label.clone()).await {
+            Ok(()) => tracing::info!("📌 Reserved {} ({})", ip, label),
+            Err(e) => {
+                tracing::warn!("Cannot reserve {} ({}): {}", ip, label, e);
+                if e == IpPoolError::IpInUse
+                    && let Some(chat) = chat
+                {
+                    let message =
+                        format!("reserved address {} ({}) is allocated to a VM", ip, label);
+                    chat.raise(Condition::Conflict, name, &message).await;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use crate::api_v2::Problem;
+use crate::ippool::IpPoolError;
+use axum::{
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::{Arc, RwLock};
+
+// Reason given when maintenance mode is switched on without one
+pub const DEFAULT_REASON: &str = "scheduled maintenance";
+
+// POST endpoints that only read, or that are needed during maintenance
+// (switching it off, taking a backup); GraphQL checks its mutations itself
+const ALLOWED_WRITES: [&str; 13] = [
+    "/api/v1/admin/maintenance/enable",
+    "/api/v1/admin/maintenance/disable",
+    "/api/v1/admin/faults",
+    "/api/v1/admin/config/validate",
+    "/api/v1/admin/snapshots/diff",
+    "/api/v1/admin/policy/reload",
+    "/api/v1/admin/s3-snapshot",
+    "/api/v1/admin/snapshots",
+    "/api/v1/grafana/search",
+    "/api/v1/grafana/query",
+    "/api/v1/cluster/vote",
+    "/api/v1/cluster/append",
+    "/graphql",
+];
+
+// Global read-only switch for backup/restore windows and migrations.
+// While on, every request that would change state is refused with 503.
+// Read replicas refuse them for good, pointing at the primary; cluster
+// followers for as long as they follow, pointing at the leader.
+#[derive(Debug, Clone, Default)]
+pub struct Maintenance {
+    reason: Arc<RwLock<Option<String>>>, // None: writes allowed
+    redirect: Arc<RwLock<Option<IpPoolError>>>, // where writes go instead
+}
+
+impl Maintenance {
+    pub fn new(reason: Option<String>) -> Self {
+        Maintenance {
+            reason: Arc::new(RwLock::new(reason)),
+            redirect: Arc::default(),
+        }
+    }
+
+    // Read replica of `primary`, which takes the writes
+    pub fn replica_of(self, primary: &str) -> Self {
+        self.redirect(Some(IpPoolError::ReadReplica(primary.to_string())));
+        self
+    }
+
+    // Refuse writes with `error` regardless of the switch, or take them
+    // again with None
+    pub fn redirect(&self, error: Option<IpPoolError>) {
+        *self.redirect.write().unwrap() = error;
+    }
+
+    pub fn enable(&self, reason: String) {
+        *self.reason.write().unwrap() = Some(reason);
+    }
+
+    pub fn disable(&self) {
+        *self.reason.write().unwrap() = None;
+    }
+
+    pub fn reason(&self) -> Option<String> {
+        self.reason.read().unwrap().clone()
+    }
+
+    pub fn check(&self) -> Result<(), IpPoolError> {
+        if let Some(error) = self.redirect.read().unwrap().clone() {
+            return Err(error);
+        }
+        match self.reason() {
+            Some(reason) => Err(IpPoolError::ReadOnly(reason)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn is_write(method: &Method, path: &str) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+        && !ALLOWED_WRITES.contains(&path)
+}
+
+// Refuse writes while maintenance mode is on, in the error format of the
+// API version being called
+pub async fn enforce(
+    State(maintenance): State<Maintenance>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if is_write(request.method(), request.uri().path())
+        && let Err(e) = maintenance.check()
+    {
+        if request.uri().path().starts_with("/api/v2/") {
+            return Problem::from(e).into_response();
+        }
+        return e.into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        Router,
+        http::StatusCode,
+        middleware,
+        routing::{get, post},
+    };
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_writes_refused_during_maintenance() {
+        let maintenance = Maintenance::default();
+        let app = Router::new()
+            .route("/api/v1/ip/allocate", post(|| async { "allocated" }))
+            .route("/api/v2/allocations", post(|| async { "allocated" }))
+            .route("/api/v1/ip/stats", get(|| async { "stats" }))
+            .route(
+                "/api/v1/admin/maintenance/disable",
+                post(|| async { "disabled" }),
+            )
+            .layer(middleware::from_fn_with_state(maintenance.clone(), enforce));
+
+        let status = |method: Method, uri: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(axum::body::Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap() }
+        };
+
+        let response = status(Method::POST, "/api/v1/ip/allocate").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        maintenance.enable("restoring backup".to_string());
+        let response = status(Method::POST, "/api/v1/ip/allocate").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let response = status(Method::POST, "/api/v2/allocations").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers()[axum::http::header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+
+        // Reads and the switch itself keep working
+        let response = status(Method::GET, "/api/v1/ip/stats").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = status(Method::POST, "/api/v1/admin/maintenance/disable").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        maintenance.disable();
+        let response = status(Method::POST, "/api/v1/ip/allocate").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Replicas never take writes, whatever the switch says
+        let replica = Maintenance::default().replica_of("http://primary:8090");
+        replica.disable();
+        assert_eq!(
+            replica.check(),
+            Err(IpPoolError::ReadReplica("http://primary:8090".to_string()))
+        );
+    }
+}
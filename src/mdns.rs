@@ -0,0 +1,344 @@
+use crate::config::MdnsConfig;
+use crate::pools::PoolRegistry;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+// DNS-SD service type the allocator is announced as
+pub const SERVICE_TYPE: &str = "_ippool._tcp.local";
+// Lists the service types announced on the segment
+const SERVICES: &str = "_services._dns-sd._udp.local";
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+// Record lifetime, what RFC 6762 recommends for records with a host name
+const TTL: u32 = 120;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+// Unique records replace what caches hold for the name
+const CACHE_FLUSH: u16 = 0x8000;
+
+// Network and gateway of a pool, published in the TXT record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolRecord {
+    pub name: String,
+    pub network: String, // "172.16.0.0/24"
+    pub gateway: String,
+}
+
+// The service instance and records announced on the local segment
+#[derive(Debug, Clone)]
+pub struct Announcement {
+    pub instance: String, // instance label, e.g. "ippool"
+    pub host: String,     // e.g. "hv1.local"
+    pub address: Ipv4Addr,
+    pub port: u16,
+    pub pools: Vec<PoolRecord>,
+}
+
+impl Announcement {
+    pub async fn new(
+        config: &MdnsConfig,
+        address: Ipv4Addr,
+        port: u16,
+        pools: &PoolRegistry,
+    ) -> Self {
+        let mut records = Vec::new();
+        for name in pools.names().await {
+            let Ok(pool) = pools.get(&name).await else {
+                continue;
+            };
+            let snapshot = pool.snapshot().await;
+            records.push(PoolRecord {
+                name,
+                network: format!("{}/{}", snapshot.network, snapshot.prefix_len),
+                gateway: snapshot.gateway,
+            });
+        }
+        Announcement {
+            instance: config.instance.clone(),
+            host: format!("{}.local", config.host),
+            address,
+            port,
+            pools: records,
+        }
+    }
+
+    fn instance_name(&self) -> Vec<&str> {
+        let mut name = vec![self.instance.as_str()];
+        name.extend(SERVICE_TYPE.split('.'));
+        name
+    }
+
+    // "key=value" TXT strings: the API path, the pool names and each pool's
+    // network and gateway
+    pub fn txt(&self) -> Vec<String> {
+        let names: Vec<&str> = self.pools.iter().map(|pool| pool.name.as_str()).collect();
+        let mut txt = vec![
+            "txtvers=1".to_string(),
+            "path=/api/v1".to_string(),
+            format!("pools={}", names.join(",")),
+        ];
+        for pool in &self.pools {
+            txt.push(format!("net.{}={}", pool.name, pool.network));
+            txt.push(format!("gw.{}={}", pool.name, pool.gateway));
+        }
+        txt
+    }
+
+    // Response carrying every record; a `ttl` of 0 withdraws them
+    pub fn packet(&self, ttl: u32) -> Vec<u8> {
+        let instance = self.instance_name();
+        let service: Vec<&str> = SERVICE_TYPE.split('.').collect();
+        let host: Vec<&str> = self.host.split('.').collect();
+
+        let mut packet = Vec::new();
+        // Id 0, authoritative answer, 5 answers
+        for field in [0, 0x8400, 0, 5, 0, 0] {
+            push_u16(&mut packet, field);
+        }
+
+        let mut rdata = Vec::new();
+        push_name(&mut rdata, &service);
+        let services: Vec<&str> = SERVICES.split('.').collect();
+        push_record(&mut packet, &services, TYPE_PTR, CLASS_IN, ttl, &rdata);
+
+        let mut rdata = Vec::new();
+        push_name(&mut rdata, &instance);
+        push_record(&mut packet, &service, TYPE_PTR, CLASS_IN, ttl, &rdata);
+
+        let mut rdata = Vec::new();
+        for field in [0, 0, self.port] {
+            push_u16(&mut rdata, field); // priority, weight, port
+        }
+        push_name(&mut rdata, &host);
+        let class = CLASS_IN | CACHE_FLUSH;
+        push_record(&mut packet, &instance, TYPE_SRV, class, ttl, &rdata);
+
+        let mut rdata = Vec::new();
+        for entry in self.txt() {
+            let entry = &entry.as_bytes()[..entry.len().min(255)];
+            rdata.push(entry.len() as u8);
+            rdata.extend_from_slice(entry);
+        }
+        push_record(&mut packet, &instance, TYPE_TXT, class, ttl, &rdata);
+
+        push_record(
+            &mut packet,
+            &host,
+            TYPE_A,
+            class,
+            ttl,
+            &self.address.octets(),
+        );
+        packet
+    }
+
+    // Whether `packet` is a query for the service, this instance or its host
+    pub fn answers(&self, packet: &[u8]) -> bool {
+        let instance = self.instance_name().join(".");
+        question_names(packet).is_some_and(|names| {
+            names.iter().any(|name| {
+                [SERVICES, SERVICE_TYPE, &instance, &self.host]
+                    .iter()
+                    .any(|ours| name.eq_ignore_ascii_case(ours))
+            })
+        })
+    }
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+// Uncompressed name; labels are cut at the 63 bytes DNS allows
+fn push_name(buf: &mut Vec<u8>, labels: &[&str]) {
+    for label in labels {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label);
+    }
+    buf.push(0);
+}
+
+fn push_record(buf: &mut Vec<u8>, name: &[&str], kind: u16, class: u16, ttl: u32, rdata: &[u8]) {
+    push_name(buf, name);
+    push_u16(buf, kind);
+    push_u16(buf, class);
+    buf.extend_from_slice(&ttl.to_be_bytes());
+    push_u16(buf, rdata.len() as u16);
+    buf.extend_from_slice(rdata);
+}
+
+// Name at `pos`, following compression pointers, and the position after it
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds the pointers followed, so a pointer loop cannot hang us
+    for _ in 0..128 {
+        let len = *packet.get(pos)? as usize;
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            let low = *packet.get(pos + 1)? as usize;
+            end.get_or_insert(pos + 2);
+            pos = ((len & 0x3f) << 8) | low;
+            continue;
+        }
+        let label = packet.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+    None
+}
+
+// Names asked about by a query, None for responses and malformed packets
+fn question_names(packet: &[u8]) -> Option<Vec<String>> {
+    let header = packet.get(..12)?;
+    if header[2] & 0x80 != 0 {
+        return None;
+    }
+    let count = u16::from_be_bytes([header[4], header[5]]);
+    let mut pos = 12;
+    let mut names = Vec::new();
+    for _ in 0..count {
+        let (name, next) = read_name(packet, pos)?;
+        names.push(name);
+        pos = next + 4; // type and class
+    }
+    Some(names)
+}
+
+// The address announced when MDNS_ADDRESS is not set: the one the host
+// would send multicast from. Nothing is sent to find it.
+pub fn default_address() -> std::io::Result<Ipv4Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((GROUP, PORT))?;
+    match socket.local_addr()?.ip() {
+        std::net::IpAddr::V4(address) => Ok(address),
+        std::net::IpAddr::V6(_) => Err(std::io::ErrorKind::AddrNotAvailable.into()),
+    }
+}
+
+// Socket on the mDNS port, shared with any other responder on the host
+pub fn bind(address: Ipv4Addr) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PORT).into())?;
+    socket.join_multicast_v4(&GROUP, &address)?;
+    socket.set_multicast_if_v4(&address)?;
+    socket.set_multicast_ttl_v4(255)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// Announce every `interval`, picking up pool changes, and answer queries
+// for the service in between
+pub async fn run(
+    socket: UdpSocket,
+    config: MdnsConfig,
+    address: Ipv4Addr,
+    port: u16,
+    pools: PoolRegistry,
+) {
+    let target = SocketAddrV4::new(GROUP, PORT);
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    let mut announcement = Announcement::new(&config, address, port, &pools).await;
+    let mut buf = vec![0; 9000];
+    loop {
+        let send = tokio::select! {
+            _ = ticker.tick() => {
+                announcement = Announcement::new(&config, address, port, &pools).await;
+                true
+            }
+            received = socket.recv_from(&mut buf) => match received {
+                Ok((len, _)) => announcement.answers(&buf[..len]),
+                Err(e) => {
+                    tracing::debug!("mDNS receive failed: {}", e);
+                    false
+                }
+            },
+        };
+        if send && let Err(e) = socket.send_to(&announcement.packet(TTL), target).await {
+            tracing::warn!("mDNS announcement failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str) -> Vec<u8> {
+        let mut packet = Vec::new();
+        for field in [0, 0, 1, 0, 0, 0] {
+            push_u16(&mut packet, field);
+        }
+        push_name(&mut packet, &name.split('.').collect::<Vec<_>>());
+        push_u16(&mut packet, TYPE_PTR);
+        push_u16(&mut packet, CLASS_IN);
+        packet
+    }
+
+    #[test]
+    fn test_announcement() {
+        let announcement = Announcement {
+            instance: "ippool".to_string(),
+            host: "hv1.local".to_string(),
+            address: Ipv4Addr::new(192, 168, 1, 10),
+            port: 8090,
+            pools: vec![PoolRecord {
+                name: "default".to_string(),
+                network: "172.16.0.0/24".to_string(),
+                gateway: "172.16.0.1".to_string(),
+            }],
+        };
+        assert_eq!(
+            announcement.txt(),
+            vec![
+                "txtvers=1",
+                "path=/api/v1",
+                "pools=default",
+                "net.default=172.16.0.0/24",
+                "gw.default=172.16.0.1",
+            ]
+        );
+
+        let packet = announcement.packet(TTL);
+        assert_eq!(&packet[..12], &[0, 0, 0x84, 0, 0, 0, 0, 5, 0, 0, 0, 0]);
+        // The first answer points the service list at our service type
+        let (name, pos) = read_name(&packet, 12).unwrap();
+        assert_eq!(name, SERVICES);
+        let (target, _) = read_name(&packet, pos + 10).unwrap();
+        assert_eq!(target, SERVICE_TYPE);
+        // The A record closes the packet
+        assert!(packet.ends_with(&[0, 4, 192, 168, 1, 10]));
+
+        assert!(announcement.answers(&query("_ippool._tcp.local")));
+        assert!(announcement.answers(&query("HV1.local")));
+        assert!(!announcement.answers(&query("_http._tcp.local")));
+        // Our own announcements are responses, not queries
+        assert!(!announcement.answers(&packet));
+
+        // A compressed question pointing back at the first one
+        let mut compressed = query("_printer._tcp.local");
+        compressed[5] = 2;
+        compressed.extend_from_slice(&[1, b'x', 0xc0, 12]);
+        push_u16(&mut compressed, TYPE_PTR);
+        push_u16(&mut compressed, CLASS_IN);
+        assert_eq!(
+            question_names(&compressed).unwrap(),
+            vec!["_printer._tcp.local", "x._printer._tcp.local"]
+        );
+        // A pointer loop is rejected rather than followed forever
+        let mut looped = query("a");
+        looped.splice(12..15, [0xc0, 12]);
+        assert_eq!(question_names(&looped), None);
+    }
+}
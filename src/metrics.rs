@@ -0,0 +1,299 @@
+use crate::pools::PoolRegistry;
+use crate::state::AppState;
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use std::fmt::Write;
+
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// Label allocations are broken down by in `ippool_allocations`
+const TENANT_LABEL: &str = "tenant";
+
+// USER_HZ, the unit of the CPU and start times in /proc, and the page size
+// resident memory is counted in; the same on every Linux platform we run on
+const CLOCK_TICKS: f64 = 100.0;
+const PAGE_SIZE: f64 = 4096.0;
+
+// Gauges taken from the pool stats: metric name, stats field, help
+const POOL_GAUGES: [(&str, &str, &str); 5] = [
+    (
+        "ippool_addresses",
+        "total",
+        "Allocatable addresses in the pool",
+    ),
+    (
+        "ippool_addresses_allocated",
+        "allocated",
+        "Addresses allocated",
+    ),
+    (
+        "ippool_addresses_available",
+        "available",
+        "Addresses free to allocate",
+    ),
+    (
+        "ippool_addresses_reserved",
+        "reserved",
+        "Addresses reserved, never allocated",
+    ),
+    (
+        "ippool_addresses_pending_release",
+        "pending_release",
+        "Allocated addresses with a deferred release scheduled",
+    ),
+];
+
+// Prometheus/OpenMetrics scrape handler
+pub async fn get_metrics(State(state): State<AppState>) -> Response {
+    tracing::debug!("Metrics scrape received");
+
+    let body = render(&state.pools).await;
+    ([(header::CONTENT_TYPE, CONTENT_TYPE)], body).into_response()
+}
+
+// Every pool series carries a `pool` label (plus `tenant` for the
+// allocation breakdown), so one scrape covers every pool
+pub async fn render(pools: &PoolRegistry) -> String {
+    let mut stats = Vec::new();
+    let mut tenants = Vec::new();
+    let mut conflicts = Vec::new();
+    let mut burn_rates = Vec::new();
+    for name in pools.names().await {
+        let Ok(pool) = pools.get(&name).await else {
+            continue;
+        };
+        stats.push((name.clone(), pool.get_stats().await));
+        tenants.push((name.clone(), pool.group_stats(TENANT_LABEL).await));
+        burn_rates.push((name.clone(), pool.burn_rates().await));
+        conflicts.push((name, pool.list_conflicts().await.len()));
+    }
+
+    let mut out = String::new();
+    for (metric, field, help) in POOL_GAUGES {
+        family(&mut out, metric, "gauge", help);
+        for (pool, stats) in &stats {
+            let value = stats[field].as_u64().unwrap_or(0);
+            let _ = writeln!(out, "{}{{pool=\"{}\"}} {}", metric, escape(pool), value);
+        }
+    }
+
+    family(
+        &mut out,
+        "ippool_burn_rate",
+        "gauge",
+        "Share of the pool consumed per hour over the window, net of releases",
+    );
+    for (pool, rates) in &burn_rates {
+        for (window, value) in rates {
+            let _ = writeln!(
+                out,
+                "ippool_burn_rate{{pool=\"{}\",window=\"{}\"}} {}",
+                escape(pool),
+                window,
+                value
+            );
+        }
+    }
+
+    family(
+        &mut out,
+        "ippool_pool_frozen",
+        "gauge",
+        "Whether the pool refuses new allocations",
+    );
+    for (pool, stats) in &stats {
+        let frozen = stats["frozen"].as_bool().unwrap_or(false);
+        let _ = writeln!(
+            out,
+            "ippool_pool_frozen{{pool=\"{}\"}} {}",
+            escape(pool),
+            u8::from(frozen)
+        );
+    }
+
+    family(
+        &mut out,
+        "ippool_conflicts",
+        "gauge",
+        "Addresses flagged as used by someone else",
+    );
+    for (pool, count) in &conflicts {
+        let _ = writeln!(
+            out,
+            "ippool_conflicts{{pool=\"{}\"}} {}",
+            escape(pool),
+            count
+        );
+    }
+
+    family(
+        &mut out,
+        "ippool_allocations",
+        "gauge",
+        "Allocated addresses by tenant label, empty for untenanted ones",
+    );
+    for (pool, groups) in &tenants {
+        for (tenant, count) in groups {
+            let _ = writeln!(
+                out,
+                "ippool_allocations{{pool=\"{}\",tenant=\"{}\"}} {}",
+                escape(pool),
+                escape(tenant.as_deref().unwrap_or("")),
+                count
+            );
+        }
+    }
+
+    family(&mut out, "ippool_build", "info", "Build information");
+    let _ = writeln!(
+        out,
+        "ippool_build_info{{version=\"{}\"}} 1",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    process_metrics(&mut out);
+    out.push_str("# EOF\n");
+    out
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+}
+
+// Standard process_* metrics, from /proc where available
+fn process_metrics(out: &mut String) {
+    let stat = std::fs::read_to_string("/proc/self/stat").unwrap_or_default();
+    // Fields after the parenthesised command name, starting with `state`
+    let fields: Vec<&str> = stat
+        .rsplit_once(')')
+        .map(|(_, rest)| rest.split_whitespace().collect())
+        .unwrap_or_default();
+    let field = |index: usize| fields.get(index).and_then(|f| f.parse::<f64>().ok());
+
+    if let (Some(utime), Some(stime)) = (field(11), field(12)) {
+        family(
+            out,
+            "process_cpu_seconds",
+            "counter",
+            "Total user and system CPU time spent in seconds",
+        );
+        let _ = writeln!(
+            out,
+            "process_cpu_seconds_total {}",
+            (utime + stime) / CLOCK_TICKS
+        );
+    }
+    if let (Some(started), Some(boot_time)) = (field(19), boot_time()) {
+        family(
+            out,
+            "process_start_time_seconds",
+            "gauge",
+            "Start time of the process since unix epoch in seconds",
+        );
+        let _ = writeln!(
+            out,
+            "process_start_time_seconds {}",
+            boot_time + started / CLOCK_TICKS
+        );
+    }
+    if let Some(rss_pages) = field(21) {
+        family(
+            out,
+            "process_resident_memory_bytes",
+            "gauge",
+            "Resident memory size in bytes",
+        );
+        let _ = writeln!(
+            out,
+            "process_resident_memory_bytes {}",
+            rss_pages * PAGE_SIZE
+        );
+    }
+    if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+        family(
+            out,
+            "process_open_fds",
+            "gauge",
+            "Number of open file descriptors",
+        );
+        let _ = writeln!(out, "process_open_fds {}", fds.count());
+    }
+}
+
+// Boot time in unix seconds, the base of the process start time
+fn boot_time() -> Option<f64> {
+    std::fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+// Label values escape backslash, double quote and newline
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ippool::IpPool;
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn test_every_pool_series_is_labelled() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let pools = PoolRegistry::new(pool.clone());
+        let lab = IpPool::new("172.16.1".to_string(), "172.16.1.1".to_string());
+        pools.insert("lab".to_string(), lab.clone()).await;
+
+        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        pool.label(
+            &ip,
+            BTreeMap::from([("tenant".to_string(), "acme".to_string())]),
+        )
+        .await
+        .unwrap();
+        pool.allocate_ip("vm-2".to_string()).await.unwrap();
+        lab.allocate_ip("vm-3".to_string()).await.unwrap();
+
+        let metrics = render(&pools).await;
+        for line in [
+            "ippool_addresses{pool=\"default\"} 253",
+            "ippool_addresses_allocated{pool=\"default\"} 2",
+            "ippool_addresses_allocated{pool=\"lab\"} 1",
+            "ippool_pool_frozen{pool=\"lab\"} 0",
+            "ippool_allocations{pool=\"default\",tenant=\"acme\"} 1",
+            "ippool_allocations{pool=\"default\",tenant=\"\"} 1",
+            "ippool_allocations{pool=\"lab\",tenant=\"\"} 1",
+            "# TYPE ippool_build info",
+        ] {
+            assert!(metrics.lines().any(|l| l == line), "missing {}", line);
+        }
+        assert!(metrics.ends_with("# EOF\n"));
+        let burn_rate = "ippool_burn_rate{pool=\"lab\",window=\"6h\"} 0.000";
+        assert!(metrics.lines().any(|l| l.starts_with(burn_rate)));
+
+        // Samples of pool families all name their pool
+        for sample in metrics
+            .lines()
+            .filter(|l| l.starts_with("ippool_") && !l.starts_with("ippool_build"))
+        {
+            assert!(sample.contains("pool=\""), "unlabelled {}", sample);
+        }
+    }
+
+    #[test]
+    fn test_label_escaping() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
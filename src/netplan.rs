@@ -0,0 +1,159 @@
+use crate::dhcp::DhcpOptions;
+use crate::routes::StaticRoute;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+
+// One of a VM's addresses with the settings of the pool it comes from
+#[derive(Debug, Clone)]
+pub struct Address {
+    pub interface: String,
+    pub ip: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>, // primary addresses only
+    pub routes: Vec<StaticRoute>,
+    pub options: DhcpOptions,
+}
+
+// Settings of one interface, merged from its addresses
+#[derive(Default)]
+struct Interface {
+    addresses: Vec<String>,
+    routes: Vec<(String, Ipv4Addr)>, // destination, next hop
+    dns_servers: Vec<String>,
+    search_domains: Vec<String>,
+}
+
+fn push_new<T: PartialEq>(values: &mut Vec<T>, value: T) {
+    if !values.contains(&value) {
+        values.push(value);
+    }
+}
+
+// Interface names are plain words in practice; anything else is quoted
+fn key(name: &str) -> String {
+    if !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+    {
+        name.to_string()
+    } else {
+        serde_json::to_string(name).unwrap()
+    }
+}
+
+// Netplan (version 2) configuration for a VM's addresses, one static
+// ethernet per interface. Only the first address with a gateway gives a
+// default route, as a VM with several would route at random.
+pub fn render(addresses: &[Address]) -> String {
+    let mut interfaces: BTreeMap<&str, Interface> = BTreeMap::new();
+    let mut routed = false;
+    for address in addresses {
+        let interface = interfaces.entry(&address.interface).or_default();
+        push_new(
+            &mut interface.addresses,
+            format!("{}/{}", address.ip, address.prefix_len),
+        );
+        if let Some(gateway) = address.gateway
+            && !routed
+        {
+            interface.routes.insert(0, ("default".to_string(), gateway));
+            routed = true;
+        }
+        for route in &address.routes {
+            push_new(&mut interface.routes, (route.cidr(), route.next_hop));
+        }
+        for server in &address.options.dns_servers {
+            push_new(&mut interface.dns_servers, server.to_string());
+        }
+        for domain in &address.options.search_domains {
+            push_new(&mut interface.search_domains, domain.clone());
+        }
+    }
+
+    let mut yaml = String::from("network:\n  version: 2\n  ethernets:\n");
+    for (name, interface) in &interfaces {
+        yaml.push_str(&format!("    {}:\n      dhcp4: false\n", key(name)));
+        yaml.push_str("      addresses:\n");
+        for address in &interface.addresses {
+            yaml.push_str(&format!("        - {}\n", address));
+        }
+        if !interface.routes.is_empty() {
+            yaml.push_str("      routes:\n");
+            for (to, via) in &interface.routes {
+                yaml.push_str(&format!("        - to: {}\n          via: {}\n", to, via));
+            }
+        }
+        if !interface.dns_servers.is_empty() || !interface.search_domains.is_empty() {
+            yaml.push_str("      nameservers:\n");
+            if !interface.dns_servers.is_empty() {
+                let servers = interface.dns_servers.join(", ");
+                yaml.push_str(&format!("        addresses: [{}]\n", servers));
+            }
+            if !interface.search_domains.is_empty() {
+                let domains = interface.search_domains.join(", ");
+                yaml.push_str(&format!("        search: [{}]\n", domains));
+            }
+        }
+    }
+    yaml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let primary = Address {
+            interface: "eth0".to_string(),
+            ip: Ipv4Addr::new(172, 16, 0, 12),
+            prefix_len: 24,
+            gateway: Some(Ipv4Addr::new(172, 16, 0, 1)),
+            routes: vec![StaticRoute::parse("10.50.0.0/16=172.16.0.254").unwrap()],
+            options: DhcpOptions {
+                dns_servers: vec![Ipv4Addr::new(172, 16, 0, 53)],
+                search_domains: vec!["lab.local".to_string()],
+                ..DhcpOptions::default()
+            },
+        };
+        let floating = Address {
+            ip: Ipv4Addr::new(172, 16, 0, 40),
+            gateway: None,
+            ..primary.clone()
+        };
+        let storage = Address {
+            interface: "eth1".to_string(),
+            ip: Ipv4Addr::new(10, 9, 0, 5),
+            prefix_len: 16,
+            gateway: Some(Ipv4Addr::new(10, 9, 0, 1)),
+            routes: Vec::new(),
+            options: DhcpOptions::default(),
+        };
+
+        assert_eq!(
+            render(&[primary, floating, storage]),
+            "network:
+  version: 2
+  ethernets:
+    eth0:
+      dhcp4: false
+      addresses:
+        - 172.16.0.12/24
+        - 172.16.0.40/24
+      routes:
+        - to: default
+          via: 172.16.0.1
+        - to: 10.50.0.0/16
+          via: 172.16.0.254
+      nameservers:
+        addresses: [172.16.0.53]
+        search: [lab.local]
+    eth1:
+      dhcp4: false
+      addresses:
+        - 10.9.0.5/16
+"
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use crate::events::EventBus;
+use crate::pools::PoolRegistry;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Condition {
+    Exhausted,
+    Threshold,
+    Conflict,
+}
+
+impl Condition {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Condition::Exhausted => "exhausted",
+            Condition::Threshold => "threshold",
+            Condition::Conflict => "conflict",
+        }
+    }
+}
+
+// Chat service the webhook URL belongs to, decides the payload shape
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatKind {
+    Slack,
+    Discord,
+}
+
+impl ChatKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "slack" => Some(ChatKind::Slack),
+            "discord" => Some(ChatKind::Discord),
+            _ => None,
+        }
+    }
+
+    fn payload(&self, text: String) -> serde_json::Value {
+        match self {
+            ChatKind::Slack => serde_json::json!({ "text": text }),
+            ChatKind::Discord => serde_json::json!({ "content": text }),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct NotifierState {
+    last_sent: HashMap<(Condition, String), Instant>,
+    suppressed: HashMap<(Condition, String), u32>,
+    active: HashSet<(Condition, String)>, // conditions currently raised per pool
+}
+
+// Slack/Discord incoming-webhook notifications for critical conditions.
+// Messages for the same condition and pool are sent at most once per
+// `min_interval`; suppressed ones are counted in the next message.
+#[derive(Debug, Clone)]
+pub struct ChatNotifier {
+    url: String,
+    kind: ChatKind,
+    template: String, // {condition}, {pool} and {message} are substituted
+    min_interval: Duration,
+    threshold: f64, // usage percent
+    http: reqwest::Client,
+    state: Arc<Mutex<NotifierState>>,
+}
+
+impl ChatNotifier {
+    pub fn new(
+        url: String,
+        kind: ChatKind,
+        template: String,
+        min_interval: Duration,
+        threshold: f64,
+    ) -> Self {
+        ChatNotifier {
+            url,
+            kind,
+            template,
+            min_interval,
+            threshold,
+            http: reqwest::Client::new(),
+            state: Arc::default(),
+        }
+    }
+
+    fn render(&self, condition: Condition, pool: &str, message: &str) -> String {
+        self.template
+            .replace("{condition}", condition.as_str())
+            .replace("{pool}", pool)
+            .replace("{message}", message)
+    }
+
+    // Rendered message, or None while the condition is rate limited
+    fn admit(&self, condition: Condition, pool: &str, message: &str) -> Option<String> {
+        let key = (condition, pool.to_string());
+        let mut state = self.state.lock().unwrap();
+
+        let now = Instant::now();
+        if let Some(last) = state.last_sent.get(&key)
+            && now.duration_since(*last) < self.min_interval
+        {
+            *state.suppressed.entry(key).or_default() += 1;
+            return None;
+        }
+        state.last_sent.insert(key.clone(), now);
+
+        let mut text = self.render(condition, pool, message);
+        if let Some(suppressed) = state.suppressed.remove(&key) {
+            text.push_str(&format!(" ({} similar suppressed)", suppressed));
+        }
+        Some(text)
+    }
+
+    // Send a notification unless it is rate limited
+    pub async fn raise(&self, condition: Condition, pool: &str, message: &str) {
+        let Some(text) = self.admit(condition, pool, message) else {
+            tracing::debug!(
+                "Chat notification rate limited: {} on {}",
+                condition.as_str(),
+                pool
+            );
+            return;
+        };
+
+        let result = self
+            .http
+            .post(&self.url)
+            .json(&self.kind.payload(text))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = result {
+            tracing::error!("Chat notification failed: {}", e);
+        }
+    }
+
+    // Whether `condition` just became true for `pool`
+    fn transition(&self, condition: Condition, pool: &str, now_active: bool) -> bool {
+        let key = (condition, pool.to_string());
+        let mut state = self.state.lock().unwrap();
+        if now_active {
+            state.active.insert(key)
+        } else {
+            state.active.remove(&key);
+            false
+        }
+    }
+
+    // Watch pool usage after every event until the bus goes away
+    pub async fn run(self, pools: PoolRegistry, events: EventBus) {
+        let mut receiver = events.subscribe();
+        loop {
+            let name = match receiver.recv().await {
+                Ok(event) => event.pool,
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("Chat notifier fell behind, {} events skipped", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let Ok(pool) = pools.get(&name).await else {
+                continue;
+            };
+
+            let stats = pool.get_stats().await;
+            let available = stats["available"].as_u64().unwrap_or(0);
+            let usage = stats["usage"].as_f64().unwrap_or(0.0);
+
+            if self.transition(Condition::Exhausted, &name, available == 0) {
+                let message = format!("no addresses left in {}", stats["network"]);
+                self.raise(Condition::Exhausted, &name, &message).await;
+            }
+            if self.transition(Condition::Threshold, &name, usage >= self.threshold) {
+                let message = format!(
+                    "usage at {:.1}% (threshold {}%), {} addresses available",
+                    usage, self.threshold, available
+                );
+                self.raise(Condition::Threshold, &name, &message).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notifier(min_interval: Duration) -> ChatNotifier {
+        ChatNotifier::new(
+            "http://127.0.0.1:9/".to_string(),
+            ChatKind::Slack,
+            "[{condition}] {pool}: {message}".to_string(),
+            min_interval,
+            90.0,
+        )
+    }
+
+    #[test]
+    fn test_rate_limit_counts_suppressed() {
+        let notifier = notifier(Duration::from_secs(3600));
+
+        assert_eq!(
+            notifier.admit(Condition::Exhausted, "default", "no addresses left"),
+            Some("[exhausted] default: no addresses left".to_string())
+        );
+        assert_eq!(
+            notifier.admit(Condition::Exhausted, "default", "again"),
+            None
+        );
+        assert_eq!(
+            notifier.admit(Condition::Exhausted, "default", "again"),
+            None
+        );
+        // Other pools and conditions are limited separately
+        assert!(notifier.admit(Condition::Exhausted, "lab", "x").is_some());
+        assert!(
+            notifier
+                .admit(Condition::Threshold, "default", "x")
+                .is_some()
+        );
+
+        let notifier = ChatNotifier {
+            min_interval: Duration::ZERO,
+            ..notifier
+        };
+        assert_eq!(
+            notifier.admit(Condition::Exhausted, "default", "still empty"),
+            Some("[exhausted] default: still empty (2 similar suppressed)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_conditions_fire_on_transition() {
+        let notifier = notifier(Duration::ZERO);
+
+        assert!(!notifier.transition(Condition::Threshold, "default", false));
+        assert!(notifier.transition(Condition::Threshold, "default", true));
+        assert!(!notifier.transition(Condition::Threshold, "default", true));
+        assert!(!notifier.transition(Condition::Threshold, "default", false));
+        assert!(notifier.transition(Condition::Threshold, "default", true));
+    }
+
+    #[test]
+    fn test_payload_shape() {
+        assert_eq!(ChatKind::parse("Discord"), Some(ChatKind::Discord));
+        assert_eq!(ChatKind::parse("teams"), None);
+        assert_eq!(
+            ChatKind::Discord.payload("hi".to_string()),
+            serde_json::json!({ "content": "hi" })
+        );
+        assert_eq!(
+            ChatKind::Slack.payload("hi".to_string()),
+            serde_json::json!({ "text": "hi" })
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use axum::{
+    BoxError, Json,
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use tower::load_shed::error::Overloaded;
+use tower::timeout::error::Elapsed;
+
+// Back-off suggested to clients whose request was shed
+const SHED_RETRY_AFTER_SECS: u64 = 1;
+
+// Requests turned away by the concurrency limit or the request timeout get
+// 503 with Retry-After instead of queueing
+pub async fn handle_overload(err: BoxError) -> Response {
+    let message = if err.is::<Overloaded>() {
+        "Too many concurrent requests, retry shortly"
+    } else if err.is::<Elapsed>() {
+        "Request timed out, retry shortly"
+    } else {
+        tracing::error!("Request failed in middleware: {}", err);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": "Internal server error" })),
+        )
+            .into_response();
+    };
+
+    tracing::warn!("Request shed: {}", message);
+    let mut response = (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(serde_json::json!({ "error": message })),
+    )
+        .into_response();
+    response.headers_mut().insert(
+        header::RETRY_AFTER,
+        HeaderValue::from(SHED_RETRY_AFTER_SECS),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, error_handling::HandleErrorLayer, routing::get};
+    use std::time::Duration;
+    use tower::{ServiceBuilder, ServiceExt};
+
+    #[tokio::test]
+    async fn test_slow_requests_get_503() {
+        let app = Router::new()
+            .route(
+                "/slow",
+                get(|| async { tokio::time::sleep(Duration::from_secs(5)).await }),
+            )
+            .layer(
+                ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(handle_overload))
+                    .load_shed()
+                    .concurrency_limit(1)
+                    .timeout(Duration::from_millis(50)),
+            );
+
+        let request = || {
+            axum::extract::Request::builder()
+                .uri("/slow")
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+
+        // Second request arrives while the first holds the only slot
+        let (first, second) = tokio::join!(
+            app.clone().oneshot(request()),
+            app.clone().oneshot(request())
+        );
+        for response in [first.unwrap(), second.unwrap()] {
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use crate::ippool::IpPoolError;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Upper bounds of the latency buckets, in microseconds
+const BUCKETS_US: [u64; 12] = [
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 50_000, 250_000,
+];
+
+// Lock-free latency histogram with fixed buckets
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS_US.len() + 1], // last bucket is overflow
+    sum_us: AtomicU64,
+    max_us: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramSummary {
+    pub count: u64,
+    pub mean_us: f64,
+    pub max_us: u64,
+    // Percentiles are bucket upper bounds, null when beyond the last bucket
+    pub p50_us: Option<u64>,
+    pub p90_us: Option<u64>,
+    pub p99_us: Option<u64>,
+    pub buckets: Vec<HistogramBucket>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramBucket {
+    pub le_us: Option<u64>, // None for the overflow bucket
+    pub count: u64,
+}
+
+impl Histogram {
+    pub fn record(&self, elapsed: Duration) {
+        let us = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let index = BUCKETS_US
+            .iter()
+            .position(|bound| us <= *bound)
+            .unwrap_or(BUCKETS_US.len());
+
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+        self.max_us.fetch_max(us, Ordering::Relaxed);
+    }
+
+    pub fn summary(&self) -> HistogramSummary {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let count: u64 = counts.iter().sum();
+        let sum_us = self.sum_us.load(Ordering::Relaxed);
+
+        let percentile = |p: f64| {
+            let rank = ((count as f64) * p).ceil().max(1.0) as u64;
+            let mut seen = 0;
+            for (index, bucket) in counts.iter().enumerate() {
+                seen += bucket;
+                if seen >= rank {
+                    return BUCKETS_US.get(index).copied();
+                }
+            }
+            None
+        };
+
+        HistogramSummary {
+            count,
+            mean_us: if count == 0 {
+                0.0
+            } else {
+                sum_us as f64 / count as f64
+            },
+            max_us: self.max_us.load(Ordering::Relaxed),
+            p50_us: if count == 0 { None } else { percentile(0.50) },
+            p90_us: if count == 0 { None } else { percentile(0.90) },
+            p99_us: if count == 0 { None } else { percentile(0.99) },
+            buckets: counts
+                .iter()
+                .enumerate()
+                .map(|(index, count)| HistogramBucket {
+                    le_us: BUCKETS_US.get(index).copied(),
+                    count: *count,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Operation {
+    Allocate,
+    Release,
+}
+
+impl Operation {
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Allocate => "allocate",
+            Operation::Release => "release",
+        }
+    }
+}
+
+// Request timings and error counts for the allocate/release paths
+#[derive(Debug, Clone, Default)]
+pub struct PerfStats {
+    inner: Arc<PerfStatsInner>,
+}
+
+#[derive(Debug, Default)]
+struct PerfStatsInner {
+    allocate: Histogram,
+    release: Histogram,
+    errors: Mutex<BTreeMap<(Operation, &'static str), u64>>,
+}
+
+impl PerfStats {
+    // Record one operation that started at `started`
+    pub fn observe<T>(
+        &self,
+        operation: Operation,
+        started: Instant,
+        result: &Result<T, IpPoolError>,
+    ) {
+        let histogram = match operation {
+            Operation::Allocate => &self.inner.allocate,
+            Operation::Release => &self.inner.release,
+        };
+        histogram.record(started.elapsed());
+
+        if let Err(e) = result {
+            let mut errors = self.inner.errors.lock().unwrap();
+            *errors.entry((operation, e.kind())).or_insert(0) += 1;
+        }
+    }
+
+    pub fn report(&self) -> serde_json::Value {
+        let mut errors: BTreeMap<&str, BTreeMap<&str, u64>> = BTreeMap::new();
+        for ((operation, kind), count) in self.inner.errors.lock().unwrap().iter() {
+            errors
+                .entry(operation.name())
+                .or_default()
+                .insert(kind, *count);
+        }
+
+        serde_json::json!({
+            "allocate": self.inner.allocate.summary(),
+            "release": self.inner.release.summary(),
+            "errors": errors,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_summary() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.summary().p50_us, None);
+
+        for _ in 0..90 {
+            histogram.record(Duration::from_micros(40));
+        }
+        for _ in 0..9 {
+            histogram.record(Duration::from_micros(800));
+        }
+        histogram.record(Duration::from_secs(1));
+
+        let summary = histogram.summary();
+        assert_eq!(summary.count, 100);
+        assert_eq!(summary.max_us, 1_000_000);
+        assert_eq!(summary.p50_us, Some(50));
+        assert_eq!(summary.p90_us, Some(50));
+        assert_eq!(summary.p99_us, Some(1_000));
+        assert_eq!(summary.buckets.last().unwrap().count, 1);
+    }
+
+    #[test]
+    fn test_error_counters() {
+        let perf = PerfStats::default();
+
+        let failed: Result<(), IpPoolError> = Err(IpPoolError::NoAvailableIps);
+        perf.observe(Operation::Allocate, Instant::now(), &failed);
+        perf.observe(Operation::Allocate, Instant::now(), &failed);
+        perf.observe(
+            Operation::Release,
+            Instant::now(),
+            &Ok::<_, IpPoolError>(()),
+        );
+
+        let report = perf.report();
+        assert_eq!(report["errors"]["allocate"]["no_available_ips"], 2);
+        assert_eq!(report["allocate"]["count"], 2);
+        assert_eq!(report["release"]["count"], 1);
+    }
+}
@@ -0,0 +1,318 @@
+use crate::ippool::Slot;
+use rhai::{AST, Dynamic, Engine, Map, Scope};
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+// Name and arity of the function every policy script must define
+const ENTRY_POINT: &str = "allocate";
+const ENTRY_POINT_PARAMS: usize = 4;
+
+// Bounds on a single script run, so a broken policy cannot hang allocations
+const MAX_OPERATIONS: u64 = 100_000;
+const MAX_CALL_DEPTH: usize = 32;
+
+// Where a new allocation may be placed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    Any,
+    Range(u32, u32), // first free address in start..=end
+}
+
+// Allocation policy in a Rhai script, consulted for every new allocation:
+//
+//     fn allocate(vm_id, pool, interface, purpose) {
+//         if vm_id.starts_with("db-") {
+//             return #{ from: "172.16.0.100", to: "172.16.0.150" };
+//         }
+//     }
+//
+// Returning nothing or `true` allows the allocation anywhere, a map with
+// `from`/`to` restricts it to that range, `false` or a string vetoes it
+// (the string being the reason). `interface` is `()` when not given,
+// `purpose` is "primary" for plain allocations.
+#[derive(Debug)]
+pub struct ScriptPolicy {
+    path: PathBuf,
+    engine: Engine,
+    ast: RwLock<AST>,
+}
+
+impl ScriptPolicy {
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_max_call_levels(MAX_CALL_DEPTH);
+        let ast = Self::compile(&engine, &path)?;
+
+        Ok(ScriptPolicy {
+            path,
+            engine,
+            ast: RwLock::new(ast),
+        })
+    }
+
+    fn compile(engine: &Engine, path: &PathBuf) -> Result<AST, String> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let ast = engine
+            .compile(&source)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == ENTRY_POINT && f.params.len() == ENTRY_POINT_PARAMS)
+        {
+            return Err(format!(
+                "{}: no fn {}(vm_id, pool, interface, purpose)",
+                path.display(),
+                ENTRY_POINT
+            ));
+        }
+        Ok(ast)
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    // Re-read the script; on errors the current one stays in effect
+    pub fn reload(&self) -> Result<(), String> {
+        let ast = Self::compile(&self.engine, &self.path)?;
+        *self.ast.write().unwrap() = ast;
+        Ok(())
+    }
+
+    // Placement for a new allocation, or the reason it is vetoed
+    pub fn evaluate(&self, pool: &str, vm_id: &str, slot: &Slot) -> Result<Placement, String> {
+        let interface = match &slot.interface {
+            Some(interface) => Dynamic::from(interface.clone()),
+            None => Dynamic::UNIT,
+        };
+        let args = (
+            vm_id.to_string(),
+            pool.to_string(),
+            interface,
+            slot.purpose.clone(),
+        );
+
+        let ast = self.ast.read().unwrap();
+        let result = self
+            .engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &ast, ENTRY_POINT, args)
+            .map_err(|e| format!("policy script failed: {}", e))?;
+        placement(result)
+    }
+}
+
+fn placement(result: Dynamic) -> Result<Placement, String> {
+    if result.is_unit() {
+        return Ok(Placement::Any);
+    }
+    if let Some(allowed) = result.clone().try_cast::<bool>() {
+        return match allowed {
+            true => Ok(Placement::Any),
+            false => Err("denied by policy script".to_string()),
+        };
+    }
+    if result.is_string() {
+        return Err(result.to_string());
+    }
+    if let Some(range) = result.clone().try_cast::<Map>() {
+        let bound = |key: &str| -> Result<u32, String> {
+            range
+                .get(key)
+                .and_then(|value| value.to_string().parse::<Ipv4Addr>().ok())
+                .map(u32::from)
+                .ok_or_else(|| format!("policy script returned an invalid `{}` address", key))
+        };
+        let (from, to) = (bound("from")?, bound("to")?);
+        if from > to {
+            return Err("policy script returned an empty range".to_string());
+        }
+        return Ok(Placement::Range(from, to));
+    }
+    Err(format!(
+        "policy script returned unsupported {}",
+        result.type_name()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ippool::{IpPool, IpPoolError, Lease};
+    use std::sync::Arc;
+
+    fn policy(source: &str) -> Result<ScriptPolicy, String> {
+        let path = std::env::temp_dir().join(format!(
+            "ippool-policy-{}-{}.rhai",
+            std::process::id(),
+            unique_suffix()
+        ));
+        std::fs::write(&path, source).unwrap();
+        let policy = ScriptPolicy::load(path.clone());
+        let _ = std::fs::remove_file(&path);
+        policy
+    }
+
+    // Distinct file names for tests running in parallel
+    fn unique_suffix() -> u64 {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    }
+
+    #[test]
+    fn test_script_decides_placement() {
+        let policy = policy(
+            r#"
+            fn allocate(vm_id, pool, interface, purpose) {
+                if vm_id.starts_with("db-") {
+                    return #{ from: "172.16.0.100", to: "172.16.0.150" };
+                }
+                if vm_id.starts_with("tmp-") {
+                    return "temporary VMs are not allowed in " + pool;
+                }
+                if purpose == "floating" {
+                    return false;
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        let primary = Slot::primary();
+
+        assert_eq!(
+            policy.evaluate("default", "web-1", &primary),
+            Ok(Placement::Any)
+        );
+        assert_eq!(
+            policy.evaluate("default", "db-1", &primary),
+            Ok(Placement::Range(
+                u32::from(Ipv4Addr::new(172, 16, 0, 100)),
+                u32::from(Ipv4Addr::new(172, 16, 0, 150))
+            ))
+        );
+        assert_eq!(
+            policy.evaluate("lab", "tmp-1", &primary),
+            Err("temporary VMs are not allowed in lab".to_string())
+        );
+        assert!(
+            policy
+                .evaluate(
+                    "default",
+                    "web-1",
+                    &Slot::new(None, Some("floating".to_string()))
+                )
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pool_allocates_within_policy_range() {
+        let policy = policy(
+            r#"
+            fn allocate(vm_id, pool, interface, purpose) {
+                if vm_id.starts_with("db-") {
+                    return #{ from: "172.16.0.100", to: "172.16.0.101" };
+                }
+                if vm_id.starts_with("tmp-") {
+                    return "no temporary VMs";
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        pool.attach_policy("default".to_string(), Arc::new(policy))
+            .await;
+
+        assert_eq!(
+            pool.allocate_ip("web-1".to_string()).await.unwrap(),
+            "172.16.0.2"
+        );
+        assert_eq!(
+            pool.allocate_ip("db-1".to_string()).await.unwrap(),
+            "172.16.0.100"
+        );
+        assert_eq!(
+            pool.allocate_ip("db-2".to_string()).await.unwrap(),
+            "172.16.0.101"
+        );
+        assert_eq!(
+            pool.allocate_ip("db-3".to_string()).await,
+            Err(IpPoolError::NoAvailableIps)
+        );
+        assert_eq!(
+            pool.allocate_ip("tmp-1".to_string()).await,
+            Err(IpPoolError::PolicyViolation("no temporary VMs".to_string()))
+        );
+        // Other VMs keep getting the lowest free address
+        assert_eq!(
+            pool.allocate_ip("web-2".to_string()).await.unwrap(),
+            "172.16.0.3"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_policy_range_outside_delegation() {
+        let policy = policy(
+            r#"
+            fn allocate(vm_id, pool, interface, purpose) {
+                if vm_id.starts_with("db-") {
+                    return #{ from: "172.16.0.100", to: "172.16.0.101" };
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        pool.attach_policy("default".to_string(), Arc::new(policy))
+            .await;
+        let ip = |last: u8| Ipv4Addr::new(172, 16, 0, last);
+        pool.delegate("squad-a", ip(10), ip(19), "hash-a".to_string())
+            .await
+            .unwrap();
+        let (slot, lease) = (Slot::primary(), Lease::PoolDefault);
+
+        // Refused, not a panic, and the pool keeps working
+        for _ in 0..2 {
+            assert!(matches!(
+                pool.allocate_delegated("squad-a", "db-1".to_string(), &slot, lease)
+                    .await,
+                Err(IpPoolError::PolicyViolation(_))
+            ));
+            assert!(matches!(
+                pool.preview_delegated("squad-a", "db-1", &slot).await,
+                Err(IpPoolError::PolicyViolation(_))
+            ));
+        }
+        let (allocated, _) = pool
+            .allocate_delegated("squad-a", "web-1".to_string(), &slot, lease)
+            .await
+            .unwrap();
+        assert_eq!(allocated, "172.16.0.10");
+        assert_eq!(
+            pool.preview_allocation("db-1", &slot).await.unwrap(),
+            "172.16.0.100"
+        );
+        assert_eq!(
+            pool.allocate_ip("db-1".to_string()).await.unwrap(),
+            "172.16.0.100"
+        );
+    }
+
+    #[test]
+    fn test_invalid_scripts_are_rejected() {
+        assert!(policy("fn allocate(vm_id) { true }").is_err());
+        assert!(policy("fn allocate(vm_id, pool, interface, purpose) {").is_err());
+
+        // Runaway scripts are cut off
+        let policy = policy("fn allocate(vm_id, pool, interface, purpose) { loop {} }").unwrap();
+        assert!(
+            policy
+                .evaluate("default", "vm-1", &Slot::primary())
+                .is_err()
+        );
+    }
+}
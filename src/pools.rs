@@ -0,0 +1,794 @@
+use crate::breaker::CircuitBreaker;
+use crate::delegations;
+use crate::events::{EventBus, EventKind};
+use crate::health::HealthRegistry;
+use crate::ippool::{Annotation, IpAllocation, IpPool, IpPoolError, Lease, PoolSnapshot, Slot};
+use crate::journal::{Journal, JournalEntry};
+use crate::perf::HistogramSummary;
+use crate::policy::ScriptPolicy;
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+pub const DEFAULT_POOL: &str = "default";
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Migration {
+    pub vm_id: String,
+    pub old_ip: String,
+    pub new_ip: String,
+}
+
+// Named pools served by this instance. The default pool backs the
+// unqualified /api/v1/ip endpoints.
+#[derive(Debug, Clone)]
+pub struct PoolRegistry {
+    pools: Arc<RwLock<HashMap<String, IpPool>>>,
+    journal: Arc<OnceLock<Arc<Journal>>>,
+    breaker: Arc<OnceLock<CircuitBreaker>>,
+    policy: Arc<OnceLock<Arc<ScriptPolicy>>>,
+}
+
+impl PoolRegistry {
+    pub fn new(default_pool: IpPool) -> Self {
+        let mut pools = HashMap::new();
+        pools.insert(DEFAULT_POOL.to_string(), default_pool);
+
+        PoolRegistry {
+            pools: Arc::new(RwLock::new(pools)),
+            journal: Arc::new(OnceLock::new()),
+            breaker: Arc::new(OnceLock::new()),
+            policy: Arc::new(OnceLock::new()),
+        }
+    }
+
+    pub async fn insert(&self, name: String, pool: IpPool) {
+        let mut pools = self.pools.write().await;
+        self.track(&name, &pool).await;
+        pools.insert(name, pool);
+    }
+
+    // Journal every mutation from now on, including pools added later
+    pub async fn attach_journal(&self, journal: Arc<Journal>) {
+        let pools = self.pools.write().await;
+        if self.journal.set(journal.clone()).is_err() {
+            return;
+        }
+        for (name, pool) in pools.iter() {
+            pool.attach_journal(name.clone(), journal.clone()).await;
+        }
+    }
+
+    // Refuse writes in every pool, including pools added later, while the
+    // storage circuit is open
+    pub async fn attach_breaker(&self, breaker: CircuitBreaker) {
+        let pools = self.pools.write().await;
+        if self.breaker.set(breaker.clone()).is_err() {
+            return;
+        }
+        for pool in pools.values() {
+            pool.attach_breaker(breaker.clone()).await;
+        }
+    }
+
+    // Run every new allocation, including in pools added later, past the
+    // policy script
+    pub async fn attach_policy(&self, policy: Arc<ScriptPolicy>) {
+        let pools = self.pools.write().await;
+        if self.policy.set(policy.clone()).is_err() {
+            return;
+        }
+        for (name, pool) in pools.iter() {
+            pool.attach_policy(name.clone(), policy.clone()).await;
+        }
+    }
+
+    // Register a new pool, refusing to replace one by the same name
+    pub async fn create(&self, name: String, pool: IpPool) -> Result<(), IpPoolError> {
+        let mut pools = self.pools.write().await;
+        if pools.contains_key(&name) {
+            return Err(IpPoolError::PoolAlreadyExists);
+        }
+        self.track(&name, &pool).await;
+        pools.insert(name, pool);
+        Ok(())
+    }
+
+    // Attach a newly registered pool to the journal and record its state
+    async fn track(&self, name: &str, pool: &IpPool) {
+        if let Some(policy) = self.policy.get() {
+            pool.attach_policy(name.to_string(), policy.clone()).await;
+        }
+        if let Some(breaker) = self.breaker.get() {
+            pool.attach_breaker(breaker.clone()).await;
+        }
+        if let Some(journal) = self.journal.get() {
+            pool.attach_journal(name.to_string(), journal.clone()).await;
+            pool.journal_state().await;
+        }
+    }
+
+    // Replay a journal entry during recovery
+    pub async fn apply_journal(&self, entry: JournalEntry) {
+        let mut pools = self.pools.write().await;
+
+        match entry {
+            JournalEntry::Remove { pool } => {
+                if pool != DEFAULT_POOL {
+                    pools.remove(&pool);
+                }
+            }
+            JournalEntry::Replace { pool, snapshot } if !pools.contains_key(&pool) => {
+                match IpPool::from_snapshot(*snapshot) {
+                    Ok(restored) => {
+                        pools.insert(pool, restored);
+                    }
+                    Err(e) => tracing::warn!("Skipping snapshot of pool {}: {}", pool, e),
+                }
+            }
+            entry => match pools.get(entry.pool()) {
+                Some(pool) => pool.apply_journal(entry).await,
+                None => tracing::warn!("Skipping journal entry for unknown pool {}", entry.pool()),
+            },
+        }
+    }
+
+    // Pool names, sorted
+    pub async fn names(&self) -> Vec<String> {
+        let pools = self.pools.read().await;
+        let mut names: Vec<String> = pools.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub async fn get(&self, name: &str) -> Result<IpPool, IpPoolError> {
+        let pools = self.pools.read().await;
+        pools.get(name).cloned().ok_or(IpPoolError::PoolNotFound)
+    }
+
+    // Snapshot of every pool, keyed by name
+    pub async fn snapshot(&self) -> BTreeMap<String, PoolSnapshot> {
+        let pools = self.pools.read().await;
+
+        let mut snapshot = BTreeMap::new();
+        for (name, pool) in pools.iter() {
+            snapshot.insert(name.clone(), pool.snapshot().await);
+        }
+        snapshot
+    }
+
+    // Snapshot of every pool, with `cut` run while all of them are locked:
+    // whatever was journaled before the cut is in the snapshot, whatever is
+    // journaled after it is not
+    pub async fn snapshot_at<E>(
+        &self,
+        cut: impl FnOnce() -> Result<(), E>,
+    ) -> Result<BTreeMap<String, PoolSnapshot>, E> {
+        let pools = self.pools.read().await;
+        let mut names: Vec<&String> = pools.keys().collect();
+        names.sort();
+
+        let mut locks = Vec::new();
+        for name in names {
+            locks.push((name, pools[name].lock().await));
+        }
+        cut()?;
+        Ok(locks
+            .iter()
+            .map(|(name, lock)| (name.to_string(), lock.snapshot()))
+            .collect())
+    }
+
+    // Load persisted state. Known pools are restored in place so handles
+    // held elsewhere (e.g. the default pool) stay valid.
+    pub async fn restore(&self, snapshot: BTreeMap<String, PoolSnapshot>) {
+        let mut pools = self.pools.write().await;
+
+        for (name, pool_snapshot) in snapshot {
+            let restored = match pools.get(&name) {
+                Some(pool) => pool.restore(pool_snapshot).await,
+                None => match IpPool::from_snapshot(pool_snapshot) {
+                    Ok(pool) => {
+                        self.track(&name, &pool).await;
+                        pools.insert(name.clone(), pool);
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                },
+            };
+            if let Err(e) = restored {
+                tracing::warn!("Skipping snapshot of pool {}: {}", name, e);
+            }
+        }
+    }
+
+    // Pool and team an API key was delegated to
+    pub async fn delegation_for_key(&self, key: &str) -> Option<(String, String)> {
+        let key_hash = delegations::hash_key(key);
+        for name in self.names().await {
+            if let Ok(pool) = self.get(&name).await
+                && let Some(team) = pool.delegated_team(&key_hash).await
+            {
+                return Some((name, team));
+            }
+        }
+        None
+    }
+
+    // Every address a VM holds in any pool, by pool name
+    pub async fn allocations_of(&self, vm_id: &str) -> Vec<(String, IpAllocation)> {
+        let pools: Vec<(String, IpPool)> = {
+            let pools = self.pools.read().await;
+            pools
+                .iter()
+                .map(|(name, pool)| (name.clone(), pool.clone()))
+                .collect()
+        };
+
+        let mut allocations = Vec::new();
+        for (name, pool) in pools {
+            if let Ok(held) = pool.get_allocations(vm_id).await {
+                allocations.extend(held.into_iter().map(|a| (name.clone(), a)));
+            }
+        }
+        allocations.sort_by(|(a_pool, a), (b_pool, b)| {
+            (&a.interface, a_pool, &a.purpose).cmp(&(&b.interface, b_pool, &b.purpose))
+        });
+        allocations
+    }
+
+    // Lock wait times of the allocate/release paths, per pool
+    pub async fn lock_wait(&self) -> BTreeMap<String, HistogramSummary> {
+        let pools = self.pools.read().await;
+        pools
+            .iter()
+            .map(|(name, pool)| (name.clone(), pool.lock_wait()))
+            .collect()
+    }
+
+    // Release expired leases, allocations past their hard expiry and due
+    // deferred releases in every pool, announcing each one, and warn about
+    // leases about to expire
+    pub async fn expire_leases(&self, events: &EventBus) -> usize {
+        let pools: Vec<(String, IpPool)> = {
+            let pools = self.pools.read().await;
+            pools
+                .iter()
+                .map(|(name, pool)| (name.clone(), pool.clone()))
+                .collect()
+        };
+
+        let mut expired = 0;
+        for (name, pool) in pools {
+            for (vm_id, ip, expires_at) in pool.expiring_leases().await {
+                tracing::info!(
+                    "Lease expiring - pool: {}, vm_id: {}, ip: {}, expires_at: {}",
+                    name,
+                    vm_id,
+                    ip,
+                    expires_at
+                );
+                // The owner is who gets asked to renew or move the VM
+                let mut details = serde_json::json!({ "expires_at": expires_at });
+                if let Some(owner) = pool.annotation(&ip).await.owner {
+                    details["owner"] = owner.into();
+                }
+                events
+                    .emit(EventKind::Expiring, &name, &vm_id, &ip, Some(details))
+                    .await;
+            }
+            for (vm_id, ip) in pool.expire_leases().await {
+                tracing::info!(
+                    "Lease expired - pool: {}, vm_id: {}, ip: {}",
+                    name,
+                    vm_id,
+                    ip
+                );
+                events
+                    .emit(EventKind::Expired, &name, &vm_id, &ip, None)
+                    .await;
+                expired += 1;
+            }
+            for (vm_id, ip) in pool.reclaim_overdue().await {
+                tracing::info!(
+                    "Hard expiry reached - pool: {}, vm_id: {}, ip: {}",
+                    name,
+                    vm_id,
+                    ip
+                );
+                let details = serde_json::json!({ "reason": "hard_expiry" });
+                events
+                    .emit(EventKind::Expired, &name, &vm_id, &ip, Some(details))
+                    .await;
+                expired += 1;
+            }
+            for (vm_id, ip) in pool.release_pending().await {
+                tracing::info!(
+                    "Deferred release done - pool: {}, vm_id: {}, ip: {}",
+                    name,
+                    vm_id,
+                    ip
+                );
+                events
+                    .emit(EventKind::Released, &name, &vm_id, &ip, None)
+                    .await;
+                expired += 1;
+            }
+        }
+        expired
+    }
+
+    // Periodically reclaim addresses whose lease ran out
+    pub async fn run_lease_expiry(
+        self,
+        events: EventBus,
+        interval: Duration,
+        health: HealthRegistry,
+    ) {
+        health.register("lease_expiry");
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            self.expire_leases(&events).await;
+            health.success("lease_expiry");
+        }
+    }
+
+    // Cheap change detection: pool names with their mutation counters
+    pub async fn fingerprint(&self) -> Vec<(String, u64)> {
+        let pools = self.pools.read().await;
+
+        let mut fingerprint = Vec::with_capacity(pools.len());
+        for (name, pool) in pools.iter() {
+            fingerprint.push((name.clone(), pool.version().await));
+        }
+        fingerprint.sort();
+        fingerprint
+    }
+
+    // Take over the primary's state on a read replica: pools are restored
+    // in place, and the ones the primary no longer has are dropped
+    pub async fn replicate(&self, state: BTreeMap<String, PoolSnapshot>) {
+        let names: Vec<String> = state.keys().cloned().collect();
+        self.restore(state).await;
+        let mut pools = self.pools.write().await;
+        pools.retain(|name, _| name == DEFAULT_POOL || names.contains(name));
+    }
+
+    // Merge `other` into `pool`; `other` is removed from the registry
+    pub async fn merge(&self, pool: &str, other: &str) -> Result<IpPool, IpPoolError> {
+        // The default pool backs the v1 endpoints and cannot go away
+        if other == DEFAULT_POOL {
+            return Err(IpPoolError::InvalidMerge);
+        }
+
+        let mut pools = self.pools.write().await;
+        let target = pools.get(pool).cloned().ok_or(IpPoolError::PoolNotFound)?;
+        let source = pools.get(other).cloned().ok_or(IpPoolError::PoolNotFound)?;
+
+        target.merge(&source).await?;
+        pools.remove(other);
+        if let Some(journal) = self.journal.get() {
+            let entry = JournalEntry::Remove {
+                pool: other.to_string(),
+            };
+            if let Err(e) = journal.append(&entry) {
+                tracing::error!("Failed to journal removal of pool {}: {}", other, e);
+            }
+        }
+
+        Ok(target)
+    }
+
+    // Split `pool` at `at`, registering the upper part as `new_pool`
+    pub async fn split(
+        &self,
+        pool: &str,
+        at: Ipv4Addr,
+        new_pool: String,
+    ) -> Result<(IpPool, IpPool), IpPoolError> {
+        let mut pools = self.pools.write().await;
+        if pools.contains_key(&new_pool) {
+            return Err(IpPoolError::PoolAlreadyExists);
+        }
+        let lower = pools.get(pool).cloned().ok_or(IpPoolError::PoolNotFound)?;
+
+        let upper = lower.split(at).await?;
+        self.track(&new_pool, &upper).await;
+        pools.insert(new_pool, upper.clone());
+
+        Ok((lower, upper))
+    }
+
+    // Re-allocate VMs from one pool into another. Either every selected VM
+    // is moved or nothing changes.
+    pub async fn migrate(
+        &self,
+        from: &str,
+        to: &str,
+        vm_ids: Option<Vec<String>>,
+    ) -> Result<Vec<Migration>, IpPoolError> {
+        if from == to {
+            return Err(IpPoolError::InvalidMigration);
+        }
+
+        let source = self.get(from).await?;
+        let target = self.get(to).await?;
+
+        // Resolve the selection against the source pool up front
+        let selected = match vm_ids {
+            Some(vm_ids) => {
+                let mut selected = Vec::with_capacity(vm_ids.len());
+                for vm_id in vm_ids {
+                    selected.extend(source.get_allocations(&vm_id).await?);
+                }
+                selected
+            }
+            None => source.list_allocations().await,
+        };
+
+        // Allocate everything in the target first, rolling back on failure.
+        // Each address keeps its interface and purpose.
+        let mut migrations = Vec::with_capacity(selected.len());
+        let mut newly_allocated = Vec::new();
+        for allocation in selected {
+            let slot = Slot::new(allocation.interface.clone(), allocation.purpose.clone());
+            let existed = target
+                .get_allocations(&allocation.vm_id)
+                .await
+                .is_ok_and(|held| {
+                    held.iter().any(|a| {
+                        a.interface == allocation.interface && a.purpose == allocation.purpose
+                    })
+                });
+            match target
+                .allocate_address(allocation.vm_id.clone(), &slot, Lease::PoolDefault)
+                .await
+            {
+                Ok((new_ip, _)) => {
+                    if !existed {
+                        newly_allocated.push(new_ip.clone());
+                    }
+                    // Labels, the owner, notes, the ticket and a hard
+                    // expiry move with the VM
+                    let annotation = Annotation {
+                        owner: allocation.owner,
+                        notes: allocation.notes,
+                        ticket: allocation.ticket,
+                    };
+                    let mut carried = Ok(());
+                    if !allocation.labels.is_empty() {
+                        carried = target.label(&new_ip, allocation.labels).await;
+                    }
+                    if carried.is_ok() && !annotation.is_empty() {
+                        carried = target.annotate(&new_ip, annotation).await;
+                    }
+                    if carried.is_ok()
+                        && let Some(expires_at) = allocation.hard_expires_at
+                    {
+                        carried = target.set_deadline(&new_ip, expires_at).await.map(drop);
+                    }
+                    if let Err(e) = carried {
+                        for ip in &newly_allocated {
+                            let _ = target.release_unchecked(ip).await;
+                        }
+                        return Err(e);
+                    }
+                    migrations.push(Migration {
+                        vm_id: allocation.vm_id,
+                        old_ip: allocation.ip,
+                        new_ip,
+                    });
+                }
+                Err(e) => {
+                    for ip in &newly_allocated {
+                        let _ = target.release_unchecked(ip).await;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        for migration in &migrations {
+            source.release_unchecked(&migration.old_ip).await?;
+        }
+
+        Ok(migrations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::ippool::Requested;
+
+    async fn registry() -> PoolRegistry {
+        let registry = PoolRegistry::new(IpPool::new(
+            "172.16.0".to_string(),
+            "172.16.0.1".to_string(),
+        ));
+        registry
+            .insert(
+                "new".to_string(),
+                IpPool::new("10.0.0".to_string(), "10.0.0.1".to_string()),
+            )
+            .await;
+        registry
+    }
+
+    #[tokio::test]
+    async fn test_migrate_selected() {
+        let registry = registry().await;
+        let source = registry.get(DEFAULT_POOL).await.unwrap();
+
+        source.allocate_ip("vm-1".to_string()).await.unwrap();
+        source.allocate_ip("vm-2".to_string()).await.unwrap();
+
+        let migrations = registry
+            .migrate(DEFAULT_POOL, "new", Some(vec!["vm-2".to_string()]))
+            .await
+            .unwrap();
+
+        assert_eq!(migrations.len(), 1);
+        assert_eq!(migrations[0].old_ip, "172.16.0.3");
+        assert_eq!(migrations[0].new_ip, "10.0.0.2");
+
+        assert!(source.get_allocation("vm-1").await.is_ok());
+        assert!(source.get_allocation("vm-2").await.is_err());
+        let target = registry.get("new").await.unwrap();
+        assert_eq!(target.get_allocation("vm-2").await.unwrap().ip, "10.0.0.2");
+    }
+
+    #[tokio::test]
+    async fn test_migrate_rolls_back_on_exhaustion() {
+        let registry = registry().await;
+        let source = registry.get(DEFAULT_POOL).await.unwrap();
+        let target = registry.get("new").await.unwrap();
+
+        // Leave room for only one VM in the target
+        for i in 0..252 {
+            target.allocate_ip(format!("other-{}", i)).await.unwrap();
+        }
+        source.allocate_ip("vm-1".to_string()).await.unwrap();
+        source.allocate_ip("vm-2".to_string()).await.unwrap();
+
+        let result = registry.migrate(DEFAULT_POOL, "new", None).await;
+        assert!(matches!(result, Err(IpPoolError::NoAvailableIps)));
+
+        // Nothing moved
+        assert_eq!(source.list_allocations().await.len(), 2);
+        assert_eq!(target.list_allocations().await.len(), 252);
+    }
+
+    #[tokio::test]
+    async fn test_split_and_merge_back() {
+        let registry = registry().await;
+
+        let (lower, upper) = registry
+            .split(
+                DEFAULT_POOL,
+                "172.16.0.100".parse().unwrap(),
+                "upper".to_string(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(lower.get_stats().await["total"].as_u64().unwrap(), 98);
+        assert_eq!(upper.get_stats().await["total"].as_u64().unwrap(), 155);
+
+        let result = registry
+            .split(
+                DEFAULT_POOL,
+                "172.16.0.50".parse().unwrap(),
+                "upper".to_string(),
+            )
+            .await;
+        assert!(matches!(result, Err(IpPoolError::PoolAlreadyExists)));
+
+        let merged = registry.merge(DEFAULT_POOL, "upper").await.unwrap();
+        assert_eq!(merged.get_stats().await["total"].as_u64().unwrap(), 253);
+        assert!(registry.get("upper").await.is_err());
+
+        let result = registry.merge("new", DEFAULT_POOL).await;
+        assert!(matches!(result, Err(IpPoolError::InvalidMerge)));
+    }
+
+    #[tokio::test]
+    async fn test_allocations_per_interface() {
+        let registry = registry().await;
+        let default = registry.get(DEFAULT_POOL).await.unwrap();
+        let other = registry.get("new").await.unwrap();
+
+        let eth0 = Slot::new(Some("eth0".to_string()), None);
+        let eth1 = Slot::new(Some("eth1".to_string()), None);
+        default
+            .allocate_address("vm-1".to_string(), &eth0, Lease::PoolDefault)
+            .await
+            .unwrap();
+        default
+            .allocate_address("vm-1".to_string(), &eth1, Lease::PoolDefault)
+            .await
+            .unwrap();
+        other
+            .allocate_address("vm-1".to_string(), &eth1, Lease::PoolDefault)
+            .await
+            .unwrap();
+
+        let allocations = registry.allocations_of("vm-1").await;
+        let found: Vec<_> = allocations
+            .iter()
+            .map(|(pool, a)| {
+                (
+                    a.interface.as_deref().unwrap(),
+                    pool.as_str(),
+                    a.ip.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("eth0", DEFAULT_POOL, "172.16.0.2"),
+                ("eth1", DEFAULT_POOL, "172.16.0.3"),
+                ("eth1", "new", "10.0.0.2"),
+            ]
+        );
+        assert!(registry.allocations_of("vm-2").await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lease_expiry_task() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string())
+            .with_clock(Arc::new(MockClock::new(1_000)));
+        let registry = PoolRegistry::new(pool.clone());
+        let events = EventBus::new();
+
+        pool.allocate_ip_with_lease("vm-1".to_string(), Lease::Ttl(Duration::from_secs(90)))
+            .await
+            .unwrap();
+        tokio::spawn(registry.run_lease_expiry(
+            events.clone(),
+            Duration::from_secs(30),
+            HealthRegistry::default(),
+        ));
+
+        tokio::time::sleep(Duration::from_secs(75)).await;
+        assert!(pool.get_allocation("vm-1").await.is_ok());
+
+        // Reclaimed on the first tick after the lease ran out
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert!(pool.get_allocation("vm-1").await.is_err());
+        assert_eq!(events.recent(1).await[0].kind, EventKind::Expired);
+    }
+
+    #[tokio::test]
+    async fn test_expiry_warning_fires_once_in_window() {
+        let clock = MockClock::new(1_000);
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string())
+            .with_clock(Arc::new(clock.clone()));
+        pool.set_expiry_warning(Some(Duration::from_secs(30))).await;
+        let registry = PoolRegistry::new(pool.clone());
+        let events = EventBus::new();
+        let lease = || Lease::Ttl(Duration::from_secs(60));
+        // Newest first
+        let warnings = || async {
+            (events.recent(100).await.into_iter())
+                .filter(|event| event.kind == EventKind::Expiring)
+                .map(|event| event.details.unwrap()["expires_at"].clone())
+                .collect::<Vec<_>>()
+        };
+
+        pool.allocate_ip_with_lease("vm-1".to_string(), lease())
+            .await
+            .unwrap();
+        assert_eq!(
+            pool.get_allocation("vm-1").await.unwrap().renew_before,
+            Some(1_030)
+        );
+
+        // Not before renew_before
+        clock.advance(Duration::from_secs(29));
+        registry.expire_leases(&events).await;
+        assert!(warnings().await.is_empty());
+
+        // Once inside the window, however often the sweep runs
+        clock.advance(Duration::from_secs(1));
+        registry.expire_leases(&events).await;
+        clock.advance(Duration::from_secs(10));
+        registry.expire_leases(&events).await;
+        assert_eq!(warnings().await, vec![1_060]);
+
+        // A renewal that moves the expiry arms it again
+        pool.allocate_ip_with_lease("vm-1".to_string(), lease())
+            .await
+            .unwrap();
+        registry.expire_leases(&events).await;
+        assert_eq!(warnings().await, vec![1_060]);
+        clock.advance(Duration::from_secs(30));
+        registry.expire_leases(&events).await;
+        registry.expire_leases(&events).await;
+        assert_eq!(warnings().await, vec![1_100, 1_060]);
+    }
+
+    #[tokio::test]
+    async fn test_owner_is_told_about_expiry() {
+        let clock = MockClock::new(1_000);
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string())
+            .with_clock(Arc::new(clock.clone()));
+        pool.set_expiry_warning(Some(Duration::from_secs(30))).await;
+        let registry = PoolRegistry::new(pool.clone());
+        let events = EventBus::new();
+        let lease = || Lease::Ttl(Duration::from_secs(60));
+
+        let owned = Requested {
+            owner: Some("alice@example.com".to_string()),
+            ..Requested::default()
+        };
+        (pool.allocate_with(None, "vm-1".to_string(), &Slot::primary(), lease(), &owned))
+            .await
+            .unwrap();
+        // Without an owner the API key's team owns it, but only then
+        let team = Requested {
+            default_owner: Some("payments".to_string()),
+            ..Requested::default()
+        };
+        (pool.allocate_with(None, "vm-2".to_string(), &Slot::primary(), lease(), &team))
+            .await
+            .unwrap();
+        let renewed = Requested {
+            owner: None,
+            ..team
+        };
+        (pool.allocate_with(
+            None,
+            "vm-1".to_string(),
+            &Slot::primary(),
+            lease(),
+            &renewed,
+        ))
+        .await
+        .unwrap();
+
+        // Stored with the address and listed with the allocation
+        let owners = pool.snapshot().await.annotations;
+        assert_eq!(
+            owners["172.16.0.2"].owner.as_deref(),
+            Some("alice@example.com")
+        );
+        assert_eq!(owners["172.16.0.3"].owner.as_deref(), Some("payments"));
+        let vm_1 = pool.get_allocation("vm-1").await.unwrap();
+        assert_eq!(vm_1.owner.as_deref(), Some("alice@example.com"));
+
+        clock.advance(Duration::from_secs(45));
+        registry.expire_leases(&events).await;
+        let mut warned: Vec<_> = (events.recent(10).await)
+            .into_iter()
+            .filter(|event| event.kind == EventKind::Expiring)
+            .map(|event| (event.vm_id, event.details.unwrap()))
+            .collect();
+        warned.sort_by(|(a, _), (b, _)| a.cmp(b));
+        assert_eq!(
+            warned,
+            vec![
+                (
+                    "vm-1".to_string(),
+                    serde_json::json!({ "expires_at": 1_060, "owner": "alice@example.com" })
+                ),
+                (
+                    "vm-2".to_string(),
+                    serde_json::json!({ "expires_at": 1_060, "owner": "payments" })
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_migrate_unknown_pool() {
+        let registry = registry().await;
+
+        let result = registry.migrate(DEFAULT_POOL, "missing", None).await;
+        assert!(matches!(result, Err(IpPoolError::PoolNotFound)));
+    }
+}
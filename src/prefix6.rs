@@ -0,0 +1,194 @@
+use crate::ippool::{IpPool, IpPoolError};
+use std::net::Ipv6Addr;
+
+// Most prefixes one parent is carved into, e.g. /64s out of a /32
+const MAX_SPLIT_BITS: u8 = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct DelegatedPrefix {
+    pub prefix: String, // e.g. "2001:db8:100:1::/64"
+    pub vm_id: String,
+}
+
+// IPv6 prefixes of one length delegated out of a parent prefix, e.g. /64s
+// out of a /56, one per router or VM. Delegations are kept in `pool`, so
+// they are journaled, snapshotted and replicated with it.
+#[derive(Debug, Clone)]
+pub struct Prefix6Pool {
+    parent: u128,
+    parent_len: u8,
+    prefix_len: u8, // of the delegated prefixes
+    pool: IpPool,
+}
+
+impl Prefix6Pool {
+    // `network` is the parent prefix, e.g. "2001:db8:100::/56"
+    pub fn new(network: &str, prefix_len: u8, pool: IpPool) -> Result<Self, String> {
+        let (address, parent_len) = network
+            .split_once('/')
+            .ok_or_else(|| format!("{} is not a prefix like 2001:db8::/56", network))?;
+        let address: Ipv6Addr = address
+            .parse()
+            .map_err(|_| format!("{} is not an IPv6 address", address))?;
+        let parent_len: u8 = parent_len
+            .parse()
+            .ok()
+            .filter(|len| *len <= 128)
+            .ok_or_else(|| format!("{} is not a prefix length", parent_len))?;
+        if prefix_len > 128 || prefix_len <= parent_len {
+            return Err(format!(
+                "delegated prefixes (/{}) must be longer than {}",
+                prefix_len, network
+            ));
+        }
+        if prefix_len - parent_len > MAX_SPLIT_BITS {
+            return Err(format!(
+                "/{} prefixes are too fine-grained for {}",
+                prefix_len, network
+            ));
+        }
+        let parent = u128::from(address);
+        if parent & !mask(parent_len) != 0 {
+            return Err(format!("{} has host bits set", network));
+        }
+
+        Ok(Prefix6Pool {
+            parent,
+            parent_len,
+            prefix_len,
+            pool,
+        })
+    }
+
+    // Delegate the lowest free prefix to `vm_id`; a VM that already holds
+    // one gets it back
+    pub async fn allocate(&self, vm_id: String) -> Result<DelegatedPrefix, IpPoolError> {
+        let candidates = (0..self.capacity()).map(|index| self.prefix(index));
+        let prefix = self.pool.delegate_prefix6(&vm_id, candidates).await?;
+        Ok(DelegatedPrefix { prefix, vm_id })
+    }
+
+    pub async fn release(&self, vm_id: &str) -> Result<DelegatedPrefix, IpPoolError> {
+        let prefix = self.pool.release_prefix6(vm_id).await?;
+        Ok(DelegatedPrefix {
+            prefix,
+            vm_id: vm_id.to_string(),
+        })
+    }
+
+    pub async fn get(&self, vm_id: &str) -> Result<DelegatedPrefix, IpPoolError> {
+        self.list()
+            .await
+            .into_iter()
+            .find(|delegated| delegated.vm_id == vm_id)
+            .ok_or(IpPoolError::IpNotFound)
+    }
+
+    // Delegated prefixes in address order
+    pub async fn list(&self) -> Vec<DelegatedPrefix> {
+        let mut delegated: Vec<DelegatedPrefix> = (self.pool.prefixes6().await.into_iter())
+            .map(|(prefix, vm_id)| DelegatedPrefix { prefix, vm_id })
+            .collect();
+        delegated.sort_by_key(|delegated| {
+            let (address, _) = delegated.prefix.split_once('/')?;
+            address.parse::<Ipv6Addr>().ok()
+        });
+        delegated
+    }
+
+    pub async fn stats(&self) -> serde_json::Value {
+        let delegated = self.pool.prefixes6().await.len() as u64;
+        serde_json::json!({
+            "network": format!("{}/{}", Ipv6Addr::from(self.parent), self.parent_len),
+            "prefix_len": self.prefix_len,
+            "total": self.capacity(),
+            "allocated": delegated,
+            "available": self.capacity().saturating_sub(delegated),
+        })
+    }
+
+    fn capacity(&self) -> u64 {
+        1 << (self.prefix_len - self.parent_len)
+    }
+
+    // The `index`th prefix within the parent
+    fn prefix(&self, index: u64) -> String {
+        let network = self.parent | (u128::from(index) << (128 - u32::from(self.prefix_len)));
+        format!("{}/{}", Ipv6Addr::from(network), self.prefix_len)
+    }
+}
+
+fn mask(prefix_len: u8) -> u128 {
+    u128::MAX
+        .checked_shl(128 - u32::from(prefix_len))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool() -> IpPool {
+        IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string())
+    }
+
+    #[tokio::test]
+    async fn test_delegate_prefixes() {
+        let pool = Prefix6Pool::new("2001:db8:100::/62", 64, self::pool()).unwrap();
+
+        let first = pool.allocate("router-1".to_string()).await.unwrap();
+        assert_eq!(first.prefix, "2001:db8:100::/64");
+        let second = pool.allocate("router-2".to_string()).await.unwrap();
+        assert_eq!(second.prefix, "2001:db8:100:1::/64");
+
+        // The same VM gets its prefix back
+        assert_eq!(pool.allocate("router-1".to_string()).await.unwrap(), first);
+
+        // Released prefixes are handed out again, lowest first
+        pool.release("router-1").await.unwrap();
+        assert_eq!(pool.get("router-1").await, Err(IpPoolError::IpNotFound));
+        let third = pool.allocate("router-3".to_string()).await.unwrap();
+        assert_eq!(third.prefix, "2001:db8:100::/64");
+
+        pool.allocate("router-4".to_string()).await.unwrap();
+        pool.allocate("router-5".to_string()).await.unwrap();
+        assert_eq!(
+            pool.allocate("router-6".to_string()).await,
+            Err(IpPoolError::NoAvailableIps)
+        );
+        assert_eq!(pool.stats().await["available"], 0);
+        assert_eq!(pool.list().await[3].prefix, "2001:db8:100:3::/64");
+    }
+
+    #[tokio::test]
+    async fn test_delegations_survive_snapshots() {
+        let ip_pool = self::pool();
+        let pool = Prefix6Pool::new("2001:db8:100::/56", 64, ip_pool.clone()).unwrap();
+        pool.allocate("router-1".to_string()).await.unwrap();
+        pool.allocate("router-2".to_string()).await.unwrap();
+        pool.release("router-1").await.unwrap();
+
+        let snapshot = ip_pool.snapshot().await;
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let restored = IpPool::from_snapshot(serde_json::from_str(&json).unwrap()).unwrap();
+        let pool = Prefix6Pool::new("2001:db8:100::/56", 64, restored).unwrap();
+        assert_eq!(
+            pool.get("router-2").await.unwrap().prefix,
+            "2001:db8:100:1::/64"
+        );
+        assert_eq!(pool.get("router-1").await, Err(IpPoolError::IpNotFound));
+        assert_eq!(
+            pool.allocate("router-3".to_string()).await.unwrap().prefix,
+            "2001:db8:100::/64"
+        );
+    }
+
+    #[test]
+    fn test_invalid_parent() {
+        assert!(Prefix6Pool::new("2001:db8:100::/56", 56, pool()).is_err());
+        assert!(Prefix6Pool::new("2001:db8:100::1/56", 64, pool()).is_err());
+        assert!(Prefix6Pool::new("2001:db8::/16", 64, pool()).is_err());
+        assert!(Prefix6Pool::new("10.0.0.0/8", 16, pool()).is_err());
+        assert!(Prefix6Pool::new("2001:db8:100::/56", 64, pool()).is_ok());
+    }
+}
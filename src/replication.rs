@@ -0,0 +1,225 @@
+use crate::health::{HealthRegistry, Status};
+use crate::pools::PoolRegistry;
+use crate::storage::StoredState;
+use axum::body::Body;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+
+pub const STREAM_PATH: &str = "/api/v1/replication/stream";
+pub const CONTENT_TYPE: &str = "application/x-ndjson";
+
+// How often the stream looks for changes, and so the most a replica lags
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+// Sent when the state did not change for this long, so replicas can tell a
+// quiet primary from a dead connection
+const HEARTBEAT: Duration = Duration::from_secs(15);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// One line of the state stream: the full state whenever it changed, or
+// just the sequence number as a heartbeat
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Update {
+    pub seq: u64, // states sent on this connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<StoredState>,
+}
+
+struct Streamer {
+    pools: PoolRegistry,
+    interval: Duration,
+    fingerprint: Option<Vec<(String, u64)>>, // of the last state sent
+    seq: u64,
+    sent_at: Instant,
+}
+
+impl Streamer {
+    async fn next(&mut self) -> Update {
+        loop {
+            if self.seq > 0 {
+                tokio::time::sleep(self.interval).await;
+            }
+            let fingerprint = self.pools.fingerprint().await;
+            if self.fingerprint.as_ref() != Some(&fingerprint) {
+                self.fingerprint = Some(fingerprint);
+                self.seq += 1;
+                self.sent_at = Instant::now();
+                return Update {
+                    seq: self.seq,
+                    state: Some(self.pools.snapshot().await),
+                };
+            }
+            if self.sent_at.elapsed() >= HEARTBEAT {
+                self.sent_at = Instant::now();
+                return Update {
+                    seq: self.seq,
+                    state: None,
+                };
+            }
+        }
+    }
+}
+
+// State stream served by the primary: the current state right away, then
+// the new state whenever it changed, checked every `interval`
+pub fn stream(pools: PoolRegistry, interval: Duration) -> Body {
+    let streamer = Streamer {
+        pools,
+        interval,
+        fingerprint: None,
+        seq: 0,
+        sent_at: Instant::now(),
+    };
+    Body::from_stream(futures_util::stream::unfold(
+        streamer,
+        |mut streamer| async move {
+            let update = streamer.next().await;
+            let mut line = serde_json::to_string(&update).expect("state serializes");
+            line.push('\n');
+            Some((Ok::<_, Infallible>(line), streamer))
+        },
+    ))
+}
+
+// Follow the primary's state stream, replacing the local state with every
+// state received. Reconnects with backoff; the `replication` health
+// component is unhealthy until the first state arrives and while
+// disconnected.
+pub async fn follow(primary: String, pools: PoolRegistry, health: HealthRegistry) {
+    health.failure(
+        "replication",
+        Status::Unhealthy,
+        "not synced with primary yet",
+    );
+    let client = reqwest::Client::new();
+    let url = format!("{}{}", primary.trim_end_matches('/'), STREAM_PATH);
+    let mut backoff = Duration::from_secs(1);
+
+    loop {
+        let mut received = false;
+        let error = match receive(&client, &url, &pools, &health, &mut received).await {
+            Ok(()) => "stream ended".to_string(),
+            Err(e) => e,
+        };
+        tracing::warn!("Replication from {} interrupted: {}", primary, error);
+        health.failure("replication", Status::Unhealthy, &error);
+
+        // A connection that delivered starts the backoff over
+        if received {
+            backoff = Duration::from_secs(1);
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn receive(
+    client: &reqwest::Client,
+    url: &str,
+    pools: &PoolRegistry,
+    health: &HealthRegistry,
+    received: &mut bool,
+) -> Result<(), String> {
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?;
+
+    let mut buffer = Vec::new();
+    loop {
+        let chunk = tokio::time::timeout(2 * HEARTBEAT, response.chunk())
+            .await
+            .map_err(|_| "no heartbeat from primary".to_string())?
+            .map_err(|e| e.to_string())?;
+        let Some(chunk) = chunk else {
+            return Ok(());
+        };
+        buffer.extend_from_slice(&chunk);
+        while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            let update: Update = serde_json::from_slice(&line).map_err(|e| e.to_string())?;
+            if let Some(state) = update.state {
+                pools.replicate(state).await;
+                tracing::debug!("Replicated state #{} from primary", update.seq);
+            }
+            *received = true;
+            health.success("replication");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ippool::IpPool;
+    use axum::{Router, routing::get};
+    use tokio::net::TcpListener;
+
+    fn registry() -> PoolRegistry {
+        PoolRegistry::new(IpPool::new(
+            "172.16.0".to_string(),
+            "172.16.0.1".to_string(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_replica_follows_primary() {
+        let primary = registry();
+        primary
+            .insert(
+                "lab".to_string(),
+                IpPool::new("10.30.0".to_string(), "10.30.0.1".to_string()),
+            )
+            .await;
+        let pool = primary.get("default").await.unwrap();
+        let ip = pool.allocate_ip("vm-1".to_string()).await.unwrap();
+
+        let streamed = primary.clone();
+        let app = Router::new().route(
+            STREAM_PATH,
+            get(move || async move { stream(streamed, Duration::from_millis(10)) }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let replica = registry();
+        replica
+            .insert(
+                "stale".to_string(),
+                IpPool::new("10.40.0".to_string(), "10.40.0.1".to_string()),
+            )
+            .await;
+        let health = HealthRegistry::default();
+        tokio::spawn(follow(
+            format!("http://{}/", addr),
+            replica.clone(),
+            health.clone(),
+        ));
+
+        let replicated = |vm_id: &'static str| {
+            let replica = replica.clone();
+            async move {
+                for _ in 0..200 {
+                    let pool = replica.get("default").await.unwrap();
+                    if let Ok(allocation) = pool.get_allocation(vm_id).await {
+                        return Some(allocation.ip);
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                None
+            }
+        };
+        assert_eq!(replicated("vm-1").await, Some(ip));
+        // Pools the primary does not have are dropped
+        assert_eq!(replica.names().await, vec!["default", "lab"]);
+        assert_eq!(health.components()["replication"].status, Status::Healthy);
+
+        // Later changes follow
+        let ip = pool.allocate_ip("vm-2".to_string()).await.unwrap();
+        assert_eq!(replicated("vm-2").await, Some(ip));
+    }
+}
@@ -0,0 +1,362 @@
+use crate::check::{CheckStatus, PoolUsage};
+use crate::events::{EventBus, EventKind, unix_now};
+use crate::pools::PoolRegistry;
+use crate::webhooks::Webhook;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+// Output of the reports endpoint
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Markdown,
+    Html,
+}
+
+impl ReportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ReportFormat::Json => "application/json",
+            ReportFormat::Markdown => "text/markdown; charset=utf-8",
+            ReportFormat::Html => "text/html; charset=utf-8",
+        }
+    }
+}
+
+// Allocation changes within the report window
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Changes {
+    pub allocated: u64,
+    pub released: u64,
+    pub expired: u64,
+    pub migrated: u64,
+    pub net: i64, // allocated minus released and expired
+}
+
+impl Changes {
+    fn count(&mut self, kind: EventKind) {
+        match kind {
+            EventKind::Allocated => self.allocated += 1,
+            EventKind::Released => self.released += 1,
+            EventKind::Expired => self.expired += 1,
+            EventKind::Migrated => self.migrated += 1,
+            EventKind::Expiring | EventKind::Anomaly => return,
+        }
+        self.net = self.allocated as i64 - self.released as i64 - self.expired as i64;
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolReport {
+    pub name: String,
+    pub allocated: u64,
+    pub total: u64,
+    pub usage_percent: f64,
+    pub status: &'static str, // monitoring check status by the pool's thresholds
+    pub changes: Changes,
+}
+
+// Utilization of every pool now, and what changed over the window
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub generated_at: u64, // unix seconds
+    pub window_secs: u64,
+    pub from: u64,            // start of the window, unix seconds
+    pub complete: bool,       // retained events reach back to the start of the window
+    pub status: &'static str, // worst pool status
+    pub pools: Vec<PoolReport>,
+    pub changes: Changes, // every pool, including ones removed since
+}
+
+pub async fn generate(
+    pools: &PoolRegistry,
+    events: &EventBus,
+    window: Duration,
+    now: u64,
+) -> Report {
+    let from = now.saturating_sub(window.as_secs());
+
+    let recent = events.recent(usize::MAX).await;
+    // Without a single event dropped yet, nothing from the window is missing
+    let complete = recent
+        .last()
+        .is_none_or(|oldest| oldest.id == 1 || oldest.timestamp <= from);
+    let mut changes = Changes::default();
+    let mut by_pool: BTreeMap<String, Changes> = BTreeMap::new();
+    for event in recent.iter().take_while(|event| event.timestamp >= from) {
+        changes.count(event.kind);
+        by_pool
+            .entry(event.pool.clone())
+            .or_default()
+            .count(event.kind);
+    }
+
+    let mut reports = Vec::new();
+    let mut status = CheckStatus::Ok;
+    for name in pools.names().await {
+        let Ok(pool) = pools.get(&name).await else {
+            continue;
+        };
+        let stats = pool.get_stats().await;
+        let usage = PoolUsage {
+            name: name.clone(),
+            allocated: stats["allocated"].as_u64().unwrap_or(0),
+            total: stats["total"].as_u64().unwrap_or(0),
+            thresholds: pool.thresholds().await.unwrap_or_default(),
+        };
+        status = status.max(usage.status());
+        reports.push(PoolReport {
+            changes: by_pool.remove(&name).unwrap_or_default(),
+            usage_percent: (usage.percent() * 10.0).round() / 10.0,
+            status: usage.status().label(),
+            allocated: usage.allocated,
+            total: usage.total,
+            name,
+        });
+    }
+
+    Report {
+        generated_at: now,
+        window_secs: window.as_secs(),
+        from,
+        complete,
+        status: status.label(),
+        pools: reports,
+        changes,
+    }
+}
+
+fn title(report: &Report) -> String {
+    format!(
+        "IP pool report, last {}: {}",
+        window(report.window_secs),
+        report.status
+    )
+}
+
+// "7d", "12h", "30m" or "45s"
+fn window(secs: u64) -> String {
+    match secs {
+        0 => "0s".to_string(),
+        _ if secs.is_multiple_of(86400) => format!("{}d", secs / 86400),
+        _ if secs.is_multiple_of(3600) => format!("{}h", secs / 3600),
+        _ if secs.is_multiple_of(60) => format!("{}m", secs / 60),
+        _ => format!("{}s", secs),
+    }
+}
+
+fn signed(net: i64) -> String {
+    if net > 0 {
+        format!("+{}", net)
+    } else {
+        net.to_string()
+    }
+}
+
+fn incomplete_note(report: &Report) -> Option<&'static str> {
+    (!report.complete)
+        .then_some("Older events are no longer retained, changes cover only part of the window.")
+}
+
+pub fn markdown(report: &Report) -> String {
+    let mut out = format!("# {}\n\n", title(report));
+    let _ = writeln!(
+        out,
+        "| Pool | Allocated | Usage | Status | Allocations | Releases | Expirations | Net |"
+    );
+    let _ = writeln!(out, "|---|---:|---:|---|---:|---:|---:|---:|");
+    for pool in &report.pools {
+        let _ = writeln!(
+            out,
+            "| {} | {}/{} | {:.1}% | {} | {} | {} | {} | {} |",
+            pool.name,
+            pool.allocated,
+            pool.total,
+            pool.usage_percent,
+            pool.status,
+            pool.changes.allocated,
+            pool.changes.released,
+            pool.changes.expired,
+            signed(pool.changes.net)
+        );
+    }
+    let changes = &report.changes;
+    let _ = write!(
+        out,
+        "\n{} allocations, {} releases, {} expirations and {} migrations in total ({} net).\n",
+        changes.allocated,
+        changes.released,
+        changes.expired,
+        changes.migrated,
+        signed(changes.net)
+    );
+    if let Some(note) = incomplete_note(report) {
+        let _ = write!(out, "\n_{}_\n", note);
+    }
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn html(report: &Report) -> String {
+    let title = escape(&title(report));
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{title}</title></head>\n<body>\n<h1>{title}</h1>\n<table>\n"
+    );
+    out.push_str(
+        "<tr><th>Pool</th><th>Allocated</th><th>Usage</th><th>Status</th>\
+         <th>Allocations</th><th>Releases</th><th>Expirations</th><th>Net</th></tr>\n",
+    );
+    for pool in &report.pools {
+        let _ = writeln!(
+            out,
+            "<tr><td>{}</td><td>{}/{}</td><td>{:.1}%</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&pool.name),
+            pool.allocated,
+            pool.total,
+            pool.usage_percent,
+            pool.status,
+            pool.changes.allocated,
+            pool.changes.released,
+            pool.changes.expired,
+            signed(pool.changes.net)
+        );
+    }
+    let changes = &report.changes;
+    let _ = writeln!(
+        out,
+        "</table>\n<p>{} allocations, {} releases, {} expirations and {} migrations in total ({} net).</p>",
+        changes.allocated,
+        changes.released,
+        changes.expired,
+        changes.migrated,
+        signed(changes.net)
+    );
+    if let Some(note) = incomplete_note(report) {
+        let _ = writeln!(out, "<p><em>{}</em></p>", note);
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+// Push a report over the last `window` to the webhook every `interval`
+pub async fn run(
+    webhook: Webhook,
+    interval: Duration,
+    window: Duration,
+    pools: PoolRegistry,
+    events: EventBus,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let report = generate(&pools, &events, window, unix_now()).await;
+        let payload = serde_json::json!({
+            "title": title(&report),
+            "markdown": markdown(&report),
+            "report": report,
+        });
+        tracing::info!("📊 Pushing scheduled report");
+        webhook.deliver(None, payload).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ippool::IpPool;
+
+    #[tokio::test]
+    async fn test_report_counts_changes_in_window() {
+        let pools = PoolRegistry::new(IpPool::new(
+            "172.16.0".to_string(),
+            "172.16.0.1".to_string(),
+        ));
+        pools
+            .insert(
+                "lab".to_string(),
+                IpPool::new("10.30.0".to_string(), "10.30.0.1".to_string()),
+            )
+            .await;
+        let events = EventBus::new();
+        let default = pools.get("default").await.unwrap();
+        for vm in ["vm-1", "vm-2", "vm-3"] {
+            let ip = default.allocate_ip(vm.to_string()).await.unwrap();
+            events
+                .emit(EventKind::Allocated, "default", vm, &ip, None)
+                .await;
+        }
+        default.release_ip("vm-2").await.unwrap();
+        events
+            .emit(EventKind::Released, "default", "vm-2", "172.16.0.3", None)
+            .await;
+        events
+            .emit(EventKind::Released, "gone", "vm-9", "10.40.0.2", None)
+            .await;
+
+        let now = unix_now();
+        let report = generate(&pools, &events, Duration::from_secs(7 * 86400), now).await;
+        assert!(report.complete);
+        assert_eq!(report.from, now - 7 * 86400);
+        assert_eq!(report.pools.len(), 2);
+        let pool = &report.pools[0];
+        assert_eq!((pool.name.as_str(), pool.allocated), ("default", 2));
+        assert_eq!(
+            pool.changes,
+            Changes {
+                allocated: 3,
+                released: 1,
+                net: 2,
+                ..Changes::default()
+            }
+        );
+        assert_eq!(report.pools[1].changes, Changes::default());
+        // Removed pools still count towards the totals
+        assert_eq!(report.changes.released, 2);
+        assert_eq!(report.changes.net, 1);
+
+        // Events from before the window are left out
+        let later = generate(&pools, &events, Duration::from_secs(60), now + 3600).await;
+        assert_eq!(later.changes, Changes::default());
+
+        let markdown = markdown(&report);
+        assert!(markdown.starts_with("# IP pool report, last 7d: OK\n"));
+        assert!(markdown.contains("| default | 2/253 | 0.8% | OK | 3 | 1 | 0 | +2 |\n"));
+        assert!(markdown.contains("(+1 net)"));
+        let html = html(&report);
+        assert!(html.contains("<td>default</td><td>2/253</td>"));
+    }
+
+    #[test]
+    fn test_html_escapes_pool_names() {
+        let report = Report {
+            generated_at: 0,
+            window_secs: 3600,
+            from: 0,
+            complete: false,
+            status: "OK",
+            pools: vec![PoolReport {
+                name: "<script>".to_string(),
+                allocated: 0,
+                total: 10,
+                usage_percent: 0.0,
+                status: "OK",
+                changes: Changes::default(),
+            }],
+            changes: Changes::default(),
+        };
+        let html = html(&report);
+        assert!(html.contains("<td>&lt;script&gt;</td>"));
+        assert!(html.contains("<em>Older events are no longer retained"));
+        assert!(markdown(&report).starts_with("# IP pool report, last 1h: OK"));
+    }
+}
@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+
+// Extra route handed to VMs of a pool, e.g. towards the storage network
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaticRoute {
+    pub destination: Ipv4Addr,
+    pub prefix_len: u8,
+    pub next_hop: Ipv4Addr,
+}
+
+impl StaticRoute {
+    // "10.50.0.0/16=172.16.0.254"
+    pub fn parse(value: &str) -> Option<Self> {
+        let (cidr, next_hop) = value.split_once('=')?;
+        let (destination, prefix_len) = cidr.trim().split_once('/')?;
+        // u8 parsing takes a leading "+"
+        if !prefix_len.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let prefix_len: u8 = prefix_len.parse().ok().filter(|len| *len <= 32)?;
+
+        Some(StaticRoute {
+            destination: destination.parse().ok()?,
+            prefix_len,
+            next_hop: next_hop.trim().parse().ok()?,
+        })
+    }
+
+    pub fn cidr(&self) -> String {
+        format!("{}/{}", self.destination, self.prefix_len)
+    }
+}
+
+// Classless static route option (DHCP option 121, RFC 3442) as colon
+// separated hex, the format ISC dhcpd and most DHCP tooling accept
+pub fn dhcp_option_121(routes: &[StaticRoute]) -> Option<String> {
+    if routes.is_empty() {
+        return None;
+    }
+
+    let mut bytes = Vec::new();
+    for route in routes {
+        // Only the significant octets of the destination are sent
+        let significant = route.prefix_len.div_ceil(8) as usize;
+        bytes.push(route.prefix_len);
+        bytes.extend_from_slice(&route.destination.octets()[..significant]);
+        bytes.extend_from_slice(&route.next_hop.octets());
+    }
+
+    Some(
+        bytes
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(":"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit;
+    use proptest::prelude::*;
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(4096))]
+
+        #[test]
+        fn prop_parse(input in testkit::fuzz_input(), (addr, len) in testkit::cidr(), hop: u32) {
+            if let Some(route) = StaticRoute::parse(&input) {
+                let canonical = format!("{}={}", route.cidr(), route.next_hop);
+                prop_assert_eq!(StaticRoute::parse(&canonical), Some(route));
+                prop_assert!(input.chars().all(|c| "0123456789./= \t".contains(c)));
+            }
+            let route = StaticRoute::parse(&format!("{}/{}={}", addr, len, Ipv4Addr::from(hop)));
+            prop_assert_eq!(route.map(|route| route.cidr()), Some(format!("{}/{}", addr, len)));
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let route = StaticRoute::parse("10.50.0.0/16=172.16.0.254").unwrap();
+        assert_eq!(route.cidr(), "10.50.0.0/16");
+        assert_eq!(route.next_hop, Ipv4Addr::new(172, 16, 0, 254));
+
+        assert!(StaticRoute::parse("10.50.0.0/33=172.16.0.254").is_none());
+        assert!(StaticRoute::parse("10.50.0.0=172.16.0.254").is_none());
+    }
+
+    // Examples from RFC 3442
+    #[test]
+    fn test_dhcp_option_121() {
+        let routes = vec![
+            StaticRoute::parse("10.17.0.0/16=10.0.0.1").unwrap(),
+            StaticRoute::parse("0.0.0.0/0=10.0.0.1").unwrap(),
+            StaticRoute::parse("10.27.129.0/24=10.0.0.1").unwrap(),
+        ];
+
+        assert_eq!(
+            dhcp_option_121(&routes).unwrap(),
+            "10:0a:11:0a:00:00:01:00:0a:00:00:01:18:0a:1b:81:0a:00:00:01"
+        );
+        assert_eq!(dhcp_option_121(&[]), None);
+    }
+}
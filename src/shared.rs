@@ -0,0 +1,218 @@
+use crate::api_v2::Problem;
+use crate::events::EventBus;
+use crate::health::{HealthRegistry, Status};
+use crate::ippool::IpPoolError;
+use crate::pools::PoolRegistry;
+use crate::storage::{StateStore, StorageError};
+use axum::{
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+// Writes waiting longer than this for the lock answer 503
+const LOCK_WAIT: Duration = Duration::from_secs(5);
+const LOCK_RETRY: Duration = Duration::from_millis(25);
+
+// Several instances on one storage backend without consensus between them.
+// Every write holds the backend's write lock, starts from the state the
+// last writer saved and saves its own before letting go, so no two
+// instances ever hand out the same address. Reads are served from the
+// local copy, refreshed in the background.
+#[derive(Debug, Clone)]
+pub struct SharedStorage {
+    store: Arc<dyn StateStore>,
+    pools: PoolRegistry,
+    local: Arc<Mutex<()>>, // one write or refresh at a time within this instance
+}
+
+impl SharedStorage {
+    pub fn new(store: Arc<dyn StateStore>, pools: PoolRegistry) -> Self {
+        SharedStorage {
+            store,
+            pools,
+            local: Arc::default(),
+        }
+    }
+
+    async fn lock(&self) -> Result<(), IpPoolError> {
+        let deadline = Instant::now() + LOCK_WAIT;
+        loop {
+            match self.store.try_lock().await {
+                Ok(true) => return Ok(()),
+                Ok(false) if Instant::now() < deadline => tokio::time::sleep(LOCK_RETRY).await,
+                Ok(false) => {
+                    return Err(IpPoolError::StorageUnavailable(
+                        "write lock held by another instance".to_string(),
+                    ));
+                }
+                Err(e) => return Err(unavailable(e)),
+            }
+        }
+    }
+
+    async fn reload(&self) -> Result<(), StorageError> {
+        if let Some(state) = self.store.load().await? {
+            self.pools.replicate(state).await;
+        }
+        Ok(())
+    }
+
+    // Run `write` on the latest shared state under the write lock and save
+    // what it changed
+    pub async fn write<T>(&self, write: impl Future<Output = T>) -> Result<T, IpPoolError> {
+        let _local = self.local.lock().await;
+        self.lock().await?;
+        let result = self.locked(write).await;
+        // Left alone, the lock still expires with LOCK_TTL
+        if let Err(e) = self.store.unlock().await {
+            tracing::warn!(
+                "Failed to release the {} write lock: {}",
+                self.store.name(),
+                e
+            );
+        }
+        result
+    }
+
+    async fn locked<T>(&self, write: impl Future<Output = T>) -> Result<T, IpPoolError> {
+        self.reload().await.map_err(unavailable)?;
+        let before = self.pools.fingerprint().await;
+        let output = write.await;
+        if self.pools.fingerprint().await != before {
+            let state = self.pools.snapshot().await;
+            self.store.save(&state).await.map_err(unavailable)?;
+        }
+        Ok(output)
+    }
+
+    // Pick up what other instances wrote, for reads
+    pub async fn run_refresh(self, interval: Duration, health: HealthRegistry) {
+        health.register("storage");
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let result = {
+                let _local = self.local.lock().await;
+                self.reload().await
+            };
+            match result {
+                Ok(()) => health.success("storage"),
+                Err(e) => {
+                    tracing::debug!("Failed to refresh shared state: {}", e);
+                    health.failure("storage", Status::Unhealthy, e);
+                }
+            }
+        }
+    }
+
+    // Lease expiry under the write lock, like any other write
+    pub async fn run_lease_expiry(
+        self,
+        events: EventBus,
+        interval: Duration,
+        health: HealthRegistry,
+    ) {
+        health.register("lease_expiry");
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match self.write(self.pools.expire_leases(&events)).await {
+                Ok(_) => health.success("lease_expiry"),
+                Err(e) => health.failure("lease_expiry", Status::Degraded, e),
+            }
+        }
+    }
+}
+
+fn unavailable(e: StorageError) -> IpPoolError {
+    IpPoolError::StorageUnavailable(e.to_string())
+}
+
+// Serialize every request that may write through the shared backend
+pub async fn serialize_writes(
+    State(shared): State<SharedStorage>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) {
+        return next.run(request).await;
+    }
+    let v2 = request.uri().path().starts_with("/api/v2/");
+    match shared.write(next.run(request)).await {
+        Ok(response) => response,
+        Err(e) if v2 => Problem::from(e).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ippool::IpPool;
+    use crate::storage::FileStore;
+    use std::collections::HashSet;
+
+    fn instance(store: Arc<dyn StateStore>) -> (SharedStorage, PoolRegistry) {
+        let pools = PoolRegistry::new(IpPool::new(
+            "172.16.0".to_string(),
+            "172.16.0.1".to_string(),
+        ));
+        (SharedStorage::new(store, pools.clone()), pools)
+    }
+
+    #[tokio::test]
+    async fn test_instances_never_allocate_the_same_address() {
+        let dir = std::env::temp_dir().join(format!("ippool-shared-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+
+        // Two instances, each with its own view of the same file
+        let mut tasks = Vec::new();
+        for instance_id in 0..2 {
+            let (shared, pools) = instance(Arc::new(FileStore::new(path.clone())));
+            tasks.push(tokio::spawn(async move {
+                let mut ips = Vec::new();
+                for vm in 0..20 {
+                    let pool = pools.get("default").await.unwrap();
+                    let vm_id = format!("vm-{}-{}", instance_id, vm);
+                    let ip = shared.write(pool.allocate_ip(vm_id)).await.unwrap();
+                    ips.push(ip.unwrap());
+                }
+                ips
+            }));
+        }
+        let mut ips = HashSet::new();
+        for task in tasks {
+            for ip in task.await.unwrap() {
+                assert!(ips.insert(ip), "address handed out twice");
+            }
+        }
+        assert_eq!(ips.len(), 40);
+
+        // The file holds every allocation, and the lock is gone
+        let store = FileStore::new(path.clone());
+        let state = store.load().await.unwrap().unwrap();
+        assert_eq!(state["default"].allocations.len(), 40);
+        assert!(!dir.join("state.lock").exists());
+
+        // A lock held elsewhere makes writes wait, then fail
+        assert!(store.try_lock().await.unwrap());
+        let (shared, pools) = instance(Arc::new(FileStore::new(path.clone())));
+        let pool = pools.get("default").await.unwrap();
+        let started = Instant::now();
+        let result = shared.write(pool.allocate_ip("vm-late".to_string())).await;
+        assert!(matches!(result, Err(IpPoolError::StorageUnavailable(_))));
+        assert!(started.elapsed() >= LOCK_WAIT);
+        store.unlock().await.unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+use crate::ippool::{IpPoolError, Slot};
+use sha2::{Digest, Sha256};
+use std::net::Ipv6Addr;
+
+// How the interface identifier of a VM's IPv6 address is derived
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Ipv6Mode {
+    #[default]
+    Eui64, // from the MAC, as SLAAC guests with EUI-64 identifiers do
+    Stable, // hash of the VM and slot, for guests configured with it
+}
+
+impl std::str::FromStr for Ipv6Mode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "eui64" => Ok(Ipv6Mode::Eui64),
+            "stable" => Ok(Ipv6Mode::Stable),
+            _ => Err(format!("{} is not eui64 or stable", value)),
+        }
+    }
+}
+
+// IPv6 /64 of a pool, in which every VM's address is derived rather than
+// allocated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Slaac {
+    prefix: Ipv6Addr,
+    mode: Ipv6Mode,
+    secret: String, // keys the stable hash
+}
+
+impl Slaac {
+    // `prefix` is a /64, e.g. "2001:db8:1::/64"
+    pub fn new(prefix: &str, mode: Ipv6Mode, secret: String) -> Result<Self, String> {
+        let address = prefix.strip_suffix("/64").unwrap_or(prefix);
+        let prefix: Ipv6Addr = address
+            .parse()
+            .map_err(|_| format!("{} is not an IPv6 /64 prefix", prefix))?;
+        if u128::from(prefix) as u64 != 0 {
+            return Err(format!("{} has interface identifier bits set", prefix));
+        }
+        Ok(Slaac {
+            prefix,
+            mode,
+            secret,
+        })
+    }
+
+    pub fn prefix(&self) -> String {
+        format!("{}/64", self.prefix)
+    }
+
+    // The address of the VM's slot, None in EUI-64 mode without a MAC
+    pub fn address(&self, vm_id: &str, slot: &Slot, mac: Option<&str>) -> Option<Ipv6Addr> {
+        let iid = match (self.mode, mac) {
+            (Ipv6Mode::Eui64, Some(mac)) => eui64(parse_mac(mac)?),
+            (Ipv6Mode::Eui64, None) => return None,
+            (Ipv6Mode::Stable, _) => self.stable(vm_id, slot),
+        };
+        Some(Ipv6Addr::from(u128::from(self.prefix) | u128::from(iid)))
+    }
+
+    // First 64 bits of SHA-256 over the secret, prefix and slot, so the
+    // address survives re-allocation and cannot be guessed from the VM ID
+    fn stable(&self, vm_id: &str, slot: &Slot) -> u64 {
+        let mut hash = Sha256::new();
+        for part in [
+            self.secret.as_bytes(),
+            &self.prefix.octets(),
+            vm_id.as_bytes(),
+            slot.interface.as_deref().unwrap_or("").as_bytes(),
+            slot.purpose.as_bytes(),
+        ] {
+            hash.update((part.len() as u32).to_be_bytes());
+            hash.update(part);
+        }
+        let digest = hash.finalize();
+        u64::from_be_bytes(digest[..8].try_into().unwrap())
+    }
+}
+
+// "52:54:00:12:34:56" or "52-54-00-12-34-56"
+pub fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let octets: Vec<u8> = mac
+        .split([':', '-'])
+        .map(|octet| {
+            // from_str_radix takes a leading "+"
+            (octet.len() == 2 && octet.bytes().all(|b| b.is_ascii_hexdigit()))
+                .then(|| u8::from_str_radix(octet, 16).ok())
+                .flatten()
+        })
+        .collect::<Option<_>>()?;
+    octets.try_into().ok()
+}
+
+// The MAC as stored with allocations: lowercase, colon separated
+pub fn canonical_mac(mac: &str) -> Option<String> {
+    Some(
+        parse_mac(mac)?
+            .map(|octet| format!("{:02x}", octet))
+            .join(":"),
+    )
+}
+
+// Modified EUI-64 (RFC 4291 appendix A): ff:fe in the middle, with the
+// universal/local bit flipped
+fn eui64(mac: [u8; 6]) -> u64 {
+    u64::from_be_bytes([
+        mac[0] ^ 0x02,
+        mac[1],
+        mac[2],
+        0xff,
+        0xfe,
+        mac[3],
+        mac[4],
+        mac[5],
+    ])
+}
+
+// Refuse MACs that cannot be parsed before anything is allocated
+pub fn check_mac(mac: Option<&str>) -> Result<(), IpPoolError> {
+    match mac {
+        Some(mac) if parse_mac(mac).is_none() => Err(IpPoolError::InvalidMac),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit;
+    use proptest::prelude::*;
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(4096))]
+
+        #[test]
+        fn prop_parse_mac(input in testkit::fuzz_input(), mac: [u8; 6]) {
+            // Accepted input is the MAC, written with either separator
+            if let Some(written) = canonical_mac(&input) {
+                prop_assert_eq!(input.to_ascii_lowercase().replace('-', ":"), written);
+            }
+            let written = mac.map(|octet| format!("{:02X}", octet)).join("-");
+            prop_assert_eq!(parse_mac(&written), Some(mac));
+        }
+    }
+
+    #[test]
+    fn test_eui64_address() {
+        let slaac = Slaac::new("2001:db8:1::/64", Ipv6Mode::Eui64, String::new()).unwrap();
+        let slot = Slot::primary();
+        assert_eq!(
+            slaac.address("vm-1", &slot, Some("52:54:00:12:34:56")),
+            Some("2001:db8:1:0:5054:ff:fe12:3456".parse().unwrap())
+        );
+        assert_eq!(slaac.address("vm-1", &slot, None), None);
+        assert_eq!(parse_mac("52-54-00-12-34-5"), None);
+        assert!(check_mac(Some("52:54:00:12:34")).is_err());
+
+        assert!(Slaac::new("2001:db8:1::1/64", Ipv6Mode::Eui64, String::new()).is_err());
+    }
+
+    #[test]
+    fn test_stable_address() {
+        let slaac = Slaac::new("2001:db8:1::", Ipv6Mode::Stable, "secret".to_string()).unwrap();
+        let eth1 = Slot::new(Some("eth1".to_string()), None);
+
+        let address = slaac.address("vm-1", &Slot::primary(), None).unwrap();
+        assert_eq!(u128::from(address) >> 64, u128::from(slaac.prefix) >> 64);
+        // Same VM and slot, same address, whatever the MAC
+        assert_eq!(
+            slaac.address("vm-1", &Slot::primary(), Some("52:54:00:12:34:56")),
+            Some(address)
+        );
+        assert_ne!(slaac.address("vm-1", &eth1, None), Some(address));
+        assert_ne!(slaac.address("vm-2", &Slot::primary(), None), Some(address));
+
+        let other = Slaac::new("2001:db8:1::/64", Ipv6Mode::Stable, "other".to_string()).unwrap();
+        assert_ne!(other.address("vm-1", &Slot::primary(), None), Some(address));
+    }
+}
@@ -0,0 +1,570 @@
+use crate::config::SnmpConfig;
+use crate::pools::PoolRegistry;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+// Net-SNMP's playpen arc, free for local use. Sites with an enterprise
+// number of their own set SNMP_BASE_OID instead.
+pub const DEFAULT_BASE_OID: &str = "1.3.6.1.4.1.8072.9999.9999";
+
+// system group objects every SNMP tool asks for first
+const SYS_DESCR: [u32; 9] = [1, 3, 6, 1, 2, 1, 1, 1, 0];
+const SYS_OBJECT_ID: [u32; 9] = [1, 3, 6, 1, 2, 1, 1, 2, 0];
+const SYS_UP_TIME: [u32; 9] = [1, 3, 6, 1, 2, 1, 1, 3, 0];
+
+// Columns of the pool table (<base>.2.1.<column>.<index>) taken from the
+// pool stats
+const POOL_GAUGES: [(u32, &str); 5] = [
+    (3, "total"),
+    (4, "allocated"),
+    (5, "available"),
+    (6, "reserved"),
+    (7, "pending_release"),
+];
+const COLUMN_INDEX: u32 = 1;
+const COLUMN_NAME: u32 = 2;
+// Allocated share of the pool, in hundredths of a percent
+const COLUMN_UTILIZATION: u32 = 8;
+
+const VERSION_1: i64 = 0;
+const VERSION_2C: i64 = 1;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_GAUGE: u8 = 0x42;
+const TAG_TIME_TICKS: u8 = 0x43;
+const TAG_NO_SUCH_OBJECT: u8 = 0x80;
+const TAG_END_OF_MIB_VIEW: u8 = 0x82;
+
+const PDU_GET: u8 = 0xa0;
+const PDU_GET_NEXT: u8 = 0xa1;
+const PDU_RESPONSE: u8 = 0xa2;
+const PDU_SET: u8 = 0xa3;
+const PDU_GET_BULK: u8 = 0xa5;
+
+const NO_SUCH_NAME: i64 = 2;
+const NOT_WRITABLE: i64 = 17;
+
+// Bindings a GetBulk answer is cut at, to stay within one datagram
+const MAX_BULK_BINDINGS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Integer(i64),
+    OctetString(Vec<u8>),
+    ObjectId(Vec<u32>),
+    Gauge(u32),
+    TimeTicks(u32),
+    Null,
+    NoSuchObject,
+    EndOfMibView,
+}
+
+impl Value {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Value::Integer(value) => push_integer(buf, TAG_INTEGER, *value),
+            Value::OctetString(bytes) => push_tlv(buf, TAG_OCTET_STRING, bytes),
+            Value::ObjectId(oid) => push_tlv(buf, TAG_OID, &encode_oid(oid)),
+            Value::Gauge(value) => push_integer(buf, TAG_GAUGE, *value as i64),
+            Value::TimeTicks(value) => push_integer(buf, TAG_TIME_TICKS, *value as i64),
+            Value::Null => push_tlv(buf, TAG_NULL, &[]),
+            Value::NoSuchObject => push_tlv(buf, TAG_NO_SUCH_OBJECT, &[]),
+            Value::EndOfMibView => push_tlv(buf, TAG_END_OF_MIB_VIEW, &[]),
+        }
+    }
+}
+
+// An SNMPv1/v2c request, with the OIDs it names; values sent along are not
+// kept since nothing can be set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub version: i64,
+    pub community: Vec<u8>,
+    pub pdu: u8,
+    pub request_id: i64,
+    // Error status and index, or non-repeaters and max-repetitions in GetBulk
+    pub fields: (i64, i64),
+    pub oids: Vec<Vec<u32>>,
+}
+
+impl Request {
+    // None for anything that is not a well-formed v1/v2c message
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        let (tag, message, _) = read_tlv(packet)?;
+        if tag != TAG_SEQUENCE {
+            return None;
+        }
+        let (version, rest) = read_integer(message)?;
+        if version != VERSION_1 && version != VERSION_2C {
+            return None;
+        }
+        let (tag, community, rest) = read_tlv(rest)?;
+        if tag != TAG_OCTET_STRING {
+            return None;
+        }
+        let (pdu, body, _) = read_tlv(rest)?;
+        let (request_id, rest) = read_integer(body)?;
+        let (first, rest) = read_integer(rest)?;
+        let (second, rest) = read_integer(rest)?;
+        let (tag, mut bindings, _) = read_tlv(rest)?;
+        if tag != TAG_SEQUENCE {
+            return None;
+        }
+        let mut oids = Vec::new();
+        while !bindings.is_empty() {
+            let (tag, binding, rest) = read_tlv(bindings)?;
+            let (oid_tag, oid, _) = read_tlv(binding)?;
+            if tag != TAG_SEQUENCE || oid_tag != TAG_OID {
+                return None;
+            }
+            oids.push(decode_oid(oid)?);
+            bindings = rest;
+        }
+        Some(Request {
+            version,
+            community: community.to_vec(),
+            pdu,
+            request_id,
+            fields: (first, second),
+            oids,
+        })
+    }
+}
+
+// Everything the agent serves, in OID order, as of one request
+#[derive(Debug, Default)]
+pub struct Mib {
+    objects: BTreeMap<Vec<u32>, Value>,
+}
+
+impl Mib {
+    pub async fn new(base: &[u32], pools: &PoolRegistry, uptime: Duration) -> Self {
+        let mut objects = BTreeMap::new();
+        let descr = format!("ippool {}", env!("CARGO_PKG_VERSION"));
+        objects.insert(SYS_DESCR.to_vec(), Value::OctetString(descr.into_bytes()));
+        objects.insert(SYS_OBJECT_ID.to_vec(), Value::ObjectId(base.to_vec()));
+        let ticks = (uptime.as_millis() / 10) as u32;
+        objects.insert(SYS_UP_TIME.to_vec(), Value::TimeTicks(ticks));
+
+        let names = pools.names().await;
+        let mut index = 0;
+        for name in names {
+            let Ok(pool) = pools.get(&name).await else {
+                continue;
+            };
+            index += 1;
+            let stats = pool.get_stats().await;
+            let column = |column: u32| [base, &[2, 1, column, index]].concat();
+            objects.insert(column(COLUMN_INDEX), Value::Integer(index as i64));
+            objects.insert(column(COLUMN_NAME), Value::OctetString(name.into_bytes()));
+            for (number, field) in POOL_GAUGES {
+                let value = stats[field].as_u64().unwrap_or(0);
+                objects.insert(column(number), Value::Gauge(value as u32));
+            }
+            let usage = stats["usage"].as_f64().unwrap_or(0.0);
+            objects.insert(
+                column(COLUMN_UTILIZATION),
+                Value::Gauge((usage * 100.0).round() as u32),
+            );
+        }
+        objects.insert([base, &[1, 0]].concat(), Value::Integer(index as i64));
+        Mib { objects }
+    }
+
+    fn next(&self, oid: &[u32]) -> Option<(&Vec<u32>, &Value)> {
+        self.objects
+            .range::<[u32], _>((Bound::Excluded(oid), Bound::Unbounded))
+            .next()
+    }
+
+    // Response to `request`, None for PDUs a read-only v1/v2c agent does
+    // not answer
+    pub fn respond(&self, request: &Request) -> Option<Vec<u8>> {
+        let v1 = request.version == VERSION_1;
+        let mut bindings = Vec::new();
+        let mut error = None;
+        match request.pdu {
+            PDU_GET => {
+                for (position, oid) in request.oids.iter().enumerate() {
+                    match self.objects.get(oid) {
+                        Some(value) => bindings.push((oid.clone(), value.clone())),
+                        None if v1 => {
+                            error = Some((NO_SUCH_NAME, position + 1));
+                            break;
+                        }
+                        None => bindings.push((oid.clone(), Value::NoSuchObject)),
+                    }
+                }
+            }
+            PDU_GET_NEXT => {
+                for (position, oid) in request.oids.iter().enumerate() {
+                    match self.next(oid) {
+                        Some((next, value)) => bindings.push((next.clone(), value.clone())),
+                        None if v1 => {
+                            error = Some((NO_SUCH_NAME, position + 1));
+                            break;
+                        }
+                        None => bindings.push((oid.clone(), Value::EndOfMibView)),
+                    }
+                }
+            }
+            PDU_GET_BULK if !v1 => {
+                let (non_repeaters, max_repetitions) = request.fields;
+                let split = (non_repeaters.max(0) as usize).min(request.oids.len());
+                let (single, repeated) = request.oids.split_at(split);
+                for oid in single {
+                    bindings.push(self.next_binding(oid));
+                }
+                let mut cursors = repeated.to_vec();
+                for _ in 0..max_repetitions.max(0) {
+                    if cursors.is_empty() || bindings.len() >= MAX_BULK_BINDINGS {
+                        break;
+                    }
+                    let mut ended = 0;
+                    for cursor in &mut cursors {
+                        let (oid, value) = self.next_binding(cursor);
+                        ended += usize::from(value == Value::EndOfMibView);
+                        cursor.clone_from(&oid);
+                        bindings.push((oid, value));
+                    }
+                    if ended == cursors.len() {
+                        break;
+                    }
+                }
+                bindings.truncate(MAX_BULK_BINDINGS);
+            }
+            PDU_SET => {
+                let status = if v1 { NO_SUCH_NAME } else { NOT_WRITABLE };
+                error = Some((status, 1));
+            }
+            _ => return None,
+        }
+
+        // An error answer carries the request's bindings back unchanged
+        let (status, index) = error.unwrap_or((0, 0));
+        if error.is_some() {
+            bindings = request
+                .oids
+                .iter()
+                .map(|oid| (oid.clone(), Value::Null))
+                .collect();
+        }
+        Some(encode_response(request, status, index as i64, &bindings))
+    }
+
+    fn next_binding(&self, oid: &[u32]) -> (Vec<u32>, Value) {
+        match self.next(oid) {
+            Some((next, value)) => (next.clone(), value.clone()),
+            None => (oid.to_vec(), Value::EndOfMibView),
+        }
+    }
+}
+
+fn encode_response(
+    request: &Request,
+    status: i64,
+    index: i64,
+    bindings: &[(Vec<u32>, Value)],
+) -> Vec<u8> {
+    let mut list = Vec::new();
+    for (oid, value) in bindings {
+        let mut binding = Vec::new();
+        push_tlv(&mut binding, TAG_OID, &encode_oid(oid));
+        value.encode(&mut binding);
+        push_tlv(&mut list, TAG_SEQUENCE, &binding);
+    }
+    let mut pdu = Vec::new();
+    push_integer(&mut pdu, TAG_INTEGER, request.request_id);
+    push_integer(&mut pdu, TAG_INTEGER, status);
+    push_integer(&mut pdu, TAG_INTEGER, index);
+    push_tlv(&mut pdu, TAG_SEQUENCE, &list);
+
+    let mut message = Vec::new();
+    push_integer(&mut message, TAG_INTEGER, request.version);
+    push_tlv(&mut message, TAG_OCTET_STRING, &request.community);
+    push_tlv(&mut message, PDU_RESPONSE, &pdu);
+    let mut packet = Vec::new();
+    push_tlv(&mut packet, TAG_SEQUENCE, &message);
+    packet
+}
+
+fn push_tlv(buf: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    buf.push(tag);
+    let len = value.len();
+    if len < 0x80 {
+        buf.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|byte| **byte == 0).count();
+        buf.push(0x80 | (bytes.len() - skip) as u8);
+        buf.extend_from_slice(&bytes[skip..]);
+    }
+    buf.extend_from_slice(value);
+}
+
+// Two's complement in as few bytes as keep the sign
+fn push_integer(buf: &mut Vec<u8>, tag: u8, value: i64) {
+    let bytes = value.to_be_bytes();
+    let mut start = 0;
+    while start < 7 {
+        let (byte, next) = (bytes[start], bytes[start + 1]);
+        if (byte == 0 && next & 0x80 == 0) || (byte == 0xff && next & 0x80 != 0) {
+            start += 1;
+        } else {
+            break;
+        }
+    }
+    push_tlv(buf, tag, &bytes[start..]);
+}
+
+// Tag, contents and what follows of the TLV at the start of `buf`
+fn read_tlv(buf: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *buf.first()?;
+    let first = *buf.get(1)? as usize;
+    let (len, start) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let count = first & 0x7f;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let bytes = buf.get(2..2 + count)?;
+        let len = bytes
+            .iter()
+            .fold(0, |len, byte| (len << 8) | *byte as usize);
+        (len, 2 + count)
+    };
+    let value = buf.get(start..start.checked_add(len)?)?;
+    Some((tag, value, &buf[start + len..]))
+}
+
+fn read_integer(buf: &[u8]) -> Option<(i64, &[u8])> {
+    let (tag, value, rest) = read_tlv(buf)?;
+    if tag != TAG_INTEGER || value.is_empty() || value.len() > 8 {
+        return None;
+    }
+    let sign = if value[0] & 0x80 != 0 { -1 } else { 0 };
+    let value = value
+        .iter()
+        .fold(sign, |acc: i64, byte| (acc << 8) | *byte as i64);
+    Some((value, rest))
+}
+
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let (first, rest) = match oid {
+        [a, b, rest @ ..] => (a * 40 + b, rest),
+        [a] => (a * 40, &[][..]),
+        [] => (0, &[][..]),
+    };
+    for arc in std::iter::once(first).chain(rest.iter().copied()) {
+        let mut groups = vec![(arc & 0x7f) as u8];
+        let mut arc = arc >> 7;
+        while arc > 0 {
+            groups.push(0x80 | (arc & 0x7f) as u8);
+            arc >>= 7;
+        }
+        buf.extend(groups.iter().rev());
+    }
+    buf
+}
+
+fn decode_oid(buf: &[u8]) -> Option<Vec<u32>> {
+    let mut arcs = Vec::new();
+    let mut arc: u32 = 0;
+    for byte in buf {
+        arc = arc.checked_mul(128)? | (byte & 0x7f) as u32;
+        if byte & 0x80 == 0 {
+            arcs.push(arc);
+            arc = 0;
+        }
+    }
+    if buf.last().is_some_and(|byte| byte & 0x80 != 0) {
+        return None;
+    }
+    let first = *arcs.first()?;
+    let top = (first / 40).min(2);
+    arcs.splice(0..1, [top, first - top * 40]);
+    Some(arcs)
+}
+
+// "1.3.6.1.4.1.8072" or ".1.3.6.1.4.1.8072"
+pub fn parse_oid(value: &str) -> Option<Vec<u32>> {
+    let oid: Vec<u32> = value
+        .trim_start_matches('.')
+        .split('.')
+        .map(|arc| arc.parse().ok())
+        .collect::<Option<_>>()?;
+    (oid.len() >= 2 && oid[0] <= 2).then_some(oid)
+}
+
+// Answer requests carrying the configured community; others are dropped
+// without a reply, as agents do
+pub async fn run(socket: UdpSocket, config: SnmpConfig, base: Vec<u32>, pools: PoolRegistry) {
+    let started = Instant::now();
+    let mut buf = vec![0; 65535];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                tracing::debug!("SNMP receive failed: {}", e);
+                continue;
+            }
+        };
+        let Some(request) = Request::parse(&buf[..len]) else {
+            tracing::debug!("Ignoring malformed SNMP packet from {}", peer);
+            continue;
+        };
+        if request.community != config.community.as_bytes() {
+            tracing::debug!("Ignoring SNMP request with a wrong community from {}", peer);
+            continue;
+        }
+        let mib = Mib::new(&base, &pools, started.elapsed()).await;
+        if let Some(response) = mib.respond(&request)
+            && let Err(e) = socket.send_to(&response, peer).await
+        {
+            tracing::warn!("SNMP response to {} failed: {}", peer, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ippool::IpPool;
+
+    fn request(version: i64, pdu: u8, fields: (i64, i64), oids: &[&[u32]]) -> Request {
+        Request {
+            version,
+            community: b"public".to_vec(),
+            pdu,
+            request_id: 42,
+            fields,
+            oids: oids.iter().map(|oid| oid.to_vec()).collect(),
+        }
+    }
+
+    // OID, tag and contents of a response binding
+    type Binding = (Vec<u32>, u8, Vec<u8>);
+
+    // Error status, error index and bindings of an encoded response
+    fn bindings(packet: &[u8]) -> (i64, i64, Vec<Binding>) {
+        let (_, message, _) = read_tlv(packet).unwrap();
+        let (_, rest) = read_integer(message).unwrap();
+        let (_, _, rest) = read_tlv(rest).unwrap();
+        let (pdu, body, _) = read_tlv(rest).unwrap();
+        assert_eq!(pdu, PDU_RESPONSE);
+        let (request_id, rest) = read_integer(body).unwrap();
+        assert_eq!(request_id, 42);
+        let (status, rest) = read_integer(rest).unwrap();
+        let (index, rest) = read_integer(rest).unwrap();
+        let (_, mut list, _) = read_tlv(rest).unwrap();
+        let mut bindings = Vec::new();
+        while !list.is_empty() {
+            let (_, binding, rest) = read_tlv(list).unwrap();
+            let (_, oid, value) = read_tlv(binding).unwrap();
+            let (tag, value, _) = read_tlv(value).unwrap();
+            bindings.push((decode_oid(oid).unwrap(), tag, value.to_vec()));
+            list = rest;
+        }
+        (status, index, bindings)
+    }
+
+    #[tokio::test]
+    async fn test_snmp_agent() {
+        let pools = PoolRegistry::new(IpPool::new(
+            "172.16.0".to_string(),
+            "172.16.0.1".to_string(),
+        ));
+        pools
+            .get("default")
+            .await
+            .unwrap()
+            .allocate_ip("vm-1".to_string())
+            .await
+            .unwrap();
+        let base = parse_oid(DEFAULT_BASE_OID).unwrap();
+        let mib = Mib::new(&base, &pools, Duration::from_secs(3)).await;
+        let column = |column: u32| [&base[..], &[2, 1, column, 1]].concat();
+
+        // What a client sends for `snmpget -v2c -c public ... sysUpTime.0`
+        // round-trips through the parser
+        let get = request(VERSION_2C, PDU_GET, (0, 0), &[&SYS_UP_TIME, &column(4)]);
+        let unset: Vec<_> = get
+            .oids
+            .iter()
+            .map(|oid| (oid.clone(), Value::Null))
+            .collect();
+        let packet = encode_response(&get, 0, 0, &unset);
+        let parsed = Request::parse(&packet).unwrap();
+        assert_eq!(parsed.pdu, PDU_RESPONSE);
+        assert_eq!(parsed.oids, get.oids);
+
+        let (status, _, values) = bindings(&mib.respond(&get).unwrap());
+        assert_eq!(status, 0);
+        assert_eq!(
+            values[0],
+            (SYS_UP_TIME.to_vec(), TAG_TIME_TICKS, vec![1, 0x2c])
+        );
+        assert_eq!(values[1], (column(4), TAG_GAUGE, vec![1]));
+
+        // Walking the pool table: index, name, then the gauges
+        let next = request(
+            VERSION_2C,
+            PDU_GET_NEXT,
+            (0, 0),
+            &[&[&base[..], &[2]].concat()],
+        );
+        let (_, _, values) = bindings(&mib.respond(&next).unwrap());
+        assert_eq!(values[0], (column(COLUMN_INDEX), TAG_INTEGER, vec![1]));
+        let bulk = request(VERSION_2C, PDU_GET_BULK, (0, 10), &[&column(COLUMN_NAME)]);
+        let (_, _, values) = bindings(&mib.respond(&bulk).unwrap());
+        let gauges: Vec<(u8, Vec<u8>)> = values
+            .into_iter()
+            .map(|(_, tag, value)| (tag, value))
+            .collect();
+        // 253 total, 1 allocated, 252 available, 1 reserved (the gateway),
+        // none pending, 0.40% used; then past the end of the MIB
+        assert_eq!(
+            gauges,
+            vec![
+                (TAG_GAUGE, vec![0, 253]),
+                (TAG_GAUGE, vec![1]),
+                (TAG_GAUGE, vec![0, 252]),
+                (TAG_GAUGE, vec![1]),
+                (TAG_GAUGE, vec![0]),
+                (TAG_GAUGE, vec![40]),
+                (TAG_END_OF_MIB_VIEW, vec![]),
+            ]
+        );
+
+        // Unknown objects: an exception in v2c, an error in v1
+        let missing: &[u32] = &[1, 3, 6, 1, 2, 1, 2, 1, 0];
+        let get = request(VERSION_2C, PDU_GET, (0, 0), &[missing]);
+        let (_, _, values) = bindings(&mib.respond(&get).unwrap());
+        assert_eq!(values[0].1, TAG_NO_SUCH_OBJECT);
+        let get = request(VERSION_1, PDU_GET, (0, 0), &[&SYS_DESCR, missing]);
+        let (status, index, values) = bindings(&mib.respond(&get).unwrap());
+        assert_eq!((status, index), (NO_SUCH_NAME, 2));
+        assert_eq!(values[1], (missing.to_vec(), TAG_NULL, vec![]));
+
+        // Nothing can be set, and v1 has no GetBulk
+        let set = request(VERSION_2C, PDU_SET, (0, 0), &[&SYS_DESCR]);
+        assert_eq!(bindings(&mib.respond(&set).unwrap()).0, NOT_WRITABLE);
+        let bulk = request(VERSION_1, PDU_GET_BULK, (0, 10), &[&SYS_DESCR]);
+        assert_eq!(mib.respond(&bulk), None);
+
+        assert_eq!(decode_oid(&encode_oid(&base)), Some(base));
+        assert_eq!(parse_oid(".1.3.6"), Some(vec![1, 3, 6]));
+        assert_eq!(parse_oid("1.3.x"), None);
+        // Truncated and overlong packets are rejected
+        assert_eq!(Request::parse(&packet[..packet.len() - 1]), None);
+        assert_eq!(Request::parse(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff]), None);
+    }
+}
@@ -0,0 +1,68 @@
+use crate::approvals::ApprovalQueue;
+use crate::budget::AllocationBudget;
+use crate::changes::ChangeFeed;
+use crate::events::EventBus;
+use crate::health::HealthRegistry;
+use crate::insights::Insights;
+use crate::ippool::IpPool;
+use crate::leaks::LeakDetector;
+use crate::maintenance::Maintenance;
+use crate::perf::PerfStats;
+use crate::pools::PoolRegistry;
+use crate::storage::StateStore;
+use crate::sweep::Sweeps;
+use crate::templates::Templates;
+use crate::webhooks::Webhooks;
+use axum::extract::FromRef;
+use std::sync::Arc;
+
+// Shared application state handed to every handler
+#[derive(Debug, Clone)]
+pub struct AppState {
+    pub pool: IpPool, // default pool
+    pub pools: PoolRegistry,
+    pub events: EventBus,
+    pub perf: PerfStats,
+    pub health: HealthRegistry,
+    pub maintenance: Maintenance,                    // read-only switch
+    pub sweeps: Sweeps,                              // ping sweep settings and latest reports
+    pub snapshot_store: Option<Arc<dyn StateStore>>, // named snapshots, None without a backend
+    pub templates: Arc<Templates>,                   // pool templates by name
+    pub webhooks: Webhooks,                          // delivery status and dead letters
+    pub budget: Option<AllocationBudget>,            // new allocations per API key, None: unlimited
+    pub insights: Insights,                          // per-caller allocation patterns
+    pub leaks: LeakDetector,                         // scores allocations for the leak report
+    pub require_fence: bool, // releases and renewals must carry a fence token
+    pub changes: ChangeFeed, // pool versions handed out for delta sync
+    pub approvals: ApprovalQueue, // new allocations waiting for an admin
+}
+
+impl AppState {
+    // State around a single pool with everything else at its defaults
+    pub fn new(pool: IpPool) -> Self {
+        AppState {
+            pool: pool.clone(),
+            pools: PoolRegistry::new(pool),
+            events: EventBus::new(),
+            perf: PerfStats::default(),
+            health: HealthRegistry::default(),
+            maintenance: Maintenance::default(),
+            sweeps: Sweeps::default(),
+            snapshot_store: None,
+            templates: Arc::default(),
+            webhooks: Webhooks::default(),
+            budget: None,
+            insights: Insights::default(),
+            leaks: LeakDetector::default(),
+            require_fence: false,
+            changes: ChangeFeed::default(),
+            approvals: ApprovalQueue::default(),
+        }
+    }
+}
+
+impl FromRef<AppState> for IpPool {
+    fn from_ref(state: &AppState) -> Self {
+        state.pool.clone()
+    }
+}
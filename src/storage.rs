@@ -0,0 +1,405 @@
+use crate::health::{HealthRegistry, Status};
+use crate::ippool::PoolSnapshot;
+use crate::pools::PoolRegistry;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+
+// A write lock older than this was left behind by a crashed instance
+pub const LOCK_TTL: Duration = Duration::from_secs(30);
+
+// Persisted state: every pool snapshot keyed by pool name
+pub type StoredState = BTreeMap<String, PoolSnapshot>;
+
+// Point-in-time copy of the pool state kept under a name, to roll back to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NamedSnapshot {
+    pub name: String,
+    pub created_at: u64, // unix seconds
+    pub state: StoredState,
+}
+
+pub type StorageFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, StorageError>> + Send + 'a>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageError {
+    Unavailable(String),
+    Conflict,
+    Corrupt(String),
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Unavailable(msg) => write!(f, "storage unavailable: {}", msg),
+            StorageError::Conflict => write!(f, "state was modified by another writer"),
+            StorageError::Corrupt(msg) => write!(f, "stored state is corrupt: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+// Durable home for pool state
+pub trait StateStore: Send + Sync + std::fmt::Debug {
+    fn name(&self) -> &'static str;
+
+    fn load(&self) -> StorageFuture<'_, Option<StoredState>>;
+
+    fn save<'a>(&'a self, state: &'a StoredState) -> StorageFuture<'a, ()>;
+
+    // Named snapshots live next to the state, one per name
+    fn save_snapshot<'a>(&'a self, snapshot: &'a NamedSnapshot) -> StorageFuture<'a, ()>;
+
+    fn load_snapshot<'a>(&'a self, name: &'a str) -> StorageFuture<'a, Option<NamedSnapshot>>;
+
+    fn list_snapshots(&self) -> StorageFuture<'_, Vec<NamedSnapshot>>;
+
+    // Write lock shared by every instance using the backend, to serialize
+    // their writes. Ok(false) while another instance holds it.
+    fn try_lock(&self) -> StorageFuture<'_, bool> {
+        let name = self.name();
+        Box::pin(async move {
+            Err(StorageError::Unavailable(format!(
+                "{} backend has no write lock",
+                name
+            )))
+        })
+    }
+
+    fn unlock(&self) -> StorageFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+// Snapshot names end up in file names and KV keys
+pub fn valid_snapshot_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+// Pool state in a local JSON file. Writes go to a temporary file that is
+// renamed over the old one, so a crash never leaves a half-written state.
+// Named snapshots are files in a `snapshots` directory next to it. The
+// write lock is a `.lock` file next to the state, created exclusively, so
+// instances sharing a volume can take turns.
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    holder: String, // written into the lock file, to only ever remove our own
+}
+
+impl FileStore {
+    pub fn new(path: PathBuf) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        FileStore {
+            path,
+            holder: format!("{}-{}", std::process::id(), started),
+        }
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        self.path.with_extension("lock")
+    }
+
+    async fn create_lock(&self) -> Result<bool, StorageError> {
+        let path = self.lock_path();
+        match tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+        {
+            Ok(mut file) => {
+                file.write_all(self.holder.as_bytes())
+                    .await
+                    .map_err(|e| StorageError::Unavailable(e.to_string()))?;
+                Ok(true)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                let stale = tokio::fs::metadata(&path)
+                    .await
+                    .ok()
+                    .and_then(|metadata| metadata.modified().ok())
+                    .and_then(|modified| modified.elapsed().ok())
+                    .is_some_and(|age| age > LOCK_TTL);
+                if stale {
+                    tracing::warn!("Breaking stale write lock {}", path.display());
+                    let _ = tokio::fs::remove_file(&path).await;
+                }
+                Ok(false)
+            }
+            Err(e) => Err(StorageError::Unavailable(e.to_string())),
+        }
+    }
+
+    async fn remove_lock(&self) -> Result<(), StorageError> {
+        let path = self.lock_path();
+        match tokio::fs::read_to_string(&path).await {
+            Ok(holder) if holder == self.holder => tokio::fs::remove_file(&path)
+                .await
+                .map_err(|e| StorageError::Unavailable(e.to_string())),
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(StorageError::Unavailable(e.to_string())),
+        }
+    }
+
+    fn snapshot_dir(&self) -> PathBuf {
+        self.path.with_file_name("snapshots")
+    }
+
+    async fn read_snapshots(&self) -> Result<Vec<NamedSnapshot>, StorageError> {
+        let mut entries = match tokio::fs::read_dir(self.snapshot_dir()).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(StorageError::Unavailable(e.to_string())),
+        };
+
+        let mut snapshots = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| StorageError::Unavailable(e.to_string()))?
+        {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "json")
+                && let Some(snapshot) = read_json(&path).await?
+            {
+                snapshots.push(snapshot);
+            }
+        }
+        Ok(snapshots)
+    }
+
+    async fn write_snapshot(&self, snapshot: &NamedSnapshot) -> Result<(), StorageError> {
+        let dir = self.snapshot_dir();
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| StorageError::Unavailable(e.to_string()))?;
+        write_json(&dir.join(format!("{}.json", snapshot.name)), snapshot).await
+    }
+}
+
+async fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<Option<T>, StorageError> {
+    let data = match tokio::fs::read(path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(StorageError::Unavailable(e.to_string())),
+    };
+
+    serde_json::from_slice(&data)
+        .map(Some)
+        .map_err(|e| StorageError::Corrupt(e.to_string()))
+}
+
+async fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), StorageError> {
+    let data =
+        serde_json::to_vec_pretty(value).map_err(|e| StorageError::Corrupt(e.to_string()))?;
+
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, data)
+        .await
+        .map_err(|e| StorageError::Unavailable(e.to_string()))?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .map_err(|e| StorageError::Unavailable(e.to_string()))
+}
+
+impl StateStore for FileStore {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn load(&self) -> StorageFuture<'_, Option<StoredState>> {
+        Box::pin(read_json(&self.path))
+    }
+
+    fn save<'a>(&'a self, state: &'a StoredState) -> StorageFuture<'a, ()> {
+        Box::pin(write_json(&self.path, state))
+    }
+
+    fn save_snapshot<'a>(&'a self, snapshot: &'a NamedSnapshot) -> StorageFuture<'a, ()> {
+        Box::pin(self.write_snapshot(snapshot))
+    }
+
+    fn load_snapshot<'a>(&'a self, name: &'a str) -> StorageFuture<'a, Option<NamedSnapshot>> {
+        Box::pin(
+            async move { read_json(&self.snapshot_dir().join(format!("{}.json", name))).await },
+        )
+    }
+
+    fn list_snapshots(&self) -> StorageFuture<'_, Vec<NamedSnapshot>> {
+        Box::pin(self.read_snapshots())
+    }
+
+    fn try_lock(&self) -> StorageFuture<'_, bool> {
+        Box::pin(self.create_lock())
+    }
+
+    fn unlock(&self) -> StorageFuture<'_, ()> {
+        Box::pin(self.remove_lock())
+    }
+}
+
+// Restore the registry from the store, if it holds any state
+pub async fn load_into(store: &dyn StateStore, pools: &PoolRegistry) -> Result<bool, StorageError> {
+    match store.load().await? {
+        Some(state) => {
+            pools.restore(state).await;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// Background task writing the registry to the store whenever it changed.
+// Failed writes are retried on the next tick.
+pub async fn run_persister(
+    store: Arc<dyn StateStore>,
+    pools: PoolRegistry,
+    interval: Duration,
+    health: HealthRegistry,
+) {
+    health.register("storage");
+    let mut failing = false;
+    let mut last_saved = pools.fingerprint().await;
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        let fingerprint = pools.fingerprint().await;
+        if fingerprint == last_saved {
+            continue;
+        }
+
+        let state = pools.snapshot().await;
+        match store.save(&state).await {
+            Ok(()) => {
+                if failing {
+                    tracing::info!("💾 Pool state saved to {} again", store.name());
+                } else {
+                    tracing::debug!("Pool state saved to {}", store.name());
+                }
+                failing = false;
+                last_saved = fingerprint;
+                health.success("storage");
+            }
+            Err(e) => {
+                // Retried every tick, only the first failure in a row is an error
+                if failing {
+                    tracing::debug!("Failed to save pool state to {}: {}", store.name(), e);
+                } else {
+                    tracing::error!("Failed to save pool state to {}: {}", store.name(), e);
+                }
+                failing = true;
+                health.failure("storage", Status::Unhealthy, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ippool::IpPool;
+    use crate::testkit;
+    use proptest::prelude::*;
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        #[test]
+        fn prop_file_store_keeps_any_state(ops in testkit::ops(40)) {
+            let path = std::env::temp_dir()
+                .join(format!("ippool-prop-state-{}.json", std::process::id()));
+            testkit::block_on(testkit::check_store(Arc::new(FileStore::new(path.clone())), &ops));
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_store_roundtrip() {
+        let path = std::env::temp_dir().join(format!("ippool-state-{}.json", std::process::id()));
+        let store = FileStore::new(path.clone());
+        let pools = PoolRegistry::new(IpPool::new(
+            "172.16.0".to_string(),
+            "172.16.0.1".to_string(),
+        ));
+
+        // Missing file means a fresh start
+        assert!(!load_into(&store, &pools).await.unwrap());
+
+        let default_pool = pools.get(crate::pools::DEFAULT_POOL).await.unwrap();
+        default_pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        store.save(&pools.snapshot().await).await.unwrap();
+
+        let restored = PoolRegistry::new(IpPool::new(
+            "172.16.0".to_string(),
+            "172.16.0.1".to_string(),
+        ));
+        assert!(load_into(&store, &restored).await.unwrap());
+        let pool = restored.get(crate::pools::DEFAULT_POOL).await.unwrap();
+        assert_eq!(pool.get_allocation("vm-1").await.unwrap().ip, "172.16.0.2");
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_file_store_named_snapshots() {
+        let dir = std::env::temp_dir().join(format!("ippool-snapshots-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = FileStore::new(dir.join("state.json"));
+        let pools = PoolRegistry::new(IpPool::new(
+            "172.16.0".to_string(),
+            "172.16.0.1".to_string(),
+        ));
+        assert!(store.list_snapshots().await.unwrap().is_empty());
+        assert!(store.load_snapshot("before").await.unwrap().is_none());
+
+        let default_pool = pools.get(crate::pools::DEFAULT_POOL).await.unwrap();
+        default_pool.allocate_ip("vm-1".to_string()).await.unwrap();
+        let snapshot = NamedSnapshot {
+            name: "before".to_string(),
+            created_at: 1767312000,
+            state: pools.snapshot().await,
+        };
+        store.save_snapshot(&snapshot).await.unwrap();
+
+        // Snapshots are kept apart from the state itself
+        assert!(store.load().await.unwrap().is_none());
+        let loaded = store.load_snapshot("before").await.unwrap().unwrap();
+        assert_eq!(loaded.created_at, 1767312000);
+        assert_eq!(loaded.state["default"].allocations["172.16.0.2"], "vm-1");
+        let listed = store.list_snapshots().await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].name, "before");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_names() {
+        for name in ["before-migration", "2026-10-17_1", "v1.2"] {
+            assert!(valid_snapshot_name(name), "{}", name);
+        }
+        for name in ["", "../state", ".hidden", "a/b", "with space"] {
+            assert!(!valid_snapshot_name(name), "{}", name);
+        }
+        assert!(!valid_snapshot_name(&"a".repeat(65)));
+    }
+}
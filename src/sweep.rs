@@ -0,0 +1,364 @@
+use crate::conflicts::{Conflict, ConflictSource};
+use crate::events::unix_now;
+use crate::gateway;
+use crate::ippool::{IpPool, IpPoolError};
+use crate::pools::PoolRegistry;
+use futures_util::{StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Unresponsive {
+    pub ip: String,
+    pub vm_id: String,
+}
+
+// Outcome of pinging every address of a pool
+#[derive(Debug, Clone, Serialize)]
+pub struct SweepReport {
+    pub pool: String,
+    pub started_at: u64, // unix seconds
+    pub finished_at: u64,
+    pub probed: usize,
+    pub unresponsive: Vec<Unresponsive>, // allocated, but no answer
+    pub rogue: Vec<String>,              // free, but answering; flagged as conflicts
+}
+
+// Free address a sweep found answering and flagged, that nobody has
+// adopted yet
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UnknownHost {
+    pub pool: String,
+    pub ip: String,
+    pub flagged_at: u64, // unix seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<u64>, // end of the latest sweep, if it answered that one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub investigation: Option<String>,
+}
+
+// What an unknown host turns out to be
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Adoption {
+    // A device outside the pool, such as a printer: reserved as external
+    External {
+        #[serde(default)]
+        description: Option<String>,
+    },
+    // A VM set up by hand: its primary address from now on
+    Static {
+        vm_id: String,
+    },
+    // Not known yet: stays flagged, with a note
+    Investigate {
+        note: String,
+    },
+}
+
+impl Adoption {
+    pub fn action(&self) -> &'static str {
+        match self {
+            Adoption::External { .. } => "external",
+            Adoption::Static { .. } => "static",
+            Adoption::Investigate { .. } => "investigate",
+        }
+    }
+}
+
+// Ping sweep settings plus the latest report of every pool
+#[derive(Debug, Clone)]
+pub struct Sweeps {
+    timeout: Duration,  // per address
+    concurrency: usize, // addresses probed at once
+    reports: Arc<RwLock<BTreeMap<String, SweepReport>>>,
+}
+
+impl Default for Sweeps {
+    fn default() -> Self {
+        Sweeps::new(Duration::from_secs(1), 32)
+    }
+}
+
+impl Sweeps {
+    pub fn new(timeout: Duration, concurrency: usize) -> Self {
+        Sweeps {
+            timeout,
+            concurrency: concurrency.max(1),
+            reports: Arc::default(),
+        }
+    }
+
+    // Latest report of every pool swept so far, by pool name
+    pub fn reports(&self) -> Vec<SweepReport> {
+        self.reports.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn report(&self, pool: &str) -> Option<SweepReport> {
+        self.reports.read().unwrap().get(pool).cloned()
+    }
+
+    // Unknown hosts of a pool, in address order
+    pub async fn unknown(&self, name: &str, pool: &IpPool) -> Vec<UnknownHost> {
+        let report = self.report(name);
+        (pool.list_conflicts().await.into_iter())
+            .filter(|(_, conflict, holder)| {
+                conflict.source == ConflictSource::Probe && holder.is_none()
+            })
+            .map(|(ip, conflict, _)| UnknownHost {
+                pool: name.to_string(),
+                last_seen: (report.as_ref())
+                    .filter(|report| report.rogue.contains(&ip))
+                    .map(|report| report.finished_at),
+                ip,
+                flagged_at: conflict.reported_at,
+                investigation: conflict.investigation,
+            })
+            .collect()
+    }
+
+    // Settle what an unknown host is. The pool checks again under its lock
+    // that the address is still flagged and free, so an address allocated
+    // or adopted meanwhile is refused rather than taken over.
+    pub async fn adopt(
+        &self,
+        name: &str,
+        pool: &IpPool,
+        ip: &str,
+        adoption: Adoption,
+    ) -> Result<(), IpPoolError> {
+        if !(self.unknown(name, pool).await.iter()).any(|host| host.ip == ip) {
+            return Err(IpPoolError::ConflictNotFound);
+        }
+        match adoption {
+            Adoption::External { description } => {
+                let description = description
+                    .filter(|description| !description.trim().is_empty())
+                    .unwrap_or_else(|| "external".to_string());
+                pool.adopt_external(ip, description).await
+            }
+            Adoption::Static { vm_id } => pool.adopt_static(ip, &vm_id).await,
+            Adoption::Investigate { note } => pool.investigate_conflict(ip, note).await,
+        }
+    }
+
+    // Sweep one pool with real pings, keeping the report
+    pub async fn run(&self, name: &str, pool: &IpPool) -> SweepReport {
+        let timeout = self.timeout;
+        let report = sweep(name, pool, self.concurrency, |address| {
+            gateway::probe(address, timeout)
+        })
+        .await;
+        self.keep(report.clone());
+        report
+    }
+
+    fn keep(&self, report: SweepReport) {
+        let mut reports = self.reports.write().unwrap();
+        reports.insert(report.pool.clone(), report);
+    }
+}
+
+// Probe every allocatable address of the pool except reserved ones, then
+// report allocations that do not answer and free addresses that do. The
+// latter are flagged as conflicts (unless already flagged) so they are not
+// handed out on top of a host nobody registered.
+pub async fn sweep<F, Fut>(name: &str, pool: &IpPool, concurrency: usize, probe: F) -> SweepReport
+where
+    F: Fn(Ipv4Addr) -> Fut,
+    Fut: Future<Output = Result<(), String>>,
+{
+    let started_at = unix_now();
+    let snapshot = pool.snapshot().await;
+    let targets: Vec<Ipv4Addr> = (u32::from(snapshot.start)..=u32::from(snapshot.end))
+        .map(Ipv4Addr::from)
+        .filter(|address| !snapshot.reserved.contains_key(&address.to_string()))
+        .collect();
+
+    let probe = &probe;
+    let mut results: Vec<(Ipv4Addr, bool)> = stream::iter(targets)
+        .map(|address| async move { (address, probe(address).await.is_ok()) })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+    results.sort();
+
+    let mut unresponsive = Vec::new();
+    let mut rogue = Vec::new();
+    for (address, answered) in &results {
+        let ip = address.to_string();
+        match (snapshot.allocations.get(&ip), answered) {
+            (Some(vm_id), false) => unresponsive.push(Unresponsive {
+                ip,
+                vm_id: vm_id.clone(),
+            }),
+            (None, true) => {
+                if !snapshot.conflicts.contains_key(&ip) {
+                    let conflict = Conflict {
+                        source: ConflictSource::Probe,
+                        detail: "free address answered a ping sweep".to_string(),
+                        reported_by: None,
+                        reported_at: unix_now(),
+                        investigation: None,
+                    };
+                    if let Err(e) = pool.flag_conflict(&ip, conflict).await {
+                        tracing::warn!("Failed to flag {} of pool '{}': {}", ip, name, e);
+                    }
+                }
+                rogue.push(ip);
+            }
+            _ => {}
+        }
+    }
+
+    if !unresponsive.is_empty() || !rogue.is_empty() {
+        tracing::warn!(
+            "⚠️  Ping sweep of pool '{}': {} allocated addresses unresponsive, {} free addresses responding",
+            name,
+            unresponsive.len(),
+            rogue.len()
+        );
+    }
+    SweepReport {
+        pool: name.to_string(),
+        started_at,
+        finished_at: unix_now(),
+        probed: results.len(),
+        unresponsive,
+        rogue,
+    }
+}
+
+// Sweep every pool every `interval`
+pub async fn run_ping_sweep(pools: PoolRegistry, sweeps: Sweeps, interval: Duration) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        ticker.tick().await;
+        for name in pools.names().await {
+            if let Ok(pool) = pools.get(&name).await {
+                sweeps.run(&name, &pool).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sweep_reports_and_flags() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        pool.allocate_ip("vm-1".to_string()).await.unwrap(); // .2, down
+        pool.allocate_ip("vm-2".to_string()).await.unwrap(); // .3, up
+
+        let answering = [
+            Ipv4Addr::new(172, 16, 0, 1),
+            Ipv4Addr::new(172, 16, 0, 3),
+            Ipv4Addr::new(172, 16, 0, 4),
+        ];
+        let probe = |address: Ipv4Addr| async move {
+            if answering.contains(&address) {
+                Ok(())
+            } else {
+                Err("no reply".to_string())
+            }
+        };
+        let report = sweep("default", &pool, 8, probe).await;
+
+        // The gateway is reserved and not probed
+        assert_eq!(report.probed, 253);
+        assert_eq!(
+            report.unresponsive,
+            vec![Unresponsive {
+                ip: "172.16.0.2".to_string(),
+                vm_id: "vm-1".to_string()
+            }]
+        );
+        assert_eq!(report.rogue, vec!["172.16.0.4".to_string()]);
+
+        // The rogue host's address is not handed out
+        let conflicts = pool.list_conflicts().await;
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].0, "172.16.0.4");
+        assert_eq!(conflicts[0].1.source, ConflictSource::Probe);
+        assert_eq!(
+            pool.allocate_ip("vm-3".to_string()).await,
+            Ok("172.16.0.5".to_string())
+        );
+
+        // Flagged addresses are reported again but keep their first report
+        let report = sweep("default", &pool, 8, probe).await;
+        assert_eq!(report.rogue, vec!["172.16.0.4".to_string()]);
+        let reported_at = conflicts[0].1.reported_at;
+        assert_eq!(pool.list_conflicts().await[0].1.reported_at, reported_at);
+    }
+
+    #[tokio::test]
+    async fn test_adopt_unknown_hosts() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let answering = [2, 3, 4, 5].map(|host| Ipv4Addr::new(172, 16, 0, host));
+        let probe = |address: Ipv4Addr| async move {
+            match answering.contains(&address) {
+                true => Ok(()),
+                false => Err("no reply".to_string()),
+            }
+        };
+        let sweeps = Sweeps::default();
+        sweeps.keep(sweep("default", &pool, 8, probe).await);
+        let unknown = sweeps.unknown("default", &pool).await;
+        assert_eq!(unknown.len(), 4);
+        assert_eq!(unknown[0].last_seen, Some(sweeps.reports()[0].finished_at));
+
+        let adopt = |ip: &'static str, adoption: serde_json::Value| {
+            let adoption: Adoption = serde_json::from_value(adoption).unwrap();
+            sweeps.adopt("default", &pool, ip, adoption)
+        };
+        adopt(
+            "172.16.0.2",
+            serde_json::json!({"action": "external", "description": "printer"}),
+        )
+        .await
+        .unwrap();
+        adopt(
+            "172.16.0.3",
+            serde_json::json!({"action": "static", "vm_id": "legacy-db"}),
+        )
+        .await
+        .unwrap();
+        adopt(
+            "172.16.0.4",
+            serde_json::json!({"action": "investigate", "note": "ops: whose?"}),
+        )
+        .await
+        .unwrap();
+
+        assert!(pool.list_reserved().await[1].external);
+        assert_eq!(
+            pool.get_allocation("legacy-db").await.unwrap().ip,
+            "172.16.0.3"
+        );
+        let unknown = sweeps.unknown("default", &pool).await;
+        let ips: Vec<&str> = unknown.iter().map(|host| host.ip.as_str()).collect();
+        assert_eq!(ips, vec!["172.16.0.4", "172.16.0.5"]);
+        assert_eq!(unknown[0].investigation.as_deref(), Some("ops: whose?"));
+
+        // Settled hosts are no longer unknown
+        assert_eq!(
+            adopt(
+                "172.16.0.3",
+                serde_json::json!({"action": "static", "vm_id": "other"})
+            )
+            .await,
+            Err(IpPoolError::ConflictNotFound)
+        );
+        // A later sweep leaves the investigation in place
+        sweeps.keep(sweep("default", &pool, 8, probe).await);
+        let unknown = sweeps.unknown("default", &pool).await;
+        assert_eq!(unknown[0].investigation.as_deref(), Some("ops: whose?"));
+    }
+}
@@ -0,0 +1,395 @@
+use crate::config::SyslogConfig;
+use crate::events::{Event, EventBus, EventKind};
+use crate::health::{HealthRegistry, Status};
+use chrono::{DateTime, SecondsFormat};
+use rustls_platform_verifier::ConfigVerifierExt;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::rustls::pki_types::ServerName;
+
+// Structured data ID the event fields are sent under. 32473 is the
+// enterprise number RFC 5612 reserves for documentation and examples.
+const SD_ID: &str = "ippool@32473";
+
+// Facility names from RFC 5424, by code
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv",
+    "ftp", "ntp", "audit", "alert", "clock", "local0", "local1", "local2", "local3", "local4",
+    "local5", "local6", "local7",
+];
+
+const SEVERITY_WARNING: u8 = 4;
+const SEVERITY_NOTICE: u8 = 5;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub fn facility(name: &str) -> Option<u8> {
+    let name = name.to_ascii_lowercase();
+    FACILITIES
+        .iter()
+        .position(|facility| *facility == name)
+        .map(|code| code as u8)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp, // RFC 5426
+    Tcp, // RFC 6587, octet-counted
+    Tls, // RFC 5425
+}
+
+// Collector address from SYSLOG_URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub transport: Transport,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Target {
+    // "udp://host[:514]", "tcp://host[:601]" or "tls://host[:6514]"
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| format!("{} has no scheme (udp://, tcp:// or tls://)", url))?;
+        let (transport, default_port) = match scheme {
+            "udp" => (Transport::Udp, 514),
+            "tcp" => (Transport::Tcp, 601),
+            "tls" => (Transport::Tls, 6514),
+            _ => return Err(format!("unsupported scheme {}", scheme)),
+        };
+        let rest = rest.trim_end_matches('/');
+        // IPv6 addresses come in brackets, "[::1]:514"
+        let (host, port) = match rest.strip_prefix('[') {
+            Some(bracketed) => bracketed
+                .split_once(']')
+                .map(|(host, port)| (host, port.strip_prefix(':')))
+                .ok_or_else(|| format!("{} has an unclosed [", url))?,
+            None => match rest.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (rest, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| format!("invalid port {}", port))?,
+            None => default_port,
+        };
+        if host.is_empty() {
+            return Err(format!("{} has no host", url));
+        }
+        Ok(Target {
+            transport,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match self.transport {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Tls => "tls",
+        };
+        match self.host.contains(':') {
+            true => write!(f, "{}://[{}]:{}", scheme, self.host, self.port),
+            false => write!(f, "{}://{}:{}", scheme, self.host, self.port),
+        }
+    }
+}
+
+// RFC 5424 header fields shared by every message
+#[derive(Debug, Clone)]
+struct Header {
+    facility: u8,
+    hostname: String,
+    app_name: String,
+    procid: u32,
+}
+
+impl Header {
+    // One message per event: the kind as MSGID, the allocation as
+    // structured data and the whole event as JSON
+    fn format(&self, event: &Event) -> String {
+        let severity = match event.kind {
+            EventKind::Anomaly => SEVERITY_WARNING,
+            _ => SEVERITY_NOTICE,
+        };
+        let timestamp = DateTime::from_timestamp(event.timestamp as i64, 0)
+            .map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true))
+            .unwrap_or_else(|| "-".to_string());
+        let kind = serde_json::to_value(event.kind).unwrap_or_default();
+        let kind = kind.as_str().unwrap_or("-");
+        let id = event.id.to_string();
+        let params = [
+            ("id", id.as_str()),
+            ("pool", event.pool.as_str()),
+            ("vm_id", event.vm_id.as_str()),
+            ("ip", event.ip.as_str()),
+        ];
+        let data: String = params
+            .iter()
+            .map(|(name, value)| format!(" {}=\"{}\"", name, escape(value)))
+            .collect();
+        let body = serde_json::to_string(event).unwrap_or_default();
+        format!(
+            "<{}>1 {} {} {} {} {} [{}{}] \u{feff}{}",
+            self.facility as u16 * 8 + severity as u16,
+            timestamp,
+            header_field(&self.hostname, 255),
+            header_field(&self.app_name, 48),
+            self.procid,
+            header_field(kind, 32),
+            SD_ID,
+            data,
+            body
+        )
+    }
+}
+
+// Printable ASCII without spaces, "-" (nil) when nothing is left
+fn header_field(value: &str, max: usize) -> String {
+    let field: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect();
+    if field.is_empty() {
+        "-".to_string()
+    } else {
+        field
+    }
+}
+
+// Characters a structured data value has to escape
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection {
+    async fn open(target: &Target) -> io::Result<Self> {
+        let address = (target.host.as_str(), target.port);
+        if target.transport == Transport::Udp {
+            let peer = tokio::net::lookup_host(address)
+                .await?
+                .next()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            let local: std::net::SocketAddr = match peer {
+                std::net::SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+                std::net::SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+            };
+            let socket = UdpSocket::bind(local).await?;
+            socket.connect(peer).await?;
+            return Ok(Connection::Udp(socket));
+        }
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        if target.transport == Transport::Tcp {
+            return Ok(Connection::Tcp(stream));
+        }
+        // Verified against the system trust store
+        let config = ClientConfig::with_platform_verifier().map_err(io::Error::other)?;
+        let name = ServerName::try_from(target.host.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let connector = TlsConnector::from(Arc::new(config));
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, connector.connect(name, stream))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        Ok(Connection::Tls(Box::new(stream)))
+    }
+
+    async fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        // Streams carry "<length> <message>" frames
+        let frame = [format!("{} ", message.len()).as_bytes(), message].concat();
+        match self {
+            Connection::Udp(socket) => socket.send(message).await.map(|_| ()),
+            Connection::Tcp(stream) => {
+                stream.write_all(&frame).await?;
+                stream.flush().await
+            }
+            Connection::Tls(stream) => {
+                stream.write_all(&frame).await?;
+                stream.flush().await
+            }
+        }
+    }
+}
+
+// Ships every emitted event to a syslog collector, for compliance logging
+// of network changes
+pub struct SyslogOutput {
+    target: Target,
+    header: Header,
+    connection: Option<Connection>,
+}
+
+impl SyslogOutput {
+    pub fn new(target: Target, config: &SyslogConfig) -> Self {
+        SyslogOutput {
+            target,
+            header: Header {
+                facility: config.facility,
+                hostname: config.hostname.clone(),
+                app_name: config.app_name.clone(),
+                procid: std::process::id(),
+            },
+            connection: None,
+        }
+    }
+
+    async fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => self
+                .connection
+                .insert(Connection::open(&self.target).await?),
+        };
+        connection.send(message).await
+    }
+
+    // Retries until the collector takes the event, reconnecting with backoff;
+    // events arriving meanwhile queue on the bus
+    async fn deliver(&mut self, event: &Event, health: &HealthRegistry) {
+        let message = self.header.format(event);
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            match self.send(message.as_bytes()).await {
+                Ok(()) => {
+                    health.success("syslog");
+                    return;
+                }
+                Err(e) => {
+                    self.connection = None;
+                    tracing::warn!(
+                        "Failed to send event {} to syslog {}: {}",
+                        event.id,
+                        self.target,
+                        e
+                    );
+                    health.failure("syslog", Status::Degraded, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    // Send every emitted event until the bus goes away
+    pub async fn run(mut self, events: EventBus, health: HealthRegistry) {
+        health.register("syslog");
+        let mut receiver = events.subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(event) => self.deliver(&event, &health).await,
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("Syslog output fell behind, {} events not sent", missed);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_syslog_output() {
+        assert_eq!(
+            Target::parse("tls://logs.example.com").unwrap().to_string(),
+            "tls://logs.example.com:6514"
+        );
+        assert_eq!(
+            Target::parse("udp://[::1]:5514").unwrap().to_string(),
+            "udp://[::1]:5514"
+        );
+        assert_eq!(Target::parse("tcp://[::1]").unwrap().port, 601);
+        assert!(Target::parse("logs.example.com:514").is_err());
+        assert!(Target::parse("http://logs.example.com").is_err());
+        assert_eq!(facility("LOCAL4"), Some(20));
+        assert_eq!(facility("local8"), None);
+
+        let header = Header {
+            facility: 16,
+            hostname: "hv1".to_string(),
+            app_name: "ippool".to_string(),
+            procid: 7,
+        };
+        let event = Event {
+            id: 3,
+            timestamp: 1_800_000_000,
+            kind: EventKind::Allocated,
+            pool: "default".to_string(),
+            vm_id: "vm \"a\"]".to_string(),
+            ip: "172.16.0.2".to_string(),
+            details: None,
+        };
+        let message = header.format(&event);
+        let (head, body) = message.split_once('\u{feff}').unwrap();
+        assert_eq!(
+            head,
+            "<133>1 2027-01-15T08:00:00Z hv1 ippool 7 allocated [ippool@32473 id=\"3\" \
+             pool=\"default\" vm_id=\"vm \\\"a\\\"\\]\" ip=\"172.16.0.2\"] "
+        );
+        let sent: Event = serde_json::from_str(body).unwrap();
+        assert_eq!(sent.vm_id, event.vm_id);
+
+        // Events go out octet-counted over TCP
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = SyslogConfig {
+            url: String::new(),
+            facility: 16,
+            hostname: "hv1".to_string(),
+            app_name: "ippool".to_string(),
+        };
+        let target = Target::parse(&format!("tcp://127.0.0.1:{}", port)).unwrap();
+        let events = EventBus::new();
+        let health = HealthRegistry::default();
+        tokio::spawn(SyslogOutput::new(target, &config).run(events.clone(), health));
+        tokio::task::yield_now().await;
+        events
+            .emit(EventKind::Released, "default", "vm-1", "172.16.0.2", None)
+            .await;
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        while !received.ends_with(b"}") {
+            let mut buf = [0; 1024];
+            let len = stream.read(&mut buf).await.unwrap();
+            received.extend_from_slice(&buf[..len]);
+        }
+        let received = String::from_utf8(received).unwrap();
+        let (len, message) = received.split_once(' ').unwrap();
+        assert_eq!(len.parse::<usize>().unwrap(), message.len());
+        assert!(message.starts_with("<133>1 "));
+        assert!(message.contains(" released [ippool@32473 id=\"1\" pool=\"default\""));
+    }
+}
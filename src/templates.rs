@@ -0,0 +1,223 @@
+use crate::check::Thresholds;
+use crate::ippool::{IpPool, IpPoolError, PoolSnapshot};
+use crate::validate::{self, ProposedPool};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+// Layout shared by pools that are created over and over, e.g. one per lab
+// network. Addresses are offsets from the network address (1 is the first
+// host); negative offsets count back from the broadcast address (-1 is the
+// broadcast address, -2 the last host).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PoolTemplate {
+    pub prefix_len: u8,
+    #[serde(default = "default_gateway")]
+    pub gateway: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<i64>, // allocation range, defaults to every host address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns: Vec<i64>, // reserved as "dns", "dns-2", ...
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub exclusions: BTreeMap<String, i64>, // label -> offset, never allocated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thresholds: Option<Thresholds>,
+}
+
+fn default_gateway() -> i64 {
+    1
+}
+
+// Templates by name
+pub type Templates = BTreeMap<String, PoolTemplate>;
+
+// JSON object of templates by name, as in POOL_TEMPLATES_FILE
+pub fn load(path: &Path) -> Result<Templates, String> {
+    let data = std::fs::read(path).map_err(|e| e.to_string())?;
+    serde_json::from_slice(&data).map_err(|e| e.to_string())
+}
+
+// A template laid out in one network
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolLayout {
+    pub network: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Ipv4Addr,
+    pub start: Ipv4Addr,
+    pub end: Ipv4Addr,
+    pub reserved: BTreeMap<String, Ipv4Addr>, // label -> IP
+    pub thresholds: Option<Thresholds>,
+}
+
+impl PoolTemplate {
+    pub fn instantiate(&self, cidr: &str) -> Result<PoolLayout, String> {
+        let (network, prefix_len) = validate::parse_network(cidr)
+            .filter(|_| cidr.contains('/'))
+            .ok_or_else(|| format!("{} is not a network like 172.16.0.0/24", cidr))?;
+        if prefix_len != self.prefix_len {
+            return Err(format!(
+                "template is for /{} networks, {} is a /{}",
+                self.prefix_len, cidr, prefix_len
+            ));
+        }
+        let broadcast = network | !validate::mask(prefix_len);
+        let address = |field: &str, offset: i64| {
+            let base = if offset < 0 {
+                i64::from(broadcast) + 1
+            } else {
+                i64::from(network)
+            };
+            u32::try_from(base + offset)
+                .map(Ipv4Addr::from)
+                .map_err(|_| format!("{} offset {} is outside IPv4", field, offset))
+        };
+
+        let mut reserved = BTreeMap::new();
+        for (i, offset) in self.dns.iter().enumerate() {
+            let label = match i {
+                0 => "dns".to_string(),
+                _ => format!("dns-{}", i + 1),
+            };
+            reserved.insert(label.clone(), address(&label, *offset)?);
+        }
+        for (label, offset) in &self.exclusions {
+            reserved.insert(label.clone(), address(label, *offset)?);
+        }
+
+        // Without a start or end the range covers every host address, as
+        // for pools from POOLS
+        let hosts = prefix_len < 31;
+        Ok(PoolLayout {
+            network: Ipv4Addr::from(network),
+            prefix_len,
+            gateway: address("gateway", self.gateway)?,
+            start: match self.start {
+                Some(offset) => address("start", offset)?,
+                None => Ipv4Addr::from(network + u32::from(hosts)),
+            },
+            end: match self.end {
+                Some(offset) => address("end", offset)?,
+                None => Ipv4Addr::from(broadcast - u32::from(hosts)),
+            },
+            reserved,
+            thresholds: self.thresholds,
+        })
+    }
+}
+
+impl PoolLayout {
+    // The layout as a pool definition, to validate it before creating it
+    pub fn proposed(&self, name: &str, allow_public: bool) -> ProposedPool {
+        ProposedPool {
+            name: name.to_string(),
+            network: format!("{}/{}", self.network, self.prefix_len),
+            gateway: Some(self.gateway.to_string()),
+            start: Some(self.start.to_string()),
+            end: Some(self.end.to_string()),
+            reserved: self
+                .reserved
+                .iter()
+                .map(|(label, ip)| (label.clone(), ip.to_string()))
+                .collect(),
+            routes: Vec::new(),
+            allow_public,
+        }
+    }
+
+    pub async fn build(&self) -> Result<IpPool, IpPoolError> {
+        let pool = IpPool::with_range(
+            self.network,
+            self.prefix_len,
+            self.gateway.to_string(),
+            u32::from(self.start),
+            u32::from(self.end),
+        )?;
+        for (label, ip) in &self.reserved {
+            pool.reserve(&ip.to_string(), label.clone()).await?;
+        }
+        pool.set_thresholds(self.thresholds).await;
+        Ok(pool)
+    }
+}
+
+// An existing pool as a pool definition, so new ones are validated against it
+pub fn existing(name: &str, snapshot: &PoolSnapshot) -> ProposedPool {
+    ProposedPool {
+        name: name.to_string(),
+        network: format!("{}/{}", snapshot.network, snapshot.prefix_len),
+        gateway: Some(snapshot.gateway.clone()),
+        start: Some(snapshot.start.to_string()),
+        end: Some(snapshot.end.to_string()),
+        reserved: BTreeMap::new(),
+        routes: Vec::new(),
+        allow_public: true, // checked when it was created
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate::{ProposedConfig, Severity};
+
+    fn lab() -> PoolTemplate {
+        serde_json::from_str(
+            r#"{
+                "prefix_len": 24,
+                "start": 10,
+                "end": -6,
+                "dns": [2, 3],
+                "exclusions": {"vip": -2},
+                "thresholds": {"warn": 70, "crit": 90}
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_instantiate_template() {
+        let layout = lab().instantiate("10.20.5.0/24").unwrap();
+        assert_eq!(layout.gateway, Ipv4Addr::new(10, 20, 5, 1));
+        assert_eq!(layout.start, Ipv4Addr::new(10, 20, 5, 10));
+        assert_eq!(layout.end, Ipv4Addr::new(10, 20, 5, 250));
+        assert_eq!(layout.reserved["dns"], Ipv4Addr::new(10, 20, 5, 2));
+        assert_eq!(layout.reserved["dns-2"], Ipv4Addr::new(10, 20, 5, 3));
+        assert_eq!(layout.reserved["vip"], Ipv4Addr::new(10, 20, 5, 254));
+
+        let pool = layout.build().await.unwrap();
+        assert_eq!(
+            pool.allocate_ip("vm-1".to_string()).await,
+            Ok("10.20.5.10".to_string())
+        );
+        assert_eq!(pool.thresholds().await.unwrap().warn, 70.0);
+
+        // Defaults cover every host, with the gateway first
+        let plain: PoolTemplate = serde_json::from_str(r#"{"prefix_len": 28}"#).unwrap();
+        let layout = plain.instantiate("10.0.0.16/28").unwrap();
+        assert_eq!(layout.gateway, Ipv4Addr::new(10, 0, 0, 17));
+        assert_eq!(layout.end, Ipv4Addr::new(10, 0, 0, 30));
+    }
+
+    #[test]
+    fn test_template_mismatch() {
+        assert!(lab().instantiate("10.20.0.0/23").is_err());
+        assert!(lab().instantiate("10.20.5").is_err());
+
+        // Offsets past the network only show up in validation
+        let mut template = lab();
+        template.exclusions.insert("far".to_string(), 300);
+        let layout = template.instantiate("10.20.5.0/24").unwrap();
+        let report = validate::validate(&ProposedConfig {
+            pools: vec![layout.proposed("lab", false)],
+        });
+        assert!(
+            report
+                .findings
+                .iter()
+                .any(|f| f.severity == Severity::Error && f.field == "reserved.far")
+        );
+    }
+}
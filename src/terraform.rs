@@ -0,0 +1,265 @@
+use crate::ippool::{IpPool, IpPoolError, Requested};
+use serde::Serialize;
+use serde_json::Value;
+use std::net::Ipv4Addr;
+
+// Attributes naming the network around a resource rather than its own
+// address, e.g. `gateway` or proxmox's `gw=` in `ipconfig0`
+const NETWORK_KEYS: [&str; 9] = [
+    "gateway",
+    "gw",
+    "dns",
+    "nameserver",
+    "netmask",
+    "broadcast",
+    "route",
+    "hop",
+    "cidr",
+];
+
+// An address assignment found in a Terraform state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateAddress {
+    pub resource: String,     // e.g. `module.web.libvirt_domain.vm["a"]`
+    pub name: Option<String>, // the instance's `name` attribute
+    pub ip: Ipv4Addr,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdoptedAddress {
+    pub ip: String,
+    pub vm_id: String,
+    pub resource: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SkippedAddress {
+    pub ip: String,
+    pub resource: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct AdoptionReport {
+    pub adopted: Vec<AdoptedAddress>,
+    pub skipped: Vec<SkippedAddress>,
+}
+
+fn is_network_key(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    NETWORK_KEYS.iter().any(|network| key.contains(network))
+}
+
+// IPv4 addresses in an attribute value: "172.16.0.10", "172.16.0.10/24" or
+// "ip=172.16.0.10/24,gw=172.16.0.1"
+fn addresses_in(value: &Value, key: &str, found: &mut Vec<Ipv4Addr>) {
+    if is_network_key(key) {
+        return;
+    }
+    match value {
+        Value::String(value) => {
+            for part in value.split([',', ' ', ';']) {
+                let part = match part.split_once('=') {
+                    Some((key, _)) if is_network_key(key) => continue,
+                    Some((_, value)) => value,
+                    None => part,
+                };
+                let address = part.split('/').next().unwrap_or_default();
+                if let Ok(ip) = address.parse()
+                    && !found.contains(&ip)
+                {
+                    found.push(ip);
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                addresses_in(value, key, found);
+            }
+        }
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                addresses_in(value, key, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Address of a resource instance as Terraform prints it
+fn resource_address(resource: &Value, index_key: Option<&Value>) -> Option<String> {
+    let mut address = String::new();
+    if let Some(module) = resource["module"].as_str() {
+        address.push_str(module);
+        address.push('.');
+    }
+    address.push_str(&format!(
+        "{}.{}",
+        resource["type"].as_str()?,
+        resource["name"].as_str()?
+    ));
+    match index_key {
+        Some(Value::Number(index)) => address.push_str(&format!("[{}]", index)),
+        Some(Value::String(key)) => address.push_str(&format!("[{:?}]", key)),
+        _ => {}
+    }
+    Some(address)
+}
+
+// Managed resource instances of a state file (format version 4), or of just
+// its `resources` array, with the IPv4 addresses in their attributes
+pub fn parse(contents: &str) -> Result<Vec<StateAddress>, String> {
+    let state: Value = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+    let resources = match &state {
+        Value::Array(resources) => resources,
+        Value::Object(_) => match &state["resources"] {
+            Value::Array(resources) => resources,
+            _ => return Err("no resources array".to_string()),
+        },
+        _ => return Err("expected a state object or a resources array".to_string()),
+    };
+
+    let mut addresses = Vec::new();
+    for resource in resources {
+        if resource["mode"].as_str() == Some("data") {
+            continue;
+        }
+        let Some(instances) = resource["instances"].as_array() else {
+            continue;
+        };
+        for instance in instances {
+            let Some(address) = resource_address(resource, instance.get("index_key")) else {
+                return Err("resource without type or name".to_string());
+            };
+            let attributes = &instance["attributes"];
+            let name = (attributes["name"].as_str())
+                .filter(|name| !name.is_empty())
+                .map(str::to_string);
+            let mut found = Vec::new();
+            addresses_in(attributes, "", &mut found);
+            addresses.extend(found.into_iter().map(|ip| StateAddress {
+                resource: address.clone(),
+                name: name.clone(),
+                ip,
+            }));
+        }
+    }
+    Ok(addresses)
+}
+
+// Adopt the addresses inside `pool` as primary addresses of VMs named after
+// the instance's `name`, or its resource address when there is none or the
+// name already holds an address. Addresses outside the pool are ignored.
+pub async fn adopt(pool: &IpPool, addresses: Vec<StateAddress>) -> AdoptionReport {
+    let mut report = AdoptionReport::default();
+    for address in addresses {
+        let ip = address.ip.to_string();
+        let vm_id = match address.name {
+            Some(name) if pool.get_allocation(&name).await.is_err() => name,
+            _ => address.resource.clone(),
+        };
+        match pool.claim(&ip, &vm_id, None, &Requested::default()).await {
+            Ok(()) => report.adopted.push(AdoptedAddress {
+                ip,
+                vm_id,
+                resource: address.resource,
+            }),
+            Err(IpPoolError::InvalidIp) => {}
+            Err(e) => report.skipped.push(SkippedAddress {
+                ip,
+                resource: address.resource,
+                reason: e.to_string(),
+            }),
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATE: &str = r#"{
+      "version": 4,
+      "terraform_version": "1.9.0",
+      "resources": [
+        {
+          "mode": "managed",
+          "type": "proxmox_vm_qemu",
+          "name": "web",
+          "instances": [
+            {"index_key": 0, "attributes": {"name": "web-1", "ipconfig0": "ip=172.16.0.10/24,gw=172.16.0.1"}},
+            {"index_key": 1, "attributes": {"name": "web-2", "ipconfig0": "ip=172.16.0.11/24,gw=172.16.0.1"}}
+          ]
+        },
+        {
+          "module": "module.db",
+          "mode": "managed",
+          "type": "libvirt_domain",
+          "name": "vm",
+          "instances": [
+            {"index_key": "a", "attributes": {"name": "", "network_interface": [
+              {"addresses": ["172.16.0.20", "203.0.113.5"], "gateway": "172.16.0.254"}
+            ]}}
+          ]
+        },
+        {
+          "mode": "data",
+          "type": "dns_a_record_set",
+          "name": "lookup",
+          "instances": [{"attributes": {"addrs": ["172.16.0.30"]}}]
+        }
+      ]
+    }"#;
+
+    #[tokio::test]
+    async fn test_adopt_state() {
+        let addresses = parse(STATE).unwrap();
+        let found: Vec<(&str, String)> = addresses
+            .iter()
+            .map(|address| (address.resource.as_str(), address.ip.to_string()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("proxmox_vm_qemu.web[0]", "172.16.0.10".to_string()),
+                ("proxmox_vm_qemu.web[1]", "172.16.0.11".to_string()),
+                (
+                    "module.db.libvirt_domain.vm[\"a\"]",
+                    "172.16.0.20".to_string()
+                ),
+                (
+                    "module.db.libvirt_domain.vm[\"a\"]",
+                    "203.0.113.5".to_string()
+                ),
+            ]
+        );
+        assert!(parse("{}").is_err());
+        assert!(parse("not json").is_err());
+
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        pool.allocate_ip("web-2".to_string()).await.unwrap();
+        let report = adopt(&pool, addresses).await;
+        let adopted: Vec<(&str, &str)> = report
+            .adopted
+            .iter()
+            .map(|address| (address.ip.as_str(), address.vm_id.as_str()))
+            .collect();
+        // web-2 already holds an address, so its instance is named after the
+        // resource; the public address is not the pool's
+        assert_eq!(
+            adopted,
+            vec![
+                ("172.16.0.10", "web-1"),
+                ("172.16.0.11", "proxmox_vm_qemu.web[1]"),
+                ("172.16.0.20", "module.db.libvirt_domain.vm[\"a\"]"),
+            ]
+        );
+        assert!(report.skipped.is_empty());
+
+        // Adopting again skips what is already allocated
+        let report = adopt(&pool, parse(STATE).unwrap()).await;
+        assert!(report.adopted.is_empty());
+        assert_eq!(report.skipped.len(), 3);
+    }
+}
@@ -0,0 +1,345 @@
+// Property-based test harness: random interleavings of allocator
+// operations checked against a reference model, and input strategies for
+// fuzzing the parsers. Storage backends reuse it through check_store and
+// check_journal, so a new backend gets the same coverage from one test.
+
+use crate::clock::MockClock;
+use crate::ippool::{IpPool, IpPoolError, Lease, PoolSnapshot};
+use crate::journal::Journal;
+use crate::pools::{DEFAULT_POOL, PoolRegistry};
+use crate::storage::{self, StateStore};
+use proptest::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Small pool so random runs exhaust it: 172.16.0.0/28, .1-.14 allocatable,
+// the gateway .1 reserved
+const NETWORK: u32 = 0xAC10_0000;
+const PREFIX_LEN: u8 = 28;
+const GATEWAY: u32 = NETWORK + 1;
+const VMS: u8 = 20; // more VMs than addresses
+const HOSTS: u8 = 18; // host numbers ops pick from, past the network too
+
+#[derive(Debug, Clone)]
+pub enum Op {
+    Allocate { vm: u8, ttl: Option<u64> }, // renews if the VM holds an address
+    Release { vm: u8 },
+    ReleaseAddress { host: u8 },
+    Reserve { host: u8 },
+    Expire { after: u64 }, // let time pass, then run lease expiry
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (0..VMS, proptest::option::of(1..600u64))
+            .prop_map(|(vm, ttl)| Op::Allocate { vm, ttl }),
+        2 => (0..VMS).prop_map(|vm| Op::Release { vm }),
+        1 => (0..HOSTS).prop_map(|host| Op::ReleaseAddress { host }),
+        1 => (0..HOSTS).prop_map(|host| Op::Reserve { host }),
+        1 => (0..900u64).prop_map(|after| Op::Expire { after }),
+    ]
+}
+
+pub fn ops(max_len: usize) -> impl Strategy<Value = Vec<Op>> {
+    proptest::collection::vec(op(), 1..max_len)
+}
+
+// Strings shaped like addresses, CIDRs, routes and MACs, mixed with
+// arbitrary ones, for parsers that must reject garbage without panicking
+pub fn fuzz_input() -> impl Strategy<Value = String> {
+    prop_oneof![
+        any::<String>(),
+        "[0-9./=: +-]{0,40}",
+        "[0-9a-fA-F:-]{0,24}",
+        mutated(),
+    ]
+}
+
+// A valid CIDR, route or MAC with one character slipped in, where lenient
+// number parsing lets mistakes through
+fn mutated() -> impl Strategy<Value = String> {
+    let valid = prop_oneof![
+        cidr().prop_map(|(addr, len)| format!("{}/{}", addr, len)),
+        (cidr(), any::<u32>()).prop_map(|((addr, len), hop)| format!(
+            "{}/{}={}",
+            addr,
+            len,
+            Ipv4Addr::from(hop)
+        )),
+        any::<[u8; 6]>().prop_map(|mac| mac.map(|octet| format!("{:02x}", octet)).join(":")),
+    ];
+    let junk = proptest::sample::select(vec!['+', '-', ' ', '.', '/', ':', '=', '0', 'x']);
+    (valid, any::<proptest::sample::Index>(), junk).prop_map(|(mut input, at, junk)| {
+        input.insert(at.index(input.len() + 1), junk);
+        input
+    })
+}
+
+// Valid "a.b.c.d/len" CIDR with its parts
+pub fn cidr() -> impl Strategy<Value = (Ipv4Addr, u8)> {
+    (any::<u32>(), 0..=32u8).prop_map(|(addr, len)| (Ipv4Addr::from(addr), len))
+}
+
+// Run an async check from a proptest body
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+fn address(host: u8) -> String {
+    Ipv4Addr::from(NETWORK + u32::from(host)).to_string()
+}
+
+fn vm(vm: u8) -> String {
+    format!("vm-{}", vm)
+}
+
+// What a correct allocator answers, kept as plain as possible
+#[derive(Debug)]
+pub struct Model {
+    allocated: BTreeMap<u32, (String, Option<u64>)>, // address -> VM, expiry
+    reserved: BTreeSet<u32>,
+}
+
+impl Model {
+    fn new() -> Self {
+        Model {
+            allocated: BTreeMap::new(),
+            reserved: BTreeSet::from([GATEWAY]),
+        }
+    }
+
+    fn in_network(addr: u32) -> bool {
+        addr >> (32 - PREFIX_LEN) == NETWORK >> (32 - PREFIX_LEN)
+    }
+
+    // Lowest free address, or the VM's own one renewed
+    fn allocate(&mut self, vm_id: &str, expires_at: Option<u64>) -> Result<u32, IpPoolError> {
+        let held = self.allocated.iter().find(|(_, (held, _))| held == vm_id);
+        if let Some((&addr, _)) = held {
+            self.allocated.insert(addr, (vm_id.to_string(), expires_at));
+            return Ok(addr);
+        }
+        let addr = (NETWORK + 1..=NETWORK + 14)
+            .find(|addr| !self.allocated.contains_key(addr) && !self.reserved.contains(addr))
+            .ok_or(IpPoolError::NoAvailableIps)?;
+        self.allocated.insert(addr, (vm_id.to_string(), expires_at));
+        Ok(addr)
+    }
+
+    fn release(&mut self, vm_id: &str) -> Result<Vec<u32>, IpPoolError> {
+        let held: Vec<u32> = (self.allocated.iter())
+            .filter(|(_, (held, _))| held == vm_id)
+            .map(|(addr, _)| *addr)
+            .collect();
+        if held.is_empty() {
+            return Err(IpPoolError::IpNotFound);
+        }
+        for addr in &held {
+            self.allocated.remove(addr);
+        }
+        Ok(held)
+    }
+
+    fn release_address(&mut self, addr: u32) -> Result<String, IpPoolError> {
+        if !Self::in_network(addr) {
+            return Err(IpPoolError::InvalidIp);
+        }
+        let (vm_id, _) = self
+            .allocated
+            .remove(&addr)
+            .ok_or(IpPoolError::IpNotFound)?;
+        Ok(vm_id)
+    }
+
+    fn reserve(&mut self, addr: u32) -> Result<(), IpPoolError> {
+        if !Self::in_network(addr) {
+            return Err(IpPoolError::InvalidIp);
+        }
+        if self.allocated.contains_key(&addr) {
+            return Err(IpPoolError::IpInUse);
+        }
+        self.reserved.insert(addr);
+        Ok(())
+    }
+
+    fn expire(&mut self, now: u64) -> BTreeSet<(String, String)> {
+        let expired: Vec<u32> = (self.allocated.iter())
+            .filter(|(_, (_, expires_at))| expires_at.is_some_and(|at| at <= now))
+            .map(|(addr, _)| *addr)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|addr| self.allocated.remove(&addr).map(|(vm_id, _)| (vm_id, addr)))
+            .map(|(vm_id, addr)| (vm_id, Ipv4Addr::from(addr).to_string()))
+            .collect()
+    }
+}
+
+// A pool and the model, driven by the same operations
+pub struct Harness {
+    pub pools: PoolRegistry,
+    pool: IpPool,
+    clock: MockClock,
+    model: Model,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        let clock = MockClock::new(1_000_000);
+        let pool = small_pool().with_clock(Arc::new(clock.clone()));
+        Harness {
+            pools: PoolRegistry::new(pool.clone()),
+            pool,
+            clock,
+            model: Model::new(),
+        }
+    }
+
+    // Apply `op` to both, asserting they answer the same and the pool
+    // still matches the model
+    pub async fn apply(&mut self, op: &Op) {
+        match *op {
+            Op::Allocate { vm: n, ttl } => {
+                let lease = ttl.map_or(Lease::PoolDefault, |ttl| {
+                    Lease::Ttl(Duration::from_secs(ttl))
+                });
+                let expires_at = ttl.map(|ttl| self.clock_now() + ttl);
+                let got = self.pool.allocate_ip_with_lease(vm(n), lease).await;
+                let want = self.model.allocate(&vm(n), expires_at);
+                let want = want.map(|addr| (Ipv4Addr::from(addr).to_string(), expires_at));
+                assert_eq!(got, want, "{:?}", op);
+            }
+            Op::Release { vm: n } => {
+                let got = self.pool.release_ip(&vm(n)).await;
+                let want = self.model.release(&vm(n));
+                let want = want.map(|addrs| {
+                    addrs
+                        .into_iter()
+                        .map(|addr| Ipv4Addr::from(addr).to_string())
+                        .collect()
+                });
+                assert_eq!(got, want, "{:?}", op);
+            }
+            Op::ReleaseAddress { host } => {
+                let got = self.pool.release_ip_by_address(&address(host)).await;
+                let want = self.model.release_address(NETWORK + u32::from(host));
+                assert_eq!(got, want, "{:?}", op);
+            }
+            Op::Reserve { host } => {
+                let got = self.pool.reserve(&address(host), "model".to_string()).await;
+                let want = self.model.reserve(NETWORK + u32::from(host));
+                assert_eq!(got, want, "{:?}", op);
+            }
+            Op::Expire { after } => {
+                self.clock.advance(Duration::from_secs(after));
+                let got: BTreeSet<_> = self.pool.expire_leases().await.into_iter().collect();
+                let want = self.model.expire(self.clock_now());
+                assert_eq!(got, want, "{:?}", op);
+            }
+        }
+        self.check().await;
+    }
+
+    pub async fn run(&mut self, ops: &[Op]) {
+        for op in ops {
+            self.apply(op).await;
+        }
+    }
+
+    fn clock_now(&self) -> u64 {
+        use crate::clock::Clock;
+        self.clock.unix_now()
+    }
+
+    // The pool's allocations, leases and counts are the model's, and its
+    // internal maps are consistent
+    async fn check(&self) {
+        let got: BTreeMap<String, (String, Option<u64>)> = (self.pool.list_allocations().await)
+            .into_iter()
+            .map(|a| (a.ip, (a.vm_id, a.expires_at)))
+            .collect();
+        let want: BTreeMap<String, (String, Option<u64>)> = (self.model.allocated.iter())
+            .map(|(addr, held)| (Ipv4Addr::from(*addr).to_string(), held.clone()))
+            .collect();
+        assert_eq!(got, want);
+
+        let stats = self.pool.get_stats().await;
+        let free = (NETWORK + 1..=NETWORK + 14)
+            .filter(|addr| !self.model.allocated.contains_key(addr))
+            .filter(|addr| !self.model.reserved.contains(addr))
+            .count();
+        assert_eq!(stats["available"], free);
+        assert_eq!(self.pool.check_consistency(false).await, []);
+    }
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn small_pool() -> IpPool {
+    let gateway = Ipv4Addr::from(GATEWAY).to_string();
+    IpPool::with_range(
+        Ipv4Addr::from(NETWORK),
+        PREFIX_LEN,
+        gateway,
+        NETWORK + 1,
+        NETWORK + 14,
+    )
+    .unwrap()
+}
+
+// Allocations, leases and reservations, what a backend must keep
+fn durable(snapshot: &PoolSnapshot) -> impl PartialEq + std::fmt::Debug {
+    (
+        snapshot.allocations.clone(),
+        snapshot.leases.clone(),
+        snapshot.reserved.clone(),
+    )
+}
+
+// Run `ops`, save through `store` and load into a fresh registry: the
+// loaded state must be the saved one
+pub async fn check_store(store: Arc<dyn StateStore>, ops: &[Op]) {
+    let mut harness = Harness::new();
+    harness.run(ops).await;
+    let saved = harness.pools.snapshot().await;
+    store.save(&saved).await.unwrap();
+
+    let loaded = PoolRegistry::new(small_pool());
+    assert!(storage::load_into(store.as_ref(), &loaded).await.unwrap());
+    assert_eq!(loaded.snapshot().await, saved);
+    let pool = loaded.get(DEFAULT_POOL).await.unwrap();
+    assert_eq!(pool.check_consistency(false).await, []);
+}
+
+// Run `ops` journaled into `dir` and replay the journal into a fresh
+// registry: it must end up where the pool did
+pub async fn check_journal(dir: PathBuf, ops: &[Op]) {
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut harness = Harness::new();
+    let journal = Arc::new(Journal::open(dir.clone(), false).unwrap());
+    harness.pools.attach_journal(journal).await;
+    harness.run(ops).await;
+    let saved = harness.pool.snapshot().await;
+
+    let replayed = PoolRegistry::new(small_pool());
+    Journal::open(dir.clone(), false)
+        .unwrap()
+        .recover(&replayed)
+        .await
+        .unwrap();
+    let pool = replayed.get(DEFAULT_POOL).await.unwrap();
+    assert_eq!(durable(&pool.snapshot().await), durable(&saved));
+    assert_eq!(pool.check_consistency(false).await, []);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
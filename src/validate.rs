@@ -0,0 +1,488 @@
+use crate::routes::StaticRoute;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+
+// Pool configuration proposed for a deploy, checked without applying it
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProposedConfig {
+    pub pools: Vec<ProposedPool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProposedPool {
+    pub name: String,
+    pub network: String, // "172.16.0.0/24", or a /24 prefix like "172.16.0" as in POOLS
+    #[serde(default)]
+    pub gateway: Option<String>, // defaults to the first host address
+    #[serde(default)]
+    pub start: Option<String>, // allocation range, defaults to every host address
+    #[serde(default)]
+    pub end: Option<String>,
+    #[serde(default)]
+    pub reserved: BTreeMap<String, String>, // label -> IP, never allocated
+    #[serde(default)]
+    pub routes: Vec<String>, // "10.50.0.0/16=172.16.0.254"
+    #[serde(default)]
+    pub allow_public: bool, // the network may lie outside private and CGNAT ranges
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,   // the configuration would not work
+    Warning, // works, but probably not as intended
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    pub pool: String,
+    pub field: String, // e.g. "gateway", "reserved.dns", "routes[0]"
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ValidationReport {
+    pub valid: bool, // no errors, warnings allowed
+    pub findings: Vec<Finding>,
+}
+
+// Network and allocation range of a pool that passed validation, for the
+// checks across pools
+struct Bounds<'a> {
+    name: &'a str,
+    network: u32,
+    prefix_len: u8,
+    start: u32,
+    end: u32,
+}
+
+pub fn validate(config: &ProposedConfig) -> ValidationReport {
+    let mut findings = Vec::new();
+    let mut bounds = Vec::new();
+    let mut names = HashMap::new();
+
+    for pool in &config.pools {
+        let mut finding = |severity, field: &str, message: String| {
+            findings.push(Finding {
+                severity,
+                pool: pool.name.clone(),
+                field: field.to_string(),
+                message,
+            })
+        };
+        if pool.name.trim().is_empty() {
+            finding(Severity::Error, "name", "pool name is empty".to_string());
+        }
+        if names.insert(pool.name.as_str(), ()).is_some() {
+            finding(
+                Severity::Error,
+                "name",
+                format!("pool {} is defined more than once", pool.name),
+            );
+        }
+        if let Some(pool_bounds) = validate_pool(pool, &mut finding) {
+            bounds.push(pool_bounds);
+        }
+    }
+
+    // Pools may share a network (e.g. after a split), not addresses
+    for (i, a) in bounds.iter().enumerate() {
+        for b in &bounds[i + 1..] {
+            if a.start <= b.end && b.start <= a.end {
+                findings.push(Finding {
+                    severity: Severity::Error,
+                    pool: b.name.to_string(),
+                    field: "network".to_string(),
+                    message: format!(
+                        "allocation range {} - {} overlaps pool {}",
+                        Ipv4Addr::from(b.start),
+                        Ipv4Addr::from(b.end),
+                        a.name
+                    ),
+                });
+            } else if a.network != b.network || a.prefix_len != b.prefix_len {
+                let shorter = a.prefix_len.min(b.prefix_len);
+                if a.network & mask(shorter) == b.network & mask(shorter) {
+                    findings.push(Finding {
+                        severity: Severity::Warning,
+                        pool: b.name.to_string(),
+                        field: "network".to_string(),
+                        message: format!("network overlaps the network of pool {}", a.name),
+                    });
+                }
+            }
+        }
+    }
+
+    ValidationReport {
+        valid: !findings.iter().any(|f| f.severity == Severity::Error),
+        findings,
+    }
+}
+
+fn validate_pool<'a>(
+    pool: &'a ProposedPool,
+    finding: &mut impl FnMut(Severity, &str, String),
+) -> Option<Bounds<'a>> {
+    let Some((network, prefix_len)) = parse_network(&pool.network) else {
+        finding(
+            Severity::Error,
+            "network",
+            format!("{} is not a CIDR like 172.16.0.0/24", pool.network),
+        );
+        return None;
+    };
+    if prefix_len > 30 {
+        finding(
+            Severity::Error,
+            "network",
+            format!("/{} leaves no addresses to allocate", prefix_len),
+        );
+        return None;
+    }
+    let masked = network & mask(prefix_len);
+    if masked != network {
+        finding(
+            Severity::Error,
+            "network",
+            format!(
+                "{} has host bits set, did you mean {}/{}?",
+                pool.network,
+                Ipv4Addr::from(masked),
+                prefix_len
+            ),
+        );
+        return None;
+    }
+    if !pool.allow_public && !is_private(network, prefix_len) {
+        finding(
+            Severity::Error,
+            "network",
+            format!(
+                "{} is not a private (RFC 1918) or CGNAT range",
+                pool.network
+            ),
+        );
+    }
+    let broadcast = network | !mask(prefix_len);
+    let in_network = |ip: u32| ip & mask(prefix_len) == network;
+    let host = |ip: u32| in_network(ip) && ip != network && ip != broadcast;
+
+    let address = |finding: &mut dyn FnMut(Severity, &str, String),
+                   field: &str,
+                   value: &str|
+     -> Option<u32> {
+        match value.parse::<Ipv4Addr>() {
+            Ok(ip) if host(u32::from(ip)) => Some(u32::from(ip)),
+            Ok(_) => {
+                finding(
+                    Severity::Error,
+                    field,
+                    format!("{} is not a host address of {}", value, pool.network),
+                );
+                None
+            }
+            Err(_) => {
+                finding(
+                    Severity::Error,
+                    field,
+                    format!("{} is not an IPv4 address", value),
+                );
+                None
+            }
+        }
+    };
+
+    let gateway = match &pool.gateway {
+        Some(gateway) => address(finding, "gateway", gateway),
+        None => Some(network + 1),
+    };
+    let start = match &pool.start {
+        Some(start) => address(finding, "start", start)?,
+        None => network + 1,
+    };
+    let end = match &pool.end {
+        Some(end) => address(finding, "end", end)?,
+        None => broadcast - 1,
+    };
+    if start > end {
+        finding(
+            Severity::Error,
+            "start",
+            format!(
+                "range start {} is above its end {}",
+                Ipv4Addr::from(start),
+                Ipv4Addr::from(end)
+            ),
+        );
+        return None;
+    }
+
+    let mut excluded = HashMap::new();
+    if let Some(gateway) = gateway {
+        excluded.insert(gateway, "gateway".to_string());
+    }
+    for (label, ip) in &pool.reserved {
+        let field = format!("reserved.{}", label);
+        let Some(ip) = address(finding, &field, ip) else {
+            continue;
+        };
+        if let Some(other) = excluded.insert(ip, label.clone()) {
+            finding(
+                Severity::Warning,
+                &field,
+                format!("{} is already reserved as {}", Ipv4Addr::from(ip), other),
+            );
+        }
+        if !(start..=end).contains(&ip) {
+            finding(
+                Severity::Warning,
+                &field,
+                format!(
+                    "{} is outside the allocation range and need not be reserved",
+                    Ipv4Addr::from(ip)
+                ),
+            );
+        }
+    }
+    let excluded_in_range = excluded
+        .keys()
+        .filter(|ip| (start..=end).contains(*ip))
+        .count() as u64;
+    if excluded_in_range > u64::from(end - start) {
+        finding(
+            Severity::Error,
+            "reserved",
+            "every address of the allocation range is reserved".to_string(),
+        );
+    }
+
+    for (i, route) in pool.routes.iter().enumerate() {
+        let field = format!("routes[{}]", i);
+        match StaticRoute::parse(route) {
+            Some(parsed) if !in_network(u32::from(parsed.next_hop)) => finding(
+                Severity::Error,
+                &field,
+                format!(
+                    "next hop {} is not reachable from {}",
+                    parsed.next_hop, pool.network
+                ),
+            ),
+            Some(_) => {}
+            None => finding(
+                Severity::Error,
+                &field,
+                format!("{} is not a route like 10.50.0.0/16=172.16.0.254", route),
+            ),
+        }
+    }
+
+    Some(Bounds {
+        name: &pool.name,
+        network,
+        prefix_len,
+        start,
+        end,
+    })
+}
+
+// Private (RFC 1918) and shared address space (RFC 6598, CGNAT) ranges
+const PRIVATE_RANGES: [(Ipv4Addr, u8); 4] = [
+    (Ipv4Addr::new(10, 0, 0, 0), 8),
+    (Ipv4Addr::new(172, 16, 0, 0), 12),
+    (Ipv4Addr::new(192, 168, 0, 0), 16),
+    (Ipv4Addr::new(100, 64, 0, 0), 10),
+];
+
+// Whether the whole network lies in a private or CGNAT range
+pub fn is_private(network: u32, prefix_len: u8) -> bool {
+    PRIVATE_RANGES.iter().any(|(range, range_len)| {
+        prefix_len >= *range_len && network & mask(*range_len) == u32::from(*range)
+    })
+}
+
+// Errors in the network or gateway of a pool configured at startup, which
+// would otherwise give a pool nobody can use
+pub fn network_errors(name: &str, network: &str, gateway: &str, allow_public: bool) -> Vec<String> {
+    let pool = ProposedPool {
+        name: name.to_string(),
+        network: network.to_string(),
+        gateway: Some(gateway.to_string()),
+        start: None,
+        end: None,
+        reserved: BTreeMap::new(),
+        routes: Vec::new(),
+        allow_public,
+    };
+    validate(&ProposedConfig { pools: vec![pool] })
+        .findings
+        .into_iter()
+        .filter(|f| f.severity == Severity::Error && (f.field == "network" || f.field == "gateway"))
+        .map(|f| format!("{}: {}", f.field, f.message))
+        .collect()
+}
+
+// "172.16.0.0/24", or the "172.16.0" prefixes NETWORK and POOLS take
+pub fn parse_network(value: &str) -> Option<(u32, u8)> {
+    let (address, prefix_len) = match value.split_once('/') {
+        Some((address, prefix_len)) => (
+            address.to_string(),
+            // u8 parsing takes a leading "+"
+            prefix_len
+                .bytes()
+                .all(|b| b.is_ascii_digit())
+                .then(|| prefix_len.parse().ok())
+                .flatten()
+                .filter(|len| *len <= 32)?,
+        ),
+        None if value.split('.').count() == 3 => (format!("{}.0", value), 24),
+        None => return None,
+    };
+    let address: Ipv4Addr = address.parse().ok()?;
+    Some((u32::from(address), prefix_len))
+}
+
+pub fn mask(prefix_len: u8) -> u32 {
+    u32::MAX
+        .checked_shl(32 - u32::from(prefix_len))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit;
+    use proptest::prelude::*;
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(4096))]
+
+        #[test]
+        fn prop_parse_network(input in testkit::fuzz_input(), (addr, len) in testkit::cidr()) {
+            // Garbage is rejected without panicking, and only digits, dots
+            // and the slash make it through
+            if parse_network(&input).is_some() {
+                prop_assert!(input.chars().all(|c| c.is_ascii_digit() || c == '.' || c == '/'));
+            }
+            prop_assert_eq!(
+                parse_network(&format!("{}/{}", addr, len)),
+                Some((u32::from(addr), len))
+            );
+        }
+    }
+
+    fn pool(name: &str, network: &str) -> ProposedPool {
+        ProposedPool {
+            name: name.to_string(),
+            network: network.to_string(),
+            gateway: None,
+            start: None,
+            end: None,
+            reserved: BTreeMap::new(),
+            routes: Vec::new(),
+            allow_public: false,
+        }
+    }
+
+    fn fields(report: &ValidationReport, severity: Severity) -> Vec<(String, String)> {
+        report
+            .findings
+            .iter()
+            .filter(|f| f.severity == severity)
+            .map(|f| (f.pool.clone(), f.field.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let mut default = pool("default", "172.16.0");
+        default
+            .reserved
+            .insert("dns".to_string(), "172.16.0.53".to_string());
+        default.routes = vec!["10.50.0.0/16=172.16.0.254".to_string()];
+        let mut lower = pool("lower", "10.0.0.0/24");
+        lower.end = Some("10.0.0.127".to_string());
+        let mut upper = pool("upper", "10.0.0.0/24");
+        upper.start = Some("10.0.0.128".to_string());
+
+        let report = validate(&ProposedConfig {
+            pools: vec![default, lower, upper],
+        });
+        assert!(report.valid);
+        assert!(report.findings.is_empty());
+    }
+
+    #[test]
+    fn test_findings() {
+        let mut default = pool("default", "172.16.0.0/24");
+        default.gateway = Some("172.16.1.1".to_string());
+        default
+            .reserved
+            .insert("dns".to_string(), "172.16.0.300".to_string());
+        default
+            .reserved
+            .insert("router".to_string(), "172.16.0.1".to_string());
+        default.routes = vec!["10.50.0.0/16=10.0.0.1".to_string()];
+        let unaligned = pool("unaligned", "10.0.0.1/24");
+        let overlapping = pool("overlapping", "172.16.0.0/25");
+        let wide = pool("wide", "172.16.0.0/23");
+        let mut empty = pool("empty", "10.1.0.0/24");
+        empty.start = Some("10.1.0.20".to_string());
+        empty.end = Some("10.1.0.10".to_string());
+
+        let report = validate(&ProposedConfig {
+            pools: vec![default, unaligned, overlapping, wide, empty],
+        });
+        assert!(!report.valid);
+        let errors = fields(&report, Severity::Error);
+        let expected: Vec<(String, String)> = [
+            ("default", "gateway"),
+            ("default", "reserved.dns"),
+            ("default", "routes[0]"),
+            ("unaligned", "network"),
+            ("empty", "start"),
+            ("overlapping", "network"),
+            ("wide", "network"),
+            ("wide", "network"),
+        ]
+        .iter()
+        .map(|(pool, field)| (pool.to_string(), field.to_string()))
+        .collect();
+        assert_eq!(errors, expected);
+        assert!(fields(&report, Severity::Warning).is_empty());
+    }
+
+    #[test]
+    fn test_public_networks() {
+        // A typo for 172.16.0 lands in public space
+        let typo = pool("typo", "172.160.0");
+        let mut public = pool("public", "198.51.100.0/24");
+        public.allow_public = true;
+        let cgnat = pool("cgnat", "100.64.8.0/22");
+        let straddling = pool("straddling", "192.0.0.0/8");
+
+        let report = validate(&ProposedConfig {
+            pools: vec![typo, public, cgnat, straddling],
+        });
+        assert_eq!(
+            fields(&report, Severity::Error),
+            vec![
+                ("typo".to_string(), "network".to_string()),
+                ("straddling".to_string(), "network".to_string())
+            ]
+        );
+
+        assert_eq!(
+            network_errors("lab", "10.30.0", "10.30.0.1", false).len(),
+            0
+        );
+        let errors = network_errors("lab", "10.30.0", "10.31.0.1", false);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("gateway: "));
+        assert_eq!(network_errors("lab", "8.8.8", "8.8.8.1", true).len(), 0);
+    }
+}
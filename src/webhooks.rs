@@ -0,0 +1,426 @@
+use crate::events::{EventBus, unix_now};
+use crate::ippool::IpPoolError;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+
+// Deliveries kept per webhook for the status API
+const MAX_DELIVERIES: usize = 200;
+// Undeliverable payloads kept per webhook until redriven; the oldest go first
+const MAX_DEAD_LETTERS: usize = 1000;
+
+// Webhook IDs in the status API
+pub const EVENTS_WEBHOOK: &str = "events";
+pub const CAPACITY_WEBHOOK: &str = "capacity";
+pub const REPORTS_WEBHOOK: &str = "reports";
+
+// Headers on signed webhook requests
+pub const SIGNATURE_HEADER: &str = "x-signature";
+pub const TIMESTAMP_HEADER: &str = "x-timestamp";
+pub const NONCE_HEADER: &str = "x-nonce";
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Signs webhook payloads with the shared WEBHOOK_SECRET. The signature
+// covers "<timestamp>.<nonce>.<body>", so receivers can reject stale or
+// replayed requests as well as forged ones.
+#[derive(Clone)]
+pub struct Signer {
+    secret: Vec<u8>,
+}
+
+impl std::fmt::Debug for Signer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Signer { .. }")
+    }
+}
+
+impl Signer {
+    pub fn new(secret: &str) -> Self {
+        Signer {
+            secret: secret.as_bytes().to_vec(),
+        }
+    }
+
+    // "sha256=<hex HMAC-SHA256>", the X-Signature value
+    pub fn sign(&self, timestamp: u64, nonce: &str, body: &[u8]) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC takes any key size");
+        mac.update(format!("{}.{}.", timestamp, nonce).as_bytes());
+        mac.update(body);
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+}
+
+// 16 random bytes hex encoded, unique per request
+fn nonce() -> String {
+    let mut bytes = [0u8; 16];
+    match std::fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes)) {
+        Ok(()) => hex::encode(bytes),
+        Err(_) => format!(
+            "{:032x}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0)
+        ),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending, // being attempted
+    Delivered,
+    DeadLettered, // every attempt failed, waiting for a redrive
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Attempt {
+    pub at: u64, // unix seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>, // HTTP status, None when no response came back
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// One payload and every attempt to deliver it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Delivery {
+    pub id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<u64>, // event the payload was made from
+    pub created_at: u64,
+    pub status: DeliveryStatus,
+    pub attempts: Vec<Attempt>,
+    pub payload: serde_json::Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WebhookSummary {
+    pub id: String,
+    pub url: String,
+    pub signed: bool,
+    pub delivered: usize, // of the recent deliveries
+    pub dead_letters: usize,
+}
+
+#[derive(Debug, Default)]
+struct DeliveryLog {
+    next_id: u64,
+    recent: VecDeque<Delivery>, // oldest first
+    dead: BTreeMap<u64, Delivery>,
+}
+
+impl DeliveryLog {
+    fn record(&mut self, delivery: &Delivery) {
+        match self.recent.iter_mut().find(|d| d.id == delivery.id) {
+            Some(recent) => *recent = delivery.clone(),
+            None => {
+                if self.recent.len() == MAX_DELIVERIES {
+                    self.recent.pop_front();
+                }
+                self.recent.push_back(delivery.clone());
+            }
+        }
+        if delivery.status == DeliveryStatus::DeadLettered {
+            self.dead.insert(delivery.id, delivery.clone());
+            while self.dead.len() > MAX_DEAD_LETTERS {
+                self.dead.pop_first();
+            }
+        }
+    }
+}
+
+// A webhook endpoint. Each payload is tried `max_attempts` times, waiting
+// `backoff` and then twice as long after every failure; payloads that never
+// get through are dead-lettered until redriven.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    id: String,
+    url: String,
+    http: reqwest::Client,
+    signer: Option<Signer>,
+    max_attempts: u32,
+    backoff: Duration,
+    log: Arc<Mutex<DeliveryLog>>,
+}
+
+impl Webhook {
+    pub fn new(id: &str, url: String, signer: Option<Signer>) -> Self {
+        Webhook {
+            id: id.to_string(),
+            url,
+            http: reqwest::Client::new(),
+            signer,
+            max_attempts: 3,
+            backoff: Duration::from_secs(1),
+            log: Arc::default(),
+        }
+    }
+
+    pub fn with_retries(mut self, max_attempts: u32, backoff: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.backoff = backoff;
+        self
+    }
+
+    // Deliver a payload, retrying until it gets through or is dead-lettered
+    pub async fn deliver(&self, event_id: Option<u64>, payload: serde_json::Value) -> Delivery {
+        let delivery = {
+            let mut log = self.log.lock().unwrap();
+            log.next_id += 1;
+            let delivery = Delivery {
+                id: log.next_id,
+                event_id,
+                created_at: unix_now(),
+                status: DeliveryStatus::Pending,
+                attempts: Vec::new(),
+                payload,
+            };
+            log.record(&delivery);
+            delivery
+        };
+        self.attempt(delivery).await
+    }
+
+    async fn attempt(&self, mut delivery: Delivery) -> Delivery {
+        let mut backoff = self.backoff;
+        for attempt in 1..=self.max_attempts {
+            let result = post(
+                &self.http,
+                &self.url,
+                &delivery.payload,
+                self.signer.as_ref(),
+            )
+            .await;
+            delivery.attempts.push(Attempt {
+                at: unix_now(),
+                status: match &result {
+                    Ok(status) => Some(*status),
+                    Err(e) => e.status().map(|status| status.as_u16()),
+                },
+                error: result.as_ref().err().map(|e| e.to_string()),
+            });
+            if result.is_ok() {
+                delivery.status = DeliveryStatus::Delivered;
+                break;
+            }
+            if attempt == self.max_attempts {
+                delivery.status = DeliveryStatus::DeadLettered;
+                tracing::error!(
+                    "Webhook {} delivery {} dead-lettered after {} attempts",
+                    self.id,
+                    delivery.id,
+                    attempt
+                );
+                break;
+            }
+            self.log.lock().unwrap().record(&delivery);
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        self.log.lock().unwrap().record(&delivery);
+        delivery
+    }
+
+    // Recent deliveries, newest first
+    pub fn deliveries(&self) -> Vec<Delivery> {
+        self.log
+            .lock()
+            .unwrap()
+            .recent
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    // Undeliverable payloads, oldest first
+    pub fn dead_letters(&self) -> Vec<Delivery> {
+        self.log.lock().unwrap().dead.values().cloned().collect()
+    }
+
+    // Try every dead letter again; the ones that fail again stay
+    // dead-lettered
+    pub async fn redrive(&self) -> Vec<Delivery> {
+        let dead = std::mem::take(&mut self.log.lock().unwrap().dead);
+        let mut redriven = Vec::new();
+        for (_, mut delivery) in dead {
+            delivery.status = DeliveryStatus::Pending;
+            redriven.push(self.attempt(delivery).await);
+        }
+        redriven
+    }
+
+    pub fn summary(&self) -> WebhookSummary {
+        let log = self.log.lock().unwrap();
+        WebhookSummary {
+            id: self.id.clone(),
+            url: self.url.clone(),
+            signed: self.signer.is_some(),
+            delivered: log
+                .recent
+                .iter()
+                .filter(|d| d.status == DeliveryStatus::Delivered)
+                .count(),
+            dead_letters: log.dead.len(),
+        }
+    }
+}
+
+// Configured webhooks by ID
+#[derive(Debug, Clone, Default)]
+pub struct Webhooks {
+    webhooks: Arc<Mutex<BTreeMap<String, Webhook>>>,
+}
+
+impl Webhooks {
+    pub fn add(&self, webhook: Webhook) {
+        self.webhooks
+            .lock()
+            .unwrap()
+            .insert(webhook.id.clone(), webhook);
+    }
+
+    pub fn get(&self, id: &str) -> Result<Webhook, IpPoolError> {
+        self.webhooks
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or(IpPoolError::WebhookNotFound)
+    }
+
+    pub fn list(&self) -> Vec<WebhookSummary> {
+        let webhooks: Vec<Webhook> = self.webhooks.lock().unwrap().values().cloned().collect();
+        webhooks.iter().map(Webhook::summary).collect()
+    }
+}
+
+// POST a JSON payload, with signature headers when a secret is configured;
+// the HTTP status on success
+async fn post(
+    http: &reqwest::Client,
+    url: &str,
+    payload: &serde_json::Value,
+    signer: Option<&Signer>,
+) -> Result<u16, reqwest::Error> {
+    let body = serde_json::to_vec(payload).expect("JSON values serialize");
+    let mut request = http
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json");
+    if let Some(signer) = signer {
+        let timestamp = unix_now();
+        let nonce = nonce();
+        request = request
+            .header(SIGNATURE_HEADER, signer.sign(timestamp, &nonce, &body))
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(NONCE_HEADER, nonce);
+    }
+    let response = request.body(body).send().await?.error_for_status()?;
+    Ok(response.status().as_u16())
+}
+
+// Delivers every allocation event (allocated, released, migrated, expired)
+// as it happens, in order
+pub async fn forward_events(webhook: Webhook, events: EventBus) {
+    let mut receiver = events.subscribe();
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!("Event webhook fell behind, {} events skipped", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let payload = serde_json::to_value(&event).expect("events serialize");
+        webhook.deliver(Some(event.id), payload).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        let signer = Signer::new("topsecret");
+        let body = br#"{"kind":"allocated"}"#;
+        assert_eq!(
+            signer.sign(1700000000, "abc123", body),
+            "sha256=c7a25a54629f504ef76573de5efdc0786b7e06455a3d3a3ec1102e62577857c7"
+        );
+        // Timestamp, nonce and secret are all covered
+        assert_ne!(
+            signer.sign(1700000001, "abc123", body),
+            signer.sign(1700000000, "abc123", body)
+        );
+        assert_ne!(
+            signer.sign(1700000000, "abc124", body),
+            signer.sign(1700000000, "abc123", body)
+        );
+        assert_ne!(
+            Signer::new("other").sign(1700000000, "abc123", body),
+            signer.sign(1700000000, "abc123", body)
+        );
+        assert_ne!(nonce(), nonce());
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter_and_redrive() {
+        use axum::{Router, http::StatusCode, routing::post};
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // Receiver that fails until switched on
+        let up = Arc::new(AtomicBool::new(false));
+        let receiver = up.clone();
+        let app = Router::new().route(
+            "/",
+            post(move || async move {
+                if receiver.load(Ordering::SeqCst) {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let webhook = Webhook::new(EVENTS_WEBHOOK, url, Some(Signer::new("secret")))
+            .with_retries(2, Duration::ZERO);
+        let delivery = webhook
+            .deliver(Some(7), serde_json::json!({"kind": "allocated"}))
+            .await;
+        assert_eq!(delivery.status, DeliveryStatus::DeadLettered);
+        assert_eq!(delivery.attempts.len(), 2);
+        assert_eq!(delivery.attempts[0].status, Some(503));
+        assert_eq!(webhook.dead_letters(), vec![delivery.clone()]);
+        assert_eq!(webhook.summary().dead_letters, 1);
+
+        // Still down: the delivery stays dead-lettered
+        let redriven = webhook.redrive().await;
+        assert_eq!(redriven[0].status, DeliveryStatus::DeadLettered);
+        assert_eq!(redriven[0].attempts.len(), 4);
+        assert_eq!(webhook.dead_letters().len(), 1);
+
+        up.store(true, Ordering::SeqCst);
+        let redriven = webhook.redrive().await;
+        assert_eq!(redriven[0].status, DeliveryStatus::Delivered);
+        assert_eq!(redriven[0].attempts.last().unwrap().status, Some(200));
+        assert!(webhook.dead_letters().is_empty());
+
+        // One entry per payload, however often it was tried
+        let deliveries = webhook.deliveries();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].event_id, Some(7));
+        assert_eq!(deliveries[0].status, DeliveryStatus::Delivered);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::ippool::{IpPool, IpPoolError};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::net::Ipv4Addr;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WireGuardPeer {
+    pub public_key: String,
+    pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+// What is kept about a peer, under its public key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tunnel {
+    pub address: String, // e.g. "10.100.0.2"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+// Tunnel address pool keyed by peer public key. Every peer gets a /32
+// from the configured tunnel range. Peers are kept in `pool`, so they are
+// journaled, snapshotted and replicated with it.
+#[derive(Debug, Clone)]
+pub struct WireGuardPool {
+    base: u32, // the tunnel /24
+    server_ip: String,
+    pool: IpPool,
+}
+
+impl WireGuardPool {
+    // `network` is the tunnel /24 prefix, e.g. "10.100.0"
+    pub fn new(network: String, server_ip: String, pool: IpPool) -> Self {
+        let base: Ipv4Addr = format!("{}.0", network)
+            .parse()
+            .expect("invalid network prefix");
+
+        WireGuardPool {
+            base: u32::from(base),
+            server_ip,
+            pool,
+        }
+    }
+
+    pub async fn add_peer(
+        &self,
+        public_key: String,
+        name: Option<String>,
+    ) -> Result<WireGuardPeer, IpPoolError> {
+        if !Self::is_valid_public_key(&public_key) {
+            return Err(IpPoolError::InvalidPublicKey);
+        }
+
+        // .1-.254, lowest first, except the server's own address
+        let candidates = (self.base + 1..=self.base + 254)
+            .map(|ip| Ipv4Addr::from(ip).to_string())
+            .filter(|ip| *ip != self.server_ip);
+        let tunnel = (self.pool)
+            .add_wireguard_peer(&public_key, name, candidates)
+            .await?;
+        Ok(peer(public_key, tunnel))
+    }
+
+    pub async fn remove_peer(&self, public_key: &str) -> Result<(), IpPoolError> {
+        self.pool.remove_wireguard_peer(public_key).await?;
+        Ok(())
+    }
+
+    pub async fn get_peer(&self, public_key: &str) -> Result<WireGuardPeer, IpPoolError> {
+        let mut peers = self.pool.wireguard_peers().await;
+        let tunnel = peers.remove(public_key).ok_or(IpPoolError::IpNotFound)?;
+        Ok(peer(public_key.to_string(), tunnel))
+    }
+
+    pub async fn list_peers(&self) -> Vec<WireGuardPeer> {
+        let mut peers: Vec<WireGuardPeer> = (self.pool.wireguard_peers().await.into_iter())
+            .map(|(public_key, tunnel)| peer(public_key, tunnel))
+            .collect();
+        peers.sort_by_key(|peer| {
+            let ip = peer.address.trim_end_matches("/32");
+            ip.parse::<Ipv4Addr>().ok()
+        });
+        peers
+    }
+
+    // Render [Peer] blocks for the server side wg config
+    pub async fn export_config(&self) -> String {
+        let mut out = String::new();
+
+        for peer in self.list_peers().await {
+            out.push_str("[Peer]\n");
+            if let Some(name) = &peer.name {
+                let _ = writeln!(out, "# {}", name);
+            }
+            let _ = writeln!(out, "PublicKey = {}", peer.public_key);
+            let _ = writeln!(out, "AllowedIPs = {}", peer.address);
+            out.push('\n');
+        }
+
+        out
+    }
+
+    // WireGuard keys are 32 bytes, base64 encoded with padding (44 chars)
+    fn is_valid_public_key(key: &str) -> bool {
+        key.len() == 44
+            && key.ends_with('=')
+            && key[..43]
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/')
+    }
+}
+
+fn peer(public_key: String, tunnel: Tunnel) -> WireGuardPeer {
+    WireGuardPeer {
+        public_key,
+        address: format!("{}/32", tunnel.address),
+        name: tunnel.name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_A: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+    const KEY_B: &str = "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=";
+
+    fn wireguard() -> WireGuardPool {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        WireGuardPool::new("10.100.0".to_string(), "10.100.0.1".to_string(), pool)
+    }
+
+    #[tokio::test]
+    async fn test_add_peer() {
+        let wg = wireguard();
+
+        let peer = wg
+            .add_peer(KEY_A.to_string(), Some("laptop".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(peer.address, "10.100.0.2/32");
+        assert_eq!(peer.name.as_deref(), Some("laptop"));
+
+        // Same key gets the same address back
+        let again = wg.add_peer(KEY_A.to_string(), None).await.unwrap();
+        assert_eq!(again.address, peer.address);
+        assert_eq!(again.name.as_deref(), Some("laptop"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_public_key() {
+        let wg = wireguard();
+
+        let result = wg.add_peer("not-a-key".to_string(), None).await;
+        assert!(matches!(result, Err(IpPoolError::InvalidPublicKey)));
+    }
+
+    #[tokio::test]
+    async fn test_export_config() {
+        let wg = wireguard();
+
+        wg.add_peer(KEY_A.to_string(), Some("laptop".to_string()))
+            .await
+            .unwrap();
+        wg.add_peer(KEY_B.to_string(), None).await.unwrap();
+
+        let config = wg.export_config().await;
+        assert_eq!(
+            config,
+            format!(
+                "[Peer]\n# laptop\nPublicKey = {}\nAllowedIPs = 10.100.0.2/32\n\n\
+                 [Peer]\nPublicKey = {}\nAllowedIPs = 10.100.0.3/32\n\n",
+                KEY_A, KEY_B
+            )
+        );
+
+        wg.remove_peer(KEY_A).await.unwrap();
+        assert_eq!(wg.list_peers().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_peers_survive_snapshots() {
+        let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+        let wg = WireGuardPool::new(
+            "10.100.0".to_string(),
+            "10.100.0.1".to_string(),
+            pool.clone(),
+        );
+        wg.add_peer(KEY_A.to_string(), Some("laptop".to_string()))
+            .await
+            .unwrap();
+        wg.add_peer(KEY_B.to_string(), None).await.unwrap();
+        wg.remove_peer(KEY_A).await.unwrap();
+
+        let json = serde_json::to_string(&pool.snapshot().await).unwrap();
+        let restored = IpPool::from_snapshot(serde_json::from_str(&json).unwrap()).unwrap();
+        let wg = WireGuardPool::new("10.100.0".to_string(), "10.100.0.1".to_string(), restored);
+        assert_eq!(wg.get_peer(KEY_B).await.unwrap().address, "10.100.0.3/32");
+        assert!(matches!(
+            wg.get_peer(KEY_A).await,
+            Err(IpPoolError::IpNotFound)
+        ));
+
+        // The freed address goes to the next peer; the VM pool is untouched
+        let peer = wg
+            .add_peer(KEY_A.to_string(), Some("phone".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(peer.address, "10.100.0.2/32");
+        assert!(pool.list_allocations().await.is_empty());
+    }
+}
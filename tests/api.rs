@@ -0,0 +1,909 @@
+// End-to-end tests against the whole API, served in-process
+
+use axum::Router;
+use axum::body::Body;
+use axum::http::{HeaderMap, Method, Request, StatusCode, request};
+use ippool::app::{self, Extras, Limits};
+use ippool::budget::AllocationBudget;
+use ippool::clock::{Clock, SystemClock};
+use ippool::delegations;
+use ippool::ippool::IpPool;
+use ippool::state::AppState;
+use serde_json::{Value, json};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tower::ServiceExt;
+
+async fn call(app: &Router, method: Method, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+    let (status, _, body) = send(app, Request::builder().method(method).uri(uri), body).await;
+    (status, body)
+}
+
+// Send a request built by the caller, e.g. with extra headers
+async fn send(
+    app: &Router,
+    request: request::Builder,
+    body: Option<Value>,
+) -> (StatusCode, HeaderMap, Value) {
+    let request = match body {
+        Some(body) => request
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string())),
+        None => request.body(Body::empty()),
+    }
+    .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (
+        status,
+        headers,
+        serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+    )
+}
+
+// GET a plain-text export
+async fn text(app: &Router, uri: &str) -> (StatusCode, String) {
+    let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, String::from_utf8(bytes.to_vec()).unwrap())
+}
+
+fn with_state(state: AppState) -> Router {
+    app::router(state, Extras::default(), &Limits::default())
+}
+
+#[tokio::test]
+async fn test_allocate_get_and_release() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let app = app::embedded(pool.clone());
+
+    let body = json!({ "vm_id": "vm-1" });
+    let (status, allocation) = call(&app, Method::POST, "/api/v1/ip/allocate", Some(body)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(allocation["ip"], "172.16.0.2");
+
+    // The router works on the pool it was given
+    assert_eq!(pool.get_allocation("vm-1").await.unwrap().ip, "172.16.0.2");
+    let (status, found) = call(&app, Method::GET, "/api/v1/ip/vm-1", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(found[0]["ip"], "172.16.0.2");
+
+    let (status, _) = call(&app, Method::DELETE, "/api/v1/ip/release/vm-1", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, error) = call(&app, Method::GET, "/api/v1/ip/vm-1", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(error["error"].is_string());
+}
+
+#[tokio::test]
+async fn test_budget_charges_callers_without_a_key() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let app = with_state(AppState {
+        budget: Some(AllocationBudget::new(1, Duration::from_secs(3600))),
+        ..AppState::new(pool)
+    });
+    let allocate = |caller: Option<&str>, vm_id: &str| {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/api/v1/ip/allocate");
+        let request = match caller {
+            Some(caller) => request.header("x-caller", caller),
+            None => request,
+        };
+        send(&app, request, Some(json!({ "vm_id": vm_id })))
+    };
+
+    // Leaving out the key does not get around the budget
+    assert_eq!(allocate(None, "vm-1").await.0, StatusCode::CREATED);
+    let (status, headers, _) = allocate(None, "vm-2").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(headers.contains_key("retry-after"));
+
+    // Each X-Caller has a budget of its own
+    assert_eq!(allocate(Some("ci"), "vm-2").await.0, StatusCode::CREATED);
+    assert_eq!(
+        allocate(Some("ci"), "vm-3").await.0,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    assert_eq!(allocate(Some("tf"), "vm-3").await.0, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn test_v2_allocates_through_the_key() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let key_hash = delegations::hash_key("key-a");
+    let range = (
+        "172.16.0.10".parse().unwrap(),
+        "172.16.0.19".parse().unwrap(),
+    );
+    pool.delegate("squad-a", range.0, range.1, key_hash)
+        .await
+        .unwrap();
+    let app = with_state(AppState {
+        budget: Some(AllocationBudget::new(1, Duration::from_secs(3600))),
+        ..AppState::new(pool.clone())
+    });
+    let allocate = |vm_id: &str| {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/api/v2/allocations")
+            .header("x-api-key", "key-a");
+        send(&app, request, Some(json!({ "vm_id": vm_id })))
+    };
+
+    // The delegation places and owns the address
+    let (status, _, created) = allocate("vm-1").await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["ip"], "172.16.0.10");
+    let allocation = pool.get_allocation("vm-1").await.unwrap();
+    assert_eq!(allocation.owner.as_deref(), Some("squad-a"));
+
+    // The key's budget is spent: a problem, and the VM got nothing
+    let (status, headers, problem) = allocate("vm-2").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(headers["content-type"], "application/problem+json");
+    assert!(headers.contains_key("retry-after"));
+    assert_eq!(problem["status"], 429);
+    assert!(pool.get_allocation("vm-2").await.is_err());
+}
+
+#[tokio::test]
+async fn test_put_allocation_converges() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let app = app::embedded(pool.clone());
+    let uri = "/api/v1/ip/allocations/vm-1";
+    let desired = json!({ "interface": "eth0", "labels": { "role": "web" } });
+
+    let (status, created) = call(&app, Method::PUT, uri, Some(desired.clone())).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, again) = call(&app, Method::PUT, uri, Some(desired)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(again["ip"], created["ip"]);
+    assert_eq!(again["labels"], json!({ "role": "web" }));
+
+    // Labels converge on the requested ones
+    let relabelled = json!({ "interface": "eth0", "labels": { "role": "db" } });
+    let (status, found) = call(&app, Method::PUT, uri, Some(relabelled)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(found["ip"], created["ip"]);
+    assert_eq!(found["labels"], json!({ "role": "db" }));
+
+    // Another interface would be another address: refused, nothing changes
+    let moved = json!({ "interface": "eth1" });
+    let (status, error) = call(&app, Method::PUT, uri, Some(moved)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(error["error"].is_string());
+    let held = pool.get_allocations("vm-1").await.unwrap();
+    assert_eq!(held.len(), 1);
+    assert_eq!(held[0].ip, created["ip"]);
+}
+
+#[tokio::test]
+async fn test_delete_allocation_is_idempotent() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let app = app::embedded(pool.clone());
+    let uri = "/api/v1/ip/allocations/vm-1";
+
+    call(&app, Method::PUT, uri, None).await;
+    let (status, _) = call(&app, Method::DELETE, uri, None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert!(pool.get_allocations("vm-1").await.is_err());
+
+    // Deleting what is already gone, or never was, is not an error
+    let (status, _) = call(&app, Method::DELETE, uri, None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = call(&app, Method::DELETE, "/api/v1/ip/allocations/vm-9", None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(pool.get_stats().await["allocated"], 0);
+}
+
+#[tokio::test]
+async fn test_expiring_leases_of_a_pool() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let lab = IpPool::new("10.20.0".to_string(), "10.20.0.1".to_string());
+    let state = AppState::new(pool);
+    state.pools.insert("lab".to_string(), lab).await;
+    let app = with_state(state);
+    for (vm_id, pool) in [("web-1", "default"), ("lab-1", "lab")] {
+        let body = json!({ "vm_id": vm_id, "pool": pool, "ttl": 600 });
+        call(&app, Method::POST, "/api/v1/ip/allocate", Some(body)).await;
+    }
+
+    let uri = "/api/v1/ip/allocations/expiring?within=1h&pool=lab";
+    let (status, expiring) = call(&app, Method::GET, uri, None).await;
+    assert_eq!(status, StatusCode::OK);
+    let vm_ids: Vec<&Value> = expiring
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|a| &a["vm_id"])
+        .collect();
+    assert_eq!(vm_ids, vec!["lab-1"]);
+    let uri = "/api/v1/ip/allocations/expiring?within=1h";
+    let (_, expiring) = call(&app, Method::GET, uri, None).await;
+    assert_eq!(expiring[0]["vm_id"], "web-1");
+
+    let uri = "/api/v1/ip/allocations/expiring?pool=missing";
+    assert_eq!(
+        call(&app, Method::GET, uri, None).await.0,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn test_freeze_one_pool() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let lab = IpPool::new("10.20.0".to_string(), "10.20.0.1".to_string());
+    let state = AppState::new(pool);
+    state.pools.insert("lab".to_string(), lab).await;
+    let app = with_state(state);
+    let allocate = |vm_id: &str, pool: &str| {
+        let body = json!({ "vm_id": vm_id, "pool": pool });
+        call(&app, Method::POST, "/api/v1/ip/allocate", Some(body))
+    };
+
+    let (status, frozen) = call(
+        &app,
+        Method::POST,
+        "/api/v1/admin/pool/freeze?pool=lab",
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(frozen["pool"], "lab");
+    assert_eq!(allocate("lab-1", "lab").await.0, StatusCode::LOCKED);
+    assert_eq!(allocate("web-1", "default").await.0, StatusCode::CREATED);
+
+    let uri = "/api/v1/admin/pool/unfreeze?pool=lab";
+    assert_eq!(call(&app, Method::POST, uri, None).await.0, StatusCode::OK);
+    assert_eq!(allocate("lab-1", "lab").await.0, StatusCode::CREATED);
+
+    let uri = "/api/v1/admin/pool/freeze?pool=missing";
+    assert_eq!(
+        call(&app, Method::POST, uri, None).await.0,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn test_forecast_of_a_pool() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let lab = IpPool::new("10.20.0".to_string(), "10.20.0.1".to_string());
+    let state = AppState::new(pool);
+    state.pools.insert("lab".to_string(), lab).await;
+    let app = with_state(state);
+    for vm_id in ["lab-1", "lab-2"] {
+        let body = json!({ "vm_id": vm_id, "pool": "lab" });
+        call(&app, Method::POST, "/api/v1/ip/allocate", Some(body)).await;
+    }
+
+    let uri = "/api/v1/ip/stats/forecast?pool=lab";
+    let (status, forecast) = call(&app, Method::GET, uri, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(forecast["allocations_in_window"], 2);
+    let uri = "/api/v1/ip/stats/forecast";
+    let (_, forecast) = call(&app, Method::GET, uri, None).await;
+    assert_eq!(forecast["allocations_in_window"], 0);
+
+    let uri = "/api/v1/ip/stats/forecast?pool=missing";
+    assert_eq!(
+        call(&app, Method::GET, uri, None).await.0,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn test_release_by_label() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let lab = IpPool::new("10.20.0".to_string(), "10.20.0.1".to_string());
+    let state = AppState::new(pool.clone());
+    state.pools.insert("lab".to_string(), lab.clone()).await;
+    let app = with_state(state);
+    let allocate = |vm_id: &str, pool: &str, labels: Value| {
+        let body = json!({ "vm_id": vm_id, "pool": pool, "labels": labels });
+        call(&app, Method::POST, "/api/v1/ip/allocate", Some(body))
+    };
+    let ci = json!({ "cluster": "ci-1" });
+    allocate("node-1", "default", ci.clone()).await;
+    allocate("node-2", "default", ci.clone()).await;
+    allocate("other", "default", json!({ "cluster": "ci-2" })).await;
+    allocate("plain", "default", json!({})).await;
+    allocate("lab-node", "lab", ci).await;
+
+    let uri = "/api/v1/ip/release-by-label?label=cluster%3Dci-1&pool=default";
+    let (status, released) = call(&app, Method::DELETE, uri, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        released,
+        json!({
+            "label": "cluster=ci-1",
+            "released": [
+                { "pool": "default", "vm_id": "node-1", "ip": "172.16.0.2" },
+                { "pool": "default", "vm_id": "node-2", "ip": "172.16.0.3" },
+            ],
+        })
+    );
+
+    // Other labels, unlabelled allocations and other pools are untouched
+    assert!(pool.get_allocation("node-1").await.is_err());
+    assert!(pool.get_allocation("node-2").await.is_err());
+    assert_eq!(pool.get_allocation("other").await.unwrap().ip, "172.16.0.4");
+    assert_eq!(pool.get_allocation("plain").await.unwrap().ip, "172.16.0.5");
+    assert_eq!(
+        lab.get_allocation("lab-node").await.unwrap().ip,
+        "10.20.0.2"
+    );
+
+    // Without a pool every pool is covered; nothing left is an empty list
+    let uri = "/api/v1/ip/release-by-label?label=cluster%3Dci-1";
+    let (_, released) = call(&app, Method::DELETE, uri, None).await;
+    assert_eq!(
+        released["released"],
+        json!([{ "pool": "lab", "vm_id": "lab-node", "ip": "10.20.0.2" }])
+    );
+    let (status, released) = call(&app, Method::DELETE, uri, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(released["released"], json!([]));
+
+    let (status, _) = call(
+        &app,
+        Method::DELETE,
+        "/api/v1/ip/release-by-label?label=ci",
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_clone_allocation() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let app = app::embedded(pool.clone());
+    let body = json!({ "vm_id": "web-blue", "labels": { "app": "web" }, "owner": "alice" });
+    call(&app, Method::POST, "/api/v1/ip/allocate", Some(body)).await;
+    let mut annotation = pool.annotation("172.16.0.2").await;
+    annotation.ticket = Some("OPS-1".to_string());
+    pool.annotate("172.16.0.2", annotation).await.unwrap();
+
+    let uri = "/api/v1/ip/web-blue/clone-to/web-green";
+    let (status, cloned) = call(&app, Method::POST, uri, None).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(cloned["source"]["vm_id"], "web-blue");
+    assert_eq!(cloned["source"]["ip"], "172.16.0.2");
+    assert_eq!(cloned["clone"]["vm_id"], "web-green");
+    assert_eq!(cloned["clone"]["ip"], "172.16.0.3");
+    assert_eq!(cloned["clone"]["labels"], json!({ "app": "web" }));
+    assert_eq!(cloned["clone"]["owner"], "alice");
+    assert_eq!(cloned["clone"]["ticket"], "OPS-1");
+
+    // Both VMs hold their addresses side by side
+    assert_eq!(
+        pool.get_allocation("web-blue").await.unwrap().ip,
+        "172.16.0.2"
+    );
+    let clone = pool.get_allocation("web-green").await.unwrap();
+    assert_eq!(clone.ip, "172.16.0.3");
+    assert_eq!(clone.owner.as_deref(), Some("alice"));
+
+    // A target that already holds an address is not given another
+    call(
+        &app,
+        Method::POST,
+        "/api/v1/ip/allocate",
+        Some(json!({ "vm_id": "web-red" })),
+    )
+    .await;
+    let uri = "/api/v1/ip/web-blue/clone-to/web-red";
+    let (status, _) = call(&app, Method::POST, uri, None).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let held = pool.get_allocations("web-red").await.unwrap();
+    assert_eq!(held.len(), 1);
+    assert_eq!(held[0].ip, "172.16.0.4");
+    assert!(held[0].labels.is_empty());
+
+    let uri = "/api/v1/ip/web-gone/clone-to/web-new";
+    let (status, _) = call(&app, Method::POST, uri, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(pool.get_stats().await["allocated"], 3);
+}
+
+#[tokio::test]
+async fn test_owner_is_listed() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let app = app::embedded(pool);
+    let body = json!({ "vm_id": "vm-1", "owner": "alice@example.com" });
+    call(&app, Method::POST, "/api/v1/ip/allocate", Some(body)).await;
+    call(
+        &app,
+        Method::POST,
+        "/api/v1/ip/allocate",
+        Some(json!({ "vm_id": "vm-2" })),
+    )
+    .await;
+
+    let (_, found) = call(&app, Method::GET, "/api/v1/ip/vm-1", None).await;
+    assert_eq!(found[0]["owner"], "alice@example.com");
+    let (_, found) = call(&app, Method::GET, "/api/v1/ip/vm-2", None).await;
+    assert!(found[0].get("owner").is_none());
+}
+
+#[tokio::test]
+async fn test_macs_and_static_mappings() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let app = app::embedded(pool.clone());
+    let allocate = |vm_id: &str, mac: &str| {
+        let body =
+            json!({ "vm_id": vm_id, "mac": mac, "hostname": format!("{}.lab.local", vm_id) });
+        call(&app, Method::POST, "/api/v1/ip/allocate", Some(body))
+    };
+
+    // Either separator and case, stored lowercase and colon separated
+    assert_eq!(
+        allocate("web-1", "52-54-00-AB-CD-EF").await.0,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        allocate("web-2", "52:54:00:12:34:56").await.0,
+        StatusCode::CREATED
+    );
+    call(
+        &app,
+        Method::POST,
+        "/api/v1/ip/allocate",
+        Some(json!({ "vm_id": "no-mac" })),
+    )
+    .await;
+    let (_, found) = call(&app, Method::GET, "/api/v1/ip/web-1", None).await;
+    assert_eq!(found[0]["mac"], "52:54:00:ab:cd:ef");
+
+    // Refused before anything is allocated
+    for mac in [
+        "52:54:00:12:34",
+        "52:54:00:12:34:56:78",
+        "52:54:00:12:34:5g",
+        "525400123456",
+    ] {
+        let (status, error) = allocate("bad", mac).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", mac);
+        assert!(error["error"].is_string());
+    }
+    assert!(pool.get_allocation("bad").await.is_err());
+
+    // Only allocations with a MAC, in address order
+    let (status, xml) = text(&app, "/api/v1/export/static-mappings").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        xml,
+        "<staticmap>\n\
+         \t<mac>52:54:00:ab:cd:ef</mac>\n\
+         \t<ipaddr>172.16.0.2</ipaddr>\n\
+         \t<hostname>web-1</hostname>\n\
+         \t<descr>web-1 (default)</descr>\n\
+         </staticmap>\n\
+         <staticmap>\n\
+         \t<mac>52:54:00:12:34:56</mac>\n\
+         \t<ipaddr>172.16.0.3</ipaddr>\n\
+         \t<hostname>web-2</hostname>\n\
+         \t<descr>web-2 (default)</descr>\n\
+         </staticmap>\n"
+    );
+    let (_, mappings) = call(
+        &app,
+        Method::GET,
+        "/api/v1/export/static-mappings?format=json",
+        None,
+    )
+    .await;
+    assert_eq!(
+        mappings[1],
+        json!({
+            "mac": "52:54:00:12:34:56",
+            "ipaddr": "172.16.0.3",
+            "hostname": "web-2",
+            "descr": "web-2 (default)",
+        })
+    );
+    assert_eq!(mappings.as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_export_targets() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let lab = IpPool::new("10.20.0".to_string(), "10.20.0.1".to_string());
+    lab.set_vlan_id(Some(120)).await;
+    let state = AppState::new(pool);
+    state.pools.insert("lab".to_string(), lab).await;
+    let app = with_state(state);
+    let allocate = |vm_id: &str, pool: &str| {
+        let body = json!({ "vm_id": vm_id, "pool": pool });
+        call(&app, Method::POST, "/api/v1/ip/allocate", Some(body))
+    };
+    allocate("web-1", "default").await;
+    allocate("web-2", "default").await;
+    allocate("db-1", "lab").await;
+    call(&app, Method::DELETE, "/api/v1/ip/release/web-1", None).await;
+    allocate("web-3", "default").await;
+    allocate("web-4", "default").await;
+
+    // Every pool, in address order
+    let (status, nmap) = text(&app, "/api/v1/export/targets").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(nmap, "10.20.0.2\n172.16.0.2\n172.16.0.3\n172.16.0.4\n");
+    let (_, nmap) = text(&app, "/api/v1/export/targets?format=nmap&hostnames=true").await;
+    assert_eq!(
+        nmap,
+        "10.20.0.2 # db-1 vlan 120\n\
+         172.16.0.2 # web-3\n\
+         172.16.0.3 # web-2\n\
+         172.16.0.4 # web-4\n"
+    );
+    let (_, ssh) = text(&app, "/api/v1/export/targets?format=ssh&hostnames=true").await;
+    assert_eq!(
+        ssh,
+        "10.20.0.2,db-1\n\
+         172.16.0.2,web-3\n\
+         172.16.0.3,web-2\n\
+         172.16.0.4,web-4\n"
+    );
+    let (_, ssh) = text(&app, "/api/v1/export/targets?format=ssh&pool=default").await;
+    assert_eq!(ssh, "172.16.0.2\n172.16.0.3\n172.16.0.4\n");
+    let (_, nmap) = text(&app, "/api/v1/export/targets?vlan=120&hostnames=true").await;
+    assert_eq!(nmap, "10.20.0.2 # db-1 vlan 120\n");
+}
+
+#[tokio::test]
+async fn test_serves_on_unix_socket() {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = std::env::temp_dir().join(format!("ippool-socket-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("ippool.sock");
+    let path = path.to_str().unwrap();
+
+    // Left behind by a run that did not shut down cleanly
+    drop(std::os::unix::net::UnixListener::bind(path).unwrap());
+    let listener = app::bind_unix_socket(path, 0o660).unwrap();
+    let mode = std::fs::metadata(path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o660);
+
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let server = tokio::spawn(axum::serve(listener, app::embedded(pool.clone())).into_future());
+
+    // A socket that is still served is not taken over
+    assert!(app::bind_unix_socket(path, 0o660).is_err());
+
+    let mut stream = tokio::net::UnixStream::connect(path).await.unwrap();
+    let body = json!({ "vm_id": "agent-1" }).to_string();
+    let request = format!(
+        "POST /api/v1/ip/allocate HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+    assert!(response.contains("\"ip\":\"172.16.0.2\""));
+    assert_eq!(
+        pool.get_allocation("agent-1").await.unwrap().ip,
+        "172.16.0.2"
+    );
+
+    server.abort();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_error_mapping() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let app = app::embedded(pool.clone());
+    let allocate = |body: Value| call(&app, Method::POST, "/api/v1/ip/allocate", Some(body));
+
+    let (status, error) = allocate(json!({ "vm_id": "vm-1", "pool": "missing" })).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(error["error"].is_string());
+    let (status, _) = allocate(json!({ "vm_id": "vm-1", "ttl": 0 })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = allocate(json!({ "vm_id": "vm-1", "hostname": "no_underscores" })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(&app, Method::GET, "/api/v1/ip/vm-1", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Exhaustion says when to come back
+    for n in 0..253 {
+        pool.allocate_ip(format!("filler-{}", n)).await.unwrap();
+    }
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/api/v1/ip/allocate");
+    let (status, headers, error) = send(&app, request, Some(json!({ "vm_id": "vm-1" }))).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(error["error"].is_string());
+    let retry_after: u64 = headers["retry-after"].to_str().unwrap().parse().unwrap();
+    assert!(retry_after >= 1);
+}
+
+#[tokio::test]
+async fn test_release_by_address_checks_the_holder() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let app = app::embedded(pool.clone());
+    call(
+        &app,
+        Method::POST,
+        "/api/v1/ip/allocate",
+        Some(json!({ "vm_id": "vm-1" })),
+    )
+    .await;
+
+    // A stale cleanup naming the previous holder takes nothing away
+    let uri = "/api/v1/ip/release-by-ip/172.16.0.2?vm_id=vm-0";
+    let (status, error) = call(&app, Method::DELETE, uri, None).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(error["error"].is_string());
+    assert_eq!(pool.get_allocation("vm-1").await.unwrap().ip, "172.16.0.2");
+    let uri = "/api/v1/ip/release-by-ip/172.16.0.2";
+    let (status, _) = call(&app, Method::DELETE, uri, Some(json!({ "vm_id": "vm-0" }))).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let uri = "/api/v1/ip/release-by-ip/172.16.0.2?vm_id=vm-1";
+    let (status, _) = call(&app, Method::DELETE, uri, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(pool.get_allocation("vm-1").await.is_err());
+    let (status, _) = call(&app, Method::DELETE, uri, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_dashboard_releases_one_address() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let app = app::embedded(pool.clone());
+    let (status, page) = text(&app, "/ui").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(page.contains("/ip/release-by-ip/"));
+
+    for interface in ["eth0", "eth1"] {
+        let body = json!({ "vm_id": "vm-1", "interface": interface });
+        call(&app, Method::POST, "/api/v1/ip/allocate", Some(body)).await;
+    }
+    let (_, listed) = call(&app, Method::GET, "/api/v1/ip/allocations", None).await;
+    let row = (listed.as_array().unwrap().iter())
+        .find(|allocation| allocation["interface"] == "eth1")
+        .unwrap();
+
+    // What its Release button sends: the row's address, holder and token
+    let uri = format!(
+        "/api/v1/ip/release-by-ip/{}?vm_id=vm-1&fence_token={}",
+        row["ip"].as_str().unwrap(),
+        row["fence_token"]
+    );
+    let (status, _) = call(&app, Method::DELETE, &uri, None).await;
+    assert_eq!(status, StatusCode::OK);
+    let left = pool.get_allocations("vm-1").await.unwrap();
+    assert_eq!(left.len(), 1);
+    assert_eq!(left[0].interface.as_deref(), Some("eth0"));
+}
+
+#[tokio::test]
+async fn test_imports_terraform_state_above_body_limit() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let app = app::embedded(pool.clone());
+    // Real states carry far more than addresses, e.g. cloud-init user data
+    let instances: Vec<Value> = (0..100)
+        .map(|i| {
+            json!({
+                "index_key": i,
+                "attributes": {
+                    "name": format!("web-{}", i),
+                    "ipconfig0": format!("ip=172.16.0.{}/24,gw=172.16.0.1", i + 10),
+                    "user_data": "#cloud-config\n".repeat(60),
+                },
+            })
+        })
+        .collect();
+    let state = json!({
+        "version": 4,
+        "resources": [
+            { "mode": "managed", "type": "proxmox_vm_qemu", "name": "web", "instances": instances },
+        ],
+    });
+    assert!(state.to_string().len() > Limits::default().max_body_bytes);
+
+    let uri = "/api/v1/import/terraform?pool=default";
+    let (status, report) = call(&app, Method::POST, uri, Some(state)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(report["adopted"].as_array().unwrap().len(), 100);
+    assert_eq!(
+        pool.get_allocation("web-99").await.unwrap().ip,
+        "172.16.0.109"
+    );
+}
+
+#[tokio::test]
+async fn test_imports_lease_file_above_body_limit() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let app = app::embedded(pool.clone());
+    // Expired leases stay in the file until the server rewrites it
+    let mut leases = String::new();
+    for i in 0..2000 {
+        leases.push_str(&format!(
+            "1000 52:54:00:00:{:02x}:{:02x} 172.16.0.{} old-{} *\n",
+            i / 256,
+            i % 256,
+            i % 200 + 10,
+            i
+        ));
+    }
+    leases.push_str("4000000000 52:54:00:aa:bb:01 172.16.0.10 web-1 *\n");
+    assert!(leases.len() > Limits::default().max_body_bytes);
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/api/v1/admin/leases/import?pool=default&format=dnsmasq")
+        .body(Body::from(leases))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        pool.get_allocation("web-1").await.unwrap().ip,
+        "172.16.0.10"
+    );
+}
+
+#[tokio::test]
+async fn test_deferred_release_can_be_cancelled() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let app = app::embedded(pool.clone());
+    call(
+        &app,
+        Method::POST,
+        "/api/v1/ip/allocate",
+        Some(json!({ "vm_id": "vm-1" })),
+    )
+    .await;
+
+    let (status, released) = call(
+        &app,
+        Method::DELETE,
+        "/api/v1/ip/release/vm-1?grace=300",
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let release_at = released["release_at"].as_u64().unwrap();
+    let (status, found) = call(&app, Method::GET, "/api/v1/ip/vm-1", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(found[0]["pending_release_at"], release_at);
+
+    let uri = "/api/v1/ip/release/vm-1/cancel";
+    let (status, _) = call(&app, Method::POST, uri, None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, found) = call(&app, Method::GET, "/api/v1/ip/vm-1", None).await;
+    assert_eq!(found[0]["ip"], "172.16.0.2");
+    assert!(found[0].get("pending_release_at").is_none());
+    let (status, _) = call(&app, Method::POST, uri, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_conditional_get() {
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let app = app::embedded(pool.clone());
+    call(
+        &app,
+        Method::POST,
+        "/api/v1/ip/allocate",
+        Some(json!({ "vm_id": "vm-1" })),
+    )
+    .await;
+    let get = |uri: &'static str, etag: Option<&str>| {
+        let request = Request::builder().uri(uri);
+        let request = match etag {
+            Some(etag) => request.header("if-none-match", etag),
+            None => request,
+        };
+        send(&app, request, None)
+    };
+
+    for uri in ["/api/v1/ip/allocations", "/api/v1/ip/stats"] {
+        let (status, headers, _) = get(uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let etag = headers["etag"].to_str().unwrap().to_string();
+        let (status, _, body) = get(uri, Some(&etag)).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED, "{}", uri);
+        assert_eq!(body, Value::Null);
+
+        // A change is sent in full, with a new tag
+        pool.allocate_ip(format!("vm-{}", uri.len())).await.unwrap();
+        let (status, headers, _) = get(uri, Some(&etag)).await;
+        assert_eq!(status, StatusCode::OK, "{}", uri);
+        assert_ne!(headers["etag"], etag.as_str());
+    }
+}
+
+// Clock the test moves forward by hand
+#[derive(Debug, Default)]
+struct Later(Mutex<Duration>);
+
+impl Clock for Later {
+    fn now(&self) -> Instant {
+        SystemClock.now() + *self.0.lock().unwrap()
+    }
+
+    fn unix_now(&self) -> u64 {
+        SystemClock.unix_now() + self.0.lock().unwrap().as_secs()
+    }
+}
+
+#[tokio::test]
+async fn test_stats_tag_outlasts_burn_rate() {
+    let clock = Arc::new(Later::default());
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let app = app::embedded(pool.with_clock(clock.clone()));
+    call(
+        &app,
+        Method::POST,
+        "/api/v1/ip/allocate",
+        Some(json!({ "vm_id": "vm-1" })),
+    )
+    .await;
+    let (_, headers, stats) = send(&app, Request::builder().uri("/api/v1/ip/stats"), None).await;
+    assert!(stats["burn_rate"]["5m"].as_f64().unwrap() > 0.0);
+    let etag = headers["etag"].to_str().unwrap().to_string();
+
+    // The allocation leaves the 5m window, the pool stays as it was
+    *clock.0.lock().unwrap() += Duration::from_secs(600);
+    let (_, _, stats) = send(&app, Request::builder().uri("/api/v1/ip/stats"), None).await;
+    assert_eq!(stats["burn_rate"]["5m"], 0.0);
+    let request = Request::builder()
+        .uri("/api/v1/ip/stats")
+        .header("if-none-match", &etag);
+    assert_eq!(send(&app, request, None).await.0, StatusCode::NOT_MODIFIED);
+}
+
+#[tokio::test]
+async fn test_requests_are_shed_under_load() {
+    use axum::body::Bytes;
+
+    let pool = IpPool::new("172.16.0".to_string(), "172.16.0.1".to_string());
+    let limits = Limits {
+        max_concurrent_requests: 1,
+        request_timeout: Duration::from_millis(200),
+        ..Limits::default()
+    };
+    let app = app::router(AppState::new(pool), Extras::default(), &limits);
+    // A client that never finishes sending its body
+    let stalled = || {
+        let body = futures_util::stream::pending::<Result<Bytes, std::io::Error>>();
+        Request::builder()
+            .method(Method::POST)
+            .uri("/api/v1/ip/allocate")
+            .header("content-type", "application/json")
+            .body(Body::from_stream(body))
+            .unwrap()
+    };
+
+    let slow = tokio::spawn(app.clone().oneshot(stalled()));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let (status, headers, error) =
+        send(&app, Request::builder().uri("/api/v1/ip/stats"), None).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(headers["retry-after"], "1");
+    assert!(error["error"].is_string());
+
+    // The stalled request itself times out the same way
+    let response = slow.await.unwrap().unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()["retry-after"], "1");
+    let (status, _, _) = send(&app, Request::builder().uri("/api/v1/ip/stats"), None).await;
+    assert_eq!(status, StatusCode::OK);
+}